@@ -0,0 +1,552 @@
+//! The format that sets of documents are stored in
+//!
+//! Storage backends write term directories and field presence bitmaps in this format, and
+//! "DocIdSet::to_bytes" writes it too, so a set written by any of them can be read by the
+//! others. Each set is prefixed with a header that records the version of the format it was
+//! written with, so the format can be changed later without breaking existing indexes.
+//!
+//!  - Version 1: a serialized RoaringBitmap, which is the smallest for dense bitmaps
+//!  - Version 2: the gaps between the documents as variable-length integers. Most terms are
+//!    rare, so this is used for most term directories and is much quicker to decode than a
+//!    RoaringBitmap
+//!  - Version 3: the gap before and the length of each run of consecutive documents, as
+//!    variable-length integers. This is used for bitmaps that have all or nearly all documents
+//!    (roaring's own run containers aren't supported by the version of the roaring crate in use)
+//!  - Version 4: a bit for each document id, as 64-bit little endian words up to the last one
+//!    that contains a document. This is how DocIdSet writes the sets it holds as bitmaps,
+//!    unless they're smaller as runs
+//!
+//! Sets written before the header was added (by kite_rocksdb) are plain roaring bitmaps. These
+//! are still readable as a headered set starts with two 0xFF bytes, which a roaring
+//! serialization never starts with (it always starts with one of two "cookie" values).
+
+use std::io::{self, Cursor};
+use std::fmt;
+use std::error::Error;
+
+use roaring::RoaringBitmap;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The version of the format that roaring bitmaps are written with
+pub const ROARING_BITMAP_FORMAT_VERSION: u8 = 1;
+
+/// The version of the format that delta encoded bitmaps are written with
+pub const DELTA_BITMAP_FORMAT_VERSION: u8 = 2;
+
+/// The version of the format that run-length encoded bitmaps are written with
+pub const RUNS_BITMAP_FORMAT_VERSION: u8 = 3;
+
+/// The version of the format that uncompressed bitmaps are written with
+pub const WORDS_BITMAP_FORMAT_VERSION: u8 = 4;
+
+const BITMAP_MAGIC: [u8; 2] = [0xFF, 0xFF];
+
+#[derive(Debug)]
+pub enum BitmapDecodeError {
+    /// The bitmap was written with a newer version of the format than this version of kite can read
+    UnsupportedVersion(u8),
+
+    /// The bitmap data couldn't be read
+    Corrupt(io::Error),
+}
+
+impl fmt::Display for BitmapDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BitmapDecodeError::UnsupportedVersion(version) => write!(f, "unsupported bitmap format version: {}", version),
+            BitmapDecodeError::Corrupt(ref e) => write!(f, "corrupt bitmap: {}", e),
+        }
+    }
+}
+
+impl Error for BitmapDecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BitmapDecodeError::UnsupportedVersion(_) => None,
+            BitmapDecodeError::Corrupt(ref e) => Some(e),
+        }
+    }
+}
+
+/// The formats a set of documents can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapFormat {
+    Roaring,
+    Delta,
+    Runs,
+    Words,
+}
+
+impl BitmapFormat {
+    fn version(&self) -> u8 {
+        match *self {
+            BitmapFormat::Roaring => ROARING_BITMAP_FORMAT_VERSION,
+            BitmapFormat::Delta => DELTA_BITMAP_FORMAT_VERSION,
+            BitmapFormat::Runs => RUNS_BITMAP_FORMAT_VERSION,
+            BitmapFormat::Words => WORDS_BITMAP_FORMAT_VERSION,
+        }
+    }
+
+    /// Returns the format that writes the bitmap in the fewest bytes
+    ///
+    /// Ties go to the delta format, then runs, as they're quicker to decode than roaring. The
+    /// words format isn't considered as it's only used by DocIdSet, which already holds its
+    /// dense sets as words.
+    pub fn smallest_for(bitmap: &RoaringBitmap) -> BitmapFormat {
+        let (format, size) = BitmapFormat::smallest_for_doc_ids(bitmap.iter());
+        if size <= bitmap.serialized_size() {
+            format
+        } else {
+            BitmapFormat::Roaring
+        }
+    }
+
+    /// Returns whichever of the delta and runs formats writes the documents, which must be
+    /// given in increasing order, in the fewest bytes, along with the size of the data
+    pub fn smallest_for_doc_ids<I: Iterator<Item = u32>>(doc_ids: I) -> (BitmapFormat, usize) {
+        let mut delta_size = 0;
+        let mut runs_size = 0;
+        let mut previous = None;
+        let mut run_start = None;
+
+        for doc_id in doc_ids {
+            delta_size += varint_len(delta_gap(previous, doc_id));
+
+            let extends_run = previous.map(|previous| previous + 1 == doc_id).unwrap_or(false);
+            if !extends_run {
+                if let (Some(start), Some(previous)) = (run_start, previous) {
+                    runs_size += varint_len(previous - start);
+                }
+                runs_size += varint_len(run_gap(previous, doc_id));
+                run_start = Some(doc_id);
+            }
+
+            previous = Some(doc_id);
+        }
+
+        if let (Some(start), Some(previous)) = (run_start, previous) {
+            runs_size += varint_len(previous - start);
+        }
+
+        if delta_size <= runs_size {
+            (BitmapFormat::Delta, delta_size)
+        } else {
+            (BitmapFormat::Runs, runs_size)
+        }
+    }
+}
+
+/// The gap before a document in the delta format
+///
+/// The first gap is from zero, the rest are one less than the difference as documents can't
+/// repeat.
+fn delta_gap(previous: Option<u32>, doc_id: u32) -> u32 {
+    match previous {
+        Some(previous) => doc_id - previous - 1,
+        None => doc_id,
+    }
+}
+
+/// The gap before a run in the runs format
+///
+/// The first gap is from zero. Runs can't touch, so the rest are two less than the difference
+/// from the end of the previous run.
+fn run_gap(previous_end: Option<u32>, start: u32) -> u32 {
+    match previous_end {
+        Some(previous_end) => start - previous_end - 2,
+        None => start,
+    }
+}
+
+fn varint_len(value: u32) -> usize {
+    match value {
+        0..=0x7F => 1,
+        0x80..=0x3FFF => 2,
+        0x4000..=0x1F_FFFF => 3,
+        0x20_0000..=0xFFF_FFFF => 4,
+        _ => 5,
+    }
+}
+
+fn push_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> Result<u64, BitmapDecodeError> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = match bytes.get(*position) {
+            Some(byte) => *byte,
+            None => return Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated integer"))),
+        };
+        *position += 1;
+
+        if shift > 28 {
+            return Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::InvalidData, "integer out of range")));
+        }
+
+        value |= ((byte & 0x7F) as u64) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn check_doc_id(doc_id: u64) -> Result<u32, BitmapDecodeError> {
+    if doc_id > u32::max_value() as u64 {
+        return Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::InvalidData, "document id out of range")));
+    }
+
+    Ok(doc_id as u32)
+}
+
+fn header(format: BitmapFormat) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&BITMAP_MAGIC);
+    bytes.push(format.version());
+    bytes
+}
+
+/// Serializes a term directory or field presence bitmap in whichever format is smallest for it
+pub fn encode_roaring_bitmap(bitmap: &RoaringBitmap) -> Vec<u8> {
+    encode_bitmap_as(bitmap, BitmapFormat::smallest_for(bitmap))
+}
+
+/// Serializes a term directory or field presence bitmap in a specific format
+pub fn encode_bitmap_as(bitmap: &RoaringBitmap, format: BitmapFormat) -> Vec<u8> {
+    match format {
+        BitmapFormat::Roaring => {
+            let mut bytes = header(format);
+
+            // Writing into a Vec can't fail
+            bitmap.serialize_into(&mut bytes).unwrap();
+            bytes
+        }
+        format => encode_doc_ids_as(bitmap.iter(), format),
+    }
+}
+
+/// Serializes a set of documents, which must be given in increasing order, in a specific format
+pub fn encode_doc_ids_as<I: Iterator<Item = u32>>(doc_ids: I, format: BitmapFormat) -> Vec<u8> {
+    match format {
+        BitmapFormat::Roaring => encode_bitmap_as(&doc_ids.collect(), format),
+        BitmapFormat::Delta => {
+            let mut bytes = header(format);
+            let mut previous = None;
+            for doc_id in doc_ids {
+                push_varint(&mut bytes, delta_gap(previous, doc_id));
+                previous = Some(doc_id);
+            }
+
+            bytes
+        }
+        BitmapFormat::Runs => {
+            let mut bytes = header(format);
+            let mut previous_end = None;
+            let mut iter = doc_ids.peekable();
+            while let Some(start) = iter.next() {
+                let mut end = start;
+                while end < u32::max_value() && iter.peek() == Some(&(end + 1)) {
+                    end += 1;
+                    iter.next();
+                }
+
+                push_varint(&mut bytes, run_gap(previous_end, start));
+                push_varint(&mut bytes, end - start);
+                previous_end = Some(end);
+            }
+
+            bytes
+        }
+        BitmapFormat::Words => {
+            let mut words: Vec<u64> = Vec::new();
+            for doc_id in doc_ids {
+                let word = doc_id as usize / 64;
+                if word >= words.len() {
+                    words.resize(word + 1, 0);
+                }
+                words[word] |= 1u64 << (doc_id % 64);
+            }
+
+            encode_words(&words)
+        }
+    }
+}
+
+/// Serializes a bitmap that's held as words in the words format
+///
+/// The words after the last one that contains a document should be left out.
+pub fn encode_words(words: &[u64]) -> Vec<u8> {
+    let mut bytes = header(BitmapFormat::Words);
+    bytes.resize(3 + words.len() * 8, 0);
+    for (i, word) in words.iter().enumerate() {
+        LittleEndian::write_u64(&mut bytes[3 + i * 8..], *word);
+    }
+
+    bytes
+}
+
+/// Splits a serialized set into the version of the format it was written with and its data
+fn split_header(bytes: &[u8]) -> Result<(u8, &[u8]), BitmapDecodeError> {
+    if bytes.starts_with(&BITMAP_MAGIC) {
+        match bytes.get(2) {
+            Some(&version) => Ok((version, &bytes[3..])),
+            None => Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::UnexpectedEof, "missing format version"))),
+        }
+    } else {
+        // Written before the format was versioned
+        Ok((ROARING_BITMAP_FORMAT_VERSION, bytes))
+    }
+}
+
+fn decode_data<F: FnMut(u32)>(version: u8, data: &[u8], mut insert: F) -> Result<(), BitmapDecodeError> {
+    match version {
+        ROARING_BITMAP_FORMAT_VERSION => {
+            let bitmap = try!(RoaringBitmap::deserialize_from(Cursor::new(data)).map_err(BitmapDecodeError::Corrupt));
+            for doc_id in bitmap.iter() {
+                insert(doc_id);
+            }
+        }
+        DELTA_BITMAP_FORMAT_VERSION => {
+            let mut previous = None;
+            let mut position = 0;
+            while position < data.len() {
+                let gap = try!(read_varint(data, &mut position));
+                let doc_id = try!(check_doc_id(match previous {
+                    Some(previous) => previous as u64 + gap + 1,
+                    None => gap,
+                }));
+
+                insert(doc_id);
+                previous = Some(doc_id);
+            }
+        }
+        RUNS_BITMAP_FORMAT_VERSION => {
+            // Every run is at least two bytes long, so the input bounds how many there can be.
+            // All of them are read before any documents are inserted so that a corrupt set
+            // is rejected before any work is done expanding its runs.
+            let mut runs = Vec::with_capacity(data.len() / 2);
+            let mut previous_end = None;
+            let mut position = 0;
+            while position < data.len() {
+                if runs.len() >= data.len() / 2 {
+                    return Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::InvalidData, "too many runs")));
+                }
+
+                let gap = try!(read_varint(data, &mut position));
+                let length = try!(read_varint(data, &mut position));
+                let start = match previous_end {
+                    Some(previous_end) => (previous_end as u64).checked_add(gap).and_then(|start| start.checked_add(2)),
+                    None => Some(gap),
+                };
+                let start = match start {
+                    Some(start) => try!(check_doc_id(start)),
+                    None => return Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::InvalidData, "document id out of range"))),
+                };
+                let end = match (start as u64).checked_add(length) {
+                    Some(end) => try!(check_doc_id(end)),
+                    None => return Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::InvalidData, "document id out of range"))),
+                };
+
+                runs.push((start, end));
+                previous_end = Some(end);
+            }
+
+            for (start, end) in runs {
+                for doc_id in start..=end {
+                    insert(doc_id);
+                }
+            }
+        }
+        WORDS_BITMAP_FORMAT_VERSION => {
+            if data.len() % 8 != 0 {
+                return Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated word")));
+            }
+
+            for (i, word) in data.chunks(8).enumerate() {
+                let base = match (i as u64).checked_mul(64) {
+                    Some(base) => base,
+                    None => return Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::InvalidData, "document id out of range"))),
+                };
+
+                let mut word = LittleEndian::read_u64(word);
+                while word != 0 {
+                    insert(try!(check_doc_id(base + word.trailing_zeros() as u64)));
+                    word &= word - 1;
+                }
+            }
+        }
+        version => return Err(BitmapDecodeError::UnsupportedVersion(version)),
+    }
+
+    Ok(())
+}
+
+/// Deserializes a set of documents, passing each of them to "insert" in increasing order
+pub fn decode_doc_ids<F: FnMut(u32)>(bytes: &[u8], insert: F) -> Result<(), BitmapDecodeError> {
+    let (version, data) = try!(split_header(bytes));
+    decode_data(version, data, insert)
+}
+
+/// Deserializes a term directory or field presence bitmap
+pub fn decode_roaring_bitmap(bytes: &[u8]) -> Result<RoaringBitmap, BitmapDecodeError> {
+    let (version, data) = try!(split_header(bytes));
+
+    if version == ROARING_BITMAP_FORMAT_VERSION {
+        return RoaringBitmap::deserialize_from(Cursor::new(data)).map_err(BitmapDecodeError::Corrupt);
+    }
+
+    let mut bitmap = RoaringBitmap::new();
+    try!(decode_data(version, data, |doc_id| { bitmap.insert(doc_id); }));
+    Ok(bitmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use roaring::RoaringBitmap;
+
+    use super::{BitmapFormat, BitmapDecodeError, encode_roaring_bitmap, encode_bitmap_as, encode_words, decode_roaring_bitmap, decode_doc_ids};
+
+    fn make_bitmap() -> RoaringBitmap {
+        let mut bitmap = RoaringBitmap::new();
+        bitmap.insert(1);
+        bitmap.insert(5);
+        bitmap.insert(65535);
+        bitmap
+    }
+
+    #[test]
+    fn test_roaring_bitmap() {
+        let bitmap = make_bitmap();
+        let bytes = encode_bitmap_as(&bitmap, BitmapFormat::Roaring);
+        assert_eq!(&bytes[..3], &[0xFF, 0xFF, 1]);
+        assert_eq!(decode_roaring_bitmap(&bytes).unwrap(), bitmap);
+    }
+
+    #[test]
+    fn test_roaring_bitmap_unversioned() {
+        let bitmap = make_bitmap();
+        let mut bytes = Vec::new();
+        bitmap.serialize_into(&mut bytes).unwrap();
+        assert_eq!(decode_roaring_bitmap(&bytes).unwrap(), bitmap);
+    }
+
+    #[test]
+    fn test_roaring_bitmap_errors() {
+        match decode_roaring_bitmap(&[0xFF, 0xFF, 5, 0, 0]) {
+            Err(BitmapDecodeError::UnsupportedVersion(5)) => {}
+            result => panic!("expected an unsupported version error, got {:?}", result),
+        }
+
+        match decode_roaring_bitmap(&[0xFF, 0xFF]) {
+            Err(BitmapDecodeError::Corrupt(_)) => {}
+            result => panic!("expected a corrupt error, got {:?}", result),
+        }
+
+        let mut bytes = encode_bitmap_as(&make_bitmap(), BitmapFormat::Roaring);
+        bytes.truncate(6);
+        assert!(decode_roaring_bitmap(&bytes).is_err());
+
+        // The last document's gap is cut off part way through
+        let mut bytes = encode_bitmap_as(&make_bitmap(), BitmapFormat::Delta);
+        bytes.pop();
+        assert!(decode_roaring_bitmap(&bytes).is_err());
+
+        // A run that goes past the largest document id
+        let mut bytes = vec![0xFF, 0xFF, 3];
+        bytes.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x01]);
+        assert!(decode_roaring_bitmap(&bytes).is_err());
+
+        // The last word is cut off part way through
+        let mut bytes = encode_bitmap_as(&make_bitmap(), BitmapFormat::Words);
+        bytes.pop();
+        assert!(decode_roaring_bitmap(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_runs_checked_before_insert() {
+        // A run covering every document id, followed by a gap that overflows
+        let mut bytes = vec![0xFF, 0xFF, 3];
+        bytes.extend_from_slice(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x00, 0x00]);
+
+        let mut inserted = 0;
+        match decode_doc_ids(&bytes, |_| inserted += 1) {
+            Err(BitmapDecodeError::Corrupt(_)) => {}
+            result => panic!("expected a corrupt error, got {:?}", result),
+        }
+        assert_eq!(inserted, 0);
+
+        // The same run, cut off before its length
+        let mut bytes = vec![0xFF, 0xFF, 3];
+        bytes.extend_from_slice(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert!(decode_doc_ids(&bytes, |_| inserted += 1).is_err());
+        assert_eq!(inserted, 0);
+    }
+
+    #[test]
+    fn test_bitmap_formats() {
+        let mut bitmaps = vec![RoaringBitmap::new(), make_bitmap()];
+        bitmaps.push((0..100000).collect());
+        bitmaps.push((0..100000).filter(|doc_id| doc_id % 3 == 0).collect());
+        bitmaps.push(vec![0, 1, 2, 10, 11, u32::max_value() - 1, u32::max_value()].into_iter().collect());
+
+        for bitmap in bitmaps {
+            let mut formats = vec![BitmapFormat::Roaring, BitmapFormat::Delta, BitmapFormat::Runs];
+
+            // Don't write 512MB of words for the largest document id
+            if bitmap.max().map(|max| max < 1000000).unwrap_or(true) {
+                formats.push(BitmapFormat::Words);
+            }
+
+            for format in formats {
+                let bytes = encode_bitmap_as(&bitmap, format);
+                assert_eq!(decode_roaring_bitmap(&bytes).unwrap(), bitmap, "{:?}", format);
+
+                let mut doc_ids = Vec::new();
+                decode_doc_ids(&bytes, |doc_id| doc_ids.push(doc_id)).unwrap();
+                assert_eq!(doc_ids, bitmap.iter().collect::<Vec<_>>(), "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn test_words_format() {
+        let bytes = encode_words(&[0b100010, 0, 1]);
+        assert_eq!(&bytes[..3], &[0xFF, 0xFF, 4]);
+        assert_eq!(bytes.len(), 3 + 3 * 8);
+        assert_eq!(decode_roaring_bitmap(&bytes).unwrap().iter().collect::<Vec<_>>(), vec![1, 5, 128]);
+        assert_eq!(encode_bitmap_as(&vec![1, 5, 128].into_iter().collect(), BitmapFormat::Words), bytes);
+    }
+
+    #[test]
+    fn test_smallest_bitmap_format() {
+        // Sparse bitmaps are delta encoded
+        assert_eq!(BitmapFormat::smallest_for(&make_bitmap()), BitmapFormat::Delta);
+        assert_eq!(encode_roaring_bitmap(&make_bitmap())[2], 2);
+        assert_eq!(BitmapFormat::smallest_for(&RoaringBitmap::new()), BitmapFormat::Delta);
+
+        // Bitmaps of consecutive documents are run-length encoded
+        let bitmap = (0..100000).filter(|doc_id| *doc_id != 500).collect::<RoaringBitmap>();
+        assert_eq!(BitmapFormat::smallest_for(&bitmap), BitmapFormat::Runs);
+        assert_eq!(encode_roaring_bitmap(&bitmap).len(), 10);
+
+        // Dense bitmaps without runs are left to roaring
+        let bitmap = (0..100000).filter(|doc_id| doc_id % 2 == 0).collect::<RoaringBitmap>();
+        assert_eq!(BitmapFormat::smallest_for(&bitmap), BitmapFormat::Roaring);
+
+        // The chosen format is never larger than the others
+        let bitmap = (0..100000).filter(|doc_id| doc_id % 3 == 0).collect::<RoaringBitmap>();
+        let size = encode_roaring_bitmap(&bitmap).len();
+        for format in vec![BitmapFormat::Roaring, BitmapFormat::Delta, BitmapFormat::Runs] {
+            assert!(size <= encode_bitmap_as(&bitmap, format).len());
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+
+use fnv::FnvHashMap;
+
+use schema::FieldId;
+use collectors::{Collector, ParallelCollector, DocumentMatch};
+
+/// Collects the top scoring documents, keeping only the best document for each value of a field
+///
+/// The field must have doc values, so to de-duplicate on a string (such as a canonical URL),
+/// index a hash of it into an integer field. Documents without a value are never considered
+/// duplicates.
+///
+/// Every matching document must be seen to count the duplicates, so searches using this
+/// collector can't skip any documents. The best document for every distinct value is kept
+/// in memory.
+#[derive(Debug)]
+pub struct DedupCollector {
+    field: FieldId,
+    max_docs: usize,
+
+    /// The id and score of the best document for each value
+    best: FnvHashMap<i64, (u64, f32)>,
+
+    /// Documents that don't have a value
+    unique: Vec<(u64, f32)>,
+    duplicates: u64,
+}
+
+impl DedupCollector {
+    pub fn new(field: FieldId, max_docs: usize) -> DedupCollector {
+        DedupCollector {
+            field: field,
+            max_docs: max_docs,
+            best: FnvHashMap::default(),
+            unique: Vec::new(),
+            duplicates: 0,
+        }
+    }
+
+    /// Returns the number of documents that were dropped as they had the same value as a better document
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Returns the top documents, highest scores first
+    ///
+    /// Each document's value of the field is returned as its sort value.
+    pub fn into_sorted_vec(self) -> Vec<DocumentMatch> {
+        let mut docs = self.best.into_iter()
+            .map(|(value, (id, score))| (id, score, Some(value)))
+            .chain(self.unique.into_iter().map(|(id, score)| (id, score, None)))
+            .collect::<Vec<_>>();
+
+        docs.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal)
+                .then(a.0.cmp(&b.0))
+        });
+        docs.truncate(self.max_docs);
+
+        docs.into_iter()
+            .map(|(id, score, value)| DocumentMatch::new_scored(id, score).with_sort_value(value))
+            .collect()
+    }
+}
+
+impl Collector for DedupCollector {
+    fn needs_score(&self) -> bool {
+        true
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        let score = match doc.score() {
+            Some(score) if score.is_nan() => panic!("document with 'NaN' score was passed into DedupCollector"),
+            Some(score) => score,
+            None => panic!("unscored document was passed into DedupCollector"),
+        };
+
+        let value = match doc.sort_value() {
+            Some(value) => value,
+            None => {
+                self.unique.push((doc.doc_id(), score));
+                return;
+            }
+        };
+
+        match self.best.entry(value) {
+            Entry::Occupied(mut entry) => {
+                self.duplicates += 1;
+
+                // Ties keep the document with the lowest id so the result doesn't depend on
+                // the order documents were collected in
+                let best = entry.get_mut();
+                if score > best.1 || (score == best.1 && doc.doc_id() < best.0) {
+                    *best = (doc.doc_id(), score);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((doc.doc_id(), score));
+            }
+        }
+    }
+
+    fn sort_field(&self) -> Option<FieldId> {
+        Some(self.field)
+    }
+}
+
+impl ParallelCollector for DedupCollector {
+    fn segment_collector(&self) -> DedupCollector {
+        DedupCollector::new(self.field, self.max_docs)
+    }
+
+    fn merge(&mut self, other: DedupCollector) {
+        self.duplicates += other.duplicates;
+        self.unique.extend(other.unique);
+
+        for (value, (id, score)) in other.best {
+            match self.best.entry(value) {
+                Entry::Occupied(mut entry) => {
+                    self.duplicates += 1;
+
+                    let best = entry.get_mut();
+                    if score > best.1 || (score == best.1 && id < best.0) {
+                        *best = (id, score);
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert((id, score));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use schema::FieldId;
+    use collectors::{Collector, ParallelCollector, DocumentMatch};
+    use super::DedupCollector;
+
+    #[test]
+    fn test_dedup_collector_collect() {
+        let mut collector = DedupCollector::new(FieldId(1), 10);
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32).with_sort_value(Some(5)));
+        collector.collect(DocumentMatch::new_scored(1, 3.0f32).with_sort_value(Some(5)));
+        collector.collect(DocumentMatch::new_scored(2, 2.0f32).with_sort_value(Some(7)));
+        collector.collect(DocumentMatch::new_scored(3, 0.5f32).with_sort_value(Some(5)));
+        collector.collect(DocumentMatch::new_scored(4, 1.5f32).with_sort_value(None));
+        collector.collect(DocumentMatch::new_scored(5, 1.5f32).with_sort_value(None));
+
+        assert_eq!(collector.duplicates(), 2);
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![1, 2, 4, 5]);
+        assert_eq!(docs[0].sort_value(), Some(5));
+        assert_eq!(docs[2].sort_value(), None);
+    }
+
+    #[test]
+    fn test_dedup_collector_truncate() {
+        let mut collector = DedupCollector::new(FieldId(1), 1);
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32).with_sort_value(Some(1)));
+        collector.collect(DocumentMatch::new_scored(1, 2.0f32).with_sort_value(Some(2)));
+        collector.collect(DocumentMatch::new_scored(2, 2.0f32).with_sort_value(Some(1)));
+
+        // Ties are broken by id
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].doc_id(), 1);
+    }
+
+    #[test]
+    fn test_dedup_collector_merge() {
+        let mut collector = DedupCollector::new(FieldId(1), 10);
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32).with_sort_value(Some(5)));
+        collector.collect(DocumentMatch::new_scored(1, 2.0f32).with_sort_value(Some(7)));
+
+        let mut segment_collector = collector.segment_collector();
+        segment_collector.collect(DocumentMatch::new_scored(2, 3.0f32).with_sort_value(Some(5)));
+        segment_collector.collect(DocumentMatch::new_scored(3, 0.5f32).with_sort_value(Some(5)));
+        segment_collector.collect(DocumentMatch::new_scored(4, 1.5f32).with_sort_value(None));
+
+        // Documents are duplicates of ones in the same segment collector or in the one they're merged into
+        collector.merge(segment_collector);
+        assert_eq!(collector.duplicates(), 2);
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![2, 1, 4]);
+    }
+}
@@ -1,10 +1,16 @@
 pub mod total_count;
 pub mod top_score;
+pub mod top_field;
+pub mod dedup;
+
+use schema::FieldId;
+use collectors::top_field::SortOrder;
 
 #[derive(Debug)]
 pub struct DocumentMatch {
     id: u64,
     score: Option<f32>,
+    sort_value: Option<i64>,
 }
 
 impl DocumentMatch {
@@ -12,6 +18,7 @@ impl DocumentMatch {
         DocumentMatch {
             id: id,
             score: None,
+            sort_value: None,
         }
     }
 
@@ -19,9 +26,16 @@ impl DocumentMatch {
         DocumentMatch {
             id: id,
             score: Some(score),
+            sort_value: None,
         }
     }
 
+    /// Sets the value of the collector's sort field for this document
+    pub fn with_sort_value(mut self, sort_value: Option<i64>) -> DocumentMatch {
+        self.sort_value = sort_value;
+        self
+    }
+
     #[inline]
     pub fn doc_id(&self) -> u64 {
         self.id
@@ -31,9 +45,100 @@ impl DocumentMatch {
     pub fn score(&self) -> Option<f32> {
         self.score
     }
+
+    /// Returns the value of the collector's sort field, None if the document doesn't have one
+    #[inline]
+    pub fn sort_value(&self) -> Option<i64> {
+        self.sort_value
+    }
+}
+
+/// How accurately a collector should count the documents that match a search
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackTotalHits {
+    /// Count every matching document
+    ///
+    /// This prevents searches from skipping any documents so it can be much slower.
+    Exact,
+
+    /// Count matching documents exactly up to this number
+    ///
+    /// Once this many documents have matched, the search may start skipping documents so
+    /// the total is a lower bound.
+    UpTo(u64),
+
+    /// The total doesn't need to be accurate, documents may be skipped as soon as possible
+    Disabled,
+}
+
+/// The number of documents that matched a search
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TotalHits {
+    /// Exactly this many documents matched
+    Exact(u64),
+
+    /// At least this many documents matched, some may have been skipped
+    LowerBound(u64),
+}
+
+impl TotalHits {
+    pub fn value(&self) -> u64 {
+        match *self {
+            TotalHits::Exact(value) | TotalHits::LowerBound(value) => value,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        match *self {
+            TotalHits::Exact(_) => true,
+            TotalHits::LowerBound(_) => false,
+        }
+    }
 }
 
 pub trait Collector {
     fn needs_score(&self) -> bool;
     fn collect(&mut self, doc: DocumentMatch);
+
+    /// Returns the score a document must beat to make a difference to this collector
+    ///
+    /// Searches may skip documents that are known to score lower than this. Returns None
+    /// if every document must be collected.
+    fn min_competitive_score(&self) -> Option<f32> {
+        None
+    }
+
+    /// Returns the field that this collector sorts documents by
+    ///
+    /// If set, the search reads the field's doc values and passes the value of each document
+    /// into the collector with it.
+    fn sort_field(&self) -> Option<FieldId> {
+        None
+    }
+
+    /// Returns the order that this collector sorts documents by its sort field
+    ///
+    /// If a segment was written in this order, the search can stop reading it as soon as
+    /// a document isn't competitive as none of the documents after it will be either.
+    fn sort_order(&self) -> Option<SortOrder> {
+        None
+    }
+
+    /// Returns false if a document with this sort value can't make a difference to this collector
+    fn is_competitive_sort_value(&self, _sort_value: Option<i64>) -> bool {
+        true
+    }
+}
+
+/// A collector that can be split up so that segments can be searched in parallel
+///
+/// Each thread collects the documents of the segments it searches into its own collector,
+/// created with "segment_collector". Once they've finished, these are merged back into the
+/// original collector in the same order each time.
+pub trait ParallelCollector: Collector + Send + Sized {
+    /// Returns an empty collector with the same configuration as this one
+    fn segment_collector(&self) -> Self;
+
+    /// Adds the documents collected by a segment collector into this one
+    fn merge(&mut self, other: Self);
 }
@@ -0,0 +1,217 @@
+use std::collections::BinaryHeap;
+
+use schema::FieldId;
+use collectors::{Collector, ParallelCollector, DocumentMatch};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+/// A document in the heap
+///
+/// Documents are ordered from best to worst. Documents without a value are always put last
+/// and documents with the same value are ordered by id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SortedDocument {
+    missing: bool,
+
+    /// The sort value, inverted for descending sorts so that lower is always better
+    key: i64,
+    id: u64,
+}
+
+/// Collects the top documents, ordered by the value of a field
+///
+/// The values are read from the field's doc values so stored fields never need to be loaded
+/// while collecting.
+#[derive(Debug)]
+pub struct TopFieldCollector {
+    field: FieldId,
+    order: SortOrder,
+    max_docs: usize,
+    heap: BinaryHeap<SortedDocument>,
+}
+
+impl TopFieldCollector {
+    pub fn new(field: FieldId, order: SortOrder, max_docs: usize) -> TopFieldCollector {
+        TopFieldCollector {
+            field: field,
+            order: order,
+            max_docs: max_docs,
+            heap: BinaryHeap::with_capacity(max_docs + 1),
+        }
+    }
+
+    pub fn into_sorted_vec(self) -> Vec<DocumentMatch> {
+        let order = self.order;
+
+        self.heap.into_sorted_vec().iter()
+            .map(|sorted_document| {
+                let value = if sorted_document.missing {
+                    None
+                } else if order == SortOrder::Descending {
+                    Some(!sorted_document.key)
+                } else {
+                    Some(sorted_document.key)
+                };
+
+                DocumentMatch::new_unscored(sorted_document.id).with_sort_value(value)
+            })
+            .collect()
+    }
+}
+
+impl TopFieldCollector {
+    fn sort_key(&self, sort_value: Option<i64>) -> (bool, i64) {
+        // Inverting the bits reverses the order without overflowing
+        match (sort_value, self.order) {
+            (Some(value), SortOrder::Ascending) => (false, value),
+            (Some(value), SortOrder::Descending) => (false, !value),
+            (None, _) => (true, 0),
+        }
+    }
+}
+
+impl Collector for TopFieldCollector {
+    fn needs_score(&self) -> bool {
+        false
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        let (missing, key) = self.sort_key(doc.sort_value());
+
+        self.heap.push(SortedDocument {
+            missing: missing,
+            key: key,
+            id: doc.doc_id(),
+        });
+
+        // Now reduce the heap size if it's too big
+        if self.heap.len() > self.max_docs {
+            self.heap.pop();
+        }
+    }
+
+    fn sort_field(&self) -> Option<FieldId> {
+        Some(self.field)
+    }
+
+    fn sort_order(&self) -> Option<SortOrder> {
+        Some(self.order)
+    }
+
+    fn is_competitive_sort_value(&self, sort_value: Option<i64>) -> bool {
+        if self.heap.len() < self.max_docs {
+            return true;
+        }
+
+        // Documents with the same value as the worst one are ordered by id, so they may
+        // still be competitive
+        match self.heap.peek() {
+            Some(worst) => self.sort_key(sort_value) <= (worst.missing, worst.key),
+            None => true,
+        }
+    }
+}
+
+impl ParallelCollector for TopFieldCollector {
+    fn segment_collector(&self) -> TopFieldCollector {
+        TopFieldCollector::new(self.field, self.order, self.max_docs)
+    }
+
+    fn merge(&mut self, other: TopFieldCollector) {
+        for sorted_document in other.heap {
+            self.heap.push(sorted_document);
+
+            if self.heap.len() > self.max_docs {
+                self.heap.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use schema::FieldId;
+    use collectors::{Collector, ParallelCollector, DocumentMatch};
+    use super::{TopFieldCollector, SortOrder};
+
+    fn collect_docs(collector: &mut TopFieldCollector) {
+        collector.collect(DocumentMatch::new_unscored(0).with_sort_value(Some(20)));
+        collector.collect(DocumentMatch::new_unscored(1).with_sort_value(None));
+        collector.collect(DocumentMatch::new_unscored(2).with_sort_value(Some(-5)));
+        collector.collect(DocumentMatch::new_unscored(3).with_sort_value(Some(20)));
+        collector.collect(DocumentMatch::new_unscored(4).with_sort_value(Some(i64::max_value())));
+    }
+
+    #[test]
+    fn test_top_field_collector_sort_field() {
+        let collector = TopFieldCollector::new(FieldId(1), SortOrder::Ascending, 10);
+
+        assert_eq!(collector.needs_score(), false);
+        assert_eq!(collector.sort_field(), Some(FieldId(1)));
+    }
+
+    #[test]
+    fn test_top_field_collector_ascending() {
+        let mut collector = TopFieldCollector::new(FieldId(1), SortOrder::Ascending, 10);
+        collect_docs(&mut collector);
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![2, 0, 3, 4, 1]);
+        assert_eq!(docs.iter().map(|doc| doc.sort_value()).collect::<Vec<_>>(), vec![Some(-5), Some(20), Some(20), Some(i64::max_value()), None]);
+    }
+
+    #[test]
+    fn test_top_field_collector_descending() {
+        let mut collector = TopFieldCollector::new(FieldId(1), SortOrder::Descending, 10);
+        collect_docs(&mut collector);
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![4, 0, 3, 2, 1]);
+        assert_eq!(docs.iter().map(|doc| doc.sort_value()).collect::<Vec<_>>(), vec![Some(i64::max_value()), Some(20), Some(20), Some(-5), None]);
+    }
+
+    #[test]
+    fn test_top_field_collector_truncate() {
+        let mut collector = TopFieldCollector::new(FieldId(1), SortOrder::Ascending, 2);
+        collect_docs(&mut collector);
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![2, 0]);
+    }
+
+    #[test]
+    fn test_top_field_collector_is_competitive_sort_value() {
+        let mut collector = TopFieldCollector::new(FieldId(1), SortOrder::Descending, 2);
+        assert_eq!(collector.sort_order(), Some(SortOrder::Descending));
+
+        // Everything is competitive until the collector is full
+        collector.collect(DocumentMatch::new_unscored(0).with_sort_value(Some(10)));
+        assert!(collector.is_competitive_sort_value(None));
+
+        collector.collect(DocumentMatch::new_unscored(1).with_sort_value(Some(5)));
+        assert!(collector.is_competitive_sort_value(Some(6)));
+        assert!(collector.is_competitive_sort_value(Some(5)));
+        assert!(!collector.is_competitive_sort_value(Some(4)));
+        assert!(!collector.is_competitive_sort_value(None));
+    }
+
+    #[test]
+    fn test_top_field_collector_merge() {
+        let mut collector = TopFieldCollector::new(FieldId(1), SortOrder::Ascending, 3);
+        collector.collect(DocumentMatch::new_unscored(0).with_sort_value(Some(20)));
+        collector.collect(DocumentMatch::new_unscored(1).with_sort_value(None));
+
+        let mut segment_collector = collector.segment_collector();
+        assert_eq!(segment_collector.sort_field(), Some(FieldId(1)));
+        segment_collector.collect(DocumentMatch::new_unscored(2).with_sort_value(Some(-5)));
+        segment_collector.collect(DocumentMatch::new_unscored(3).with_sort_value(Some(20)));
+
+        collector.merge(segment_collector);
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![2, 0, 3]);
+    }
+}
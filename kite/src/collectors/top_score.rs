@@ -1,7 +1,7 @@
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 
-use collectors::{Collector, DocumentMatch};
+use collectors::{Collector, ParallelCollector, DocumentMatch, TrackTotalHits, TotalHits};
 
 /// An f32 that cannot be NaN.
 /// We need to order documents by score but NaN cannot be ordered, so we convert all scores into
@@ -49,6 +49,8 @@ impl PartialOrd for ScoredDocument {
 pub struct TopScoreCollector {
     max_docs: usize,
     heap: BinaryHeap<ScoredDocument>,
+    track_total_hits: TrackTotalHits,
+    total_hits: u64,
 }
 
 impl TopScoreCollector {
@@ -56,6 +58,39 @@ impl TopScoreCollector {
         TopScoreCollector {
             max_docs: max_docs,
             heap: BinaryHeap::with_capacity(max_docs + 1),
+            track_total_hits: TrackTotalHits::Disabled,
+            total_hits: 0,
+        }
+    }
+
+    /// Sets how accurately the total number of matching documents is counted
+    ///
+    /// By default, this is disabled so searches can skip documents that won't make it
+    /// into the top documents as soon as possible.
+    pub fn set_track_total_hits(&mut self, track_total_hits: TrackTotalHits) {
+        self.track_total_hits = track_total_hits;
+    }
+
+    /// Returns the number of documents that were counted before documents could be skipped
+    fn exact_total_hits_threshold(&self) -> u64 {
+        let threshold = match self.track_total_hits {
+            TrackTotalHits::Exact => return u64::max_value(),
+            TrackTotalHits::UpTo(threshold) => threshold,
+            TrackTotalHits::Disabled => 0,
+        };
+
+        // Documents can't be skipped until the heap is full
+        if threshold > self.max_docs as u64 { threshold } else { self.max_docs as u64 }
+    }
+
+    /// Returns the number of documents that matched the search
+    ///
+    /// This is exact unless the search may have skipped some documents.
+    pub fn total_hits(&self) -> TotalHits {
+        if self.total_hits < self.exact_total_hits_threshold() {
+            TotalHits::Exact(self.total_hits)
+        } else {
+            TotalHits::LowerBound(self.total_hits)
         }
     }
 
@@ -99,6 +134,8 @@ impl Collector for TopScoreCollector {
             }
         };
 
+        self.total_hits += 1;
+
         // Now insert the document into the heap
         self.heap.push(scored_document);
 
@@ -107,11 +144,42 @@ impl Collector for TopScoreCollector {
             self.heap.pop();
         }
     }
+
+    fn min_competitive_score(&self) -> Option<f32> {
+        if self.total_hits < self.exact_total_hits_threshold() {
+            // Every document must be seen until the heap is full and enough documents
+            // have been counted
+            return None;
+        }
+
+        // Scores are negated in the heap so the lowest score is at the top
+        self.heap.peek().map(|scored_document| -scored_document.score.0)
+    }
+}
+
+impl ParallelCollector for TopScoreCollector {
+    fn segment_collector(&self) -> TopScoreCollector {
+        let mut collector = TopScoreCollector::new(self.max_docs);
+        collector.set_track_total_hits(self.track_total_hits);
+        collector
+    }
+
+    fn merge(&mut self, other: TopScoreCollector) {
+        self.total_hits += other.total_hits;
+
+        for scored_document in other.heap {
+            self.heap.push(scored_document);
+
+            if self.heap.len() > self.max_docs {
+                self.heap.pop();
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use collectors::{Collector, DocumentMatch};
+    use collectors::{Collector, ParallelCollector, DocumentMatch, TrackTotalHits, TotalHits};
     use super::TopScoreCollector;
 
     #[test]
@@ -159,4 +227,79 @@ mod tests {
         assert_eq!(docs[0].id, 2);
         assert_eq!(docs[1].id, 0);
     }
+
+    #[test]
+    fn test_top_score_collector_min_competitive_score() {
+        let mut collector = TopScoreCollector::new(2);
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+        assert_eq!(collector.min_competitive_score(), None);
+
+        collector.collect(DocumentMatch::new_scored(1, 0.5f32));
+        assert_eq!(collector.min_competitive_score(), Some(0.5f32));
+
+        collector.collect(DocumentMatch::new_scored(2, 2.0f32));
+        assert_eq!(collector.min_competitive_score(), Some(1.0f32));
+    }
+
+    #[test]
+    fn test_top_score_collector_total_hits() {
+        let mut collector = TopScoreCollector::new(2);
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+        assert_eq!(collector.total_hits(), TotalHits::Exact(1));
+
+        // Once the heap is full, documents may be skipped
+        collector.collect(DocumentMatch::new_scored(1, 0.5f32));
+        assert_eq!(collector.total_hits(), TotalHits::LowerBound(2));
+    }
+
+    #[test]
+    fn test_top_score_collector_track_total_hits_up_to() {
+        let mut collector = TopScoreCollector::new(2);
+        collector.set_track_total_hits(TrackTotalHits::UpTo(3));
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+        collector.collect(DocumentMatch::new_scored(1, 0.5f32));
+        assert_eq!(collector.min_competitive_score(), None);
+        assert_eq!(collector.total_hits(), TotalHits::Exact(2));
+
+        collector.collect(DocumentMatch::new_scored(2, 2.0f32));
+        assert_eq!(collector.min_competitive_score(), Some(1.0f32));
+        assert_eq!(collector.total_hits(), TotalHits::LowerBound(3));
+    }
+
+    #[test]
+    fn test_top_score_collector_track_total_hits_exact() {
+        let mut collector = TopScoreCollector::new(2);
+        collector.set_track_total_hits(TrackTotalHits::Exact);
+
+        for i in 0..10 {
+            collector.collect(DocumentMatch::new_scored(i, i as f32));
+        }
+
+        assert_eq!(collector.min_competitive_score(), None);
+        assert_eq!(collector.total_hits(), TotalHits::Exact(10));
+    }
+
+    #[test]
+    fn test_top_score_collector_merge() {
+        let mut collector = TopScoreCollector::new(2);
+        collector.set_track_total_hits(TrackTotalHits::UpTo(5));
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+
+        let mut segment_collector = collector.segment_collector();
+        segment_collector.collect(DocumentMatch::new_scored(1, 0.5f32));
+        segment_collector.collect(DocumentMatch::new_scored(2, 2.0f32));
+        segment_collector.collect(DocumentMatch::new_scored(3, 1.5f32));
+
+        // The segment collector counts up to the same threshold
+        assert_eq!(segment_collector.min_competitive_score(), None);
+
+        collector.merge(segment_collector);
+        assert_eq!(collector.total_hits(), TotalHits::Exact(4));
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![2, 3]);
+    }
 }
@@ -1,4 +1,4 @@
-use collectors::{Collector, DocumentMatch};
+use collectors::{Collector, ParallelCollector, DocumentMatch};
 
 #[derive(Debug)]
 pub struct TotalCountCollector {
@@ -27,9 +27,19 @@ impl Collector for TotalCountCollector {
     }
 }
 
+impl ParallelCollector for TotalCountCollector {
+    fn segment_collector(&self) -> TotalCountCollector {
+        TotalCountCollector::new()
+    }
+
+    fn merge(&mut self, other: TotalCountCollector) {
+        self.total_count += other.total_count;
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use collectors::{Collector, DocumentMatch};
+    use collectors::{Collector, ParallelCollector, DocumentMatch};
     use super::TotalCountCollector;
 
     #[test]
@@ -56,4 +66,18 @@ mod tests {
 
         assert_eq!(collector.get_total_count(), 3);
     }
+
+    #[test]
+    fn test_total_count_collector_merge() {
+        let mut collector = TotalCountCollector::new();
+        collector.collect(DocumentMatch::new_unscored(0));
+
+        let mut segment_collector = collector.segment_collector();
+        assert_eq!(segment_collector.get_total_count(), 0);
+        segment_collector.collect(DocumentMatch::new_unscored(1));
+        segment_collector.collect(DocumentMatch::new_unscored(2));
+
+        collector.merge(segment_collector);
+        assert_eq!(collector.get_total_count(), 3);
+    }
 }
@@ -0,0 +1,383 @@
+//! Helpers for searching many independent indexes (shards) and combining their results
+//!
+//! Each shard is searched on its own and returns a ShardResult. These can be serialized to
+//! send them between processes and are then merged into a single list of hits.
+//!
+//! Scores use statistics (like the number of documents containing each term) that differ
+//! between shards, so the same document could score differently depending on which shard
+//! it's in. To make scores comparable, collect the CorpusStatistics of the query from every
+//! shard, merge them, and search each shard with the merged statistics.
+//!
+//! Shards must have the same schema, as field ids are compared between them.
+//!
+//! Documents are placed in shards by a ShardRouter, using their routing key. This is the
+//! document's key unless it has been given a routing key to keep related documents together.
+
+use std::cmp::Ordering;
+use std::hash::Hasher;
+
+use fnv::{FnvHashMap, FnvHasher};
+
+use term::Term;
+use document::Document;
+use schema::FieldId;
+use collectors::{DocumentMatch, TotalHits};
+use collectors::top_field::SortOrder;
+
+/// Decides which shard documents are placed in
+///
+/// The same routing key must always be placed in the same shard (for the same number of
+/// shards) so documents can be found again when they're updated or deleted. Implemented for
+/// closures that take the routing key and the number of shards.
+pub trait ShardRouter: Send + Sync {
+    /// Returns the shard for a routing key, from 0 to "num_shards" - 1
+    fn shard(&self, routing_key: &str, num_shards: u32) -> u32;
+
+    /// Returns the shard a document should be placed in
+    fn route(&self, doc: &Document, num_shards: u32) -> u32 {
+        self.shard(doc.routing_key(), num_shards)
+    }
+}
+
+impl<F> ShardRouter for F
+    where F: Fn(&str, u32) -> u32 + Send + Sync
+{
+    fn shard(&self, routing_key: &str, num_shards: u32) -> u32 {
+        self(routing_key, num_shards)
+    }
+}
+
+/// Spreads documents evenly across shards by hashing their routing key
+///
+/// The hash (FNV-1a) doesn't depend on the platform or process, so documents are placed in
+/// the same shard every time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashRouter;
+
+impl ShardRouter for HashRouter {
+    fn shard(&self, routing_key: &str, num_shards: u32) -> u32 {
+        let mut hasher = FnvHasher::default();
+        hasher.write(routing_key.as_bytes());
+        (hasher.finish() % num_shards as u64) as u32
+    }
+}
+
+/// A document found by a search of a shard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShardHit {
+    /// The id of the document in its shard
+    pub doc_id: u64,
+    pub score: Option<f32>,
+    pub sort_value: Option<i64>,
+}
+
+impl<'a> From<&'a DocumentMatch> for ShardHit {
+    fn from(doc: &'a DocumentMatch) -> ShardHit {
+        ShardHit {
+            doc_id: doc.doc_id(),
+            score: doc.score(),
+            sort_value: doc.sort_value(),
+        }
+    }
+}
+
+/// The top documents of a search of a single shard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShardResult {
+    pub shard: u32,
+    pub total_hits: u64,
+
+    /// False if "total_hits" is a lower bound
+    pub total_hits_exact: bool,
+
+    /// In the order they were collected in
+    pub hits: Vec<ShardHit>,
+}
+
+impl ShardResult {
+    pub fn new(shard: u32, total_hits: TotalHits, hits: &[DocumentMatch]) -> ShardResult {
+        ShardResult {
+            shard: shard,
+            total_hits: total_hits.value(),
+            total_hits_exact: total_hits.is_exact(),
+            hits: hits.iter().map(ShardHit::from).collect(),
+        }
+    }
+}
+
+/// A document in the merged results
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedHit {
+    pub shard: u32,
+    pub doc_id: u64,
+    pub score: Option<f32>,
+    pub sort_value: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedResult {
+    /// Exact only if every shard counted its hits exactly
+    pub total_hits: TotalHits,
+    pub hits: Vec<MergedHit>,
+}
+
+fn merge_results<F>(results: &[ShardResult], size: usize, compare: F) -> MergedResult
+    where F: Fn(&MergedHit, &MergedHit) -> Ordering
+{
+    let mut total_hits = 0;
+    let mut total_hits_exact = true;
+    let mut hits = Vec::new();
+
+    for result in results {
+        total_hits += result.total_hits;
+        total_hits_exact &= result.total_hits_exact;
+
+        for hit in result.hits.iter() {
+            hits.push(MergedHit {
+                shard: result.shard,
+                doc_id: hit.doc_id,
+                score: hit.score,
+                sort_value: hit.sort_value,
+            });
+        }
+    }
+
+    // Ties are broken by shard and document so the order doesn't depend on the order the
+    // shards responded in
+    hits.sort_by(|a, b| {
+        compare(a, b)
+            .then(a.shard.cmp(&b.shard))
+            .then(a.doc_id.cmp(&b.doc_id))
+    });
+    hits.truncate(size);
+
+    MergedResult {
+        total_hits: if total_hits_exact { TotalHits::Exact(total_hits) } else { TotalHits::LowerBound(total_hits) },
+        hits: hits,
+    }
+}
+
+/// Merges the results of shards that were collected by score, highest scores first
+pub fn merge_top_scores(results: &[ShardResult], size: usize) -> MergedResult {
+    merge_results(results, size, |a, b| {
+        let a_score = a.score.unwrap_or(0.0);
+        let b_score = b.score.unwrap_or(0.0);
+        b_score.partial_cmp(&a_score).unwrap_or(Ordering::Equal)
+    })
+}
+
+/// Merges the results of shards that were collected by a sort field
+///
+/// Like TopFieldCollector, documents without a value are put last.
+pub fn merge_top_fields(results: &[ShardResult], size: usize, order: SortOrder) -> MergedResult {
+    merge_results(results, size, |a, b| {
+        match (a.sort_value, b.sort_value) {
+            (Some(a_value), Some(b_value)) => {
+                match order {
+                    SortOrder::Ascending => a_value.cmp(&b_value),
+                    SortOrder::Descending => b_value.cmp(&a_value),
+                }
+            }
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    })
+}
+
+/// Merges counts of keys (such as facet paths) from many shards, largest counts first
+///
+/// Keys with the same count are ordered by key. If each shard only returned its top
+/// counts, a key that's just outside the top of some shards may be undercounted.
+pub fn merge_counts(shard_counts: &[Vec<(String, u64)>], size: usize) -> Vec<(String, u64)> {
+    let mut counts = FnvHashMap::default();
+    for shard in shard_counts {
+        for &(ref key, count) in shard {
+            *counts.entry(key.clone()).or_insert(0) += count;
+        }
+    }
+
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts.truncate(size);
+    counts
+}
+
+/// The statistics of a field used for scoring
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FieldCorpusStatistics {
+    pub total_docs: u64,
+    pub total_tokens: u64,
+
+    /// The number of documents containing each term, sorted by term
+    term_document_frequencies: Vec<(Vec<u8>, u64)>,
+}
+
+impl FieldCorpusStatistics {
+    pub fn term_document_frequency(&self, term: &Term) -> Option<u64> {
+        self.term_document_frequencies.binary_search_by(|&(ref other, _)| other[..].cmp(term.as_bytes()))
+            .ok()
+            .map(|position| self.term_document_frequencies[position].1)
+    }
+
+    pub fn terms(&self) -> Vec<(Term, u64)> {
+        self.term_document_frequencies.iter().map(|&(ref term, frequency)| (Term::from_bytes(term), frequency)).collect()
+    }
+
+    fn add_term_document_frequency(&mut self, term: &Term, frequency: u64) {
+        match self.term_document_frequencies.binary_search_by(|&(ref other, _)| other[..].cmp(term.as_bytes())) {
+            Ok(position) => self.term_document_frequencies[position].1 += frequency,
+            Err(position) => self.term_document_frequencies.insert(position, (term.as_bytes().to_vec(), frequency)),
+        }
+    }
+}
+
+/// The statistics that scores are calculated from, for the fields and terms of a query
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CorpusStatistics {
+    fields: FnvHashMap<FieldId, FieldCorpusStatistics>,
+}
+
+impl CorpusStatistics {
+    pub fn new() -> CorpusStatistics {
+        CorpusStatistics::default()
+    }
+
+    /// Adds to the number of documents and tokens in a field
+    pub fn add_field(&mut self, field: FieldId, total_docs: u64, total_tokens: u64) {
+        let field_stats = self.fields.entry(field).or_insert_with(FieldCorpusStatistics::default);
+        field_stats.total_docs += total_docs;
+        field_stats.total_tokens += total_tokens;
+    }
+
+    /// Adds to the number of documents in a field that contain a term
+    pub fn add_term(&mut self, field: FieldId, term: &Term, document_frequency: u64) {
+        let field_stats = self.fields.entry(field).or_insert_with(FieldCorpusStatistics::default);
+        field_stats.add_term_document_frequency(term, document_frequency);
+    }
+
+    /// Adds the statistics of another shard to these
+    pub fn merge(&mut self, other: &CorpusStatistics) {
+        for (field, other_field_stats) in other.fields.iter() {
+            self.add_field(*field, other_field_stats.total_docs, other_field_stats.total_tokens);
+
+            for &(ref term, frequency) in other_field_stats.term_document_frequencies.iter() {
+                self.add_term(*field, &Term::from_bytes(term), frequency);
+            }
+        }
+    }
+
+    pub fn field(&self, field: FieldId) -> Option<&FieldCorpusStatistics> {
+        self.fields.get(&field)
+    }
+
+    pub fn fields(&self) -> Vec<FieldId> {
+        self.fields.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use term::Term;
+    use schema::FieldId;
+    use collectors::TotalHits;
+    use collectors::top_field::SortOrder;
+
+    use fnv::FnvHashMap;
+
+    use document::Document;
+    use super::{ShardHit, ShardResult, CorpusStatistics, ShardRouter, HashRouter, merge_top_scores, merge_top_fields, merge_counts};
+
+    fn hit(doc_id: u64, score: Option<f32>, sort_value: Option<i64>) -> ShardHit {
+        ShardHit {
+            doc_id: doc_id,
+            score: score,
+            sort_value: sort_value,
+        }
+    }
+
+    #[test]
+    fn test_merge_top_scores() {
+        let results = vec![
+            ShardResult { shard: 1, total_hits: 10, total_hits_exact: true, hits: vec![hit(1, Some(2.0), None), hit(2, Some(1.0), None)] },
+            ShardResult { shard: 0, total_hits: 5, total_hits_exact: true, hits: vec![hit(7, Some(3.0), None), hit(8, Some(1.0), None)] },
+        ];
+
+        let merged = merge_top_scores(&results, 3);
+        assert_eq!(merged.total_hits, TotalHits::Exact(15));
+        assert_eq!(merged.hits.iter().map(|hit| (hit.shard, hit.doc_id)).collect::<Vec<_>>(), vec![(0, 7), (1, 1), (0, 8)]);
+    }
+
+    #[test]
+    fn test_merge_top_fields() {
+        let results = vec![
+            ShardResult { shard: 0, total_hits: 2, total_hits_exact: false, hits: vec![hit(1, None, Some(5)), hit(2, None, None)] },
+            ShardResult { shard: 1, total_hits: 2, total_hits_exact: true, hits: vec![hit(1, None, Some(3)), hit(3, None, Some(9))] },
+        ];
+
+        let merged = merge_top_fields(&results, 10, SortOrder::Descending);
+        assert_eq!(merged.total_hits, TotalHits::LowerBound(4));
+        assert_eq!(merged.hits.iter().map(|hit| (hit.shard, hit.doc_id)).collect::<Vec<_>>(), vec![(1, 3), (0, 1), (1, 1), (0, 2)]);
+
+        let merged = merge_top_fields(&results, 2, SortOrder::Ascending);
+        assert_eq!(merged.hits.iter().map(|hit| (hit.shard, hit.doc_id)).collect::<Vec<_>>(), vec![(1, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn test_merge_counts() {
+        let counts = merge_counts(&[
+            vec![("a".to_string(), 3), ("b".to_string(), 1)],
+            vec![("b".to_string(), 4), ("c".to_string(), 2)],
+        ], 2);
+
+        assert_eq!(counts, vec![("b".to_string(), 5), ("a".to_string(), 3)]);
+    }
+
+    #[test]
+    fn test_merge_corpus_statistics() {
+        let mut stats = CorpusStatistics::new();
+        stats.add_field(FieldId(1), 10, 100);
+        stats.add_term(FieldId(1), &Term::from_string("hello"), 2);
+
+        let mut other = CorpusStatistics::new();
+        other.add_field(FieldId(1), 5, 20);
+        other.add_term(FieldId(1), &Term::from_string("hello"), 1);
+        other.add_term(FieldId(1), &Term::from_string("world"), 4);
+        stats.merge(&other);
+
+        let field_stats = stats.field(FieldId(1)).unwrap();
+        assert_eq!(field_stats.total_docs, 15);
+        assert_eq!(field_stats.total_tokens, 120);
+        assert_eq!(field_stats.term_document_frequency(&Term::from_string("hello")), Some(3));
+        assert_eq!(field_stats.term_document_frequency(&Term::from_string("world")), Some(4));
+        assert_eq!(field_stats.term_document_frequency(&Term::from_string("missing")), None);
+        assert!(stats.field(FieldId(2)).is_none());
+    }
+
+    #[test]
+    fn test_hash_router() {
+        let doc = |key: &str, routing: Option<&str>| Document {
+            key: key.to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: routing.map(|routing| routing.to_string()),
+        };
+
+        let router = HashRouter;
+        assert_eq!(router.route(&doc("a", None), 16), router.shard("a", 16));
+        assert_eq!(router.route(&doc("a", Some("user1")), 16), router.route(&doc("b", Some("user1")), 16));
+        assert!((0..100).all(|i| router.shard(&i.to_string(), 3) < 3));
+
+        // Placement must never change between versions
+        assert_eq!(router.shard("hello", 1000), (0xa430d84680aabd0bu64 % 1000) as u32);
+    }
+
+    #[test]
+    fn test_closure_router() {
+        let router = |routing_key: &str, num_shards: u32| routing_key.len() as u32 % num_shards;
+        assert_eq!(router.shard("abc", 2), 1);
+    }
+}
@@ -0,0 +1,1185 @@
+use std::{fmt, cmp, io, u32};
+use std::io::{Read, Write};
+use std::iter::FromIterator;
+
+use roaring::RoaringBitmap;
+
+use postings::PostingsIterator;
+use bitmap_format::{self, BitmapFormat, BitmapDecodeError};
+use error::SegmentError;
+
+/// Scalar implementations of the set operations
+///
+/// These work a 64-bit word at a time.
+mod scalar {
+    #[inline]
+    pub fn union(a: &mut [u64], b: &[u64]) {
+        for (a, b) in a.iter_mut().zip(b.iter()) {
+            *a |= *b;
+        }
+    }
+
+    #[inline]
+    pub fn intersection(a: &mut [u64], b: &[u64]) {
+        for (a, b) in a.iter_mut().zip(b.iter()) {
+            *a &= *b;
+        }
+    }
+
+    #[inline]
+    pub fn difference(a: &mut [u64], b: &[u64]) {
+        for (a, b) in a.iter_mut().zip(b.iter()) {
+            *a &= !*b;
+        }
+    }
+}
+
+/// SIMD implementations of the set operations
+///
+/// These work 128 bits at a time using SSE2 (which every x86_64 processor supports) and fall
+/// back to the scalar implementations for any words left over.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd {
+    use std::arch::x86_64::*;
+
+    use super::scalar;
+
+    macro_rules! simd_op {
+        ($name:ident, $a:ident, $b:ident, $op:expr) => {
+            #[inline]
+            pub fn $name(a: &mut [u64], b: &[u64]) {
+                let len = if a.len() < b.len() { a.len() } else { b.len() };
+                let chunks = len / 2;
+
+                unsafe {
+                    for i in 0..chunks {
+                        let a_ptr = a.as_mut_ptr().add(i * 2) as *mut __m128i;
+                        let b_ptr = b.as_ptr().add(i * 2) as *const __m128i;
+
+                        let $a = _mm_loadu_si128(a_ptr);
+                        let $b = _mm_loadu_si128(b_ptr);
+                        _mm_storeu_si128(a_ptr, $op);
+                    }
+                }
+
+                scalar::$name(&mut a[chunks * 2..len], &b[chunks * 2..len]);
+            }
+        }
+    }
+
+    simd_op!(union, a, b, _mm_or_si128(a, b));
+    simd_op!(intersection, a, b, _mm_and_si128(a, b));
+    simd_op!(difference, a, b, _mm_andnot_si128(b, a));
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+use self::simd as ops;
+
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+use self::scalar as ops;
+
+#[derive(Clone)]
+enum Repr {
+    /// The document ids in increasing order
+    Array(Vec<u32>),
+
+    /// A bit for each possible document id, the words after the last one containing a
+    /// document are not stored
+    Bitmap(Vec<u64>),
+}
+
+/// Returns true if a bitmap holding documents up to "max_doc_id" would be smaller than an
+/// array of "len" documents
+#[inline]
+fn bitmap_is_smaller(len: usize, max_doc_id: u32) -> bool {
+    (max_doc_id as usize / 64 + 1) * 8 < len * 4
+}
+
+#[inline]
+fn set_bit(words: &mut Vec<u64>, doc_id: u32) {
+    let word = doc_id as usize / 64;
+    if word >= words.len() {
+        words.resize(word + 1, 0);
+    }
+
+    words[word] |= 1u64 << (doc_id % 64);
+}
+
+#[inline]
+fn get_bit(words: &[u64], doc_id: u32) -> bool {
+    match words.get(doc_id as usize / 64) {
+        Some(word) => word & (1u64 << (doc_id % 64)) != 0,
+        None => false,
+    }
+}
+
+/// Finds the position of the first document in a sorted array that isn't before "target"
+///
+/// This gallops (doubling the distance until it passes the target) before binary searching,
+/// so finding a document near the start of the array only looks at a few of its documents.
+fn gallop(doc_ids: &[u32], target: u32) -> usize {
+    let mut bound = 1;
+    while bound < doc_ids.len() && doc_ids[bound] < target {
+        bound *= 2;
+    }
+
+    let start = bound / 2;
+    let end = cmp::min(bound + 1, doc_ids.len());
+    start + match doc_ids[start..end].binary_search(&target) {
+        Ok(offset) | Err(offset) => offset,
+    }
+}
+
+/// Finds the documents of a sorted array that are also in a much larger one
+///
+/// Each document is galloped to from the previous one, so only a small part of the larger
+/// array is read when the smaller one is sparse.
+fn intersect_sorted(smaller: &[u32], larger: &[u32]) -> Vec<u32> {
+    let mut result = Vec::new();
+    let mut position = 0;
+
+    for doc_id in smaller {
+        position += gallop(&larger[position..], *doc_id);
+        match larger.get(position) {
+            Some(other) if other == doc_id => result.push(*doc_id),
+            Some(_) => {}
+            None => break,
+        }
+    }
+
+    result
+}
+
+/// Merges two sorted arrays of document ids
+fn union_sorted(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() && j < b.len() {
+        if a[i] < b[j] {
+            result.push(a[i]);
+            i += 1;
+        } else if b[j] < a[i] {
+            result.push(b[j]);
+            j += 1;
+        } else {
+            result.push(a[i]);
+            i += 1;
+            j += 1;
+        }
+    }
+
+    result.extend_from_slice(&a[i..]);
+    result.extend_from_slice(&b[j..]);
+    result
+}
+
+/// A set of documents in a segment
+///
+/// Sparse sets are stored as a sorted array of document ids and dense ones as a bitmap with a
+/// bit for each possible document id. Whichever is smaller is chosen as documents are added
+/// and after each set operation.
+///
+/// Operations between two bitmaps are performed a word at a time. If the "simd" feature is
+/// enabled, SIMD instructions are used where the target supports them.
+#[derive(Clone)]
+pub struct DocIdSet {
+    repr: Repr,
+}
+
+impl Default for DocIdSet {
+    fn default() -> DocIdSet {
+        DocIdSet::new()
+    }
+}
+
+impl DocIdSet {
+    pub fn new() -> DocIdSet {
+        DocIdSet {
+            repr: Repr::Array(Vec::new()),
+        }
+    }
+
+    /// Creates a set containing every document id below "len"
+    pub fn full(len: u32) -> DocIdSet {
+        let len = len as usize;
+        let mut words = vec![!0u64; len / 64];
+
+        if len % 64 != 0 {
+            words.push((1u64 << (len % 64)) - 1);
+        }
+
+        let mut doc_id_set = DocIdSet {
+            repr: Repr::Bitmap(words),
+        };
+        doc_id_set.optimize();
+        doc_id_set
+    }
+
+    /// Returns true if the set is currently stored as a bitmap
+    pub fn is_bitmap(&self) -> bool {
+        match self.repr {
+            Repr::Array(_) => false,
+            Repr::Bitmap(_) => true,
+        }
+    }
+
+    pub fn insert(&mut self, doc_id: u32) {
+        match self.repr {
+            Repr::Array(ref mut doc_ids) => {
+                // Documents are usually added in order, so check the end first
+                match doc_ids.last().cloned() {
+                    Some(last) if last >= doc_id => {
+                        if let Err(position) = doc_ids.binary_search(&doc_id) {
+                            doc_ids.insert(position, doc_id);
+                        }
+                        return;
+                    }
+                    _ => doc_ids.push(doc_id),
+                }
+            }
+            Repr::Bitmap(ref mut words) => {
+                if (doc_id as usize / 64) < words.len() {
+                    set_bit(words, doc_id);
+                    return;
+                }
+            }
+        }
+
+        // The set has grown, so it may be smaller in the other representation
+        let len = if self.is_bitmap() { self.len() + 1 } else { self.len() };
+        let use_bitmap = bitmap_is_smaller(len, doc_id);
+
+        if self.is_bitmap() && !use_bitmap {
+            let mut doc_ids = self.iter().collect::<Vec<_>>();
+            doc_ids.push(doc_id);
+            self.repr = Repr::Array(doc_ids);
+        } else if let Repr::Bitmap(ref mut words) = self.repr {
+            set_bit(words, doc_id);
+        } else if use_bitmap {
+            self.convert_to_bitmap();
+        }
+    }
+
+    pub fn contains(&self, doc_id: u32) -> bool {
+        match self.repr {
+            Repr::Array(ref doc_ids) => doc_ids.binary_search(&doc_id).is_ok(),
+            Repr::Bitmap(ref words) => get_bit(words, doc_id),
+        }
+    }
+
+    /// Returns the number of documents in the set
+    pub fn len(&self) -> usize {
+        match self.repr {
+            Repr::Array(ref doc_ids) => doc_ids.len(),
+            Repr::Bitmap(ref words) => words.iter().map(|word| word.count_ones() as usize).sum(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self.repr {
+            Repr::Array(ref doc_ids) => doc_ids.is_empty(),
+            Repr::Bitmap(ref words) => words.iter().all(|word| *word == 0),
+        }
+    }
+
+    /// Returns the number of bytes used to store the document ids, for sizing caches
+    pub fn heap_size(&self) -> usize {
+        match self.repr {
+            Repr::Array(ref doc_ids) => doc_ids.len() * 4,
+            Repr::Bitmap(ref words) => words.len() * 8,
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(&self.repr)
+    }
+
+    /// Iterates the documents that are in both sets, without building a new set
+    pub fn intersection<'a>(&'a self, other: &'a DocIdSet) -> Intersection<'a> {
+        Intersection {
+            a: self.iter(),
+            b: other.iter(),
+        }
+    }
+
+    /// Iterates the documents that are in either set, without building a new set
+    pub fn union<'a>(&'a self, other: &'a DocIdSet) -> Union<'a> {
+        let mut a = self.iter();
+        let mut b = other.iter();
+
+        Union {
+            next_a: a.next(),
+            next_b: b.next(),
+            a: a,
+            b: b,
+        }
+    }
+
+    /// Iterates the documents that are in this set but not in "other", without building a new set
+    pub fn difference<'a>(&'a self, other: &'a DocIdSet) -> Difference<'a> {
+        let mut b = other.iter();
+
+        Difference {
+            a: self.iter(),
+            next_b: b.next(),
+            b: b,
+        }
+    }
+
+    fn convert_to_bitmap(&mut self) {
+        let words = match self.repr {
+            Repr::Array(ref doc_ids) => {
+                let mut words = Vec::with_capacity(doc_ids.last().map(|last| *last as usize / 64 + 1).unwrap_or(0));
+                for doc_id in doc_ids {
+                    set_bit(&mut words, *doc_id);
+                }
+                words
+            }
+            Repr::Bitmap(_) => return,
+        };
+
+        self.repr = Repr::Bitmap(words);
+    }
+
+    /// Switches to whichever representation is smaller
+    fn optimize(&mut self) {
+        let len = self.len();
+        let max_doc_id = match self.repr {
+            Repr::Array(ref doc_ids) => doc_ids.last().cloned(),
+            Repr::Bitmap(ref mut words) => {
+                // Trim words that no longer contain any documents
+                while words.last() == Some(&0) {
+                    words.pop();
+                }
+
+                words.last().map(|last| (words.len() as u32 - 1) * 64 + 63 - last.leading_zeros())
+            }
+        };
+
+        let use_bitmap = max_doc_id.map(|max_doc_id| bitmap_is_smaller(len, max_doc_id)).unwrap_or(false);
+        if use_bitmap != self.is_bitmap() {
+            if use_bitmap {
+                self.convert_to_bitmap();
+            } else {
+                self.repr = Repr::Array(self.iter().collect());
+            }
+        }
+    }
+
+    /// Adds all documents in "other" to this set
+    pub fn union_with(&mut self, other: &DocIdSet) {
+        let repr = match (&mut self.repr, &other.repr) {
+            (&mut Repr::Bitmap(ref mut a), &Repr::Bitmap(ref b)) => {
+                if b.len() > a.len() {
+                    a.resize(b.len(), 0);
+                }
+
+                ops::union(a, b);
+                None
+            }
+            (&mut Repr::Bitmap(ref mut a), &Repr::Array(ref b)) => {
+                for doc_id in b {
+                    set_bit(a, *doc_id);
+                }
+                None
+            }
+            (&mut Repr::Array(ref a), &Repr::Bitmap(ref b)) => {
+                let mut words = b.clone();
+                for doc_id in a {
+                    set_bit(&mut words, *doc_id);
+                }
+                Some(Repr::Bitmap(words))
+            }
+            (&mut Repr::Array(ref a), &Repr::Array(ref b)) => Some(Repr::Array(union_sorted(a, b))),
+        };
+
+        if let Some(repr) = repr {
+            self.repr = repr;
+        }
+        self.optimize();
+    }
+
+    /// Removes all documents that are not in "other" from this set
+    pub fn intersect_with(&mut self, other: &DocIdSet) {
+        let repr = match (&mut self.repr, &other.repr) {
+            (&mut Repr::Bitmap(ref mut a), &Repr::Bitmap(ref b)) => {
+                a.truncate(b.len());
+
+                ops::intersection(a, b);
+                None
+            }
+            (&mut Repr::Bitmap(ref a), &Repr::Array(ref b)) => {
+                Some(Repr::Array(b.iter().cloned().filter(|doc_id| get_bit(a, *doc_id)).collect()))
+            }
+            (&mut Repr::Array(ref mut a), &Repr::Bitmap(ref b)) => {
+                a.retain(|doc_id| get_bit(b, *doc_id));
+                None
+            }
+            (&mut Repr::Array(ref a), &Repr::Array(ref b)) => {
+                // Look up the documents of the smaller array in the larger one
+                if a.len() <= b.len() {
+                    Some(Repr::Array(intersect_sorted(a, b)))
+                } else {
+                    Some(Repr::Array(intersect_sorted(b, a)))
+                }
+            }
+        };
+
+        if let Some(repr) = repr {
+            self.repr = repr;
+        }
+        self.optimize();
+    }
+
+    /// Removes all documents in "other" from this set
+    pub fn difference_with(&mut self, other: &DocIdSet) {
+        match (&mut self.repr, &other.repr) {
+            (&mut Repr::Bitmap(ref mut a), &Repr::Bitmap(ref b)) => {
+                ops::difference(a, b);
+            }
+            (&mut Repr::Bitmap(ref mut a), &Repr::Array(ref b)) => {
+                for doc_id in b {
+                    if let Some(word) = a.get_mut(*doc_id as usize / 64) {
+                        *word &= !(1u64 << (doc_id % 64));
+                    }
+                }
+            }
+            (&mut Repr::Array(ref mut a), _) => {
+                a.retain(|doc_id| !other.contains(*doc_id));
+            }
+        }
+
+        self.optimize();
+    }
+
+    /// Adds the documents of this set that appear in a postings list to "result"
+    ///
+    /// The postings iterator is advanced to each of the set's documents in turn, so a postings
+    /// list that's loaded lazily only loads the blocks that could contain them.
+    pub fn intersect_postings_into<P: PostingsIterator>(&self, postings: &mut P, result: &mut DocIdSet) -> Result<(), SegmentError> {
+        let mut iter = self.iter();
+        let mut next = iter.next();
+
+        while let Some(doc_id) = next {
+            match try!(postings.advance(doc_id)) {
+                Some(posting) if posting == doc_id => {
+                    result.insert(doc_id);
+                    next = iter.next();
+                }
+                Some(posting) => next = iter.advance(posting),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serializes the set in the format of "bitmap_format"
+    ///
+    /// Arrays are written in the delta format and bitmaps in the words format, unless the set is
+    /// smaller as runs. The set has no length prefix, it takes up the rest of the value it's
+    /// written into.
+    pub fn serialize_into<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    /// Deserializes a set written by "serialize_into", reading until the end of the reader
+    pub fn deserialize_from<R: Read>(mut reader: R) -> Result<DocIdSet, BitmapDecodeError> {
+        let mut bytes = Vec::new();
+        try!(reader.read_to_end(&mut bytes).map_err(BitmapDecodeError::Corrupt));
+        DocIdSet::from_bytes(&bytes)
+    }
+
+    /// Serializes the set into a new buffer, in the same format as "serialize_into"
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (format, size) = BitmapFormat::smallest_for_doc_ids(self.iter());
+        match self.repr {
+            Repr::Array(ref doc_ids) => bitmap_format::encode_doc_ids_as(doc_ids.iter().cloned(), format),
+            Repr::Bitmap(ref words) if size >= words.len() * 8 => bitmap_format::encode_words(words),
+            Repr::Bitmap(_) => bitmap_format::encode_doc_ids_as(self.iter(), format),
+        }
+    }
+
+    /// Deserializes a set written by "to_bytes", or in any other version of the format of
+    /// "bitmap_format"
+    ///
+    /// This includes term directories written by the storage backends.
+    pub fn from_bytes(bytes: &[u8]) -> Result<DocIdSet, BitmapDecodeError> {
+        let mut doc_ids = Vec::new();
+        try!(bitmap_format::decode_doc_ids(bytes, |doc_id| doc_ids.push(doc_id)));
+
+        let mut doc_id_set = DocIdSet {
+            repr: Repr::Array(doc_ids),
+        };
+        doc_id_set.optimize();
+        Ok(doc_id_set)
+    }
+}
+
+impl PartialEq for DocIdSet {
+    fn eq(&self, other: &DocIdSet) -> bool {
+        match (&self.repr, &other.repr) {
+            (&Repr::Array(ref a), &Repr::Array(ref b)) => a == b,
+            (&Repr::Bitmap(ref a), &Repr::Bitmap(ref b)) => {
+                // Missing words are zero so sets that only differ by trailing zero words are equal
+                let (shorter, longer) = if a.len() < b.len() { (a, b) } else { (b, a) };
+
+                longer[..shorter.len()] == shorter[..] && longer[shorter.len()..].iter().all(|word| *word == 0)
+            }
+            _ => self.iter().eq(other.iter()),
+        }
+    }
+}
+
+impl Eq for DocIdSet {}
+
+impl fmt::Debug for DocIdSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl FromIterator<u32> for DocIdSet {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> DocIdSet {
+        let mut doc_ids = iter.into_iter().collect::<Vec<_>>();
+        doc_ids.sort();
+        doc_ids.dedup();
+
+        let mut doc_id_set = DocIdSet {
+            repr: Repr::Array(doc_ids),
+        };
+        doc_id_set.optimize();
+        doc_id_set
+    }
+}
+
+impl<'a> From<&'a RoaringBitmap> for DocIdSet {
+    fn from(bitmap: &'a RoaringBitmap) -> DocIdSet {
+        bitmap.iter().collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a DocIdSet {
+    type Item = u32;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl IntoIterator for DocIdSet {
+    type Item = u32;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> IntoIter {
+        IntoIter {
+            cursor: Cursor::new(&self.repr),
+            repr: self.repr,
+        }
+    }
+}
+
+/// A position within a set, shared by the borrowing and owning iterators
+///
+/// For arrays, "position" is the index of the next document. For bitmaps, it's the index of
+/// the current word and "current_word" holds the bits of it that haven't been returned yet.
+struct Cursor {
+    position: usize,
+    current_word: u64,
+}
+
+impl Cursor {
+    fn new(repr: &Repr) -> Cursor {
+        Cursor {
+            position: 0,
+            current_word: match *repr {
+                Repr::Array(_) => 0,
+                Repr::Bitmap(ref words) => words.first().cloned().unwrap_or(0),
+            },
+        }
+    }
+
+    #[inline]
+    fn next(&mut self, repr: &Repr) -> Option<u32> {
+        match *repr {
+            Repr::Array(ref doc_ids) => {
+                let doc_id = doc_ids.get(self.position).cloned();
+                if doc_id.is_some() {
+                    self.position += 1;
+                }
+                doc_id
+            }
+            Repr::Bitmap(ref words) => {
+                while self.current_word == 0 {
+                    self.position += 1;
+
+                    match words.get(self.position) {
+                        Some(word) => self.current_word = *word,
+                        None => return None,
+                    }
+                }
+
+                let bit = self.current_word.trailing_zeros();
+
+                // Clear the lowest set bit
+                self.current_word &= self.current_word - 1;
+
+                Some(self.position as u32 * 64 + bit)
+            }
+        }
+    }
+
+    fn advance(&mut self, repr: &Repr, target: u32) -> Option<u32> {
+        match *repr {
+            Repr::Array(ref doc_ids) => {
+                let remaining = &doc_ids[cmp::min(self.position, doc_ids.len())..];
+                self.position += gallop(remaining, target);
+            }
+            Repr::Bitmap(ref words) => {
+                let word = target as usize / 64;
+
+                if word > self.position {
+                    self.position = word;
+                    self.current_word = match words.get(word) {
+                        Some(word) => *word,
+                        None => return None,
+                    };
+                }
+
+                if word == self.position {
+                    // Skip the documents in the word that are before the target
+                    self.current_word &= !0u64 << (target % 64);
+                }
+            }
+        }
+
+        self.next(repr)
+    }
+}
+
+/// Iterates the documents in a set in increasing order
+pub struct Iter<'a> {
+    repr: &'a Repr,
+    cursor: Cursor,
+}
+
+impl<'a> Iter<'a> {
+    fn new(repr: &'a Repr) -> Iter<'a> {
+        Iter {
+            repr: repr,
+            cursor: Cursor::new(repr),
+        }
+    }
+
+    /// Moves to the first document that is greater than or equal to "target" and returns it
+    ///
+    /// The iterator never moves backwards, documents that have already been returned are
+    /// skipped.
+    pub fn advance(&mut self, target: u32) -> Option<u32> {
+        self.cursor.advance(self.repr, target)
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        self.cursor.next(self.repr)
+    }
+}
+
+pub struct IntoIter {
+    repr: Repr,
+    cursor: Cursor,
+}
+
+impl IntoIter {
+    /// Moves to the first document that is greater than or equal to "target", see "Iter::advance"
+    pub fn advance(&mut self, target: u32) -> Option<u32> {
+        self.cursor.advance(&self.repr, target)
+    }
+}
+
+impl Iterator for IntoIter {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        self.cursor.next(&self.repr)
+    }
+}
+
+/// Iterates the documents that are in both of two sets, see "DocIdSet::intersection"
+///
+/// Each iterator is advanced to the other's document, so runs of documents that are only in
+/// one of the sets are skipped over.
+pub struct Intersection<'a> {
+    a: Iter<'a>,
+    b: Iter<'a>,
+}
+
+impl<'a> Iterator for Intersection<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let mut doc_id = match self.a.next() {
+            Some(doc_id) => doc_id,
+            None => return None,
+        };
+
+        loop {
+            let other = match self.b.advance(doc_id) {
+                Some(other) => other,
+                None => return None,
+            };
+
+            if other == doc_id {
+                return Some(doc_id);
+            }
+
+            doc_id = match self.a.advance(other) {
+                Some(doc_id) => doc_id,
+                None => return None,
+            };
+
+            if doc_id == other {
+                return Some(doc_id);
+            }
+        }
+    }
+}
+
+/// Iterates the documents that are in either of two sets, in increasing order
+pub struct Union<'a> {
+    a: Iter<'a>,
+    b: Iter<'a>,
+    next_a: Option<u32>,
+    next_b: Option<u32>,
+}
+
+impl<'a> Iterator for Union<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        match (self.next_a, self.next_b) {
+            (Some(a), Some(b)) if a < b => {
+                self.next_a = self.a.next();
+                Some(a)
+            }
+            (Some(a), Some(b)) if b < a => {
+                self.next_b = self.b.next();
+                Some(b)
+            }
+            (Some(a), Some(_)) => {
+                self.next_a = self.a.next();
+                self.next_b = self.b.next();
+                Some(a)
+            }
+            (Some(a), None) => {
+                self.next_a = self.a.next();
+                Some(a)
+            }
+            (None, Some(b)) => {
+                self.next_b = self.b.next();
+                Some(b)
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+/// Iterates the documents that are in one set but not another, in increasing order
+///
+/// The other set's iterator is advanced to each document, so it only reads the parts of
+/// the other set that could contain them.
+pub struct Difference<'a> {
+    a: Iter<'a>,
+    b: Iter<'a>,
+    next_b: Option<u32>,
+}
+
+impl<'a> Iterator for Difference<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        loop {
+            let doc_id = match self.a.next() {
+                Some(doc_id) => doc_id,
+                None => return None,
+            };
+
+            if self.next_b.map(|next_b| next_b < doc_id).unwrap_or(false) {
+                self.next_b = self.b.advance(doc_id);
+            }
+
+            if self.next_b != Some(doc_id) {
+                return Some(doc_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use roaring::RoaringBitmap;
+
+    use bitmap_format::{BitmapDecodeError, decode_roaring_bitmap};
+    use super::DocIdSet;
+    use postings::PostingsIterator;
+    use error::SegmentError;
+
+    struct VecPostings {
+        doc_ids: Vec<u32>,
+        position: usize,
+        started: bool,
+    }
+
+    impl PostingsIterator for VecPostings {
+        fn doc(&self) -> Option<u32> {
+            if self.started { self.doc_ids.get(self.position).cloned() } else { None }
+        }
+
+        fn next_doc(&mut self) -> Result<Option<u32>, SegmentError> {
+            if self.started {
+                self.position += 1;
+            }
+            self.started = true;
+            Ok(self.doc())
+        }
+
+        fn advance(&mut self, target: u32) -> Result<Option<u32>, SegmentError> {
+            self.started = true;
+            while self.doc_ids.get(self.position).map(|doc_id| *doc_id < target).unwrap_or(false) {
+                self.position += 1;
+            }
+            Ok(self.doc())
+        }
+    }
+
+    #[test]
+    fn test_insert_and_contains() {
+        let mut doc_id_set = DocIdSet::new();
+        doc_id_set.insert(1);
+        doc_id_set.insert(200);
+        doc_id_set.insert(65535);
+
+        assert!(doc_id_set.contains(1));
+        assert!(doc_id_set.contains(200));
+        assert!(doc_id_set.contains(65535));
+        assert!(!doc_id_set.contains(2));
+        assert_eq!(doc_id_set.len(), 3);
+    }
+
+    #[test]
+    fn test_iter() {
+        let doc_id_set = vec![0, 63, 64, 1000, 65535].into_iter().collect::<DocIdSet>();
+
+        assert_eq!(doc_id_set.iter().collect::<Vec<_>>(), vec![0, 63, 64, 1000, 65535]);
+        assert_eq!(doc_id_set.into_iter().collect::<Vec<_>>(), vec![0, 63, 64, 1000, 65535]);
+    }
+
+    #[test]
+    fn test_full() {
+        assert_eq!(DocIdSet::full(0).len(), 0);
+        assert_eq!(DocIdSet::full(10).iter().collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+        assert_eq!(DocIdSet::full(128).len(), 128);
+        assert_eq!(DocIdSet::full(65536).len(), 65536);
+    }
+
+    #[test]
+    fn test_from_roaring_bitmap() {
+        let bitmap = vec![1, 5, 300].into_iter().collect::<RoaringBitmap>();
+
+        assert_eq!(DocIdSet::from(&bitmap), vec![1, 5, 300].into_iter().collect());
+    }
+
+    #[test]
+    fn test_union() {
+        let mut a = vec![1, 2, 3].into_iter().collect::<DocIdSet>();
+        a.union_with(&vec![3, 4, 1000].into_iter().collect());
+
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![1, 2, 3, 4, 1000]);
+    }
+
+    #[test]
+    fn test_intersection() {
+        let mut a = vec![1, 2, 3, 1000].into_iter().collect::<DocIdSet>();
+        a.intersect_with(&vec![2, 3, 4].into_iter().collect());
+
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_intersection_of_different_sizes() {
+        let large = (0..1000).map(|i| i * 1000).collect::<DocIdSet>();
+        let small = vec![0, 5, 7000, 998000, 999000, 1000000].into_iter().collect::<DocIdSet>();
+        assert!(!large.is_bitmap());
+
+        let mut a = large.clone();
+        a.intersect_with(&small);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![0, 7000, 998000, 999000]);
+
+        let mut b = small.clone();
+        b.intersect_with(&large);
+        assert_eq!(b.iter().collect::<Vec<_>>(), vec![0, 7000, 998000, 999000]);
+    }
+
+    #[test]
+    fn test_gallop() {
+        let doc_ids = [2, 4, 6, 8, 10, 12, 14, 16, 18];
+
+        for target in 0..21 {
+            let expected = doc_ids.iter().position(|doc_id| *doc_id >= target).unwrap_or(doc_ids.len());
+            assert_eq!(super::gallop(&doc_ids, target), expected);
+        }
+        assert_eq!(super::gallop(&[], 5), 0);
+    }
+
+    #[test]
+    fn test_difference() {
+        let mut a = vec![1, 2, 3, 1000].into_iter().collect::<DocIdSet>();
+        a.difference_with(&vec![2, 3, 4].into_iter().collect());
+
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![1, 1000]);
+    }
+
+    #[test]
+    fn test_equality_ignores_trailing_words() {
+        let mut a = vec![1, 1000].into_iter().collect::<DocIdSet>();
+        a.intersect_with(&vec![1, 2].into_iter().collect());
+
+        assert_eq!(a, vec![1].into_iter().collect());
+        assert_eq!(a.len(), 1);
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[test]
+    fn test_simd_matches_scalar() {
+        use super::{simd, scalar};
+
+        // Odd length to check leftover words are handled
+        let a = (0..101).map(|i: u64| i.wrapping_mul(0x9e3779b97f4a7c15)).collect::<Vec<_>>();
+        let b = (0..101).map(|i: u64| i.wrapping_mul(0xc2b2ae3d27d4eb4f)).collect::<Vec<_>>();
+
+        let mut expected = a.clone();
+        let mut actual = a.clone();
+        scalar::union(&mut expected, &b);
+        simd::union(&mut actual, &b);
+        assert_eq!(actual, expected);
+
+        let mut expected = a.clone();
+        let mut actual = a.clone();
+        scalar::intersection(&mut expected, &b);
+        simd::intersection(&mut actual, &b);
+        assert_eq!(actual, expected);
+
+        let mut expected = a.clone();
+        let mut actual = a.clone();
+        scalar::difference(&mut expected, &b);
+        simd::difference(&mut actual, &b);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_representation() {
+        // Sparse sets are arrays
+        let mut doc_id_set = vec![1, 100000].into_iter().collect::<DocIdSet>();
+        assert!(!doc_id_set.is_bitmap());
+
+        // Dense sets are bitmaps
+        for doc_id in 0..1000 {
+            doc_id_set.insert(doc_id);
+        }
+        assert!(!doc_id_set.is_bitmap());
+        doc_id_set.difference_with(&vec![100000].into_iter().collect());
+        assert!(doc_id_set.is_bitmap());
+        assert_eq!(doc_id_set.len(), 1000);
+
+        // Adding a document far past the others switches back to an array
+        doc_id_set.insert(10000000);
+        assert!(!doc_id_set.is_bitmap());
+        assert_eq!(doc_id_set.len(), 1001);
+        assert!(doc_id_set.contains(10000000));
+
+        assert!(DocIdSet::full(1000).is_bitmap());
+        assert_eq!(DocIdSet::full(1000), (0..1000).collect());
+    }
+
+    #[test]
+    fn test_heap_size() {
+        assert_eq!(DocIdSet::new().heap_size(), 0);
+        assert_eq!(vec![1, 100000].into_iter().collect::<DocIdSet>().heap_size(), 8);
+        assert_eq!(DocIdSet::full(1000).heap_size(), 16 * 8);
+    }
+
+    #[test]
+    fn test_u32_doc_ids() {
+        let mut doc_id_set = DocIdSet::full(100000);
+        assert_eq!(doc_id_set.len(), 100000);
+        assert!(doc_id_set.contains(99999));
+        assert!(!doc_id_set.contains(100000));
+
+        doc_id_set.intersect_with(&vec![5, 70000, 4000000000].into_iter().collect());
+        assert_eq!(doc_id_set.iter().collect::<Vec<_>>(), vec![5, 70000]);
+    }
+
+    #[test]
+    fn test_mixed_representations() {
+        let dense = (0..2000).filter(|doc_id| doc_id % 3 != 0).collect::<DocIdSet>();
+        let sparse = vec![0, 1, 3, 1999, 50000].into_iter().collect::<DocIdSet>();
+        assert!(dense.is_bitmap());
+        assert!(!sparse.is_bitmap());
+
+        let mut a = dense.clone();
+        a.union_with(&sparse);
+        let mut b = sparse.clone();
+        b.union_with(&dense);
+        assert_eq!(a, b);
+        assert_eq!(a.len(), dense.len() + 3);
+
+        let mut a = dense.clone();
+        a.intersect_with(&sparse);
+        let mut b = sparse.clone();
+        b.intersect_with(&dense);
+        assert_eq!(a, b);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![1, 1999]);
+
+        let mut a = sparse.clone();
+        a.difference_with(&dense);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![0, 3, 50000]);
+
+        let mut a = dense.clone();
+        a.difference_with(&sparse);
+        assert_eq!(a.len(), dense.len() - 2);
+    }
+
+    #[test]
+    fn test_advance() {
+        for doc_id_set in [[3, 64, 65, 200, 1000].iter().cloned().collect::<DocIdSet>(), (0..1000).filter(|doc_id| doc_id % 2 == 0).collect()] {
+            let expected = doc_id_set.iter().collect::<Vec<_>>();
+            let mut iter = doc_id_set.iter();
+
+            let mut previous = None;
+            for target in [0, 64, 65, 66, 199, 998, 1001] {
+                // Documents that have already been returned are skipped
+                let next = expected.iter().cloned().find(|doc_id| *doc_id >= target && previous.map(|previous| *doc_id > previous).unwrap_or(true));
+                assert_eq!(iter.advance(target), next, "{:?} advance({})", doc_id_set.is_bitmap(), target);
+                previous = next;
+            }
+
+            // Never moves backwards
+            let mut iter = doc_id_set.iter();
+            assert_eq!(iter.advance(200), Some(200));
+            assert_eq!(iter.advance(10), expected.iter().cloned().find(|doc_id| *doc_id > 200));
+
+            let mut iter = doc_id_set.clone().into_iter();
+            assert_eq!(iter.advance(100), expected.iter().cloned().find(|doc_id| *doc_id >= 100));
+        }
+    }
+
+    #[test]
+    fn test_intersection_iterator() {
+        let a = (0..1000).filter(|doc_id| doc_id % 2 == 0).collect::<DocIdSet>();
+        let b = vec![1, 2, 3, 4, 500, 999, 5000].into_iter().collect::<DocIdSet>();
+
+        assert_eq!(a.intersection(&b).collect::<Vec<_>>(), vec![2, 4, 500]);
+        assert_eq!(b.intersection(&a).collect::<Vec<_>>(), vec![2, 4, 500]);
+        assert_eq!(a.intersection(&DocIdSet::new()).next(), None);
+    }
+
+    #[test]
+    fn test_union_iterator() {
+        let a = (0..1000).filter(|doc_id| doc_id % 2 == 0).collect::<DocIdSet>();
+        let b = vec![1, 2, 3, 4, 500, 999, 5000].into_iter().collect::<DocIdSet>();
+
+        let mut expected = a.clone();
+        expected.union_with(&b);
+        assert_eq!(a.union(&b).collect::<Vec<_>>(), expected.iter().collect::<Vec<_>>());
+        assert_eq!(b.union(&a).collect::<Vec<_>>(), expected.iter().collect::<Vec<_>>());
+        assert_eq!(b.union(&DocIdSet::new()).collect::<Vec<_>>(), vec![1, 2, 3, 4, 500, 999, 5000]);
+    }
+
+    #[test]
+    fn test_difference_iterator() {
+        let a = (0..1000).filter(|doc_id| doc_id % 2 == 0).collect::<DocIdSet>();
+        let b = vec![1, 2, 3, 4, 500, 999, 5000].into_iter().collect::<DocIdSet>();
+
+        let mut expected = a.clone();
+        expected.difference_with(&b);
+        assert_eq!(a.difference(&b).collect::<Vec<_>>(), expected.iter().collect::<Vec<_>>());
+        assert_eq!(b.difference(&a).collect::<Vec<_>>(), vec![1, 3, 999, 5000]);
+        assert_eq!(b.difference(&DocIdSet::new()).collect::<Vec<_>>(), vec![1, 2, 3, 4, 500, 999, 5000]);
+    }
+
+    #[test]
+    fn test_intersect_postings_into() {
+        let doc_id_set = vec![1, 5, 7, 300, 70000].into_iter().collect::<DocIdSet>();
+        let mut postings = VecPostings { doc_ids: vec![0, 5, 6, 7, 299, 300, 301], position: 0, started: false };
+
+        let mut result = vec![2].into_iter().collect::<DocIdSet>();
+        doc_id_set.intersect_postings_into(&mut postings, &mut result).unwrap();
+        assert_eq!(result.iter().collect::<Vec<_>>(), vec![2, 5, 7, 300]);
+    }
+
+    #[test]
+    fn test_serialization() {
+        let sets = vec![
+            DocIdSet::new(),
+            vec![0].into_iter().collect::<DocIdSet>(),
+            vec![1, 2, 200, 100000, 4294967295].into_iter().collect(),
+            DocIdSet::full(1000),
+            (0..5000).filter(|doc_id| doc_id % 5 != 0).collect(),
+        ];
+
+        for doc_id_set in sets {
+            let bytes = doc_id_set.to_bytes();
+
+            let decoded = DocIdSet::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, doc_id_set);
+            assert_eq!(decoded.is_bitmap(), doc_id_set.is_bitmap());
+
+            // The storage backends can read the set too
+            assert_eq!(DocIdSet::from(&decode_roaring_bitmap(&bytes).unwrap()), doc_id_set);
+        }
+
+        // Gaps are varint encoded
+        assert_eq!(vec![1, 2, 200].into_iter().collect::<DocIdSet>().to_bytes(), vec![0xFF, 0xFF, 2, 1, 0, 0xc5, 0x01]);
+
+        // Bitmaps are written as words, unless they're smaller as runs
+        assert_eq!(DocIdSet::full(1000).to_bytes(), vec![0xFF, 0xFF, 3, 0, 0xe7, 0x07]);
+        let doc_id_set = (0..5000).filter(|doc_id| doc_id % 5 != 0).collect::<DocIdSet>();
+        assert!(doc_id_set.is_bitmap());
+        assert_eq!(doc_id_set.to_bytes()[2], 4);
+
+        // Sets written by the storage backends can be read, including plain roaring bitmaps
+        let bitmap = (0..5000).filter(|doc_id| doc_id % 7 != 0).collect::<RoaringBitmap>();
+        let mut bytes = Vec::new();
+        bitmap.serialize_into(&mut bytes).unwrap();
+        assert_eq!(DocIdSet::from_bytes(&bytes).unwrap(), DocIdSet::from(&bitmap));
+
+        match DocIdSet::from_bytes(&[0xFF, 0xFF, 5]) {
+            Err(BitmapDecodeError::UnsupportedVersion(5)) => {}
+            result => panic!("expected an unsupported version error, got {:?}", result),
+        }
+        assert!(DocIdSet::from_bytes(&[0xFF, 0xFF]).is_err());
+        assert!(DocIdSet::from_bytes(&[0xFF, 0xFF, 2, 0x80]).is_err());
+        assert!(DocIdSet::from_bytes(&[0xFF, 0xFF, 4, 1, 2, 3]).is_err());
+        assert!(DocIdSet::from_bytes(&[0xFF, 0xFF, 2, 0xff, 0xff, 0xff, 0xff, 0x0f, 0]).is_err());
+    }
+
+    #[test]
+    fn test_serialize_into() {
+        let doc_id_set = vec![1, 2, 200, 100000].into_iter().collect::<DocIdSet>();
+
+        let mut bytes = vec![0xff];
+        doc_id_set.serialize_into(&mut bytes).unwrap();
+        assert_eq!(&bytes[1..], &doc_id_set.to_bytes()[..]);
+
+        assert_eq!(DocIdSet::deserialize_from(&bytes[1..]).unwrap(), doc_id_set);
+        match DocIdSet::deserialize_from(&bytes[..0]) {
+            Err(BitmapDecodeError::Corrupt(_)) => {}
+            result => panic!("expected a corrupt error, got {:?}", result),
+        }
+    }
+}
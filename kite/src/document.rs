@@ -1,3 +1,5 @@
+use std::ops::{Deref, DerefMut};
+
 use chrono::{DateTime, Utc, Timelike};
 use byteorder::{WriteBytesExt, LittleEndian};
 use fnv::FnvHashMap;
@@ -6,18 +8,19 @@ use term_vector::TermVector;
 use schema::FieldId;
 use segment::SegmentId;
 
+/// The id of a document, its segment and its ord within the segment
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
-pub struct DocId(pub SegmentId, pub u16);
+pub struct DocId(pub SegmentId, pub u32);
 
 impl DocId {
     pub fn as_u64(&self) -> u64 {
-        ((self.0).0 as u64) << 16 | (self.1 as u64)
+        ((self.0).0 as u64) << 32 | (self.1 as u64)
     }
 
     pub fn from_u64(val: u64) -> DocId {
-        let segment = (val >> 16) & 0xFFFFFFFF;
-        let local_id = val & 0xFFFF;
-        DocId(SegmentId(segment as u32), local_id as u16)
+        let segment = val >> 32;
+        let local_id = val & 0xFFFFFFFF;
+        DocId(SegmentId(segment as u32), local_id as u32)
     }
 }
 
@@ -63,6 +66,31 @@ impl FieldValue {
             }
         }
     }
+
+    /// Converts the value into an integer that sorts in the same order as the value
+    ///
+    /// This is used for building doc values columns. Strings can't be converted so None is
+    /// returned for them.
+    pub fn to_doc_value(&self) -> Option<i64> {
+        match *self {
+            FieldValue::String(_) => None,
+            FieldValue::Integer(value) => Some(value),
+            FieldValue::Boolean(value) => Some(value as i64),
+            FieldValue::DateTime(value) => Some(value.timestamp() * 1000000 + (value.nanosecond() / 1000) as i64),
+        }
+    }
+}
+
+/// An input for the completion suggester
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub input: String,
+
+    /// Completions with higher weights are suggested first
+    pub weight: u32,
+
+    /// Returned with the completion when it's suggested
+    pub payload: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
@@ -70,4 +98,148 @@ pub struct Document {
     pub key: String,
     pub indexed_fields: FnvHashMap<FieldId, TermVector>,
     pub stored_fields: FnvHashMap<FieldId, FieldValue>,
+
+    /// Positive weights for the document's rank feature fields
+    pub rank_features: FnvHashMap<FieldId, f32>,
+
+    /// Inputs for the document's completion fields
+    pub completions: FnvHashMap<FieldId, Vec<Completion>>,
+
+    /// The original document (such as the JSON it was built from)
+    ///
+    /// This is stored as a single value so the whole document can be returned in results
+    /// without reconstructing it from stored fields.
+    pub source: Option<Vec<u8>>,
+
+    /// Decides which shard the document is placed in, the key is used if this isn't set
+    ///
+    /// Documents with the same routing key are always placed in the same shard. This is
+    /// stored with the document so it can be found again when it's updated or deleted.
+    pub routing: Option<String>,
+}
+
+impl Document {
+    /// Returns the key that decides which shard the document is placed in
+    pub fn routing_key(&self) -> &str {
+        self.routing.as_ref().unwrap_or(&self.key)
+    }
+}
+
+/// A document that has been read back from an index
+///
+/// Only the stored fields, source and routing key can be read back, the other fields of the
+/// document are left empty. The accessors return None if the document doesn't have a value
+/// for the field or if its value is of a different type.
+#[derive(Debug, Clone)]
+pub struct StoredDocument {
+    document: Document,
+}
+
+impl StoredDocument {
+    pub fn new(document: Document) -> StoredDocument {
+        StoredDocument {
+            document: document,
+        }
+    }
+
+    pub fn into_document(self) -> Document {
+        self.document
+    }
+
+    pub fn get(&self, field_id: FieldId) -> Option<&FieldValue> {
+        self.document.stored_fields.get(&field_id)
+    }
+
+    pub fn get_str(&self, field_id: FieldId) -> Option<&str> {
+        match self.get(field_id) {
+            Some(&FieldValue::String(ref value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_i64(&self, field_id: FieldId) -> Option<i64> {
+        match self.get(field_id) {
+            Some(&FieldValue::Integer(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_bool(&self, field_id: FieldId) -> Option<bool> {
+        match self.get(field_id) {
+            Some(&FieldValue::Boolean(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_datetime(&self, field_id: FieldId) -> Option<DateTime<Utc>> {
+        match self.get(field_id) {
+            Some(&FieldValue::DateTime(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl Deref for StoredDocument {
+    type Target = Document;
+
+    fn deref(&self) -> &Document {
+        &self.document
+    }
+}
+
+impl DerefMut for StoredDocument {
+    fn deref_mut(&mut self) -> &mut Document {
+        &mut self.document
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use fnv::FnvHashMap;
+
+    use schema::FieldId;
+    use segment::SegmentId;
+    use super::{DocId, Document, FieldValue, StoredDocument};
+
+    #[test]
+    fn test_doc_id_u64() {
+        for doc_id in vec![DocId(SegmentId(0), 0), DocId(SegmentId(1), 65536), DocId(SegmentId(u32::max_value()), u32::max_value())] {
+            assert_eq!(DocId::from_u64(doc_id.as_u64()), doc_id);
+        }
+
+        // Documents sort by segment then ord
+        assert!(DocId(SegmentId(1), 0).as_u64() > DocId(SegmentId(0), 100000).as_u64());
+    }
+
+    #[test]
+    fn test_stored_document_accessors() {
+        let published = Utc.ymd(2016, 5, 1).and_hms(12, 0, 0);
+        let mut stored_fields = FnvHashMap::default();
+        stored_fields.insert(FieldId(1), FieldValue::String("Hello".to_string()));
+        stored_fields.insert(FieldId(2), FieldValue::Integer(42));
+        stored_fields.insert(FieldId(3), FieldValue::Boolean(true));
+        stored_fields.insert(FieldId(4), FieldValue::DateTime(published));
+
+        let doc = StoredDocument::new(Document {
+            key: "doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        });
+
+        assert_eq!(doc.key, "doc");
+        assert_eq!(doc.get_str(FieldId(1)), Some("Hello"));
+        assert_eq!(doc.get_i64(FieldId(2)), Some(42));
+        assert_eq!(doc.get_bool(FieldId(3)), Some(true));
+        assert_eq!(doc.get_datetime(FieldId(4)), Some(published));
+
+        // Values of other types and missing values aren't returned
+        assert_eq!(doc.get_i64(FieldId(1)), None);
+        assert_eq!(doc.get_str(FieldId(2)), None);
+        assert_eq!(doc.get_str(FieldId(5)), None);
+    }
 }
@@ -0,0 +1,428 @@
+//! A fluent API for building documents from values, according to the schema
+//!
+//! Each value is indexed and/or stored depending on the flags of its field, and text is split
+//! into terms with an analyzer. Fields can only hold one stored value so, if a field is given
+//! more than one, the last one is stored (every value is indexed).
+
+use std::fmt;
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+use roaring::RoaringBitmap;
+use fnv::FnvHashMap;
+
+use term::Term;
+use token::Token;
+use term_vector::TermVector;
+use schema::{Schema, FieldId, FieldType, FieldFlags, FIELD_INDEXED, FIELD_STORED};
+use document::{Document, FieldValue};
+use facet::FacetPath;
+use field::{Field, FieldKind, Text, PlainString, Join, Facet, RankFeature};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DocumentBuildError {
+    /// The field isn't in the schema
+    FieldDoesNotExist(FieldId),
+
+    /// The value can't be put in a field of this type
+    WrongFieldType(FieldId, FieldType),
+}
+
+impl fmt::Display for DocumentBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DocumentBuildError::FieldDoesNotExist(field) => write!(f, "field {} doesn't exist", field.0),
+            DocumentBuildError::WrongFieldType(field, ref field_type) => write!(f, "field {} has type {:?}, which can't hold this value", field.0, field_type),
+        }
+    }
+}
+
+impl Error for DocumentBuildError {}
+
+/// Splits text into lowercase words, separated by anything that isn't a letter or number
+///
+/// This is used for text fields unless the builder is given another analyzer.
+pub fn simple_analyzer(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut word_start = None;
+
+    for (index, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+        match (c.is_alphanumeric(), word_start) {
+            (true, None) => word_start = Some(index),
+            (false, Some(start)) => {
+                tokens.push(Token {
+                    term: Term::from_string(&text[start..index].to_lowercase()),
+                    position: tokens.len() as u32 + 1,
+                    offsets: Some((start as u32, index as u32)),
+                });
+                word_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    tokens
+}
+
+/// Values that can be added to fields of type T, see "DocumentBuilder::add"
+pub trait DocumentValue<T: FieldKind> {
+    fn add_to<'a>(self, builder: DocumentBuilder<'a>, field: FieldId) -> DocumentBuilder<'a>;
+}
+
+impl<'s> DocumentValue<Text> for &'s str {
+    fn add_to<'a>(self, builder: DocumentBuilder<'a>, field: FieldId) -> DocumentBuilder<'a> {
+        builder.text(field, self)
+    }
+}
+
+impl<'s> DocumentValue<PlainString> for &'s str {
+    fn add_to<'a>(self, builder: DocumentBuilder<'a>, field: FieldId) -> DocumentBuilder<'a> {
+        builder.string(field, self)
+    }
+}
+
+impl<'s> DocumentValue<Join> for &'s str {
+    fn add_to<'a>(self, builder: DocumentBuilder<'a>, field: FieldId) -> DocumentBuilder<'a> {
+        builder.string(field, self)
+    }
+}
+
+impl DocumentValue<i64> for i64 {
+    fn add_to<'a>(self, builder: DocumentBuilder<'a>, field: FieldId) -> DocumentBuilder<'a> {
+        builder.integer(field, self)
+    }
+}
+
+impl DocumentValue<bool> for bool {
+    fn add_to<'a>(self, builder: DocumentBuilder<'a>, field: FieldId) -> DocumentBuilder<'a> {
+        builder.boolean(field, self)
+    }
+}
+
+impl DocumentValue<DateTime<Utc>> for DateTime<Utc> {
+    fn add_to<'a>(self, builder: DocumentBuilder<'a>, field: FieldId) -> DocumentBuilder<'a> {
+        builder.datetime(field, self)
+    }
+}
+
+impl DocumentValue<Facet> for FacetPath {
+    fn add_to<'a>(self, builder: DocumentBuilder<'a>, field: FieldId) -> DocumentBuilder<'a> {
+        builder.facet(field, self)
+    }
+}
+
+impl DocumentValue<RankFeature> for f32 {
+    fn add_to<'a>(self, builder: DocumentBuilder<'a>, field: FieldId) -> DocumentBuilder<'a> {
+        builder.rank_feature(field, self)
+    }
+}
+
+pub struct DocumentBuilder<'a> {
+    schema: &'a Schema,
+    analyzer: &'a dyn Fn(&str) -> Vec<Token>,
+    doc: Document,
+
+    /// The last position that was used in each field, so values added later are positioned after it
+    last_positions: FnvHashMap<FieldId, u32>,
+
+    /// The length of the text that's been added to each field, so the offsets of values added
+    /// later are after it. Values are treated as if they were separated by a single character
+    text_lengths: FnvHashMap<FieldId, u32>,
+    facets: FnvHashMap<FieldId, Vec<FacetPath>>,
+
+    /// The first error, this is returned by "build"
+    error: Option<DocumentBuildError>,
+}
+
+impl<'a> DocumentBuilder<'a> {
+    pub fn new(schema: &'a Schema, key: &str) -> DocumentBuilder<'a> {
+        DocumentBuilder {
+            schema: schema,
+            analyzer: &simple_analyzer,
+            doc: Document {
+                key: key.to_string(),
+                indexed_fields: FnvHashMap::default(),
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            },
+            last_positions: FnvHashMap::default(),
+            text_lengths: FnvHashMap::default(),
+            facets: FnvHashMap::default(),
+            error: None,
+        }
+    }
+
+    /// Sets the analyzer for the text fields that are added after this
+    pub fn analyzer(mut self, analyzer: &'a dyn Fn(&str) -> Vec<Token>) -> DocumentBuilder<'a> {
+        self.analyzer = analyzer;
+        self
+    }
+
+    /// Returns the flags of a field, or records an error if the value can't be put in it
+    fn check_field(&mut self, field: FieldId, field_types: &[FieldType]) -> Option<FieldFlags> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        match self.schema.get(&field) {
+            Some(field_info) => {
+                if field_types.contains(&field_info.field_type) {
+                    Some(field_info.field_flags)
+                } else {
+                    self.error = Some(DocumentBuildError::WrongFieldType(field, field_info.field_type.clone()));
+                    None
+                }
+            }
+            None => {
+                self.error = Some(DocumentBuildError::FieldDoesNotExist(field));
+                None
+            }
+        }
+    }
+
+    fn index_tokens(&mut self, field: FieldId, tokens: Vec<Token>) {
+        let offset = self.last_positions.get(&field).cloned().unwrap_or(0);
+        let mut last_position = offset;
+
+        let term_vector = self.doc.indexed_fields.entry(field).or_insert_with(TermVector::new);
+        for token in tokens {
+            let position = offset + token.position;
+            if let Some(offsets) = token.offsets {
+                term_vector.offsets.insert(position, offsets);
+            }
+            term_vector.entry(token.term).or_insert_with(RoaringBitmap::new).insert(position);
+
+            if position > last_position {
+                last_position = position;
+            }
+        }
+
+        self.last_positions.insert(field, last_position);
+    }
+
+    /// Adds a value that's indexed as a single term
+    fn add_value(mut self, field: FieldId, field_types: &[FieldType], term: Term, value: FieldValue) -> DocumentBuilder<'a> {
+        if let Some(flags) = self.check_field(field, field_types) {
+            if flags.contains(FIELD_INDEXED) {
+                self.index_tokens(field, vec![Token { term: term, position: 1, offsets: None }]);
+            }
+
+            if flags.contains(FIELD_STORED) {
+                self.doc.stored_fields.insert(field, value);
+            }
+        }
+
+        self
+    }
+
+    /// Adds text to a text field, this is split into terms by the analyzer
+    pub fn text(mut self, field: FieldId, text: &str) -> DocumentBuilder<'a> {
+        if let Some(flags) = self.check_field(field, &[FieldType::Text]) {
+            if flags.contains(FIELD_INDEXED) {
+                let mut tokens = (self.analyzer)(text);
+
+                // Offset the tokens so they're after the text that was added before
+                let text_offset = self.text_lengths.get(&field).map_or(0, |text_length| text_length + 1);
+                for token in tokens.iter_mut() {
+                    token.offsets = token.offsets.map(|(start, end)| (start + text_offset, end + text_offset));
+                }
+                self.text_lengths.insert(field, text_offset + text.len() as u32);
+
+                self.index_tokens(field, tokens);
+            }
+
+            if flags.contains(FIELD_STORED) {
+                self.doc.stored_fields.insert(field, FieldValue::String(text.to_string()));
+            }
+        }
+
+        self
+    }
+
+    /// Adds a string that's indexed without analysis, such as an identifier
+    ///
+    /// This is also used to set the parent of a document in a join field.
+    pub fn string(self, field: FieldId, value: &str) -> DocumentBuilder<'a> {
+        self.add_value(field, &[FieldType::PlainString, FieldType::Join], Term::from_string(value), FieldValue::String(value.to_string()))
+    }
+
+    pub fn integer(self, field: FieldId, value: i64) -> DocumentBuilder<'a> {
+        self.add_value(field, &[FieldType::I64], Term::from_integer(value), FieldValue::Integer(value))
+    }
+
+    pub fn boolean(self, field: FieldId, value: bool) -> DocumentBuilder<'a> {
+        self.add_value(field, &[FieldType::Boolean], Term::from_boolean(value), FieldValue::Boolean(value))
+    }
+
+    pub fn datetime(self, field: FieldId, value: DateTime<Utc>) -> DocumentBuilder<'a> {
+        self.add_value(field, &[FieldType::DateTime], Term::from_datetime(&value), FieldValue::DateTime(value))
+    }
+
+    /// Adds a path to a facet field, the document is also indexed in all of its ancestors
+    pub fn facet(mut self, field: FieldId, path: FacetPath) -> DocumentBuilder<'a> {
+        if let Some(flags) = self.check_field(field, &[FieldType::Facet]) {
+            if flags.contains(FIELD_STORED) {
+                self.doc.stored_fields.insert(field, FieldValue::String(path.to_string()));
+            }
+
+            if flags.contains(FIELD_INDEXED) {
+                self.facets.entry(field).or_insert_with(Vec::new).push(path);
+            }
+        }
+
+        self
+    }
+
+    pub fn rank_feature(mut self, field: FieldId, value: f32) -> DocumentBuilder<'a> {
+        if self.check_field(field, &[FieldType::RankFeature]).is_some() {
+            self.doc.rank_features.insert(field, value);
+        }
+
+        self
+    }
+
+    /// Adds a value through a typed field handle, values of the wrong type don't compile
+    pub fn add<T: FieldKind, V: DocumentValue<T>>(self, field: Field<T>, value: V) -> DocumentBuilder<'a> {
+        value.add_to(self, field.id())
+    }
+
+    pub fn source(mut self, source: Vec<u8>) -> DocumentBuilder<'a> {
+        self.doc.source = Some(source);
+        self
+    }
+
+    pub fn routing(mut self, routing: &str) -> DocumentBuilder<'a> {
+        self.doc.routing = Some(routing.to_string());
+        self
+    }
+
+    /// Returns the document, or the first error if any value couldn't be added
+    pub fn build(mut self) -> Result<Document, DocumentBuildError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        for (field, paths) in self.facets {
+            self.doc.indexed_fields.insert(field, FacetPath::to_term_vector(&paths));
+        }
+
+        Ok(self.doc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use term::Term;
+    use token::Token;
+    use schema::{Schema, FieldType, FIELD_INDEXED, FIELD_STORED};
+    use document::FieldValue;
+    use facet::FacetPath;
+    use field::Text;
+
+    use super::{DocumentBuilder, DocumentBuildError, simple_analyzer};
+
+    #[test]
+    fn test_simple_analyzer() {
+        let tokens = simple_analyzer("Hello, World!");
+        assert_eq!(tokens, vec![
+            Token { term: Term::from_string("hello"), position: 1, offsets: Some((0, 5)) },
+            Token { term: Term::from_string("world"), position: 2, offsets: Some((7, 12)) },
+        ]);
+        assert_eq!(simple_analyzer("Café au lait")[1].offsets, Some((6, 8)));
+        assert_eq!(simple_analyzer(" !"), vec![]);
+    }
+
+    #[test]
+    fn test_build() {
+        let mut schema = Schema::new();
+        let title = schema.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+        let pk = schema.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+        let category = schema.add_field("category".to_string(), FieldType::Facet, FIELD_INDEXED).unwrap();
+
+        let doc = DocumentBuilder::new(&schema, "doc")
+            .text(title, "Hello world")
+            .text(title, "again")
+            .integer(pk, 5)
+            .facet(category, FacetPath::parse("a/b"))
+            .routing("user1")
+            .build()
+            .unwrap();
+
+        assert_eq!(doc.key, "doc");
+        assert_eq!(doc.routing, Some("user1".to_string()));
+
+        // Text added later is positioned after the text before it
+        let title_terms = &doc.indexed_fields[&title];
+        assert!(title_terms[&Term::from_string("world")].contains(2));
+        assert!(title_terms[&Term::from_string("again")].contains(3));
+        assert_eq!(title_terms.offsets.get(&3), Some(&(12, 17)));
+        match doc.stored_fields.get(&title) {
+            Some(&FieldValue::String(ref title)) => assert_eq!(title, "again"),
+            _ => panic!("title wasn't stored"),
+        }
+
+        // Fields are only indexed if they have the flag
+        assert!(!doc.indexed_fields.contains_key(&pk));
+        match doc.stored_fields.get(&pk) {
+            Some(&FieldValue::Integer(5)) => {}
+            _ => panic!("pk wasn't stored"),
+        }
+
+        assert_eq!(doc.indexed_fields[&category].len(), 2);
+        assert!(!doc.stored_fields.contains_key(&category));
+    }
+
+    #[test]
+    fn test_typed_fields() {
+        let mut schema = Schema::new();
+        let title = schema.add_typed_field::<Text>("title".to_string(), FIELD_INDEXED).unwrap();
+        let pk = schema.add_typed_field::<i64>("pk".to_string(), FIELD_STORED).unwrap();
+
+        assert_eq!(schema.typed_field::<i64>("pk"), Some(pk));
+        assert_eq!(schema.typed_field::<Text>("pk"), None);
+        assert_eq!(schema.typed_field::<Text>("missing"), None);
+
+        let doc = DocumentBuilder::new(&schema, "doc")
+            .add(title, "Hello world")
+            .add(pk, 5)
+            .build()
+            .unwrap();
+
+        assert!(doc.indexed_fields[&title.id()].contains_key(&Term::from_string("world")));
+        match doc.stored_fields.get(&pk.id()) {
+            Some(&FieldValue::Integer(5)) => {}
+            _ => panic!("pk wasn't stored"),
+        }
+    }
+
+    #[test]
+    fn test_analyzer() {
+        let mut schema = Schema::new();
+        let title = schema.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        let analyzer = |text: &str| vec![Token { term: Term::from_string(text), position: 1, offsets: None }];
+        let doc = DocumentBuilder::new(&schema, "doc")
+            .analyzer(&analyzer)
+            .text(title, "Hello world")
+            .build()
+            .unwrap();
+
+        assert!(doc.indexed_fields[&title].contains_key(&Term::from_string("Hello world")));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut schema = Schema::new();
+        let title = schema.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        let result = DocumentBuilder::new(&schema, "doc").integer(title, 1).build();
+        assert_eq!(result.unwrap_err(), DocumentBuildError::WrongFieldType(title, FieldType::Text));
+
+        let missing = ::schema::FieldId(100);
+        let result = DocumentBuilder::new(&schema, "doc").text(missing, "hello").text(title, "hello").build();
+        assert_eq!(result.unwrap_err(), DocumentBuildError::FieldDoesNotExist(missing));
+    }
+}
@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::fmt;
+
+/// An error that occurred while loading data from a segment
+#[derive(Debug)]
+pub enum SegmentError {
+    /// The storage backend of the segment failed to read it
+    Storage(Box<dyn Error + Send + Sync>),
+
+    /// A value was read from the segment but couldn't be decoded
+    Corrupt(String),
+}
+
+impl SegmentError {
+    /// Wraps an error from the storage backend
+    pub fn storage<E: Error + Send + Sync + 'static>(e: E) -> SegmentError {
+        SegmentError::Storage(Box::new(e))
+    }
+}
+
+impl fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SegmentError::Storage(ref e) => write!(f, "segment storage error: {}", e),
+            SegmentError::Corrupt(ref message) => write!(f, "segment is corrupt: {}", message),
+        }
+    }
+}
+
+impl Error for SegmentError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SegmentError::Storage(ref e) => Some(&**e),
+            SegmentError::Corrupt(_) => None,
+        }
+    }
+}
+
+/// An error that occurred while writing to or reading from a store
+#[derive(Debug)]
+pub enum StoreError {
+    /// The storage backend of the store failed
+    Storage(Box<dyn Error + Send + Sync>),
+
+    /// The store refused to index the document (eg, a field had an invalid value)
+    InvalidDocument(String),
+}
+
+impl StoreError {
+    /// Wraps an error from the storage backend
+    pub fn storage<E: Error + Send + Sync + 'static>(e: E) -> StoreError {
+        StoreError::Storage(Box::new(e))
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Storage(ref e) => write!(f, "store error: {}", e),
+            StoreError::InvalidDocument(ref message) => write!(f, "invalid document: {}", message),
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            StoreError::Storage(ref e) => Some(&**e),
+            StoreError::InvalidDocument(_) => None,
+        }
+    }
+}
+
+impl From<StoreError> for String {
+    fn from(e: StoreError) -> String {
+        e.to_string()
+    }
+}
+
+/// Lets code that reports errors as strings keep using "try!" on segment reads
+impl From<SegmentError> for String {
+    fn from(e: SegmentError) -> String {
+        e.to_string()
+    }
+}
+
+/// An error that stopped a search
+#[derive(Debug)]
+pub enum SearchError {
+    /// The search was cancelled through its cancellation token
+    Cancelled,
+
+    /// A segment couldn't be read while the query was being run
+    Storage(SegmentError),
+
+    /// The query couldn't be planned (eg, the statistics needed to plan it couldn't be read)
+    Plan(String),
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SearchError::Cancelled => write!(f, "search cancelled"),
+            SearchError::Storage(ref e) => write!(f, "search failed: {}", e),
+            SearchError::Plan(ref message) => write!(f, "couldn't plan query: {}", message),
+        }
+    }
+}
+
+impl Error for SearchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SearchError::Cancelled => None,
+            SearchError::Storage(ref e) => Some(e),
+            SearchError::Plan(_) => None,
+        }
+    }
+}
+
+impl From<SegmentError> for SearchError {
+    fn from(e: SegmentError) -> SearchError {
+        SearchError::Storage(e)
+    }
+}
+
+impl From<SearchError> for String {
+    fn from(e: SearchError) -> String {
+        e.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fmt;
+
+    use super::{SegmentError, StoreError, SearchError};
+
+    #[derive(Debug)]
+    struct DiskError;
+
+    impl fmt::Display for DiskError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "disk on fire")
+        }
+    }
+
+    impl Error for DiskError {}
+
+    #[test]
+    fn test_source() {
+        let e = SegmentError::storage(DiskError);
+        assert_eq!(e.to_string(), "segment storage error: disk on fire");
+        assert_eq!(e.source().unwrap().to_string(), "disk on fire");
+
+        let e = SegmentError::Corrupt("bad term directory".to_string());
+        assert!(e.source().is_none());
+        assert_eq!(String::from(e), "segment is corrupt: bad term directory");
+    }
+
+    #[test]
+    fn test_store_error_source() {
+        let e = StoreError::storage(DiskError);
+        assert_eq!(e.to_string(), "store error: disk on fire");
+        assert_eq!(e.source().unwrap().to_string(), "disk on fire");
+
+        let e = StoreError::InvalidDocument("segment is full".to_string());
+        assert!(e.source().is_none());
+        assert_eq!(String::from(e), "invalid document: segment is full");
+    }
+
+    #[test]
+    fn test_search_error_source() {
+        let e = SearchError::from(SegmentError::storage(DiskError));
+        assert_eq!(e.to_string(), "search failed: segment storage error: disk on fire");
+        assert_eq!(e.source().unwrap().to_string(), "segment storage error: disk on fire");
+
+        let e = SearchError::Plan("no statistics".to_string());
+        assert!(e.source().is_none());
+        assert_eq!(String::from(e), "couldn't plan query: no statistics");
+
+        assert_eq!(SearchError::Cancelled.to_string(), "search cancelled");
+    }
+}
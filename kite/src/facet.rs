@@ -0,0 +1,167 @@
+use std::fmt;
+
+use term::Term;
+use token::Token;
+use term_vector::TermVector;
+
+/// Separates the depth and components of a facet path in its terms
+const SEPARATOR: char = '\u{1f}';
+
+/// A path in a hierarchy of facets, such as "Electronics/Cameras/DSLR"
+///
+/// Facet fields index a term for the path and each of its ancestors, so a document in
+/// "Electronics/Cameras/DSLR" also matches "Electronics/Cameras" and "Electronics". Each
+/// term starts with the depth of its path so the paths at one depth can be found by prefix.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FacetPath(Vec<String>);
+
+impl FacetPath {
+    /// The path above every other path, this has a depth of 0
+    pub fn root() -> FacetPath {
+        FacetPath(Vec::new())
+    }
+
+    /// Parses a "/" separated path, empty components are ignored
+    pub fn parse(path: &str) -> FacetPath {
+        FacetPath(path.split('/').filter(|component| !component.is_empty()).map(|component| component.to_string()).collect())
+    }
+
+    pub fn from_components(components: Vec<String>) -> FacetPath {
+        FacetPath(components)
+    }
+
+    pub fn components(&self) -> &[String] {
+        &self.0
+    }
+
+    pub fn depth(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the path this many levels from the root, or None if the path isn't that deep
+    pub fn ancestor(&self, depth: usize) -> Option<FacetPath> {
+        if depth > self.depth() {
+            return None;
+        }
+
+        Some(FacetPath(self.0[..depth].to_vec()))
+    }
+
+    /// Returns the term that's indexed for this path
+    pub fn to_term(&self) -> Term {
+        let mut term = self.depth().to_string();
+        for component in self.0.iter() {
+            term.push(SEPARATOR);
+            term.push_str(component);
+        }
+
+        Term::from_string(&term)
+    }
+
+    /// Converts a term created by "to_term" back into a path
+    pub fn from_term(term: &Term) -> Option<FacetPath> {
+        let term = match ::std::str::from_utf8(term.as_bytes()) {
+            Ok(term) => term,
+            Err(_) => return None,
+        };
+
+        let mut parts = term.split(SEPARATOR);
+        let depth = match parts.next().and_then(|depth| depth.parse::<usize>().ok()) {
+            Some(depth) => depth,
+            None => return None,
+        };
+
+        let components = parts.map(|component| component.to_string()).collect::<Vec<_>>();
+        if components.len() != depth {
+            return None;
+        }
+
+        Some(FacetPath(components))
+    }
+
+    /// Returns the prefix of the terms of this path's descendants that are "depth" levels below it
+    pub fn descendant_term_prefix(&self, depth: usize) -> String {
+        let mut prefix = (self.depth() + depth).to_string();
+        for component in self.0.iter() {
+            prefix.push(SEPARATOR);
+            prefix.push_str(component);
+        }
+        prefix.push(SEPARATOR);
+        prefix
+    }
+
+    /// Builds the value of a facet field for a document in these paths
+    ///
+    /// This indexes each path and all of their ancestors.
+    pub fn to_term_vector(paths: &[FacetPath]) -> TermVector {
+        let mut tokens = Vec::new();
+
+        for path in paths.iter() {
+            for depth in 1..path.depth() + 1 {
+                tokens.push(Token {
+                    term: FacetPath(path.0[..depth].to_vec()).to_term(),
+                    position: depth as u32,
+                    offsets: None,
+                });
+            }
+        }
+
+        tokens.into()
+    }
+}
+
+impl fmt::Display for FacetPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.join("/"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use term::Term;
+    use super::FacetPath;
+
+    #[test]
+    fn test_parse() {
+        let path = FacetPath::parse("/Electronics/Cameras//DSLR");
+
+        assert_eq!(path.components(), &["Electronics".to_string(), "Cameras".to_string(), "DSLR".to_string()]);
+        assert_eq!(path.depth(), 3);
+        assert_eq!(path.to_string(), "Electronics/Cameras/DSLR");
+        assert!(FacetPath::parse("").is_root());
+    }
+
+    #[test]
+    fn test_term() {
+        let path = FacetPath::parse("Electronics/Cameras");
+
+        assert_eq!(path.to_term(), Term::from_string("2\u{1f}Electronics\u{1f}Cameras"));
+        assert_eq!(FacetPath::from_term(&path.to_term()), Some(path));
+        assert_eq!(FacetPath::from_term(&Term::from_string("2\u{1f}Electronics")), None);
+        assert_eq!(FacetPath::from_term(&Term::from_string("hello")), None);
+    }
+
+    #[test]
+    fn test_descendant_term_prefix() {
+        let path = FacetPath::parse("Electronics");
+        let prefix = path.descendant_term_prefix(1);
+
+        assert!(FacetPath::parse("Electronics/Cameras").to_term().as_bytes().starts_with(prefix.as_bytes()));
+        assert!(!FacetPath::parse("Electronics/Cameras/DSLR").to_term().as_bytes().starts_with(prefix.as_bytes()));
+        assert!(!FacetPath::parse("Electronicsx/Cameras").to_term().as_bytes().starts_with(prefix.as_bytes()));
+        assert!(FacetPath::parse("Books").to_term().as_bytes().starts_with(FacetPath::root().descendant_term_prefix(1).as_bytes()));
+    }
+
+    #[test]
+    fn test_to_term_vector() {
+        let term_vector = FacetPath::to_term_vector(&[FacetPath::parse("Electronics/Cameras"), FacetPath::parse("Electronics/Phones")]);
+
+        assert_eq!(term_vector.len(), 3);
+        assert!(term_vector.contains_key(&FacetPath::parse("Electronics").to_term()));
+        assert!(term_vector.contains_key(&FacetPath::parse("Electronics/Phones").to_term()));
+    }
+}
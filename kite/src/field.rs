@@ -0,0 +1,202 @@
+//! Field handles that know the type of their field
+//!
+//! A `Field<T>` can only be created for a field of type T so, when it's used to build
+//! documents or queries, passing a value of the wrong type fails to compile rather than
+//! being found at runtime. For example, a `Field<i64>` only accepts integers.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use chrono::{DateTime, Utc};
+
+use term::Term;
+use schema::{FieldId, FieldType};
+use facet::FacetPath;
+
+/// Types that fields can have
+pub trait FieldKind {
+    fn field_type() -> FieldType;
+}
+
+/// Marker for text fields, their values are split into terms by an analyzer
+pub struct Text;
+
+/// Marker for string fields, their values are indexed as a single term
+pub struct PlainString;
+
+/// Marker for facet fields
+pub struct Facet;
+
+/// Marker for rank feature fields
+pub struct RankFeature;
+
+/// Marker for join fields, their value is the key of the document's parent
+pub struct Join;
+
+impl FieldKind for Text {
+    fn field_type() -> FieldType {
+        FieldType::Text
+    }
+}
+
+impl FieldKind for PlainString {
+    fn field_type() -> FieldType {
+        FieldType::PlainString
+    }
+}
+
+impl FieldKind for i64 {
+    fn field_type() -> FieldType {
+        FieldType::I64
+    }
+}
+
+impl FieldKind for bool {
+    fn field_type() -> FieldType {
+        FieldType::Boolean
+    }
+}
+
+impl FieldKind for DateTime<Utc> {
+    fn field_type() -> FieldType {
+        FieldType::DateTime
+    }
+}
+
+impl FieldKind for Facet {
+    fn field_type() -> FieldType {
+        FieldType::Facet
+    }
+}
+
+impl FieldKind for RankFeature {
+    fn field_type() -> FieldType {
+        FieldType::RankFeature
+    }
+}
+
+impl FieldKind for Join {
+    fn field_type() -> FieldType {
+        FieldType::Join
+    }
+}
+
+/// A handle for a field of type T
+pub struct Field<T: FieldKind> {
+    id: FieldId,
+    kind: PhantomData<T>,
+}
+
+impl<T: FieldKind> Field<T> {
+    /// Creates a handle without checking the type of the field
+    ///
+    /// Use Schema::typed_field to look up a field, which checks its type.
+    pub fn new(id: FieldId) -> Field<T> {
+        Field {
+            id: id,
+            kind: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> FieldId {
+        self.id
+    }
+}
+
+// These aren't derived as derive would require T to implement them too
+
+impl<T: FieldKind> Clone for Field<T> {
+    fn clone(&self) -> Field<T> {
+        Field::new(self.id)
+    }
+}
+
+impl<T: FieldKind> Copy for Field<T> {}
+
+impl<T: FieldKind> PartialEq for Field<T> {
+    fn eq(&self, other: &Field<T>) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T: FieldKind> fmt::Debug for Field<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Field({}, {:?})", self.id.0, T::field_type())
+    }
+}
+
+impl<T: FieldKind> From<Field<T>> for FieldId {
+    fn from(field: Field<T>) -> FieldId {
+        field.id
+    }
+}
+
+/// Values that are indexed as a single term in fields of type T
+pub trait FieldTerm<T: FieldKind> {
+    fn to_term(&self) -> Term;
+}
+
+impl<'a> FieldTerm<Text> for &'a str {
+    fn to_term(&self) -> Term {
+        Term::from_string(self)
+    }
+}
+
+impl<'a> FieldTerm<PlainString> for &'a str {
+    fn to_term(&self) -> Term {
+        Term::from_string(self)
+    }
+}
+
+impl<'a> FieldTerm<Join> for &'a str {
+    fn to_term(&self) -> Term {
+        Term::from_string(self)
+    }
+}
+
+impl FieldTerm<i64> for i64 {
+    fn to_term(&self) -> Term {
+        Term::from_integer(*self)
+    }
+}
+
+impl FieldTerm<bool> for bool {
+    fn to_term(&self) -> Term {
+        Term::from_boolean(*self)
+    }
+}
+
+impl FieldTerm<DateTime<Utc>> for DateTime<Utc> {
+    fn to_term(&self) -> Term {
+        Term::from_datetime(self)
+    }
+}
+
+impl<'a> FieldTerm<Facet> for &'a FacetPath {
+    fn to_term(&self) -> Term {
+        FacetPath::to_term(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use term::Term;
+    use schema::FieldId;
+
+    use super::{Field, FieldTerm, PlainString};
+
+    #[test]
+    fn test_field() {
+        let field: Field<i64> = Field::new(FieldId(3));
+        let copy = field;
+        assert_eq!(field, copy);
+        assert_eq!(FieldId::from(field), FieldId(3));
+        assert_eq!(format!("{:?}", field), "Field(3, I64)");
+    }
+
+    #[test]
+    fn test_field_term() {
+        assert_eq!(FieldTerm::<i64>::to_term(&5), Term::from_integer(5));
+        assert_eq!(FieldTerm::<PlainString>::to_term(&"abc"), Term::from_string("abc"));
+    }
+}
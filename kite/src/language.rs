@@ -0,0 +1,169 @@
+//! Routing the text of multilingual fields to language-specific analyzers
+//!
+//! Kite doesn't analyze text itself, documents are tokenized by the application before they're
+//! indexed. When a field contains text in many languages, a LanguageRouter can be used to detect
+//! the language of each value and tokenize it with the analyzer for that language (for example,
+//! to apply the right stemmer). The detected language can also be recorded in a stored field so
+//! it can be filtered on or returned with the document.
+
+use fnv::FnvHashMap;
+
+use token::Token;
+use schema::FieldId;
+use document::{Document, FieldValue};
+
+/// Detects the language of some text
+///
+/// Implemented for closures so a detection library can be plugged in with
+/// `|text: &str| detect(text).map(|lang| lang.code().to_string())`.
+pub trait LanguageDetector: Send + Sync {
+    /// Returns the code of the language of the text (such as "en"), or None if it's unknown
+    fn detect(&self, text: &str) -> Option<String>;
+}
+
+impl<F> LanguageDetector for F
+    where F: Fn(&str) -> Option<String> + Send + Sync
+{
+    fn detect(&self, text: &str) -> Option<String> {
+        self(text)
+    }
+}
+
+/// Converts text into tokens
+pub type Analyzer = Box<dyn Fn(&str) -> Vec<Token> + Send + Sync>;
+
+pub struct LanguageRouter {
+    detector: Box<dyn LanguageDetector>,
+    analyzers: FnvHashMap<String, Analyzer>,
+    default_analyzer: Analyzer,
+    language_field: Option<FieldId>,
+}
+
+impl LanguageRouter {
+    /// Creates a router that uses "default_analyzer" for text in languages that don't have
+    /// their own analyzer, or where the language couldn't be detected
+    pub fn new<D, A>(detector: D, default_analyzer: A) -> LanguageRouter
+        where D: LanguageDetector + 'static,
+              A: Fn(&str) -> Vec<Token> + Send + Sync + 'static
+    {
+        LanguageRouter {
+            detector: Box::new(detector),
+            analyzers: FnvHashMap::default(),
+            default_analyzer: Box::new(default_analyzer),
+            language_field: None,
+        }
+    }
+
+    /// Sets the analyzer for text detected as "language"
+    pub fn add_analyzer<A>(&mut self, language: &str, analyzer: A)
+        where A: Fn(&str) -> Vec<Token> + Send + Sync + 'static
+    {
+        self.analyzers.insert(language.to_string(), Box::new(analyzer));
+    }
+
+    /// Records the detected language in a stored field of each document
+    ///
+    /// Nothing is stored if the language couldn't be detected.
+    pub fn set_language_field(&mut self, field: FieldId) {
+        self.language_field = Some(field);
+    }
+
+    /// Detects the language of the text and tokenizes it with the analyzer for that language
+    ///
+    /// Returns the detected language and the tokens.
+    pub fn analyze(&self, text: &str) -> (Option<String>, Vec<Token>) {
+        let language = self.detector.detect(text);
+        let analyzer = language.as_ref()
+            .and_then(|language| self.analyzers.get(language))
+            .unwrap_or(&self.default_analyzer);
+
+        let tokens = analyzer(text);
+        (language, tokens)
+    }
+
+    /// Analyzes the text and indexes it into "field" of the document
+    ///
+    /// This replaces any terms the document already has in the field. Returns the detected
+    /// language.
+    pub fn index_text(&self, doc: &mut Document, field: FieldId, text: &str) -> Option<String> {
+        let (language, tokens) = self.analyze(text);
+        doc.indexed_fields.insert(field, tokens.into());
+
+        if let Some(language_field) = self.language_field {
+            if let Some(ref language) = language {
+                doc.stored_fields.insert(language_field, FieldValue::String(language.clone()));
+            }
+        }
+
+        language
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fnv::FnvHashMap;
+
+    use term::Term;
+    use token::Token;
+    use schema::FieldId;
+    use document::{Document, FieldValue};
+
+    use super::LanguageRouter;
+
+    fn detect(text: &str) -> Option<String> {
+        if text.contains("the") {
+            Some("en".to_string())
+        } else if text.contains("le") {
+            Some("fr".to_string())
+        } else {
+            None
+        }
+    }
+
+    fn tokenize(text: &str, suffix: &str) -> Vec<Token> {
+        text.split_whitespace().enumerate().map(|(position, word)| {
+            Token { term: Term::from_string(&format!("{}{}", word, suffix)), position: position as u32 + 1, offsets: None }
+        }).collect()
+    }
+
+    #[test]
+    fn test_analyze() {
+        let mut router = LanguageRouter::new(detect, |text: &str| tokenize(text, ""));
+        router.add_analyzer("en", |text: &str| tokenize(text, "_en"));
+
+        let (language, tokens) = router.analyze("the cat");
+        assert_eq!(language, Some("en".to_string()));
+        assert_eq!(tokens[1].term, Term::from_string("cat_en"));
+
+        // French has no analyzer so the default is used
+        let (language, tokens) = router.analyze("le chat");
+        assert_eq!(language, Some("fr".to_string()));
+        assert_eq!(tokens[1].term, Term::from_string("chat"));
+
+        let (language, _) = router.analyze("zzz");
+        assert_eq!(language, None);
+    }
+
+    #[test]
+    fn test_index_text() {
+        let mut router = LanguageRouter::new(detect, |text: &str| tokenize(text, ""));
+        router.set_language_field(FieldId(2));
+
+        let mut doc = Document {
+            key: "doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        };
+
+        assert_eq!(router.index_text(&mut doc, FieldId(1), "le chat"), Some("fr".to_string()));
+        assert!(doc.indexed_fields[&FieldId(1)].contains_key(&Term::from_string("chat")));
+        match doc.stored_fields.get(&FieldId(2)) {
+            Some(&FieldValue::String(ref language)) => assert_eq!(language, "fr"),
+            _ => panic!("language wasn't stored"),
+        }
+    }
+}
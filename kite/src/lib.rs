@@ -7,20 +7,38 @@ extern crate byteorder;
 #[macro_use]
 extern crate bitflags;
 extern crate fnv;
+extern crate regex_automata;
 
+pub mod error;
 pub mod term;
 pub mod token;
 pub mod term_vector;
 pub mod schema;
+pub mod field;
 pub mod document;
+pub mod document_builder;
 pub mod segment;
+pub mod postings;
+pub mod doc_id_set;
+pub mod bitmap_format;
 pub mod similarity;
 pub mod query;
 pub mod collectors;
+pub mod cancellation;
+pub mod metrics;
+pub mod facet;
+pub mod distributed;
+pub mod store;
+pub mod memory;
+pub mod language;
+pub mod testing;
 
 pub use term::{Term, TermId};
 pub use token::Token;
-pub use document::{Document, DocId};
+pub use document::{Document, DocId, StoredDocument};
+pub use document_builder::DocumentBuilder;
+pub use field::Field;
 pub use query::multi_term_selector::MultiTermSelector;
+pub use query::multi_term_rewrite::MultiTermRewrite;
 pub use query::term_scorer::TermScorer;
 pub use query::Query;
@@ -0,0 +1,662 @@
+//! An in-memory storage backend
+//!
+//! "MemoryStore" implements the "Store" traits without any storage, its data is lost when it's
+//! dropped. It's meant for tests, small indexes that are rebuilt when they're loaded, and
+//! targets that don't have a filesystem or can't build the other backends (such as
+//! wasm32-unknown-unknown).
+//!
+//! Like kite_sled, documents aren't split into segments and only some queries are supported
+//! (see "MemoryReader::search"). Scores are combined the same way as kite_rocksdb.
+//!
+//! The index is shared with readers through an "Arc". Writes change it in place while no
+//! readers are open, otherwise the index is copied first so open readers keep seeing the
+//! version they were opened with.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+use roaring::RoaringBitmap;
+use fnv::FnvHashMap;
+
+use term::Term;
+use document::{Document, DocId};
+use schema::{Schema, FieldType, FieldFlags, FieldId, AddFieldError};
+use segment::SegmentId;
+use query::Query;
+use query::term_scorer::TermScorer;
+use query::field_value_factor::FieldValueFactor;
+use query::function_score::{ScoreFunction, FunctionScoreCombine};
+use collectors::{Collector, DocumentMatch};
+use store::{Store, StoreReader};
+use error::{StoreError, SearchError};
+
+#[derive(Debug, Clone, Default)]
+struct MemoryIndex {
+    /// The live documents, by their number
+    documents: FnvHashMap<u32, Document>,
+
+    /// The number of each live document, by its key
+    keys: HashMap<String, u32>,
+
+    /// The live documents that contain each term
+    term_directories: HashMap<(FieldId, Term), RoaringBitmap>,
+
+    /// The number of live documents that have each field indexed, and their total length
+    field_totals: FnvHashMap<FieldId, (u64, u64)>,
+
+    live_docs: RoaringBitmap,
+    next_doc: u32,
+}
+
+fn field_length(doc: &Document, field_id: FieldId) -> u64 {
+    doc.indexed_fields.get(&field_id).map_or(0, |term_vector| term_vector.values().map(|positions| positions.len()).sum())
+}
+
+impl MemoryIndex {
+    fn insert(&mut self, doc: &Document) {
+        self.remove(&doc.key);
+
+        let doc_number = self.next_doc;
+        self.next_doc += 1;
+
+        for (field_id, term_vector) in doc.indexed_fields.iter() {
+            for term in term_vector.keys() {
+                self.term_directories.entry((*field_id, term.clone())).or_insert_with(RoaringBitmap::new).insert(doc_number);
+            }
+
+            let totals = self.field_totals.entry(*field_id).or_insert((0, 0));
+            totals.0 += 1;
+            totals.1 += field_length(doc, *field_id);
+        }
+
+        self.live_docs.insert(doc_number);
+        self.keys.insert(doc.key.clone(), doc_number);
+        self.documents.insert(doc_number, doc.clone());
+    }
+
+    fn remove(&mut self, doc_key: &str) -> bool {
+        let doc_number = match self.keys.remove(doc_key) {
+            Some(doc_number) => doc_number,
+            None => return false,
+        };
+        let doc = self.documents.remove(&doc_number).unwrap();
+
+        for (field_id, term_vector) in doc.indexed_fields.iter() {
+            for term in term_vector.keys() {
+                let key = (*field_id, term.clone());
+                let is_empty = match self.term_directories.get_mut(&key) {
+                    Some(term_directory) => {
+                        term_directory.remove(doc_number);
+                        term_directory.is_empty()
+                    }
+                    None => false,
+                };
+
+                if is_empty {
+                    self.term_directories.remove(&key);
+                }
+            }
+
+            if let Some(totals) = self.field_totals.get_mut(field_id) {
+                totals.0 -= 1;
+                totals.1 -= field_length(&doc, *field_id);
+            }
+        }
+
+        self.live_docs.remove(doc_number);
+        true
+    }
+}
+
+/// The id of a document, every document is in segment 0 with its number as its ord
+#[inline]
+fn doc_id(doc: u32) -> DocId {
+    DocId(SegmentId(0), doc)
+}
+
+/// An index that's kept in memory
+///
+/// This can be shared between threads. Writes are serialized by a lock, readers don't block
+/// them.
+#[derive(Debug)]
+pub struct MemoryStore {
+    schema: Schema,
+    index: Mutex<Arc<MemoryIndex>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            schema: Schema::new(),
+            index: Mutex::new(Arc::new(MemoryIndex::default())),
+        }
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn add_field(&mut self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError> {
+        self.schema.add_field(name, field_type, field_flags)
+    }
+
+    pub fn remove_field(&mut self, field_id: &FieldId) -> bool {
+        self.schema.remove_field(field_id)
+    }
+
+    pub fn insert_or_update_document(&self, doc: &Document) {
+        let mut index = self.index.lock().unwrap();
+        Arc::make_mut(&mut index).insert(doc);
+    }
+
+    pub fn remove_document_by_key(&self, doc_key: &str) -> bool {
+        let mut index = self.index.lock().unwrap();
+
+        // Check first so the index isn't copied for open readers if there's nothing to remove
+        if !index.keys.contains_key(doc_key) {
+            return false;
+        }
+
+        Arc::make_mut(&mut index).remove(doc_key)
+    }
+
+    /// Opens a reader that sees the documents that were live when it was opened
+    pub fn reader<'a>(&'a self) -> MemoryReader<'a> {
+        MemoryReader {
+            schema: &self.schema,
+            index: self.index.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+enum Scorer {
+    Literal(f32),
+    Term {
+        field_id: FieldId,
+        term: Term,
+        scorer: TermScorer,
+
+        /// The live documents that contain the term
+        docs: RoaringBitmap,
+    },
+    Avg(Vec<Scorer>),
+    Max(Vec<Scorer>),
+    FieldValueFactor(Box<Scorer>, FieldValueFactor),
+    FunctionScore(Box<Scorer>, Vec<ScoreFunction>, FunctionScoreCombine),
+    Boosting(Box<Scorer>, RoaringBitmap, f32),
+}
+
+pub struct MemoryReader<'a> {
+    schema: &'a Schema,
+    index: Arc<MemoryIndex>,
+}
+
+impl<'a> MemoryReader<'a> {
+    pub fn schema(&self) -> &Schema {
+        self.schema
+    }
+
+    /// The segments that contain live documents
+    ///
+    /// Documents aren't split into segments, so this is a single segment unless the index is
+    /// empty.
+    pub fn segments(&self) -> Vec<SegmentId> {
+        if self.index.live_docs.is_empty() {
+            Vec::new()
+        } else {
+            vec![SegmentId(0)]
+        }
+    }
+
+    pub fn contains_document_key(&self, doc_key: &str) -> bool {
+        self.index.keys.contains_key(doc_key)
+    }
+
+    /// Finds the id of a document from its key
+    pub fn get_document_id(&self, doc_key: &str) -> Option<DocId> {
+        self.index.keys.get(doc_key).map(|doc| doc_id(*doc))
+    }
+
+    /// Fetches a document by its key
+    ///
+    /// The document has the values of all the fields that are stored and its source and
+    /// routing key (if it was indexed with them), other fields are left empty.
+    pub fn get_document(&self, doc_key: &str) -> Option<Document> {
+        let doc = match self.index.keys.get(doc_key) {
+            Some(doc) => &self.index.documents[doc],
+            None => return None,
+        };
+
+        // Values of fields that have been removed from the schema aren't returned
+        let stored_fields = doc.stored_fields.iter()
+            .filter(|&(field_id, _)| self.schema.get(field_id).is_some())
+            .map(|(field_id, value)| (*field_id, value.clone()))
+            .collect();
+
+        Some(Document {
+            key: doc.key.clone(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: doc.source.clone(),
+            routing: doc.routing.clone(),
+        })
+    }
+
+    fn load_term_directory(&self, field_id: FieldId, term: &Term) -> RoaringBitmap {
+        self.index.term_directories.get(&(field_id, term.clone())).cloned().unwrap_or_default()
+    }
+
+    /// Reads a stored value of a document as an integer (dates in microseconds)
+    fn read_doc_value(&self, doc: u32, field_id: FieldId) -> Option<i64> {
+        if self.schema.get(&field_id).is_none() {
+            return None;
+        }
+
+        self.index.documents[&doc].stored_fields.get(&field_id).and_then(|value| value.to_doc_value())
+    }
+
+    fn plan_all<'q, I: IntoIterator<Item = &'q Query>>(&self, queries: I) -> Result<(Vec<RoaringBitmap>, Vec<Scorer>), SearchError> {
+        let mut matches = Vec::new();
+        let mut scorers = Vec::new();
+
+        for query in queries {
+            let (docs, scorer) = try!(self.plan(query));
+            matches.push(docs);
+            scorers.push(scorer);
+        }
+
+        Ok((matches, scorers))
+    }
+
+    /// Finds the documents that match the query and builds the scorer for them
+    fn plan(&self, query: &Query) -> Result<(RoaringBitmap, Scorer), SearchError> {
+        match *query {
+            Query::All{score} => Ok((self.index.live_docs.clone(), Scorer::Literal(score))),
+            Query::None => Ok((RoaringBitmap::new(), Scorer::Literal(0.0f32))),
+            Query::Term{field, ref term, ref scorer} => {
+                let docs = self.load_term_directory(field, term);
+
+                Ok((docs.clone(), Scorer::Term {
+                    field_id: field,
+                    term: term.clone(),
+                    scorer: scorer.clone(),
+                    docs: docs,
+                }))
+            }
+            Query::Terms{field, ref terms, score} => {
+                let mut docs = RoaringBitmap::new();
+                for term in terms.iter() {
+                    docs |= self.load_term_directory(field, term);
+                }
+
+                Ok((docs, Scorer::Literal(score)))
+            }
+            Query::Conjunction{ref queries} => {
+                let (matches, scorers) = try!(self.plan_all(queries));
+                let mut matches = matches.into_iter();
+                let docs = match matches.next() {
+                    Some(first) => matches.fold(first, |docs, other| docs & other),
+                    None => RoaringBitmap::new(),
+                };
+
+                Ok((docs, Scorer::Avg(scorers)))
+            }
+            Query::Disjunction{ref queries} => {
+                let (matches, scorers) = try!(self.plan_all(queries));
+                let docs = matches.into_iter().fold(RoaringBitmap::new(), |docs, other| docs | other);
+
+                Ok((docs, Scorer::Avg(scorers)))
+            }
+            Query::DisjunctionMax{ref queries} => {
+                let (matches, scorers) = try!(self.plan_all(queries));
+                let docs = matches.into_iter().fold(RoaringBitmap::new(), |docs, other| docs | other);
+
+                Ok((docs, Scorer::Max(scorers)))
+            }
+            Query::Boolean{ref must, ref should, ref must_not, minimum_should_match} => {
+                let (must_matches, mut scorers) = try!(self.plan_all(must));
+                let (should_matches, should_scorers) = try!(self.plan_all(should));
+                scorers.extend(should_scorers);
+
+                let mut docs = must_matches.into_iter().fold(self.index.live_docs.clone(), |docs, other| docs & other);
+
+                // Without any "must" queries, at least one "should" query must match
+                let minimum_should_match = if must.is_empty() {
+                    minimum_should_match.max(1)
+                } else {
+                    minimum_should_match
+                };
+
+                if minimum_should_match > 0 {
+                    docs = docs.iter()
+                        .filter(|doc| should_matches.iter().filter(|matches| matches.contains(*doc)).count() >= minimum_should_match)
+                        .collect();
+                }
+
+                for query in must_not.iter() {
+                    docs -= try!(self.plan(query)).0;
+                }
+
+                Ok((docs, Scorer::Avg(scorers)))
+            }
+            Query::Filter{ref query, ref filter} => {
+                let (docs, scorer) = try!(self.plan(query));
+                Ok((docs & try!(self.plan(filter)).0, scorer))
+            }
+            Query::Exclude{ref query, ref exclude} => {
+                let (docs, scorer) = try!(self.plan(query));
+                Ok((docs - try!(self.plan(exclude)).0, scorer))
+            }
+            Query::Boosting{ref positive, ref negative, negative_boost} => {
+                let (docs, scorer) = try!(self.plan(positive));
+                let negative_docs = try!(self.plan(negative)).0;
+
+                Ok((docs, Scorer::Boosting(Box::new(scorer), negative_docs, negative_boost)))
+            }
+            Query::ConstantScore{ref query, score} => {
+                Ok((try!(self.plan(query)).0, Scorer::Literal(score)))
+            }
+            Query::FieldValueFactor{ref query, ref factor} => {
+                let (docs, scorer) = try!(self.plan(query));
+                Ok((docs, Scorer::FieldValueFactor(Box::new(scorer), factor.clone())))
+            }
+            Query::FunctionScore{ref query, ref functions, combine} => {
+                let (docs, scorer) = try!(self.plan(query));
+                Ok((docs, Scorer::FunctionScore(Box::new(scorer), functions.clone(), combine)))
+            }
+            Query::Exists{field, score} => {
+                let docs = self.index.live_docs.iter()
+                    .filter(|doc| {
+                        let doc = &self.index.documents[doc];
+                        doc.indexed_fields.contains_key(&field) || doc.stored_fields.contains_key(&field)
+                    })
+                    .collect();
+
+                Ok((docs, Scorer::Literal(score)))
+            }
+            Query::Range{field, min, max, score} => {
+                let docs = self.index.live_docs.iter()
+                    .filter(|doc| {
+                        match self.read_doc_value(*doc, field) {
+                            Some(value) => min.map_or(true, |min| value >= min) && max.map_or(true, |max| value <= max),
+                            None => false,
+                        }
+                    })
+                    .collect();
+
+                Ok((docs, Scorer::Literal(score)))
+            }
+            _ => Err(SearchError::Plan(format!("query isn't supported by the memory backend: {:?}", query))),
+        }
+    }
+
+    fn score_term(&self, doc: u32, field_id: FieldId, term: &Term, scorer: &TermScorer, docs: &RoaringBitmap) -> f32 {
+        let doc = &self.index.documents[&doc];
+        let term_frequency = doc.indexed_fields.get(&field_id)
+            .and_then(|term_vector| term_vector.get(term))
+            .map_or(1, |positions| positions.len());
+        let field_length = field_length(doc, field_id).max(1);
+        let (total_docs, total_tokens) = self.index.field_totals.get(&field_id).cloned().unwrap_or((0, 0));
+
+        let score = scorer.similarity_model.score(term_frequency as u32, field_length as f32, total_tokens, total_docs, docs.len());
+        score * scorer.boost
+    }
+
+    fn score(&self, doc: u32, scorer: &Scorer) -> f32 {
+        match *scorer {
+            Scorer::Literal(score) => score,
+            Scorer::Term{field_id, ref term, ref scorer, ref docs} => {
+                if docs.contains(doc) {
+                    self.score_term(doc, field_id, term, scorer, docs)
+                } else {
+                    0.0f32
+                }
+            }
+            Scorer::Avg(ref scorers) => {
+                let total_score: f32 = scorers.iter().map(|scorer| self.score(doc, scorer)).sum();
+                total_score / scorers.len() as f32
+            }
+            Scorer::Max(ref scorers) => {
+                scorers.iter().fold(0.0f32, |max_score, scorer| max_score.max(self.score(doc, scorer)))
+            }
+            Scorer::FieldValueFactor(ref scorer, ref factor) => {
+                let base_score = self.score(doc, scorer);
+                let value = self.read_doc_value(doc, factor.field);
+
+                factor.apply(base_score, value.map(|value| value as f64))
+            }
+            Scorer::FunctionScore(ref scorer, ref functions, combine) => {
+                let base_score = self.score(doc, scorer);
+                let values = functions.iter()
+                    .map(|function| function.compute(self.read_doc_value(doc, function.field()).map(|value| value as f64)));
+
+                combine.combine(base_score, values)
+            }
+            Scorer::Boosting(ref scorer, ref negative_docs, negative_boost) => {
+                let base_score = self.score(doc, scorer);
+
+                if negative_docs.contains(doc) {
+                    base_score * negative_boost
+                } else {
+                    base_score
+                }
+            }
+        }
+    }
+
+    /// Runs a query, passing the documents that match it to the collector
+    ///
+    /// Queries on term directories, stored values and combinations of them are supported.
+    /// Others (such as phrase, prefix and rank feature queries) return a "Plan" error.
+    pub fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), SearchError> {
+        let (docs, scorer) = try!(self.plan(query));
+        let sort_field = collector.sort_field();
+
+        for doc in docs.iter() {
+            let mut doc_match = if collector.needs_score() {
+                DocumentMatch::new_scored(doc_id(doc).as_u64(), self.score(doc, &scorer))
+            } else {
+                DocumentMatch::new_unscored(doc_id(doc).as_u64())
+            };
+
+            if let Some(sort_field) = sort_field {
+                doc_match = doc_match.with_sort_value(self.read_doc_value(doc, sort_field));
+            }
+
+            collector.collect(doc_match);
+        }
+
+        Ok(())
+    }
+}
+
+impl Store for MemoryStore {
+    type Reader<'a> = MemoryReader<'a>;
+
+    fn schema(&self) -> &Schema {
+        MemoryStore::schema(self)
+    }
+
+    fn add_field(&mut self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError> {
+        MemoryStore::add_field(self, name, field_type, field_flags)
+    }
+
+    fn remove_field(&mut self, field_id: &FieldId) -> Result<bool, StoreError> {
+        Ok(MemoryStore::remove_field(self, field_id))
+    }
+
+    fn insert_or_update_document(&self, doc: &Document) -> Result<(), StoreError> {
+        MemoryStore::insert_or_update_document(self, doc);
+        Ok(())
+    }
+
+    fn remove_document_by_key(&self, doc_key: &str) -> Result<bool, StoreError> {
+        Ok(MemoryStore::remove_document_by_key(self, doc_key))
+    }
+
+    fn reader<'a>(&'a self) -> Result<MemoryReader<'a>, StoreError> {
+        Ok(MemoryStore::reader(self))
+    }
+}
+
+impl<'a> StoreReader for MemoryReader<'a> {
+    fn schema(&self) -> &Schema {
+        MemoryReader::schema(self)
+    }
+
+    fn segments(&self) -> Vec<SegmentId> {
+        MemoryReader::segments(self)
+    }
+
+    fn contains_document_key(&self, doc_key: &str) -> Result<bool, StoreError> {
+        Ok(MemoryReader::contains_document_key(self, doc_key))
+    }
+
+    fn get_document(&self, doc_key: &str) -> Result<Option<Document>, StoreError> {
+        Ok(MemoryReader::get_document(self, doc_key))
+    }
+
+    fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), SearchError> {
+        MemoryReader::search(self, collector, query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use term::Term;
+    use document_builder::DocumentBuilder;
+    use document::FieldValue;
+    use schema::{FieldType, FIELD_INDEXED, FIELD_STORED};
+    use segment::SegmentId;
+    use query::Query;
+    use collectors::Collector;
+    use collectors::top_score::TopScoreCollector;
+    use collectors::top_field::{TopFieldCollector, SortOrder};
+    use collectors::total_count::TotalCountCollector;
+    use store::{Store, StoreReader};
+
+    use super::MemoryStore;
+
+    #[test]
+    fn test_search() {
+        let mut store = MemoryStore::new();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let rating_field = store.add_field("rating".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+
+        for (key, title, rating) in vec![("a", "red apple", 3), ("b", "green apple", 5), ("c", "red cherry", 1)] {
+            let doc = DocumentBuilder::new(store.schema(), key)
+                .text(title_field, title)
+                .integer(rating_field, rating)
+                .build().unwrap();
+            store.insert_or_update_document(&doc);
+        }
+
+        let reader = store.reader();
+        let term = |term: &str| Query::term(title_field, Term::from_string(term));
+        let search = |query: &Query| {
+            let mut collector = TopScoreCollector::new(10);
+            reader.search(&mut collector, query).unwrap();
+            collector.into_sorted_vec().iter().map(|doc| (doc.doc_id(), doc.score().unwrap())).collect::<Vec<_>>()
+        };
+        let matches = |query: &Query| {
+            let mut docs = search(query).iter().map(|&(doc_id, _)| doc_id).collect::<Vec<_>>();
+            docs.sort();
+            docs
+        };
+
+        assert_eq!(matches(&Query::conjunction(vec![term("red"), term("apple")])), vec![0]);
+        assert_eq!(matches(&Query::disjunction(vec![term("green"), term("cherry")])), vec![1, 2]);
+        assert_eq!(matches(&term("apple").filter(term("red"))), vec![0]);
+        assert_eq!(matches(&term("apple").exclude(term("red"))), vec![1]);
+        assert_eq!(matches(&Query::any_term(title_field, vec![Term::from_string("green"), Term::from_string("cherry")])), vec![1, 2]);
+        assert_eq!(matches(&Query::boolean(vec![], vec![term("red"), term("apple"), term("green")], vec![]).minimum_should_match(2)), vec![0, 1]);
+        assert_eq!(matches(&Query::range(rating_field, Some(2), None)), vec![0, 1]);
+        assert_eq!(matches(&Query::exists(rating_field)), vec![0, 1, 2]);
+
+        // Non-matching clauses of a disjunction score 0, so documents matching both score higher
+        let scores = search(&Query::disjunction(vec![term("red"), term("apple")]));
+        assert_eq!(scores[0].0, 0);
+
+        // Boosted terms score higher
+        assert_eq!(search(&term("red").boost(2.0))[0].1, search(&term("red"))[0].1 * 2.0);
+
+        // Demoted documents score lower
+        let scores = search(&term("red").demote(term("cherry"), 0.1));
+        assert_eq!(scores[0].0, 0);
+
+        // Sorting by a stored field
+        let mut collector = TopFieldCollector::new(rating_field, SortOrder::Descending, 10);
+        reader.search(&mut collector, &Query::all()).unwrap();
+        assert!(!collector.needs_score());
+        assert_eq!(collector.into_sorted_vec().iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![1, 0, 2]);
+
+        // Unsupported queries return an error
+        let mut collector = TopScoreCollector::new(10);
+        assert!(reader.search(&mut collector, &Query::prefix(title_field, "re")).is_err());
+    }
+
+    #[test]
+    fn test_update_and_delete() {
+        let mut store = MemoryStore::new();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+        let title = |title: &str| DocumentBuilder::new(store.schema(), "a").text(title_field, title).build().unwrap();
+
+        store.insert_or_update_document(&title("hello"));
+        let old_reader = store.reader();
+
+        store.insert_or_update_document(&title("goodbye"));
+        let reader = store.reader();
+        let count = |reader: &super::MemoryReader, term: &str| {
+            let mut collector = TotalCountCollector::new();
+            reader.search(&mut collector, &Query::term(title_field, Term::from_string(term))).unwrap();
+            collector.get_total_count()
+        };
+
+        // The update replaced the document
+        assert_eq!(count(&reader, "hello"), 0);
+        assert_eq!(count(&reader, "goodbye"), 1);
+        match reader.get_document("a").unwrap().stored_fields.get(&title_field) {
+            Some(&FieldValue::String(ref value)) => assert_eq!(value, "goodbye"),
+            value => panic!("unexpected value: {:?}", value),
+        }
+
+        // Readers that were open before keep seeing the old version
+        assert_eq!(count(&old_reader, "hello"), 1);
+        assert_eq!(count(&old_reader, "goodbye"), 0);
+
+        assert!(store.remove_document_by_key("a"));
+        assert!(!store.remove_document_by_key("a"));
+        assert!(reader.contains_document_key("a"));
+        assert!(!store.reader().contains_document_key("a"));
+        assert_eq!(store.reader().segments(), vec![]);
+    }
+
+    #[test]
+    fn test_store_traits() {
+        let mut store = MemoryStore::new();
+        let title_field = Store::add_field(&mut store, "title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        for key in vec!["a", "b"] {
+            let doc = DocumentBuilder::new(Store::schema(&store), key).text(title_field, "hello").build().unwrap();
+            Store::insert_or_update_document(&store, &doc).unwrap();
+        }
+        assert!(Store::remove_document_by_key(&store, "a").unwrap());
+
+        let reader = Store::reader(&store).unwrap();
+        assert_eq!(StoreReader::segments(&reader), vec![SegmentId(0)]);
+        assert!(StoreReader::contains_document_key(&reader, "b").unwrap());
+        assert!(!StoreReader::contains_document_key(&reader, "a").unwrap());
+        assert!(StoreReader::get_document(&reader, "b").unwrap().is_some());
+
+        let mut collector = TotalCountCollector::new();
+        StoreReader::search(&reader, &mut collector, &Query::term(title_field, Term::from_string("hello"))).unwrap();
+        assert_eq!(collector.get_total_count(), 1);
+    }
+}
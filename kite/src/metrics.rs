@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+/// Documents that were inserted or updated (counter)
+pub const DOCS_INDEXED: &'static str = "kite_docs_indexed";
+
+/// Segments that were written to disk (counter)
+pub const SEGMENTS_FLUSHED: &'static str = "kite_segments_flushed";
+
+/// Segments that were merged into larger ones (counter)
+pub const SEGMENTS_MERGED: &'static str = "kite_segments_merged";
+
+/// Time taken to merge segments in seconds (histogram)
+pub const MERGE_DURATION_SECONDS: &'static str = "kite_merge_duration_seconds";
+
+/// Searches that were run (counter)
+pub const SEARCHES: &'static str = "kite_searches";
+
+/// Searches that were stopped by their deadline (counter)
+pub const SEARCH_TIMEOUTS: &'static str = "kite_search_timeouts";
+
+/// Time taken to run a search in seconds (histogram)
+pub const SEARCH_LATENCY_SECONDS: &'static str = "kite_search_latency_seconds";
+
+/// The number of documents collected by a search (histogram)
+pub const SEARCH_HITS: &'static str = "kite_search_hits";
+
+/// Converts a duration into seconds for recording in a histogram
+pub fn duration_to_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+/// Receives metrics from the index
+///
+/// Storage backends report these as they index, merge and search. Implement this to bridge
+/// them to a monitoring system such as Prometheus or statsd. Both methods do nothing by
+/// default so implementations only need to handle the metrics they're interested in.
+pub trait Metrics: Send + Sync {
+    /// Adds "value" to a counter
+    fn increment_counter(&self, _name: &'static str, _value: u64) {}
+
+    /// Records an observation in a histogram
+    fn record_histogram(&self, _name: &'static str, _value: f64) {}
+}
+
+/// Discards all metrics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
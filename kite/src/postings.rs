@@ -0,0 +1,340 @@
+use roaring::RoaringBitmap;
+
+use schema::FieldId;
+use term::TermId;
+use segment::Segment;
+use error::SegmentError;
+
+/// The maximum number of documents in each block of a postings list
+pub const POSTINGS_BLOCK_SIZE: usize = 128;
+
+/// The highest-scoring values in a block of a postings list
+///
+/// Scores increase with term frequency and decrease with field length so these can be
+/// used to calculate an upper bound for the score of any document in the block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockImpact {
+    pub max_term_frequency: u32,
+
+    /// The encoded length of the shortest field in the block
+    pub min_field_length: u8,
+}
+
+/// Splits a term directory into blocks of POSTINGS_BLOCK_SIZE documents
+///
+/// Returns the skip list (the last document in each block) along with the blocks.
+pub fn split_into_blocks(term_directory: &RoaringBitmap) -> (Vec<u32>, Vec<Vec<u32>>) {
+    let mut skip_list = Vec::new();
+    let mut blocks = Vec::new();
+    let mut current_block = Vec::with_capacity(POSTINGS_BLOCK_SIZE);
+
+    for doc_id in term_directory.iter() {
+        current_block.push(doc_id);
+
+        if current_block.len() == POSTINGS_BLOCK_SIZE {
+            skip_list.push(doc_id);
+            blocks.push(current_block);
+            current_block = Vec::with_capacity(POSTINGS_BLOCK_SIZE);
+        }
+    }
+
+    if let Some(last_doc_id) = current_block.last().cloned() {
+        skip_list.push(last_doc_id);
+        blocks.push(current_block);
+    }
+
+    (skip_list, blocks)
+}
+
+/// Iterates over the documents in a postings list in increasing order
+pub trait PostingsIterator {
+    /// Returns the document the iterator is currently positioned on
+    ///
+    /// This is None before the iterator has been moved for the first time and
+    /// after it has been exhausted.
+    fn doc(&self) -> Option<u32>;
+
+    /// Moves to the next document
+    fn next_doc(&mut self) -> Result<Option<u32>, SegmentError>;
+
+    /// Moves to the first document that is greater than or equal to "target"
+    ///
+    /// The iterator never moves backwards so, if it's already positioned on a
+    /// document after the target, it stays where it is.
+    fn advance(&mut self, target: u32) -> Result<Option<u32>, SegmentError>;
+}
+
+/// A postings iterator that loads the blocks of a term's postings list from the segment
+/// as they are needed
+///
+/// Only the skip list is loaded up front. When advancing, the skip list is used to find
+/// the block that may contain the target so any blocks in between are never loaded.
+pub struct BlockPostingsIterator<'a, S: Segment + 'a> {
+    segment: &'a S,
+    field_id: FieldId,
+    term_id: TermId,
+    skip_list: Vec<u32>,
+    block_ord: usize,
+    block: Vec<u32>,
+    position: usize,
+    current_doc: Option<u32>,
+    started: bool,
+}
+
+impl<'a, S: Segment + 'a> BlockPostingsIterator<'a, S> {
+    pub fn new(segment: &'a S, field_id: FieldId, term_id: TermId) -> Result<BlockPostingsIterator<'a, S>, SegmentError> {
+        let skip_list = try!(segment.load_postings_skip_list(field_id, term_id)).unwrap_or_else(Vec::new);
+
+        Ok(BlockPostingsIterator {
+            segment: segment,
+            field_id: field_id,
+            term_id: term_id,
+            skip_list: skip_list,
+            block_ord: 0,
+            block: Vec::new(),
+            position: 0,
+            current_doc: None,
+            started: false,
+        })
+    }
+
+    /// An upper bound on the number of documents in the postings list
+    pub fn cost(&self) -> usize {
+        self.skip_list.len() * POSTINGS_BLOCK_SIZE
+    }
+
+    /// Returns the ord of the block that would contain the target document without loading it
+    ///
+    /// Returns None if the target is after the end of the postings list.
+    pub fn block_for(&self, target: u32) -> Option<usize> {
+        let first_block = if self.started { self.block_ord } else { 0 };
+        let block_ord = first_block + match self.skip_list[first_block..].binary_search(&target) {
+            Ok(i) | Err(i) => i,
+        };
+
+        if block_ord < self.skip_list.len() {
+            Some(block_ord)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the last document in the block
+    pub fn block_last_doc(&self, block_ord: usize) -> u32 {
+        self.skip_list[block_ord]
+    }
+
+    fn load_block(&mut self, block_ord: usize) -> Result<(), SegmentError> {
+        self.block = try!(self.segment.load_postings_block(self.field_id, self.term_id, block_ord as u32)).unwrap_or_else(Vec::new);
+        self.block_ord = block_ord;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<'a, S: Segment + 'a> PostingsIterator for BlockPostingsIterator<'a, S> {
+    fn doc(&self) -> Option<u32> {
+        self.current_doc
+    }
+
+    fn next_doc(&mut self) -> Result<Option<u32>, SegmentError> {
+        if !self.started {
+            return self.advance(0);
+        }
+
+        match self.current_doc {
+            Some(doc_id) if doc_id < u32::max_value() => self.advance(doc_id + 1),
+            _ => {
+                self.current_doc = None;
+                Ok(None)
+            }
+        }
+    }
+
+    fn advance(&mut self, target: u32) -> Result<Option<u32>, SegmentError> {
+        if self.started {
+            match self.current_doc {
+                Some(doc_id) if doc_id >= target => return Ok(Some(doc_id)),
+                Some(_) => {}
+                None => return Ok(None),  // Exhausted
+            }
+        }
+
+        // Find the first block that ends on or after the target
+        let block_ord = match self.block_for(target) {
+            Some(block_ord) => block_ord,
+            None => {
+                // Target is after the end of the postings list
+                self.started = true;
+                self.current_doc = None;
+                return Ok(None);
+            }
+        };
+
+        if !self.started || block_ord != self.block_ord {
+            try!(self.load_block(block_ord));
+            self.started = true;
+        }
+
+        // Find the target within the block
+        self.position += match self.block[self.position..].binary_search(&target) {
+            Ok(i) | Err(i) => i,
+        };
+        self.current_doc = self.block.get(self.position).cloned();
+
+        Ok(self.current_doc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use roaring::RoaringBitmap;
+
+    use schema::FieldId;
+    use term::TermId;
+    use segment::{Segment, SegmentId};
+    use error::SegmentError;
+    use super::{split_into_blocks, BlockPostingsIterator, PostingsIterator, POSTINGS_BLOCK_SIZE};
+
+    struct TestSegment {
+        term_directory: RoaringBitmap,
+        blocks_loaded: RefCell<Vec<u32>>,
+    }
+
+    impl TestSegment {
+        fn new(doc_ids: Vec<u32>) -> TestSegment {
+            TestSegment {
+                term_directory: doc_ids.into_iter().collect(),
+                blocks_loaded: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Segment for TestSegment {
+        fn id(&self) -> SegmentId {
+            SegmentId(1)
+        }
+
+        fn load_statistic(&self, _stat_name: &[u8]) -> Result<Option<i64>, SegmentError> {
+            Ok(None)
+        }
+
+        fn load_stored_field_value_raw(&self, _doc_local_id: u32, _field_id: FieldId, _value_type: &[u8]) -> Result<Option<Vec<u8>>, SegmentError> {
+            Ok(None)
+        }
+
+        fn load_term_directory(&self, _field_id: FieldId, _term_id: TermId) -> Result<Option<RoaringBitmap>, SegmentError> {
+            Ok(Some(self.term_directory.clone()))
+        }
+
+        fn load_postings_block(&self, _field_id: FieldId, _term_id: TermId, block_ord: u32) -> Result<Option<Vec<u32>>, SegmentError> {
+            self.blocks_loaded.borrow_mut().push(block_ord);
+            let (_, mut blocks) = split_into_blocks(&self.term_directory);
+
+            if (block_ord as usize) < blocks.len() {
+                Ok(Some(blocks.swap_remove(block_ord as usize)))
+            } else {
+                Ok(None)
+            }
+        }
+
+        fn load_deletion_list(&self) -> Result<Option<RoaringBitmap>, SegmentError> {
+            Ok(None)
+        }
+
+        fn load_rank_feature_column(&self, _field_id: FieldId) -> Result<Option<Vec<f32>>, SegmentError> {
+            Ok(None)
+        }
+
+        fn load_doc_values_column(&self, _field_id: FieldId) -> Result<Option<Vec<Option<i64>>>, SegmentError> {
+            Ok(None)
+        }
+
+        fn load_field_presence(&self, _field_id: FieldId) -> Result<Option<RoaringBitmap>, SegmentError> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_split_into_blocks() {
+        let term_directory = (0..300).collect::<RoaringBitmap>();
+        let (skip_list, blocks) = split_into_blocks(&term_directory);
+
+        assert_eq!(skip_list, vec![127, 255, 299]);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].len(), POSTINGS_BLOCK_SIZE);
+        assert_eq!(blocks[2].len(), 44);
+    }
+
+    #[test]
+    fn test_next_doc() {
+        let segment = TestSegment::new(vec![1, 5, 200, 65535]);
+        let mut postings = BlockPostingsIterator::new(&segment, FieldId(1), TermId(1)).unwrap();
+
+        assert_eq!(postings.doc(), None);
+        assert_eq!(postings.next_doc().unwrap(), Some(1));
+        assert_eq!(postings.next_doc().unwrap(), Some(5));
+        assert_eq!(postings.next_doc().unwrap(), Some(200));
+        assert_eq!(postings.next_doc().unwrap(), Some(65535));
+        assert_eq!(postings.next_doc().unwrap(), None);
+        assert_eq!(postings.next_doc().unwrap(), None);
+    }
+
+    #[test]
+    fn test_advance() {
+        let segment = TestSegment::new((0..1000).map(|doc_id| doc_id * 2).collect());
+        let mut postings = BlockPostingsIterator::new(&segment, FieldId(1), TermId(1)).unwrap();
+
+        assert_eq!(postings.advance(3).unwrap(), Some(4));
+        assert_eq!(postings.advance(4).unwrap(), Some(4));
+
+        // Shouldn't move backwards
+        assert_eq!(postings.advance(0).unwrap(), Some(4));
+
+        assert_eq!(postings.advance(1500).unwrap(), Some(1500));
+        assert_eq!(postings.doc(), Some(1500));
+        assert_eq!(postings.advance(2000).unwrap(), None);
+        assert_eq!(postings.doc(), None);
+    }
+
+    #[test]
+    fn test_advance_skips_blocks() {
+        let segment = TestSegment::new((0..1000).collect());
+        let mut postings = BlockPostingsIterator::new(&segment, FieldId(1), TermId(1)).unwrap();
+
+        assert_eq!(postings.advance(10).unwrap(), Some(10));
+        assert_eq!(postings.advance(900).unwrap(), Some(900));
+
+        // Only the first and last blocks should've been loaded
+        assert_eq!(*segment.blocks_loaded.borrow(), vec![0, 7]);
+    }
+
+    #[test]
+    fn test_block_for() {
+        let segment = TestSegment::new((0..300).collect());
+        let mut postings = BlockPostingsIterator::new(&segment, FieldId(1), TermId(1)).unwrap();
+
+        assert_eq!(postings.block_for(200), Some(1));
+        assert_eq!(postings.block_last_doc(1), 255);
+        assert_eq!(postings.block_for(300), None);
+
+        // Shouldn't load any blocks
+        assert!(segment.blocks_loaded.borrow().is_empty());
+
+        // Blocks before the current one are never returned
+        postings.advance(280).unwrap();
+        assert_eq!(postings.block_for(10), Some(2));
+    }
+
+    #[test]
+    fn test_empty() {
+        let segment = TestSegment::new(vec![]);
+        let mut postings = BlockPostingsIterator::new(&segment, FieldId(1), TermId(1)).unwrap();
+
+        assert_eq!(postings.cost(), 0);
+        assert_eq!(postings.next_doc().unwrap(), None);
+        assert_eq!(postings.advance(10).unwrap(), None);
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use schema::FieldId;
+use document::FieldValue;
+use term_vector::TermVector;
+
+/// Gives a custom score function access to the stored values of the document being scored
+pub trait DocValues {
+    /// Returns the value of the field, or None if the document doesn't have a value for it
+    fn get(&self, field_id: FieldId) -> Option<FieldValue>;
+
+    fn get_i64(&self, field_id: FieldId) -> Option<i64> {
+        match self.get(field_id) {
+            Some(FieldValue::Integer(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn get_bool(&self, field_id: FieldId) -> Option<bool> {
+        match self.get(field_id) {
+            Some(FieldValue::Boolean(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn get_datetime(&self, field_id: FieldId) -> Option<DateTime<Utc>> {
+        match self.get(field_id) {
+            Some(FieldValue::DateTime(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn get_string(&self, field_id: FieldId) -> Option<String> {
+        match self.get(field_id) {
+            Some(FieldValue::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the positions of each term in the field, or None if the field doesn't record
+    /// term vectors
+    fn get_term_vector(&self, _field_id: FieldId) -> Option<TermVector> {
+        None
+    }
+}
+
+/// A user-provided function that computes the final score of a document
+///
+/// The function is given the score calculated by the wrapped query and an
+/// accessor for the document's stored values.
+#[derive(Clone)]
+pub struct CustomScoreFunction(Arc<dyn Fn(f32, &dyn DocValues) -> f32 + Send + Sync>);
+
+impl CustomScoreFunction {
+    pub fn new<F>(function: F) -> CustomScoreFunction
+        where F: Fn(f32, &dyn DocValues) -> f32 + Send + Sync + 'static
+    {
+        CustomScoreFunction(Arc::new(function))
+    }
+
+    #[inline]
+    pub fn score(&self, base_score: f32, doc_values: &dyn DocValues) -> f32 {
+        (self.0)(base_score, doc_values)
+    }
+}
+
+impl fmt::Debug for CustomScoreFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CustomScoreFunction")
+    }
+}
+
+// Functions can't be compared, so two CustomScoreFunctions are only equal if they
+// share the same underlying function
+impl PartialEq for CustomScoreFunction {
+    fn eq(&self, other: &CustomScoreFunction) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fnv::FnvHashMap;
+
+    use schema::FieldId;
+    use document::FieldValue;
+    use super::{CustomScoreFunction, DocValues};
+
+    struct TestDocValues(FnvHashMap<FieldId, FieldValue>);
+
+    impl DocValues for TestDocValues {
+        fn get(&self, field_id: FieldId) -> Option<FieldValue> {
+            self.0.get(&field_id).cloned()
+        }
+    }
+
+    #[test]
+    fn test_custom_score_function_score() {
+        let mut values = FnvHashMap::default();
+        values.insert(FieldId(1), FieldValue::Integer(10));
+        let doc_values = TestDocValues(values);
+
+        let function = CustomScoreFunction::new(|score, doc_values| {
+            score * doc_values.get_i64(FieldId(1)).unwrap_or(1) as f32
+        });
+
+        assert_eq!(function.score(2.0, &doc_values), 20.0);
+    }
+
+    #[test]
+    fn test_custom_score_function_missing_value() {
+        let doc_values = TestDocValues(FnvHashMap::default());
+
+        let function = CustomScoreFunction::new(|score, doc_values| {
+            score * doc_values.get_i64(FieldId(1)).unwrap_or(1) as f32
+        });
+
+        assert_eq!(function.score(2.0, &doc_values), 2.0);
+    }
+
+    #[test]
+    fn test_custom_score_function_equality() {
+        let function = CustomScoreFunction::new(|score, _| score);
+
+        assert!(function == function.clone());
+        assert!(function != CustomScoreFunction::new(|score, _| score));
+    }
+}
@@ -0,0 +1,148 @@
+use schema::FieldId;
+
+/// A function to apply to the field value before it's combined with the score
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValueModifier {
+    /// Use the value as-is
+    None,
+
+    /// log10(value + 1)
+    Log1p,
+
+    /// ln(value + 1)
+    Ln1p,
+
+    /// sqrt(value)
+    Sqrt,
+
+    /// value ^ 2
+    Square,
+
+    /// 1 / value
+    Reciprocal,
+}
+
+impl FieldValueModifier {
+    #[inline]
+    fn apply(&self, value: f64) -> f64 {
+        match *self {
+            FieldValueModifier::None => value,
+            FieldValueModifier::Log1p => (value + 1.0).log10(),
+            FieldValueModifier::Ln1p => value.ln_1p(),
+            FieldValueModifier::Sqrt => value.sqrt(),
+            FieldValueModifier::Square => value * value,
+            FieldValueModifier::Reciprocal => 1.0 / value,
+        }
+    }
+}
+
+/// How the computed field value is combined with the document's score
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValueCombine {
+    Multiply,
+    Add,
+}
+
+/// Modifies the score of a document using the value of one of its numeric fields
+///
+/// The field value is multiplied by "factor", passed through the "modifier" function and then
+/// combined with the document's original score. Documents that don't have a value for the field
+/// use the "missing" value instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldValueFactor {
+    pub field: FieldId,
+    pub factor: f64,
+    pub modifier: FieldValueModifier,
+    pub missing: f64,
+    pub combine: FieldValueCombine,
+}
+
+impl FieldValueFactor {
+    pub fn new(field: FieldId) -> FieldValueFactor {
+        FieldValueFactor {
+            field: field,
+            factor: 1.0,
+            modifier: FieldValueModifier::None,
+            missing: 1.0,
+            combine: FieldValueCombine::Multiply,
+        }
+    }
+
+    /// Computes the value to combine with the score
+    ///
+    /// Values that cannot be computed (eg, the square root of a negative number) are treated
+    /// as 0 so they never produce a NaN score.
+    pub fn compute(&self, value: Option<f64>) -> f32 {
+        let value = self.modifier.apply(value.unwrap_or(self.missing) * self.factor);
+
+        if value.is_finite() {
+            value as f32
+        } else {
+            0.0f32
+        }
+    }
+
+    /// Combines the field value with the document's original score
+    pub fn apply(&self, score: f32, value: Option<f64>) -> f32 {
+        let value = self.compute(value);
+
+        match self.combine {
+            FieldValueCombine::Multiply => score * value,
+            FieldValueCombine::Add => score + value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use schema::FieldId;
+    use super::{FieldValueFactor, FieldValueModifier, FieldValueCombine};
+
+    #[test]
+    fn test_field_value_factor_default() {
+        let fvf = FieldValueFactor::new(FieldId(1));
+
+        assert_eq!(fvf.apply(2.0, Some(3.0)), 6.0);
+    }
+
+    #[test]
+    fn test_field_value_factor_missing() {
+        let mut fvf = FieldValueFactor::new(FieldId(1));
+        fvf.missing = 4.0;
+
+        assert_eq!(fvf.apply(2.0, None), 8.0);
+    }
+
+    #[test]
+    fn test_field_value_factor_factor_and_modifier() {
+        let mut fvf = FieldValueFactor::new(FieldId(1));
+        fvf.factor = 2.0;
+        fvf.modifier = FieldValueModifier::Sqrt;
+
+        assert_eq!(fvf.apply(1.0, Some(8.0)), 4.0);
+    }
+
+    #[test]
+    fn test_field_value_factor_log1p() {
+        let mut fvf = FieldValueFactor::new(FieldId(1));
+        fvf.modifier = FieldValueModifier::Log1p;
+
+        assert_eq!(fvf.apply(1.0, Some(99.0)), 2.0);
+    }
+
+    #[test]
+    fn test_field_value_factor_add() {
+        let mut fvf = FieldValueFactor::new(FieldId(1));
+        fvf.combine = FieldValueCombine::Add;
+
+        assert_eq!(fvf.apply(2.0, Some(3.0)), 5.0);
+    }
+
+    #[test]
+    fn test_field_value_factor_invalid_value() {
+        let mut fvf = FieldValueFactor::new(FieldId(1));
+        fvf.modifier = FieldValueModifier::Sqrt;
+
+        assert_eq!(fvf.apply(2.0, Some(-1.0)), 0.0);
+    }
+}
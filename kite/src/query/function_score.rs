@@ -0,0 +1,214 @@
+use chrono::{DateTime, Utc, Duration};
+
+use schema::FieldId;
+use document::FieldValue;
+use query::field_value_factor::FieldValueFactor;
+
+/// The shape of the curve a decay function follows as values move away from the origin
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecayShape {
+    /// Falls in a straight line, reaching 0 at twice the distance where it reaches "decay"
+    Linear,
+
+    /// Falls quickly at first and then flattens out
+    Exp,
+
+    /// Falls slowly near the origin, then quickly, then flattens out (a normal distribution)
+    Gauss,
+}
+
+/// Scores documents by how close the value of one of their numeric or date fields is to an origin
+///
+/// Values within "offset" of the origin score 1. Further away, the score falls so that values
+/// that are "scale" past the offset score "decay". Documents that don't have a value for the
+/// field score 1 so they aren't penalised.
+///
+/// Dates are compared in microseconds, use "Decay::datetime" to build a decay function for a
+/// date field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decay {
+    pub field: FieldId,
+    pub shape: DecayShape,
+    pub origin: f64,
+    pub scale: f64,
+    pub offset: f64,
+    pub decay: f64,
+}
+
+impl Decay {
+    pub fn new(field: FieldId, shape: DecayShape, origin: f64, scale: f64) -> Decay {
+        Decay {
+            field: field,
+            shape: shape,
+            origin: origin,
+            scale: scale,
+            offset: 0.0,
+            decay: 0.5,
+        }
+    }
+
+    /// Creates a decay function for a date field
+    pub fn datetime(field: FieldId, shape: DecayShape, origin: DateTime<Utc>, scale: Duration) -> Decay {
+        let origin = FieldValue::DateTime(origin).to_doc_value().unwrap_or(0);
+        let scale = scale.num_microseconds().unwrap_or(i64::max_value());
+        Decay::new(field, shape, origin as f64, scale as f64)
+    }
+
+    /// Computes the score of a field value, between 0 and 1
+    pub fn compute(&self, value: Option<f64>) -> f32 {
+        let value = match value {
+            Some(value) => value,
+            None => return 1.0f32,
+        };
+
+        let distance = ((value - self.origin).abs() - self.offset).max(0.0);
+        let score = match self.shape {
+            DecayShape::Linear => {
+                let zero_at = self.scale / (1.0 - self.decay);
+                ((zero_at - distance) / zero_at).max(0.0)
+            }
+            DecayShape::Exp => {
+                (self.decay.ln() / self.scale * distance).exp()
+            }
+            DecayShape::Gauss => {
+                (self.decay.ln() * (distance * distance) / (self.scale * self.scale)).exp()
+            }
+        };
+
+        if score.is_finite() {
+            score as f32
+        } else {
+            0.0f32
+        }
+    }
+}
+
+/// A function of a document's field value that is combined with its score
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScoreFunction {
+    FieldValueFactor(FieldValueFactor),
+    Decay(Decay),
+}
+
+impl ScoreFunction {
+    /// The field the function reads
+    pub fn field(&self) -> FieldId {
+        match *self {
+            ScoreFunction::FieldValueFactor(ref factor) => factor.field,
+            ScoreFunction::Decay(ref decay) => decay.field,
+        }
+    }
+
+    /// Computes the function's value for a document with the given field value
+    pub fn compute(&self, value: Option<f64>) -> f32 {
+        match *self {
+            ScoreFunction::FieldValueFactor(ref factor) => factor.compute(value),
+            ScoreFunction::Decay(ref decay) => decay.compute(value),
+        }
+    }
+}
+
+impl From<FieldValueFactor> for ScoreFunction {
+    fn from(factor: FieldValueFactor) -> ScoreFunction {
+        ScoreFunction::FieldValueFactor(factor)
+    }
+}
+
+impl From<Decay> for ScoreFunction {
+    fn from(decay: Decay) -> ScoreFunction {
+        ScoreFunction::Decay(decay)
+    }
+}
+
+/// How the values of a FunctionScore query's functions are combined with the document's score
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionScoreCombine {
+    /// Multiply the score by every value
+    Multiply,
+
+    /// Add every value to the score
+    Sum,
+
+    /// Use the highest of the score and the values
+    Max,
+}
+
+impl FunctionScoreCombine {
+    pub fn combine<I: IntoIterator<Item = f32>>(&self, score: f32, values: I) -> f32 {
+        values.into_iter().fold(score, |score, value| {
+            match *self {
+                FunctionScoreCombine::Multiply => score * value,
+                FunctionScoreCombine::Sum => score + value,
+                FunctionScoreCombine::Max => score.max(value),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc, Duration};
+
+    use schema::FieldId;
+    use query::field_value_factor::FieldValueFactor;
+    use super::{Decay, DecayShape, ScoreFunction, FunctionScoreCombine};
+
+    #[test]
+    fn test_decay_at_scale() {
+        // Every shape reaches "decay" at "scale" from the origin
+        for shape in [DecayShape::Linear, DecayShape::Exp, DecayShape::Gauss] {
+            let decay = Decay::new(FieldId(1), shape, 100.0, 10.0);
+
+            assert_eq!(decay.compute(Some(100.0)), 1.0);
+            assert!((decay.compute(Some(110.0)) - 0.5).abs() < 1e-6);
+            assert!((decay.compute(Some(90.0)) - 0.5).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_decay_shapes() {
+        let linear = Decay::new(FieldId(1), DecayShape::Linear, 0.0, 10.0);
+        assert_eq!(linear.compute(Some(15.0)), 0.25);
+        assert_eq!(linear.compute(Some(20.0)), 0.0);
+        assert_eq!(linear.compute(Some(100.0)), 0.0);
+
+        let exp = Decay::new(FieldId(1), DecayShape::Exp, 0.0, 10.0);
+        assert!((exp.compute(Some(20.0)) - 0.25).abs() < 1e-6);
+
+        let gauss = Decay::new(FieldId(1), DecayShape::Gauss, 0.0, 10.0);
+        assert!((gauss.compute(Some(20.0)) - 0.0625).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_decay_offset_and_missing() {
+        let mut decay = Decay::new(FieldId(1), DecayShape::Linear, 0.0, 10.0);
+        decay.offset = 5.0;
+
+        assert_eq!(decay.compute(Some(-5.0)), 1.0);
+        assert_eq!(decay.compute(Some(15.0)), 0.5);
+        assert_eq!(decay.compute(None), 1.0);
+    }
+
+    #[test]
+    fn test_decay_datetime() {
+        let decay = Decay::datetime(FieldId(1), DecayShape::Exp, Utc.ymd(2020, 1, 1).and_hms(0, 0, 0), Duration::days(1));
+
+        assert_eq!(decay.origin, 1577836800000000.0);
+        assert_eq!(decay.scale, 86400000000.0);
+    }
+
+    #[test]
+    fn test_score_function() {
+        let function = ScoreFunction::from(FieldValueFactor::new(FieldId(2)));
+        assert_eq!(function.field(), FieldId(2));
+        assert_eq!(function.compute(Some(3.0)), 3.0);
+    }
+
+    #[test]
+    fn test_function_score_combine() {
+        assert_eq!(FunctionScoreCombine::Multiply.combine(2.0, vec![3.0, 0.5]), 3.0);
+        assert_eq!(FunctionScoreCombine::Sum.combine(2.0, vec![3.0, 0.5]), 5.5);
+        assert_eq!(FunctionScoreCombine::Max.combine(2.0, vec![3.0, 0.5]), 3.0);
+        assert_eq!(FunctionScoreCombine::Max.combine(2.0, vec![]), 2.0);
+    }
+}
@@ -0,0 +1,86 @@
+//! Contains-style ("%foo%") matching of the terms in a field
+//!
+//! Fields with the FIELD_INFIX flag have the character n-grams of each of their terms indexed
+//! into a hidden sub-field, which the infix query searches instead of scanning every term.
+
+use std::collections::HashSet;
+
+use schema::FieldId;
+
+/// The longest n-grams that are indexed
+///
+/// Shorter n-grams are also indexed so substrings of any length can be matched.
+pub const MAX_NGRAM_LENGTH: usize = 3;
+
+/// Field ids are allocated from 1 upwards so the top bit is free to mark hidden sub-fields
+const INFIX_FIELD_BIT: u32 = 1 << 31;
+
+/// Returns the id of the hidden sub-field that the n-grams of a field are indexed into
+pub fn infix_field(field: FieldId) -> FieldId {
+    FieldId(field.0 | INFIX_FIELD_BIT)
+}
+
+/// Returns the n-grams of a term that are indexed, from 1 to MAX_NGRAM_LENGTH characters long
+pub fn index_ngrams(term: &str) -> Vec<String> {
+    let chars = term.chars().collect::<Vec<char>>();
+    let mut seen = HashSet::new();
+    let mut ngrams = Vec::new();
+
+    for length in 1..MAX_NGRAM_LENGTH + 1 {
+        for window in chars.windows(length) {
+            let ngram = window.iter().collect::<String>();
+            if seen.insert(ngram.clone()) {
+                ngrams.push(ngram);
+            }
+        }
+    }
+
+    ngrams
+}
+
+/// Returns the n-grams that a term containing the substring must have
+///
+/// Short substrings are an n-gram themselves, longer ones are split into overlapping
+/// n-grams of MAX_NGRAM_LENGTH characters.
+pub fn query_ngrams(substring: &str) -> Vec<String> {
+    let chars = substring.chars().collect::<Vec<char>>();
+    if chars.len() <= MAX_NGRAM_LENGTH {
+        return if chars.is_empty() { Vec::new() } else { vec![substring.to_string()] };
+    }
+
+    let mut seen = HashSet::new();
+    let mut ngrams = Vec::new();
+    for window in chars.windows(MAX_NGRAM_LENGTH) {
+        let ngram = window.iter().collect::<String>();
+        if seen.insert(ngram.clone()) {
+            ngrams.push(ngram);
+        }
+    }
+
+    ngrams
+}
+
+#[cfg(test)]
+mod tests {
+    use schema::FieldId;
+    use super::{infix_field, index_ngrams, query_ngrams};
+
+    #[test]
+    fn test_infix_field() {
+        assert_ne!(infix_field(FieldId(1)), FieldId(1));
+        assert_ne!(infix_field(FieldId(1)), infix_field(FieldId(2)));
+    }
+
+    #[test]
+    fn test_index_ngrams() {
+        assert_eq!(index_ngrams("abca"), vec!["a", "b", "c", "ab", "bc", "ca", "abc", "bca"]);
+        assert_eq!(index_ngrams("é"), vec!["é"]);
+    }
+
+    #[test]
+    fn test_query_ngrams() {
+        assert_eq!(query_ngrams(""), Vec::<String>::new());
+        assert_eq!(query_ngrams("ab"), vec!["ab"]);
+        assert_eq!(query_ngrams("abcd"), vec!["abc", "bcd"]);
+    }
+}
@@ -1,10 +1,31 @@
 pub mod multi_term_selector;
+pub mod multi_term_rewrite;
 pub mod term_scorer;
+pub mod custom_score;
+pub mod field_value_factor;
+pub mod function_score;
+pub mod rank_feature;
+pub mod rescore;
+pub mod infix;
+pub mod verify;
+pub mod span;
+
+use chrono::{DateTime, Utc};
 
 use term::Term;
 use schema::FieldId;
-use query::multi_term_selector::MultiTermSelector;
+use document::FieldValue;
+use field::{Field, FieldKind, FieldTerm};
+use facet::FacetPath;
+use query::multi_term_selector::{MultiTermSelector, RegexError};
+use query::multi_term_rewrite::{MultiTermRewrite, DEFAULT_MAX_EXPANSIONS};
 use query::term_scorer::TermScorer;
+use query::custom_score::{CustomScoreFunction, DocValues};
+use query::field_value_factor::FieldValueFactor;
+use query::function_score::{ScoreFunction, FunctionScoreCombine};
+use query::rank_feature::RankFeatureFunction;
+use query::verify::MatchVerifier;
+use query::span::SpanQuery;
 
 #[derive(Debug, PartialEq)]
 pub enum Query {
@@ -29,6 +50,32 @@ pub enum Query {
         scorer: TermScorer,
     },
 
+    /// Matches documents where the positions of terms meet the span query's constraints
+    ///
+    /// The field must record term vectors, see the "span" module. Matches are scored like a
+    /// Conjunction of the span query's terms.
+    Span {
+        query: SpanQuery,
+
+        /// The method of scoring each term
+        scorer: TermScorer,
+    },
+
+    /// Matches documents that contain any of the terms in the field
+    ///
+    /// This is cheaper than a Disjunction of Term queries as the terms aren't scored, every
+    /// match is given the same score.
+    Terms {
+        /// The field being searched
+        field: FieldId,
+
+        /// The terms to search for
+        terms: Vec<Term>,
+
+        /// The score to assign to each document
+        score: f32,
+    },
+
     /// Matches documents by a multi term selector
     /// Used for prefix, fuzzy and regex queries
     MultiTerm {
@@ -40,6 +87,15 @@ pub enum Query {
 
         /// The method of scoring each match.
         scorer: TermScorer,
+
+        /// How the selected terms are searched and scored
+        rewrite: MultiTermRewrite,
+
+        /// The most terms that are selected, these are the first ones in term order
+        ///
+        /// None selects every term that matches. TopTermsByDocFrequency chooses its terms
+        /// from all of the matches, so this is ignored for it.
+        max_expansions: Option<usize>,
     },
 
     /// Joins two queries with an AND operator
@@ -61,6 +117,19 @@ pub enum Query {
         queries: Vec<Query>,
     },
 
+    /// Matches documents that match all of the "must" queries, at least "minimum_should_match"
+    /// of the "should" queries and none of the "must_not" queries
+    ///
+    /// If there are no "must" queries, at least one "should" query must match. The scores of
+    /// the "must" and "should" queries are combined by average, "must_not" queries don't
+    /// affect the score.
+    Boolean {
+        must: Vec<Query>,
+        should: Vec<Query>,
+        must_not: Vec<Query>,
+        minimum_should_match: usize,
+    },
+
     /// Removes documents that do not match the "filter" query from the results
     /// Basically the same as a Conjunction query except that the "filter" query does not affect the score
     Filter {
@@ -73,6 +142,145 @@ pub enum Query {
         query: Box<Query>,
         exclude: Box<Query>
     },
+
+    /// Matches the same documents as the "positive" query, multiplying the score of the ones
+    /// that also match the "negative" query by "negative_boost"
+    ///
+    /// This demotes the documents that match the "negative" query rather than excluding them.
+    /// The "negative" query only decides which documents are demoted, it isn't scored.
+    Boosting {
+        positive: Box<Query>,
+        negative: Box<Query>,
+        negative_boost: f32,
+    },
+
+    /// Matches the same documents as the inner query, assigning the specified score to each one
+    /// The inner query is only used as a filter so no scoring is performed for it
+    ConstantScore {
+        query: Box<Query>,
+
+        /// The score to assign to each document
+        score: f32,
+    },
+
+    /// Matches the same documents as the inner query but computes their final score
+    /// by calling a user-provided function
+    CustomScore {
+        query: Box<Query>,
+
+        /// The function to call. This is given the score of the inner query and an accessor
+        /// for the document's stored values
+        function: CustomScoreFunction,
+
+        /// Multiplies the score returned by the function
+        boost: f32,
+    },
+
+    /// Matches the same documents as the inner query, combining their score with
+    /// a function of a numeric field value
+    FieldValueFactor {
+        query: Box<Query>,
+
+        /// The field value function to apply to the score
+        factor: FieldValueFactor,
+    },
+
+    /// Matches the same documents as the inner query, combining their score with the values
+    /// of functions of their field values (such as how close a date is to today)
+    FunctionScore {
+        query: Box<Query>,
+
+        /// The functions to compute for each document
+        functions: Vec<ScoreFunction>,
+
+        /// How the function values are combined with the score of the inner query
+        combine: FunctionScoreCombine,
+    },
+
+    /// Matches documents that have a value for the specified rank feature field, scoring
+    /// them by passing that value through a function
+    RankFeature {
+        /// The rank feature field to read
+        field: FieldId,
+
+        /// The function to convert the value into a score
+        function: RankFeatureFunction,
+
+        /// Multiplies the score
+        boost: f32,
+    },
+
+    /// Matches parent documents that have at least one child that matches the inner query
+    ///
+    /// Children are related to their parents by a join field. The inner query is only used
+    /// to find the children, each parent is assigned the specified score.
+    HasChild {
+        /// The join field of the children
+        join_field: FieldId,
+
+        /// The query the children must match
+        query: Box<Query>,
+
+        /// The score to assign to each parent
+        score: f32,
+    },
+
+    /// Matches child documents whose parent matches the inner query
+    ///
+    /// The inner query is only used to find the parents, each child is assigned the
+    /// specified score.
+    HasParent {
+        /// The join field of the children
+        join_field: FieldId,
+
+        /// The query the parents must match
+        query: Box<Query>,
+
+        /// The score to assign to each child
+        score: f32,
+    },
+
+    /// Matches documents that have any value for the field
+    Exists {
+        field: FieldId,
+
+        /// The score to assign to each document
+        score: f32,
+    },
+
+    /// Matches documents with a doc value in the field between "min" and "max" (inclusive)
+    ///
+    /// Integer, boolean and date fields have doc values. Each segment records the range of
+    /// its doc values, so segments that can't have a match are skipped without reading them.
+    ///
+    /// Doc values are only recorded for stored fields. Integer and date fields that are only
+    /// indexed are searched by their terms instead, see "MultiTermSelector::IntegerRange".
+    Range {
+        field: FieldId,
+
+        /// The smallest value to match, there's no lower bound if this is None
+        min: Option<i64>,
+
+        /// The largest value to match, there's no upper bound if this is None
+        max: Option<i64>,
+
+        /// The score to assign to each document
+        score: f32,
+    },
+
+    /// Matches the documents of the approximation query that the verifier accepts
+    ///
+    /// This is for queries that are expensive to check. The approximation is planned like any
+    /// other query and must match every document that the verifier would accept, the verifier
+    /// is then only called on the candidates that are left after intersecting it with the
+    /// other clauses of a conjunction. Documents are scored by the approximation.
+    Verify {
+        approximation: Box<Query>,
+
+        /// The function to call on each candidate. This is given an accessor for the
+        /// document's stored values
+        verifier: MatchVerifier,
+    },
 }
 
 impl Query {
@@ -92,6 +300,268 @@ impl Query {
         }
     }
 
+    /// Creates a new Span query
+    pub fn span(query: SpanQuery) -> Query {
+        Query::Span {
+            query: query,
+            scorer: TermScorer::default(),
+        }
+    }
+
+    /// Creates a Terms query, matching documents that contain any of the terms
+    ///
+    /// Duplicate terms are removed.
+    pub fn any_term<I: IntoIterator<Item = Term>>(field: FieldId, terms: I) -> Query {
+        let mut terms = terms.into_iter().collect::<Vec<_>>();
+        terms.sort();
+        terms.dedup();
+
+        Query::Terms {
+            field: field,
+            terms: terms,
+            score: 1.0f32,
+        }
+    }
+
+    /// Creates a query that matches documents with any term in the field that the selector matches
+    ///
+    /// At most DEFAULT_MAX_EXPANSIONS terms are searched, see "max_expansions" and "rewrite" to
+    /// change how the terms are expanded.
+    pub fn multi_term(field: FieldId, term_selector: MultiTermSelector) -> Query {
+        Query::MultiTerm {
+            field: field,
+            term_selector: term_selector,
+            scorer: TermScorer::default(),
+            rewrite: MultiTermRewrite::default(),
+            max_expansions: Some(DEFAULT_MAX_EXPANSIONS),
+        }
+    }
+
+    /// Creates a query that matches documents with a term in the field that starts with "prefix"
+    pub fn prefix(field: FieldId, prefix: &str) -> Query {
+        Query::multi_term(field, MultiTermSelector::Prefix(prefix.to_string()))
+    }
+
+    /// Creates a query that matches documents with a term in the field that the whole of a
+    /// regular expression matches, see "MultiTermSelector::Regex"
+    pub fn regex(field: FieldId, pattern: &str) -> Result<Query, RegexError> {
+        MultiTermSelector::regex(pattern).map(|term_selector| Query::multi_term(field, term_selector))
+    }
+
+    /// Sets how a MultiTerm query searches its terms, this has no effect on other queries
+    pub fn rewrite(mut self, new_rewrite: MultiTermRewrite) -> Query {
+        if let Query::MultiTerm{ref mut rewrite, ..} = self {
+            *rewrite = new_rewrite;
+        }
+
+        self
+    }
+
+    /// Sets the most terms a MultiTerm query can expand to (None for no limit), this has no
+    /// effect on other queries
+    pub fn max_expansions(mut self, new_max_expansions: Option<usize>) -> Query {
+        if let Query::MultiTerm{ref mut max_expansions, ..} = self {
+            *max_expansions = new_max_expansions;
+        }
+
+        self
+    }
+
+    /// Creates a term query from a value of the field's type
+    ///
+    /// Values aren't analyzed, so a text value must be a single term.
+    pub fn typed_term<T: FieldKind, V: FieldTerm<T>>(field: Field<T>, value: V) -> Query {
+        Query::term(field.id(), value.to_term())
+    }
+
+    /// Creates a query that matches documents with a term in the field that contains "substring"
+    ///
+    /// The field must be indexed with the FIELD_INFIX flag. Substrings longer than
+    /// MAX_NGRAM_LENGTH characters are matched by all of their n-grams, so this can match a
+    /// document that has every n-gram but not the whole substring in one of its terms. Every
+    /// match is given a score of 1 and an empty substring matches nothing.
+    pub fn infix(field: FieldId, substring: &str) -> Query {
+        let infix_field = infix::infix_field(field);
+        let queries = infix::query_ngrams(substring).into_iter()
+            .map(|ngram| Query::term(infix_field, Term::from_string(&ngram)))
+            .collect::<Vec<Query>>();
+
+        if queries.is_empty() {
+            return Query::None;
+        }
+
+        Query::ConstantScore {
+            query: Box::new(Query::Conjunction { queries: queries }),
+            score: 1.0f32,
+        }
+    }
+
+    /// Creates a query that matches documents that have any value for the field
+    ///
+    /// Use this with "must_not" to find the documents that are missing a value.
+    pub fn exists(field: FieldId) -> Query {
+        Query::Exists {
+            field: field,
+            score: 1.0f32,
+        }
+    }
+
+    /// Creates a query that matches documents with a value in the field between "min" and "max" (inclusive)
+    pub fn range(field: FieldId, min: Option<i64>, max: Option<i64>) -> Query {
+        Query::Range {
+            field: field,
+            min: min,
+            max: max,
+            score: 1.0f32,
+        }
+    }
+
+    /// Creates a query that matches documents with a date in the field between "min" and "max" (inclusive)
+    ///
+    /// For example, to find documents published in the last 30 days:
+    /// "Query::datetime_range(published, Some(now - Duration::days(30)), Some(now))".
+    pub fn datetime_range(field: FieldId, min: Option<DateTime<Utc>>, max: Option<DateTime<Utc>>) -> Query {
+        let to_doc_value = |datetime| FieldValue::DateTime(datetime).to_doc_value();
+        Query::range(field, min.and_then(&to_doc_value), max.and_then(&to_doc_value))
+    }
+
+    /// Creates a query that matches documents in the facet path or any path below it
+    ///
+    /// This is used as a filter to drill down into a facet.
+    pub fn facet(field: FieldId, path: &FacetPath) -> Query {
+        Query::term(field, path.to_term())
+    }
+
+    /// Creates a new RankFeature query
+    pub fn rank_feature(field: FieldId, function: RankFeatureFunction) -> Query {
+        Query::RankFeature {
+            field: field,
+            function: function,
+            boost: 1.0f32,
+        }
+    }
+
+    /// Creates a query that matches parents with a child that matches the query
+    pub fn has_child(join_field: FieldId, query: Query) -> Query {
+        Query::HasChild {
+            join_field: join_field,
+            query: Box::new(query),
+            score: 1.0f32,
+        }
+    }
+
+    /// Creates a query that matches children with a parent that matches the query
+    pub fn has_parent(join_field: FieldId, query: Query) -> Query {
+        Query::HasParent {
+            join_field: join_field,
+            query: Box::new(query),
+            score: 1.0f32,
+        }
+    }
+
+    /// Creates a query that matches documents that match all of the queries
+    pub fn conjunction<I: IntoIterator<Item = Query>>(queries: I) -> Query {
+        Query::Conjunction {
+            queries: queries.into_iter().collect(),
+        }
+    }
+
+    /// Creates a query that matches documents that match any of the queries
+    pub fn disjunction<I: IntoIterator<Item = Query>>(queries: I) -> Query {
+        Query::Disjunction {
+            queries: queries.into_iter().collect(),
+        }
+    }
+
+    /// Creates a query that matches documents that match any of the queries, scoring each
+    /// one by the best query it matches
+    pub fn disjunction_max<I: IntoIterator<Item = Query>>(queries: I) -> Query {
+        Query::DisjunctionMax {
+            queries: queries.into_iter().collect(),
+        }
+    }
+
+    /// Creates a Boolean query, at least one of the "should" queries must match unless there
+    /// are "must" queries (see "minimum_should_match" to require more)
+    pub fn boolean<A, B, C>(must: A, should: B, must_not: C) -> Query
+        where A: IntoIterator<Item = Query>, B: IntoIterator<Item = Query>, C: IntoIterator<Item = Query>
+    {
+        Query::Boolean {
+            must: must.into_iter().collect(),
+            should: should.into_iter().collect(),
+            must_not: must_not.into_iter().collect(),
+            minimum_should_match: 0,
+        }
+    }
+
+    /// Sets how many of a Boolean query's "should" queries must match, this has no effect on
+    /// other queries
+    pub fn minimum_should_match(mut self, minimum: usize) -> Query {
+        if let Query::Boolean{ref mut minimum_should_match, ..} = self {
+            *minimum_should_match = minimum;
+        }
+
+        self
+    }
+
+    /// Requires documents to match both this query and the other query
+    ///
+    /// Chained calls build a single conjunction, so "a.and(b).and(c)" averages the scores of
+    /// all three queries equally.
+    pub fn and(self, other: Query) -> Query {
+        match self {
+            Query::Conjunction{mut queries} => {
+                queries.push(other);
+                Query::Conjunction {
+                    queries: queries,
+                }
+            }
+            query => Query::conjunction(vec![query, other]),
+        }
+    }
+
+    /// Matches documents that match either this query or the other query
+    ///
+    /// Chained calls build a single disjunction, like "and".
+    pub fn or(self, other: Query) -> Query {
+        match self {
+            Query::Disjunction{mut queries} => {
+                queries.push(other);
+                Query::Disjunction {
+                    queries: queries,
+                }
+            }
+            query => Query::disjunction(vec![query, other]),
+        }
+    }
+
+    /// Matches documents that match either this query or the other query, scoring each one
+    /// by the best query it matches
+    pub fn dis_max(self, other: Query) -> Query {
+        match self {
+            Query::DisjunctionMax{mut queries} => {
+                queries.push(other);
+                Query::DisjunctionMax {
+                    queries: queries,
+                }
+            }
+            query => Query::disjunction_max(vec![query, other]),
+        }
+    }
+
+    /// Removes documents that match the other query from the results, same as "exclude"
+    pub fn must_not(self, other: Query) -> Query {
+        self.exclude(other)
+    }
+
+    /// Assigns the same score to every document that matches this query
+    pub fn constant_score(self, score: f32) -> Query {
+        Query::ConstantScore {
+            query: Box::new(self),
+            score: score,
+        }
+    }
+
     /// Filters the query by another query
     /// Only documents that match the other query will remain in the results but the other query will not affect the score
     pub fn filter(self, filter: Query) -> Query {
@@ -109,6 +579,119 @@ impl Query {
         }
     }
 
+    /// Multiplies the score of the documents that also match the other query by "negative_boost"
+    ///
+    /// Use a "negative_boost" between 0 and 1 to demote the documents rather than excluding them.
+    pub fn demote(self, negative: Query, negative_boost: f32) -> Query {
+        Query::Boosting {
+            positive: Box::new(self),
+            negative: Box::new(negative),
+            negative_boost: negative_boost,
+        }
+    }
+
+    /// Computes the score of the documents that match this query with a user-provided function
+    /// The function is given the original score and an accessor for the document's stored values
+    pub fn custom_score<F>(self, function: F) -> Query
+        where F: Fn(f32, &dyn DocValues) -> f32 + Send + Sync + 'static
+    {
+        Query::CustomScore {
+            query: Box::new(self),
+            function: CustomScoreFunction::new(function),
+            boost: 1.0f32,
+        }
+    }
+
+    /// Only matches the documents of this query that a user-provided function accepts
+    ///
+    /// This query is used as a cheap approximation, the function is given an accessor for the
+    /// stored values of each candidate.
+    pub fn verify<F>(self, verifier: F) -> Query
+        where F: Fn(&dyn DocValues) -> bool + Send + Sync + 'static
+    {
+        Query::Verify {
+            approximation: Box::new(self),
+            verifier: MatchVerifier::new(verifier),
+        }
+    }
+
+    /// Combines the score of the documents that match this query with a function of a numeric field value
+    pub fn field_value_factor(self, factor: FieldValueFactor) -> Query {
+        Query::FieldValueFactor {
+            query: Box::new(self),
+            factor: factor,
+        }
+    }
+
+    /// Combines the score of the documents that match this query with the values of functions
+    /// of their field values
+    pub fn function_score<I>(self, functions: I, combine: FunctionScoreCombine) -> Query
+        where I: IntoIterator<Item = ScoreFunction>
+    {
+        Query::FunctionScore {
+            query: Box::new(self),
+            functions: functions.into_iter().collect(),
+            combine: combine,
+        }
+    }
+
+    /// Returns the field and term of every Term query in the query
+    ///
+    /// Terms of MultiTerm queries depend on the index's term dictionary so aren't included.
+    pub fn terms(&self) -> Vec<(FieldId, &Term)> {
+        let mut terms = Vec::new();
+        self.add_terms(&mut terms);
+        terms
+    }
+
+    fn add_terms<'a>(&'a self, terms: &mut Vec<(FieldId, &'a Term)>) {
+        match *self {
+            Query::Term{field, ref term, ..} => terms.push((field, term)),
+            Query::Span{ref query, ..} => {
+                if let Some(field) = query.field() {
+                    for term in query.terms() {
+                        terms.push((field, term));
+                    }
+                }
+            }
+            Query::Terms{field, terms: ref query_terms, ..} => {
+                for term in query_terms.iter() {
+                    terms.push((field, term));
+                }
+            }
+            Query::Conjunction{ref queries} | Query::Disjunction{ref queries} | Query::DisjunctionMax{ref queries} => {
+                for query in queries {
+                    query.add_terms(terms);
+                }
+            }
+            Query::Boolean{ref must, ref should, ref must_not, ..} => {
+                for query in must.iter().chain(should.iter()).chain(must_not.iter()) {
+                    query.add_terms(terms);
+                }
+            }
+            Query::Filter{ref query, ref filter} => {
+                query.add_terms(terms);
+                filter.add_terms(terms);
+            }
+            Query::Exclude{ref query, ref exclude} => {
+                query.add_terms(terms);
+                exclude.add_terms(terms);
+            }
+            Query::Boosting{ref positive, ref negative, ..} => {
+                positive.add_terms(terms);
+                negative.add_terms(terms);
+            }
+            Query::ConstantScore{ref query, ..} | Query::CustomScore{ref query, ..} | Query::FieldValueFactor{ref query, ..} | Query::FunctionScore{ref query, ..} => {
+                query.add_terms(terms);
+            }
+            Query::Verify{ref approximation, ..} => {
+                approximation.add_terms(terms);
+            }
+            // The inner queries of joins match other documents so they don't affect scores
+            Query::All{..} | Query::None | Query::MultiTerm{..} | Query::Exists{..} | Query::Range{..} | Query::RankFeature{..} | Query::HasChild{..} | Query::HasParent{..} => {}
+        }
+    }
+
     #[inline]
     /// Multiplies the score of documents that match the query by the specified "boost" value
     pub fn boost(mut self, boost: f32) -> Query {
@@ -130,7 +713,7 @@ impl Query {
             Query::Term{ref mut scorer, ..} => {
                 scorer.boost *= add_boost;
             }
-            Query::MultiTerm{ref mut scorer, ..} => {
+            Query::MultiTerm{ref mut scorer, ..} | Query::Span{ref mut scorer, ..} => {
                 scorer.boost *= add_boost;
             }
             Query::Conjunction{ref mut queries} => {
@@ -148,12 +731,183 @@ impl Query {
                     query.add_boost(add_boost);
                 }
             }
+            Query::Boolean{ref mut must, ref mut should, ..} => {
+                for query in must.iter_mut().chain(should.iter_mut()) {
+                    query.add_boost(add_boost);
+                }
+            }
             Query::Filter{ref mut query, ..} => {
                 query.add_boost(add_boost);
             }
             Query::Exclude{ref mut query, ..} => {
                 query.add_boost(add_boost);
             }
+            Query::Boosting{ref mut positive, ..} => {
+                positive.add_boost(add_boost);
+            }
+            Query::ConstantScore{ref mut score, ..} => {
+                *score *= add_boost;
+            }
+            Query::CustomScore{ref mut boost, ..} => {
+                *boost *= add_boost;
+            }
+            Query::FieldValueFactor{ref mut query, ..} | Query::FunctionScore{ref mut query, ..} => {
+                query.add_boost(add_boost);
+            }
+            Query::Verify{ref mut approximation, ..} => {
+                approximation.add_boost(add_boost);
+            }
+            Query::RankFeature{ref mut boost, ..} => {
+                *boost *= add_boost;
+            }
+            Query::Terms{ref mut score, ..} | Query::Exists{ref mut score, ..} | Query::Range{ref mut score, ..} | Query::HasChild{ref mut score, ..} | Query::HasParent{ref mut score, ..} => {
+                *score *= add_boost;
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use term::Term;
+    use schema::FieldId;
+
+    use query::multi_term_selector::MultiTermSelector;
+    use query::multi_term_rewrite::{MultiTermRewrite, DEFAULT_MAX_EXPANSIONS};
+    use query::term_scorer::TermScorer;
+
+    use super::Query;
+
+    fn term_query(term: &str) -> Query {
+        Query::term(FieldId(1), Term::from_string(term))
+    }
+
+    #[test]
+    fn test_combinators() {
+        let query = term_query("a").and(term_query("b")).and(term_query("c"));
+        assert_eq!(query, Query::conjunction(vec![term_query("a"), term_query("b"), term_query("c")]));
+
+        let query = term_query("a").or(term_query("b")).and(term_query("c"));
+        assert_eq!(query, Query::Conjunction {
+            queries: vec![
+                Query::Disjunction {
+                    queries: vec![term_query("a"), term_query("b")],
+                },
+                term_query("c"),
+            ],
+        });
+
+        let query = term_query("a").dis_max(term_query("b")).dis_max(term_query("c"));
+        assert_eq!(query, Query::disjunction_max(vec!["a", "b", "c"].into_iter().map(term_query)));
+
+        let query = term_query("a").must_not(term_query("b")).constant_score(2.0);
+        assert_eq!(query, Query::ConstantScore {
+            query: Box::new(Query::Exclude {
+                query: Box::new(term_query("a")),
+                exclude: Box::new(term_query("b")),
+            }),
+            score: 2.0,
+        });
+    }
+
+    #[test]
+    fn test_any_term() {
+        let query = Query::any_term(FieldId(1), vec![Term::from_string("b"), Term::from_string("a"), Term::from_string("b")]).boost(2.0);
+        assert_eq!(query, Query::Terms {
+            field: FieldId(1),
+            terms: vec![Term::from_string("a"), Term::from_string("b")],
+            score: 2.0,
+        });
+        assert_eq!(query.terms(), vec![(FieldId(1), &Term::from_string("a")), (FieldId(1), &Term::from_string("b"))]);
+    }
+
+    #[test]
+    fn test_demote() {
+        let query = term_query("a").demote(term_query("b"), 0.5).boost(2.0);
+        assert_eq!(query, Query::Boosting {
+            positive: Box::new(Query::Term {
+                field: FieldId(1),
+                term: Term::from_string("a"),
+                scorer: TermScorer::default_with_boost(2.0),
+            }),
+            negative: Box::new(term_query("b")),
+            negative_boost: 0.5,
+        });
+    }
+
+    #[test]
+    fn test_multi_term() {
+        let query = Query::prefix(FieldId(1), "hel");
+        assert_eq!(query, Query::MultiTerm {
+            field: FieldId(1),
+            term_selector: MultiTermSelector::Prefix("hel".to_string()),
+            scorer: TermScorer::default(),
+            rewrite: MultiTermRewrite::ScoringBoolean,
+            max_expansions: Some(DEFAULT_MAX_EXPANSIONS),
+        });
+
+        let query = query.rewrite(MultiTermRewrite::ConstantScore).max_expansions(None).boost(2.0);
+        assert_eq!(query, Query::MultiTerm {
+            field: FieldId(1),
+            term_selector: MultiTermSelector::Prefix("hel".to_string()),
+            scorer: TermScorer::default_with_boost(2.0),
+            rewrite: MultiTermRewrite::ConstantScore,
+            max_expansions: None,
+        });
+
+        // Other queries are unchanged
+        assert_eq!(term_query("a").rewrite(MultiTermRewrite::ConstantScore).max_expansions(Some(1)), term_query("a"));
+    }
+
+    #[test]
+    fn test_range() {
+        use chrono::{TimeZone, Utc};
+
+        let query = Query::range(FieldId(1), Some(1), None).boost(2.0);
+        assert_eq!(query, Query::Range {
+            field: FieldId(1),
+            min: Some(1),
+            max: None,
+            score: 2.0,
+        });
+
+        // Dates are compared by their doc values, which are in microseconds
+        let query = Query::datetime_range(FieldId(1), None, Some(Utc.timestamp(10, 0)));
+        assert_eq!(query, Query::range(FieldId(1), None, Some(10000000)));
+    }
+
+    #[test]
+    fn test_function_score() {
+        use query::field_value_factor::FieldValueFactor;
+        use query::function_score::{Decay, DecayShape, FunctionScoreCombine};
+
+        let functions = vec![
+            FieldValueFactor::new(FieldId(2)).into(),
+            Decay::new(FieldId(3), DecayShape::Gauss, 0.0, 10.0).into(),
+        ];
+        let query = term_query("a").function_score(functions.clone(), FunctionScoreCombine::Sum).boost(2.0);
+        assert_eq!(query, Query::FunctionScore {
+            query: Box::new(Query::Term {
+                field: FieldId(1),
+                term: Term::from_string("a"),
+                scorer: TermScorer::default_with_boost(2.0),
+            }),
+            functions: functions,
+            combine: FunctionScoreCombine::Sum,
+        });
+    }
+
+    #[test]
+    fn test_boolean() {
+        let query = Query::boolean(vec![term_query("a")], vec![term_query("b"), term_query("c")], vec![term_query("d")]).minimum_should_match(2);
+        assert_eq!(query, Query::Boolean {
+            must: vec![term_query("a")],
+            should: vec![term_query("b"), term_query("c")],
+            must_not: vec![term_query("d")],
+            minimum_should_match: 2,
+        });
+
+        // Other queries are unchanged
+        assert_eq!(term_query("a").minimum_should_match(2), term_query("a"));
+    }
+}
@@ -0,0 +1,26 @@
+/// The most terms a MultiTerm query expands to unless it's given another limit
+pub const DEFAULT_MAX_EXPANSIONS: usize = 1024;
+
+/// How a MultiTerm query is run once its terms have been selected from the dictionary
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MultiTermRewrite {
+    /// Scores each document by the terms it matched, like a disjunction of term queries
+    ///
+    /// Every selected term is scored separately so this gets slower with each term.
+    ScoringBoolean,
+
+    /// Unions the documents of every term and gives each match the query's boost as its score
+    ///
+    /// Nothing is scored, so this is the cheapest way to run a query that matches many terms.
+    ConstantScore,
+
+    /// Only searches the N selected terms that are in the most documents, scoring them like
+    /// ScoringBoolean
+    TopTermsByDocFrequency(usize),
+}
+
+impl Default for MultiTermRewrite {
+    fn default() -> MultiTermRewrite {
+        MultiTermRewrite::ScoringBoolean
+    }
+}
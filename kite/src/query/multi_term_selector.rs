@@ -1,16 +1,236 @@
+use std::fmt;
+use std::error::Error;
+
+use regex_automata::{Input, Anchored, MatchKind};
+use regex_automata::dfa::{dense, Automaton, StartKind};
+
 use term::Term;
 
+/// The most memory a compiled regex can use, patterns that need more are rejected
+const REGEX_SIZE_LIMIT: usize = 10 * (1 << 20);
+
 #[derive(Debug, PartialEq)]
 pub enum MultiTermSelector {
     Prefix(String),
+
+    /// Selects the terms that the whole of a regular expression matches
+    ///
+    /// The pattern is compiled to a DFA which each term is run through, a term is rejected as
+    /// soon as the DFA can't match it. Patterns are anchored at both ends, so "[a-z]{3}-\d+"
+    /// matches "abc-123" but not "xabc-123" (use ".*" to match anywhere in a term). Invalid
+    /// patterns don't select any terms, use "MultiTermSelector::regex" to check a pattern.
+    Regex(String),
+
+    /// Selects the terms of integer and date values between "min" and "max" (inclusive)
+    ///
+    /// These terms are the 8 byte encoding of the value (dates are in microseconds, the same
+    /// as their doc values). The encoding sorts in value order, so stores that keep the terms
+    /// of a field in order can seek straight to the range, see "integer_term_range".
+    IntegerRange {
+        min: Option<i64>,
+        max: Option<i64>,
+    },
 }
 
 impl MultiTermSelector {
+    /// Creates a Regex selector, returns an error if the pattern can't be compiled
+    pub fn regex(pattern: &str) -> Result<MultiTermSelector, RegexError> {
+        try!(compile_regex(pattern));
+        Ok(MultiTermSelector::Regex(pattern.to_string()))
+    }
+
+    /// Returns the lowest and highest terms an IntegerRange selector can match
+    ///
+    /// Other selectors aren't ranges of terms, so they return None.
+    pub fn integer_term_range(&self) -> Option<(Term, Term)> {
+        match *self {
+            MultiTermSelector::IntegerRange { min, max } => {
+                Some((Term::from_integer(min.unwrap_or(i64::min_value())), Term::from_integer(max.unwrap_or(i64::max_value()))))
+            }
+            _ => None,
+        }
+    }
+
+    /// Compiles the selector so it can be matched against many terms
+    pub fn compile(&self) -> Result<TermMatcher, RegexError> {
+        match *self {
+            MultiTermSelector::Prefix(ref prefix) => Ok(TermMatcher::Prefix(prefix.as_bytes().to_vec())),
+            MultiTermSelector::Regex(ref pattern) => compile_regex(pattern).map(TermMatcher::Regex),
+            MultiTermSelector::IntegerRange { min, max } => Ok(TermMatcher::IntegerRange(min, max)),
+        }
+    }
+
+    /// Returns true if the selector matches the term
+    ///
+    /// Regex selectors are compiled each time this is called, use "compile" to match many terms.
+    pub fn matches(&self, term: &Term) -> bool {
+        match self.compile() {
+            Ok(matcher) => matcher.matches(term),
+            Err(_) => false,
+        }
+    }
+}
+
+fn compile_regex(pattern: &str) -> Result<dense::DFA<Vec<u32>>, RegexError> {
+    let config = dense::Config::new()
+        .start_kind(StartKind::Anchored)
+        .match_kind(MatchKind::All)
+        .dfa_size_limit(Some(REGEX_SIZE_LIMIT))
+        .determinize_size_limit(Some(REGEX_SIZE_LIMIT));
+
+    // The start is anchored by the search, "$" anchors the end
+    dense::Builder::new()
+        .configure(config)
+        .build(&format!("(?:{})$", pattern))
+        .map_err(|e| RegexError(e.to_string()))
+}
+
+/// A compiled MultiTermSelector
+#[derive(Debug)]
+pub enum TermMatcher {
+    Prefix(Vec<u8>),
+    Regex(dense::DFA<Vec<u32>>),
+    IntegerRange(Option<i64>, Option<i64>),
+}
+
+impl TermMatcher {
     pub fn matches(&self, term: &Term) -> bool {
         match *self {
-            MultiTermSelector::Prefix(ref prefix) => {
-                return term.as_bytes().starts_with(prefix.as_bytes());
+            TermMatcher::Prefix(ref prefix) => term.as_bytes().starts_with(prefix),
+            TermMatcher::Regex(ref dfa) => {
+                let bytes = term.as_bytes();
+                let mut state = match dfa.start_state_forward(&Input::new(bytes).anchored(Anchored::Yes)) {
+                    Ok(state) => state,
+                    Err(_) => return false,
+                };
+
+                for byte in bytes {
+                    state = dfa.next_state(state, *byte);
+
+                    // No continuation of the term can match
+                    if dfa.is_dead_state(state) {
+                        return false;
+                    }
+                }
+
+                dfa.is_match_state(dfa.next_eoi_state(state))
+            }
+            TermMatcher::IntegerRange(min, max) => {
+                match term.as_integer() {
+                    Some(value) => min.map_or(true, |min| value >= min) && max.map_or(true, |max| value <= max),
+                    None => false,
+                }
             }
         }
     }
 }
+
+/// A regular expression couldn't be compiled
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegexError(pub String);
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid regex: {}", self.0)
+    }
+}
+
+impl Error for RegexError {}
+
+#[cfg(test)]
+mod tests {
+    use term::Term;
+    use super::MultiTermSelector;
+
+    fn matches(selector: &MultiTermSelector, term: &str) -> bool {
+        selector.compile().unwrap().matches(&Term::from_string(term))
+    }
+
+    #[test]
+    fn test_prefix() {
+        let selector = MultiTermSelector::Prefix("hel".to_string());
+
+        assert!(matches(&selector, "hello"));
+        assert!(matches(&selector, "hel"));
+        assert!(!matches(&selector, "he"));
+        assert!(!matches(&selector, "shell"));
+    }
+
+    #[test]
+    fn test_regex() {
+        let selector = MultiTermSelector::regex(r"[a-z]{3}-\d+").unwrap();
+
+        assert!(matches(&selector, "abc-123"));
+        assert!(matches(&selector, "xyz-9"));
+        assert!(!matches(&selector, "abc-"));
+        assert!(!matches(&selector, "xabc-123"));
+        assert!(!matches(&selector, "abc-123x"));
+        assert!(selector.matches(&Term::from_string("abc-1")));
+    }
+
+    #[test]
+    fn test_regex_alternation() {
+        // A shorter alternative matching first mustn't stop the longer one from matching
+        let selector = MultiTermSelector::regex("a|ab|abc*").unwrap();
+
+        assert!(matches(&selector, "a"));
+        assert!(matches(&selector, "ab"));
+        assert!(matches(&selector, "abccc"));
+        assert!(!matches(&selector, "abd"));
+
+        let selector = MultiTermSelector::regex(".*ell.*").unwrap();
+        assert!(matches(&selector, "hello"));
+        assert!(matches(&selector, "ell"));
+        assert!(!matches(&selector, "help"));
+    }
+
+    #[test]
+    fn test_regex_unicode() {
+        let selector = MultiTermSelector::regex("caf.").unwrap();
+
+        assert!(matches(&selector, "café"));
+        assert!(matches(&selector, "cafe"));
+        assert!(!matches(&selector, "caf"));
+    }
+
+    #[test]
+    fn test_integer_range() {
+        use chrono::{TimeZone, Utc};
+
+        let selector = MultiTermSelector::IntegerRange { min: Some(-5), max: Some(10) };
+        let matcher = selector.compile().unwrap();
+
+        assert!(matcher.matches(&Term::from_integer(-5)));
+        assert!(matcher.matches(&Term::from_integer(10)));
+        assert!(!matcher.matches(&Term::from_integer(11)));
+        assert!(!matcher.matches(&Term::from_integer(-6)));
+        assert!(!matcher.matches(&Term::from_string("a")));
+
+        // Dates are in microseconds
+        let matcher = MultiTermSelector::IntegerRange { min: Some(10000000), max: None }.compile().unwrap();
+        assert!(matcher.matches(&Term::from_datetime(&Utc.timestamp_opt(10, 0).unwrap())));
+        assert!(!matcher.matches(&Term::from_datetime(&Utc.timestamp_opt(9, 999999000).unwrap())));
+    }
+
+    #[test]
+    fn test_integer_term_range() {
+        let selector = MultiTermSelector::IntegerRange { min: Some(-5), max: None };
+        assert_eq!(selector.integer_term_range(), Some((Term::from_integer(-5), Term::from_integer(i64::max_value()))));
+
+        // Every term in the range matches
+        let (first, last) = selector.integer_term_range().unwrap();
+        let matcher = selector.compile().unwrap();
+        assert!(matcher.matches(&first) && matcher.matches(&last));
+
+        assert_eq!(MultiTermSelector::Prefix("a".to_string()).integer_term_range(), None);
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(MultiTermSelector::regex("abc(").is_err());
+
+        let selector = MultiTermSelector::Regex("abc(".to_string());
+        assert!(selector.compile().is_err());
+        assert!(!selector.matches(&Term::from_string("abc(")));
+    }
+}
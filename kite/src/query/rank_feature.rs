@@ -0,0 +1,87 @@
+/// The function used to convert a rank feature value into a score
+#[derive(Debug, Clone, PartialEq)]
+pub enum RankFeatureFunction {
+    /// value / (value + pivot)
+    /// Scores are between 0 and 1 and grow quickly for values below the pivot
+    Saturation {
+        pivot: f32,
+    },
+
+    /// ln(scaling_factor + value)
+    Log {
+        scaling_factor: f32,
+    },
+
+    /// value^exponent / (value^exponent + pivot^exponent)
+    /// Similar to saturation but allows the shape of the curve to be tuned
+    Sigmoid {
+        pivot: f32,
+        exponent: f32,
+    },
+
+    /// Use the value as the score
+    Linear,
+}
+
+impl RankFeatureFunction {
+    pub fn score(&self, value: f32) -> f32 {
+        match *self {
+            RankFeatureFunction::Saturation{pivot} => {
+                value / (value + pivot)
+            }
+            RankFeatureFunction::Log{scaling_factor} => {
+                (scaling_factor + value).ln()
+            }
+            RankFeatureFunction::Sigmoid{pivot, exponent} => {
+                let value_pow = value.powf(exponent);
+                value_pow / (value_pow + pivot.powf(exponent))
+            }
+            RankFeatureFunction::Linear => value,
+        }
+    }
+}
+
+impl Default for RankFeatureFunction {
+    fn default() -> RankFeatureFunction {
+        RankFeatureFunction::Saturation {
+            pivot: 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RankFeatureFunction;
+
+    #[test]
+    fn test_saturation() {
+        let function = RankFeatureFunction::Saturation { pivot: 10.0 };
+
+        assert_eq!(function.score(10.0), 0.5);
+        assert!(function.score(100.0) > function.score(10.0));
+        assert!(function.score(100.0) < 1.0);
+    }
+
+    #[test]
+    fn test_log() {
+        let function = RankFeatureFunction::Log { scaling_factor: 1.0 };
+
+        assert_eq!(function.score(0.0), 0.0);
+        assert!(function.score(10.0) > function.score(1.0));
+    }
+
+    #[test]
+    fn test_sigmoid() {
+        let function = RankFeatureFunction::Sigmoid { pivot: 10.0, exponent: 2.0 };
+
+        assert_eq!(function.score(10.0), 0.5);
+        assert!(function.score(20.0) > RankFeatureFunction::Saturation { pivot: 10.0 }.score(20.0));
+    }
+
+    #[test]
+    fn test_linear() {
+        let function = RankFeatureFunction::Linear;
+
+        assert_eq!(function.score(3.5), 3.5);
+    }
+}
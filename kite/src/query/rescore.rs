@@ -0,0 +1,113 @@
+use query::Query;
+
+/// How the original score and the rescore query's score are combined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RescoreMode {
+    /// Add the scores together
+    Total,
+
+    /// Multiply the scores together
+    Multiply,
+
+    /// Average the scores
+    Avg,
+
+    /// Take the highest score
+    Max,
+
+    /// Take the lowest score
+    Min,
+}
+
+/// A second, more expensive, query to re-score the top results of a search with
+///
+/// Only the top "window_size" documents of the original query are re-scored. Documents
+/// that don't match the rescore query keep their original score (multiplied by "query_weight").
+#[derive(Debug, PartialEq)]
+pub struct Rescore {
+    /// The query to re-score the documents with
+    pub query: Query,
+
+    /// The number of top documents from the original query to re-score
+    pub window_size: usize,
+
+    /// Multiplies the original score
+    pub query_weight: f32,
+
+    /// Multiplies the score of the rescore query
+    pub rescore_query_weight: f32,
+
+    /// How the scores are combined
+    pub mode: RescoreMode,
+}
+
+impl Rescore {
+    pub fn new(query: Query, window_size: usize) -> Rescore {
+        Rescore {
+            query: query,
+            window_size: window_size,
+            query_weight: 1.0f32,
+            rescore_query_weight: 1.0f32,
+            mode: RescoreMode::Total,
+        }
+    }
+
+    /// Computes the new score of a document that matched the rescore query
+    pub fn combine(&self, original_score: f32, rescore_score: f32) -> f32 {
+        let original_score = original_score * self.query_weight;
+        let rescore_score = rescore_score * self.rescore_query_weight;
+
+        match self.mode {
+            RescoreMode::Total => original_score + rescore_score,
+            RescoreMode::Multiply => original_score * rescore_score,
+            RescoreMode::Avg => (original_score + rescore_score) / 2.0,
+            RescoreMode::Max => original_score.max(rescore_score),
+            RescoreMode::Min => original_score.min(rescore_score),
+        }
+    }
+
+    /// Computes the new score of a document that didn't match the rescore query
+    pub fn no_match(&self, original_score: f32) -> f32 {
+        original_score * self.query_weight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use query::Query;
+    use super::{Rescore, RescoreMode};
+
+    #[test]
+    fn test_rescore_total() {
+        let rescore = Rescore::new(Query::all(), 10);
+
+        assert_eq!(rescore.combine(1.0, 2.0), 3.0);
+    }
+
+    #[test]
+    fn test_rescore_weights() {
+        let mut rescore = Rescore::new(Query::all(), 10);
+        rescore.query_weight = 0.5;
+        rescore.rescore_query_weight = 2.0;
+
+        assert_eq!(rescore.combine(1.0, 2.0), 4.5);
+        assert_eq!(rescore.no_match(1.0), 0.5);
+    }
+
+    #[test]
+    fn test_rescore_modes() {
+        let mut rescore = Rescore::new(Query::all(), 10);
+
+        rescore.mode = RescoreMode::Multiply;
+        assert_eq!(rescore.combine(3.0, 2.0), 6.0);
+
+        rescore.mode = RescoreMode::Avg;
+        assert_eq!(rescore.combine(3.0, 2.0), 2.5);
+
+        rescore.mode = RescoreMode::Max;
+        assert_eq!(rescore.combine(3.0, 2.0), 3.0);
+
+        rescore.mode = RescoreMode::Min;
+        assert_eq!(rescore.combine(3.0, 2.0), 2.0);
+    }
+}
@@ -0,0 +1,295 @@
+//! Span queries match terms by their positions in a field
+//!
+//! A span is a range of positions in a field, from "start" up to but not including "end". Span
+//! queries find the spans that their terms occupy in a document and build larger spans from
+//! them, so proximity constraints can be nested (eg, "quick" near "brown fox", with "brown fox"
+//! in order).
+//!
+//! The positions are read from the term vector of each candidate document, so span queries
+//! only match fields that record term vectors.
+
+use term::Term;
+use schema::FieldId;
+use term_vector::TermVector;
+
+/// A range of positions in a field, "end" is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Span {
+    #[inline]
+    pub fn len(&self) -> u32 {
+        self.end - self.start
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpanQuery {
+    /// Matches each position of the term
+    Term {
+        field: FieldId,
+        term: Term,
+    },
+
+    /// Matches where every clause matches close to the others
+    ///
+    /// "slop" is the number of positions allowed between the clauses' spans. When "in_order"
+    /// is set, the clauses must also appear in the order they're given without overlapping.
+    Near {
+        clauses: Vec<SpanQuery>,
+        slop: u32,
+        in_order: bool,
+    },
+}
+
+impl SpanQuery {
+    pub fn term(field: FieldId, term: Term) -> SpanQuery {
+        SpanQuery::Term {
+            field: field,
+            term: term,
+        }
+    }
+
+    pub fn near<I: IntoIterator<Item = SpanQuery>>(clauses: I, slop: u32, in_order: bool) -> SpanQuery {
+        SpanQuery::Near {
+            clauses: clauses.into_iter().collect(),
+            slop: slop,
+            in_order: in_order,
+        }
+    }
+
+    /// The field the query searches
+    ///
+    /// Returns None if the clauses search different fields (or there aren't any), as spans in
+    /// different fields can't be compared so the query never matches.
+    pub fn field(&self) -> Option<FieldId> {
+        match *self {
+            SpanQuery::Term{field, ..} => Some(field),
+            SpanQuery::Near{ref clauses, ..} => {
+                let mut fields = clauses.iter().map(|clause| clause.field());
+                let first = match fields.next() {
+                    Some(field) => field,
+                    None => return None,
+                };
+
+                if fields.all(|field| field == first) {
+                    first
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Returns every term in the query, a document must contain all of them to match
+    pub fn terms(&self) -> Vec<&Term> {
+        let mut terms = Vec::new();
+        self.add_terms(&mut terms);
+        terms
+    }
+
+    fn add_terms<'a>(&'a self, terms: &mut Vec<&'a Term>) {
+        match *self {
+            SpanQuery::Term{ref term, ..} => terms.push(term),
+            SpanQuery::Near{ref clauses, ..} => {
+                for clause in clauses.iter() {
+                    clause.add_terms(terms);
+                }
+            }
+        }
+    }
+
+    /// Finds the spans the query matches in a document's term vector for the field, sorted
+    /// by start position
+    pub fn spans(&self, term_vector: &TermVector) -> Vec<Span> {
+        match *self {
+            SpanQuery::Term{ref term, ..} => {
+                match term_vector.get(term) {
+                    Some(positions) => positions.iter().map(|position| Span { start: position, end: position + 1 }).collect(),
+                    None => Vec::new(),
+                }
+            }
+            SpanQuery::Near{ref clauses, slop, in_order} => {
+                let clause_spans = clauses.iter().map(|clause| clause.spans(term_vector)).collect::<Vec<_>>();
+                if clause_spans.is_empty() || clause_spans.iter().any(|spans| spans.is_empty()) {
+                    return Vec::new();
+                }
+
+                let mut spans = if in_order {
+                    near_ordered(&clause_spans, slop)
+                } else {
+                    near_unordered(&clause_spans, slop)
+                };
+
+                spans.sort();
+                spans.dedup();
+                spans
+            }
+        }
+    }
+
+    /// Returns true if the query matches anywhere in the term vector
+    pub fn matches(&self, term_vector: &TermVector) -> bool {
+        !self.spans(term_vector).is_empty()
+    }
+}
+
+/// Finds the spans where the clauses appear in order
+///
+/// Starting at each span of the first clause, each following clause takes the span that starts
+/// after the previous one ends and ends soonest. The slop is the total of the gaps between them.
+fn near_ordered(clause_spans: &[Vec<Span>], slop: u32) -> Vec<Span> {
+    let mut spans = Vec::new();
+
+    'starts: for first in clause_spans[0].iter() {
+        let mut previous = *first;
+        let mut gaps = 0;
+
+        for candidates in clause_spans[1..].iter() {
+            let next = candidates.iter()
+                .filter(|span| span.start >= previous.end)
+                .min_by_key(|span| span.end);
+
+            match next {
+                Some(next) => {
+                    gaps += next.start - previous.end;
+                    if gaps > slop {
+                        continue 'starts;
+                    }
+
+                    previous = *next;
+                }
+                None => continue 'starts,
+            }
+        }
+
+        spans.push(Span { start: first.start, end: previous.end });
+    }
+
+    spans
+}
+
+/// Finds the spans where the clauses appear in any order
+///
+/// Each span of each clause is tried as the start of a match, with the other clauses taking
+/// the span that starts at or after it and ends soonest. The slop is the width of the match
+/// minus the lengths of the spans in it.
+fn near_unordered(clause_spans: &[Vec<Span>], slop: u32) -> Vec<Span> {
+    let mut spans = Vec::new();
+
+    for (i, firsts) in clause_spans.iter().enumerate() {
+        'starts: for first in firsts.iter() {
+            let mut end = first.end;
+            let mut length = first.len();
+
+            for (j, candidates) in clause_spans.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+
+                let next = candidates.iter()
+                    .filter(|span| span.start >= first.start)
+                    .min_by_key(|span| span.end);
+
+                match next {
+                    Some(next) => {
+                        end = end.max(next.end);
+                        length += next.len();
+                    }
+                    None => continue 'starts,
+                }
+            }
+
+            if (end - first.start).saturating_sub(length) <= slop {
+                spans.push(Span { start: first.start, end: end });
+            }
+        }
+    }
+
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use term::Term;
+    use token::Token;
+    use schema::FieldId;
+    use term_vector::TermVector;
+
+    use super::{Span, SpanQuery};
+
+    fn term_vector(text: &str) -> TermVector {
+        text.split_whitespace().enumerate().map(|(position, word)| {
+            Token { term: Term::from_string(word), position: position as u32, offsets: None }
+        }).collect::<Vec<_>>().into()
+    }
+
+    fn span_term(term: &str) -> SpanQuery {
+        SpanQuery::term(FieldId(1), Term::from_string(term))
+    }
+
+    #[test]
+    fn test_span_term() {
+        let tv = term_vector("the quick brown fox jumps over the lazy dog");
+
+        assert_eq!(span_term("the").spans(&tv), vec![Span { start: 0, end: 1 }, Span { start: 6, end: 7 }]);
+        assert_eq!(span_term("cat").spans(&tv), vec![]);
+    }
+
+    #[test]
+    fn test_span_near_ordered() {
+        let tv = term_vector("the quick brown fox jumps over the lazy dog");
+
+        let query = SpanQuery::near(vec![span_term("quick"), span_term("fox")], 1, true);
+        assert_eq!(query.spans(&tv), vec![Span { start: 1, end: 4 }]);
+
+        // Not enough slop
+        let query = SpanQuery::near(vec![span_term("quick"), span_term("fox")], 0, true);
+        assert!(!query.matches(&tv));
+
+        // Wrong order
+        let query = SpanQuery::near(vec![span_term("fox"), span_term("quick")], 5, true);
+        assert!(!query.matches(&tv));
+
+        // Each position of the first clause is tried
+        let query = SpanQuery::near(vec![span_term("the"), span_term("lazy")], 0, true);
+        assert_eq!(query.spans(&tv), vec![Span { start: 6, end: 8 }]);
+    }
+
+    #[test]
+    fn test_span_near_unordered() {
+        let tv = term_vector("the quick brown fox jumps over the lazy dog");
+
+        let query = SpanQuery::near(vec![span_term("fox"), span_term("quick")], 1, false);
+        assert_eq!(query.spans(&tv), vec![Span { start: 1, end: 4 }]);
+
+        let query = SpanQuery::near(vec![span_term("fox"), span_term("quick")], 0, false);
+        assert!(!query.matches(&tv));
+
+        let query = SpanQuery::near(vec![span_term("dog"), span_term("the")], 1, false);
+        assert_eq!(query.spans(&tv), vec![Span { start: 6, end: 9 }]);
+    }
+
+    #[test]
+    fn test_span_near_nested() {
+        let tv = term_vector("the quick brown fox jumps over the lazy dog");
+
+        // "jumps" within two positions of the phrase "brown fox"
+        let phrase = SpanQuery::near(vec![span_term("brown"), span_term("fox")], 0, true);
+        let query = SpanQuery::near(vec![span_term("jumps"), phrase.clone()], 0, false);
+        assert_eq!(query.spans(&tv), vec![Span { start: 2, end: 5 }]);
+
+        let query = SpanQuery::near(vec![span_term("lazy"), phrase], 2, false);
+        assert!(!query.matches(&tv));
+    }
+
+    #[test]
+    fn test_span_field() {
+        assert_eq!(SpanQuery::near(vec![span_term("a"), span_term("b")], 0, true).field(), Some(FieldId(1)));
+        assert_eq!(SpanQuery::near(vec![span_term("a"), SpanQuery::term(FieldId(2), Term::from_string("b"))], 0, true).field(), None);
+        assert_eq!(SpanQuery::near(vec![], 0, true).field(), None);
+    }
+}
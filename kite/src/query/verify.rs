@@ -0,0 +1,77 @@
+//! Two-phase matching for queries that are expensive to check
+//!
+//! A verified query is made up of a cheap approximation, which is planned like any other query
+//! and must match every document the query matches, and a verifier that's called on each
+//! candidate to decide whether it really matches. When a verified query is intersected with
+//! other queries, the intersection is computed first so only the documents that match the
+//! cheaper clauses are verified.
+
+use std::fmt;
+use std::sync::Arc;
+
+use query::custom_score::DocValues;
+
+/// A user-provided function that decides whether a candidate document matches
+///
+/// The function is given an accessor for the document's stored values.
+#[derive(Clone)]
+pub struct MatchVerifier(Arc<dyn Fn(&dyn DocValues) -> bool + Send + Sync>);
+
+impl MatchVerifier {
+    pub fn new<F>(function: F) -> MatchVerifier
+        where F: Fn(&dyn DocValues) -> bool + Send + Sync + 'static
+    {
+        MatchVerifier(Arc::new(function))
+    }
+
+    #[inline]
+    pub fn matches(&self, doc_values: &dyn DocValues) -> bool {
+        (self.0)(doc_values)
+    }
+}
+
+impl fmt::Debug for MatchVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MatchVerifier")
+    }
+}
+
+// Functions can't be compared, so two MatchVerifiers are only equal if they share the
+// same underlying function
+impl PartialEq for MatchVerifier {
+    fn eq(&self, other: &MatchVerifier) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fnv::FnvHashMap;
+
+    use schema::FieldId;
+    use document::FieldValue;
+    use query::custom_score::DocValues;
+    use super::MatchVerifier;
+
+    struct TestDocValues(FnvHashMap<FieldId, FieldValue>);
+
+    impl DocValues for TestDocValues {
+        fn get(&self, field_id: FieldId) -> Option<FieldValue> {
+            self.0.get(&field_id).cloned()
+        }
+    }
+
+    #[test]
+    fn test_match_verifier() {
+        let mut values = FnvHashMap::default();
+        values.insert(FieldId(1), FieldValue::Integer(10));
+        let doc_values = TestDocValues(values);
+
+        let verifier = MatchVerifier::new(|doc_values| doc_values.get_i64(FieldId(1)) == Some(10));
+        assert!(verifier.matches(&doc_values));
+        assert!(!verifier.matches(&TestDocValues(FnvHashMap::default())));
+
+        assert!(verifier == verifier.clone());
+        assert!(verifier != MatchVerifier::new(|_| true));
+    }
+}
@@ -1,14 +1,19 @@
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::fmt;
+use std::error::Error;
 
 use serde::{Serialize, Deserialize, Serializer, Deserializer};
 use fnv::FnvHashMap;
 
+use field::{Field, FieldKind};
+
 bitflags! {
     pub flags FieldFlags: u32 {
         const FIELD_INDEXED = 0b00000001,
         const FIELD_STORED  = 0b00000010,
+        const FIELD_TERM_VECTORS = 0b00000100,
+        const FIELD_INFIX = 0b00001000,
     }
 }
 
@@ -26,6 +31,14 @@ impl Serialize for FieldFlags {
             flag_strings.push("STORED");
         }
 
+        if self.contains(FIELD_TERM_VECTORS) {
+            flag_strings.push("TERM_VECTORS");
+        }
+
+        if self.contains(FIELD_INFIX) {
+            flag_strings.push("INFIX");
+        }
+
         serializer.serialize_str(&flag_strings.join("|"))
     }
 }
@@ -56,6 +69,12 @@ impl<'a> Deserialize<'a> for FieldFlags {
                         "STORED" => {
                             flags |= FIELD_STORED;
                         }
+                        "TERM_VECTORS" => {
+                            flags |= FIELD_TERM_VECTORS;
+                        }
+                        "INFIX" => {
+                            flags |= FIELD_INFIX;
+                        }
                         _ => {} // TODO: error
                     }
                 }
@@ -75,6 +94,22 @@ pub enum FieldType {
     I64,
     Boolean,
     DateTime,
+
+    /// A positive per-document weight that can be used for scoring by the RankFeature query
+    RankFeature,
+
+    /// Weighted inputs for autocompletion, these can only be searched by prefix through
+    /// the completion suggester
+    Completion,
+
+    /// Hierarchical paths such as "Electronics/Cameras/DSLR", indexed with FacetPath
+    Facet,
+
+    /// Relates a child document to its parent, searched with the HasChild and HasParent queries
+    ///
+    /// Child documents index the key of their parent as a single term. Parents and children
+    /// are separate documents so either can be updated without reindexing the other.
+    Join,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,6 +127,10 @@ impl FieldInfo {
             field_flags: field_flags,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
@@ -136,6 +175,34 @@ impl<'a> Deserialize<'a> for FieldId {
 #[derive(Debug)]
 pub enum AddFieldError {
     FieldAlreadyExists(String),
+
+    /// The storage backend failed to save the schema
+    Storage(Box<dyn Error + Send + Sync>),
+}
+
+impl AddFieldError {
+    /// Wraps an error from the storage backend
+    pub fn storage<E: Error + Send + Sync + 'static>(e: E) -> AddFieldError {
+        AddFieldError::Storage(Box::new(e))
+    }
+}
+
+impl fmt::Display for AddFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AddFieldError::FieldAlreadyExists(ref name) => write!(f, "field {:?} already exists", name),
+            AddFieldError::Storage(ref e) => write!(f, "couldn't save the schema: {}", e),
+        }
+    }
+}
+
+impl Error for AddFieldError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            AddFieldError::FieldAlreadyExists(_) => None,
+            AddFieldError::Storage(ref e) => Some(&**e),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -179,6 +246,24 @@ impl Schema {
         Ok(field_id)
     }
 
+    /// Adds a field, returning a handle that only accepts values of the field's type
+    pub fn add_typed_field<T: FieldKind>(&mut self, name: String, field_flags: FieldFlags) -> Result<Field<T>, AddFieldError> {
+        self.add_field(name, T::field_type(), field_flags).map(Field::new)
+    }
+
+    /// Finds a field by name, returns None if it doesn't exist or isn't of type T
+    pub fn typed_field<T: FieldKind>(&self, name: &str) -> Option<Field<T>> {
+        self.get_field_by_name(name)
+            .and_then(|field_id| self.fields.get(&field_id).map(|field_info| (field_id, field_info)))
+            .and_then(|(field_id, field_info)| {
+                if field_info.field_type == T::field_type() {
+                    Some(Field::new(field_id))
+                } else {
+                    None
+                }
+            })
+    }
+
     pub fn remove_field(&mut self, field_id: &FieldId) -> bool {
         match self.fields.remove(field_id) {
             Some(removed_field) => {
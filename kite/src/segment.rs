@@ -3,18 +3,53 @@ use roaring::RoaringBitmap;
 use schema::FieldId;
 use term::TermId;
 use document::DocId;
+use postings::{split_into_blocks, BlockImpact};
+use error::SegmentError;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct SegmentId(pub u32);
 
 pub trait Segment {
-    fn load_statistic(&self, stat_name: &[u8]) -> Result<Option<i64>, String>;
-    fn load_stored_field_value_raw(&self, doc_local_id: u16, field_id: FieldId, value_type: &[u8]) -> Result<Option<Vec<u8>>, String>;
-    fn load_term_directory(&self, field_id: FieldId, term_id: TermId) -> Result<Option<RoaringBitmap>, String>;
-    fn load_deletion_list(&self) -> Result<Option<RoaringBitmap>, String>;
+    fn load_statistic(&self, stat_name: &[u8]) -> Result<Option<i64>, SegmentError>;
+    fn load_stored_field_value_raw(&self, doc_local_id: u32, field_id: FieldId, value_type: &[u8]) -> Result<Option<Vec<u8>>, SegmentError>;
+    fn load_term_directory(&self, field_id: FieldId, term_id: TermId) -> Result<Option<RoaringBitmap>, SegmentError>;
+    fn load_deletion_list(&self) -> Result<Option<RoaringBitmap>, SegmentError>;
+    fn load_rank_feature_column(&self, field_id: FieldId) -> Result<Option<Vec<f32>>, SegmentError>;
+
+    /// Loads the doc values of a field, indexed by document ord
+    ///
+    /// Documents without a value for the field are None.
+    fn load_doc_values_column(&self, field_id: FieldId) -> Result<Option<Vec<Option<i64>>>, SegmentError>;
+
+    /// Loads the set of documents that have any value for a field
+    fn load_field_presence(&self, field_id: FieldId) -> Result<Option<RoaringBitmap>, SegmentError>;
+
+    /// Loads the last document of each block in a term's postings list
+    ///
+    /// By default, this is derived from the term directory. Segments that store postings in
+    /// blocks should override this and load_postings_block so they can be read lazily.
+    fn load_postings_skip_list(&self, field_id: FieldId, term_id: TermId) -> Result<Option<Vec<u32>>, SegmentError> {
+        Ok(try!(self.load_term_directory(field_id, term_id)).map(|term_directory| split_into_blocks(&term_directory).0))
+    }
+
+    /// Loads a single block of a term's postings list
+    fn load_postings_block(&self, field_id: FieldId, term_id: TermId, block_ord: u32) -> Result<Option<Vec<u32>>, SegmentError> {
+        Ok(try!(self.load_term_directory(field_id, term_id)).and_then(|term_directory| {
+            split_into_blocks(&term_directory).1.into_iter().nth(block_ord as usize)
+        }))
+    }
+
+    /// Loads the impacts of each block in a term's postings list
+    ///
+    /// Returns None if the segment doesn't store impacts, in which case scores can't be
+    /// bounded so no documents are skipped.
+    fn load_postings_impacts(&self, _field_id: FieldId, _term_id: TermId) -> Result<Option<Vec<BlockImpact>>, SegmentError> {
+        Ok(None)
+    }
+
     fn id(&self) -> SegmentId;
 
-    fn doc_id(&self, local_id: u16) -> DocId {
+    fn doc_id(&self, local_id: u32) -> DocId {
         DocId(self.id(), local_id)
     }
 }
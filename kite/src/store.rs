@@ -0,0 +1,60 @@
+//! Traits for the storage backends of an index
+//!
+//! Applications can be written against these traits instead of a particular backend (such as
+//! RocksDBStore in kite_rocksdb or MemoryStore in this crate) so the backend can be swapped out.
+//! Backends usually have more features than the traits cover, these are the operations every
+//! backend must support.
+
+use document::Document;
+use schema::{Schema, FieldType, FieldFlags, FieldId, AddFieldError};
+use segment::SegmentId;
+use field::{Field, FieldKind};
+use collectors::Collector;
+use query::Query;
+use error::{StoreError, SearchError};
+
+/// An index that documents can be written to
+///
+/// Writes are visible to readers that are opened after they return.
+pub trait Store {
+    /// A consistent view of the index, see StoreReader
+    type Reader<'a>: StoreReader where Self: 'a;
+
+    fn schema(&self) -> &Schema;
+
+    fn add_field(&mut self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError>;
+
+    /// Adds a field, returning a handle that only accepts values of the field's type
+    fn add_typed_field<T: FieldKind>(&mut self, name: String, field_flags: FieldFlags) -> Result<Field<T>, AddFieldError> {
+        self.add_field(name, T::field_type(), field_flags).map(Field::new)
+    }
+
+    /// Removes a field from the schema, returns false if there wasn't a field with the id
+    fn remove_field(&mut self, field_id: &FieldId) -> Result<bool, StoreError>;
+
+    /// Indexes a document, replacing the document with the same key if there is one
+    fn insert_or_update_document(&self, doc: &Document) -> Result<(), StoreError>;
+
+    /// Deletes the document with the key, returns false if there wasn't one
+    fn remove_document_by_key(&self, doc_key: &str) -> Result<bool, StoreError>;
+
+    /// Opens a reader that sees the documents in the index at the time it's opened
+    fn reader<'a>(&'a self) -> Result<Self::Reader<'a>, StoreError>;
+}
+
+/// A view of an index that doesn't change while it's open
+pub trait StoreReader {
+    fn schema(&self) -> &Schema;
+
+    /// The segments the reader sees, in no particular order
+    fn segments(&self) -> Vec<SegmentId>;
+
+    fn contains_document_key(&self, doc_key: &str) -> Result<bool, StoreError>;
+
+    /// Fetches a document by its key
+    ///
+    /// Only the values of stored fields can be read back, others are left empty.
+    fn get_document(&self, doc_key: &str) -> Result<Option<Document>, StoreError>;
+
+    fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), SearchError>;
+}
@@ -1,5 +1,5 @@
 use chrono::{DateTime, Utc, Timelike};
-use byteorder::{WriteBytesExt, LittleEndian};
+use byteorder::{ByteOrder, WriteBytesExt, BigEndian, LittleEndian};
 
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
@@ -32,19 +32,37 @@ impl Term {
         }
     }
 
+    /// Integers are big endian with the sign bit flipped, so their terms sort in value order
     pub fn from_integer(value: i64) -> Term {
         let mut bytes = Vec::with_capacity(8);
-        bytes.write_i64::<LittleEndian>(value).unwrap();
+        bytes.write_u64::<BigEndian>(value as u64 ^ (1 << 63)).unwrap();
         Term(bytes)
     }
 
+    /// Dates are encoded like integers, as the number of microseconds since the epoch
     pub fn from_datetime(value: &DateTime<Utc>) -> Term {
-        let mut bytes = Vec::with_capacity(0);
         let timestamp = value.timestamp();
         let micros = value.nanosecond() / 1000;
-        let timestamp_with_micros = timestamp * 1000000 + micros as i64;
-        bytes.write_i64::<LittleEndian>(timestamp_with_micros).unwrap();
-        Term(bytes)
+        Term::from_integer(timestamp * 1000000 + micros as i64)
+    }
+
+    /// Converts an integer or date term from the little endian encoding used by older
+    /// versions of kite, returns None if the term isn't 8 bytes long
+    pub fn from_little_endian_integer(bytes: &[u8]) -> Option<Term> {
+        if bytes.len() != 8 {
+            return None;
+        }
+
+        Some(Term::from_integer(LittleEndian::read_i64(bytes)))
+    }
+
+    /// Decodes an integer or date term, returns None if the term isn't 8 bytes long
+    pub fn as_integer(&self) -> Option<i64> {
+        if self.0.len() != 8 {
+            return None;
+        }
+
+        Some((BigEndian::read_u64(&self.0) ^ (1 << 63)) as i64)
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -98,14 +116,36 @@ mod tests {
     fn test_integer_to_bytes() {
         let term = Term::from_integer(123);
 
-        assert_eq!(term.as_bytes().to_vec(), vec![123, 0, 0, 0, 0, 0, 0, 0])
+        assert_eq!(term.as_bytes().to_vec(), vec![128, 0, 0, 0, 0, 0, 0, 123])
     }
 
     #[test]
     fn test_negative_integer_to_bytes() {
         let term = Term::from_integer(-123);
 
-        assert_eq!(term.as_bytes().to_vec(), vec![133, 255, 255, 255, 255, 255, 255, 255])
+        assert_eq!(term.as_bytes().to_vec(), vec![127, 255, 255, 255, 255, 255, 255, 133])
+    }
+
+    #[test]
+    fn test_integers_sort_in_value_order() {
+        let values = [i64::min_value(), -1000000, -123, -1, 0, 1, 123, 256, 1000000, i64::max_value()];
+        let terms = values.iter().map(|value| Term::from_integer(*value)).collect::<Vec<_>>();
+
+        let mut sorted_terms = terms.clone();
+        sorted_terms.sort();
+        assert_eq!(sorted_terms, terms);
+
+        for (value, term) in values.iter().zip(terms.iter()) {
+            assert_eq!(term.as_integer(), Some(*value));
+        }
+        assert_eq!(Term::from_string("foo").as_integer(), None);
+    }
+
+    #[test]
+    fn test_from_little_endian_integer() {
+        assert_eq!(Term::from_little_endian_integer(&[123, 0, 0, 0, 0, 0, 0, 0]), Some(Term::from_integer(123)));
+        assert_eq!(Term::from_little_endian_integer(&[133, 255, 255, 255, 255, 255, 255, 255]), Some(Term::from_integer(-123)));
+        assert_eq!(Term::from_little_endian_integer(b"foo"), None);
     }
 
     #[test]
@@ -113,7 +153,7 @@ mod tests {
         let date = "2016-07-23T16:15:00+01:00".parse::<DateTime<Utc>>().unwrap();
         let term = Term::from_datetime(&date);
 
-        assert_eq!(term.as_bytes().to_vec(), vec![0, 101, 191, 3, 79, 56, 5, 0])
+        assert_eq!(term.as_bytes().to_vec(), vec![128, 5, 56, 79, 3, 191, 101, 0])
     }
 
     #[test]
@@ -123,7 +163,7 @@ mod tests {
         let term = Term::from_datetime(&date);
 
         // This is exactly 123123 higher than the result of "test_datetime_to_bytes"
-        assert_eq!(term.as_bytes().to_vec(), vec![243, 69, 193, 3, 79, 56, 5, 0])
+        assert_eq!(term.as_bytes().to_vec(), vec![128, 5, 56, 79, 3, 193, 69, 243])
     }
 
     #[test]
@@ -132,6 +172,6 @@ mod tests {
         let term = Term::from_datetime(&date);
 
         // This is exactly 3_600_000_000 lower than the result of "test_datetime_to_bytes"
-        assert_eq!(term.as_bytes().to_vec(), vec![0, 193, 43, 45, 78, 56, 5, 0])
+        assert_eq!(term.as_bytes().to_vec(), vec![128, 5, 56, 78, 45, 43, 193, 0])
     }
 }
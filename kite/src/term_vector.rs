@@ -1,5 +1,5 @@
 use std::ops::{Deref, DerefMut};
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 
 use roaring::RoaringBitmap;
 
@@ -7,11 +7,21 @@ use term::Term;
 use token::Token;
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct TermVector(HashMap<Term, RoaringBitmap>);
+pub struct TermVector {
+    terms: HashMap<Term, RoaringBitmap>,
+
+    /// The start and end byte offsets of the token at each position
+    ///
+    /// Only positions of tokens that were given offsets by the analyzer are included.
+    pub offsets: BTreeMap<u32, (u32, u32)>,
+}
 
 impl TermVector {
     pub fn new() -> TermVector {
-        TermVector(HashMap::new())
+        TermVector {
+            terms: HashMap::new(),
+            offsets: BTreeMap::new(),
+        }
     }
 }
 
@@ -19,26 +29,30 @@ impl Deref for TermVector {
     type Target = HashMap<Term, RoaringBitmap>;
 
     fn deref(&self) -> &HashMap<Term, RoaringBitmap> {
-        &self.0
+        &self.terms
     }
 }
 
 impl DerefMut for TermVector {
     fn deref_mut(&mut self) -> &mut HashMap<Term, RoaringBitmap> {
-        &mut self.0
+        &mut self.terms
     }
 }
 
 impl Into<TermVector> for Vec<Token> {
     fn into(self) -> TermVector {
-        let mut map = HashMap::new();
+        let mut term_vector = TermVector::new();
 
         for token in self {
-            let positions = map.entry(token.term).or_insert_with(RoaringBitmap::new);
+            if let Some(offsets) = token.offsets {
+                term_vector.offsets.insert(token.position, offsets);
+            }
+
+            let positions = term_vector.terms.entry(token.term).or_insert_with(RoaringBitmap::new);
             positions.insert(token.position);
         }
 
-         TermVector(map)
+        term_vector
     }
 }
 
@@ -46,9 +60,10 @@ impl Into<Vec<Token>> for TermVector {
     fn into(self) -> Vec<Token> {
         let mut vec = Vec::new();
 
-        for (term, positions) in self.0 {
+        for (term, positions) in self.terms {
             for position in positions {
-                vec.push(Token { term: term.clone(), position: position });
+                let offsets = self.offsets.get(&position).cloned();
+                vec.push(Token { term: term.clone(), position: position, offsets: offsets });
             }
         }
 
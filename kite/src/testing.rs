@@ -0,0 +1,786 @@
+//! Helpers for testing storage backends
+//!
+//! Storage backends give the query engine access to their data by implementing the "Segment"
+//! trait. This module contains the pieces needed to check that a backend does this correctly,
+//! without each backend having to write its own test suite:
+//!
+//!  - "MockSegment", an in-memory segment that can be built from documents. It's used as the
+//!    reference that other backends are compared with, and can stand in for a real segment
+//!    when testing code that reads segments.
+//!  - "CorpusGenerator", which generates reproducible documents for any schema
+//!  - "check_segment", which compares everything a segment returns with a reference segment
+//!
+//! A backend's tests would generate a corpus, write it into a segment of the backend and into
+//! a MockSegment, then check that the two segments agree.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::u32;
+
+use chrono::{TimeZone, Utc};
+use roaring::RoaringBitmap;
+use fnv::FnvHashMap;
+
+use term::{Term, TermId};
+use schema::{Schema, FieldId, FieldType};
+use document::Document;
+use document_builder::DocumentBuilder;
+use segment::{Segment, SegmentId};
+use postings::{split_into_blocks, BlockImpact, BlockPostingsIterator, PostingsIterator};
+use error::SegmentError;
+
+/// The error that a MockSegment returns from every read after "fail_reads" has been called
+#[derive(Debug)]
+pub struct MockStorageError;
+
+impl ::std::fmt::Display for MockStorageError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "mock segment read failure")
+    }
+}
+
+impl ::std::error::Error for MockStorageError {}
+
+/// An in-memory segment
+///
+/// Postings are derived from the term directories, split into blocks the same way as the
+/// default implementations of the "Segment" trait.
+#[derive(Debug)]
+pub struct MockSegment {
+    id: u32,
+    terms: BTreeMap<Term, TermId>,
+    term_directories: HashMap<(FieldId, TermId), RoaringBitmap>,
+    impacts: HashMap<(FieldId, TermId), Vec<BlockImpact>>,
+    statistics: BTreeMap<Vec<u8>, i64>,
+    stored_values: BTreeMap<(u32, u32, Vec<u8>), Vec<u8>>,
+    deletion_list: Option<RoaringBitmap>,
+    rank_feature_columns: FnvHashMap<FieldId, Vec<f32>>,
+    doc_values_columns: FnvHashMap<FieldId, Vec<Option<i64>>>,
+    field_presence: FnvHashMap<FieldId, RoaringBitmap>,
+    fail_reads: bool,
+
+    /// The postings blocks that have been loaded, in order
+    blocks_loaded: RefCell<Vec<(FieldId, TermId, u32)>>,
+}
+
+impl MockSegment {
+    pub fn new(id: u32) -> MockSegment {
+        MockSegment {
+            id: id,
+            terms: BTreeMap::new(),
+            term_directories: HashMap::new(),
+            impacts: HashMap::new(),
+            statistics: BTreeMap::new(),
+            stored_values: BTreeMap::new(),
+            deletion_list: None,
+            rank_feature_columns: FnvHashMap::default(),
+            doc_values_columns: FnvHashMap::default(),
+            field_presence: FnvHashMap::default(),
+            fail_reads: false,
+            blocks_loaded: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Builds a segment containing the documents, the first document is given the local id 0
+    ///
+    /// Stored values, sources, doc values, rank features and field presence are recorded the
+    /// same way as the RocksDB backend records them. Term ids are given to terms in the order
+    /// they're first seen, use "term_id" to find them. Statistics aren't recorded as their
+    /// names are chosen by each backend, add them with "set_statistic".
+    pub fn from_documents(id: u32, docs: &[Document]) -> MockSegment {
+        let mut segment = MockSegment::new(id);
+
+        for (doc_id, doc) in docs.iter().enumerate() {
+            let doc_id = doc_id as u32;
+
+            // Sort the terms so term ids don't depend on the order of the hash maps
+            let mut fields = doc.indexed_fields.iter().collect::<Vec<_>>();
+            fields.sort_by_key(|&(field_id, _)| field_id.0);
+            for (field_id, term_vector) in fields {
+                let mut terms = term_vector.keys().collect::<Vec<_>>();
+                terms.sort();
+
+                for term in terms {
+                    let term_id = segment.get_or_create_term(term);
+                    segment.term_directories.entry((*field_id, term_id)).or_insert_with(RoaringBitmap::new).insert(doc_id);
+                }
+            }
+
+            for (field_id, value) in doc.stored_fields.iter() {
+                segment.set_stored_value(doc_id, *field_id, b"val", value.to_bytes());
+
+                if let Some(value) = value.to_doc_value() {
+                    let column = segment.doc_values_columns.entry(*field_id).or_insert_with(Vec::new);
+                    if column.len() <= doc_id as usize {
+                        column.resize(doc_id as usize + 1, None);
+                    }
+                    column[doc_id as usize] = Some(value);
+                }
+            }
+
+            if let Some(ref source) = doc.source {
+                segment.set_stored_value(doc_id, FieldId(0), b"src", source.clone());
+            }
+
+            if let Some(ref routing) = doc.routing {
+                segment.set_stored_value(doc_id, FieldId(0), b"rt", routing.as_bytes().to_vec());
+            }
+
+            for (field_id, value) in doc.rank_features.iter() {
+                let column = segment.rank_feature_columns.entry(*field_id).or_insert_with(Vec::new);
+                if column.len() <= doc_id as usize {
+                    column.resize(doc_id as usize + 1, 0.0);
+                }
+                column[doc_id as usize] = *value;
+            }
+
+            let present_fields = doc.indexed_fields.keys()
+                .chain(doc.stored_fields.keys())
+                .chain(doc.rank_features.keys())
+                .chain(doc.completions.keys());
+            for field_id in present_fields {
+                segment.field_presence.entry(*field_id).or_insert_with(RoaringBitmap::new).insert(doc_id);
+            }
+        }
+
+        segment
+    }
+
+    fn get_or_create_term(&mut self, term: &Term) -> TermId {
+        let next_term_id = TermId(self.terms.len() as u32 + 1);
+        *self.terms.entry(term.clone()).or_insert(next_term_id)
+    }
+
+    /// Returns the id the segment has given to a term
+    pub fn term_id(&self, term: &Term) -> Option<TermId> {
+        self.terms.get(term).cloned()
+    }
+
+    /// Adds documents to a term's postings list
+    pub fn add_postings(&mut self, field_id: FieldId, term: &Term, doc_ids: &[u32]) -> TermId {
+        let term_id = self.get_or_create_term(term);
+        let term_directory = self.term_directories.entry((field_id, term_id)).or_insert_with(RoaringBitmap::new);
+        for doc_id in doc_ids {
+            term_directory.insert(*doc_id);
+        }
+
+        term_id
+    }
+
+    /// Sets the impacts of each block of a term's postings list
+    ///
+    /// Without these, the segment behaves like a backend that doesn't store impacts.
+    pub fn set_postings_impacts(&mut self, field_id: FieldId, term_id: TermId, impacts: Vec<BlockImpact>) {
+        self.impacts.insert((field_id, term_id), impacts);
+    }
+
+    pub fn set_statistic(&mut self, stat_name: &[u8], value: i64) {
+        self.statistics.insert(stat_name.to_vec(), value);
+    }
+
+    pub fn set_stored_value(&mut self, doc_local_id: u32, field_id: FieldId, value_type: &[u8], value: Vec<u8>) {
+        self.stored_values.insert((doc_local_id, field_id.0, value_type.to_vec()), value);
+    }
+
+    pub fn delete(&mut self, doc_local_id: u32) {
+        self.deletion_list.get_or_insert_with(RoaringBitmap::new).insert(doc_local_id);
+    }
+
+    pub fn set_rank_feature_column(&mut self, field_id: FieldId, column: Vec<f32>) {
+        self.rank_feature_columns.insert(field_id, column);
+    }
+
+    pub fn set_doc_values_column(&mut self, field_id: FieldId, column: Vec<Option<i64>>) {
+        self.doc_values_columns.insert(field_id, column);
+    }
+
+    pub fn set_field_presence(&mut self, field_id: FieldId, doc_ids: RoaringBitmap) {
+        self.field_presence.insert(field_id, doc_ids);
+    }
+
+    /// Makes every read from the segment fail with a storage error
+    ///
+    /// This is for testing how readers of segments handle backend failures.
+    pub fn fail_reads(&mut self) {
+        self.fail_reads = true;
+    }
+
+    /// Returns the postings blocks that have been loaded since the segment was created, in the
+    /// order they were loaded
+    pub fn blocks_loaded(&self) -> Vec<(FieldId, TermId, u32)> {
+        self.blocks_loaded.borrow().clone()
+    }
+
+    fn check_read(&self) -> Result<(), SegmentError> {
+        if self.fail_reads {
+            Err(SegmentError::storage(MockStorageError))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Segment for MockSegment {
+    fn id(&self) -> SegmentId {
+        SegmentId(self.id)
+    }
+
+    fn load_statistic(&self, stat_name: &[u8]) -> Result<Option<i64>, SegmentError> {
+        try!(self.check_read());
+        Ok(self.statistics.get(stat_name).cloned())
+    }
+
+    fn load_stored_field_value_raw(&self, doc_local_id: u32, field_id: FieldId, value_type: &[u8]) -> Result<Option<Vec<u8>>, SegmentError> {
+        try!(self.check_read());
+        Ok(self.stored_values.get(&(doc_local_id, field_id.0, value_type.to_vec())).cloned())
+    }
+
+    fn load_term_directory(&self, field_id: FieldId, term_id: TermId) -> Result<Option<RoaringBitmap>, SegmentError> {
+        try!(self.check_read());
+        Ok(self.term_directories.get(&(field_id, term_id)).cloned())
+    }
+
+    fn load_postings_block(&self, field_id: FieldId, term_id: TermId, block_ord: u32) -> Result<Option<Vec<u32>>, SegmentError> {
+        try!(self.check_read());
+        self.blocks_loaded.borrow_mut().push((field_id, term_id, block_ord));
+
+        Ok(self.term_directories.get(&(field_id, term_id)).and_then(|term_directory| {
+            split_into_blocks(term_directory).1.into_iter().nth(block_ord as usize)
+        }))
+    }
+
+    fn load_postings_impacts(&self, field_id: FieldId, term_id: TermId) -> Result<Option<Vec<BlockImpact>>, SegmentError> {
+        try!(self.check_read());
+        Ok(self.impacts.get(&(field_id, term_id)).cloned())
+    }
+
+    fn load_deletion_list(&self) -> Result<Option<RoaringBitmap>, SegmentError> {
+        try!(self.check_read());
+        Ok(self.deletion_list.clone())
+    }
+
+    fn load_rank_feature_column(&self, field_id: FieldId) -> Result<Option<Vec<f32>>, SegmentError> {
+        try!(self.check_read());
+        Ok(self.rank_feature_columns.get(&field_id).cloned())
+    }
+
+    fn load_doc_values_column(&self, field_id: FieldId) -> Result<Option<Vec<Option<i64>>>, SegmentError> {
+        try!(self.check_read());
+        Ok(self.doc_values_columns.get(&field_id).cloned())
+    }
+
+    fn load_field_presence(&self, field_id: FieldId) -> Result<Option<RoaringBitmap>, SegmentError> {
+        try!(self.check_read());
+        Ok(self.field_presence.get(&field_id).cloned())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorpusConfig {
+    /// The same seed always generates the same documents
+    pub seed: u64,
+
+    /// The number of distinct words in text fields
+    pub vocabulary_size: usize,
+
+    /// The range of the number of words in each text field (inclusive)
+    pub min_words: usize,
+    pub max_words: usize,
+
+    /// The range of values in integer fields (inclusive)
+    pub min_integer: i64,
+    pub max_integer: i64,
+}
+
+impl Default for CorpusConfig {
+    fn default() -> CorpusConfig {
+        CorpusConfig {
+            seed: 1,
+            vocabulary_size: 1000,
+            min_words: 1,
+            max_words: 50,
+            min_integer: -1000,
+            max_integer: 1000,
+        }
+    }
+}
+
+/// Generates reproducible random documents
+///
+/// Words are chosen with a skewed distribution so, like real text, a few words appear in most
+/// documents and most words only appear in a few.
+pub struct CorpusGenerator {
+    config: CorpusConfig,
+    state: u64,
+    vocabulary: Vec<String>,
+}
+
+impl CorpusGenerator {
+    pub fn new(config: CorpusConfig) -> CorpusGenerator {
+        let vocabulary = (0..config.vocabulary_size).map(word).collect();
+
+        CorpusGenerator {
+            // Xorshift gets stuck on zero
+            state: if config.seed == 0 { 0x9e3779b97f4a7c15 } else { config.seed },
+            config: config,
+            vocabulary: vocabulary,
+        }
+    }
+
+    /// Returns the next random number (xorshift64*)
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Returns a random number between 0 (inclusive) and 1 (exclusive)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a random number in a range (inclusive)
+    pub fn next_in_range(&mut self, min: i64, max: i64) -> i64 {
+        if max <= min {
+            return min;
+        }
+
+        min.wrapping_add((self.next_u64() % (max.wrapping_sub(min) as u64).wrapping_add(1).max(1)) as i64)
+    }
+
+    pub fn word(&mut self) -> &str {
+        let x = self.next_f64();
+        let index = (x * x * x * self.vocabulary.len() as f64) as usize;
+        &self.vocabulary[index]
+    }
+
+    pub fn text(&mut self) -> String {
+        let words = self.next_in_range(self.config.min_words as i64, self.config.max_words as i64) as usize;
+        let mut text = String::new();
+
+        for i in 0..words {
+            if i > 0 {
+                text.push(' ');
+            }
+
+            let word = self.word().to_string();
+            text.push_str(&word);
+        }
+
+        text
+    }
+
+    /// Generates a document with a value for each of the schema's fields
+    ///
+    /// Completion, facet and join fields are left empty.
+    pub fn document(&mut self, schema: &Schema, key: &str) -> Document {
+        let mut fields = schema.iter().map(|(field_id, field_info)| (*field_id, field_info.field_type.clone())).collect::<Vec<_>>();
+        fields.sort_by_key(|&(field_id, _)| field_id.0);
+
+        let mut builder = DocumentBuilder::new(schema, key);
+        for (field_id, field_type) in fields {
+            builder = match field_type {
+                FieldType::Text => {
+                    let text = self.text();
+                    builder.text(field_id, &text)
+                }
+                FieldType::PlainString => {
+                    let word = self.word().to_string();
+                    builder.string(field_id, &word)
+                }
+                FieldType::I64 => {
+                    let value = self.next_in_range(self.config.min_integer, self.config.max_integer);
+                    builder.integer(field_id, value)
+                }
+                FieldType::Boolean => {
+                    let value = self.next_u64() % 2 == 0;
+                    builder.boolean(field_id, value)
+                }
+                FieldType::DateTime => {
+                    // Sometime between 2000 and 2030
+                    let timestamp = self.next_in_range(946684800, 1893456000);
+                    builder.datetime(field_id, Utc.timestamp_opt(timestamp, 0).unwrap())
+                }
+                FieldType::RankFeature => {
+                    let value = self.next_f64() as f32 * 10.0 + 0.1;
+                    builder.rank_feature(field_id, value)
+                }
+                _ => builder,
+            };
+        }
+
+        builder.build().expect("corpus generator: couldn't build document")
+    }
+
+    /// Generates documents with the keys "doc0", "doc1" and so on
+    pub fn corpus(&mut self, schema: &Schema, count: usize) -> Vec<Document> {
+        (0..count).map(|i| self.document(schema, &format!("doc{}", i))).collect()
+    }
+}
+
+/// Returns the n-th word of the vocabulary: "a" to "z", then "ba" to "zz" and so on
+fn word(mut n: usize) -> String {
+    let mut word = Vec::new();
+    loop {
+        word.push(b'a' + (n % 26) as u8);
+        n /= 26;
+
+        if n == 0 {
+            break;
+        }
+    }
+
+    word.reverse();
+    String::from_utf8(word).unwrap()
+}
+
+/// Compares two columns, treating missing values at the end as defaults
+///
+/// Backends only need to store columns up to the last document with a value.
+fn columns_equal<T: PartialEq + Clone>(a: &[T], b: &[T], default: T) -> bool {
+    let len = a.len().max(b.len());
+    (0..len).all(|i| a.get(i).cloned().unwrap_or_else(|| default.clone()) == b.get(i).cloned().unwrap_or_else(|| default.clone()))
+}
+
+/// Checks that a segment returns the same data as a reference segment
+///
+/// "term_id" finds the id that the segment's backend has given to a term. Only the data in the
+/// reference is checked, so the segment can contain extra statistics and stored values. The
+/// postings of each term are also checked for consistency: the blocks must cover the term
+/// directory, the skip list must contain the last document of each block, and the postings
+/// must be iterated and advanced in order.
+///
+/// Returns a description of each difference that was found.
+pub fn check_segment<S: Segment, F: Fn(&Term) -> Option<TermId>>(segment: &S, expected: &MockSegment, term_id: F) -> Result<(), Vec<String>> {
+    let mut failures = Vec::new();
+
+    macro_rules! check {
+        ($what:expr, $result:expr, $f:expr) => {
+            match $result {
+                Ok(value) => {
+                    if let Some(failure) = $f(value) {
+                        failures.push(format!("{}: {}", $what, failure));
+                    }
+                }
+                Err(e) => failures.push(format!("{}: {}", $what, e)),
+            }
+        }
+    }
+
+    if segment.id() != expected.id() {
+        failures.push(format!("id: expected {:?}, got {:?}", expected.id(), segment.id()));
+    }
+
+    let terms = expected.terms.iter().map(|(term, term_id)| (*term_id, term)).collect::<FnvHashMap<_, _>>();
+    let mut term_directories = expected.term_directories.iter().collect::<Vec<_>>();
+    term_directories.sort_by_key(|&(&(field_id, term_id), _)| (field_id.0, term_id.0));
+
+    for (&(field_id, expected_term_id), expected_directory) in term_directories {
+        let term = terms[&expected_term_id];
+        let what = format!("field {} term {:?}", field_id.0, term);
+
+        let term_id = match term_id(term) {
+            Some(term_id) => term_id,
+            None => {
+                failures.push(format!("{}: not found", what));
+                continue;
+            }
+        };
+
+        let expected_doc_ids = expected_directory.iter().collect::<Vec<_>>();
+
+        check!(format!("{} directory", what), segment.load_term_directory(field_id, term_id), |term_directory: Option<RoaringBitmap>| {
+            match term_directory {
+                Some(ref term_directory) if term_directory == expected_directory => None,
+                term_directory => Some(format!("expected {:?}, got {:?}", expected_doc_ids, term_directory.map(|d| d.iter().collect::<Vec<_>>()))),
+            }
+        });
+
+        // The blocks must concatenate to the term directory, and each entry in the skip list
+        // must be the last document of its block
+        check!(format!("{} blocks", what), load_blocks(segment, field_id, term_id), |(skip_list, blocks): (Vec<u32>, Vec<Vec<u32>>)| {
+            let doc_ids = blocks.iter().flat_map(|block| block.iter().cloned()).collect::<Vec<_>>();
+            let last_docs = blocks.iter().filter_map(|block| block.last().cloned()).collect::<Vec<_>>();
+
+            if doc_ids != expected_doc_ids {
+                Some(format!("expected {:?}, got {:?}", expected_doc_ids, doc_ids))
+            } else if skip_list != last_docs {
+                Some(format!("skip list {:?} doesn't match the last documents of the blocks {:?}", skip_list, last_docs))
+            } else {
+                None
+            }
+        });
+
+        check!(format!("{} iteration", what), iterate_postings(segment, field_id, term_id), |doc_ids: Vec<u32>| {
+            if doc_ids != expected_doc_ids {
+                Some(format!("expected {:?}, got {:?}", expected_doc_ids, doc_ids))
+            } else {
+                None
+            }
+        });
+
+        // Advance to every third document and to the document after each of them
+        let targets = expected_doc_ids.iter().step_by(3).flat_map(|doc_id| vec![*doc_id, *doc_id + 1]).collect::<Vec<_>>();
+        check!(format!("{} advance", what), advance_postings(segment, field_id, term_id, &targets), |doc_ids: Vec<Option<u32>>| {
+            let mut position = 0;
+            let expected = targets.iter().map(|target| {
+                while position < expected_doc_ids.len() && expected_doc_ids[position] < *target {
+                    position += 1;
+                }
+                expected_doc_ids.get(position).cloned()
+            }).collect::<Vec<_>>();
+
+            if doc_ids != expected {
+                Some(format!("advancing to {:?}: expected {:?}, got {:?}", targets, expected, doc_ids))
+            } else {
+                None
+            }
+        });
+
+        check!(format!("{} impacts", what), segment.load_postings_impacts(field_id, term_id), |impacts: Option<Vec<BlockImpact>>| {
+            let blocks = split_into_blocks(expected_directory).1.len();
+            match impacts {
+                Some(ref impacts) if impacts.len() != blocks => Some(format!("expected an impact for each of the {} blocks, got {}", blocks, impacts.len())),
+                _ => None,
+            }
+        });
+    }
+
+    // A term that doesn't exist has no postings
+    check!("missing term directory", segment.load_term_directory(FieldId(u32::MAX), TermId(u32::MAX)), |term_directory: Option<RoaringBitmap>| {
+        term_directory.map(|_| "expected None".to_string())
+    });
+
+    for (stat_name, expected_value) in expected.statistics.iter() {
+        check!(format!("statistic {:?}", String::from_utf8_lossy(stat_name)), segment.load_statistic(stat_name), |value: Option<i64>| {
+            if value != Some(*expected_value) {
+                Some(format!("expected {}, got {:?}", expected_value, value))
+            } else {
+                None
+            }
+        });
+    }
+
+    for (&(doc_id, field_id, ref value_type), expected_value) in expected.stored_values.iter() {
+        let what = format!("stored value {:?} of field {} doc {}", String::from_utf8_lossy(value_type), field_id, doc_id);
+        check!(what, segment.load_stored_field_value_raw(doc_id, FieldId(field_id), value_type), |value: Option<Vec<u8>>| {
+            if value.as_ref() != Some(expected_value) {
+                Some(format!("expected {:?}, got {:?}", expected_value, value))
+            } else {
+                None
+            }
+        });
+    }
+
+    check!("deletion list", segment.load_deletion_list(), |deletion_list: Option<RoaringBitmap>| {
+        // A missing deletion list is the same as an empty one
+        let deletion_list = deletion_list.unwrap_or_else(RoaringBitmap::new);
+        let expected_deletion_list = expected.deletion_list.clone().unwrap_or_else(RoaringBitmap::new);
+        if deletion_list != expected_deletion_list {
+            Some(format!("expected {:?}, got {:?}", expected_deletion_list.iter().collect::<Vec<_>>(), deletion_list.iter().collect::<Vec<_>>()))
+        } else {
+            None
+        }
+    });
+
+    for (field_id, expected_column) in sorted(&expected.rank_feature_columns) {
+        check!(format!("rank feature column of field {}", field_id.0), segment.load_rank_feature_column(field_id), |column: Option<Vec<f32>>| {
+            match column {
+                Some(ref column) if columns_equal(column, expected_column, 0.0) => None,
+                column => Some(format!("expected {:?}, got {:?}", expected_column, column)),
+            }
+        });
+    }
+
+    for (field_id, expected_column) in sorted(&expected.doc_values_columns) {
+        check!(format!("doc values column of field {}", field_id.0), segment.load_doc_values_column(field_id), |column: Option<Vec<Option<i64>>>| {
+            match column {
+                Some(ref column) if columns_equal(column, expected_column, None) => None,
+                column => Some(format!("expected {:?}, got {:?}", expected_column, column)),
+            }
+        });
+    }
+
+    for (field_id, expected_presence) in sorted(&expected.field_presence) {
+        check!(format!("field presence of field {}", field_id.0), segment.load_field_presence(field_id), |presence: Option<RoaringBitmap>| {
+            match presence {
+                Some(ref presence) if presence == expected_presence => None,
+                presence => Some(format!("expected {:?}, got {:?}", expected_presence.iter().collect::<Vec<_>>(), presence.map(|p| p.iter().collect::<Vec<_>>()))),
+            }
+        });
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+/// Panics with a list of the differences if a segment doesn't match the reference segment,
+/// see "check_segment"
+pub fn assert_segment_conforms<S: Segment, F: Fn(&Term) -> Option<TermId>>(segment: &S, expected: &MockSegment, term_id: F) {
+    if let Err(failures) = check_segment(segment, expected, term_id) {
+        panic!("segment doesn't match the reference segment:\n  {}", failures.join("\n  "));
+    }
+}
+
+fn sorted<T>(map: &FnvHashMap<FieldId, T>) -> Vec<(FieldId, &T)> {
+    let mut entries = map.iter().map(|(field_id, value)| (*field_id, value)).collect::<Vec<_>>();
+    entries.sort_by_key(|&(field_id, _)| field_id.0);
+    entries
+}
+
+fn load_blocks<S: Segment>(segment: &S, field_id: FieldId, term_id: TermId) -> Result<(Vec<u32>, Vec<Vec<u32>>), SegmentError> {
+    let skip_list = try!(segment.load_postings_skip_list(field_id, term_id)).unwrap_or_else(Vec::new);
+    let mut blocks = Vec::with_capacity(skip_list.len());
+
+    for block_ord in 0..skip_list.len() {
+        blocks.push(try!(segment.load_postings_block(field_id, term_id, block_ord as u32)).unwrap_or_else(Vec::new));
+    }
+
+    Ok((skip_list, blocks))
+}
+
+fn iterate_postings<S: Segment>(segment: &S, field_id: FieldId, term_id: TermId) -> Result<Vec<u32>, SegmentError> {
+    let mut postings = try!(BlockPostingsIterator::new(segment, field_id, term_id));
+    let mut doc_ids = Vec::new();
+
+    while let Some(doc_id) = try!(postings.next_doc()) {
+        doc_ids.push(doc_id);
+    }
+
+    Ok(doc_ids)
+}
+
+fn advance_postings<S: Segment>(segment: &S, field_id: FieldId, term_id: TermId, targets: &[u32]) -> Result<Vec<Option<u32>>, SegmentError> {
+    let mut postings = try!(BlockPostingsIterator::new(segment, field_id, term_id));
+    let mut doc_ids = Vec::with_capacity(targets.len());
+
+    for target in targets {
+        doc_ids.push(try!(postings.advance(*target)));
+    }
+
+    Ok(doc_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use term::Term;
+    use schema::{Schema, FieldId, FieldType, FIELD_INDEXED, FIELD_STORED};
+    use segment::Segment;
+    use postings::{BlockPostingsIterator, PostingsIterator};
+    use super::{MockSegment, CorpusGenerator, CorpusConfig, check_segment, word};
+
+    fn schema() -> Schema {
+        let mut schema = Schema::new();
+        schema.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+        schema.add_field("category".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+        schema.add_field("rating".to_string(), FieldType::I64, FIELD_INDEXED | FIELD_STORED).unwrap();
+        schema.add_field("published".to_string(), FieldType::DateTime, FIELD_STORED).unwrap();
+        schema.add_field("popularity".to_string(), FieldType::RankFeature, FIELD_INDEXED).unwrap();
+        schema
+    }
+
+    #[test]
+    fn test_word() {
+        assert_eq!(word(0), "a");
+        assert_eq!(word(25), "z");
+        assert_eq!(word(26), "ba");
+        assert_eq!(word(26 * 26), "baa");
+    }
+
+    #[test]
+    fn test_corpus_is_reproducible() {
+        let schema = schema();
+        let a = CorpusGenerator::new(CorpusConfig::default()).corpus(&schema, 20);
+        let b = CorpusGenerator::new(CorpusConfig::default()).corpus(&schema, 20);
+        let c = CorpusGenerator::new(CorpusConfig { seed: 2, ..CorpusConfig::default() }).corpus(&schema, 20);
+
+        assert_eq!(a.len(), 20);
+        assert_eq!(a[7].key, "doc7");
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert_eq!(a.indexed_fields, b.indexed_fields);
+        }
+        assert!(a.iter().zip(c.iter()).any(|(a, c)| a.indexed_fields != c.indexed_fields));
+
+        // Every field is filled in
+        for doc in a.iter() {
+            assert_eq!(doc.indexed_fields.len(), 3);
+            assert_eq!(doc.stored_fields.len(), 3);
+            assert_eq!(doc.rank_features.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_mock_segment_from_documents() {
+        let schema = schema();
+        let docs = CorpusGenerator::new(CorpusConfig::default()).corpus(&schema, 300);
+        let segment = MockSegment::from_documents(1, &docs);
+        let title = schema.get_field_by_name("title").unwrap();
+        let rating = schema.get_field_by_name("rating").unwrap();
+
+        // "a" is the most common word, so its postings list spans multiple blocks
+        let term_id = segment.term_id(&Term::from_string("a")).unwrap();
+        let term_directory = segment.load_term_directory(title, term_id).unwrap().unwrap();
+        assert!(term_directory.len() > 128);
+        for (doc_id, doc) in docs.iter().enumerate() {
+            assert_eq!(term_directory.contains(doc_id as u32), doc.indexed_fields[&title].contains_key(&Term::from_string("a")));
+        }
+
+        let column = segment.load_doc_values_column(rating).unwrap().unwrap();
+        assert_eq!(column[5], docs[5].stored_fields[&rating].to_doc_value());
+        assert_eq!(segment.load_stored_field_value_raw(5, rating, b"val").unwrap(), Some(docs[5].stored_fields[&rating].to_bytes()));
+        assert_eq!(segment.load_field_presence(rating).unwrap().unwrap().len(), 300);
+
+        // Postings are loaded a block at a time
+        let mut postings = BlockPostingsIterator::new(&segment, title, term_id).unwrap();
+        postings.advance(term_directory.max().unwrap()).unwrap();
+        let blocks_loaded = segment.blocks_loaded().into_iter().map(|(_, _, block_ord)| block_ord).collect::<Vec<_>>();
+        assert_eq!(blocks_loaded, vec![(term_directory.len() as u32 - 1) / 128]);
+    }
+
+    #[test]
+    fn test_fail_reads() {
+        let mut segment = MockSegment::new(1);
+        segment.set_statistic(b"total_docs", 10);
+        assert_eq!(segment.load_statistic(b"total_docs").unwrap(), Some(10));
+
+        segment.fail_reads();
+        assert!(segment.load_statistic(b"total_docs").is_err());
+        assert!(segment.load_deletion_list().is_err());
+    }
+
+    #[test]
+    fn test_check_segment() {
+        let schema = schema();
+        let docs = CorpusGenerator::new(CorpusConfig::default()).corpus(&schema, 300);
+        let expected = MockSegment::from_documents(1, &docs);
+
+        let mut segment = MockSegment::from_documents(1, &docs);
+        assert_eq!(check_segment(&segment, &expected, |term| expected.term_id(term)), Ok(()));
+
+        // Extra data in the segment is allowed
+        segment.set_statistic(b"total_docs", 300);
+        assert_eq!(check_segment(&segment, &expected, |term| expected.term_id(term)), Ok(()));
+
+        // Differences are reported
+        segment.delete(3);
+        segment.add_postings(FieldId(1), &Term::from_string("a"), &[1000]);
+        segment.set_stored_value(0, FieldId(1), b"val", b"wrong".to_vec());
+        let failures = check_segment(&segment, &expected, |term| expected.term_id(term)).unwrap_err();
+        assert!(failures.iter().any(|failure| failure.starts_with("deletion list")));
+        assert!(failures.iter().any(|failure| failure.starts_with("field 1 term") && failure.contains("directory")));
+        assert!(failures.iter().any(|failure| failure.starts_with("stored value \"val\" of field 1 doc 0")));
+
+        // So are backend errors
+        segment.fail_reads();
+        let failures = check_segment(&segment, &expected, |term| expected.term_id(term)).unwrap_err();
+        assert!(failures.iter().any(|failure| failure.contains("mock segment read failure")));
+
+        // And terms that can't be found
+        let failures = check_segment(&MockSegment::new(1), &expected, |_| None).unwrap_err();
+        assert!(failures.iter().any(|failure| failure.starts_with("field 1 term") && failure.ends_with("not found")));
+    }
+}
@@ -4,4 +4,8 @@ use term::Term;
 pub struct Token {
     pub term: Term,
     pub position: u32,
+
+    /// The start and end byte offsets of the token in the text it was analyzed from, if the
+    /// analyzer records them
+    pub offsets: Option<(u32, u32)>,
 }
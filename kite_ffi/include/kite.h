@@ -0,0 +1,48 @@
+/*
+ * C API for the Kite search engine
+ *
+ * Documents, queries and results are JSON strings. Functions that fail return NULL
+ * (or -1) and the reason can be read with kite_last_error().
+ */
+
+#ifndef KITE_H
+#define KITE_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct KiteIndex KiteIndex;
+typedef struct KiteReader KiteReader;
+
+/* Returns the last error on this thread, or NULL. Valid until the next call. */
+const char *kite_last_error(void);
+
+/* Frees a string returned by Kite */
+void kite_string_free(char *string);
+
+/* Creates an index, fields_json is a list of {"name", "type", "flags"} objects */
+KiteIndex *kite_index_create(const char *path, const char *fields_json);
+KiteIndex *kite_index_open(const char *path);
+void kite_index_free(KiteIndex *index);
+
+/* Inserts a document, replacing any with the same key. Returns 0, or -1 on error. */
+int kite_index_insert(const KiteIndex *index, const char *document_json);
+
+/* Returns 1 if the document was deleted, 0 if it doesn't exist, or -1 on error */
+int kite_index_delete(const KiteIndex *index, const char *key);
+
+/* Opens a point-in-time view of the index, it can be used after the index is freed */
+KiteReader *kite_reader_open(const KiteIndex *index);
+void kite_reader_free(KiteReader *reader);
+
+/* Returns the top "limit" documents as JSON, free the result with kite_string_free */
+char *kite_reader_search(const KiteReader *reader, const char *query_json, size_t limit);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
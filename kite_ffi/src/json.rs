@@ -0,0 +1,220 @@
+//! Converts the JSON that's passed through the C API into fields, documents and queries
+//!
+//! Fields are defined as a list of objects with a name, type and flags:
+//!
+//! ```json
+//! [{"name": "title", "type": "Text", "flags": "INDEXED|STORED"}]
+//! ```
+//!
+//! Documents have a key and a value (or list of values) for each field, these are mapped onto
+//! the schema by "kite_rocksdb::add_json_fields":
+//!
+//! ```json
+//! {"key": "doc1", "fields": {"title": "Hello world", "tags": ["a", "b"]}, "routing": "user1"}
+//! ```
+//!
+//! Queries are objects with a single key, the type of the query:
+//!
+//! ```json
+//! {"and": [{"term": {"field": "title", "value": "hello"}}, {"all": {}}]}
+//! ```
+//!
+//! The types are "all", "none", "term", "and", "or", "dis_max", "filter" (with "query" and
+//! "filter"), "exclude" (with "query" and "exclude"), "constant_score" (with "query" and
+//! "score") and "boost" (with "query" and "boost").
+
+use serde_json::{self, Value};
+use chrono::{DateTime, Utc};
+use kite::{Document, DocumentBuilder, Query, Term};
+use kite::schema::{Schema, FieldType, FieldFlags, FieldId};
+use kite::facet::FacetPath;
+use kite_rocksdb::add_json_fields;
+
+/// Parses a list of field definitions into their names, types and flags
+pub fn parse_fields(value: &Value) -> Result<Vec<(String, FieldType, FieldFlags)>, String> {
+    let fields = try!(value.as_array().ok_or("fields must be a list"));
+    let mut parsed_fields = Vec::with_capacity(fields.len());
+
+    for field in fields {
+        let name = try!(field.get("name").and_then(Value::as_str).ok_or("field must have a \"name\" string"));
+        let field_type = try!(field.get("type").ok_or(format!("field {:?} must have a \"type\"", name)));
+        let field_type = try!(serde_json::from_value(field_type.clone()).map_err(|e| format!("field {:?} has an invalid type: {}", name, e)));
+        let flags = match field.get("flags") {
+            Some(flags) => try!(serde_json::from_value(flags.clone()).map_err(|e| format!("field {:?} has invalid flags: {}", name, e))),
+            None => FieldFlags::empty(),
+        };
+
+        parsed_fields.push((name.to_string(), field_type, flags));
+    }
+
+    Ok(parsed_fields)
+}
+
+fn get_field(schema: &Schema, name: &str) -> Result<(FieldId, FieldType), String> {
+    schema.get_field_by_name(name)
+        .and_then(|field_id| schema.get(&field_id).map(|field_info| (field_id, field_info.field_type.clone())))
+        .ok_or(format!("field {:?} doesn't exist", name))
+}
+
+fn as_str<'a>(name: &str, value: &'a Value) -> Result<&'a str, String> {
+    value.as_str().ok_or(format!("field {:?} expects a string", name))
+}
+
+fn as_datetime(name: &str, value: &Value) -> Result<DateTime<Utc>, String> {
+    let value = try!(as_str(name, value));
+    DateTime::parse_from_rfc3339(value)
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .map_err(|e| format!("field {:?} expects an RFC 3339 date: {}", name, e))
+}
+
+pub fn parse_document(schema: &Schema, value: &Value) -> Result<Document, String> {
+    let key = try!(value.get("key").and_then(Value::as_str).ok_or("document must have a \"key\" string"));
+    let mut builder = DocumentBuilder::new(schema, key);
+
+    if let Some(fields) = value.get("fields") {
+        if !fields.is_object() {
+            return Err("document \"fields\" must be an object".to_string());
+        }
+
+        builder = try!(add_json_fields(builder, schema, fields, false));
+    }
+
+    if let Some(routing) = value.get("routing") {
+        builder = builder.routing(try!(routing.as_str().ok_or("document \"routing\" must be a string")));
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn parse_term(schema: &Schema, value: &Value) -> Result<Query, String> {
+    let name = try!(value.get("field").and_then(Value::as_str).ok_or("term query must have a \"field\" string"));
+    let term_value = try!(value.get("value").ok_or("term query must have a \"value\""));
+    let (field, field_type) = try!(get_field(schema, name));
+
+    let term = match field_type {
+        FieldType::Text | FieldType::PlainString | FieldType::Join => Term::from_string(try!(as_str(name, term_value))),
+        FieldType::I64 => Term::from_integer(try!(term_value.as_i64().ok_or(format!("field {:?} expects an integer", name)))),
+        FieldType::Boolean => Term::from_boolean(try!(term_value.as_bool().ok_or(format!("field {:?} expects a boolean", name)))),
+        FieldType::DateTime => Term::from_datetime(&try!(as_datetime(name, term_value))),
+        FieldType::Facet => FacetPath::parse(try!(as_str(name, term_value))).to_term(),
+        FieldType::RankFeature | FieldType::Completion => return Err(format!("field {:?} can't be searched by term", name)),
+    };
+
+    Ok(Query::term(field, term))
+}
+
+fn parse_queries(schema: &Schema, value: &Value) -> Result<Vec<Query>, String> {
+    let values = try!(value.as_array().ok_or("expected a list of queries"));
+    let mut queries = Vec::with_capacity(values.len());
+
+    for value in values {
+        queries.push(try!(parse_query(schema, value)));
+    }
+
+    Ok(queries)
+}
+
+fn parse_inner_query(schema: &Schema, value: &Value, key: &str) -> Result<Query, String> {
+    match value.get(key) {
+        Some(query) => parse_query(schema, query),
+        None => Err(format!("expected a {:?} query", key)),
+    }
+}
+
+fn parse_number(value: &Value, key: &str, default: f64) -> Result<f32, String> {
+    match value.get(key) {
+        Some(number) => number.as_f64().map(|number| number as f32).ok_or(format!("{:?} must be a number", key)),
+        None => Ok(default as f32),
+    }
+}
+
+pub fn parse_query(schema: &Schema, value: &Value) -> Result<Query, String> {
+    let object = try!(value.as_object().ok_or("query must be an object"));
+    if object.len() != 1 {
+        return Err("query must have exactly one key, the type of the query".to_string());
+    }
+    let (query_type, params) = object.iter().next().unwrap();
+
+    match query_type.as_ref() {
+        "all" => Ok(Query::all()),
+        "none" => Ok(Query::None),
+        "term" => parse_term(schema, params),
+        "and" => Ok(Query::conjunction(try!(parse_queries(schema, params)))),
+        "or" => Ok(Query::disjunction(try!(parse_queries(schema, params)))),
+        "dis_max" => Ok(Query::disjunction_max(try!(parse_queries(schema, params)))),
+        "filter" => Ok(try!(parse_inner_query(schema, params, "query")).filter(try!(parse_inner_query(schema, params, "filter")))),
+        "exclude" => Ok(try!(parse_inner_query(schema, params, "query")).exclude(try!(parse_inner_query(schema, params, "exclude")))),
+        "constant_score" => Ok(try!(parse_inner_query(schema, params, "query")).constant_score(try!(parse_number(params, "score", 1.0)))),
+        "boost" => Ok(try!(parse_inner_query(schema, params, "query")).boost(try!(parse_number(params, "boost", 1.0)))),
+        _ => Err(format!("unknown query type {:?}", query_type)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kite::{Query, Term};
+    use kite::schema::{Schema, FieldType, FIELD_INDEXED, FIELD_STORED};
+    use kite::document::FieldValue;
+
+    use super::{parse_fields, parse_document, parse_query};
+
+    fn make_schema() -> Schema {
+        let mut schema = Schema::new();
+        for (name, field_type, flags) in parse_fields(&json!([
+            {"name": "title", "type": "Text", "flags": "INDEXED|STORED"},
+            {"name": "pk", "type": "I64", "flags": "STORED"},
+        ])).unwrap() {
+            schema.add_field(name, field_type, flags).unwrap();
+        }
+        schema
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let schema = make_schema();
+        let title = schema.get_field_by_name("title").unwrap();
+        assert_eq!(schema[&title].field_type, FieldType::Text);
+        assert_eq!(schema[&title].field_flags, FIELD_INDEXED | FIELD_STORED);
+
+        assert!(parse_fields(&json!([{"name": "title", "type": "Unknown"}])).is_err());
+    }
+
+    #[test]
+    fn test_parse_document() {
+        let schema = make_schema();
+        let title = schema.get_field_by_name("title").unwrap();
+        let pk = schema.get_field_by_name("pk").unwrap();
+
+        let doc = parse_document(&schema, &json!({"key": "doc1", "fields": {"title": "Hello world", "pk": 1}})).unwrap();
+        assert_eq!(doc.key, "doc1");
+        assert!(doc.indexed_fields[&title].contains_key(&Term::from_string("hello")));
+        match doc.stored_fields.get(&pk) {
+            Some(&FieldValue::Integer(1)) => {}
+            _ => panic!("pk wasn't stored"),
+        }
+
+        assert_eq!(parse_document(&schema, &json!({"key": "doc1", "fields": {"pk": "one"}})).unwrap_err(), "field \"pk\" expects an integer");
+        assert_eq!(parse_document(&schema, &json!({"key": "doc1", "fields": {"missing": 1}})).unwrap_err(), "field \"missing\" doesn't exist");
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let schema = make_schema();
+        let title = schema.get_field_by_name("title").unwrap();
+
+        let query = parse_query(&schema, &json!({
+            "and": [
+                {"term": {"field": "title", "value": "hello"}},
+                {"boost": {"query": {"all": {}}, "boost": 2.0}},
+            ]
+        })).unwrap();
+
+        assert_eq!(query, Query::conjunction(vec![
+            Query::term(title, Term::from_string("hello")),
+            Query::all().boost(2.0),
+        ]));
+
+        assert_eq!(parse_query(&schema, &json!({"unknown": {}})).unwrap_err(), "unknown query type \"unknown\"");
+        assert!(parse_query(&schema, &json!({"all": {}, "none": {}})).is_err());
+    }
+}
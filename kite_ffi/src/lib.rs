@@ -0,0 +1,299 @@
+//! A C API for embedding Kite
+//!
+//! Indexes and readers are opaque handles that must be freed with their "_free" function.
+//! Documents, queries and results are passed as JSON strings, see the "json" module for
+//! their format. Functions that fail return NULL (or -1) and the reason can be read with
+//! "kite_last_error". Strings returned by the API must be freed with "kite_string_free".
+//!
+//! The declarations for C are in "include/kite.h".
+
+extern crate kite;
+extern crate kite_rocksdb;
+#[macro_use]
+extern crate serde_json;
+extern crate chrono;
+extern crate libc;
+
+pub mod json;
+
+use std::ptr;
+use std::mem;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use libc::{c_char, c_int, size_t};
+use serde_json::Value;
+use kite::DocId;
+use kite::collectors::top_score::TopScoreCollector;
+use kite::schema::FIELD_STORED;
+use kite_rocksdb::{RocksDBStore, RocksDBReader, field_value_to_json};
+
+/// An index opened through the C API
+pub struct KiteIndex {
+    store: Arc<RocksDBStore>,
+}
+
+/// A point-in-time view of an index for searching
+///
+/// The reader holds a reference to the index so it stays open until the reader is freed,
+/// even if the index handle is freed first.
+pub struct KiteReader {
+    // Declared before "store" so it's dropped first
+    reader: RocksDBReader<'static>,
+    // Only held to keep the store open for the reader
+    #[allow(dead_code)]
+    store: Arc<RocksDBStore>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+/// Runs "f", recording its error (or panic) as the last error
+///
+/// Panics must not unwind into C so they're caught here and reported like any other error.
+fn call<T, F: FnOnce() -> Result<T, String>>(f: F) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            set_last_error(e);
+            None
+        }
+        Err(_) => {
+            set_last_error("kite panicked".to_string());
+            None
+        }
+    }
+}
+
+unsafe fn read_str<'a>(string: *const c_char) -> Result<&'a str, String> {
+    if string.is_null() {
+        return Err("unexpected null pointer".to_string());
+    }
+
+    CStr::from_ptr(string).to_str().map_err(|_| "string isn't valid UTF-8".to_string())
+}
+
+unsafe fn read_json(string: *const c_char) -> Result<Value, String> {
+    serde_json::from_str(try!(read_str(string))).map_err(|e| format!("invalid JSON: {}", e))
+}
+
+unsafe fn read_handle<'a, T>(handle: *const T) -> Result<&'a T, String> {
+    handle.as_ref().ok_or("unexpected null handle".to_string())
+}
+
+/// Returns the message of the last error that occurred on this thread, or NULL
+///
+/// The message is owned by Kite and stays valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn kite_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        match *last_error.borrow() {
+            Some(ref message) => message.as_ptr(),
+            None => ptr::null(),
+        }
+    })
+}
+
+/// Frees a string that was returned by Kite
+#[no_mangle]
+pub unsafe extern "C" fn kite_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Creates an index at "path" with the fields defined in "fields_json"
+#[no_mangle]
+pub unsafe extern "C" fn kite_index_create(path: *const c_char, fields_json: *const c_char) -> *mut KiteIndex {
+    call(|| {
+        let path = try!(read_str(path));
+        let fields = try!(json::parse_fields(&try!(read_json(fields_json))));
+
+        let mut store = try!(RocksDBStore::create(path));
+        for (name, field_type, field_flags) in fields {
+            try!(store.add_field(name, field_type, field_flags).map_err(|e| e.to_string()));
+        }
+
+        Ok(Box::into_raw(Box::new(KiteIndex { store: Arc::new(store) })))
+    }).unwrap_or(ptr::null_mut())
+}
+
+/// Opens an existing index
+#[no_mangle]
+pub unsafe extern "C" fn kite_index_open(path: *const c_char) -> *mut KiteIndex {
+    call(|| {
+        let store = try!(RocksDBStore::open(try!(read_str(path))));
+        Ok(Box::into_raw(Box::new(KiteIndex { store: Arc::new(store) })))
+    }).unwrap_or(ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn kite_index_free(index: *mut KiteIndex) {
+    if !index.is_null() {
+        drop(Box::from_raw(index));
+    }
+}
+
+/// Inserts a document, replacing any document with the same key
+///
+/// Returns 0 on success or -1 on error.
+#[no_mangle]
+pub unsafe extern "C" fn kite_index_insert(index: *const KiteIndex, document_json: *const c_char) -> c_int {
+    call(|| {
+        let index = try!(read_handle(index));
+        let document = try!(json::parse_document(index.store.schema(), &try!(read_json(document_json))));
+        try!(index.store.insert_or_update_document(&document).map_err(|e| e.to_string()));
+        Ok(0)
+    }).unwrap_or(-1)
+}
+
+/// Deletes the document with the key
+///
+/// Returns 1 if the document was deleted, 0 if there isn't a document with the key or -1
+/// on error.
+#[no_mangle]
+pub unsafe extern "C" fn kite_index_delete(index: *const KiteIndex, key: *const c_char) -> c_int {
+    call(|| {
+        let index = try!(read_handle(index));
+        let deleted = try!(index.store.remove_document_by_key(try!(read_str(key))));
+        Ok(if deleted { 1 } else { 0 })
+    }).unwrap_or(-1)
+}
+
+/// Opens a reader that sees the documents that have been inserted so far
+#[no_mangle]
+pub unsafe extern "C" fn kite_reader_open(index: *const KiteIndex) -> *mut KiteReader {
+    call(|| {
+        let store = try!(read_handle(index)).store.clone();
+
+        // The reader borrows the store, which is kept alive by the Arc that's stored next
+        // to it. Moving the Arc doesn't move the store so the borrow stays valid.
+        let reader = mem::transmute::<RocksDBReader, RocksDBReader<'static>>(store.reader());
+
+        Ok(Box::into_raw(Box::new(KiteReader { reader: reader, store: store })))
+    }).unwrap_or(ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn kite_reader_free(reader: *mut KiteReader) {
+    if !reader.is_null() {
+        drop(Box::from_raw(reader));
+    }
+}
+
+/// Searches the reader, returning the top "limit" documents as JSON
+///
+/// The result is an object with a list of "hits", each with the document's "id", "score"
+/// and the values of its stored "fields". Returns NULL on error.
+#[no_mangle]
+pub unsafe extern "C" fn kite_reader_search(reader: *const KiteReader, query_json: *const c_char, limit: size_t) -> *mut c_char {
+    call(|| {
+        let reader = &try!(read_handle(reader)).reader;
+        let schema = reader.schema();
+        let query = try!(json::parse_query(schema, &try!(read_json(query_json))));
+
+        let mut collector = TopScoreCollector::new(limit as usize);
+        try!(reader.search(&mut collector, &query));
+
+        let mut hits = Vec::new();
+        for doc in collector.into_sorted_vec() {
+            let mut fields = serde_json::Map::new();
+            for (field_id, field_info) in schema.iter() {
+                if !field_info.field_flags.contains(FIELD_STORED) {
+                    continue;
+                }
+
+                let value = try!(reader.read_stored_field(*field_id, DocId::from_u64(doc.doc_id())).map_err(|e| e.to_string()));
+                if let Some(value) = value {
+                    fields.insert(field_info.name().to_string(), field_value_to_json(&value));
+                }
+            }
+
+            hits.push(json!({
+                "id": doc.doc_id(),
+                "score": doc.score(),
+                "fields": fields,
+            }));
+        }
+
+        let results = json!({"hits": hits}).to_string();
+        Ok(CString::new(results).unwrap().into_raw())
+    }).unwrap_or(ptr::null_mut())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+    use std::ffi::{CStr, CString};
+
+    use serde_json::{self, Value};
+
+    use super::*;
+
+    fn c_string(string: &str) -> CString {
+        CString::new(string).unwrap()
+    }
+
+    unsafe fn search(reader: *const KiteReader, query: &str) -> Value {
+        let results = kite_reader_search(reader, c_string(query).as_ptr(), 10);
+        assert!(!results.is_null());
+        let value = serde_json::from_str(CStr::from_ptr(results).to_str().unwrap()).unwrap();
+        kite_string_free(results);
+        value
+    }
+
+    #[test]
+    fn test_index_lifecycle() {
+        let _ = remove_dir_all("test_indices/test_index_lifecycle");
+
+        unsafe {
+            let fields = c_string(r#"[
+                {"name": "title", "type": "Text", "flags": "INDEXED|STORED"},
+                {"name": "pk", "type": "I64", "flags": "STORED"}
+            ]"#);
+            let index = kite_index_create(c_string("test_indices/test_index_lifecycle").as_ptr(), fields.as_ptr());
+            assert!(!index.is_null());
+
+            assert_eq!(kite_index_insert(index, c_string(r#"{"key": "a", "fields": {"title": "Hello world", "pk": 1}}"#).as_ptr()), 0);
+            assert_eq!(kite_index_insert(index, c_string(r#"{"key": "b", "fields": {"title": "Hello again", "pk": 2}}"#).as_ptr()), 0);
+
+            let reader = kite_reader_open(index);
+            assert!(!reader.is_null());
+
+            // Readers stay usable after the index handle is freed
+            assert_eq!(kite_index_delete(index, c_string("b").as_ptr()), 1);
+            assert_eq!(kite_index_delete(index, c_string("b").as_ptr()), 0);
+            kite_index_free(index);
+
+            let results = search(reader, r#"{"term": {"field": "title", "value": "hello"}}"#);
+            let hits = results["hits"].as_array().unwrap();
+            assert_eq!(hits.len(), 2);
+            kite_reader_free(reader);
+
+            let index = kite_index_open(c_string("test_indices/test_index_lifecycle").as_ptr());
+            assert!(!index.is_null());
+            let reader = kite_reader_open(index);
+            let results = search(reader, r#"{"term": {"field": "title", "value": "world"}}"#);
+            assert_eq!(results["hits"][0]["fields"], json!({"title": "Hello world", "pk": 1}));
+            assert_eq!(search(reader, r#"{"all": {}}"#)["hits"].as_array().unwrap().len(), 1);
+
+            // Errors
+            assert!(kite_reader_search(reader, c_string(r#"{"term": {"field": "missing", "value": "x"}}"#).as_ptr(), 10).is_null());
+            assert_eq!(CStr::from_ptr(kite_last_error()).to_str().unwrap(), "field \"missing\" doesn't exist");
+            assert_eq!(kite_index_insert(index, c_string("not json").as_ptr()), -1);
+            assert_eq!(kite_index_insert(ptr::null(), c_string("{}").as_ptr()), -1);
+            assert_eq!(CStr::from_ptr(kite_last_error()).to_str().unwrap(), "unexpected null handle");
+
+            kite_reader_free(reader);
+            kite_index_free(index);
+        }
+    }
+}
@@ -51,6 +51,10 @@ fn bench_insert_single_document(b: &mut Bencher) {
             key: i.to_string(),
             indexed_fields: indexed_fields,
             stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
         });
     });
 }
@@ -85,6 +89,10 @@ fn bench_insert_documents_parallel(b: &mut Bencher) {
             key: (i + 1).to_string(),
             indexed_fields: indexed_fields,
             stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
         });
     }
 
@@ -47,6 +47,10 @@ fn bench_merge_segments(b: &mut Bencher) {
             key: i.to_string(),
             indexed_fields: indexed_fields,
             stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
         });
     }
 
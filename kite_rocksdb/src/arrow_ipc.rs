@@ -0,0 +1,473 @@
+//! A writer for the Apache Arrow IPC streaming format
+//!
+//! Only supports what's needed to export doc values: nullable Int64, Boolean and Timestamp
+//! (microseconds, UTC) columns.
+//!
+//! A stream is a schema message followed by any number of record batch messages and an end of
+//! stream marker. Each message is a Flatbuffers-encoded header (see Message.fbs and Schema.fbs
+//! in the Arrow repository) followed by the body containing the column buffers.
+
+use std::io::{self, Write};
+use std::cmp::Reverse;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The format version messages are written with (MetadataVersion::V5)
+const METADATA_VERSION: i16 = 4;
+
+const MESSAGE_HEADER_SCHEMA: u8 = 1;
+const MESSAGE_HEADER_RECORD_BATCH: u8 = 3;
+
+const TYPE_INT: u8 = 2;
+const TYPE_BOOL: u8 = 6;
+const TYPE_TIMESTAMP: u8 = 10;
+
+const TIME_UNIT_MICROSECOND: i16 = 2;
+
+const CONTINUATION_MARKER: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArrowType {
+    Int64,
+    Boolean,
+
+    /// Microseconds since the epoch, in UTC
+    Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArrowField {
+    pub name: String,
+    pub data_type: ArrowType,
+}
+
+/// A value in a Flatbuffers table
+enum FbValue {
+    Bool(bool),
+    U8(u8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    String(String),
+    Table(Vec<(u16, FbValue)>),
+    Tables(Vec<Vec<(u16, FbValue)>>),
+
+    /// A vector of structs made up of two longs (such as FieldNode and Buffer)
+    LongPairs(Vec<(i64, i64)>),
+}
+
+impl FbValue {
+    /// The number of bytes the value takes up in its table
+    fn inline_size(&self) -> usize {
+        match *self {
+            FbValue::Bool(_) | FbValue::U8(_) => 1,
+            FbValue::I16(_) => 2,
+            FbValue::I32(_) => 4,
+            FbValue::I64(_) => 8,
+            FbValue::String(_) | FbValue::Table(_) | FbValue::Tables(_) | FbValue::LongPairs(_) => 4,
+        }
+    }
+}
+
+fn pad_to(buf: &mut Vec<u8>, alignment: usize) {
+    while buf.len() % alignment != 0 {
+        buf.push(0);
+    }
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    let mut bytes = [0; 4];
+    LittleEndian::write_u32(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+fn push_i64(buf: &mut Vec<u8>, value: i64) {
+    let mut bytes = [0; 8];
+    LittleEndian::write_i64(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+/// Points the offset at "position" to "target"
+///
+/// Offsets are relative to where they are stored and can only point forwards, so everything a
+/// table or vector refers to is written after it.
+fn patch_offset(buf: &mut Vec<u8>, position: usize, target: usize) {
+    LittleEndian::write_u32(&mut buf[position..position + 4], (target - position) as u32);
+}
+
+/// Writes a table, followed by the objects it refers to, and returns its position
+fn write_table(buf: &mut Vec<u8>, fields: &[(u16, FbValue)]) -> usize {
+    // Lay out the fields with the largest first so none of them need padding
+    let mut order = (0..fields.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| Reverse(fields[i].1.inline_size()));
+    let mut field_offsets = vec![0; fields.len()];
+    let mut table_size = 4;
+    for i in order {
+        let size = fields[i].1.inline_size();
+        while table_size % size != 0 {
+            table_size += 1;
+        }
+        field_offsets[i] = table_size;
+        table_size += size;
+    }
+
+    // The vtable gives the offset of each field in the table, 0 for fields that aren't set
+    let num_slots = fields.iter().map(|&(slot, _)| slot as usize + 1).max().unwrap_or(0);
+    let mut vtable = vec![0u16; 2 + num_slots];
+    vtable[0] = (4 + num_slots * 2) as u16;
+    vtable[1] = table_size as u16;
+    for (i, &(slot, _)) in fields.iter().enumerate() {
+        vtable[2 + slot as usize] = field_offsets[i] as u16;
+    }
+
+    pad_to(buf, 2);
+    let vtable_position = buf.len();
+    for value in vtable {
+        let mut bytes = [0; 2];
+        LittleEndian::write_u16(&mut bytes, value);
+        buf.extend_from_slice(&bytes);
+    }
+
+    // Tables start with the distance back to their vtable
+    pad_to(buf, 8);
+    let table_position = buf.len();
+    buf.resize(table_position + table_size, 0);
+    LittleEndian::write_i32(&mut buf[table_position..], (table_position - vtable_position) as i32);
+
+    for (i, &(_, ref value)) in fields.iter().enumerate() {
+        let position = table_position + field_offsets[i];
+        match *value {
+            FbValue::Bool(value) => buf[position] = value as u8,
+            FbValue::U8(value) => buf[position] = value,
+            FbValue::I16(value) => LittleEndian::write_i16(&mut buf[position..], value),
+            FbValue::I32(value) => LittleEndian::write_i32(&mut buf[position..], value),
+            FbValue::I64(value) => LittleEndian::write_i64(&mut buf[position..], value),
+            FbValue::String(ref value) => {
+                pad_to(buf, 4);
+                let target = buf.len();
+                push_u32(buf, value.len() as u32);
+                buf.extend_from_slice(value.as_bytes());
+                buf.push(0);
+                patch_offset(buf, position, target);
+            }
+            FbValue::Table(ref fields) => {
+                let target = write_table(buf, fields);
+                patch_offset(buf, position, target);
+            }
+            FbValue::Tables(ref tables) => {
+                pad_to(buf, 4);
+                let target = buf.len();
+                push_u32(buf, tables.len() as u32);
+                buf.resize(target + 4 + tables.len() * 4, 0);
+                for (j, fields) in tables.iter().enumerate() {
+                    let table = write_table(buf, fields);
+                    patch_offset(buf, target + 4 + j * 4, table);
+                }
+                patch_offset(buf, position, target);
+            }
+            FbValue::LongPairs(ref pairs) => {
+                // The structs must be 8 byte aligned, they come straight after the length
+                pad_to(buf, 4);
+                if buf.len() % 8 == 0 {
+                    push_u32(buf, 0);
+                }
+                let target = buf.len();
+                push_u32(buf, pairs.len() as u32);
+                for &(a, b) in pairs {
+                    push_i64(buf, a);
+                    push_i64(buf, b);
+                }
+                patch_offset(buf, position, target);
+            }
+        }
+    }
+
+    table_position
+}
+
+/// Encodes a message header, the root of a Flatbuffer
+fn encode_message(header_type: u8, header: Vec<(u16, FbValue)>, body_length: usize) -> Vec<u8> {
+    let message = vec![
+        (0, FbValue::I16(METADATA_VERSION)),
+        (1, FbValue::U8(header_type)),
+        (2, FbValue::Table(header)),
+        (3, FbValue::I64(body_length as i64)),
+    ];
+
+    let mut buf = vec![0; 4];
+    let root = write_table(&mut buf, &message);
+    patch_offset(&mut buf, 0, root);
+    pad_to(&mut buf, 8);
+    buf
+}
+
+fn encode_field(field: &ArrowField) -> Vec<(u16, FbValue)> {
+    let (type_type, type_table) = match field.data_type {
+        ArrowType::Int64 => (TYPE_INT, vec![(0, FbValue::I32(64)), (1, FbValue::Bool(true))]),
+        ArrowType::Boolean => (TYPE_BOOL, vec![]),
+        ArrowType::Timestamp => (TYPE_TIMESTAMP, vec![(0, FbValue::I16(TIME_UNIT_MICROSECOND)), (1, FbValue::String("UTC".to_string()))]),
+    };
+
+    vec![
+        (0, FbValue::String(field.name.clone())),
+        (1, FbValue::Bool(true)),
+        (2, FbValue::U8(type_type)),
+        (3, FbValue::Table(type_table)),
+        (5, FbValue::Tables(vec![])),
+    ]
+}
+
+/// Packs booleans into a bitmap, least significant bit first
+fn encode_bitmap<I: Iterator<Item = bool>>(bits: I) -> Vec<u8> {
+    let mut bitmap = Vec::new();
+    for (i, bit) in bits.enumerate() {
+        if i % 8 == 0 {
+            bitmap.push(0);
+        }
+        if bit {
+            *bitmap.last_mut().unwrap() |= 1 << (i % 8);
+        }
+    }
+    bitmap
+}
+
+/// Writes a stream of record batches
+pub struct ArrowStreamWriter<W: Write> {
+    writer: W,
+    fields: Vec<ArrowField>,
+}
+
+impl<W: Write> ArrowStreamWriter<W> {
+    /// Starts a stream by writing its schema
+    pub fn new(writer: W, fields: Vec<ArrowField>) -> io::Result<ArrowStreamWriter<W>> {
+        let mut stream_writer = ArrowStreamWriter {
+            writer: writer,
+            fields: fields,
+        };
+
+        let schema = vec![
+            (0, FbValue::I16(0)),  // Little endian
+            (1, FbValue::Tables(stream_writer.fields.iter().map(encode_field).collect())),
+        ];
+        try!(stream_writer.write_message(&encode_message(MESSAGE_HEADER_SCHEMA, schema, 0), &[]));
+
+        Ok(stream_writer)
+    }
+
+    fn write_message(&mut self, metadata: &[u8], body: &[u8]) -> io::Result<()> {
+        let mut prefix = Vec::with_capacity(8);
+        push_u32(&mut prefix, CONTINUATION_MARKER);
+        push_u32(&mut prefix, metadata.len() as u32);
+        try!(self.writer.write_all(&prefix));
+        try!(self.writer.write_all(metadata));
+        self.writer.write_all(body)
+    }
+
+    /// Writes a record batch of "length" rows, with a column of values for each field of the schema
+    ///
+    /// Values of boolean columns must be 0 or 1.
+    pub fn write_batch(&mut self, length: usize, columns: &[Vec<Option<i64>>]) -> io::Result<()> {
+        assert_eq!(columns.len(), self.fields.len(), "a column must be given for each field");
+
+        let mut body = Vec::new();
+        let mut nodes = Vec::new();
+        let mut buffers = Vec::new();
+        {
+            let mut push_buffer = |body: &mut Vec<u8>, data: &[u8]| {
+                buffers.push((body.len() as i64, data.len() as i64));
+                body.extend_from_slice(data);
+                pad_to(body, 8);
+            };
+
+            for (field, column) in self.fields.iter().zip(columns) {
+                assert_eq!(column.len(), length, "all columns must be the same length");
+                let null_count = column.iter().filter(|value| value.is_none()).count();
+                nodes.push((length as i64, null_count as i64));
+
+                // The validity bitmap can be left out if there are no nulls
+                if null_count > 0 {
+                    push_buffer(&mut body, &encode_bitmap(column.iter().map(|value| value.is_some())));
+                } else {
+                    push_buffer(&mut body, &[]);
+                }
+
+                match field.data_type {
+                    ArrowType::Int64 | ArrowType::Timestamp => {
+                        let mut values = vec![0; length * 8];
+                        for (i, value) in column.iter().enumerate() {
+                            LittleEndian::write_i64(&mut values[i * 8..], value.unwrap_or(0));
+                        }
+                        push_buffer(&mut body, &values);
+                    }
+                    ArrowType::Boolean => {
+                        push_buffer(&mut body, &encode_bitmap(column.iter().map(|value| value.unwrap_or(0) != 0)));
+                    }
+                }
+            }
+        }
+
+        let record_batch = vec![
+            (0, FbValue::I64(length as i64)),
+            (1, FbValue::LongPairs(nodes)),
+            (2, FbValue::LongPairs(buffers)),
+        ];
+        let metadata = encode_message(MESSAGE_HEADER_RECORD_BATCH, record_batch, body.len());
+        self.write_message(&metadata, &body)
+    }
+
+    /// Writes the end of stream marker and returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.write_message(&[], &[]));
+        try!(self.writer.flush());
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::{ArrowStreamWriter, ArrowField, ArrowType};
+
+    /// Just enough of a Flatbuffers reader to check the messages
+    struct Table<'a> {
+        buf: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> Table<'a> {
+        fn root(buf: &'a [u8]) -> Table<'a> {
+            Table { buf: buf, position: LittleEndian::read_u32(buf) as usize }
+        }
+
+        fn field(&self, slot: usize) -> Option<usize> {
+            let vtable = (self.position as i64 - LittleEndian::read_i32(&self.buf[self.position..]) as i64) as usize;
+            let vtable_size = LittleEndian::read_u16(&self.buf[vtable..]) as usize;
+            if 4 + slot * 2 >= vtable_size {
+                return None;
+            }
+
+            match LittleEndian::read_u16(&self.buf[vtable + 4 + slot * 2..]) {
+                0 => None,
+                offset => Some(self.position + offset as usize),
+            }
+        }
+
+        fn deref(&self, position: usize) -> usize {
+            position + LittleEndian::read_u32(&self.buf[position..]) as usize
+        }
+
+        fn i64(&self, slot: usize) -> i64 {
+            LittleEndian::read_i64(&self.buf[self.field(slot).unwrap()..])
+        }
+
+        fn u8(&self, slot: usize) -> u8 {
+            self.buf[self.field(slot).unwrap()]
+        }
+
+        fn table(&self, slot: usize) -> Table<'a> {
+            Table { buf: self.buf, position: self.deref(self.field(slot).unwrap()) }
+        }
+
+        fn string(&self, slot: usize) -> &'a str {
+            let position = self.deref(self.field(slot).unwrap());
+            let len = LittleEndian::read_u32(&self.buf[position..]) as usize;
+            ::std::str::from_utf8(&self.buf[position + 4..position + 4 + len]).unwrap()
+        }
+
+        fn tables(&self, slot: usize) -> Vec<Table<'a>> {
+            let position = self.deref(self.field(slot).unwrap());
+            let len = LittleEndian::read_u32(&self.buf[position..]) as usize;
+            (0..len).map(|i| Table { buf: self.buf, position: self.deref(position + 4 + i * 4) }).collect()
+        }
+
+        fn long_pairs(&self, slot: usize) -> Vec<(i64, i64)> {
+            let position = self.deref(self.field(slot).unwrap());
+            assert_eq!((position + 4) % 8, 0);
+            let len = LittleEndian::read_u32(&self.buf[position..]) as usize;
+            (0..len).map(|i| {
+                let pair = position + 4 + i * 16;
+                (LittleEndian::read_i64(&self.buf[pair..]), LittleEndian::read_i64(&self.buf[pair + 8..]))
+            }).collect()
+        }
+    }
+
+    /// Splits a stream into the metadata and body of each message
+    fn read_messages(mut stream: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut messages = Vec::new();
+        loop {
+            assert_eq!(LittleEndian::read_u32(stream), 0xFFFF_FFFF);
+            let metadata_length = LittleEndian::read_u32(&stream[4..]) as usize;
+            if metadata_length == 0 {
+                assert_eq!(stream.len(), 8);
+                return messages;
+            }
+
+            assert_eq!(metadata_length % 8, 0);
+            let metadata = &stream[8..8 + metadata_length];
+            let body_length = Table::root(metadata).i64(3) as usize;
+            messages.push((metadata, &stream[8 + metadata_length..8 + metadata_length + body_length]));
+            stream = &stream[8 + metadata_length + body_length..];
+        }
+    }
+
+    #[test]
+    fn test_stream() {
+        let fields = vec![
+            ArrowField { name: "count".to_string(), data_type: ArrowType::Int64 },
+            ArrowField { name: "published".to_string(), data_type: ArrowType::Boolean },
+            ArrowField { name: "created".to_string(), data_type: ArrowType::Timestamp },
+        ];
+        let mut writer = ArrowStreamWriter::new(Vec::new(), fields).unwrap();
+        writer.write_batch(3, &[
+            vec![Some(1), Some(-2), Some(3)],
+            vec![Some(1), None, Some(0)],
+            vec![None, None, Some(1000000)],
+        ]).unwrap();
+        let stream = writer.finish().unwrap();
+
+        let messages = read_messages(&stream);
+        assert_eq!(messages.len(), 2);
+
+        // Schema
+        let message = Table::root(messages[0].0);
+        assert_eq!(message.u8(1), 1);
+        let fields = message.table(2).tables(1);
+        assert_eq!(fields.iter().map(|field| field.string(0)).collect::<Vec<_>>(), vec!["count", "published", "created"]);
+        assert_eq!(fields.iter().map(|field| field.u8(2)).collect::<Vec<_>>(), vec![2, 6, 10]);
+        assert_eq!(fields[0].table(3).u8(1), 1);
+        assert_eq!(fields[2].table(3).string(1), "UTC");
+        assert!(fields.iter().all(|field| field.tables(5).is_empty()));
+
+        // Record batch
+        let message = Table::root(messages[1].0);
+        assert_eq!(message.u8(1), 3);
+        let record_batch = message.table(2);
+        assert_eq!(record_batch.i64(0), 3);
+        assert_eq!(record_batch.long_pairs(1), vec![(3, 0), (3, 1), (3, 2)]);
+
+        let body = messages[1].1;
+        let buffers = record_batch.long_pairs(2);
+        let buffer = |i: usize| &body[buffers[i].0 as usize..(buffers[i].0 + buffers[i].1) as usize];
+        assert!(buffers.iter().all(|&(offset, _)| offset % 8 == 0));
+        assert_eq!(buffer(0), b"");
+        assert_eq!((0..3).map(|i| LittleEndian::read_i64(&buffer(1)[i * 8..])).collect::<Vec<_>>(), vec![1, -2, 3]);
+        assert_eq!(buffer(2), &[0b101]);
+        assert_eq!(buffer(3), &[0b001]);
+        assert_eq!(buffer(4), &[0b100]);
+        assert_eq!(LittleEndian::read_i64(&buffer(5)[16..]), 1000000);
+    }
+
+    #[test]
+    fn test_no_columns() {
+        let mut writer = ArrowStreamWriter::new(Vec::new(), vec![]).unwrap();
+        writer.write_batch(5, &[]).unwrap();
+        let stream = writer.finish().unwrap();
+
+        let messages = read_messages(&stream);
+        assert_eq!(messages.len(), 2);
+        assert!(Table::root(messages[0].0).table(2).tables(1).is_empty());
+        assert_eq!(Table::root(messages[1].0).table(2).i64(0), 5);
+        assert_eq!(messages[1].1, b"");
+    }
+}
@@ -0,0 +1,616 @@
+//! Ingests documents in the Elasticsearch bulk format
+//!
+//! The body is newline delimited JSON. Each action is on its own line and, apart from "delete",
+//! is followed by a line with its document:
+//!
+//! ```json
+//! {"index": {"_id": "1"}}
+//! {"title": "Hello world"}
+//! {"update": {"_id": "1"}}
+//! {"doc": {"title": "Hello again"}}
+//! {"delete": {"_id": "2"}}
+//! ```
+//!
+//! Documents are stored as their source, keyed by "_id", so they can be updated later. Their
+//! fields are mapped onto the schema by name (see "json_document"). Unlike Elasticsearch, every
+//! action must have an "_id", the "_index" is ignored and updates can't run scripts.
+//!
+//! Actions run in order, but documents are written together in batched segments rather than
+//! one segment each.
+
+use std::fmt;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use serde_json::{self, Value};
+use kite::{Document, DocumentBuilder};
+use fnv::FnvHashMap;
+use rocksdb;
+
+use {RocksDBStore, RocksDBReader, DocumentInsertError, StoredFieldReadError};
+use json_document::add_json_fields;
+
+#[derive(Debug, Clone)]
+pub struct BulkConfig {
+    /// The most documents that are written into each segment
+    pub batch_size: usize,
+
+    /// Skip fields that aren't in the schema rather than failing the document
+    pub ignore_unknown_fields: bool,
+}
+
+impl Default for BulkConfig {
+    fn default() -> BulkConfig {
+        BulkConfig {
+            batch_size: 1000,
+            ignore_unknown_fields: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BulkOp {
+    /// Inserts a document, replacing any document with the same id
+    Index,
+
+    /// Inserts a document, unless there's already a document with the id
+    Create,
+
+    /// Merges fields into an existing document
+    Update,
+
+    /// Deletes a document
+    Delete,
+}
+
+impl BulkOp {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BulkOp::Index => "index",
+            BulkOp::Create => "create",
+            BulkOp::Update => "update",
+            BulkOp::Delete => "delete",
+        }
+    }
+}
+
+/// An action and its document, as parsed from a bulk body
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkAction {
+    pub op: BulkOp,
+    pub id: String,
+    pub routing: Option<String>,
+
+    /// The document for index and create, the "doc"/"upsert" object for update and None for delete
+    pub body: Option<Value>,
+}
+
+#[derive(Debug)]
+pub enum BulkError {
+    /// A line isn't valid JSON
+    InvalidJson(usize, serde_json::Error),
+
+    /// An action line isn't a valid action
+    InvalidAction(usize, String),
+
+    /// An action is missing the line with its document
+    MissingBody(usize),
+
+    /// A RocksDB error occurred, actions before this one may have been applied
+    RocksDBError(rocksdb::Error),
+
+    /// An existing document couldn't be read, actions before this one may have been applied
+    ReadError(StoredFieldReadError),
+}
+
+impl From<rocksdb::Error> for BulkError {
+    fn from(e: rocksdb::Error) -> BulkError {
+        BulkError::RocksDBError(e)
+    }
+}
+
+impl From<StoredFieldReadError> for BulkError {
+    fn from(e: StoredFieldReadError) -> BulkError {
+        BulkError::ReadError(e)
+    }
+}
+
+impl fmt::Display for BulkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BulkError::InvalidJson(line, ref e) => write!(f, "line {} isn't valid JSON: {}", line, e),
+            BulkError::InvalidAction(line, ref reason) => write!(f, "line {} isn't a valid action: {}", line, reason),
+            BulkError::MissingBody(line) => write!(f, "the action on line {} must be followed by a document", line),
+            BulkError::RocksDBError(ref e) => write!(f, "RocksDB error: {}", e),
+            BulkError::ReadError(ref e) => write!(f, "couldn't read document: {}", e),
+        }
+    }
+}
+
+impl Error for BulkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BulkError::InvalidJson(_, ref e) => Some(e),
+            BulkError::RocksDBError(ref e) => Some(e),
+            BulkError::ReadError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of an action that succeeded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BulkItemResult {
+    Created,
+    Updated,
+    Deleted,
+    NotFound,
+}
+
+/// The reason an action failed, the other actions still run
+#[derive(Debug)]
+pub enum BulkItemError {
+    /// A create action's document already exists
+    VersionConflict,
+
+    /// An update action's document doesn't exist and there's nothing to upsert
+    DocumentMissing,
+
+    /// The document couldn't be mapped onto the schema
+    InvalidDocument(String),
+
+    /// The action uses something that Kite doesn't support, such as scripts
+    Unsupported(String),
+
+    /// The document couldn't be written into its segment
+    InsertError(DocumentInsertError),
+}
+
+impl fmt::Display for BulkItemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BulkItemError::VersionConflict => write!(f, "document already exists"),
+            BulkItemError::DocumentMissing => write!(f, "document missing"),
+            BulkItemError::InvalidDocument(ref reason) => write!(f, "invalid document: {}", reason),
+            BulkItemError::Unsupported(ref reason) => write!(f, "{}", reason),
+            BulkItemError::InsertError(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for BulkItemError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BulkItemError::InsertError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BulkItem {
+    pub op: BulkOp,
+    pub id: String,
+    pub result: Result<BulkItemResult, BulkItemError>,
+}
+
+impl BulkItem {
+    /// The HTTP status code Elasticsearch would give the action
+    pub fn status(&self) -> u16 {
+        match self.result {
+            Ok(BulkItemResult::Created) => 201,
+            Ok(BulkItemResult::Updated) | Ok(BulkItemResult::Deleted) => 200,
+            Ok(BulkItemResult::NotFound) | Err(BulkItemError::DocumentMissing) => 404,
+            Err(BulkItemError::VersionConflict) => 409,
+            Err(_) => 400,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut item = json!({
+            "_id": self.id,
+            "status": self.status(),
+        });
+
+        match self.result {
+            Ok(result) => {
+                item["result"] = Value::from(match result {
+                    BulkItemResult::Created => "created",
+                    BulkItemResult::Updated => "updated",
+                    BulkItemResult::Deleted => "deleted",
+                    BulkItemResult::NotFound => "not_found",
+                });
+            }
+            Err(ref e) => {
+                let error_type = match *e {
+                    BulkItemError::VersionConflict => "version_conflict_engine_exception",
+                    BulkItemError::DocumentMissing => "document_missing_exception",
+                    BulkItemError::InvalidDocument(_) => "mapper_parsing_exception",
+                    BulkItemError::Unsupported(_) | BulkItemError::InsertError(_) => "illegal_argument_exception",
+                };
+                item["error"] = json!({"type": error_type, "reason": e.to_string()});
+            }
+        }
+
+        let mut wrapper = serde_json::Map::new();
+        wrapper.insert(self.op.name().to_string(), item);
+        Value::Object(wrapper)
+    }
+}
+
+#[derive(Debug)]
+pub struct BulkResponse {
+    pub took: Duration,
+
+    /// The result of each action, in the order they were given
+    pub items: Vec<BulkItem>,
+}
+
+impl BulkResponse {
+    /// Returns true if any of the actions failed
+    pub fn has_errors(&self) -> bool {
+        self.items.iter().any(|item| item.result.is_err())
+    }
+
+    /// Formats the response like Elasticsearch does
+    pub fn to_json(&self) -> Value {
+        let took = self.took.as_secs() * 1000 + self.took.subsec_nanos() as u64 / 1_000_000;
+
+        json!({
+            "took": took,
+            "errors": self.has_errors(),
+            "items": self.items.iter().map(BulkItem::to_json).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn parse_action(line_number: usize, value: &Value) -> Result<(BulkOp, String, Option<String>), BulkError> {
+    let object = try!(value.as_object().ok_or(BulkError::InvalidAction(line_number, "expected an object".to_string())));
+    if object.len() != 1 {
+        return Err(BulkError::InvalidAction(line_number, "expected exactly one action".to_string()));
+    }
+    let (name, metadata) = object.iter().next().unwrap();
+
+    let op = match name.as_ref() {
+        "index" => BulkOp::Index,
+        "create" => BulkOp::Create,
+        "update" => BulkOp::Update,
+        "delete" => BulkOp::Delete,
+        _ => return Err(BulkError::InvalidAction(line_number, format!("unknown action {:?}", name))),
+    };
+
+    let id = match metadata.get("_id") {
+        Some(&Value::String(ref id)) => id.clone(),
+        Some(&Value::Number(ref id)) => id.to_string(),
+        _ => return Err(BulkError::InvalidAction(line_number, "action must have an \"_id\"".to_string())),
+    };
+
+    let routing = metadata.get("routing").or(metadata.get("_routing")).and_then(Value::as_str).map(|routing| routing.to_string());
+
+    Ok((op, id, routing))
+}
+
+/// Parses a bulk body into its actions
+///
+/// The whole body is parsed before anything is run so a malformed line doesn't leave the
+/// actions before it half applied.
+pub fn parse_bulk(body: &str) -> Result<Vec<BulkAction>, BulkError> {
+    let mut actions = Vec::new();
+    let mut lines = body.lines().enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|&(_, line)| !line.trim().is_empty());
+
+    while let Some((line_number, line)) = lines.next() {
+        let value = try!(serde_json::from_str(line).map_err(|e| BulkError::InvalidJson(line_number, e)));
+        let (op, id, routing) = try!(parse_action(line_number, &value));
+
+        let body = if op == BulkOp::Delete {
+            None
+        } else {
+            let (body_line_number, body_line) = try!(lines.next().ok_or(BulkError::MissingBody(line_number)));
+            Some(try!(serde_json::from_str(body_line).map_err(|e| BulkError::InvalidJson(body_line_number, e))))
+        };
+
+        actions.push(BulkAction {
+            op: op,
+            id: id,
+            routing: routing,
+            body: body,
+        });
+    }
+
+    Ok(actions)
+}
+
+/// Recursively merges the fields of "patch" into "target", like an Elasticsearch partial update
+fn merge_json(target: &mut Value, patch: &Value) {
+    match (target, patch) {
+        (&mut Value::Object(ref mut target), &Value::Object(ref patch)) => {
+            for (key, value) in patch {
+                match target.get_mut(key) {
+                    Some(existing) if existing.is_object() && value.is_object() => {
+                        merge_json(existing, value);
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                target.insert(key.clone(), value.clone());
+            }
+        }
+        (target, patch) => *target = patch.clone(),
+    }
+}
+
+/// A document that's waiting to be written in the next batch
+struct PendingDoc {
+    item: usize,
+    source: Value,
+    doc: Document,
+}
+
+/// The current state of a document, for create and update actions
+struct ExistingDoc {
+    /// None if the document wasn't stored with its source
+    source: Option<Value>,
+    routing: Option<String>,
+}
+
+struct BulkExecutor<'a> {
+    store: &'a RocksDBStore,
+    config: &'a BulkConfig,
+    items: Vec<BulkItem>,
+    pending: Vec<Option<PendingDoc>>,
+    pending_keys: FnvHashMap<String, usize>,
+    num_pending: usize,
+
+    /// Sees everything except the pending documents, renewed after each write
+    reader: RocksDBReader<'a>,
+}
+
+impl<'a> BulkExecutor<'a> {
+    fn new(store: &'a RocksDBStore, config: &'a BulkConfig) -> BulkExecutor<'a> {
+        BulkExecutor {
+            store: store,
+            config: config,
+            items: Vec::new(),
+            pending: Vec::new(),
+            pending_keys: FnvHashMap::default(),
+            num_pending: 0,
+            reader: store.reader(),
+        }
+    }
+
+    fn exists(&self, id: &str) -> Result<bool, rocksdb::Error> {
+        Ok(self.pending_keys.contains_key(id) || try!(self.reader.get_document_id(id)).is_some())
+    }
+
+    fn get_existing(&self, id: &str) -> Result<Option<ExistingDoc>, StoredFieldReadError> {
+        if let Some(&i) = self.pending_keys.get(id) {
+            let pending = self.pending[i].as_ref().unwrap();
+
+            return Ok(Some(ExistingDoc {
+                source: Some(pending.source.clone()),
+                routing: pending.doc.routing.clone(),
+            }));
+        }
+
+        let doc_id = match try!(self.reader.get_document_id(id)) {
+            Some(doc_id) => doc_id,
+            None => return Ok(None),
+        };
+
+        let source = try!(self.reader.read_source(doc_id)).and_then(|source| serde_json::from_slice(&source).ok());
+
+        Ok(Some(ExistingDoc {
+            source: source,
+            routing: try!(self.reader.read_routing(doc_id)),
+        }))
+    }
+
+    /// Adds a document to the next batch, replacing any pending document with the same id
+    fn stage(&mut self, id: &str, routing: Option<String>, source: Value) -> Result<(), BulkItemError> {
+        let mut builder = DocumentBuilder::new(self.store.schema(), id);
+        builder = try!(add_json_fields(builder, self.store.schema(), &source, self.config.ignore_unknown_fields).map_err(BulkItemError::InvalidDocument));
+        if let Some(ref routing) = routing {
+            builder = builder.routing(routing);
+        }
+        let doc = try!(builder.source(serde_json::to_vec(&source).unwrap()).build().map_err(|e| BulkItemError::InvalidDocument(e.to_string())));
+
+        if let Some(i) = self.pending_keys.remove(id) {
+            self.pending[i] = None;
+            self.num_pending -= 1;
+        }
+
+        self.pending_keys.insert(id.to_string(), self.pending.len());
+        self.pending.push(Some(PendingDoc {
+            item: self.items.len(),
+            source: source,
+            doc: doc,
+        }));
+        self.num_pending += 1;
+
+        Ok(())
+    }
+
+    fn execute(&mut self, action: BulkAction) -> Result<(), BulkError> {
+        let result = match action.op {
+            BulkOp::Index => {
+                let existed = try!(self.exists(&action.id));
+                self.stage(&action.id, action.routing, action.body.unwrap())
+                    .map(|_| if existed { BulkItemResult::Updated } else { BulkItemResult::Created })
+            }
+            BulkOp::Create => {
+                if try!(self.exists(&action.id)) {
+                    Err(BulkItemError::VersionConflict)
+                } else {
+                    self.stage(&action.id, action.routing, action.body.unwrap()).map(|_| BulkItemResult::Created)
+                }
+            }
+            BulkOp::Update => {
+                let existing = try!(self.get_existing(&action.id));
+                self.update(&action.id, action.routing, action.body.unwrap(), existing)
+            }
+            BulkOp::Delete => self.delete(&action.id),
+        };
+
+        self.items.push(BulkItem {
+            op: action.op,
+            id: action.id,
+            result: result,
+        });
+
+        if self.num_pending >= self.config.batch_size.max(1) {
+            try!(self.flush());
+        }
+
+        Ok(())
+    }
+
+    fn update(&mut self, id: &str, routing: Option<String>, body: Value, existing: Option<ExistingDoc>) -> Result<BulkItemResult, BulkItemError> {
+        if body.get("script").is_some() {
+            return Err(BulkItemError::Unsupported("scripted updates aren't supported".to_string()));
+        }
+
+        let doc = body.get("doc");
+        match existing {
+            Some(existing) => {
+                let mut source = try!(existing.source.ok_or(BulkItemError::InvalidDocument("the document wasn't stored with its source so can't be updated".to_string())));
+                if let Some(doc) = doc {
+                    merge_json(&mut source, doc);
+                }
+
+                try!(self.stage(id, routing.or(existing.routing), source));
+                Ok(BulkItemResult::Updated)
+            }
+            None => {
+                let upsert = if body.get("doc_as_upsert").and_then(Value::as_bool) == Some(true) {
+                    doc
+                } else {
+                    body.get("upsert")
+                };
+
+                match upsert {
+                    Some(upsert) => {
+                        try!(self.stage(id, routing, upsert.clone()));
+                        Ok(BulkItemResult::Created)
+                    }
+                    None => Err(BulkItemError::DocumentMissing),
+                }
+            }
+        }
+    }
+
+    fn delete(&mut self, id: &str) -> Result<BulkItemResult, BulkItemError> {
+        let was_pending = match self.pending_keys.remove(id) {
+            Some(i) => {
+                self.pending[i] = None;
+                self.num_pending -= 1;
+                true
+            }
+            None => false,
+        };
+
+        // The document may have been in the index before it was pending, so this is
+        // needed either way
+        let deleted = try!(self.store.remove_document_by_key(id).map_err(|e| BulkItemError::InsertError(e.into())));
+        if deleted {
+            self.reader = self.store.reader();
+        }
+
+        Ok(if deleted || was_pending { BulkItemResult::Deleted } else { BulkItemResult::NotFound })
+    }
+
+    /// Writes the pending documents into a segment
+    fn flush(&mut self) -> Result<(), BulkError> {
+        let batch = self.pending.drain(..)
+            .filter_map(|pending| pending)
+            .map(|pending| (pending.item as u64, pending.doc))
+            .collect::<Vec<_>>();
+        self.pending_keys.clear();
+        self.num_pending = 0;
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        for (item, result) in self.store.commit_document_batch(batch) {
+            match result {
+                Ok(()) => {}
+                Err(DocumentInsertError::RocksDBError(e)) => return Err(BulkError::RocksDBError(e)),
+                Err(e) => self.items[item as usize].result = Err(BulkItemError::InsertError(e)),
+            }
+        }
+
+        self.reader = self.store.reader();
+        Ok(())
+    }
+}
+
+impl RocksDBStore {
+    /// Runs the actions in an Elasticsearch bulk body
+    ///
+    /// Each action succeeds or fails on its own and its result is in the response. An error is
+    /// only returned if the body can't be parsed, in which case nothing is run, or RocksDB fails.
+    pub fn bulk(&self, body: &str, config: &BulkConfig) -> Result<BulkResponse, BulkError> {
+        let started = Instant::now();
+        let actions = try!(parse_bulk(body));
+
+        let mut executor = BulkExecutor::new(self, config);
+        for action in actions {
+            try!(executor.execute(action));
+        }
+        try!(executor.flush());
+
+        log_debug!("bulk request ran {} actions", executor.items.len());
+
+        Ok(BulkResponse {
+            took: started.elapsed(),
+            items: executor.items,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_bulk, merge_json, BulkOp, BulkError};
+
+    #[test]
+    fn test_parse_bulk() {
+        let actions = parse_bulk(concat!(
+            "{\"index\": {\"_index\": \"test\", \"_id\": \"1\", \"routing\": \"a\"}}\n",
+            "{\"title\": \"Hello\"}\n",
+            "\n",
+            "{\"delete\": {\"_id\": 2}}\n",
+        )).unwrap();
+
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].op, BulkOp::Index);
+        assert_eq!(actions[0].id, "1");
+        assert_eq!(actions[0].routing, Some("a".to_string()));
+        assert_eq!(actions[0].body, Some(json!({"title": "Hello"})));
+        assert_eq!(actions[1].op, BulkOp::Delete);
+        assert_eq!(actions[1].id, "2");
+        assert_eq!(actions[1].body, None);
+
+        match parse_bulk("{\"index\": {\"_id\": \"1\"}}\n") {
+            Err(BulkError::MissingBody(1)) => {}
+            result => panic!("expected a missing body error, got {:?}", result),
+        }
+        match parse_bulk("{\"upsert\": {\"_id\": \"1\"}}\n{}\n") {
+            Err(BulkError::InvalidAction(1, _)) => {}
+            result => panic!("expected an invalid action error, got {:?}", result),
+        }
+        match parse_bulk("{\"delete\": {\"_id\": \"1\"}}\nnot json\n") {
+            Err(BulkError::InvalidJson(2, _)) => {}
+            result => panic!("expected an invalid JSON error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_merge_json() {
+        let mut source = json!({"title": "Hello", "meta": {"a": 1, "b": 2}});
+        merge_json(&mut source, &json!({"meta": {"b": 3}, "tags": ["x"]}));
+        assert_eq!(source, json!({"title": "Hello", "meta": {"a": 1, "b": 3}, "tags": ["x"]}));
+    }
+}
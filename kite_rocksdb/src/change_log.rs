@@ -0,0 +1,315 @@
+use std::str;
+use std::sync::{Mutex, MutexGuard};
+
+use rocksdb::{self, DB, WriteBatch};
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+use RocksDBStore;
+use key_builder::KeyBuilder;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    /// The document was inserted or replaced
+    Upsert,
+
+    /// The document was deleted
+    Delete,
+}
+
+/// An entry in the change log
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Changes are numbered in the order they were committed, starting at 1
+    pub sequence: u64,
+    pub kind: ChangeKind,
+
+    /// The tenant of the document, None for documents that weren't written through a tenant
+    pub tenant: Option<String>,
+
+    pub key: String,
+
+    /// Set by the change payload function, if there is one
+    pub payload: Option<Vec<u8>>,
+}
+
+/// A change that hasn't been given a sequence number yet
+pub struct PendingChange {
+    pub kind: ChangeKind,
+    pub tenant: Option<Vec<u8>>,
+    pub key: Vec<u8>,
+    pub payload: Option<Vec<u8>>,
+}
+
+fn push_length_prefixed(bytes: &mut Vec<u8>, value: &[u8]) {
+    let mut length = [0; 4];
+    LittleEndian::write_u32(&mut length, value.len() as u32);
+    bytes.extend(&length);
+    bytes.extend(value);
+}
+
+/// Changes of documents that belong to a tenant have an upper case kind and the tenant is
+/// written before the key. Changes written before tenants were added are read as having none.
+fn encode_change(change: &PendingChange) -> Vec<u8> {
+    let tenant_length = change.tenant.as_ref().map(|tenant| 4 + tenant.len()).unwrap_or(0);
+    let mut bytes = Vec::with_capacity(6 + tenant_length + change.key.len() + change.payload.as_ref().map(|payload| payload.len()).unwrap_or(0));
+    bytes.push(match (change.kind, change.tenant.is_some()) {
+        (ChangeKind::Upsert, false) => b'u',
+        (ChangeKind::Delete, false) => b'd',
+        (ChangeKind::Upsert, true) => b'U',
+        (ChangeKind::Delete, true) => b'D',
+    });
+
+    if let Some(ref tenant) = change.tenant {
+        push_length_prefixed(&mut bytes, tenant);
+    }
+
+    push_length_prefixed(&mut bytes, &change.key);
+
+    if let Some(ref payload) = change.payload {
+        bytes.push(1);
+        bytes.extend(payload);
+    } else {
+        bytes.push(0);
+    }
+
+    bytes
+}
+
+/// Reads a length prefixed string from the start of "bytes", returns it and the rest of the bytes
+fn read_length_prefixed<'a>(sequence: u64, bytes: &'a [u8], name: &str) -> Result<(String, &'a [u8]), String> {
+    if bytes.len() < 4 {
+        return Err(format!("change {} is truncated", sequence));
+    }
+
+    let length = LittleEndian::read_u32(&bytes[0..4]) as usize;
+    if bytes.len() < 4 + length {
+        return Err(format!("change {} is truncated", sequence));
+    }
+
+    match str::from_utf8(&bytes[4..4 + length]) {
+        Ok(value) => Ok((value.to_string(), &bytes[4 + length..])),
+        Err(e) => Err(format!("change {} {} isn't UTF-8: {}", sequence, name, e)),
+    }
+}
+
+fn decode_change(sequence: u64, bytes: &[u8]) -> Result<Change, String> {
+    if bytes.len() < 6 {
+        return Err(format!("change {} is truncated", sequence));
+    }
+
+    let (kind, has_tenant) = match bytes[0] {
+        b'u' => (ChangeKind::Upsert, false),
+        b'd' => (ChangeKind::Delete, false),
+        b'U' => (ChangeKind::Upsert, true),
+        b'D' => (ChangeKind::Delete, true),
+        kind => return Err(format!("change {} has unrecognised kind {}", sequence, kind)),
+    };
+
+    let mut rest = &bytes[1..];
+    let tenant = if has_tenant {
+        let (tenant, after_tenant) = try!(read_length_prefixed(sequence, rest, "tenant"));
+        rest = after_tenant;
+        Some(tenant)
+    } else {
+        None
+    };
+
+    let (key, rest) = try!(read_length_prefixed(sequence, rest, "key"));
+
+    let payload = match rest.first() {
+        Some(&0) => None,
+        Some(_) => Some(rest[1..].to_vec()),
+        None => return Err(format!("change {} is truncated", sequence)),
+    };
+
+    Ok(Change {
+        sequence: sequence,
+        kind: kind,
+        tenant: tenant,
+        key: key,
+        payload: payload,
+    })
+}
+
+/// Records document inserts, updates and deletes in the order they're committed
+///
+/// Changes are written in the same write batch as the primary key index update they
+/// describe, so the log can't miss a change or contain one that didn't happen.
+pub struct ChangeLog {
+    enabled: bool,
+    last_sequence: Mutex<u64>,
+}
+
+impl ChangeLog {
+    pub fn new(_db: &DB) -> Result<ChangeLog, rocksdb::Error> {
+        Ok(ChangeLog {
+            enabled: false,
+            last_sequence: Mutex::new(0),
+        })
+    }
+
+    pub fn open(db: &DB) -> Result<ChangeLog, rocksdb::Error> {
+        let last_sequence = match try!(db.get(b".last_change")) {
+            Some(last_sequence) => str::from_utf8(&last_sequence).ok().and_then(|last_sequence| last_sequence.parse::<u64>().ok()).unwrap_or(0),
+            None => 0,
+        };
+
+        Ok(ChangeLog {
+            enabled: false,
+            last_sequence: Mutex::new(last_sequence),
+        })
+    }
+
+    /// Adds the changes to the write batch
+    ///
+    /// If the log is enabled, the returned guard must be held until the write batch has been
+    /// written so changes are committed in sequence order.
+    pub fn log(&self, write_batch: &mut WriteBatch, changes: &[PendingChange]) -> Result<Option<MutexGuard<u64>>, rocksdb::Error> {
+        if !self.enabled || changes.is_empty() {
+            return Ok(None);
+        }
+
+        let mut last_sequence = self.last_sequence.lock().unwrap();
+        for change in changes {
+            *last_sequence += 1;
+            let kb = KeyBuilder::change(*last_sequence);
+            try!(write_batch.put(kb.key(), &encode_change(change)));
+        }
+        try!(write_batch.put(b".last_change", last_sequence.to_string().as_bytes()));
+
+        Ok(Some(last_sequence))
+    }
+}
+
+impl RocksDBStore {
+    /// Records document inserts, updates and deletes in the change log
+    ///
+    /// Consumers can tail the log with "changes_since". Changes made while the log is
+    /// disabled aren't recorded.
+    pub fn set_change_log_enabled(&mut self, enabled: bool) {
+        self.change_log.enabled = enabled;
+    }
+
+    /// Sets a function to create the payload of each upsert in the change log
+    ///
+    /// This could be the parts of the document that a downstream consumer needs, so it
+    /// doesn't need to read the document back from the index.
+    pub fn set_change_payload<F>(&mut self, change_payload: F)
+        where F: Fn(&::kite::Document) -> Option<Vec<u8>> + Send + Sync + 'static
+    {
+        self.change_payload = Some(Box::new(change_payload));
+    }
+
+    pub(crate) fn upsert_change(&self, doc: &::kite::Document) -> PendingChange {
+        PendingChange {
+            kind: ChangeKind::Upsert,
+            tenant: None,
+            key: doc.key.as_bytes().to_vec(),
+            payload: self.change_payload.as_ref().and_then(|change_payload| change_payload(doc)),
+        }
+    }
+
+    /// The sequence number of the last change that was logged, 0 if there are none
+    pub fn last_change_sequence(&self) -> u64 {
+        *self.change_log.last_sequence.lock().unwrap()
+    }
+
+    /// Returns up to "limit" changes with sequence numbers after "sequence", oldest first
+    ///
+    /// To tail the log, pass the sequence number of the last change that was processed.
+    pub fn changes_since(&self, sequence: u64, limit: usize) -> Result<Vec<Change>, String> {
+        let mut changes = Vec::new();
+        let prefix = KeyBuilder::change_log_prefix();
+        let mut iter = self.db.raw_iterator();
+        iter.seek(KeyBuilder::change(sequence + 1).key());
+        while iter.valid() && changes.len() < limit {
+            {
+                let k = iter.key().unwrap();
+                if !k.starts_with(prefix.key()) {
+                    break;
+                }
+
+                let change_sequence = BigEndian::read_u64(&k[prefix.key().len()..]);
+                changes.push(try!(decode_change(change_sequence, &iter.value().unwrap())));
+            }
+
+            iter.next();
+        }
+
+        Ok(changes)
+    }
+
+    /// Deletes changes up to and including "sequence" from the log
+    ///
+    /// Call this once every consumer has processed them.
+    pub fn truncate_change_log(&self, sequence: u64) -> Result<(), rocksdb::Error> {
+        let mut write_batch = WriteBatch::default();
+        let prefix = KeyBuilder::change_log_prefix();
+        let end = KeyBuilder::change(sequence);
+        let mut iter = self.db.raw_iterator();
+        iter.seek(prefix.key());
+        while iter.valid() {
+            {
+                let k = iter.key().unwrap();
+                if !k.starts_with(prefix.key()) || &k[..] > end.key() {
+                    break;
+                }
+
+                try!(write_batch.delete(&k));
+            }
+
+            iter.next();
+        }
+
+        self.db.write(write_batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChangeKind, PendingChange, Change, encode_change, decode_change};
+
+    #[test]
+    fn test_encode_decode() {
+        let encoded = encode_change(&PendingChange {
+            kind: ChangeKind::Upsert,
+            tenant: None,
+            key: b"doc".to_vec(),
+            payload: Some(b"payload".to_vec()),
+        });
+        assert_eq!(decode_change(1, &encoded), Ok(Change {
+            sequence: 1,
+            kind: ChangeKind::Upsert,
+            tenant: None,
+            key: "doc".to_string(),
+            payload: Some(b"payload".to_vec()),
+        }));
+
+        let encoded = encode_change(&PendingChange {
+            kind: ChangeKind::Delete,
+            tenant: None,
+            key: b"doc".to_vec(),
+            payload: None,
+        });
+        assert_eq!(decode_change(2, &encoded).unwrap().payload, None);
+        assert!(decode_change(3, &encoded[..4]).is_err());
+    }
+
+    #[test]
+    fn test_encode_decode_tenant() {
+        let encoded = encode_change(&PendingChange {
+            kind: ChangeKind::Delete,
+            tenant: Some(b"acme".to_vec()),
+            key: b"doc".to_vec(),
+            payload: None,
+        });
+        assert_eq!(decode_change(1, &encoded), Ok(Change {
+            sequence: 1,
+            kind: ChangeKind::Delete,
+            tenant: Some("acme".to_string()),
+            key: "doc".to_string(),
+            payload: None,
+        }));
+        assert!(decode_change(2, &encoded[..10]).is_err());
+    }
+}
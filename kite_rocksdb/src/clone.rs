@@ -0,0 +1,74 @@
+use std::fs;
+use std::path::Path;
+
+use rocksdb::{DB, WriteBatch, Options};
+
+use {RocksDBStore, ReaderScope, merge_keys};
+
+/// The number of keys that are copied in each write batch
+const CLONE_BATCH_SIZE: usize = 10000;
+
+impl RocksDBStore {
+    /// Copies the committed state of the index into a new, independent index at "path"
+    ///
+    /// The copy is taken from a snapshot so writes and merges made while it's running aren't
+    /// included. Segment files never change once written, so they're hard linked into the new
+    /// index (or copied if that isn't possible, such as when "path" is on another filesystem).
+    ///
+    /// Settings that aren't saved in the index (such as the search executor) aren't copied.
+    pub fn clone_to<P: AsRef<Path>>(&self, path: P) -> Result<RocksDBStore, String> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(format!("{} already exists", path.display()));
+        }
+
+        let reader = self.new_reader(ReaderScope::WholeStore);
+
+        {
+            let mut opts = Options::default();
+            opts.set_merge_operator("merge operator", merge_keys);
+            opts.create_if_missing(true);
+            let db = try!(DB::open(&opts, path));
+
+            let mut write_batch = WriteBatch::default();
+            let mut batch_size = 0;
+            let mut iter = reader.snapshot.raw_iterator();
+            iter.seek_to_first();
+            while iter.valid() {
+                try!(write_batch.put(&iter.key().unwrap(), &iter.value().unwrap()));
+                batch_size += 1;
+
+                if batch_size >= CLONE_BATCH_SIZE {
+                    try!(db.write(write_batch));
+                    write_batch = WriteBatch::default();
+                    batch_size = 0;
+                }
+
+                iter.next();
+            }
+            try!(db.write(write_batch));
+        }
+
+        // Link the files of the segments that were active when the snapshot was taken
+        let mut segments_dir_created = false;
+        for segment in reader.generation.segments() {
+            if reader.generation.segment_file(*segment).is_none() {
+                continue;
+            }
+
+            if !segments_dir_created {
+                try!(fs::create_dir_all(path.join("segments")).map_err(|e| e.to_string()));
+                segments_dir_created = true;
+            }
+
+            let file_name = format!("{}.seg", segment);
+            let source = self.path().join("segments").join(&file_name);
+            let destination = path.join("segments").join(&file_name);
+            if fs::hard_link(&source, &destination).is_err() {
+                try!(fs::copy(&source, &destination).map_err(|e| format!("{}: {}", source.display(), e)));
+            }
+        }
+
+        Ok(try!(RocksDBStore::open(path)))
+    }
+}
@@ -0,0 +1,322 @@
+use std::str;
+use std::cmp::Ordering;
+
+use kite::DocId;
+use kite::document::Completion;
+use kite::schema::{FieldId, FieldType};
+use kite::segment::Segment;
+use byteorder::{ByteOrder, LittleEndian};
+use fst::{Map, MapBuilder, Streamer, IntoStreamer};
+
+use RocksDBReader;
+
+/// A completion input of a document in a segment
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletionEntry {
+    pub input: String,
+    pub doc_id: u32,
+    pub weight: u32,
+    pub payload: Vec<u8>,
+}
+
+impl CompletionEntry {
+    pub fn new(completion: &Completion, doc_id: u32) -> CompletionEntry {
+        CompletionEntry {
+            input: completion.input.clone(),
+            doc_id: doc_id,
+            weight: completion.weight,
+            payload: completion.payload.clone(),
+        }
+    }
+}
+
+/// A completion returned by the completion suggester
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletionSuggestion {
+    pub input: String,
+    pub weight: u32,
+    pub payload: Vec<u8>,
+    pub doc_id: DocId,
+}
+
+/// FST keys are the input followed by a zero byte and the document id, so the same input can
+/// be given to many documents and prefix searches match whole inputs
+///
+/// The document id is big endian and takes "doc_id_width" bytes, which is 2 unless the index
+/// has documents that don't fit in a u16
+fn completion_key(input: &str, doc_id: u32, doc_id_width: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(input.len() + 1 + doc_id_width);
+    key.extend(input.as_bytes());
+    key.push(0);
+    for i in (0..doc_id_width).rev() {
+        key.push((doc_id >> (i * 8)) as u8);
+    }
+    key
+}
+
+fn parse_completion_key(key: &[u8], doc_id_width: usize) -> (&str, u32) {
+    let input_length = key.len() - 1 - doc_id_width;
+    let doc_id = key[input_length + 1..].iter().fold(0, |doc_id, byte| doc_id << 8 | *byte as u32);
+    (str::from_utf8(&key[..input_length]).unwrap(), doc_id)
+}
+
+/// Builds the completion index of a field in a segment
+///
+/// The index is an FST mapping each input to its weight and the position of its payload,
+/// followed by the payloads:
+///
+/// [fst length: u32][fst][payload count: u32][payload offsets: u32 * (count + 1)][payloads]
+///
+/// Indexes of segments with more than 65536 documents are followed by a byte with the width of
+/// the document ids in their keys (4). Indexes without it have 2 byte ids.
+///
+/// If a document has the same input more than once, the highest weight is kept.
+pub fn encode_completion_index(mut entries: Vec<CompletionEntry>) -> Vec<u8> {
+    entries.sort_by(|a, b| {
+        a.input.cmp(&b.input)
+            .then(a.doc_id.cmp(&b.doc_id))
+            .then(b.weight.cmp(&a.weight))
+    });
+    entries.dedup_by(|a, b| a.input == b.input && a.doc_id == b.doc_id);
+    let doc_id_width = if entries.iter().any(|entry| entry.doc_id > 0xFFFF) { 4 } else { 2 };
+
+    // Keys are sorted and unique so building the FST in memory can't fail
+    let mut fst_builder = MapBuilder::memory();
+    let mut payload_offsets = vec![0];
+    let mut payloads: Vec<u8> = Vec::new();
+    for (payload_index, entry) in entries.iter().enumerate() {
+        let value = (entry.weight as u64) << 32 | payload_index as u64;
+        fst_builder.insert(completion_key(&entry.input, entry.doc_id, doc_id_width), value).unwrap();
+
+        payloads.extend(&entry.payload);
+        payload_offsets.push(payloads.len() as u32);
+    }
+    let fst_bytes = fst_builder.into_inner().unwrap();
+
+    let mut bytes = vec![0; 8 + fst_bytes.len() + payload_offsets.len() * 4];
+    LittleEndian::write_u32(&mut bytes[0..4], fst_bytes.len() as u32);
+    bytes[4..4 + fst_bytes.len()].copy_from_slice(&fst_bytes);
+
+    let mut position = 4 + fst_bytes.len();
+    LittleEndian::write_u32(&mut bytes[position..position + 4], entries.len() as u32);
+    for offset in payload_offsets {
+        position += 4;
+        LittleEndian::write_u32(&mut bytes[position..position + 4], offset);
+    }
+    bytes.extend(payloads);
+    if doc_id_width != 2 {
+        bytes.push(doc_id_width as u8);
+    }
+
+    bytes
+}
+
+/// Reads a completion index without copying it
+pub struct CompletionIndex<'a> {
+    fst: Map<&'a [u8]>,
+    payload_offsets: &'a [u8],
+    payloads: &'a [u8],
+    doc_id_width: usize,
+}
+
+impl<'a> CompletionIndex<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<CompletionIndex<'a>, String> {
+        if bytes.len() < 4 {
+            return Err("completion index is truncated".to_string());
+        }
+
+        let fst_length = LittleEndian::read_u32(&bytes[0..4]) as usize;
+        if bytes.len() < 8 + fst_length {
+            return Err("completion index is truncated".to_string());
+        }
+
+        let fst = try!(Map::new(&bytes[4..4 + fst_length]).map_err(|e| e.to_string()));
+        let payload_count = LittleEndian::read_u32(&bytes[4 + fst_length..8 + fst_length]) as usize;
+        let payloads_start = 8 + fst_length + (payload_count + 1) * 4;
+        if bytes.len() < payloads_start {
+            return Err("completion index is truncated".to_string());
+        }
+
+        let payload_offsets = &bytes[8 + fst_length..payloads_start];
+        let payloads_length = LittleEndian::read_u32(&payload_offsets[payload_count * 4..]) as usize;
+        if bytes.len() < payloads_start + payloads_length {
+            return Err("completion index is truncated".to_string());
+        }
+        let doc_id_width = match bytes.get(payloads_start + payloads_length) {
+            None => 2,
+            Some(&4) => 4,
+            Some(_) => return Err("completion index is corrupt".to_string()),
+        };
+
+        Ok(CompletionIndex {
+            fst: fst,
+            payload_offsets: payload_offsets,
+            payloads: &bytes[payloads_start..payloads_start + payloads_length],
+            doc_id_width: doc_id_width,
+        })
+    }
+
+    fn entry(&self, key: &[u8], value: u64) -> CompletionEntry {
+        let (input, doc_id) = parse_completion_key(key, self.doc_id_width);
+        let payload_index = (value & 0xFFFFFFFF) as usize;
+        let payload_start = LittleEndian::read_u32(&self.payload_offsets[payload_index * 4..]) as usize;
+        let payload_end = LittleEndian::read_u32(&self.payload_offsets[(payload_index + 1) * 4..]) as usize;
+
+        CompletionEntry {
+            input: input.to_string(),
+            doc_id: doc_id,
+            weight: (value >> 32) as u32,
+            payload: self.payloads[payload_start..payload_end].to_vec(),
+        }
+    }
+
+    /// Returns every entry in the index, used for merging
+    pub fn entries(&self) -> Vec<CompletionEntry> {
+        let mut entries = Vec::with_capacity(self.fst.len());
+        let mut stream = self.fst.stream();
+        while let Some((key, value)) = stream.next() {
+            entries.push(self.entry(key, value));
+        }
+
+        entries
+    }
+
+    /// Returns the highest weighted entries that start with the prefix
+    ///
+    /// Payloads are only decoded for the entries that are returned.
+    pub fn top_completions<F: Fn(u32) -> bool>(&self, prefix: &str, size: usize, is_live: F) -> Vec<CompletionEntry> {
+        let mut matches = Vec::new();
+        let mut stream = self.fst.range().ge(prefix.as_bytes()).into_stream();
+        while let Some((key, value)) = stream.next() {
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+
+            if is_live(parse_completion_key(key, self.doc_id_width).1) {
+                matches.push((key.to_vec(), value));
+            }
+        }
+
+        // Highest weight first, ties are broken by the key so results are deterministic
+        matches.sort_by(|a, b| (b.1 >> 32).cmp(&(a.1 >> 32)).then(a.0.cmp(&b.0)));
+        matches.truncate(size);
+        matches.into_iter().map(|(key, value)| self.entry(&key, value)).collect()
+    }
+}
+
+fn compare_suggestions(a: &CompletionSuggestion, b: &CompletionSuggestion) -> Ordering {
+    b.weight.cmp(&a.weight)
+        .then(a.input.cmp(&b.input))
+        .then(a.doc_id.as_u64().cmp(&b.doc_id.as_u64()))
+}
+
+impl<'a> RocksDBReader<'a> {
+    /// Returns the highest weighted completions of a completion field that start with the prefix
+    pub fn complete(&self, field: FieldId, prefix: &str, size: usize) -> Result<Vec<CompletionSuggestion>, String> {
+        match self.schema().get(&field) {
+            Some(field_info) if field_info.field_type == FieldType::Completion => {}
+            Some(_) => return Err(format!("field {:?} isn't a completion field", field)),
+            None => return Err(format!("field {:?} doesn't exist", field)),
+        }
+
+        let mut suggestions = Vec::new();
+
+        for segment in self.store.segments.iter_active(self) {
+            let index_bytes = match try!(segment.load_completion_index(field)) {
+                Some(index_bytes) => index_bytes,
+                None => continue,
+            };
+            let index = try!(CompletionIndex::new(&index_bytes));
+            let deletion_list = try!(segment.load_deletion_list());
+
+            let is_live = |doc_id: u32| {
+                deletion_list.as_ref().map(|deletion_list| !deletion_list.contains(doc_id)).unwrap_or(true)
+            };
+
+            // Each segment can't contribute more than "size" completions
+            for entry in index.top_completions(prefix, size, is_live) {
+                suggestions.push(CompletionSuggestion {
+                    input: entry.input,
+                    weight: entry.weight,
+                    payload: entry.payload,
+                    doc_id: DocId(segment.id(), entry.doc_id),
+                });
+            }
+        }
+
+        suggestions.sort_by(compare_suggestions);
+        suggestions.truncate(size);
+        Ok(suggestions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompletionEntry, CompletionIndex, encode_completion_index};
+
+    fn entry(input: &str, doc_id: u32, weight: u32, payload: &[u8]) -> CompletionEntry {
+        CompletionEntry {
+            input: input.to_string(),
+            doc_id: doc_id,
+            weight: weight,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let bytes = encode_completion_index(vec![
+            entry("nirvana", 1, 5, b"band"),
+            entry("nevermind", 0, 10, b""),
+            entry("nirvana", 0, 1, b"album"),
+            entry("nirvana", 1, 3, b"duplicate"),
+        ]);
+        let index = CompletionIndex::new(&bytes).unwrap();
+
+        // The duplicate input of document 1 is dropped
+        assert_eq!(index.entries(), vec![
+            entry("nevermind", 0, 10, b""),
+            entry("nirvana", 0, 1, b"album"),
+            entry("nirvana", 1, 5, b"band"),
+        ]);
+    }
+
+    #[test]
+    fn test_top_completions() {
+        let bytes = encode_completion_index(vec![
+            entry("nirvana", 0, 5, b"a"),
+            entry("nevermind", 1, 10, b"b"),
+            entry("nine inch nails", 2, 7, b"c"),
+            entry("oasis", 3, 20, b"d"),
+        ]);
+        let index = CompletionIndex::new(&bytes).unwrap();
+
+        assert_eq!(index.top_completions("n", 2, |_| true), vec![
+            entry("nevermind", 1, 10, b"b"),
+            entry("nine inch nails", 2, 7, b"c"),
+        ]);
+        assert_eq!(index.top_completions("ni", 10, |doc_id| doc_id != 2), vec![
+            entry("nirvana", 0, 5, b"a"),
+        ]);
+        assert!(index.top_completions("x", 10, |_| true).is_empty());
+    }
+
+    #[test]
+    fn test_wide_doc_ids() {
+        let bytes = encode_completion_index(vec![
+            entry("nirvana", 70000, 5, b"a"),
+            entry("nirvana", 256, 3, b"b"),
+            entry("nevermind", 0, 10, b"c"),
+        ]);
+        let index = CompletionIndex::new(&bytes).unwrap();
+
+        assert_eq!(index.entries(), vec![
+            entry("nevermind", 0, 10, b"c"),
+            entry("nirvana", 256, 3, b"b"),
+            entry("nirvana", 70000, 5, b"a"),
+        ]);
+        assert_eq!(index.top_completions("ni", 10, |doc_id| doc_id != 256), vec![
+            entry("nirvana", 70000, 5, b"a"),
+        ]);
+    }
+}
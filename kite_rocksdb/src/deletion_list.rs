@@ -0,0 +1,242 @@
+//! Encoding of segments' deletion lists
+//!
+//! Deletion lists are stored as a sequence of little endian u16 document ids, so deletes can be
+//! appended to them with the merge operator. They're prefixed with a version byte, so the format
+//! can be changed later without breaking existing indexes. Term directories and field presence
+//! bitmaps are stored in the format of "kite::bitmap_format" instead.
+//!
+//! Deletion lists written before the version byte was added are still readable: a versioned
+//! deletion list has a single version byte before the document ids, so it always has an odd
+//! length. Unversioned deletion lists only contain document ids so are always even.
+//!
+//! Deletion lists of segments with more than 65536 documents are written with version 2 of the
+//! format, which has u32 document ids. Deletes of documents that don't fit in a u16 are merged
+//! in as single element version 2 lists, which the merge operator combines with the existing
+//! list rather than appending to it (see "merge_deletion_list").
+
+use std::io;
+
+use roaring::RoaringBitmap;
+use byteorder::{ByteOrder, LittleEndian};
+
+use kite::bitmap_format::BitmapDecodeError;
+
+/// The version of the format that deletion lists are written with
+pub const DELETION_LIST_FORMAT_VERSION: u8 = 1;
+
+/// The version of the format that deletion lists with u32 document ids are written with
+pub const WIDE_DELETION_LIST_FORMAT_VERSION: u8 = 2;
+
+/// Encodes a segment's deletion list
+///
+/// The u16 format is used unless the list has a document id that doesn't fit in one.
+pub fn encode_deletion_list(deletion_list: &RoaringBitmap) -> Vec<u8> {
+    if deletion_list.max().map(|doc_id| doc_id > 0xFFFF).unwrap_or(false) {
+        let mut bytes = vec![0; 1 + deletion_list.len() as usize * 4];
+        bytes[0] = WIDE_DELETION_LIST_FORMAT_VERSION;
+        for (i, doc_id) in deletion_list.iter().enumerate() {
+            LittleEndian::write_u32(&mut bytes[1 + i * 4..], doc_id);
+        }
+        bytes
+    } else {
+        let mut bytes = vec![0; 1 + deletion_list.len() as usize * 2];
+        bytes[0] = DELETION_LIST_FORMAT_VERSION;
+        for (i, doc_id) in deletion_list.iter().enumerate() {
+            LittleEndian::write_u16(&mut bytes[1 + i * 2..], doc_id as u16);
+        }
+        bytes
+    }
+}
+
+/// Decodes a segment's deletion list
+pub fn decode_deletion_list(bytes: &[u8]) -> Result<RoaringBitmap, BitmapDecodeError> {
+    let mut deletion_list = RoaringBitmap::new();
+
+    if bytes.len() % 2 == 1 {
+        match bytes[0] {
+            DELETION_LIST_FORMAT_VERSION => {
+                for doc_id in bytes[1..].chunks(2) {
+                    deletion_list.insert(LittleEndian::read_u16(doc_id) as u32);
+                }
+            }
+            WIDE_DELETION_LIST_FORMAT_VERSION => {
+                if (bytes.len() - 1) % 4 != 0 {
+                    return Err(BitmapDecodeError::Corrupt(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated document id")));
+                }
+
+                for doc_id in bytes[1..].chunks(4) {
+                    deletion_list.insert(LittleEndian::read_u32(doc_id));
+                }
+            }
+            version => return Err(BitmapDecodeError::UnsupportedVersion(version)),
+        }
+    } else {
+        // Written before the format was versioned
+        for doc_id in bytes.chunks(2) {
+            deletion_list.insert(LittleEndian::read_u16(doc_id) as u32);
+        }
+    }
+
+    Ok(deletion_list)
+}
+
+/// Encodes the merge operand that adds a document to a deletion list
+///
+/// This is the document's id as a little endian u16, which is appended to the list, unless
+/// it doesn't fit in one. Then it's a version 2 deletion list of just that document.
+pub fn encode_deletion(doc_id: u32) -> Vec<u8> {
+    if doc_id > 0xFFFF {
+        let mut deletion_list = RoaringBitmap::new();
+        deletion_list.insert(doc_id);
+        encode_deletion_list(&deletion_list)
+    } else {
+        let mut bytes = vec![0; 2];
+        LittleEndian::write_u16(&mut bytes, doc_id as u16);
+        bytes
+    }
+}
+
+/// Merges deletes into a deletion list, implements the merge operator of deletion lists
+///
+/// While the list and the deletes are all in the u16 format, the deletes are appended to it.
+/// Otherwise the list is decoded and the deletes are added to it before it's encoded again,
+/// which switches it to the u32 format if it needs it. Anything that can't be decoded is
+/// appended as it is, so the error is reported when the list is read.
+pub fn merge_deletion_list<'a, I: Iterator<Item = &'a [u8]>>(existing: Option<&[u8]>, deletes: I) -> Vec<u8> {
+    let mut new_val = existing.map(|existing| existing.to_vec()).unwrap_or_default();
+    let mut deletion_list: Option<RoaringBitmap> = None;
+
+    for delete in deletes {
+        if deletion_list.is_none() {
+            let is_wide = new_val.len() % 2 == 1 && new_val[0] == WIDE_DELETION_LIST_FORMAT_VERSION;
+            if !is_wide && delete.len() == 2 {
+                new_val.extend_from_slice(delete);
+                continue;
+            }
+
+            deletion_list = decode_deletion_list(&new_val).ok();
+        }
+
+        match (deletion_list.as_mut(), decode_deletion_list(delete)) {
+            (Some(deletion_list), Ok(delete)) => deletion_list.union_with(&delete),
+            (Some(_), Err(_)) => {
+                new_val = encode_deletion_list(&deletion_list.take().unwrap());
+                new_val.extend_from_slice(delete);
+            }
+            (None, _) => new_val.extend_from_slice(delete),
+        }
+    }
+
+    match deletion_list {
+        Some(deletion_list) => encode_deletion_list(&deletion_list),
+        None => new_val,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use roaring::RoaringBitmap;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use kite::bitmap_format::BitmapDecodeError;
+
+    use super::{encode_deletion_list, decode_deletion_list, encode_deletion, merge_deletion_list};
+
+    fn make_bitmap() -> RoaringBitmap {
+        let mut bitmap = RoaringBitmap::new();
+        bitmap.insert(1);
+        bitmap.insert(5);
+        bitmap.insert(65535);
+        bitmap
+    }
+
+    #[test]
+    fn test_deletion_list() {
+        let bitmap = make_bitmap();
+        let mut bytes = encode_deletion_list(&bitmap);
+        assert_eq!(bytes[0], 1);
+        assert_eq!(decode_deletion_list(&bytes).unwrap(), bitmap);
+
+        // Deletes are appended by the merge operator
+        let mut doc_id = [0; 2];
+        LittleEndian::write_u16(&mut doc_id, 3);
+        bytes.extend_from_slice(&doc_id);
+        let mut expected = bitmap.clone();
+        expected.insert(3);
+        assert_eq!(decode_deletion_list(&bytes).unwrap(), expected);
+
+        assert_eq!(decode_deletion_list(&encode_deletion_list(&RoaringBitmap::new())).unwrap(), RoaringBitmap::new());
+    }
+
+    #[test]
+    fn test_deletion_list_unversioned() {
+        let mut bytes = vec![0; 4];
+        LittleEndian::write_u16(&mut bytes, 65535);
+        LittleEndian::write_u16(&mut bytes[2..], 7);
+
+        let mut expected = RoaringBitmap::new();
+        expected.insert(7);
+        expected.insert(65535);
+        assert_eq!(decode_deletion_list(&bytes).unwrap(), expected);
+        assert_eq!(decode_deletion_list(b"").unwrap(), RoaringBitmap::new());
+    }
+
+    #[test]
+    fn test_deletion_list_unsupported_version() {
+        match decode_deletion_list(&[3, 0, 0]) {
+            Err(BitmapDecodeError::UnsupportedVersion(3)) => {}
+            result => panic!("expected an unsupported version error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_wide_deletion_list() {
+        let mut bitmap = make_bitmap();
+        bitmap.insert(65536);
+        bitmap.insert(1000000);
+        let bytes = encode_deletion_list(&bitmap);
+        assert_eq!(bytes[0], 2);
+        assert_eq!(bytes.len(), 1 + 5 * 4);
+        assert_eq!(decode_deletion_list(&bytes).unwrap(), bitmap);
+
+        match decode_deletion_list(&[2, 0, 0, 0, 0, 0, 0]) {
+            Err(BitmapDecodeError::Corrupt(_)) => {}
+            result => panic!("expected a corrupt error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_merge_deletion_list() {
+        let existing = encode_deletion_list(&make_bitmap());
+
+        // Deletes that fit in a u16 are appended
+        let deletes = vec![encode_deletion(3), encode_deletion(7)];
+        let merged = merge_deletion_list(Some(&existing), deletes.iter().map(|delete| &delete[..]));
+        assert_eq!(merged.len(), existing.len() + 4);
+        let mut expected = make_bitmap();
+        expected.insert(3);
+        expected.insert(7);
+        assert_eq!(decode_deletion_list(&merged).unwrap(), expected);
+
+        // A delete that doesn't switches the list to the u32 format, later deletes are merged into it
+        let deletes = vec![encode_deletion(70000), encode_deletion(9)];
+        let merged = merge_deletion_list(Some(&merged), deletes.iter().map(|delete| &delete[..]));
+        assert_eq!(merged[0], 2);
+        expected.insert(70000);
+        expected.insert(9);
+        assert_eq!(decode_deletion_list(&merged).unwrap(), expected);
+
+        let deletes = vec![encode_deletion(11)];
+        let merged = merge_deletion_list(Some(&merged), deletes.iter().map(|delete| &delete[..]));
+        expected.insert(11);
+        assert_eq!(decode_deletion_list(&merged).unwrap(), expected);
+
+        // Deletes can be merged before the list is written
+        let deletes = vec![encode_deletion(1), encode_deletion(65536)];
+        let merged = merge_deletion_list(None, deletes.iter().map(|delete| &delete[..]));
+        let mut expected = RoaringBitmap::new();
+        expected.insert(1);
+        expected.insert(65536);
+        assert_eq!(decode_deletion_list(&merged).unwrap(), expected);
+    }
+}
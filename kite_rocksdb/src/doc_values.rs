@@ -0,0 +1,38 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Encodes a doc values column
+///
+/// Each document takes 9 bytes, a flag byte that is 1 if the document has a value followed
+/// by the value as a little endian i64.
+pub fn encode_doc_values_column(column: &[Option<i64>]) -> Vec<u8> {
+    let mut bytes = vec![0; column.len() * 9];
+    for (doc_id, value) in column.iter().enumerate() {
+        if let Some(value) = *value {
+            bytes[doc_id * 9] = 1;
+            LittleEndian::write_i64(&mut bytes[doc_id * 9 + 1..], value);
+        }
+    }
+    bytes
+}
+
+pub fn decode_doc_values_column(bytes: &[u8]) -> Vec<Option<i64>> {
+    bytes.chunks(9).map(|chunk| {
+        if chunk[0] == 1 {
+            Some(LittleEndian::read_i64(&chunk[1..]))
+        } else {
+            None
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_doc_values_column, decode_doc_values_column};
+
+    #[test]
+    fn test_encode_decode() {
+        let column = vec![Some(1), None, Some(-1), Some(i64::min_value()), None];
+
+        assert_eq!(decode_doc_values_column(&encode_doc_values_column(&column)), column);
+    }
+}
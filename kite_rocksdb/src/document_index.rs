@@ -1,6 +1,5 @@
 use std::sync::RwLock;
 use std::collections::HashMap;
-use std::io::Cursor;
 
 use rocksdb::{self, DB, WriteBatch};
 use roaring::RoaringBitmap;
@@ -9,12 +8,70 @@ use kite::segment::SegmentId;
 use byteorder::{ByteOrder, LittleEndian};
 use fnv::FnvHashMap;
 
-use key_builder::KeyBuilder;
+use key_builder::{KeyBuilder, Key, KeyIterator};
 use segment_ops::SegmentMergeError;
+use change_log::{ChangeLog, ChangeKind, PendingChange};
+use deletion_list::{encode_deletion_list, decode_deletion_list, encode_deletion};
+
+/// Encodes a document id as a value of the primary key index
+///
+/// This is the segment id followed by the document's ord in the segment, both little endian
+/// u32s. Versions of kite that only supported 65536 documents per segment wrote the ord as a
+/// u16, and still read the ord correctly from this encoding if it fits in one.
+pub fn encode_doc_id(doc_id: DocId) -> [u8; 8] {
+    let mut doc_id_bytes = [0; 8];
+    LittleEndian::write_u32(&mut doc_id_bytes, (doc_id.0).0);
+    LittleEndian::write_u32(&mut doc_id_bytes[4..], doc_id.1);
+    doc_id_bytes
+}
+
+/// Decodes a value of the primary key index, including values with u16 ords
+pub fn decode_doc_id(bytes: &[u8]) -> DocId {
+    let segment = LittleEndian::read_u32(&bytes[0..4]);
+    let ord = if bytes.len() >= 8 {
+        LittleEndian::read_u32(&bytes[4..8])
+    } else {
+        LittleEndian::read_u16(&bytes[4..6]) as u32
+    };
+    DocId(SegmentId(segment), ord)
+}
+
+/// The key of a document in the primary key index
+///
+/// Documents written through a tenant are keyed by the tenant too, so tenants can use the same
+/// keys as each other (and as documents outside of any tenant) without clashing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DocumentKey {
+    pub tenant: Option<Vec<u8>>,
+    pub key: Vec<u8>,
+}
+
+impl DocumentKey {
+    pub fn new(key: Vec<u8>) -> DocumentKey {
+        DocumentKey {
+            tenant: None,
+            key: key,
+        }
+    }
+
+    pub fn for_tenant(tenant: Vec<u8>, key: Vec<u8>) -> DocumentKey {
+        DocumentKey {
+            tenant: Some(tenant),
+            key: key,
+        }
+    }
+
+    pub fn key_builder(&self) -> KeyBuilder {
+        match self.tenant {
+            Some(ref tenant) => KeyBuilder::tenant_primary_key_index(tenant, &self.key),
+            None => KeyBuilder::primary_key_index(&self.key),
+        }
+    }
+}
 
 /// Manages the index's "document index"
 pub struct DocumentIndexManager {
-    primary_key_index: RwLock<HashMap<Vec<u8>, DocId>>,
+    primary_key_index: RwLock<HashMap<DocumentKey, DocId>>,
 }
 
 impl DocumentIndexManager {
@@ -29,23 +86,16 @@ impl DocumentIndexManager {
     pub fn open(db: &DB) -> Result<DocumentIndexManager, rocksdb::Error> {
         // Read primary key index
         let mut primary_key_index = HashMap::new();
-        let mut iter = db.raw_iterator();
-        iter.seek(b"k");
-        while iter.valid() {
-            let k = iter.key().unwrap();
-
-            if k[0] != b'k' {
-                break;
+        for (key, v) in KeyIterator::new(db.raw_iterator(), KeyBuilder::primary_key_index_prefix()) {
+            if let Key::PrimaryKeyIndex(key) = key {
+                primary_key_index.insert(DocumentKey::new(key), decode_doc_id(&v));
             }
+        }
 
-            let v = iter.value().unwrap();
-            let segment = LittleEndian::read_u32(&v[0..4]);
-            let ord = LittleEndian::read_u16(&v[4..6]);
-            let doc_id = DocId(SegmentId(segment), ord);
-
-            primary_key_index.insert(k[1..].to_vec(), doc_id);
-
-            iter.next();
+        for (key, v) in KeyIterator::new(db.raw_iterator(), KeyBuilder::all_tenants_primary_key_index_prefix()) {
+            if let Key::TenantPrimaryKeyIndex { tenant, key } = key {
+                primary_key_index.insert(DocumentKey::for_tenant(tenant, key), decode_doc_id(&v));
+            }
         }
 
         Ok(DocumentIndexManager {
@@ -55,9 +105,7 @@ impl DocumentIndexManager {
 
     fn delete_document_by_id_unchecked(&self, write_batch: &mut WriteBatch, doc_id: DocId) -> Result<(), rocksdb::Error> {
         let kb = KeyBuilder::segment_del_list((doc_id.0).0);
-        let mut previous_doc_id_bytes = [0; 2];
-        LittleEndian::write_u16(&mut previous_doc_id_bytes, doc_id.1);
-        try!(write_batch.merge(&kb.key(), &previous_doc_id_bytes));
+        try!(write_batch.merge(&kb.key(), &encode_deletion(doc_id.1)));
 
         // Increment deleted docs
         let kb = KeyBuilder::segment_stat((doc_id.0).0, b"deleted_docs");
@@ -68,16 +116,13 @@ impl DocumentIndexManager {
         Ok(())
     }
 
-    pub fn insert_or_replace_key(&self, db: &DB, key: &Vec<u8>, doc_id: DocId) -> Result<Option<DocId>, rocksdb::Error> {
+    pub fn insert_or_replace_key(&self, db: &DB, key: &DocumentKey, doc_id: DocId, change_log: &ChangeLog, change: PendingChange) -> Result<Option<DocId>, rocksdb::Error> {
         // Update primary_key_index
         let mut write_batch = WriteBatch::default();
         let previous_doc_id = self.primary_key_index.write().unwrap().insert(key.clone(), doc_id);
 
-        let kb = KeyBuilder::primary_key_index(key);
-        let mut doc_id_bytes = [0; 6];
-        LittleEndian::write_u32(&mut doc_id_bytes, (doc_id.0).0);
-        LittleEndian::write_u16(&mut doc_id_bytes[4..], doc_id.1);
-        try!(write_batch.put(&kb.key(), &doc_id_bytes));
+        let kb = key.key_builder();
+        try!(write_batch.put(&kb.key(), &encode_doc_id(doc_id)));
 
         // If there was a document there previously, delete it
         if let Some(previous_doc_id) = previous_doc_id {
@@ -85,36 +130,73 @@ impl DocumentIndexManager {
         }
 
         // Write document data
+        let _change_log_guard = try!(change_log.log(&mut write_batch, &[change]));
         try!(db.write(write_batch));
 
         Ok(previous_doc_id)
     }
 
-    pub fn delete_document_by_key(&self, db: &DB, key: &Vec<u8>) -> Result<Option<DocId>, rocksdb::Error> {
+    /// Inserts or replaces many keys in a single write batch
+    ///
+    /// Keys are applied in order so if a key appears more than once, the last document wins.
+    pub fn insert_or_replace_keys(&self, db: &DB, keys: &[(DocumentKey, DocId)], change_log: &ChangeLog, changes: &[PendingChange]) -> Result<(), rocksdb::Error> {
+        let mut write_batch = WriteBatch::default();
+        let mut primary_key_index = self.primary_key_index.write().unwrap();
+
+        for &(ref key, doc_id) in keys {
+            let previous_doc_id = primary_key_index.insert(key.clone(), doc_id);
+
+            let kb = key.key_builder();
+            try!(write_batch.put(&kb.key(), &encode_doc_id(doc_id)));
+
+            if let Some(previous_doc_id) = previous_doc_id {
+                try!(self.delete_document_by_id_unchecked(&mut write_batch, previous_doc_id));
+            }
+        }
+
+        let _change_log_guard = try!(change_log.log(&mut write_batch, changes));
+        try!(db.write(write_batch));
+
+        Ok(())
+    }
+
+    pub fn delete_document_by_key(&self, db: &DB, key: &DocumentKey, change_log: &ChangeLog) -> Result<Option<DocId>, rocksdb::Error> {
         // Remove document from index
         let doc_id = self.primary_key_index.write().unwrap().remove(key);
 
         if let Some(doc_id) = doc_id {
             let mut write_batch = WriteBatch::default();
 
+            // Remove the key from the on-disk index too, otherwise it comes back when the
+            // index is reopened and readers can still find it
+            let kb = key.key_builder();
+            try!(write_batch.delete(&kb.key()));
+
             try!(self.delete_document_by_id_unchecked(&mut write_batch, doc_id));
 
+            let change = PendingChange {
+                kind: ChangeKind::Delete,
+                tenant: key.tenant.clone(),
+                key: key.key.clone(),
+                payload: None,
+            };
+            let _change_log_guard = try!(change_log.log(&mut write_batch, &[change]));
             try!(db.write(write_batch));
         }
 
         Ok(doc_id)
     }
 
-    pub fn contains_document_key(&self, key: &Vec<u8>) -> bool {
+    pub fn contains_document_key(&self, key: &DocumentKey) -> bool {
         self.primary_key_index.read().unwrap().contains_key(key)
     }
 
-    pub fn commit_segment_merge(&self, db: &DB, mut write_batch: WriteBatch, source_segments: &Vec<u32>, dest_segment: u32, doc_id_mapping: &FnvHashMap<DocId, u16>) -> Result<(), SegmentMergeError> {
+    pub fn commit_segment_merge(&self, db: &DB, mut write_batch: WriteBatch, source_segments: &Vec<u32>, dest_segment: u32, doc_id_mapping: &FnvHashMap<DocId, u32>) -> Result<(), SegmentMergeError> {
         // Lock the primary key index
         let mut primary_key_index = self.primary_key_index.write().unwrap();
 
         // Update primary keys to point to their new locations
-        let mut keys_to_update: HashMap<Vec<u8>, DocId> = HashMap::with_capacity(doc_id_mapping.len());
+        let mut keys_to_update: HashMap<DocumentKey, DocId> = HashMap::with_capacity(doc_id_mapping.len());
         for (key, doc_id) in primary_key_index.iter() {
             if doc_id_mapping.contains_key(&doc_id) {
                 keys_to_update.insert(key.clone(), *doc_id);
@@ -125,11 +207,8 @@ impl DocumentIndexManager {
             let new_doc_local_id = doc_id_mapping.get(&doc_id).unwrap();
             let new_doc_id = DocId(SegmentId(dest_segment), *new_doc_local_id);
 
-            let kb = KeyBuilder::primary_key_index(&key);
-            let mut doc_id_bytes = [0; 6];
-            LittleEndian::write_u32(&mut doc_id_bytes, (new_doc_id.0).0);
-            LittleEndian::write_u16(&mut doc_id_bytes[4..], new_doc_id.1);
-            try!(write_batch.put(&kb.key(), &doc_id_bytes));
+            let kb = key.key_builder();
+            try!(write_batch.put(&kb.key(), &encode_doc_id(new_doc_id)));
 
             primary_key_index.insert(key, new_doc_id);
         }
@@ -141,22 +220,19 @@ impl DocumentIndexManager {
             let kb = KeyBuilder::segment_del_list(*source_segment);
             match try!(db.get(&kb.key())) {
                 Some(bitmap) => {
-                    let bitmap = RoaringBitmap::deserialize_from(Cursor::new(&bitmap[..])).unwrap();
+                    let bitmap = try!(decode_deletion_list(&bitmap));
                     for doc_id in bitmap.iter() {
-                        let doc_id = DocId(SegmentId(*source_segment), doc_id as u16);
+                        let doc_id = DocId(SegmentId(*source_segment), doc_id);
                         let new_doc_id = doc_id_mapping.get(&doc_id).unwrap();
-                        deletion_list.insert(*new_doc_id as u32);
+                        deletion_list.insert(*new_doc_id);
                     }
                 }
                 None => {},
             }
         }
 
-        let mut dl_vec = Vec::new();
-        deletion_list.serialize_into(&mut dl_vec).unwrap();
-
         let kb = KeyBuilder::segment_del_list(dest_segment);
-        try!(db.put(&kb.key(), &dl_vec));
+        try!(db.put(&kb.key(), &encode_deletion_list(&deletion_list)));
 
         // Commit!
         try!(db.write_without_wal(write_batch));
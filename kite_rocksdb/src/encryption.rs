@@ -0,0 +1,244 @@
+//! Encryption of stored data
+//!
+//! Deployments that must keep documents encrypted at rest can give the store a BlockEncryptor.
+//! The values of stored fields and the sources of documents are then encrypted before they're
+//! written to RocksDB (or a segment file), and optionally the postings lists too. Terms, term
+//! directories, term vectors, doc values and statistics aren't encrypted.
+//!
+//! Each segment records the id of the key its data was encrypted with. Keys can be rotated by
+//! changing the encryptor's current key: new segments (including the segments written by
+//! merges) are encrypted with the new key, while existing segments are decrypted with the key
+//! they were written with. Once every segment that uses a key has been merged, the key can be
+//! retired.
+
+use std::fmt;
+use std::error::Error;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::collections::BTreeMap;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use {RocksDBStore, RocksDBReader, ReaderScope};
+use key_builder::KeyBuilder;
+use segment_ops::SegmentMergeError;
+
+/// Encrypts and decrypts blocks of stored data
+///
+/// Implementations are responsible for choosing the cipher and for managing the keys, for
+/// example an AEAD cipher with a random nonce prepended to each block. Encryption can't fail,
+/// so any keys that must be fetched from elsewhere should be loaded up front.
+pub trait BlockEncryptor: Send + Sync {
+    /// The id of the key new segments are encrypted with
+    fn current_key_id(&self) -> u32;
+
+    fn encrypt(&self, key_id: u32, plaintext: &[u8]) -> Vec<u8>;
+
+    /// Returns an error if the key is unknown or the block has been tampered with
+    fn decrypt(&self, key_id: u32, ciphertext: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+#[derive(Debug, Clone)]
+pub struct EncryptionConfig {
+    /// Encrypt the postings lists as well as the stored values
+    ///
+    /// Postings are read by every search, so this makes searches slower. Term directories
+    /// aren't encrypted, so this doesn't hide which documents contain each term.
+    pub encrypt_postings: bool,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> EncryptionConfig {
+        EncryptionConfig {
+            encrypt_postings: false,
+        }
+    }
+}
+
+const SEGMENT_ENCRYPTION_FORMAT_VERSION: u8 = 1;
+
+const SEGMENT_ENCRYPTION_POSTINGS: u8 = 1;
+
+/// How a segment's data was encrypted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentEncryption {
+    pub key_id: u32,
+    pub postings: bool,
+}
+
+impl SegmentEncryption {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 6];
+        bytes[0] = SEGMENT_ENCRYPTION_FORMAT_VERSION;
+        bytes[1] = if self.postings { SEGMENT_ENCRYPTION_POSTINGS } else { 0 };
+        LittleEndian::write_u32(&mut bytes[2..], self.key_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SegmentEncryption, String> {
+        if bytes.len() != 6 {
+            return Err(format!("segment encryption record is {} bytes, expected 6", bytes.len()));
+        }
+
+        if bytes[0] != SEGMENT_ENCRYPTION_FORMAT_VERSION {
+            return Err(format!("unsupported segment encryption format version: {}", bytes[0]));
+        }
+
+        Ok(SegmentEncryption {
+            key_id: LittleEndian::read_u32(&bytes[2..]),
+            postings: bytes[1] & SEGMENT_ENCRYPTION_POSTINGS != 0,
+        })
+    }
+}
+
+/// Returns true for the types of stored value that are encrypted (stored field values and sources)
+pub fn is_encrypted_value_type(value_type: &[u8]) -> bool {
+    value_type == b"val" || value_type == b"src"
+}
+
+/// An error from decrypting a segment's data
+#[derive(Debug)]
+pub enum DecryptionError {
+    /// The segment is encrypted but the store doesn't have an encryptor
+    NoEncryptor { segment: u32, key_id: u32 },
+
+    /// The encryptor couldn't decrypt the data
+    Failed { segment: u32, key_id: u32, error: String },
+
+    /// The segment's encryption record couldn't be decoded
+    InvalidRecord { segment: u32, error: String },
+}
+
+impl fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecryptionError::NoEncryptor { segment, key_id } => write!(f, "segment {} is encrypted with key {} but the store has no encryptor", segment, key_id),
+            DecryptionError::Failed { segment, key_id, ref error } => write!(f, "unable to decrypt segment {} with key {}: {}", segment, key_id, error),
+            DecryptionError::InvalidRecord { segment, ref error } => write!(f, "invalid encryption record for segment {}: {}", segment, error),
+        }
+    }
+}
+
+impl Error for DecryptionError {}
+
+impl RocksDBStore {
+    /// Encrypts the stored data of segments written from now on
+    ///
+    /// The encryptor isn't saved in the store, it must be set again each time the store is
+    /// opened. Reading an encrypted segment without it is an error.
+    pub fn set_encryptor(&mut self, encryptor: Arc<dyn BlockEncryptor>, config: EncryptionConfig) {
+        self.encryptor = Some(encryptor);
+        self.encryption_config = config;
+    }
+
+    /// How a new segment should be encrypted, None if the store doesn't have an encryptor
+    pub(crate) fn new_segment_encryption(&self) -> Option<SegmentEncryption> {
+        self.encryptor.as_ref().map(|encryptor| SegmentEncryption {
+            key_id: encryptor.current_key_id(),
+            postings: self.encryption_config.encrypt_postings,
+        })
+    }
+
+    /// Encrypts a stored value of a new segment if it's a type of value that is encrypted
+    ///
+    /// "encryption" must have come from "new_segment_encryption".
+    pub(crate) fn encrypt_stored_value<'a>(&self, encryption: Option<&SegmentEncryption>, value_type: &[u8], value: &'a [u8]) -> Cow<'a, [u8]> {
+        match (encryption, self.encryptor.as_ref()) {
+            (Some(encryption), Some(encryptor)) if is_encrypted_value_type(value_type) => Cow::Owned(encryptor.encrypt(encryption.key_id, value)),
+            _ => Cow::Borrowed(value),
+        }
+    }
+
+    /// Encrypts a postings skip list, block or impacts of a new segment if postings are encrypted
+    pub(crate) fn encrypt_postings(&self, encryption: Option<&SegmentEncryption>, value: Vec<u8>) -> Vec<u8> {
+        match (encryption, self.encryptor.as_ref()) {
+            (Some(encryption), Some(encryptor)) if encryption.postings => encryptor.encrypt(encryption.key_id, &value),
+            _ => value,
+        }
+    }
+
+    pub(crate) fn decrypt_block(&self, segment: u32, encryption: &SegmentEncryption, ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        match self.encryptor {
+            Some(ref encryptor) => encryptor.decrypt(encryption.key_id, ciphertext).map_err(|error| {
+                DecryptionError::Failed { segment: segment, key_id: encryption.key_id, error: error }
+            }),
+            None => Err(DecryptionError::NoEncryptor { segment: segment, key_id: encryption.key_id }),
+        }
+    }
+
+    /// Reads how a segment was encrypted from the database (rather than a reader's generation)
+    pub(crate) fn load_segment_encryption(&self, segment: u32) -> Result<Option<SegmentEncryption>, SegmentMergeError> {
+        let kb = KeyBuilder::segment_encryption(segment);
+        match try!(self.db.get(kb.key())) {
+            Some(value) => match SegmentEncryption::from_bytes(&value) {
+                Ok(encryption) => Ok(Some(encryption)),
+                Err(error) => Err(SegmentMergeError::DecryptionError(DecryptionError::InvalidRecord { segment: segment, error: error })),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// The active segments that are encrypted, grouped by the id of the key they're encrypted with
+    ///
+    /// Before a key is retired, merge its segments so they're encrypted with the current key.
+    pub fn segments_by_encryption_key(&self) -> BTreeMap<u32, Vec<u32>> {
+        let reader = self.new_reader(ReaderScope::WholeStore);
+        let mut segments = BTreeMap::new();
+        for segment in reader.generation.segments() {
+            if let Ok(Some(encryption)) = reader.generation.segment_encryption(*segment) {
+                segments.entry(encryption.key_id).or_insert_with(Vec::new).push(*segment);
+            }
+        }
+        segments
+    }
+}
+
+impl<'a> RocksDBReader<'a> {
+    pub(crate) fn segment_encryption(&self, segment: u32) -> Result<Option<SegmentEncryption>, DecryptionError> {
+        self.generation.segment_encryption(segment).map_err(|error| DecryptionError::InvalidRecord { segment: segment, error: error })
+    }
+
+    /// Decrypts a stored field value or source of the segment, returns None if the segment
+    /// isn't encrypted
+    pub(crate) fn decrypt_stored_value(&self, segment: u32, value: &[u8]) -> Result<Option<Vec<u8>>, DecryptionError> {
+        match try!(self.segment_encryption(segment)) {
+            Some(encryption) => self.store.decrypt_block(segment, &encryption, value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Decrypts a postings skip list, block or impacts of the segment, returns None if the
+    /// segment's postings aren't encrypted
+    pub(crate) fn decrypt_postings(&self, segment: u32, value: &[u8]) -> Result<Option<Vec<u8>>, DecryptionError> {
+        match try!(self.segment_encryption(segment)) {
+            Some(ref encryption) if encryption.postings => self.store.decrypt_block(segment, encryption, value).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SegmentEncryption, is_encrypted_value_type};
+
+    #[test]
+    fn test_segment_encryption() {
+        let encryption = SegmentEncryption { key_id: 300, postings: true };
+        assert_eq!(SegmentEncryption::from_bytes(&encryption.to_bytes()), Ok(encryption));
+
+        let encryption = SegmentEncryption { key_id: 1, postings: false };
+        assert_eq!(SegmentEncryption::from_bytes(&encryption.to_bytes()), Ok(encryption));
+
+        assert!(SegmentEncryption::from_bytes(&[2, 0, 1, 0, 0, 0]).is_err());
+        assert!(SegmentEncryption::from_bytes(&[1, 0]).is_err());
+    }
+
+    #[test]
+    fn test_is_encrypted_value_type() {
+        assert!(is_encrypted_value_type(b"val"));
+        assert!(is_encrypted_value_type(b"src"));
+        assert!(!is_encrypted_value_type(b"rt"));
+        assert!(!is_encrypted_value_type(b"tf1"));
+        assert!(!is_encrypted_value_type(b"len"));
+    }
+}
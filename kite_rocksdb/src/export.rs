@@ -0,0 +1,250 @@
+//! Exports the documents of a store as JSON Lines
+//!
+//! Each live document is written on its own line, in key order, so exports of two stores can
+//! be compared with a line diff:
+//!
+//! ```json
+//! {"_id":"doc1","_routing":"user1","_source":{"title":"Hello world"},"fields":{"title":"Hello world"}}
+//! ```
+//!
+//! "_routing" is only included for documents that have one. "_source" is included if the
+//! document was indexed with one (it's written as a string if it isn't valid JSON) and "fields"
+//! has the values of its stored fields, by name.
+//!
+//! The doc values of a store can also be exported as an Apache Arrow IPC stream, so they can be
+//! loaded into analytics tools (such as pandas, Polars or DataFusion) without reindexing.
+
+use std::fmt;
+use std::error::Error;
+use std::io::{self, Write};
+
+use serde_json::{self, Value, Map};
+use kite::{Document, DocId};
+use kite::segment::{Segment, SegmentId};
+use kite::schema::{FieldId, FieldType, FIELD_STORED};
+use kite::query::Query;
+use kite::error::SearchError;
+
+use {RocksDBStore, StoredFieldReadError};
+use json_document::field_value_to_json;
+use segment::RocksDBSegment;
+use arrow_ipc::{ArrowStreamWriter, ArrowField, ArrowType};
+
+#[derive(Debug, Clone)]
+pub struct ExportConfig {
+    /// The number of documents that are read at a time
+    pub batch_size: usize,
+
+    /// Write each document's source, if it has one
+    pub include_source: bool,
+
+    /// Write the values of each document's stored fields
+    pub include_fields: bool,
+}
+
+impl Default for ExportConfig {
+    fn default() -> ExportConfig {
+        ExportConfig {
+            batch_size: 1000,
+            include_source: true,
+            include_fields: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArrowExportConfig {
+    /// The maximum number of rows in each record batch
+    pub batch_size: usize,
+}
+
+impl Default for ArrowExportConfig {
+    fn default() -> ArrowExportConfig {
+        ArrowExportConfig {
+            batch_size: 65536,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    /// A stored document couldn't be read
+    ReadError(StoredFieldReadError),
+
+    /// The field doesn't exist or doesn't have doc values
+    InvalidField(FieldId),
+
+    /// The documents to export couldn't be searched for
+    SearchError(SearchError),
+
+    /// The export couldn't be written
+    IoError(io::Error),
+}
+
+impl From<StoredFieldReadError> for ExportError {
+    fn from(e: StoredFieldReadError) -> ExportError {
+        ExportError::ReadError(e)
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(e: io::Error) -> ExportError {
+        ExportError::IoError(e)
+    }
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExportError::ReadError(ref e) => write!(f, "couldn't read document: {}", e),
+            ExportError::InvalidField(field_id) => write!(f, "field {} doesn't have doc values", field_id.0),
+            ExportError::SearchError(ref e) => write!(f, "couldn't search documents: {}", e),
+            ExportError::IoError(ref e) => write!(f, "couldn't write export: {}", e),
+        }
+    }
+}
+
+impl Error for ExportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ExportError::ReadError(ref e) => Some(e),
+            ExportError::InvalidField(_) => None,
+            ExportError::SearchError(ref e) => Some(e),
+            ExportError::IoError(ref e) => Some(e),
+        }
+    }
+}
+
+impl RocksDBStore {
+    fn document_to_json(&self, doc: &Document, config: &ExportConfig) -> Value {
+        let mut object = Map::new();
+        object.insert("_id".to_string(), Value::from(doc.key.clone()));
+
+        if let Some(ref routing) = doc.routing {
+            object.insert("_routing".to_string(), Value::from(routing.clone()));
+        }
+
+        if config.include_source {
+            if let Some(ref source) = doc.source {
+                let source = serde_json::from_slice(source).unwrap_or_else(|_| Value::from(String::from_utf8_lossy(source).into_owned()));
+                object.insert("_source".to_string(), source);
+            }
+        }
+
+        if config.include_fields {
+            let mut fields = Map::new();
+            for (field_id, value) in doc.stored_fields.iter() {
+                if let Some(field_info) = self.schema.get(field_id) {
+                    fields.insert(field_info.name().to_string(), field_value_to_json(value));
+                }
+            }
+            object.insert("fields".to_string(), Value::Object(fields));
+        }
+
+        Value::Object(object)
+    }
+
+    /// Writes every live document as a line of JSON, returns the number of documents written
+    ///
+    /// Documents are read from a snapshot taken when the export starts, so changes made while
+    /// it's running aren't included.
+    pub fn export_json_lines<W: Write>(&self, writer: &mut W, config: &ExportConfig) -> Result<u64, ExportError> {
+        let reader = self.reader();
+        let keys = reader.document_keys();
+        let batch_size = if config.batch_size == 0 { 1 } else { config.batch_size };
+        let mut exported = 0;
+
+        for batch_keys in keys.chunks(batch_size) {
+            let batch_keys = batch_keys.iter().map(|key| key.as_str()).collect::<Vec<_>>();
+
+            for doc in try!(reader.multi_get(&batch_keys)) {
+                if let Some(doc) = doc {
+                    try!(serde_json::to_writer(&mut *writer, &self.document_to_json(&doc, config)).map_err(io::Error::from));
+                    try!(writer.write_all(b"\n"));
+                    exported += 1;
+                }
+            }
+        }
+
+        try!(writer.flush());
+        log_debug!("exported {} documents", exported);
+
+        Ok(exported)
+    }
+
+    /// Writes the doc values of the fields for every live document that matches the query as an
+    /// Apache Arrow IPC stream, returns the number of rows written
+    ///
+    /// Each field becomes a nullable column named after it. I64 fields are written as Int64
+    /// columns, booleans as Boolean and datetimes as Timestamps (in microseconds, UTC). Only
+    /// stored fields have doc values. Rows are written in index order.
+    pub fn export_doc_values_arrow<W: Write>(&self, writer: W, fields: &[FieldId], query: &Query, config: &ArrowExportConfig) -> Result<u64, ExportError> {
+        let mut arrow_fields = Vec::with_capacity(fields.len());
+        for field_id in fields {
+            let field_info = match self.schema.get(field_id) {
+                Some(field_info) if field_info.field_flags.contains(FIELD_STORED) => field_info,
+                _ => return Err(ExportError::InvalidField(*field_id)),
+            };
+
+            let data_type = match field_info.field_type {
+                FieldType::I64 => ArrowType::Int64,
+                FieldType::Boolean => ArrowType::Boolean,
+                FieldType::DateTime => ArrowType::Timestamp,
+                _ => return Err(ExportError::InvalidField(*field_id)),
+            };
+
+            arrow_fields.push(ArrowField {
+                name: field_info.name().to_string(),
+                data_type: data_type,
+            });
+        }
+
+        let reader = self.reader();
+        let batch_size = if config.batch_size == 0 { 1 } else { config.batch_size };
+        let mut stream = try!(ArrowStreamWriter::new(writer, arrow_fields));
+        let mut columns = fields.iter().map(|_| Vec::with_capacity(batch_size)).collect::<Vec<_>>();
+        let mut rows = 0;
+        let mut exported = 0;
+
+        // Matches are yielded a segment at a time, so the columns of each segment are only read once
+        let mut segment_columns: Option<(SegmentId, Vec<Option<Vec<Option<i64>>>>)> = None;
+
+        for doc in try!(reader.search_iter(query, false).map_err(ExportError::SearchError)) {
+            let doc_id = DocId::from_u64(try!(doc.map_err(ExportError::SearchError)).doc_id());
+
+            if segment_columns.as_ref().map(|&(segment, _)| segment != doc_id.0).unwrap_or(true) {
+                let segment = RocksDBSegment::new(&reader, (doc_id.0).0);
+                let mut loaded_columns = Vec::with_capacity(fields.len());
+                for field_id in fields {
+                    loaded_columns.push(try!(segment.load_doc_values_column(*field_id).map_err(|e| ExportError::SearchError(e.into()))));
+                }
+                segment_columns = Some((doc_id.0, loaded_columns));
+            }
+
+            let &(_, ref loaded_columns) = segment_columns.as_ref().unwrap();
+            for (column, loaded_column) in columns.iter_mut().zip(loaded_columns) {
+                column.push(loaded_column.as_ref().and_then(|values| values.get(doc_id.1 as usize).cloned()).unwrap_or(None));
+            }
+            rows += 1;
+
+            if rows == batch_size {
+                try!(stream.write_batch(rows, &columns));
+                exported += rows as u64;
+                rows = 0;
+                for column in columns.iter_mut() {
+                    column.clear();
+                }
+            }
+        }
+
+        if rows > 0 {
+            try!(stream.write_batch(rows, &columns));
+            exported += rows as u64;
+        }
+
+        try!(stream.finish());
+        log_debug!("exported doc values of {} documents", exported);
+
+        Ok(exported)
+    }
+}
@@ -0,0 +1,82 @@
+use std::str;
+use std::cmp;
+
+use kite::schema::FieldId;
+use kite::segment::Segment;
+use fnv::FnvHashSet;
+
+use RocksDBReader;
+use key_builder::KeyBuilder;
+
+/// Statistics about the values of a field across the whole index
+///
+/// These are read from statistics recorded when each segment is written, so they don't
+/// require any documents to be loaded. Like the other statistics, they include documents
+/// that have been deleted until their segment is merged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldStatistics {
+    /// The number of documents that have a value for the field
+    pub doc_count: i64,
+
+    /// The smallest and largest doc values of the field
+    ///
+    /// Only fields that have doc values (integers, booleans and dates) have these.
+    pub min_value: Option<i64>,
+    pub max_value: Option<i64>,
+
+    /// The number of distinct terms indexed in the field
+    pub distinct_terms: u64,
+}
+
+impl<'a> RocksDBReader<'a> {
+    pub fn field_statistics(&self, field_id: FieldId) -> Result<FieldStatistics, String> {
+        if self.schema().get(&field_id).is_none() {
+            return Err(format!("field {:?} doesn't exist", field_id));
+        }
+
+        let mut stats = FieldStatistics {
+            doc_count: 0,
+            min_value: None,
+            max_value: None,
+            distinct_terms: 0,
+        };
+
+        // Term ids are shared by all segments, so the distinct terms of the field are found by
+        // collecting the terms that have a document frequency in any segment
+        let mut term_ids = FnvHashSet::default();
+        let term_stat_prefix = KeyBuilder::segment_stat_term_doc_frequency_stat_name_prefix(field_id.0);
+
+        for segment in self.store.segments.iter_active(self) {
+            stats.doc_count += try!(segment.load_statistic(&KeyBuilder::segment_stat_field_doc_count_stat_name(field_id.0))).unwrap_or(0);
+
+            if let Some(min_value) = try!(segment.load_statistic(&KeyBuilder::segment_stat_field_min_value_stat_name(field_id.0))) {
+                stats.min_value = Some(stats.min_value.map_or(min_value, |value| cmp::min(value, min_value)));
+            }
+
+            if let Some(max_value) = try!(segment.load_statistic(&KeyBuilder::segment_stat_field_max_value_stat_name(field_id.0))) {
+                stats.max_value = Some(stats.max_value.map_or(max_value, |value| cmp::max(value, max_value)));
+            }
+
+            let kb = KeyBuilder::segment_stat(segment.id().0, &term_stat_prefix);
+            let mut iter = self.snapshot.raw_iterator();
+            iter.seek(kb.key());
+            while iter.valid() {
+                {
+                    let k = iter.key().unwrap();
+                    if !k.starts_with(kb.key()) {
+                        break;
+                    }
+
+                    if let Some(term_id) = str::from_utf8(&k[kb.key().len()..]).ok().and_then(|term_id| term_id.parse::<u32>().ok()) {
+                        term_ids.insert(term_id);
+                    }
+                }
+
+                iter.next();
+            }
+        }
+
+        stats.distinct_terms = term_ids.len() as u64;
+        Ok(stats)
+    }
+}
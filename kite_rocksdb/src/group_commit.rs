@@ -0,0 +1,254 @@
+use std::mem;
+use std::sync::{Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+use kite::{Document, DocId};
+use kite::segment::SegmentId;
+use kite::metrics;
+use fnv::FnvHashMap;
+
+use {RocksDBStore, DocumentInsertError};
+use document_index::DocumentKey;
+
+#[derive(Debug, Clone)]
+pub struct GroupCommitConfig {
+    /// How long to wait for other documents to arrive before committing
+    pub commit_interval: Duration,
+
+    /// The most documents that can be committed together, these are committed straight away
+    /// without waiting for the rest of the interval
+    pub max_batch_size: usize,
+}
+
+impl Default for GroupCommitConfig {
+    fn default() -> GroupCommitConfig {
+        GroupCommitConfig {
+            commit_interval: Duration::from_millis(5),
+            max_batch_size: 1000,
+        }
+    }
+}
+
+struct GroupCommitState {
+    next_ticket: u64,
+
+    /// Documents waiting to be committed
+    queue: Vec<(u64, Document)>,
+
+    /// True while a thread is committing the queue
+    has_leader: bool,
+
+    /// The results of committed documents, removed by the thread that inserted them
+    results: FnvHashMap<u64, Result<(), DocumentInsertError>>,
+}
+
+/// Batches documents that are inserted at the same time from different threads
+///
+/// Without this, every document is written as its own segment, which is a RocksDB write and
+/// a new segment to merge per document. The first thread to insert a document becomes the
+/// "leader", it waits for the commit interval to collect documents from other threads then
+/// writes all of them together as one segment. The other threads wait for it to finish.
+pub struct GroupCommit {
+    config: GroupCommitConfig,
+    state: Mutex<GroupCommitState>,
+
+    /// Notified when documents are queued and when results are ready
+    condvar: Condvar,
+}
+
+impl GroupCommit {
+    pub fn new(config: GroupCommitConfig) -> GroupCommit {
+        let max_batch_size = config.max_batch_size.max(1);
+
+        GroupCommit {
+            config: GroupCommitConfig {
+                max_batch_size: max_batch_size,
+                ..config
+            },
+            state: Mutex::new(GroupCommitState {
+                next_ticket: 0,
+                queue: Vec::new(),
+                has_leader: false,
+                results: FnvHashMap::default(),
+            }),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Waits for the commit interval or until a full batch has been queued
+    fn wait_for_batch(&self) {
+        let deadline = Instant::now() + self.config.commit_interval;
+        let mut state = self.state.lock().unwrap();
+
+        while state.queue.len() < self.config.max_batch_size {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            state = self.condvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+impl RocksDBStore {
+    /// Batches documents inserted concurrently into shared segments
+    ///
+    /// This greatly increases indexing throughput when many threads are inserting one
+    /// document at a time, at the cost of each insert taking up to "commit_interval" longer.
+    pub fn set_group_commit(&mut self, config: GroupCommitConfig) {
+        self.group_commit = Some(GroupCommit::new(config));
+    }
+
+    /// Inserts the document into the group commit queue and waits for it to be committed
+    pub(crate) fn insert_or_update_document_grouped(&self, group_commit: &GroupCommit, doc: &Document) -> Result<(), DocumentInsertError> {
+        let (ticket, is_leader) = {
+            let mut state = group_commit.state.lock().unwrap();
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.queue.push((ticket, doc.clone()));
+
+            let is_leader = !state.has_leader;
+            state.has_leader = true;
+            (ticket, is_leader)
+        };
+
+        if is_leader {
+            // Commit batches until the queue is empty, so documents queued while the last
+            // batch was being written aren't left without a leader
+            loop {
+                group_commit.wait_for_batch();
+
+                let batch = {
+                    let mut state = group_commit.state.lock().unwrap();
+                    // Everything in a batch is written into one segment so the batch has to fit in one
+                    let batch_size = state.queue.len().min(group_commit.config.max_batch_size).min(self.max_segment_docs as usize);
+                    let rest = state.queue.split_off(batch_size);
+                    mem::replace(&mut state.queue, rest)
+                };
+
+                let results = self.commit_document_batch(batch);
+
+                let mut state = group_commit.state.lock().unwrap();
+                state.results.extend(results);
+
+                if state.queue.is_empty() {
+                    state.has_leader = false;
+                    group_commit.condvar.notify_all();
+                    return state.results.remove(&ticket).expect("group commit: document wasn't committed");
+                }
+
+                group_commit.condvar.notify_all();
+            }
+        } else {
+            group_commit.condvar.notify_all();
+
+            let mut state = group_commit.state.lock().unwrap();
+            loop {
+                if let Some(result) = state.results.remove(&ticket) {
+                    return result;
+                }
+
+                state = group_commit.condvar.wait(state).unwrap();
+            }
+        }
+    }
+
+    /// Writes a batch of documents into a single segment
+    pub(crate) fn commit_document_batch(&self, batch: Vec<(u64, Document)>) -> Vec<(u64, Result<(), DocumentInsertError>)> {
+        let mut results = Vec::with_capacity(batch.len());
+        let mut builder = self.new_segment_builder();
+        let mut added = Vec::with_capacity(batch.len());
+        let mut changes = Vec::with_capacity(batch.len());
+
+        // Documents that can't be added only fail on their own
+        for (ticket, doc) in batch {
+            match builder.add_document(&doc) {
+                Ok(ord) => {
+                changes.push(self.upsert_change(&doc));
+                added.push((ticket, doc.key.into_bytes(), ord));
+            }
+                Err(e) => results.push((ticket, Err(e.into()))),
+            }
+        }
+
+        if added.is_empty() {
+            return results;
+        }
+
+        if let Some(index_sort) = self.index_sort {
+            let mapping = builder.sort_documents(index_sort);
+            for &mut (_, _, ref mut ord) in added.iter_mut() {
+                *ord = mapping[*ord as usize];
+            }
+        }
+
+        let commit_result = self.write_segment(&builder).and_then(|segment| {
+            let keys = added.iter()
+                .map(|&(_, ref key, ord)| (DocumentKey::new(key.clone()), DocId(SegmentId(segment), ord)))
+                .collect::<Vec<_>>();
+
+            self.document_index.insert_or_replace_keys(&self.db, &keys, &self.change_log, &changes)
+        });
+
+        match commit_result {
+            Ok(()) => {
+                self.metrics.increment_counter(metrics::DOCS_INDEXED, added.len() as u64);
+
+                for (ticket, key, _) in added {
+                    self.notify_listeners(|listener| listener.document_indexed(&String::from_utf8_lossy(&key)));
+                    results.push((ticket, Ok(())));
+                }
+            }
+            Err(e) => {
+                for (ticket, _, _) in added {
+                    results.push((ticket, Err(DocumentInsertError::RocksDBError(e.clone()))));
+                }
+            }
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{GroupCommit, GroupCommitConfig};
+
+    #[test]
+    fn test_full_batch_doesnt_wait() {
+        let group_commit = GroupCommit::new(GroupCommitConfig {
+            commit_interval: Duration::from_secs(60),
+            max_batch_size: 0,
+        });
+
+        // The batch size is at least one so the queue is already full
+        group_commit.state.lock().unwrap().queue.push((0, ::kite::Document {
+            key: "doc".to_string(),
+            indexed_fields: Default::default(),
+            stored_fields: Default::default(),
+            rank_features: Default::default(),
+            completions: Default::default(),
+            source: None,
+            routing: None,
+        }));
+
+        let start = Instant::now();
+        group_commit.wait_for_batch();
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_wait_for_interval() {
+        let group_commit = GroupCommit::new(GroupCommitConfig {
+            commit_interval: Duration::from_millis(20),
+            max_batch_size: 10,
+        });
+
+        let start = Instant::now();
+        group_commit.wait_for_batch();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,195 @@
+use std::cmp::Ordering;
+
+use rocksdb;
+use kite::schema::FieldId;
+use kite::document::DocId;
+use kite::segment::SegmentId;
+use kite::collectors::top_field::SortOrder;
+use byteorder::{ByteOrder, LittleEndian};
+
+use RocksDBStore;
+use key_builder::KeyBuilder;
+use doc_values::decode_doc_values_column;
+
+/// The order that documents are written into segments
+///
+/// Documents are ordered by the doc values of a field, documents without a value go last.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexSort {
+    pub field: FieldId,
+    pub order: SortOrder,
+}
+
+impl IndexSort {
+    pub fn compare(&self, a: Option<i64>, b: Option<i64>) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                match self.order {
+                    SortOrder::Ascending => a.cmp(&b),
+                    SortOrder::Descending => b.cmp(&a),
+                }
+            }
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 5];
+        LittleEndian::write_u32(&mut bytes[0..4], self.field.0);
+        bytes[4] = match self.order {
+            SortOrder::Ascending => b'a',
+            SortOrder::Descending => b'd',
+        };
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<IndexSort> {
+        if bytes.len() != 5 {
+            return None;
+        }
+
+        let order = match bytes[4] {
+            b'a' => SortOrder::Ascending,
+            b'd' => SortOrder::Descending,
+            _ => return None,
+        };
+
+        Some(IndexSort {
+            field: FieldId(LittleEndian::read_u32(&bytes[0..4])),
+            order: order,
+        })
+    }
+}
+
+/// Returns the position each document will be moved to when sorting them by "values"
+///
+/// The sort is stable so documents with the same value keep their current order.
+pub fn sort_mapping(index_sort: IndexSort, values: &[Option<i64>]) -> Vec<u32> {
+    let mut order = (0..values.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| index_sort.compare(values[*a], values[*b]));
+
+    let mut mapping = vec![0; values.len()];
+    for (new_doc_id, old_doc_id) in order.into_iter().enumerate() {
+        mapping[old_doc_id] = new_doc_id as u32;
+    }
+
+    mapping
+}
+
+impl RocksDBStore {
+    /// Writes documents into segments in the order of a field's values
+    ///
+    /// Segments record the order they were written in. Searches that sort by the same
+    /// field and order can stop reading a segment once its documents are no longer
+    /// competitive. Only segments written (or merged) after this is set are sorted.
+    pub fn set_index_sort(&mut self, field: FieldId, order: SortOrder) -> Result<(), String> {
+        if self.schema.get(&field).is_none() {
+            return Err(format!("field {:?} doesn't exist", field));
+        }
+
+        let index_sort = IndexSort {
+            field: field,
+            order: order,
+        };
+
+        try!(self.db.put(b".index_sort", &index_sort.to_bytes()));
+        self.index_sort = Some(index_sort);
+
+        Ok(())
+    }
+
+    pub fn index_sort(&self) -> Option<IndexSort> {
+        self.index_sort
+    }
+
+    /// Returns every document in the source segments of a merge, in the index's sort order
+    pub(crate) fn sorted_merge_docs(&self, source_segments: &Vec<u32>, index_sort: IndexSort) -> Result<Vec<DocId>, rocksdb::Error> {
+        let mut docs = Vec::new();
+
+        for source_segment in source_segments.iter() {
+            let kb = KeyBuilder::segment_stat(*source_segment, b"total_docs");
+            let total_docs = match try!(self.db.get(&kb.key())) {
+                Some(total_docs_bytes) => LittleEndian::read_i64(&total_docs_bytes),
+                None => continue,
+            };
+
+            let kb = KeyBuilder::segment_doc_values_column(*source_segment, index_sort.field.0);
+            let column = try!(self.db.get(&kb.key())).map(|column| decode_doc_values_column(&column)).unwrap_or_else(Vec::new);
+
+            for source_doc_id in 0..total_docs {
+                let value = column.get(source_doc_id as usize).cloned().unwrap_or(None);
+                docs.push((DocId(SegmentId(*source_segment), source_doc_id as u32), value));
+            }
+        }
+
+        docs.sort_by(|a, b| index_sort.compare(a.1, b.1));
+        Ok(docs.into_iter().map(|(doc_id, _)| doc_id).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kite::Document;
+    use kite::document::FieldValue;
+    use kite::schema::FieldId;
+    use kite::collectors::top_field::SortOrder;
+    use fnv::FnvHashMap;
+
+    use segment_builder::SegmentBuilder;
+    use super::{IndexSort, sort_mapping};
+
+    #[test]
+    fn test_sort_mapping() {
+        let index_sort = IndexSort {
+            field: FieldId(1),
+            order: SortOrder::Descending,
+        };
+
+        // Documents without a value go last, ties keep their order
+        assert_eq!(sort_mapping(index_sort, &[Some(1), None, Some(5), Some(1)]), vec![1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn test_encode() {
+        let index_sort = IndexSort {
+            field: FieldId(7),
+            order: SortOrder::Ascending,
+        };
+
+        assert_eq!(IndexSort::from_bytes(&index_sort.to_bytes()), Some(index_sort));
+        assert_eq!(IndexSort::from_bytes(b"nope"), None);
+    }
+
+    #[test]
+    fn test_sort_segment_builder() {
+        let index_sort = IndexSort {
+            field: FieldId(1),
+            order: SortOrder::Ascending,
+        };
+
+        let mut builder = SegmentBuilder::new();
+        for (key, pk) in vec![("b", Some(2)), ("none", None), ("a", Some(1))] {
+            let mut stored_fields = FnvHashMap::default();
+            if let Some(pk) = pk {
+                stored_fields.insert(FieldId(1), FieldValue::Integer(pk));
+            }
+
+            builder.add_document(&Document {
+                key: key.to_string(),
+                indexed_fields: FnvHashMap::default(),
+                stored_fields: stored_fields,
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        }
+
+        assert_eq!(builder.sort_documents(index_sort), vec![1, 2, 0]);
+        assert_eq!(builder.index_sort, Some(index_sort));
+        assert_eq!(builder.doc_values.get(&FieldId(1)), Some(&vec![Some(1), Some(2), None]));
+        assert_eq!(builder.stored_field_values.get(&(FieldId(1), 0, b"val".to_vec())), Some(&FieldValue::Integer(1).to_bytes()));
+    }
+}
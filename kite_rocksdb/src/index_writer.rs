@@ -0,0 +1,201 @@
+//! Separate handles for writing to and searching a store
+//!
+//! A store has at most one `IndexWriter` at a time. The writer owns the segment that's being
+//! built and is the only thing that should add, delete or merge documents while it's open.
+//! `IndexReader`s are cheap to clone and can be sent to any number of threads, each search
+//! sees the documents that were committed before it started.
+
+use std::mem;
+use std::fmt;
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use kite::{Document, DocId};
+use kite::segment::SegmentId;
+use kite::metrics;
+use fnv::FnvHashMap;
+use rocksdb;
+
+use {RocksDBStore, RocksDBReader, DocumentInsertError};
+use segment_builder::SegmentBuilder;
+use segment_ops::SegmentMergeError;
+use change_log::PendingChange;
+use document_index::DocumentKey;
+
+#[derive(Debug)]
+pub enum IndexWriterError {
+    /// Another writer is already open on the store
+    WriterAlreadyOpen,
+}
+
+impl fmt::Display for IndexWriterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndexWriterError::WriterAlreadyOpen => write!(f, "another writer is already open on the store"),
+        }
+    }
+}
+
+impl Error for IndexWriterError {}
+
+enum WriterOp {
+    Upsert(Vec<u8>, u32, PendingChange),
+    Delete(String),
+}
+
+/// The single writer of a store
+///
+/// Documents that are added or deleted are buffered and nothing is visible to readers until
+/// "commit" is called. Uncommitted changes are discarded when the writer is dropped.
+pub struct IndexWriter {
+    store: Arc<RocksDBStore>,
+    builder: SegmentBuilder,
+    ops: Vec<WriterOp>,
+}
+
+impl IndexWriter {
+    /// Opens the writer of a store, returns an error if it already has one
+    pub fn open(store: Arc<RocksDBStore>) -> Result<IndexWriter, IndexWriterError> {
+        if store.writer_open.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(IndexWriterError::WriterAlreadyOpen);
+        }
+
+        let builder = store.new_segment_builder();
+
+        Ok(IndexWriter {
+            store: store,
+            builder: builder,
+            ops: Vec::new(),
+        })
+    }
+
+    /// Returns a reader of the store
+    pub fn reader(&self) -> IndexReader {
+        IndexReader::new(self.store.clone())
+    }
+
+    /// Adds a document, replacing any document with the same key when committed
+    ///
+    /// If the segment that's being built is full, everything added so far is committed first.
+    pub fn add_document(&mut self, doc: &Document) -> Result<(), DocumentInsertError> {
+        if self.builder.is_full() {
+            try!(self.commit());
+        }
+
+        let ord = try!(self.builder.add_document(doc));
+        self.ops.push(WriterOp::Upsert(doc.key.as_bytes().to_vec(), ord, self.store.upsert_change(doc)));
+        Ok(())
+    }
+
+    /// Deletes the document with the key when committed
+    ///
+    /// This also deletes a document with the key that was added earlier in the same commit.
+    pub fn delete_document(&mut self, doc_key: &str) {
+        self.ops.push(WriterOp::Delete(doc_key.to_string()));
+    }
+
+    /// Returns the number of documents that have been added or deleted since the last commit
+    pub fn num_pending(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Makes every change since the last commit visible to new readers
+    ///
+    /// Added documents are written into a single segment, the id of which is returned.
+    pub fn commit(&mut self) -> Result<Option<u32>, rocksdb::Error> {
+        let mut builder = mem::replace(&mut self.builder, self.store.new_segment_builder());
+        let ops = mem::replace(&mut self.ops, Vec::new());
+
+        // Only the last change to each key counts, a delete after an add removes the document
+        // again once it's been written
+        let mut last_ops = FnvHashMap::default();
+        for (i, op) in ops.iter().enumerate() {
+            let key = match *op {
+                WriterOp::Upsert(ref key, _, _) => key.clone(),
+                WriterOp::Delete(ref key) => key.as_bytes().to_vec(),
+            };
+            last_ops.insert(key, i);
+        }
+
+        let mut upserts = Vec::new();
+        let mut deletes = Vec::new();
+        for (i, op) in ops.into_iter().enumerate() {
+            match op {
+                WriterOp::Upsert(key, ord, change) => upserts.push((key, ord, change)),
+                WriterOp::Delete(key) => {
+                    if last_ops.get(key.as_bytes()) == Some(&i) {
+                        deletes.push(key);
+                    }
+                }
+            }
+        }
+
+        let upserts_len = upserts.len();
+        let mut segment = None;
+        if !upserts.is_empty() {
+            let mapping = self.store.index_sort.map(|index_sort| builder.sort_documents(index_sort));
+            let segment_id = try!(self.store.write_segment(&builder));
+
+            let mut keys = Vec::with_capacity(upserts.len());
+            let mut changes = Vec::with_capacity(upserts.len());
+            for (key, ord, change) in upserts {
+                let ord = mapping.as_ref().map(|mapping| mapping[ord as usize]).unwrap_or(ord);
+                keys.push((DocumentKey::new(key), DocId(SegmentId(segment_id), ord)));
+                changes.push(change);
+            }
+
+            try!(self.store.document_index.insert_or_replace_keys(&self.store.db, &keys, &self.store.change_log, &changes));
+            self.store.metrics.increment_counter(metrics::DOCS_INDEXED, keys.len() as u64);
+
+            for &(ref key, _) in keys.iter() {
+                self.store.notify_listeners(|listener| listener.document_indexed(&String::from_utf8_lossy(&key.key)));
+            }
+
+            segment = Some(segment_id);
+        }
+
+        let deletes_len = deletes.len();
+        for key in deletes {
+            try!(self.store.remove_document_by_key(&key));
+        }
+
+        log_debug!("index writer committed {} documents and {} deletes", upserts_len, deletes_len);
+
+        Ok(segment)
+    }
+
+    /// Merges segments into a new one, see "RocksDBStore::merge_segments"
+    pub fn merge_segments(&self, source_segments: &Vec<u32>) -> Result<u32, SegmentMergeError> {
+        self.store.merge_segments(source_segments)
+    }
+}
+
+impl Drop for IndexWriter {
+    fn drop(&mut self) {
+        self.store.writer_open.store(false, Ordering::SeqCst);
+    }
+}
+
+/// A handle for searching a store
+///
+/// Readers are cheap to clone, give each thread its own.
+#[derive(Clone)]
+pub struct IndexReader {
+    store: Arc<RocksDBStore>,
+}
+
+impl IndexReader {
+    pub fn new(store: Arc<RocksDBStore>) -> IndexReader {
+        IndexReader {
+            store: store,
+        }
+    }
+
+    /// Returns a point-in-time view of the store for searching
+    ///
+    /// The view sees everything that was committed before it was created and nothing after.
+    pub fn searcher<'a>(&'a self) -> RocksDBReader<'a> {
+        self.store.reader()
+    }
+}
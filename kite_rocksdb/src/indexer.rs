@@ -0,0 +1,295 @@
+use std::fmt;
+use std::mem;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver, TrySendError, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+use kite::Document;
+
+use {RocksDBStore, DocumentInsertError};
+
+type IndexJob = (Document, JobCompletion);
+
+#[derive(Debug, Clone)]
+pub struct IndexerConfig {
+    /// The number of documents that can wait to be indexed before "submit" blocks
+    pub queue_size: usize,
+
+    /// The most documents that are written into a single segment
+    pub max_batch_size: usize,
+}
+
+impl Default for IndexerConfig {
+    fn default() -> IndexerConfig {
+        IndexerConfig {
+            queue_size: 1024,
+            max_batch_size: 1000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IndexerError {
+    /// The queue is full, the document was not submitted
+    QueueFull,
+
+    /// The indexer stopped before the document was committed
+    Stopped,
+
+    /// The document couldn't be inserted
+    InsertError(DocumentInsertError),
+}
+
+impl fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndexerError::QueueFull => write!(f, "indexer queue full"),
+            IndexerError::Stopped => write!(f, "indexer stopped"),
+            IndexerError::InsertError(ref e) => write!(f, "couldn't insert document: {}", e),
+        }
+    }
+}
+
+impl Error for IndexerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            IndexerError::InsertError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<DocumentInsertError> for IndexerError {
+    fn from(e: DocumentInsertError) -> IndexerError {
+        IndexerError::InsertError(e)
+    }
+}
+
+/// Indexes documents in the background
+///
+/// Documents are submitted to a bounded queue and a background thread writes them into
+/// segments in batches. When the queue is full, "submit" blocks until there's space (or
+/// "try_submit" returns an error) so callers can't get too far ahead of the indexer.
+///
+/// Dropping the indexer waits for all queued documents to be committed.
+pub struct Indexer {
+    sender: Mutex<Option<SyncSender<IndexJob>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Indexer {
+    pub fn new(store: Arc<RocksDBStore>, config: IndexerConfig) -> Indexer {
+        let (sender, receiver) = sync_channel::<IndexJob>(config.queue_size);
+
+        // Everything in a batch is written into one segment so the batch has to fit in one
+        let max_batch_size = config.max_batch_size.max(1).min(store.max_segment_docs() as usize);
+
+        let thread = thread::Builder::new()
+            .name("kite-indexer".to_string())
+            .spawn(move || run_indexer(&store, receiver, max_batch_size))
+            .expect("failed to spawn indexer thread");
+
+        Indexer {
+            sender: Mutex::new(Some(sender)),
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues a document to be indexed, blocks while the queue is full
+    pub fn submit(&self, doc: Document) -> Result<IndexHandle, IndexerError> {
+        let sender = match *self.sender.lock().unwrap() {
+            Some(ref sender) => sender.clone(),
+            None => return Err(IndexerError::Stopped),
+        };
+
+        let (completion, handle) = IndexHandle::new();
+        try!(sender.send((doc, completion)).map_err(|_| IndexerError::Stopped));
+
+        Ok(handle)
+    }
+
+    /// Queues a document to be indexed, returns an error if the queue is full
+    pub fn try_submit(&self, doc: Document) -> Result<IndexHandle, IndexerError> {
+        let (completion, handle) = IndexHandle::new();
+
+        match *self.sender.lock().unwrap() {
+            Some(ref sender) => {
+                match sender.try_send((doc, completion)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => return Err(IndexerError::QueueFull),
+                    Err(TrySendError::Disconnected(_)) => return Err(IndexerError::Stopped),
+                }
+            }
+            None => return Err(IndexerError::Stopped),
+        }
+
+        Ok(handle)
+    }
+}
+
+impl Drop for Indexer {
+    fn drop(&mut self) {
+        // Closing the queue stops the thread once it's empty
+        self.sender.lock().unwrap().take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run_indexer(store: &RocksDBStore, receiver: Receiver<IndexJob>, max_batch_size: usize) {
+    // Wait for a document then take everything else that's queued, up to the batch size
+    while let Ok(job) = receiver.recv() {
+        let mut batch = vec![job];
+
+        while batch.len() < max_batch_size {
+            match receiver.try_recv() {
+                Ok(job) => batch.push(job),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        let mut completions = Vec::with_capacity(batch.len());
+        let docs = batch.into_iter().enumerate().map(|(i, (doc, completion))| {
+            completions.push(Some(completion));
+            (i as u64, doc)
+        }).collect();
+
+        for (i, result) in store.commit_document_batch(docs) {
+            if let Some(completion) = completions[i as usize].take() {
+                completion.complete(result.map_err(IndexerError::from));
+            }
+        }
+    }
+}
+
+enum JobState {
+    /// The document hasn't been committed yet, holds the waker of the task polling the handle
+    Queued(Option<Waker>),
+
+    Done(Result<(), IndexerError>),
+
+    /// The result has been taken from the handle
+    Taken,
+}
+
+/// The state of a submitted document, shared by its handle and the indexer thread
+struct JobShared {
+    state: Mutex<JobState>,
+    done: Condvar,
+}
+
+impl JobShared {
+    /// Takes the result if the document has been committed
+    fn take_result(state: &mut JobState) -> Option<Result<(), IndexerError>> {
+        match *state {
+            JobState::Queued(_) => None,
+            JobState::Done(_) => {
+                match mem::replace(state, JobState::Taken) {
+                    JobState::Done(result) => Some(result),
+                    _ => unreachable!(),
+                }
+            }
+            JobState::Taken => Some(Err(IndexerError::Stopped)),
+        }
+    }
+}
+
+/// Used by the indexer thread to pass the result of a document to its handle
+///
+/// If this is dropped without being completed (eg, the indexer thread panicked), the handle
+/// gets an "IndexerError::Stopped".
+struct JobCompletion {
+    shared: Arc<JobShared>,
+}
+
+impl JobCompletion {
+    fn complete(self, result: Result<(), IndexerError>) {
+        self.set_result(result);
+    }
+
+    fn set_result(&self, result: Result<(), IndexerError>) {
+        let mut state = self.shared.state.lock().unwrap();
+        let waker = match *state {
+            JobState::Queued(ref mut waker) => waker.take(),
+            _ => return,
+        };
+
+        *state = JobState::Done(result);
+        self.shared.done.notify_all();
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for JobCompletion {
+    fn drop(&mut self) {
+        // Does nothing if the result has already been set
+        self.set_result(Err(IndexerError::Stopped));
+    }
+}
+
+/// A document that has been submitted to the indexer
+///
+/// The result can be waited for by blocking with "wait" or by awaiting the handle, which is a
+/// future that's woken by the indexer thread once the document has been committed.
+pub struct IndexHandle {
+    shared: Arc<JobShared>,
+}
+
+impl IndexHandle {
+    fn new() -> (JobCompletion, IndexHandle) {
+        let shared = Arc::new(JobShared {
+            state: Mutex::new(JobState::Queued(None)),
+            done: Condvar::new(),
+        });
+
+        (JobCompletion { shared: shared.clone() }, IndexHandle { shared: shared })
+    }
+
+    /// Blocks until the document has been committed
+    pub fn wait(self) -> Result<(), IndexerError> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            if let Some(result) = JobShared::take_result(&mut state) {
+                return result;
+            }
+
+            state = self.shared.done.wait(state).unwrap();
+        }
+    }
+
+    /// Returns the result if the document has been committed, or None if it's still queued
+    pub fn try_wait(&self) -> Option<Result<(), IndexerError>> {
+        JobShared::take_result(&mut self.shared.state.lock().unwrap())
+    }
+}
+
+impl Future for IndexHandle {
+    type Output = Result<(), IndexerError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IndexerError>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let JobState::Queued(ref mut waker) = *state {
+            // Only the waker of the most recent poll needs to be woken
+            let is_current = waker.as_ref().map(|waker| waker.will_wake(cx.waker())).unwrap_or(false);
+            if !is_current {
+                *waker = Some(cx.waker().clone());
+            }
+
+            return Poll::Pending;
+        }
+
+        Poll::Ready(JobShared::take_result(&mut state).unwrap())
+    }
+}
@@ -0,0 +1,176 @@
+//! The ordered indexes of the terms of integer and date fields
+//!
+//! The term dictionary is a hash map, so it can't find the terms in a range without checking
+//! every term. Each integer and date field also keeps its terms in order under "i{field}/",
+//! mapping them to their term ids, so range queries seek straight to the first term in the
+//! range and stop after the last one.
+//!
+//! Like the term dictionary, entries are never removed. A term that no segment uses anymore
+//! is still found by a range, it just doesn't have any term directories.
+
+use std::str;
+
+use rocksdb::WriteBatch;
+use fnv::{FnvHashMap, FnvHashSet};
+use kite::{Term, TermId, MultiTermSelector};
+use kite::schema::{Schema, FieldType, FieldId};
+
+use {RocksDBStore, RocksDBReader, StoreOpenError};
+use key_builder::{KeyBuilder, Key, KeyIterator};
+
+/// Set once the terms of integer and date fields are in the sortable encoding, and are in the
+/// ordered indexes of their fields
+pub const SORTABLE_INTEGER_TERMS: &'static [u8] = b".sortable_integer_terms";
+
+/// Returns true if the terms of the field are integers (dates are integers too)
+pub fn is_integer_field(schema: &Schema, field_id: FieldId) -> bool {
+    match schema.get(&field_id).map(|field_info| &field_info.field_type) {
+        Some(&FieldType::I64) | Some(&FieldType::DateTime) => true,
+        _ => false,
+    }
+}
+
+fn parse_term_id(value: &[u8]) -> Option<TermId> {
+    str::from_utf8(value).ok().and_then(|term_id| term_id.parse::<u32>().ok()).map(TermId)
+}
+
+impl<'a> RocksDBReader<'a> {
+    /// Returns the terms of an integer or date field that an IntegerRange selector matches,
+    /// in value order
+    ///
+    /// Only the field's terms in the range are read. Like "TermDictionaryManager::select_until",
+    /// the scan stops (returning the terms found so far) once "is_cancelled" returns true.
+    pub(crate) fn select_integer_terms_until<C: Fn() -> bool>(&self, field_id: FieldId, term_selector: &MultiTermSelector, is_cancelled: C) -> Vec<TermId> {
+        let (first, last) = match term_selector.integer_term_range() {
+            Some(range) => range,
+            None => return Vec::new(),
+        };
+
+        let first_key = KeyBuilder::integer_term(field_id.0, first.as_bytes());
+        let last_key = KeyBuilder::integer_term(field_id.0, last.as_bytes());
+
+        let mut term_ids = Vec::new();
+        let mut iter = self.snapshot.raw_iterator();
+        iter.seek(first_key.key());
+        while iter.valid() {
+            if &iter.key().unwrap()[..] > last_key.key() || is_cancelled() {
+                break;
+            }
+
+            if let Some(term_id) = parse_term_id(&iter.value().unwrap()) {
+                term_ids.push(term_id);
+            }
+
+            iter.next();
+        }
+
+        term_ids
+    }
+}
+
+impl RocksDBStore {
+    /// Converts the terms of integer and date fields that were written by older versions of
+    /// kite to the sortable encoding, and adds them to the ordered indexes of their fields
+    ///
+    /// These terms used to be little endian, which doesn't sort in value order. Each term
+    /// keeps its term id so term directories and postings don't need to be rewritten. As the
+    /// term dictionary is shared by all fields, this fails if a term is also used by a field
+    /// that isn't an integer or date field, or if the new encoding of a term is already used
+    /// by another term. The index must be rebuilt in these cases.
+    ///
+    /// Returns true if any terms were converted, the term dictionary must be reloaded then.
+    pub(crate) fn migrate_integer_terms(&self) -> Result<bool, StoreOpenError> {
+        if try!(self.db.get(SORTABLE_INTEGER_TERMS)).is_some() {
+            return Ok(false);
+        }
+
+        // Find the terms that integer and date fields use, and the terms other fields use.
+        // Fields that have been removed from the schema can't be searched, so they're ignored.
+        let mut integer_field_terms = FnvHashSet::default();
+        let mut other_term_ids = FnvHashSet::default();
+        {
+            let mut add_term_directory = |key: Key| {
+                if let Key::TermDirectory { field_id, term_id, .. } = key {
+                    if is_integer_field(&self.schema, FieldId(field_id)) {
+                        integer_field_terms.insert((field_id, TermId(term_id)));
+                    } else if self.schema.contains_key(&FieldId(field_id)) {
+                        other_term_ids.insert(TermId(term_id));
+                    }
+                }
+            };
+
+            let prefix = KeyBuilder::dir_list_prefix();
+            for segment_file in self.segment_files.read().unwrap().values() {
+                for (k, _) in segment_file.iter().skip_while(|&(k, _)| !k.starts_with(prefix.key())).take_while(|&(k, _)| k.starts_with(prefix.key())) {
+                    if let Some(key) = Key::parse(k) {
+                        add_term_directory(key);
+                    }
+                }
+            }
+
+            for (key, _) in KeyIterator::new(self.db.raw_iterator(), prefix) {
+                add_term_directory(key);
+            }
+        }
+
+        let integer_term_ids = integer_field_terms.iter().map(|&(_, term_id)| term_id).collect::<FnvHashSet<TermId>>();
+
+        // Integer terms are 8 bytes, so longer or shorter terms can't clash with them
+        let mut terms = FnvHashMap::default();
+        for (key, v) in KeyIterator::new(self.db.raw_iterator(), KeyBuilder::term_dict_prefix()) {
+            if let Key::TermDictMapping(term) = key {
+                if let (8, Some(term_id)) = (term.len(), parse_term_id(&v)) {
+                    terms.insert(term, term_id);
+                }
+            }
+        }
+
+        let mut new_terms = FnvHashMap::default();
+        for (term, term_id) in terms.iter() {
+            if !integer_term_ids.contains(term_id) {
+                continue;
+            }
+
+            if other_term_ids.contains(term_id) {
+                return Err(StoreOpenError::IntegerTermsError(format!("term {} is used by both integer or date fields and other fields, the index must be rebuilt", term_id.0)));
+            }
+
+            // The term is 8 bytes long, so this can't fail
+            new_terms.insert(*term_id, Term::from_little_endian_integer(term).unwrap());
+        }
+
+        // A term that's being converted can take the old encoding of another one, but not a
+        // term that's being kept
+        for (term_id, new_term) in new_terms.iter() {
+            if let Some(other_term_id) = terms.get(new_term.as_bytes()) {
+                if !new_terms.contains_key(other_term_id) {
+                    return Err(StoreOpenError::IntegerTermsError(format!("the new encoding of term {} is already used by term {}, the index must be rebuilt", term_id.0, other_term_id.0)));
+                }
+            }
+        }
+
+        // The old terms are deleted before any new ones are written, so a new term that has
+        // the old encoding of another term isn't deleted
+        let mut write_batch = WriteBatch::default();
+        for (term, term_id) in terms.iter() {
+            if new_terms.contains_key(term_id) {
+                try!(write_batch.delete(KeyBuilder::term_dict_mapping(term).key()));
+            }
+        }
+
+        for (term_id, new_term) in new_terms.iter() {
+            try!(write_batch.put(KeyBuilder::term_dict_mapping(new_term.as_bytes()).key(), term_id.0.to_string().as_bytes()));
+        }
+
+        for &(field_id, term_id) in integer_field_terms.iter() {
+            if let Some(new_term) = new_terms.get(&term_id) {
+                try!(write_batch.put(KeyBuilder::integer_term(field_id, new_term.as_bytes()).key(), term_id.0.to_string().as_bytes()));
+            }
+        }
+
+        try!(write_batch.put(SORTABLE_INTEGER_TERMS, b""));
+        try!(self.db.write(write_batch));
+
+        Ok(!new_terms.is_empty())
+    }
+}
@@ -0,0 +1,108 @@
+//! Builds documents from JSON objects, according to the schema
+//!
+//! Each key of the object is the name of a field and its value is either a single value or a
+//! list of them. Text is split into terms by the simple analyzer, dates are RFC 3339 strings
+//! and null values are skipped.
+
+use serde_json::Value;
+use chrono::{DateTime, Utc};
+use kite::DocumentBuilder;
+use kite::document::FieldValue;
+use kite::schema::{Schema, FieldType, FieldId};
+use kite::facet::FacetPath;
+
+fn get_field(schema: &Schema, name: &str) -> Option<(FieldId, FieldType)> {
+    schema.get_field_by_name(name)
+        .and_then(|field_id| schema.get(&field_id).map(|field_info| (field_id, field_info.field_type.clone())))
+}
+
+fn as_str<'a>(name: &str, value: &'a Value) -> Result<&'a str, String> {
+    value.as_str().ok_or(format!("field {:?} expects a string", name))
+}
+
+fn as_datetime(name: &str, value: &Value) -> Result<DateTime<Utc>, String> {
+    let value = try!(as_str(name, value));
+    DateTime::parse_from_rfc3339(value)
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .map_err(|e| format!("field {:?} expects an RFC 3339 date: {}", name, e))
+}
+
+fn add_value<'a>(builder: DocumentBuilder<'a>, name: &str, field: FieldId, field_type: &FieldType, value: &Value) -> Result<DocumentBuilder<'a>, String> {
+    Ok(match *field_type {
+        FieldType::Text => builder.text(field, try!(as_str(name, value))),
+        FieldType::PlainString | FieldType::Join => builder.string(field, try!(as_str(name, value))),
+        FieldType::I64 => builder.integer(field, try!(value.as_i64().ok_or(format!("field {:?} expects an integer", name)))),
+        FieldType::Boolean => builder.boolean(field, try!(value.as_bool().ok_or(format!("field {:?} expects a boolean", name)))),
+        FieldType::DateTime => builder.datetime(field, try!(as_datetime(name, value))),
+        FieldType::Facet => builder.facet(field, FacetPath::parse(try!(as_str(name, value)))),
+        FieldType::RankFeature => builder.rank_feature(field, try!(value.as_f64().ok_or(format!("field {:?} expects a number", name))) as f32),
+        FieldType::Completion => return Err(format!("field {:?} is a completion field, these can't be set from JSON", name)),
+    })
+}
+
+/// Adds the values of a JSON object to a document
+///
+/// Keys that aren't fields in the schema are an error unless "ignore_unknown_fields" is set.
+pub fn add_json_fields<'a>(mut builder: DocumentBuilder<'a>, schema: &Schema, fields: &Value, ignore_unknown_fields: bool) -> Result<DocumentBuilder<'a>, String> {
+    let fields = try!(fields.as_object().ok_or("document fields must be a JSON object"));
+
+    for (name, field_value) in fields {
+        let (field, field_type) = match get_field(schema, name) {
+            Some(field) => field,
+            None if ignore_unknown_fields => continue,
+            None => return Err(format!("field {:?} doesn't exist", name)),
+        };
+
+        match *field_value {
+            Value::Null => {}
+            Value::Array(ref values) => {
+                for value in values {
+                    builder = try!(add_value(builder, name, field, &field_type, value));
+                }
+            }
+            ref value => builder = try!(add_value(builder, name, field, &field_type, value)),
+        }
+    }
+
+    Ok(builder)
+}
+
+/// Converts a stored value to JSON, dates are written as RFC 3339 strings
+pub fn field_value_to_json(value: &FieldValue) -> Value {
+    match *value {
+        FieldValue::String(ref string) => Value::from(string.clone()),
+        FieldValue::Integer(integer) => Value::from(integer),
+        FieldValue::Boolean(boolean) => Value::from(boolean),
+        FieldValue::DateTime(ref datetime) => Value::from(datetime.to_rfc3339()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kite::{DocumentBuilder, Term};
+    use kite::schema::{Schema, FieldType, FIELD_INDEXED, FIELD_STORED};
+    use kite::document::FieldValue;
+
+    use super::add_json_fields;
+
+    #[test]
+    fn test_add_json_fields() {
+        let mut schema = Schema::new();
+        let title = schema.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+        let pk = schema.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+
+        let fields = json!({"title": ["Hello world", "Again"], "pk": 1, "extra": true, "empty": null});
+        let builder = add_json_fields(DocumentBuilder::new(&schema, "doc1"), &schema, &fields, true).unwrap();
+        let doc = builder.build().unwrap();
+        assert!(doc.indexed_fields[&title].contains_key(&Term::from_string("again")));
+        match doc.stored_fields.get(&pk) {
+            Some(&FieldValue::Integer(1)) => {}
+            _ => panic!("pk wasn't stored"),
+        }
+
+        let err = add_json_fields(DocumentBuilder::new(&schema, "doc1"), &schema, &fields, false).err();
+        assert_eq!(err, Some("field \"empty\" doesn't exist".to_string()));
+        let err = add_json_fields(DocumentBuilder::new(&schema, "doc1"), &schema, &json!({"pk": "one"}), false).err();
+        assert_eq!(err, Some("field \"pk\" expects an integer".to_string()));
+    }
+}
@@ -1,7 +1,28 @@
+use std::str;
+use std::str::FromStr;
+
+use rocksdb::DBRawIterator;
+use byteorder::{ByteOrder, BigEndian};
+
 pub struct KeyBuilder {
     key: Vec<u8>,
 }
 
+/// The prefixes of the keys that start with the segment they belong to
+///
+/// These are stored field values, segment statistics, rank feature and doc values columns,
+/// field presence bitmaps, completion indexes, postings, index sorts, deletion lists and
+/// segment encryption records. Any new kind of segment data keyed this way must be added here
+/// so the data of segments that were never activated is purged when the store is opened.
+pub const SEGMENT_KEY_PREFIXES: &[u8] = b"vsrcefpoxy";
+
+fn field_stat_name(prefix: &[u8], field_id: u32) -> Vec<u8> {
+    let mut stat_name = prefix.to_vec();
+    stat_name.push(b'-');
+    stat_name.extend(field_id.to_string().as_bytes());
+    stat_name
+}
+
 impl KeyBuilder {
     pub fn new() -> KeyBuilder {
         KeyBuilder {
@@ -15,7 +36,7 @@ impl KeyBuilder {
         }
     }
 
-    pub fn stored_field_value(segment: u32, doc_local_id: u16, field_id: u32, value_type: &[u8]) -> KeyBuilder {
+    pub fn stored_field_value(segment: u32, doc_local_id: u32, field_id: u32, value_type: &[u8]) -> KeyBuilder {
         let mut kb = KeyBuilder::new();
         kb.push_char(b'v');
         kb.push_string(segment.to_string().as_bytes());
@@ -28,6 +49,19 @@ impl KeyBuilder {
         kb
     }
 
+    /// The source of a document is kept with its stored field values, under field id 0
+    ///
+    /// Field ids start at 1, so this can't clash with a stored field. Being a stored value, it's
+    /// moved by merges and written into segment files like any other.
+    pub fn stored_source(segment: u32, doc_local_id: u32) -> KeyBuilder {
+        KeyBuilder::stored_field_value(segment, doc_local_id, 0, b"src")
+    }
+
+    /// Like the source, the routing key of a document is kept under field id 0
+    pub fn stored_routing(segment: u32, doc_local_id: u32) -> KeyBuilder {
+        KeyBuilder::stored_field_value(segment, doc_local_id, 0, b"rt")
+    }
+
     pub fn segment_stored_values_prefix(segment: u32) -> KeyBuilder {
         let mut kb = KeyBuilder::new();
         kb.push_char(b'v');
@@ -36,6 +70,38 @@ impl KeyBuilder {
         kb
     }
 
+    pub fn document_stored_values_prefix(segment: u32, doc_local_id: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'v');
+        kb.push_string(segment.to_string().as_bytes());
+        kb.separator();
+        kb.push_string(doc_local_id.to_string().as_bytes());
+        kb.separator();
+        kb
+    }
+
+    pub fn change_log_prefix() -> KeyBuilder {
+        let mut kb = KeyBuilder::with_capacity(9);
+        kb.push_char(b'l');
+        kb
+    }
+
+    /// Sequence numbers are big endian so changes are iterated in order
+    pub fn change(sequence: u64) -> KeyBuilder {
+        let mut kb = KeyBuilder::change_log_prefix();
+        for shift in (0..8).rev() {
+            kb.push_char((sequence >> (shift * 8)) as u8);
+        }
+        kb
+    }
+
+    /// The prefix of the keys of the primary key index
+    pub fn primary_key_index_prefix() -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'k');
+        kb
+    }
+
     pub fn primary_key_index(key: &[u8]) -> KeyBuilder {
         let mut kb = KeyBuilder::with_capacity(1 + key.len());
         kb.push_char(b'k');
@@ -43,6 +109,49 @@ impl KeyBuilder {
         kb
     }
 
+    /// The prefix of the primary key indexes of all tenants
+    pub fn all_tenants_primary_key_index_prefix() -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'n');
+        kb
+    }
+
+    /// The prefix of a tenant's primary key index
+    pub fn tenant_primary_key_index_prefix(tenant: &[u8]) -> KeyBuilder {
+        let mut kb = KeyBuilder::all_tenants_primary_key_index_prefix();
+        kb.push_string(tenant);
+        kb.separator();
+        kb
+    }
+
+    /// Documents of a tenant are keyed by the tenant too, so each tenant has its own key space
+    pub fn tenant_primary_key_index(tenant: &[u8], key: &[u8]) -> KeyBuilder {
+        let mut kb = KeyBuilder::tenant_primary_key_index_prefix(tenant);
+        kb.push_string(key);
+        kb
+    }
+
+    /// The prefix of the keys of the application's metadata
+    pub fn metadata_prefix() -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'm');
+        kb
+    }
+
+    pub fn metadata(key: &[u8]) -> KeyBuilder {
+        let mut kb = KeyBuilder::with_capacity(1 + key.len());
+        kb.push_char(b'm');
+        kb.push_string(key);
+        kb
+    }
+
+    /// The prefix of the keys of the term dictionary
+    pub fn term_dict_prefix() -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b't');
+        kb
+    }
+
     pub fn term_dict_mapping(term: &[u8]) -> KeyBuilder {
         let mut kb = KeyBuilder::with_capacity(1 + term.len());
         kb.push_char(b't');
@@ -50,6 +159,31 @@ impl KeyBuilder {
         kb
     }
 
+    /// The prefix of the ordered indexes of the terms of all integer and date fields
+    pub fn all_integer_terms_prefix() -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'i');
+        kb
+    }
+
+    /// The prefix of the ordered index of the terms of an integer or date field
+    pub fn integer_terms_prefix(field_id: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::all_integer_terms_prefix();
+        kb.push_string(field_id.to_string().as_bytes());
+        kb.separator();
+        kb
+    }
+
+    /// Maps an integer or date term of a field to its term id
+    ///
+    /// The term is written as 16 hex digits so the keys of a field sort in the same order as
+    /// its values, escaping the raw bytes wouldn't keep them in order.
+    pub fn integer_term(field_id: u32, term: &[u8]) -> KeyBuilder {
+        let mut kb = KeyBuilder::integer_terms_prefix(field_id);
+        kb.push_string(format!("{:016x}", BigEndian::read_u64(term)).as_bytes());
+        kb
+    }
+
     pub fn segment_active(segment: u32) -> KeyBuilder {
         let mut kb = KeyBuilder::new();
         kb.push_char(b'a');
@@ -57,6 +191,31 @@ impl KeyBuilder {
         kb
     }
 
+    /// The prefix of the segment ownership records of all tenants
+    pub fn all_tenants_segments_prefix() -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'b');
+        kb
+    }
+
+    /// The prefix of the segments a tenant owns
+    pub fn tenant_segments_prefix(tenant: &[u8]) -> KeyBuilder {
+        let mut kb = KeyBuilder::all_tenants_segments_prefix();
+        kb.push_string(tenant);
+        kb.separator();
+        kb
+    }
+
+    /// Records that a segment only contains documents of the tenant
+    ///
+    /// Everything else in a segment is keyed by the segment id, so this scopes the segment's
+    /// documents, term directories and stored fields to the tenant.
+    pub fn tenant_segment(tenant: &[u8], segment: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::tenant_segments_prefix(tenant);
+        kb.push_string(segment.to_string().as_bytes());
+        kb
+    }
+
     pub fn segment_dir_list(segment: u32, field_id: u32, term_id: u32) -> KeyBuilder {
         let mut kb = KeyBuilder::new();
         kb.push_char(b'd');
@@ -68,6 +227,22 @@ impl KeyBuilder {
         kb
     }
 
+    /// The prefix of the term directories of all fields in all segments
+    pub fn dir_list_prefix() -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'd');
+        kb
+    }
+
+    /// The prefix of the term directories of a field in all segments
+    pub fn field_dir_list_prefix(field_id: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'd');
+        kb.push_string(field_id.to_string().as_bytes());
+        kb.separator();
+        kb
+    }
+
     pub fn segment_stat_prefix(segment: u32) -> KeyBuilder {
         let mut kb = KeyBuilder::new();
         kb.push_char(b's');
@@ -103,6 +278,13 @@ impl KeyBuilder {
         stat_name
     }
 
+    /// The start of the names of all the term document frequency statistics of a field
+    pub fn segment_stat_term_doc_frequency_stat_name_prefix(field_id: u32) -> Vec<u8> {
+        let mut stat_name = field_stat_name(b"tdf", field_id);
+        stat_name.push(b'-');
+        stat_name
+    }
+
     pub fn segment_stat_total_field_tokens_stat_name(field_id: u32) -> Vec<u8> {
         let mut stat_name = Vec::new();
         for c in b"fttok" {
@@ -133,6 +315,114 @@ impl KeyBuilder {
         stat_name
     }
 
+    /// The number of documents that have a value for the field (indexed or stored)
+    pub fn segment_stat_field_doc_count_stat_name(field_id: u32) -> Vec<u8> {
+        field_stat_name(b"fdc", field_id)
+    }
+
+    /// The smallest doc value of the field, combined with "min" when segments are merged
+    pub fn segment_stat_field_min_value_stat_name(field_id: u32) -> Vec<u8> {
+        field_stat_name(b"fmin", field_id)
+    }
+
+    /// The largest doc value of the field, combined with "max" when segments are merged
+    pub fn segment_stat_field_max_value_stat_name(field_id: u32) -> Vec<u8> {
+        field_stat_name(b"fmax", field_id)
+    }
+
+    pub fn segment_rank_features_prefix(segment: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'r');
+        kb.push_string(segment.to_string().as_bytes());
+        kb.separator();
+        kb
+    }
+
+    pub fn segment_rank_feature_column(segment: u32, field_id: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::segment_rank_features_prefix(segment);
+        kb.push_string(field_id.to_string().as_bytes());
+        kb
+    }
+
+    pub fn segment_doc_values_prefix(segment: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'c');
+        kb.push_string(segment.to_string().as_bytes());
+        kb.separator();
+        kb
+    }
+
+    pub fn segment_doc_values_column(segment: u32, field_id: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::segment_doc_values_prefix(segment);
+        kb.push_string(field_id.to_string().as_bytes());
+        kb
+    }
+
+    pub fn segment_field_presence_prefix(segment: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'e');
+        kb.push_string(segment.to_string().as_bytes());
+        kb.separator();
+        kb
+    }
+
+    pub fn segment_field_presence(segment: u32, field_id: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::segment_field_presence_prefix(segment);
+        kb.push_string(field_id.to_string().as_bytes());
+        kb
+    }
+
+    pub fn segment_completions_prefix(segment: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'f');
+        kb.push_string(segment.to_string().as_bytes());
+        kb.separator();
+        kb
+    }
+
+    pub fn segment_completion_index(segment: u32, field_id: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::segment_completions_prefix(segment);
+        kb.push_string(field_id.to_string().as_bytes());
+        kb
+    }
+
+    pub fn segment_postings_prefix(segment: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'p');
+        kb.push_string(segment.to_string().as_bytes());
+        kb.separator();
+        kb
+    }
+
+    pub fn segment_postings_skip_list(segment: u32, field_id: u32, term_id: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::segment_postings_prefix(segment);
+        kb.push_string(field_id.to_string().as_bytes());
+        kb.separator();
+        kb.push_string(term_id.to_string().as_bytes());
+        kb
+    }
+
+    pub fn segment_postings_block(segment: u32, field_id: u32, term_id: u32, block_ord: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::segment_postings_skip_list(segment, field_id, term_id);
+        kb.separator();
+        kb.push_string(block_ord.to_string().as_bytes());
+        kb
+    }
+
+    pub fn segment_postings_impacts(segment: u32, field_id: u32, term_id: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::segment_postings_skip_list(segment, field_id, term_id);
+        kb.separator();
+        kb.push_char(b'i');
+        kb
+    }
+
+    pub fn segment_index_sort(segment: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'o');
+        kb.push_string(segment.to_string().as_bytes());
+        kb
+    }
+
     pub fn segment_del_list(segment: u32) -> KeyBuilder {
         let mut kb = KeyBuilder::new();
         kb.push_char(b'x');
@@ -140,6 +430,18 @@ impl KeyBuilder {
         kb
     }
 
+    pub fn all_segments_encryption_prefix() -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'y');
+        kb
+    }
+
+    pub fn segment_encryption(segment: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::all_segments_encryption_prefix();
+        kb.push_string(segment.to_string().as_bytes());
+        kb
+    }
+
     #[inline]
     pub fn key(&self) -> &[u8] {
         &self.key[..]
@@ -163,3 +465,288 @@ impl KeyBuilder {
         self.key.push(b'/');
     }
 }
+
+/// A key that's been decoded back from the bytes built by a KeyBuilder
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    StoredFieldValue { segment: u32, doc_local_id: u32, field_id: u32, value_type: Vec<u8> },
+    Change(u64),
+    PrimaryKeyIndex(Vec<u8>),
+    TenantPrimaryKeyIndex { tenant: Vec<u8>, key: Vec<u8> },
+    TenantSegment { tenant: Vec<u8>, segment: u32 },
+    Metadata(Vec<u8>),
+    TermDictMapping(Vec<u8>),
+    IntegerTerm { field_id: u32, term: Vec<u8> },
+    SegmentActive(u32),
+    TermDirectory { segment: u32, field_id: u32, term_id: u32 },
+    SegmentStat { segment: u32, name: Vec<u8> },
+    RankFeatureColumn { segment: u32, field_id: u32 },
+    DocValuesColumn { segment: u32, field_id: u32 },
+    FieldPresence { segment: u32, field_id: u32 },
+    CompletionIndex { segment: u32, field_id: u32 },
+    PostingsSkipList { segment: u32, field_id: u32, term_id: u32 },
+    PostingsBlock { segment: u32, field_id: u32, term_id: u32, block_ord: u32 },
+    PostingsImpacts { segment: u32, field_id: u32, term_id: u32 },
+    IndexSort(u32),
+    DeletionList(u32),
+    SegmentEncryption(u32),
+}
+
+/// Splits a key into the parts that were separated with separator(), removing the escaping
+/// added by push_char()
+fn split_key(key: &[u8]) -> Vec<Vec<u8>> {
+    let mut parts = vec![Vec::new()];
+    let mut escaped = false;
+    for c in key {
+        if escaped {
+            parts.last_mut().unwrap().push(*c);
+            escaped = false;
+        } else if *c == b'\\' {
+            escaped = true;
+        } else if *c == b'/' {
+            parts.push(Vec::new());
+        } else {
+            parts.last_mut().unwrap().push(*c);
+        }
+    }
+    parts
+}
+
+fn parse_number<T: FromStr>(part: &[u8]) -> Option<T> {
+    str::from_utf8(part).ok().and_then(|part| part.parse::<T>().ok())
+}
+
+/// Decodes the hex digits of an integer term key back into the term
+fn parse_integer_term(part: &[u8]) -> Option<Vec<u8>> {
+    if part.len() != 16 {
+        return None;
+    }
+
+    str::from_utf8(part).ok().and_then(|part| u64::from_str_radix(part, 16).ok()).map(|value| {
+        let mut term = vec![0; 8];
+        BigEndian::write_u64(&mut term, value);
+        term
+    })
+}
+
+impl Key {
+    /// Decodes a key
+    ///
+    /// Returns None for keys that aren't built by a KeyBuilder (such as ".next_term_id").
+    pub fn parse(key: &[u8]) -> Option<Key> {
+        if key.is_empty() {
+            return None;
+        }
+
+        let parts = split_key(&key[1..]);
+        let number = |i: usize| parse_number::<u32>(&parts[i]);
+
+        match (key[0], parts.len()) {
+            (b'v', 4) => number(0).and_then(|segment| parse_number::<u32>(&parts[1]).and_then(|doc_local_id| number(2).map(|field_id| {
+                Key::StoredFieldValue { segment: segment, doc_local_id: doc_local_id, field_id: field_id, value_type: parts[3].clone() }
+            }))),
+            (b'l', 1) if parts[0].len() == 8 => Some(Key::Change(BigEndian::read_u64(&parts[0]))),
+            (b'k', 1) => Some(Key::PrimaryKeyIndex(parts[0].clone())),
+            (b'n', 2) => Some(Key::TenantPrimaryKeyIndex { tenant: parts[0].clone(), key: parts[1].clone() }),
+            (b'b', 2) => number(1).map(|segment| Key::TenantSegment { tenant: parts[0].clone(), segment: segment }),
+            (b'm', 1) => Some(Key::Metadata(parts[0].clone())),
+            (b't', 1) => Some(Key::TermDictMapping(parts[0].clone())),
+            (b'i', 2) => number(0).and_then(|field_id| parse_integer_term(&parts[1]).map(|term| Key::IntegerTerm { field_id: field_id, term: term })),
+            (b'a', 1) => number(0).map(Key::SegmentActive),
+            (b'd', 3) => number(0).and_then(|field_id| number(1).and_then(|term_id| number(2).map(|segment| {
+                Key::TermDirectory { segment: segment, field_id: field_id, term_id: term_id }
+            }))),
+            (b's', 2) => number(0).map(|segment| Key::SegmentStat { segment: segment, name: parts[1].clone() }),
+            (b'r', 2) => number(0).and_then(|segment| number(1).map(|field_id| Key::RankFeatureColumn { segment: segment, field_id: field_id })),
+            (b'c', 2) => number(0).and_then(|segment| number(1).map(|field_id| Key::DocValuesColumn { segment: segment, field_id: field_id })),
+            (b'e', 2) => number(0).and_then(|segment| number(1).map(|field_id| Key::FieldPresence { segment: segment, field_id: field_id })),
+            (b'f', 2) => number(0).and_then(|segment| number(1).map(|field_id| Key::CompletionIndex { segment: segment, field_id: field_id })),
+            (b'p', 3) => number(0).and_then(|segment| number(1).and_then(|field_id| number(2).map(|term_id| {
+                Key::PostingsSkipList { segment: segment, field_id: field_id, term_id: term_id }
+            }))),
+            (b'p', 4) => number(0).and_then(|segment| number(1).and_then(|field_id| number(2).and_then(|term_id| {
+                if parts[3] == b"i" {
+                    Some(Key::PostingsImpacts { segment: segment, field_id: field_id, term_id: term_id })
+                } else {
+                    number(3).map(|block_ord| Key::PostingsBlock { segment: segment, field_id: field_id, term_id: term_id, block_ord: block_ord })
+                }
+            }))),
+            (b'o', 1) => number(0).map(Key::IndexSort),
+            (b'x', 1) => number(0).map(Key::DeletionList),
+            (b'y', 1) => number(0).map(Key::SegmentEncryption),
+            _ => None,
+        }
+    }
+
+    /// Encodes the key again
+    pub fn key_builder(&self) -> KeyBuilder {
+        match *self {
+            Key::StoredFieldValue { segment, doc_local_id, field_id, ref value_type } => KeyBuilder::stored_field_value(segment, doc_local_id, field_id, value_type),
+            Key::Change(sequence) => KeyBuilder::change(sequence),
+            Key::PrimaryKeyIndex(ref key) => KeyBuilder::primary_key_index(key),
+            Key::TenantPrimaryKeyIndex { ref tenant, ref key } => KeyBuilder::tenant_primary_key_index(tenant, key),
+            Key::TenantSegment { ref tenant, segment } => KeyBuilder::tenant_segment(tenant, segment),
+            Key::Metadata(ref key) => KeyBuilder::metadata(key),
+            Key::TermDictMapping(ref term) => KeyBuilder::term_dict_mapping(term),
+            Key::IntegerTerm { field_id, ref term } => KeyBuilder::integer_term(field_id, term),
+            Key::SegmentActive(segment) => KeyBuilder::segment_active(segment),
+            Key::TermDirectory { segment, field_id, term_id } => KeyBuilder::segment_dir_list(segment, field_id, term_id),
+            Key::SegmentStat { segment, ref name } => KeyBuilder::segment_stat(segment, name),
+            Key::RankFeatureColumn { segment, field_id } => KeyBuilder::segment_rank_feature_column(segment, field_id),
+            Key::DocValuesColumn { segment, field_id } => KeyBuilder::segment_doc_values_column(segment, field_id),
+            Key::FieldPresence { segment, field_id } => KeyBuilder::segment_field_presence(segment, field_id),
+            Key::CompletionIndex { segment, field_id } => KeyBuilder::segment_completion_index(segment, field_id),
+            Key::PostingsSkipList { segment, field_id, term_id } => KeyBuilder::segment_postings_skip_list(segment, field_id, term_id),
+            Key::PostingsBlock { segment, field_id, term_id, block_ord } => KeyBuilder::segment_postings_block(segment, field_id, term_id, block_ord),
+            Key::PostingsImpacts { segment, field_id, term_id } => KeyBuilder::segment_postings_impacts(segment, field_id, term_id),
+            Key::IndexSort(segment) => KeyBuilder::segment_index_sort(segment),
+            Key::DeletionList(segment) => KeyBuilder::segment_del_list(segment),
+            Key::SegmentEncryption(segment) => KeyBuilder::segment_encryption(segment),
+        }
+    }
+
+    /// The segment the key belongs to, if it belongs to one
+    pub fn segment(&self) -> Option<u32> {
+        match *self {
+            Key::StoredFieldValue { segment, .. } |
+            Key::SegmentActive(segment) |
+            Key::TenantSegment { segment, .. } |
+            Key::TermDirectory { segment, .. } |
+            Key::SegmentStat { segment, .. } |
+            Key::RankFeatureColumn { segment, .. } |
+            Key::DocValuesColumn { segment, .. } |
+            Key::FieldPresence { segment, .. } |
+            Key::CompletionIndex { segment, .. } |
+            Key::PostingsSkipList { segment, .. } |
+            Key::PostingsBlock { segment, .. } |
+            Key::PostingsImpacts { segment, .. } |
+            Key::IndexSort(segment) |
+            Key::DeletionList(segment) |
+            Key::SegmentEncryption(segment) => Some(segment),
+            Key::Change(_) |
+            Key::PrimaryKeyIndex(_) |
+            Key::TenantPrimaryKeyIndex { .. } |
+            Key::Metadata(_) |
+            Key::TermDictMapping(_) |
+            Key::IntegerTerm { .. } => None,
+        }
+    }
+}
+
+/// Iterates over the keys that start with a prefix, decoding each of them
+///
+/// Yields the decoded key and its value. Keys that can't be decoded are skipped.
+pub struct KeyIterator {
+    iter: DBRawIterator,
+    prefix: KeyBuilder,
+}
+
+impl KeyIterator {
+    pub fn new(mut iter: DBRawIterator, prefix: KeyBuilder) -> KeyIterator {
+        iter.seek(prefix.key());
+
+        KeyIterator {
+            iter: iter,
+            prefix: prefix,
+        }
+    }
+}
+
+impl Iterator for KeyIterator {
+    type Item = (Key, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Key, Vec<u8>)> {
+        while self.iter.valid() {
+            let k = self.iter.key().unwrap();
+            if !k.starts_with(self.prefix.key()) {
+                return None;
+            }
+
+            let value = self.iter.value().unwrap();
+            self.iter.next();
+
+            if let Some(key) = Key::parse(&k) {
+                return Some((key, value));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyBuilder, Key};
+
+    #[test]
+    fn test_parse() {
+        let keys = vec![
+            Key::StoredFieldValue { segment: 1, doc_local_id: 2, field_id: 3, value_type: b"val".to_vec() },
+            Key::StoredFieldValue { segment: 1, doc_local_id: 2, field_id: 0, value_type: b"src".to_vec() },
+            Key::Change(47),
+            Key::Change(0x2f5c),
+            Key::PrimaryKeyIndex(b"a/b\\c".to_vec()),
+            Key::TenantPrimaryKeyIndex { tenant: b"acme/eu".to_vec(), key: b"a/b".to_vec() },
+            Key::TenantSegment { tenant: b"acme".to_vec(), segment: 12 },
+            Key::Metadata(b"version".to_vec()),
+            Key::TermDictMapping(b"http://".to_vec()),
+            Key::IntegerTerm { field_id: 2, term: vec![128, 0, 0, 0, 0, 0, 0x2f, 0x5c] },
+            Key::SegmentActive(12),
+            Key::TermDirectory { segment: 1, field_id: 2, term_id: 3 },
+            Key::SegmentStat { segment: 1, name: b"tdf-2-3".to_vec() },
+            Key::RankFeatureColumn { segment: 1, field_id: 2 },
+            Key::DocValuesColumn { segment: 1, field_id: 2 },
+            Key::FieldPresence { segment: 1, field_id: 2 },
+            Key::CompletionIndex { segment: 1, field_id: 2 },
+            Key::PostingsSkipList { segment: 1, field_id: 2, term_id: 3 },
+            Key::PostingsBlock { segment: 1, field_id: 2, term_id: 3, block_ord: 4 },
+            Key::PostingsImpacts { segment: 1, field_id: 2, term_id: 3 },
+            Key::IndexSort(1),
+            Key::DeletionList(1),
+            Key::SegmentEncryption(1),
+        ];
+
+        for key in keys {
+            assert_eq!(Key::parse(key.key_builder().key()), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(Key::parse(b""), None);
+        assert_eq!(Key::parse(b".next_term_id"), None);
+        assert_eq!(Key::parse(b"d1/2"), None);
+        assert_eq!(Key::parse(b"dx/2/3"), None);
+        assert_eq!(Key::parse(b"v1/5000000000/3/val"), None);
+        assert_eq!(Key::parse(KeyBuilder::field_dir_list_prefix(1).key()), None);
+        assert_eq!(Key::parse(b"bacme/x"), None);
+        assert_eq!(Key::parse(b"i1/123"), None);
+    }
+
+    #[test]
+    fn test_integer_terms_sort_in_value_order() {
+        use kite::Term;
+
+        let keys = [-1000, -1, 0, 1, 255, 256, 1000].iter()
+            .map(|value| KeyBuilder::integer_term(3, Term::from_integer(*value).as_bytes()).key().to_vec())
+            .collect::<Vec<_>>();
+
+        let mut sorted_keys = keys.clone();
+        sorted_keys.sort();
+        assert_eq!(sorted_keys, keys);
+        assert!(keys.iter().all(|key| key.starts_with(KeyBuilder::integer_terms_prefix(3).key())));
+    }
+
+    #[test]
+    fn test_parse_term_directory_key() {
+        assert_eq!(Key::parse(b"d1/23/4"), Some(Key::TermDirectory { segment: 4, field_id: 1, term_id: 23 }));
+        assert_eq!(Key::parse(b"d1/23"), None);
+    }
+
+    #[test]
+    fn test_segment() {
+        assert_eq!(Key::TermDirectory { segment: 3, field_id: 1, term_id: 2 }.segment(), Some(3));
+        assert_eq!(Key::PrimaryKeyIndex(b"3".to_vec()).segment(), None);
+        assert_eq!(Key::TenantSegment { tenant: b"3".to_vec(), segment: 4 }.segment(), Some(4));
+    }
+}
@@ -1,46 +1,139 @@
 extern crate kite;
 extern crate rocksdb;
+#[macro_use]
 extern crate serde_json;
 extern crate roaring;
 extern crate byteorder;
 extern crate chrono;
 extern crate fnv;
+extern crate libc;
+extern crate fst;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
+#[macro_use]
+mod logging;
 mod key_builder;
 mod segment;
 mod segment_manager;
 mod segment_ops;
 mod segment_stats;
 mod segment_builder;
+mod postings;
+mod doc_values;
 mod term_dictionary;
+mod integer_terms;
 mod document_index;
+mod deletion_list;
 mod search;
+mod search_executor;
+mod slow_query_log;
+mod segment_file;
+mod reader_epochs;
+mod group_commit;
+mod stored_fields;
+mod indexer;
+mod index_sort;
+mod completion;
+mod term_vectors;
+mod reindex;
+mod clone;
+mod field_stats;
+mod change_log;
+mod listener;
+mod replication;
+mod metadata;
+mod snapshot_repository;
+mod index_writer;
+mod json_document;
+mod bulk;
+mod export;
+mod arrow_ipc;
+mod tenant;
+mod encryption;
+mod store;
+mod term_directory_cache;
+mod lru_cache;
 
 use std::str;
 use std::fmt;
+use std::error::Error;
+use std::ops::Deref;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock, Mutex};
+use std::sync::atomic::AtomicBool;
 
-use rocksdb::{DB, WriteBatch, Options, MergeOperands, Snapshot};
-use kite::{Document, DocId, TermId};
+use rocksdb::{DB, DBRawIterator, WriteBatch, Options, MergeOperands, Snapshot};
+use kite::{Document, DocId, StoredDocument, TermId};
 use kite::document::FieldValue;
-use kite::schema::{Schema, FieldType, FieldFlags, FieldId, AddFieldError};
+use kite::schema::{Schema, FieldType, FieldFlags, FieldId, AddFieldError, FIELD_STORED, FIELD_TERM_VECTORS, FIELD_INFIX};
 use kite::segment::SegmentId;
+use kite::field::{Field, FieldKind};
+use kite::cancellation::CancellationToken;
+use kite::metrics::{self, Metrics, NoopMetrics};
+use kite::distributed::CorpusStatistics;
+use kite::doc_id_set::DocIdSet;
+use roaring::RoaringBitmap;
 use byteorder::{ByteOrder, LittleEndian};
-use chrono::{NaiveDateTime, DateTime, Utc};
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 
-use key_builder::KeyBuilder;
-use segment_manager::SegmentManager;
+use key_builder::{KeyBuilder, Key, KeyIterator};
+use segment_manager::{SegmentManager, SegmentGeneration};
 use term_dictionary::TermDictionaryManager;
-use document_index::DocumentIndexManager;
+use integer_terms::{SORTABLE_INTEGER_TERMS, is_integer_field};
+use document_index::{DocumentIndexManager, DocumentKey, decode_doc_id};
+use change_log::ChangeLog;
+use metadata::PendingMetadata;
+use search_executor::SearchExecutor;
+use slow_query_log::SlowQueryLog;
+use segment_file::SegmentFile;
+use search::warm_queries::WarmQueries;
+use search::filter_cache::FilterCache;
+use search::join::JoinMap;
+use reader_epochs::ReaderEpochs;
+use term_directory_cache::TermDirectoryCache;
+use group_commit::GroupCommit;
+use stored_fields::decode_stored_field_ref;
+use deletion_list::{encode_deletion_list, merge_deletion_list};
+
+pub use search::SearchResult;
+pub use search_executor::{SearchExecutorConfig, SearchExecutorError, SearchHandle};
+pub use slow_query_log::SlowQuery;
+pub use group_commit::GroupCommitConfig;
+pub use stored_fields::{StoredFieldBytes, StoredFieldRef};
+pub use indexer::{Indexer, IndexerConfig, IndexerError, IndexHandle};
+pub use index_sort::IndexSort;
+pub use completion::CompletionSuggestion;
+pub use search::suggest::{Suggestion, TermSuggestions, PopularTerm};
+pub use search::facets::FacetCount;
+pub use search::scroll::Scroll;
+pub use reindex::{ReindexConfig, ReindexProgress, ReindexError};
+pub use field_stats::FieldStatistics;
+pub use segment_stats::{GarbageReport, GarbageReportConfig, SegmentGarbage, CompactionAction};
+pub use change_log::{Change, ChangeKind};
+pub use listener::IndexListener;
+pub use replication::{ReplicationCheckpoint, ReplicationDelta};
+pub use snapshot_repository::{SnapshotRepository, FsSnapshotRepository};
+pub use index_writer::{IndexWriter, IndexWriterError, IndexReader};
+pub use json_document::{add_json_fields, field_value_to_json};
+pub use export::{ExportConfig, ArrowExportConfig, ExportError};
+pub use tenant::Tenant;
+pub use encryption::{BlockEncryptor, EncryptionConfig, DecryptionError};
+pub use term_directory_cache::DEFAULT_TERM_DIRECTORY_CACHE_SIZE;
+pub use search::filter_cache::DEFAULT_FILTER_CACHE_SIZE;
+pub use bulk::{BulkConfig, BulkOp, BulkAction, BulkError, BulkItem, BulkItemResult, BulkItemError, BulkResponse, parse_bulk};
 
 fn merge_keys(key: &[u8], existing_val: Option<&[u8]>, operands: &mut MergeOperands) -> Vec<u8> {
     match key[0] {
-        b'd' | b'x' => {
-            // Sequence of two byte document ids
-            // d = directory
-            // x = deletion list
+        b'x' => {
+            // Deletion list
+            merge_deletion_list(existing_val, operands)
+        }
+        b'd' => {
+            // Directory, a sequence of two byte document ids
 
             // Allocate vec for new Value
             let new_size = match existing_val {
@@ -96,6 +189,9 @@ pub enum DocumentInsertError {
 
     /// The segment is full
     SegmentFull,
+
+    /// A rank feature value was zero, negative or not a number
+    InvalidRankFeatureValue(FieldId, f32),
 }
 
 impl From<rocksdb::Error> for DocumentInsertError {
@@ -104,24 +200,179 @@ impl From<rocksdb::Error> for DocumentInsertError {
     }
 }
 
+impl fmt::Display for DocumentInsertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DocumentInsertError::RocksDBError(ref e) => write!(f, "RocksDB error: {}", e),
+            DocumentInsertError::SegmentFull => write!(f, "segment is full"),
+            DocumentInsertError::InvalidRankFeatureValue(field_id, value) => write!(f, "invalid value for rank feature field {}: {}", field_id.0, value),
+        }
+    }
+}
+
+impl Error for DocumentInsertError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            DocumentInsertError::RocksDBError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<segment_builder::DocumentInsertError> for DocumentInsertError {
     fn from(e: segment_builder::DocumentInsertError) -> DocumentInsertError {
         match e {
             segment_builder::DocumentInsertError::SegmentFull => DocumentInsertError::SegmentFull,
+            segment_builder::DocumentInsertError::InvalidRankFeatureValue(field_id, value) => DocumentInsertError::InvalidRankFeatureValue(field_id, value),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreOpenError {
+    /// A RocksDB error occurred while opening the database
+    RocksDBError(rocksdb::Error),
+
+    /// The database doesn't have a schema, so it isn't an index
+    SchemaMissing,
+
+    /// The schema couldn't be encoded or decoded
+    SchemaError(serde_json::Error),
+
+    /// The segment files couldn't be opened
+    SegmentFileError(String),
+
+    /// The terms of integer and date fields couldn't be converted to the sortable encoding
+    IntegerTermsError(String),
+}
+
+impl From<rocksdb::Error> for StoreOpenError {
+    fn from(e: rocksdb::Error) -> StoreOpenError {
+        StoreOpenError::RocksDBError(e)
+    }
+}
+
+impl fmt::Display for StoreOpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreOpenError::RocksDBError(ref e) => write!(f, "RocksDB error: {}", e),
+            StoreOpenError::SchemaMissing => write!(f, "unable to find schema in store"),
+            StoreOpenError::SchemaError(ref e) => write!(f, "schema error: {}", e),
+            StoreOpenError::SegmentFileError(ref e) => write!(f, "segment file error: {}", e),
+            StoreOpenError::IntegerTermsError(ref e) => write!(f, "unable to convert integer terms: {}", e),
+        }
+    }
+}
+
+impl Error for StoreOpenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            StoreOpenError::RocksDBError(ref e) => Some(e),
+            StoreOpenError::SchemaError(ref e) => Some(e),
+            _ => None,
         }
     }
 }
 
+impl From<StoreOpenError> for String {
+    fn from(e: StoreOpenError) -> String {
+        e.to_string()
+    }
+}
+
+/// The most documents a segment can have, unless the store is configured otherwise
+///
+/// Segments with more documents than this are written in formats that versions of kite from
+/// before document ords were widened to u32 can't read.
+pub const DEFAULT_MAX_SEGMENT_DOCS: u32 = 65536;
+
+/// A search index stored in RocksDB
+///
+/// # Concurrency
+///
+/// The store is `Send + Sync`, share it between threads with an `Arc`. Every method takes
+/// `&self` except those that change the configuration of the store or its schema, so inserts,
+/// deletes, merges and searches can all run at the same time from different threads. Writes
+/// are atomic, a search never sees half of an insert or merge.
+///
+/// Readers are `Send + Sync` too. Each one is a point-in-time view that borrows the store so
+/// it can be moved to (or shared with) any thread that doesn't outlive the store. Use an
+/// `IndexReader` to hand threads an owned handle instead.
 pub struct RocksDBStore {
     schema: Arc<Schema>,
     db: DB,
     term_dictionary: TermDictionaryManager,
     segments: SegmentManager,
     document_index: DocumentIndexManager,
+    search_executor: Option<SearchExecutor>,
+    slow_query_log: Option<SlowQueryLog>,
+    metrics: Arc<dyn Metrics>,
+    write_segment_files: bool,
+    segment_files: RwLock<FnvHashMap<u32, Arc<SegmentFile>>>,
+    warm_queries: WarmQueries,
+    term_directory_cache: TermDirectoryCache,
+    filter_cache: Arc<FilterCache>,
+    reader_epochs: ReaderEpochs,
+    group_commit: Option<GroupCommit>,
+    index_sort: Option<IndexSort>,
+    max_segment_docs: u32,
+    change_log: ChangeLog,
+    change_payload: Option<Box<dyn Fn(&Document) -> Option<Vec<u8>> + Send + Sync>>,
+    listeners: Vec<Arc<dyn IndexListener>>,
+    pending_metadata: PendingMetadata,
+    encryptor: Option<Arc<dyn BlockEncryptor>>,
+    encryption_config: EncryptionConfig,
+
+    /// Set while an IndexWriter is open, there can only be one at a time
+    writer_open: AtomicBool,
+}
+
+/// Converts a term frequency value type ("tf{term_id}") from a segment builder's term id to the real one
+///
+/// Other value types are returned unchanged.
+fn remap_term_frequency_value_type(value_type: &[u8], term_dictionary_map: &FnvHashMap<TermId, TermId>) -> Vec<u8> {
+    if value_type.starts_with(b"tf") {
+        let term_id = str::from_utf8(&value_type[2..]).ok().and_then(|term_id| term_id.parse::<u32>().ok());
+
+        if let Some(new_term_id) = term_id.and_then(|term_id| term_dictionary_map.get(&TermId(term_id))) {
+            let mut new_value_type = vec![b't', b'f'];
+            new_value_type.extend(new_term_id.0.to_string().as_bytes());
+            return new_value_type;
+        }
+    }
+
+    value_type.to_vec()
+}
+
+/// Converts a term document frequency statistic name ("tdf-{field_id}-{term_id}") from a segment
+/// builder's term id to the real one
+///
+/// Other statistic names are returned unchanged.
+fn remap_term_doc_frequency_stat_name(stat_name: &[u8], term_dictionary_map: &FnvHashMap<TermId, TermId>) -> Vec<u8> {
+    if stat_name.starts_with(b"tdf-") {
+        let mut parts = stat_name[4..].split(|b| *b == b'-').map(|part| str::from_utf8(part).ok().and_then(|part| part.parse::<u32>().ok()));
+
+        if let (Some(Some(field_id)), Some(Some(term_id)), None) = (parts.next(), parts.next(), parts.next()) {
+            if let Some(new_term_id) = term_dictionary_map.get(&TermId(term_id)) {
+                return KeyBuilder::segment_stat_term_doc_frequency_stat_name(field_id, new_term_id.0);
+            }
+        }
+    }
+
+    stat_name.to_vec()
+}
+
+/// Serializes a term directory or field presence bitmap with "DocIdSet::serialize_into"
+fn encode_doc_id_set(bitmap: &RoaringBitmap) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    // Writing into a Vec can't fail
+    DocIdSet::from(bitmap).serialize_into(&mut bytes).unwrap();
+    bytes
 }
 
 impl RocksDBStore {
-    pub fn create<P: AsRef<Path>>(path: P) -> Result<RocksDBStore, String> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<RocksDBStore, StoreOpenError> {
         let mut opts = Options::default();
         opts.set_merge_operator("merge operator", merge_keys);
         opts.create_if_missing(true);
@@ -129,12 +380,12 @@ impl RocksDBStore {
 
         // Schema
         let schema = Schema::new();
-        let schema_encoded = match serde_json::to_string(&schema) {
-            Ok(schema_encoded) => schema_encoded,
-            Err(e) => return Err(format!("schema encode error: {:?}", e).into()),
-        };
+        let schema_encoded = try!(serde_json::to_string(&schema).map_err(StoreOpenError::SchemaError));
         try!(db.put(b".schema", schema_encoded.as_bytes()));
 
+        // Integer terms are written in the sortable encoding from the start
+        try!(db.put(SORTABLE_INTEGER_TERMS, b""));
+
         // Segment manager
         let segments = try!(SegmentManager::new(&db));
 
@@ -144,16 +395,38 @@ impl RocksDBStore {
         // Document index
         let document_index = try!(DocumentIndexManager::new(&db));
 
+        // Change log
+        let change_log = try!(ChangeLog::new(&db));
+
         Ok(RocksDBStore {
             schema: Arc::new(schema),
             db: db,
             term_dictionary: term_dictionary,
             segments: segments,
             document_index: document_index,
+            search_executor: None,
+            slow_query_log: None,
+            metrics: Arc::new(NoopMetrics),
+            write_segment_files: false,
+            segment_files: RwLock::new(FnvHashMap::default()),
+            warm_queries: WarmQueries::new(),
+            term_directory_cache: TermDirectoryCache::new(DEFAULT_TERM_DIRECTORY_CACHE_SIZE),
+            filter_cache: Arc::new(FilterCache::new(DEFAULT_FILTER_CACHE_SIZE)),
+            reader_epochs: ReaderEpochs::new(),
+            group_commit: None,
+            index_sort: None,
+            max_segment_docs: DEFAULT_MAX_SEGMENT_DOCS,
+            change_log: change_log,
+            change_payload: None,
+            listeners: Vec::new(),
+            pending_metadata: PendingMetadata::new(),
+            encryptor: None,
+            encryption_config: EncryptionConfig::default(),
+            writer_open: AtomicBool::new(false),
         })
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<RocksDBStore, String> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RocksDBStore, StoreOpenError> {
         let mut opts = Options::default();
         opts.set_merge_operator("merge operator", merge_keys);
         let db = try!(DB::open(&opts, path));
@@ -161,12 +434,9 @@ impl RocksDBStore {
         let schema = match try!(db.get(b".schema")) {
             Some(schema) => {
                 let schema = schema.to_utf8().unwrap().to_string();
-                match serde_json::from_str(&schema) {
-                    Ok(schema) => schema,
-                    Err(e) => return Err(format!("schema parse error: {:?}", e).into()),
-                }
+                try!(serde_json::from_str(&schema).map_err(StoreOpenError::SchemaError))
             }
-            None => return Err("unable to find schema in store".into()),
+            None => return Err(StoreOpenError::SchemaMissing),
         };
 
         // Segment manager
@@ -178,19 +448,61 @@ impl RocksDBStore {
         // Document index
         let document_index = try!(DocumentIndexManager::open(&db));
 
-        Ok(RocksDBStore {
+        // Change log
+        let change_log = try!(ChangeLog::open(&db));
+
+        let index_sort = try!(db.get(b".index_sort")).and_then(|index_sort| IndexSort::from_bytes(&index_sort));
+        let max_segment_docs = try!(db.get(b".max_segment_docs")).map(|value| LittleEndian::read_u32(&value)).unwrap_or(DEFAULT_MAX_SEGMENT_DOCS);
+
+        let mut store = RocksDBStore {
             schema: Arc::new(schema),
             db: db,
             term_dictionary: term_dictionary,
             segments: segments,
             document_index: document_index,
-        })
+            search_executor: None,
+            slow_query_log: None,
+            metrics: Arc::new(NoopMetrics),
+            write_segment_files: false,
+            segment_files: RwLock::new(FnvHashMap::default()),
+            warm_queries: WarmQueries::new(),
+            term_directory_cache: TermDirectoryCache::new(DEFAULT_TERM_DIRECTORY_CACHE_SIZE),
+            filter_cache: Arc::new(FilterCache::new(DEFAULT_FILTER_CACHE_SIZE)),
+            reader_epochs: ReaderEpochs::new(),
+            group_commit: None,
+            index_sort: index_sort,
+            max_segment_docs: max_segment_docs,
+            change_log: change_log,
+            change_payload: None,
+            listeners: Vec::new(),
+            pending_metadata: PendingMetadata::new(),
+            encryptor: None,
+            encryption_config: EncryptionConfig::default(),
+            writer_open: AtomicBool::new(false),
+        };
+
+        // Segments that have been written to files
+        try!(store.open_segment_files().map_err(StoreOpenError::SegmentFileError));
+
+        // Clean up after merges that were interrupted
+        try!(store.recover_segments());
+
+        // Convert the integer terms of indexes written by older versions of kite
+        if try!(store.migrate_integer_terms()) {
+            store.term_dictionary = try!(TermDictionaryManager::open(&store.db));
+        }
+
+        Ok(store)
     }
 
     pub fn path(&self) -> &Path {
         self.db.path()
     }
 
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
     pub fn add_field(&mut self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError> {
         let mut schema_copy = (*self.schema).clone();
         let field_id = try!(schema_copy.add_field(name, field_type, field_flags));
@@ -202,6 +514,11 @@ impl RocksDBStore {
         Ok(field_id)
     }
 
+    /// Adds a field, returning a handle that only accepts values of the field's type
+    pub fn add_typed_field<T: FieldKind>(&mut self, name: String, field_flags: FieldFlags) -> Result<Field<T>, AddFieldError> {
+        self.add_field(name, T::field_type(), field_flags).map(Field::new)
+    }
+
     pub fn remove_field(&mut self, field_id: &FieldId) -> bool {
         let mut schema_copy = (*self.schema).clone();
         let field_removed = schema_copy.remove_field(field_id);
@@ -216,23 +533,121 @@ impl RocksDBStore {
         field_removed
     }
 
+    /// Reports metrics about indexing, merging and searching to "metrics"
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Sets the most documents that a segment can have, this is saved in the index
+    ///
+    /// This limits how many documents are written into a segment at once and how large merges
+    /// can get. Existing segments keep working after raising it above the default of 65536, but
+    /// once a segment has more documents than that, the index can't be opened by older versions
+    /// of kite.
+    pub fn set_max_segment_docs(&mut self, max_segment_docs: u32) -> Result<(), String> {
+        if max_segment_docs == 0 {
+            return Err("segments must be able to hold at least one document".to_string());
+        }
+
+        let mut value = [0; 4];
+        LittleEndian::write_u32(&mut value, max_segment_docs);
+        try!(self.db.put(b".max_segment_docs", &value));
+        self.max_segment_docs = max_segment_docs;
+
+        Ok(())
+    }
+
+    pub fn max_segment_docs(&self) -> u32 {
+        self.max_segment_docs
+    }
+
+    /// Sets how many bytes of decoded term directories are cached for searches
+    ///
+    /// The cache is shared by every reader of the store so the term directories of frequently
+    /// searched terms don't have to be loaded and decoded for each search. Setting it to 0
+    /// disables the cache. This isn't saved in the index.
+    pub fn set_term_directory_cache_size(&mut self, bytes: usize) {
+        self.term_directory_cache.set_capacity(bytes);
+    }
+
+    pub fn term_directory_cache_size(&self) -> usize {
+        self.term_directory_cache.capacity()
+    }
+
+    /// Sets how many bytes of filter matches are cached for searches
+    ///
+    /// Once a filter (the "filter" of a Filter query) has been used by a few recent searches,
+    /// the documents it matches in each segment are cached for later searches to use. Setting
+    /// it to 0 disables the cache. This isn't saved in the index.
+    pub fn set_filter_cache_size(&mut self, bytes: usize) {
+        self.filter_cache.set_capacity(bytes);
+    }
+
+    pub fn filter_cache_size(&self) -> usize {
+        self.filter_cache.capacity()
+    }
+
     pub fn insert_or_update_document(&self, doc: &Document) -> Result<(), DocumentInsertError> {
+        if let Some(ref group_commit) = self.group_commit {
+            return self.insert_or_update_document_grouped(group_commit, doc);
+        }
+
+        try!(self.insert_document_segment(doc, None));
+        self.notify_listeners(|listener| listener.document_indexed(&doc.key));
+
+        Ok(())
+    }
+
+    /// Writes the document into a segment of its own, owned by the tenant if there is one
+    pub(crate) fn insert_document_segment(&self, doc: &Document, tenant: Option<&[u8]>) -> Result<(), DocumentInsertError> {
         // Build segment in memory
-        let mut builder = segment_builder::SegmentBuilder::new();
-        let doc_key = doc.key.clone();
+        let mut builder = self.new_segment_builder();
         try!(builder.add_document(doc));
 
+        // Only one document so there's nothing to move, but this records the segment as sorted
+        if let Some(index_sort) = self.index_sort {
+            builder.sort_documents(index_sort);
+        }
+
         // Write the segment
-        let segment = try!(self.write_segment(&builder));
+        let segment = try!(self.write_segment_owned_by(&builder, tenant));
 
         // Update document index
         let doc_id = DocId(SegmentId(segment), 0);
-        try!(self.document_index.insert_or_replace_key(&self.db, &doc_key.as_bytes().iter().cloned().collect(), doc_id));
+        let doc_key = DocumentKey {
+            tenant: tenant.map(|tenant| tenant.to_vec()),
+            key: doc.key.as_bytes().to_vec(),
+        };
+        let mut change = self.upsert_change(doc);
+        change.tenant = doc_key.tenant.clone();
+        try!(self.document_index.insert_or_replace_key(&self.db, &doc_key, doc_id, &self.change_log, change));
+
+        self.metrics.increment_counter(metrics::DOCS_INDEXED, 1);
 
         Ok(())
     }
 
+    /// Creates a segment builder that records the term vectors and n-grams of the fields that need them
+    pub(crate) fn new_segment_builder(&self) -> segment_builder::SegmentBuilder {
+        let mut builder = segment_builder::SegmentBuilder::new();
+        builder.max_docs = self.max_segment_docs;
+        builder.term_vector_fields = self.schema.iter()
+            .filter(|&(_, field_info)| field_info.field_flags.contains(FIELD_TERM_VECTORS))
+            .map(|(field_id, _)| *field_id)
+            .collect();
+        builder.infix_fields = self.schema.iter()
+            .filter(|&(_, field_info)| field_info.field_flags.contains(FIELD_INFIX))
+            .map(|(field_id, _)| *field_id)
+            .collect();
+        builder
+    }
+
     pub fn write_segment(&self, builder: &segment_builder::SegmentBuilder) -> Result<u32, rocksdb::Error> {
+        self.write_segment_owned_by(builder, None)
+    }
+
+    /// Writes a segment, recording the tenant as its owner if there is one
+    pub(crate) fn write_segment_owned_by(&self, builder: &segment_builder::SegmentBuilder, tenant: Option<&[u8]>) -> Result<u32, rocksdb::Error> {
         // Allocate a segment ID
         let segment = try!(self.segments.new_segment(&self.db));
 
@@ -244,36 +659,112 @@ impl RocksDBStore {
         let kb = KeyBuilder::segment_active(segment);
         try!(write_batch.put(&kb.key(), b""));
 
+        // Only the tenant's readers can see the segment
+        if let Some(tenant) = tenant {
+            let kb = KeyBuilder::tenant_segment(tenant, segment);
+            try!(write_batch.put(&kb.key(), b""));
+        }
+
+        // Start with an empty deletion list and no deleted docs
+        // Deletes are applied with the merge operator, which needs an existing value to merge into
+        let kb = KeyBuilder::segment_del_list(segment);
+        try!(write_batch.put(&kb.key(), &encode_deletion_list(&RoaringBitmap::new())));
+        if !builder.statistics.contains_key(&b"deleted_docs"[..]) {
+            let kb = KeyBuilder::segment_stat(segment, b"deleted_docs");
+            try!(write_batch.put(&kb.key(), &[0; 8]));
+        }
+
+        // Record the order of the documents so sorted searches can stop reading early
+        if let Some(index_sort) = builder.index_sort {
+            let kb = KeyBuilder::segment_index_sort(segment);
+            try!(write_batch.put(&kb.key(), &index_sort.to_bytes()));
+        }
+
+        // Record the key the segment is encrypted with
+        let encryption = self.new_segment_encryption();
+        if let Some(ref encryption) = encryption {
+            let kb = KeyBuilder::segment_encryption(segment);
+            try!(write_batch.put(&kb.key(), &encryption.to_bytes()));
+        }
+
         // Merge the term dictionary
         // Writes new terms to disk and generates mapping between the builder's term dictionary and the real one
         let mut term_dictionary_map: FnvHashMap<TermId, TermId> = FnvHashMap::default();
+        let mut integer_terms = FnvHashMap::default();
         for (term, current_term_id) in builder.term_dictionary.iter() {
             let new_term_id = try!(self.term_dictionary.get_or_create(&self.db, term));
             term_dictionary_map.insert(*current_term_id, new_term_id);
+
+            if term.as_bytes().len() == 8 {
+                integer_terms.insert(*current_term_id, term);
+            }
         }
 
         // Write term directories
         for (&(field_id, term_id), term_directory) in builder.term_directories.iter() {
             let new_term_id = term_dictionary_map.get(&term_id).expect("TermId not in term_dictionary_map");
 
-            // Serialise
-            let mut term_directory_bytes = Vec::new();
-            term_directory.serialize_into(&mut term_directory_bytes).unwrap();
-
             // Write
             let kb = KeyBuilder::segment_dir_list(segment, field_id.0, new_term_id.0);
-            try!(write_batch.put(&kb.key(), &term_directory_bytes));
+            try!(write_batch.put(&kb.key(), &encode_doc_id_set(term_directory)));
+
+            // Add integer and date terms to their field's ordered index, so ranges can seek to them
+            if is_integer_field(&self.schema, field_id) {
+                if let Some(term) = integer_terms.get(&term_id) {
+                    let kb = KeyBuilder::integer_term(field_id.0, term.as_bytes());
+                    try!(write_batch.put(&kb.key(), new_term_id.0.to_string().as_bytes()));
+                }
+            }
+
+            // Write postings blocks
+            let doc_impact = |doc_id| (builder.term_frequency(field_id, term_id, doc_id), builder.field_length(field_id, doc_id));
+            for (kb, value) in postings::build_postings(segment, field_id.0, new_term_id.0, term_directory, doc_impact) {
+                try!(write_batch.put(&kb.key(), &self.encrypt_postings(encryption.as_ref(), value)));
+            }
         }
 
         // Write stored fields
+        // Term frequencies are keyed by the builder's term ids so these must be remapped
         for (&(field_id, doc_id, ref value_type), value) in builder.stored_field_values.iter() {
-            let kb = KeyBuilder::stored_field_value(segment, doc_id, field_id.0, value_type);
-            try!(write_batch.put(&kb.key(), value));
+            let value_type = remap_term_frequency_value_type(value_type, &term_dictionary_map);
+            let kb = KeyBuilder::stored_field_value(segment, doc_id, field_id.0, &value_type);
+            try!(write_batch.put(&kb.key(), &self.encrypt_stored_value(encryption.as_ref(), &value_type, value)));
+        }
+
+        // Write rank feature columns
+        for (field_id, column) in builder.rank_features.iter() {
+            let mut column_bytes = vec![0; column.len() * 4];
+            for (doc_id, value) in column.iter().enumerate() {
+                LittleEndian::write_f32(&mut column_bytes[doc_id * 4..], *value);
+            }
+
+            let kb = KeyBuilder::segment_rank_feature_column(segment, field_id.0);
+            try!(write_batch.put(&kb.key(), &column_bytes));
+        }
+
+        // Write doc values columns
+        for (field_id, column) in builder.doc_values.iter() {
+            let kb = KeyBuilder::segment_doc_values_column(segment, field_id.0);
+            try!(write_batch.put(&kb.key(), &doc_values::encode_doc_values_column(column)));
+        }
+
+        // Write field presence bitmaps
+        for (field_id, field_presence) in builder.field_presence.iter() {
+            let kb = KeyBuilder::segment_field_presence(segment, field_id.0);
+            try!(write_batch.put(&kb.key(), &encode_doc_id_set(field_presence)));
+        }
+
+        // Write completion indexes
+        for (field_id, entries) in builder.completions.iter() {
+            let kb = KeyBuilder::segment_completion_index(segment, field_id.0);
+            try!(write_batch.put(&kb.key(), &completion::encode_completion_index(entries.clone())));
         }
 
         // Write statistics
+        // Like term frequencies, term document frequencies need their term ids remapping
         for (name, value) in builder.statistics.iter() {
-            let kb = KeyBuilder::segment_stat(segment, name);
+            let name = remap_term_doc_frequency_stat_name(name, &term_dictionary_map);
+            let kb = KeyBuilder::segment_stat(segment, &name);
 
             let mut value_bytes = [0; 8];
             LittleEndian::write_i64(&mut value_bytes, *value);
@@ -281,32 +772,114 @@ impl RocksDBStore {
         }
 
         // Write data
-        try!(self.db.write(write_batch));
+        // Metadata set by the application is committed with the segment
+        let generation = {
+            let mut pending_metadata = try!(self.pending_metadata.write(&mut write_batch));
+            let generation = try!(self.segments.begin_commit(&mut write_batch));
+            try!(self.db.write(write_batch));
+            pending_metadata.clear();
+            *generation
+        };
+
+        log_debug!("flushed segment {} with {} documents", segment, builder.num_docs());
+        trace_event!(segment = segment, documents = builder.num_docs(), "flushed segment");
+        self.metrics.increment_counter(metrics::SEGMENTS_FLUSHED, 1);
+        self.notify_listeners(|listener| {
+            listener.segment_flushed(segment, builder.num_docs());
+            listener.committed(generation);
+        });
+
+        // Warming is only an optimisation, if it fails the warm queries are run normally
+        let _ = self.refresh_warm_queries();
 
         Ok(segment)
     }
 
     pub fn remove_document_by_key(&self, doc_key: &str) -> Result<bool, rocksdb::Error> {
-        match try!(self.document_index.delete_document_by_key(&self.db, &doc_key.as_bytes().iter().cloned().collect())) {
-            Some(_doc_id) => Ok(true),
+        match try!(self.document_index.delete_document_by_key(&self.db, &DocumentKey::new(doc_key.as_bytes().to_vec()), &self.change_log)) {
+            Some(_doc_id) => {
+                self.notify_listeners(|listener| listener.document_deleted(doc_key));
+                Ok(true)
+            }
             None => Ok(false),
         }
     }
 
+    /// Opens a reader that sees the documents that weren't written through a tenant
+    ///
+    /// The segments of tenants are left out, so the documents of tenants can't be found by
+    /// searching or by their keys through this reader. Use "Tenant::reader" for readers of a
+    /// tenant.
     pub fn reader<'a>(&'a self) -> RocksDBReader<'a> {
+        self.new_reader(ReaderScope::Untenanted)
+    }
+
+    pub(crate) fn new_reader<'a>(&'a self, scope: ReaderScope) -> RocksDBReader<'a> {
+        // The epoch must be taken before the snapshot so segments the snapshot can see can't
+        // be purged before the reader is dropped
+        let epoch = self.reader_epochs.enter();
+        let snapshot = self.db.snapshot();
+        let mut generation = self.segments.generation(&snapshot, &self.segment_files);
+
+        match scope {
+            ReaderScope::WholeStore => {}
+            ReaderScope::Untenanted => {
+                let tenant_segments = KeyIterator::new(snapshot.raw_iterator(), KeyBuilder::all_tenants_segments_prefix())
+                    .filter_map(|(key, _)| key.segment())
+                    .collect::<FnvHashSet<u32>>();
+
+                if !tenant_segments.is_empty() {
+                    let segments = generation.segments().iter().cloned().filter(|segment| !tenant_segments.contains(segment)).collect::<FnvHashSet<u32>>();
+                    generation = Arc::new(generation.restrict(&segments));
+                }
+            }
+            ReaderScope::Tenant(tenant) => {
+                let segments = KeyIterator::new(snapshot.raw_iterator(), KeyBuilder::tenant_segments_prefix(tenant))
+                    .filter_map(|(key, _)| key.segment())
+                    .collect::<FnvHashSet<u32>>();
+                generation = Arc::new(generation.restrict(&segments));
+            }
+        }
+
+        let tenant = match scope {
+            ReaderScope::Tenant(tenant) => Some(tenant.to_vec()),
+            _ => None,
+        };
+
         RocksDBReader {
             store: &self,
-            snapshot: self.db.snapshot(),
+            snapshot: SharedSnapshot(snapshot),
+            generation: generation,
+            tenant: tenant,
+            cancellation_token: None,
+            corpus_statistics: None,
+            epoch: epoch,
+            join_maps: Mutex::new(FnvHashMap::default()),
         }
     }
 }
 
+// The concurrency model that's documented on RocksDBStore relies on these, they stop
+// compiling if something that isn't thread safe is added to one of the types
+#[allow(dead_code)]
+fn assert_thread_safety() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_send<T: Send>() {}
+
+    assert_send_sync::<RocksDBStore>();
+    assert_send_sync::<RocksDBReader<'static>>();
+    assert_send_sync::<IndexReader>();
+    assert_send::<IndexWriter>();
+    assert_send_sync::<Indexer>();
+}
+
 impl fmt::Debug for RocksDBStore {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "RocksDBStore {{ path: {:?} }}", self.db.path())
     }
 }
 
+#[derive(Debug)]
 pub enum StoredFieldReadError {
     /// The provided FieldId wasn't valid for this index
     InvalidFieldId(FieldId),
@@ -322,6 +895,15 @@ pub enum StoredFieldReadError {
 
     /// An integer/datetime field was read but the value wasn't 8 bytes
     IntegerFieldValueSizeError(usize),
+
+    /// A datetime field was read but its timestamp (in microseconds) can't be represented
+    DateTimeFieldOutOfRange(i64),
+
+    /// The field type doesn't have stored values
+    FieldTypeNotStored(FieldType),
+
+    /// The value is in an encrypted segment and couldn't be decrypted
+    DecryptionError(DecryptionError),
 }
 
 impl From<rocksdb::Error> for StoredFieldReadError {
@@ -330,107 +912,539 @@ impl From<rocksdb::Error> for StoredFieldReadError {
     }
 }
 
+impl From<DecryptionError> for StoredFieldReadError {
+    fn from(e: DecryptionError) -> StoredFieldReadError {
+        StoredFieldReadError::DecryptionError(e)
+    }
+}
+
+impl fmt::Display for StoredFieldReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoredFieldReadError::InvalidFieldId(field_id) => write!(f, "invalid field id: {}", field_id.0),
+            StoredFieldReadError::RocksDBError(ref e) => write!(f, "RocksDB error: {}", e),
+            StoredFieldReadError::TextFieldUTF8DecodeError(_, ref e) => write!(f, "text field isn't valid UTF-8: {}", e),
+            StoredFieldReadError::BooleanFieldDecodeError(ref value) => write!(f, "boolean field has invalid value: {:?}", value),
+            StoredFieldReadError::IntegerFieldValueSizeError(size) => write!(f, "integer field value is {} bytes, expected 8", size),
+            StoredFieldReadError::DateTimeFieldOutOfRange(timestamp) => write!(f, "datetime field value is out of range: {}", timestamp),
+            StoredFieldReadError::FieldTypeNotStored(ref field_type) => write!(f, "fields of type {:?} aren't stored", field_type),
+            StoredFieldReadError::DecryptionError(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for StoredFieldReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            StoredFieldReadError::RocksDBError(ref e) => Some(e),
+            StoredFieldReadError::TextFieldUTF8DecodeError(_, ref e) => Some(e),
+            StoredFieldReadError::DecryptionError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The values a document was stored with, as read by "RocksDBReader::read_stored_values"
+struct StoredValues {
+    fields: FnvHashMap<FieldId, FieldValue>,
+    source: Option<Vec<u8>>,
+    routing: Option<String>,
+}
+
+/// Returns false for field types that keep their values somewhere other than the stored fields
+fn has_stored_values(field_type: &FieldType) -> bool {
+    match *field_type {
+        FieldType::RankFeature | FieldType::Completion => false,
+        _ => true,
+    }
+}
+
+/// Converts the raw bytes of a stored field value back into a FieldValue
+fn decode_stored_field_value(field_type: &FieldType, value: &[u8]) -> Result<FieldValue, StoredFieldReadError> {
+    decode_stored_field_ref(field_type, value).map(|value| value.to_field_value())
+}
+
+/// A RocksDB snapshot that can be shared between threads
+///
+/// Snapshots are immutable and RocksDB allows them to be read and released from any thread,
+/// the rocksdb crate just doesn't mark them as Send or Sync as they wrap a raw pointer.
+struct SharedSnapshot<'a>(Snapshot<'a>);
+
+unsafe impl<'a> Send for SharedSnapshot<'a> {}
+unsafe impl<'a> Sync for SharedSnapshot<'a> {}
+
+impl<'a> Deref for SharedSnapshot<'a> {
+    type Target = Snapshot<'a>;
+
+    fn deref(&self) -> &Snapshot<'a> {
+        &self.0
+    }
+}
+
+/// The segments that a reader sees
+#[derive(Clone, Copy)]
+pub(crate) enum ReaderScope<'b> {
+    /// Every segment, including those of tenants. This is only for operations on the whole
+    /// store, such as cloning and replication, readers for searching are never opened with it
+    WholeStore,
+
+    /// The segments that aren't owned by a tenant
+    Untenanted,
+
+    /// The segments owned by a tenant
+    Tenant(&'b [u8]),
+}
+
+/// A point-in-time view of a store for searching, see "RocksDBStore::reader"
+///
+/// Readers can be sent to and shared between threads.
 pub struct RocksDBReader<'a> {
     store: &'a RocksDBStore,
-    snapshot: Snapshot<'a>
+    snapshot: SharedSnapshot<'a>,
+    generation: Arc<SegmentGeneration>,
+
+    /// Readers of a tenant only see the tenant's segments and keys
+    tenant: Option<Vec<u8>>,
+
+    cancellation_token: Option<CancellationToken>,
+    corpus_statistics: Option<Arc<CorpusStatistics>>,
+    epoch: u64,
+    join_maps: Mutex<FnvHashMap<FieldId, Arc<JoinMap>>>,
+}
+
+impl<'a> Drop for RocksDBReader<'a> {
+    fn drop(&mut self) {
+        self.store.reader_epochs.exit(self.epoch);
+    }
 }
 
 impl<'a> RocksDBReader<'a> {
+    /// Aborts any searches run through this reader when the token is cancelled
+    ///
+    /// Cancelled searches return an error.
+    pub fn set_cancellation_token(&mut self, cancellation_token: CancellationToken) {
+        self.cancellation_token = Some(cancellation_token);
+    }
+
+    /// Scores searches run through this reader with statistics from outside of this index
+    ///
+    /// This is used when the index is one of many shards, so that documents are scored as if
+    /// all the shards were a single index. Statistics that aren't given are read from this
+    /// index.
+    pub fn set_corpus_statistics(&mut self, corpus_statistics: Arc<CorpusStatistics>) {
+        self.corpus_statistics = Some(corpus_statistics);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation_token.as_ref().map(|token| token.is_cancelled()).unwrap_or(false)
+    }
+
+    /// The generation of active segments this reader sees
+    ///
+    /// This doesn't change for the lifetime of the reader, readers with the same generation
+    /// number see the same segments.
+    pub fn segment_generation(&self) -> Option<u64> {
+        self.generation.number()
+    }
+
     pub fn schema(&self) -> &Schema {
         &self.store.schema
     }
 
+    /// The tenant the reader is restricted to, if it's restricted to one
+    pub fn tenant(&self) -> Option<&[u8]> {
+        self.tenant.as_ref().map(|tenant| &tenant[..])
+    }
+
+    fn document_key(&self, doc_key: &str) -> DocumentKey {
+        DocumentKey {
+            tenant: self.tenant.clone(),
+            key: doc_key.as_bytes().to_vec(),
+        }
+    }
+
+    /// Readers can't read documents from outside their segments, so neither the store's readers
+    /// nor those of other tenants can read a tenant's documents
+    pub(crate) fn can_read(&self, doc_id: DocId) -> bool {
+        self.generation.segments().contains(&(doc_id.0).0)
+    }
+
     pub fn contains_document_key(&self, doc_key: &str) -> bool {
         // TODO: use snapshot
-        self.store.document_index.contains_document_key(&doc_key.as_bytes().iter().cloned().collect())
+        self.store.document_index.contains_document_key(&self.document_key(doc_key))
     }
 
-    pub fn read_stored_field(&self, field_id: FieldId, doc_id: DocId) -> Result<Option<FieldValue>, StoredFieldReadError> {
-        let field_info = match self.schema().get(&field_id) {
-            Some(field_info) => field_info,
-            None => return Err(StoredFieldReadError::InvalidFieldId(field_id)),
+    /// Finds the id of a document from its key
+    ///
+    /// The key is looked up in the reader's snapshot of the primary key index, so documents
+    /// that were deleted or replaced before the reader was opened aren't found, and nor are
+    /// documents inserted after it.
+    pub fn get_document_id(&self, doc_key: &str) -> Result<Option<DocId>, rocksdb::Error> {
+        let kb = self.document_key(doc_key).key_builder();
+        Ok(try!(self.snapshot.get(&kb.key())).map(|value| {
+            decode_doc_id(&value)
+        }))
+    }
+
+    /// Returns the keys of every live document, in byte order
+    pub(crate) fn document_keys(&self) -> Vec<String> {
+        // The keys of every live document are in the primary key index
+        let prefix = match self.tenant {
+            Some(ref tenant) => KeyBuilder::tenant_primary_key_index_prefix(tenant),
+            None => KeyBuilder::primary_key_index_prefix(),
         };
 
-        let kb = KeyBuilder::stored_field_value((doc_id.0).0, doc_id.1, field_id.0, b"val");
+        KeyIterator::new(self.snapshot.raw_iterator(), prefix)
+            .filter_map(|(key, _)| match key {
+                Key::PrimaryKeyIndex(key) | Key::TenantPrimaryKeyIndex { key, .. } => Some(String::from_utf8_lossy(&key).into_owned()),
+                _ => None,
+            })
+            .collect()
+    }
 
-        match try!(self.snapshot.get(&kb.key())) {
-            Some(value) => {
-                match field_info.field_type {
-                    FieldType::Text | FieldType::PlainString => {
-                        match str::from_utf8(&value) {
-                            Ok(value_str) => {
-                                Ok(Some(FieldValue::String(value_str.to_string())))
-                            }
-                            Err(e) => {
-                                Err(StoredFieldReadError::TextFieldUTF8DecodeError(value.to_vec(), e))
-                            }
-                        }
-                    }
-                    FieldType::I64 => {
-                        if value.len() != 8 {
-                            return Err(StoredFieldReadError::IntegerFieldValueSizeError(value.len()));
-                        }
+    /// Fetches a document by its key
+    ///
+    /// The document has the values of all the fields that are stored and its source and
+    /// routing key (if it was indexed with them), other fields (such as indexed fields) can't be rebuilt from the
+    /// index so they're left empty. The stored fields can be read with typed accessors such as
+    /// "get_str" and "get_i64".
+    pub fn get_document(&self, doc_key: &str) -> Result<Option<StoredDocument>, StoredFieldReadError> {
+        let doc_id = match try!(self.get_document_id(doc_key)) {
+            Some(doc_id) => doc_id,
+            None => return Ok(None),
+        };
 
-                        Ok(Some(FieldValue::Integer(LittleEndian::read_i64(&value))))
-                    }
-                    FieldType::Boolean => {
-                        if value[..] == [b't'] {
-                            Ok(Some(FieldValue::Boolean(true)))
-                        } else if value[..] == [b'f'] {
-                            Ok(Some(FieldValue::Boolean(false)))
-                        } else {
-                            Err(StoredFieldReadError::BooleanFieldDecodeError(value.to_vec()))
-                        }
-                    }
-                    FieldType::DateTime => {
-                        if value.len() != 8 {
-                            return Err(StoredFieldReadError::IntegerFieldValueSizeError(value.len()))
-                        }
+        let mut stored_fields = FnvHashMap::default();
+        for (field_id, field_info) in self.schema().iter() {
+            if !field_info.field_flags.contains(FIELD_STORED) || !has_stored_values(&field_info.field_type) {
+                continue;
+            }
 
-                        let timestamp_with_micros = LittleEndian::read_i64(&value);
-                        let timestamp = timestamp_with_micros / 1000000;
-                        let micros = timestamp_with_micros % 1000000;
-                        let nanos = micros * 1000;
-                        let datetime = NaiveDateTime::from_timestamp(timestamp, nanos as u32);
-                        Ok(Some(FieldValue::DateTime(DateTime::from_utc(datetime, Utc))))
-                    }
-                }
+            if let Some(value) = try!(self.read_stored_field(*field_id, doc_id)) {
+                stored_fields.insert(*field_id, value);
             }
-            None => Ok(None),
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::fs::remove_dir_all;
-    use std::path::Path;
+        Ok(Some(StoredDocument::new(Document {
+            key: doc_key.to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: try!(self.read_source(doc_id)),
+            routing: try!(self.read_routing(doc_id)),
+        })))
+    }
 
-    use rocksdb::DB;
-    use fnv::FnvHashMap;
-    use kite::{Term, Token, Document};
-    use kite::document::FieldValue;
-    use kite::schema::{FieldType, FIELD_INDEXED, FIELD_STORED};
-    use kite::query::Query;
-    use kite::query::term_scorer::TermScorer;
-    use kite::collectors::top_score::TopScoreCollector;
+    /// Fetches many documents by their keys, in the same order as the keys
+    ///
+    /// The keys are resolved first and the documents are then read in id order. RocksDB's
+    /// multi-get isn't exposed by the rocksdb crate, so instead each document's stored values
+    /// are read with a single seek of an iterator that is shared by the whole batch (rather
+    /// than a lookup per field). Documents in segments that have been written to a segment
+    /// file are read from the file.
+    pub fn multi_get(&self, doc_keys: &[&str]) -> Result<Vec<Option<Document>>, StoredFieldReadError> {
+        let mut doc_ids = Vec::new();
+        for (i, doc_key) in doc_keys.iter().enumerate() {
+            if let Some(doc_id) = try!(self.get_document_id(doc_key)) {
+                doc_ids.push((doc_id, i));
+            }
+        }
+        doc_ids.sort_by_key(|&(doc_id, i)| ((doc_id.0).0, doc_id.1, i));
 
-    use super::RocksDBStore;
+        let mut documents = doc_keys.iter().map(|_| None).collect::<Vec<_>>();
+        let mut iter = self.snapshot.raw_iterator();
+        for (doc_id, i) in doc_ids {
+            let values = try!(self.read_stored_values(&mut iter, doc_id, None, true));
 
-    fn remove_dir_all_ignore_error<P: AsRef<Path>>(path: P) {
-        match remove_dir_all(&path) {
-            Ok(_) => {}
-            Err(_) => {}  // Don't care if this fails
+            documents[i] = Some(Document {
+                key: doc_keys[i].to_string(),
+                indexed_fields: FnvHashMap::default(),
+                stored_fields: values.fields,
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: values.source,
+                routing: values.routing,
+            });
         }
+
+        Ok(documents)
     }
 
-    #[test]
-    fn test_create() {
-        remove_dir_all_ignore_error("test_indices/test_create");
+    /// Reads some of the stored fields of many documents (such as the hits of a search), in
+    /// the same order as the documents
+    ///
+    /// Like "multi_get", the documents are read in id order with an iterator that's shared by
+    /// the whole batch rather than with a lookup for each field of each document. Fields that
+    /// a document doesn't have a value for are left out of its values, and documents that this
+    /// reader can't read have none.
+    pub fn fetch_documents(&self, doc_ids: &[DocId], field_ids: &[FieldId]) -> Result<Vec<FnvHashMap<FieldId, FieldValue>>, StoredFieldReadError> {
+        for field_id in field_ids {
+            let field_info = match self.schema().get(field_id) {
+                Some(field_info) => field_info,
+                None => return Err(StoredFieldReadError::InvalidFieldId(*field_id)),
+            };
 
-        let store = RocksDBStore::create("test_indices/test_create");
-        assert!(store.is_ok());
-    }
+            if !has_stored_values(&field_info.field_type) {
+                return Err(StoredFieldReadError::FieldTypeNotStored(field_info.field_type.clone()));
+            }
+        }
+
+        let mut order = (0..doc_ids.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| ((doc_ids[*i].0).0, doc_ids[*i].1));
+
+        let mut documents = doc_ids.iter().map(|_| FnvHashMap::default()).collect::<Vec<_>>();
+        let mut iter = self.snapshot.raw_iterator();
+        for i in order {
+            if !self.can_read(doc_ids[i]) {
+                continue;
+            }
+
+            documents[i] = try!(self.read_stored_values(&mut iter, doc_ids[i], Some(field_ids), false)).fields;
+        }
+
+        Ok(documents)
+    }
+
+    /// Reads the sources of many documents (such as the hits of a search), in the same order
+    /// as the documents
+    ///
+    /// The sources are read in a batch like "fetch_documents" reads stored fields. Documents
+    /// that were indexed without a source, or that this reader can't read, have None.
+    pub fn fetch_sources(&self, doc_ids: &[DocId]) -> Result<Vec<Option<Vec<u8>>>, StoredFieldReadError> {
+        let mut order = (0..doc_ids.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| ((doc_ids[*i].0).0, doc_ids[*i].1));
+
+        let mut sources = doc_ids.iter().map(|_| None).collect::<Vec<_>>();
+        let mut iter = self.snapshot.raw_iterator();
+        for i in order {
+            if !self.can_read(doc_ids[i]) {
+                continue;
+            }
+
+            sources[i] = try!(self.read_stored_values(&mut iter, doc_ids[i], Some(&[]), true)).source;
+        }
+
+        Ok(sources)
+    }
+
+    /// Reads the stored values of a document that's part of a batch
+    ///
+    /// Documents in segments that haven't been written to a segment file are read with a
+    /// single seek of "iter", which is shared by the batch. Only the fields in "field_ids" are
+    /// read (or every stored field if it's None), and the source and routing key are only read
+    /// if "read_metadata" is set.
+    fn read_stored_values(&self, iter: &mut DBRawIterator, doc_id: DocId, field_ids: Option<&[FieldId]>, read_metadata: bool) -> Result<StoredValues, StoredFieldReadError> {
+        let mut values = StoredValues {
+            fields: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        };
+
+        if self.generation.segment_file((doc_id.0).0).is_some() {
+            for (field_id, field_info) in self.schema().iter() {
+                if !field_info.field_flags.contains(FIELD_STORED) || !has_stored_values(&field_info.field_type) {
+                    continue;
+                }
+
+                if field_ids.map(|field_ids| !field_ids.contains(field_id)).unwrap_or(false) {
+                    continue;
+                }
+
+                if let Some(value) = try!(self.read_stored_field(*field_id, doc_id)) {
+                    values.fields.insert(*field_id, value);
+                }
+            }
+
+            if read_metadata {
+                values.source = try!(self.read_source(doc_id));
+                values.routing = try!(self.read_routing(doc_id));
+            }
+        } else {
+            let kb = KeyBuilder::document_stored_values_prefix((doc_id.0).0, doc_id.1);
+            iter.seek(kb.key());
+            while iter.valid() {
+                {
+                    let k = iter.key().unwrap();
+                    if !k.starts_with(kb.key()) {
+                        break;
+                    }
+
+                    // The rest of the key is "{field}/{value type}"
+                    let mut parts = k[kb.key().len()..].splitn(2, |b| *b == b'/');
+                    let field_id = parts.next().and_then(|field| str::from_utf8(field).ok()).and_then(|field| field.parse::<u32>().ok());
+                    let value_type = parts.next();
+
+                    match (field_id.map(FieldId), value_type) {
+                        (Some(field_id), Some(b"val")) => {
+                            if let Some(field_info) = self.schema().get(&field_id) {
+                                let is_requested = field_ids.map(|field_ids| field_ids.contains(&field_id)).unwrap_or(true);
+                                if field_info.field_flags.contains(FIELD_STORED) && is_requested {
+                                    let value = iter.value().unwrap();
+                                    let decrypted = try!(self.decrypt_stored_value((doc_id.0).0, &value));
+                                    let value = try!(decode_stored_field_value(&field_info.field_type, decrypted.as_ref().unwrap_or(&value)));
+                                    values.fields.insert(field_id, value);
+                                }
+                            }
+                        }
+                        (Some(FieldId(0)), Some(b"src")) if read_metadata => {
+                            let value = iter.value().unwrap();
+                            values.source = Some(try!(self.decrypt_stored_value((doc_id.0).0, &value)).unwrap_or_else(|| value.to_vec()));
+                        }
+                        (Some(FieldId(0)), Some(b"rt")) if read_metadata => {
+                            values.routing = String::from_utf8(iter.value().unwrap().to_vec()).ok();
+                        }
+                        _ => {}
+                    }
+                }
+
+                iter.next();
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Reads the source the document was indexed with
+    ///
+    /// Returns None if the document was indexed without one. The source isn't compressed
+    /// separately, RocksDB compresses it along with the rest of the block it's written into.
+    pub fn read_source(&self, doc_id: DocId) -> Result<Option<Vec<u8>>, StoredFieldReadError> {
+        if !self.can_read(doc_id) {
+            return Ok(None);
+        }
+
+        let kb = KeyBuilder::stored_source((doc_id.0).0, doc_id.1);
+
+        let source = match self.generation.segment_file((doc_id.0).0) {
+            Some(segment_file) => segment_file.get(kb.key()).map(|value| value.to_vec()),
+            None => try!(self.snapshot.get(&kb.key())).map(|value| value.to_vec()),
+        };
+
+        match source {
+            Some(source) => Ok(Some(try!(self.decrypt_stored_value((doc_id.0).0, &source)).unwrap_or(source))),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the routing key the document was indexed with
+    ///
+    /// Returns None if the document was routed by its key.
+    pub fn read_routing(&self, doc_id: DocId) -> Result<Option<String>, rocksdb::Error> {
+        if !self.can_read(doc_id) {
+            return Ok(None);
+        }
+
+        let kb = KeyBuilder::stored_routing((doc_id.0).0, doc_id.1);
+
+        let routing = match self.generation.segment_file((doc_id.0).0) {
+            Some(segment_file) => segment_file.get(kb.key()).map(|value| value.to_vec()),
+            None => try!(self.snapshot.get(&kb.key())).map(|value| value.to_vec()),
+        };
+
+        Ok(routing.and_then(|routing| String::from_utf8(routing).ok()))
+    }
+
+    /// Reads the value of a stored field
+    ///
+    /// Returns None if the document doesn't have a value for the field (or the field isn't
+    /// stored). Rank feature and completion fields are kept elsewhere so reading them is an
+    /// error, as is a field id that isn't in the schema.
+    pub fn read_stored_field(&self, field_id: FieldId, doc_id: DocId) -> Result<Option<FieldValue>, StoredFieldReadError> {
+        match try!(self.read_stored_field_bytes(field_id, doc_id)) {
+            Some(value) => value.decode().map(|value| Some(value.to_field_value())),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a stored field value without copying it
+    ///
+    /// The value stays in the buffer RocksDB (or the segment file) read it into, unless it
+    /// had to be decrypted. Call "decode" on the result to get a StoredFieldRef that borrows
+    /// from it.
+    pub fn read_stored_field_bytes(&self, field_id: FieldId, doc_id: DocId) -> Result<Option<StoredFieldBytes>, StoredFieldReadError> {
+        let field_info = match self.schema().get(&field_id) {
+            Some(field_info) => field_info,
+            None => return Err(StoredFieldReadError::InvalidFieldId(field_id)),
+        };
+
+        if !has_stored_values(&field_info.field_type) {
+            return Err(StoredFieldReadError::FieldTypeNotStored(field_info.field_type.clone()));
+        }
+
+        if !self.can_read(doc_id) {
+            return Ok(None);
+        }
+
+        let kb = KeyBuilder::stored_field_value((doc_id.0).0, doc_id.1, field_id.0, b"val");
+
+        let value = match self.generation.segment_file((doc_id.0).0) {
+            Some(segment_file) => SegmentFile::get_shared(&segment_file, kb.key()).map(|value| {
+                StoredFieldBytes::from_segment_file(field_info.field_type.clone(), value)
+            }),
+            None => try!(self.snapshot.get(&kb.key())).map(|value| {
+                StoredFieldBytes::from_rocksdb(field_info.field_type.clone(), value)
+            }),
+        };
+
+        match value {
+            Some(value) => match try!(self.decrypt_stored_value((doc_id.0).0, &value)) {
+                Some(decrypted) => Ok(Some(StoredFieldBytes::from_decrypted(field_info.field_type.clone(), decrypted))),
+                None => Ok(Some(value)),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
+
+    use rocksdb::{DB, WriteBatch};
+    use fnv::FnvHashMap;
+    use byteorder::{ByteOrder, LittleEndian};
+    use kite::{Term, Token, Document, DocId, MultiTermSelector};
+    use kite::document::FieldValue;
+    use kite::segment::{SegmentId, Segment};
+    use kite::schema::{FieldId, FieldType, FieldFlags, FIELD_INDEXED, FIELD_STORED};
+    use kite::query::Query;
+    use kite::query::term_scorer::TermScorer;
+    use kite::query::field_value_factor::{FieldValueFactor, FieldValueModifier};
+    use kite::query::rank_feature::RankFeatureFunction;
+    use kite::query::rescore::Rescore;
+    use kite::collectors::{Collector, DocumentMatch, TrackTotalHits, TotalHits};
+    use kite::collectors::top_score::TopScoreCollector;
+    use kite::collectors::top_field::{TopFieldCollector, SortOrder};
+    use kite::collectors::total_count::TotalCountCollector;
+    use kite::collectors::dedup::DedupCollector;
+    use kite::cancellation::CancellationToken;
+    use kite::error::SearchError;
+    use kite::metrics::{self, Metrics};
+    use kite::distributed::CorpusStatistics;
+
+    use super::{RocksDBStore, RocksDBReader, SearchExecutorConfig, StoredFieldRef, Suggestion, PopularTerm, ReindexConfig, ReindexProgress, FieldStatistics, Change, ChangeKind, IndexListener, SnapshotRepository, FsSnapshotRepository, IndexWriter, IndexWriterError, IndexReader, StoreOpenError, StoredFieldReadError, BulkConfig, ExportConfig, ArrowExportConfig, ExportError};
+    use segment_ops::SegmentMergeError;
+    use key_builder::{KeyBuilder, Key, KeyIterator};
+    use integer_terms::SORTABLE_INTEGER_TERMS;
+
+    fn remove_dir_all_ignore_error<P: AsRef<Path>>(path: P) {
+        match remove_dir_all(&path) {
+            Ok(_) => {}
+            Err(_) => {}  // Don't care if this fails
+        }
+    }
+
+    #[test]
+    fn test_create() {
+        remove_dir_all_ignore_error("test_indices/test_create");
+
+        let store = RocksDBStore::create("test_indices/test_create");
+        assert!(store.is_ok());
+    }
 
     #[test]
     fn test_open() {
@@ -438,7 +1452,18 @@ mod tests {
 
         // Check that it fails to open a DB which doesn't exist
         let store = RocksDBStore::open("test_indices/test_open");
-        assert!(store.is_err());
+        match store {
+            Err(StoreOpenError::RocksDBError(_)) => {}
+            _ => panic!("opened a DB which doesn't exist"),
+        }
+
+        // Or a DB which isn't an index
+        DB::open_default("test_indices/test_open").unwrap();
+        match RocksDBStore::open("test_indices/test_open") {
+            Err(StoreOpenError::SchemaMissing) => {}
+            _ => panic!("opened a DB without a schema"),
+        }
+        remove_dir_all_ignore_error("test_indices/test_open");
 
         // Create the DB
         RocksDBStore::create("test_indices/test_open").expect("failed to create test DB");
@@ -453,22 +1478,23 @@ mod tests {
         let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
         let body_field = store.add_field("body".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
         let pk_field = store.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+        let popularity_field = store.add_field("popularity".to_string(), FieldType::RankFeature, FieldFlags::empty()).unwrap();
 
 
         let mut indexed_fields = FnvHashMap::default();
         indexed_fields.insert(
             title_field,
             vec![
-                Token { term: Term::from_string("hello"), position: 1 },
-                Token { term: Term::from_string("world"), position: 2 },
+                Token { term: Term::from_string("hello"), position: 1, offsets: None },
+                Token { term: Term::from_string("world"), position: 2, offsets: None },
             ].into()
         );
         indexed_fields.insert(
             body_field,
             vec![
-                Token { term: Term::from_string("lorem"), position: 1 },
-                Token { term: Term::from_string("ipsum"), position: 2 },
-                Token { term: Term::from_string("dolar"), position: 3 },
+                Token { term: Term::from_string("lorem"), position: 1, offsets: None },
+                Token { term: Term::from_string("ipsum"), position: 2, offsets: None },
+                Token { term: Term::from_string("dolar"), position: 3, offsets: None },
             ].into()
         );
 
@@ -478,26 +1504,33 @@ mod tests {
             FieldValue::Integer(1)
         );
 
+        let mut rank_features = FnvHashMap::default();
+        rank_features.insert(popularity_field, 10.0f32);
+
         store.insert_or_update_document(&Document {
             key: "test_doc".to_string(),
             indexed_fields: indexed_fields,
             stored_fields: stored_fields,
+            rank_features: rank_features,
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
         }).unwrap();
 
         let mut indexed_fields = FnvHashMap::default();
         indexed_fields.insert(
             title_field,
             vec![
-                Token { term: Term::from_string("howdy"), position: 1 },
-                Token { term: Term::from_string("partner"), position: 2 },
+                Token { term: Term::from_string("howdy"), position: 1, offsets: None },
+                Token { term: Term::from_string("partner"), position: 2, offsets: None },
             ].into()
         );
         indexed_fields.insert(
             body_field,
             vec![
-                Token { term: Term::from_string("lorem"), position: 1 },
-                Token { term: Term::from_string("ipsum"), position: 2 },
-                Token { term: Term::from_string("dolar"), position: 3 },
+                Token { term: Term::from_string("lorem"), position: 1, offsets: None },
+                Token { term: Term::from_string("ipsum"), position: 2, offsets: None },
+                Token { term: Term::from_string("dolar"), position: 3, offsets: None },
             ].into()
         );
 
@@ -507,10 +1540,17 @@ mod tests {
             FieldValue::Integer(2)
         );
 
+        let mut rank_features = FnvHashMap::default();
+        rank_features.insert(popularity_field, 30.0f32);
+
         store.insert_or_update_document(&Document {
             key: "another_test_doc".to_string(),
             indexed_fields: indexed_fields,
             stored_fields: stored_fields,
+            rank_features: rank_features,
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
         }).unwrap();
 
         store.merge_segments(&vec![1, 2]).unwrap();
@@ -586,4 +1626,4084 @@ mod tests {
         let docs = collector.into_sorted_vec();
         println!("{:?}", docs);
     }
+
+    #[test]
+    fn test_custom_score() {
+        remove_dir_all_ignore_error("test_indices/test_custom_score");
+
+        make_test_store("test_indices/test_custom_score");
+
+        let store = RocksDBStore::open("test_indices/test_custom_score").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+
+        let index_reader = store.reader();
+
+        // Score each document by its "pk" field
+        let query = Query::term(body_field, Term::from_string("lorem")).custom_score(move |_score, doc_values| {
+            doc_values.get_i64(pk_field).unwrap_or(0) as f32
+        });
+
+        let mut collector = TopScoreCollector::new(10);
+        index_reader.search(&mut collector, &query).unwrap();
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].score(), Some(2.0f32));
+        assert_eq!(docs[1].score(), Some(1.0f32));
+    }
+
+    #[test]
+    fn test_constant_score() {
+        remove_dir_all_ignore_error("test_indices/test_constant_score");
+
+        make_test_store("test_indices/test_constant_score");
+
+        let store = RocksDBStore::open("test_indices/test_constant_score").unwrap();
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let index_reader = store.reader();
+
+        let query = Query::ConstantScore {
+            query: Box::new(Query::Disjunction {
+                queries: vec![
+                    Query::term(title_field, Term::from_string("hello")),
+                    Query::term(body_field, Term::from_string("lorem")),
+                ],
+            }),
+            score: 3.0f32,
+        };
+
+        let mut collector = TopScoreCollector::new(10);
+        index_reader.search(&mut collector, &query).unwrap();
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].score(), Some(3.0f32));
+        assert_eq!(docs[1].score(), Some(3.0f32));
+    }
+
+    #[test]
+    fn test_unscored_search() {
+        remove_dir_all_ignore_error("test_indices/test_unscored_search");
+
+        make_test_store("test_indices/test_unscored_search");
+
+        let store = RocksDBStore::open("test_indices/test_unscored_search").unwrap();
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let index_reader = store.reader();
+
+        let query = Query::term(body_field, Term::from_string("lorem"))
+            .filter(Query::term(title_field, Term::from_string("howdy")));
+
+        let mut collector = TotalCountCollector::new();
+        index_reader.search(&mut collector, &query).unwrap();
+
+        assert_eq!(collector.get_total_count(), 1);
+    }
+
+    #[test]
+    fn test_field_value_factor() {
+        remove_dir_all_ignore_error("test_indices/test_field_value_factor");
+
+        make_test_store("test_indices/test_field_value_factor");
+
+        let store = RocksDBStore::open("test_indices/test_field_value_factor").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+
+        let index_reader = store.reader();
+
+        // Score is multiplied by the square of the "pk" field
+        let mut factor = FieldValueFactor::new(pk_field);
+        factor.modifier = FieldValueModifier::Square;
+        let query = Query::ConstantScore {
+            query: Box::new(Query::term(body_field, Term::from_string("lorem"))),
+            score: 2.0f32,
+        }.field_value_factor(factor);
+
+        let mut collector = TopScoreCollector::new(10);
+        index_reader.search(&mut collector, &query).unwrap();
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].score(), Some(8.0f32));
+        assert_eq!(docs[1].score(), Some(2.0f32));
+    }
+
+    #[test]
+    fn test_rank_feature() {
+        remove_dir_all_ignore_error("test_indices/test_rank_feature");
+
+        make_test_store("test_indices/test_rank_feature");
+
+        let store = RocksDBStore::open("test_indices/test_rank_feature").unwrap();
+        let popularity_field = store.schema.get_field_by_name("popularity").unwrap();
+
+        let index_reader = store.reader();
+
+        let query = Query::rank_feature(popularity_field, RankFeatureFunction::Saturation { pivot: 10.0 });
+
+        let mut collector = TopScoreCollector::new(10);
+        index_reader.search(&mut collector, &query).unwrap();
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].score(), Some(0.75f32));
+        assert_eq!(docs[1].score(), Some(0.5f32));
+    }
+
+    #[test]
+    fn test_rescore() {
+        remove_dir_all_ignore_error("test_indices/test_rescore");
+
+        make_test_store("test_indices/test_rescore");
+
+        let store = RocksDBStore::open("test_indices/test_rescore").unwrap();
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let index_reader = store.reader();
+
+        // Both documents match the original query with the same score, the rescore query
+        // should push "howdy partner" to the top
+        let query = Query::ConstantScore {
+            query: Box::new(Query::term(body_field, Term::from_string("lorem"))),
+            score: 1.0f32,
+        };
+        let rescore = Rescore::new(Query::ConstantScore {
+            query: Box::new(Query::term(title_field, Term::from_string("howdy"))),
+            score: 5.0f32,
+        }, 10);
+
+        let mut collector = TopScoreCollector::new(10);
+        index_reader.search_with_rescore(&mut collector, &query, &rescore).unwrap();
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].score(), Some(6.0f32));
+        assert_eq!(docs[1].score(), Some(1.0f32));
+    }
+
+    #[test]
+    fn test_search_iter() {
+        remove_dir_all_ignore_error("test_indices/test_search_iter");
+
+        make_test_store("test_indices/test_search_iter");
+
+        let store = RocksDBStore::open("test_indices/test_search_iter").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let index_reader = store.reader();
+
+        let query = Query::term(body_field, Term::from_string("lorem"));
+
+        let docs = index_reader.search_iter(&query, true).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(docs.len(), 2);
+        assert!(docs.iter().all(|doc| doc.score().is_some()));
+
+        // Matches should be yielded without scores when they aren't needed
+        let mut matches = index_reader.search_iter(&query, false).unwrap();
+        assert_eq!(matches.next().unwrap().unwrap().score(), None);
+        assert!(matches.next().is_some());
+        assert!(matches.next().is_none());
+    }
+
+    #[test]
+    fn test_search_with_timeout() {
+        remove_dir_all_ignore_error("test_indices/test_search_with_timeout");
+
+        make_test_store("test_indices/test_search_with_timeout");
+
+        let store = RocksDBStore::open("test_indices/test_search_with_timeout").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let index_reader = store.reader();
+
+        let query = Query::term(body_field, Term::from_string("lorem"));
+
+        let mut collector = TotalCountCollector::new();
+        let result = index_reader.search_with_timeout(&mut collector, &query, Duration::from_secs(3600)).unwrap();
+        assert!(!result.timed_out);
+        assert_eq!(collector.get_total_count(), 2);
+
+        // Deadline passes before the first segment is searched
+        let mut collector = TotalCountCollector::new();
+        let result = index_reader.search_with_timeout(&mut collector, &query, Duration::from_secs(0)).unwrap();
+        assert!(result.timed_out);
+        assert_eq!(collector.get_total_count(), 0);
+    }
+
+    #[test]
+    fn test_cancelled_search() {
+        remove_dir_all_ignore_error("test_indices/test_cancelled_search");
+
+        make_test_store("test_indices/test_cancelled_search");
+
+        let store = RocksDBStore::open("test_indices/test_cancelled_search").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let token = CancellationToken::new();
+        let mut index_reader = store.reader();
+        index_reader.set_cancellation_token(token.clone());
+
+        let query = Query::term(body_field, Term::from_string("lorem"));
+
+        let mut collector = TotalCountCollector::new();
+        index_reader.search(&mut collector, &query).unwrap();
+        assert_eq!(collector.get_total_count(), 2);
+
+        token.cancel();
+
+        let mut collector = TotalCountCollector::new();
+        match index_reader.search(&mut collector, &query) {
+            Err(SearchError::Cancelled) => {}
+            result => panic!("expected search to be cancelled, got {:?}", result),
+        }
+
+        let mut matches = index_reader.search_iter(&query, true).unwrap();
+        match matches.next() {
+            Some(Err(SearchError::Cancelled)) => {}
+            result => panic!("expected search to be cancelled, got {:?}", result),
+        }
+        assert!(matches.next().is_none());
+    }
+
+    #[test]
+    fn test_cancelled_merge() {
+        remove_dir_all_ignore_error("test_indices/test_cancelled_merge");
+
+        let store = make_test_store("test_indices/test_cancelled_merge");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(
+            title_field,
+            vec![
+                Token { term: Term::from_string("hello"), position: 1, offsets: None },
+            ].into()
+        );
+
+        store.insert_or_update_document(&Document {
+            key: "third_test_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+
+        let token = CancellationToken::new();
+        token.cancel();
+
+        match store.merge_segments_cancellable(&vec![3, 4], &token) {
+            Err(SegmentMergeError::Cancelled) => {}
+            result => panic!("expected merge to be cancelled, got {:?}", result),
+        }
+
+        // The source segments should be untouched
+        let query = Query::term(title_field, Term::from_string("hello"));
+        let mut collector = TotalCountCollector::new();
+        store.reader().search(&mut collector, &query).unwrap();
+        assert_eq!(collector.get_total_count(), 2);
+    }
+
+    #[test]
+    fn test_spawn_search() {
+        remove_dir_all_ignore_error("test_indices/test_spawn_search");
+
+        let mut store = make_test_store("test_indices/test_spawn_search");
+        store.set_search_executor(SearchExecutorConfig::default());
+        let store = Arc::new(store);
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let handles = (0..8).map(|_| {
+            RocksDBStore::spawn_search(&store, move |reader| {
+                let query = Query::term(body_field, Term::from_string("lorem"));
+                let mut collector = TotalCountCollector::new();
+                reader.search(&mut collector, &query).map(|_| collector.get_total_count())
+            }).unwrap()
+        }).collect::<Vec<_>>();
+
+        for handle in handles {
+            assert_eq!(handle.wait().unwrap().unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn test_slow_query_log() {
+        remove_dir_all_ignore_error("test_indices/test_slow_query_log");
+
+        let mut store = make_test_store("test_indices/test_slow_query_log");
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let slow_queries = Arc::new(Mutex::new(Vec::new()));
+        {
+            let slow_queries = slow_queries.clone();
+            store.set_slow_query_log(Duration::from_secs(0), move |slow_query| {
+                slow_queries.lock().unwrap().push(slow_query.clone());
+            });
+        }
+
+        let query = Query::term(body_field, Term::from_string("lorem"));
+        let mut collector = TotalCountCollector::new();
+        store.reader().search(&mut collector, &query).unwrap();
+
+        let slow_queries = slow_queries.lock().unwrap();
+        assert_eq!(slow_queries.len(), 1);
+        assert_eq!(slow_queries[0].query, format!("{:?}", query));
+        assert_eq!(slow_queries[0].hits, 2);
+        assert!(!slow_queries[0].timed_out);
+        assert_eq!(slow_queries[0].total_time(), slow_queries[0].planning_time + slow_queries[0].execution_time);
+    }
+
+    #[derive(Default)]
+    struct TestMetrics {
+        counters: Mutex<FnvHashMap<&'static str, u64>>,
+        histograms: Mutex<FnvHashMap<&'static str, Vec<f64>>>,
+    }
+
+    impl Metrics for TestMetrics {
+        fn increment_counter(&self, name: &'static str, value: u64) {
+            *self.counters.lock().unwrap().entry(name).or_insert(0) += value;
+        }
+
+        fn record_histogram(&self, name: &'static str, value: f64) {
+            self.histograms.lock().unwrap().entry(name).or_insert_with(Vec::new).push(value);
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        remove_dir_all_ignore_error("test_indices/test_metrics");
+
+        make_test_store("test_indices/test_metrics");
+
+        let test_metrics = Arc::new(TestMetrics::default());
+        let mut store = RocksDBStore::open("test_indices/test_metrics").unwrap();
+        store.set_metrics(test_metrics.clone());
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(
+            title_field,
+            vec![
+                Token { term: Term::from_string("hello"), position: 1, offsets: None },
+            ].into()
+        );
+
+        store.insert_or_update_document(&Document {
+            key: "third_test_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+
+        store.merge_segments(&vec![3, 4]).unwrap();
+
+        let query = Query::term(title_field, Term::from_string("hello"));
+        let mut collector = TotalCountCollector::new();
+        store.reader().search(&mut collector, &query).unwrap();
+
+        let counters = test_metrics.counters.lock().unwrap();
+        assert_eq!(counters.get(metrics::DOCS_INDEXED), Some(&1));
+        assert_eq!(counters.get(metrics::SEGMENTS_FLUSHED), Some(&1));
+        assert_eq!(counters.get(metrics::SEGMENTS_MERGED), Some(&2));
+        assert_eq!(counters.get(metrics::SEARCHES), Some(&1));
+        assert_eq!(counters.get(metrics::SEARCH_TIMEOUTS), None);
+
+        let histograms = test_metrics.histograms.lock().unwrap();
+        assert_eq!(histograms.get(metrics::SEARCH_HITS), Some(&vec![2.0]));
+        assert_eq!(histograms.get(metrics::SEARCH_LATENCY_SECONDS).map(|values| values.len()), Some(1));
+        assert_eq!(histograms.get(metrics::MERGE_DURATION_SECONDS).map(|values| values.len()), Some(1));
+    }
+
+    #[test]
+    fn test_read_stored_field_bytes() {
+        remove_dir_all_ignore_error("test_indices/test_read_stored_field_bytes");
+
+        let store = make_test_store("test_indices/test_read_stored_field_bytes");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+        let index_reader = store.reader();
+
+        let value = index_reader.read_stored_field_bytes(pk_field, DocId(SegmentId(3), 0)).ok().and_then(|value| value).unwrap();
+        assert_eq!(value.as_bytes().len(), 8);
+        assert_eq!(*value.field_type(), FieldType::I64);
+        match value.decode() {
+            Ok(StoredFieldRef::Integer(1)) | Ok(StoredFieldRef::Integer(2)) => {}
+            _ => panic!("expected an integer"),
+        }
+
+        assert!(index_reader.read_stored_field_bytes(pk_field, DocId(SegmentId(3), 10)).ok().unwrap().is_none());
+        assert!(index_reader.read_stored_field_bytes(title_field, DocId(SegmentId(3), 0)).ok().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_segment_files() {
+        remove_dir_all_ignore_error("test_indices/test_segment_files");
+
+        make_test_store("test_indices/test_segment_files");
+
+        let mut store = RocksDBStore::open("test_indices/test_segment_files").unwrap();
+        store.set_write_segment_files(true);
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+
+        let insert_hello_doc = |store: &RocksDBStore, key: &str| {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(
+                title_field,
+                vec![
+                    Token { term: Term::from_string("hello"), position: 1, offsets: None },
+                ].into()
+            );
+
+            store.insert_or_update_document(&Document {
+                key: key.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        };
+
+        let count_hello_docs = |store: &RocksDBStore| {
+            let query = Query::term(title_field, Term::from_string("hello"));
+            let mut collector = TotalCountCollector::new();
+            store.reader().search(&mut collector, &query).unwrap();
+            collector.get_total_count()
+        };
+
+        insert_hello_doc(&store, "third_test_doc");
+        store.merge_segments(&vec![3, 4]).unwrap();
+        store.purge_segments(&vec![3, 4]).unwrap();
+
+        // The merged segment's postings and stored values should be in the file
+        assert!(Path::new("test_indices/test_segment_files/segments/5.seg").exists());
+        assert!(store.db.get(&KeyBuilder::stored_field_value(5, 0, pk_field.0, b"val").key()).unwrap().is_none());
+        assert_eq!(count_hello_docs(&store), 2);
+        match store.reader().read_stored_field(pk_field, DocId(SegmentId(5), 0)) {
+            Ok(Some(FieldValue::Integer(1))) => {}
+            _ => panic!("expected stored field to be read from the segment file"),
+        }
+        let index_reader = store.reader();
+        let value = index_reader.read_stored_field_bytes(pk_field, DocId(SegmentId(5), 0)).ok().and_then(|value| value).unwrap();
+        assert_eq!(value.decode().ok(), Some(StoredFieldRef::Integer(1)));
+        drop(index_reader);
+
+        // Merging a segment that's in a file
+        insert_hello_doc(&store, "fourth_test_doc");
+        store.merge_segments(&vec![5, 6]).unwrap();
+        store.purge_segments(&vec![5, 6]).unwrap();
+        assert!(!Path::new("test_indices/test_segment_files/segments/5.seg").exists());
+        assert_eq!(count_hello_docs(&store), 3);
+
+        // Segment files should be opened with the store
+        drop(store);
+        let store = RocksDBStore::open("test_indices/test_segment_files").unwrap();
+        assert_eq!(count_hello_docs(&store), 3);
+    }
+
+    #[test]
+    fn test_count() {
+        remove_dir_all_ignore_error("test_indices/test_count");
+
+        let store = make_test_store("test_indices/test_count");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let index_reader = store.reader();
+
+        assert_eq!(index_reader.count(&Query::term(body_field, Term::from_string("lorem"))).unwrap(), 2);
+        assert_eq!(index_reader.count(&Query::term(title_field, Term::from_string("hello"))).unwrap(), 1);
+        assert_eq!(index_reader.count(&Query::term(title_field, Term::from_string("missing"))).unwrap(), 0);
+        assert_eq!(index_reader.count(&Query::all()).unwrap(), 2);
+        assert_eq!(index_reader.count(&Query::Exclude {
+            query: Box::new(Query::all()),
+            exclude: Box::new(Query::term(title_field, Term::from_string("hello"))),
+        }).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sort_by_doc_values() {
+        remove_dir_all_ignore_error("test_indices/test_sort_by_doc_values");
+
+        let store = make_test_store("test_indices/test_sort_by_doc_values");
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+
+        // Remove the stored values to check that sorting doesn't need them
+        for doc_id in 0..2 {
+            store.db.delete(&KeyBuilder::stored_field_value(3, doc_id, pk_field.0, b"val").key()).unwrap();
+        }
+
+        let index_reader = store.reader();
+        let query = Query::term(body_field, Term::from_string("lorem"));
+
+        let mut collector = TopFieldCollector::new(pk_field, SortOrder::Descending, 10);
+        index_reader.search(&mut collector, &query).unwrap();
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.sort_value()).collect::<Vec<_>>(), vec![Some(2), Some(1)]);
+
+        let mut collector = TopFieldCollector::new(pk_field, SortOrder::Ascending, 1);
+        index_reader.search(&mut collector, &query).unwrap();
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.sort_value()).collect::<Vec<_>>(), vec![Some(1)]);
+    }
+
+    #[test]
+    fn test_dedup_collector() {
+        remove_dir_all_ignore_error("test_indices/test_dedup_collector");
+
+        let store = make_test_store("test_indices/test_dedup_collector");
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+
+        // A copy of "test_doc" with the same pk
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(body_field, vec![Token { term: Term::from_string("lorem"), position: 1, offsets: None }].into());
+        let mut stored_fields = FnvHashMap::default();
+        stored_fields.insert(pk_field, FieldValue::Integer(1));
+        store.insert_or_update_document(&Document {
+            key: "copy_of_test_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+
+        let index_reader = store.reader();
+        let mut collector = DedupCollector::new(pk_field, 10);
+        index_reader.search(&mut collector, &Query::term(body_field, Term::from_string("lorem"))).unwrap();
+
+        assert_eq!(collector.duplicates(), 1);
+        let docs = collector.into_sorted_vec();
+        let mut values = docs.iter().map(|doc| doc.sort_value()).collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(values, vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn test_metadata() {
+        remove_dir_all_ignore_error("test_indices/test_metadata");
+
+        let store = make_test_store("test_indices/test_metadata");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+
+        // Not visible until the next commit
+        store.set_metadata("checkpoint", b"1");
+        assert_eq!(store.get_metadata("checkpoint").unwrap(), None);
+
+        let index_reader = store.reader();
+
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+        store.insert_or_update_document(&Document {
+            key: "new_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+
+        assert_eq!(store.get_metadata("checkpoint").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.reader().get_metadata("checkpoint").unwrap(), Some(b"1".to_vec()));
+
+        // Readers see the metadata that was committed when they were opened
+        assert_eq!(index_reader.get_metadata("checkpoint").unwrap(), None);
+        drop(index_reader);
+
+        store.remove_metadata("checkpoint");
+        store.set_metadata("schema_version", b"2");
+        store.commit_metadata().unwrap();
+        assert_eq!(store.get_metadata("checkpoint").unwrap(), None);
+        assert_eq!(store.get_metadata("schema_version").unwrap(), Some(b"2".to_vec()));
+        drop(store);
+
+        let store = RocksDBStore::open("test_indices/test_metadata").unwrap();
+        assert_eq!(store.get_metadata("schema_version").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_infix_queries() {
+        use kite::schema::FIELD_INFIX;
+
+        remove_dir_all_ignore_error("test_indices/test_infix_queries");
+
+        let mut store = make_test_store("test_indices/test_infix_queries");
+        let sku_field = store.add_field("sku".to_string(), FieldType::PlainString, FIELD_INDEXED | FIELD_INFIX).unwrap();
+
+        for (key, sku) in vec![("widget", "ab-1234-xy"), ("gadget", "cd-5678-xy")] {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(sku_field, vec![Token { term: Term::from_string(sku), position: 1, offsets: None }].into());
+            store.insert_or_update_document(&Document {
+                key: key.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        }
+
+        let check = |store: &RocksDBStore| {
+            let index_reader = store.reader();
+            assert_eq!(index_reader.count(&Query::infix(sku_field, "1234")).unwrap(), 1);
+            assert_eq!(index_reader.count(&Query::infix(sku_field, "-xy")).unwrap(), 2);
+            assert_eq!(index_reader.count(&Query::infix(sku_field, "x")).unwrap(), 2);
+            assert_eq!(index_reader.count(&Query::infix(sku_field, "4321")).unwrap(), 0);
+            assert_eq!(index_reader.count(&Query::infix(sku_field, "")).unwrap(), 0);
+
+            // The n-grams aren't searchable as terms of the field itself
+            assert_eq!(index_reader.count(&Query::term(sku_field, Term::from_string("1234"))).unwrap(), 0);
+        };
+        check(&store);
+
+        // The n-grams are kept when segments are merged
+        store.merge_segments(&vec![3, 4, 5]).unwrap();
+        check(&store);
+    }
+
+    #[test]
+    fn test_join_queries() {
+        remove_dir_all_ignore_error("test_indices/test_join_queries");
+
+        let mut store = make_test_store("test_indices/test_join_queries");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let parent_field = store.add_field("parent".to_string(), FieldType::Join, FIELD_INDEXED).unwrap();
+
+        let insert_child = |store: &RocksDBStore, key: &str, parent: &str, body: &str| {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(parent_field, vec![Token { term: Term::from_string(parent), position: 1, offsets: None }].into());
+            indexed_fields.insert(body_field, vec![Token { term: Term::from_string(body), position: 1, offsets: None }].into());
+
+            store.insert_or_update_document(&Document {
+                key: key.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        };
+
+        // "test_doc" and "another_test_doc" are the parents
+        insert_child(&store, "red_child", "test_doc", "red");
+        insert_child(&store, "blue_child", "test_doc", "blue");
+        insert_child(&store, "another_blue_child", "another_test_doc", "blue");
+        insert_child(&store, "orphan", "missing_doc", "red");
+
+        let index_reader = store.reader();
+        assert_eq!(index_reader.count(&Query::has_child(parent_field, Query::term(body_field, Term::from_string("red")))).unwrap(), 1);
+        assert_eq!(index_reader.count(&Query::has_child(parent_field, Query::term(body_field, Term::from_string("blue")))).unwrap(), 2);
+        assert_eq!(index_reader.count(&Query::has_parent(parent_field, Query::term(title_field, Term::from_string("hello")))).unwrap(), 2);
+        assert_eq!(index_reader.count(&Query::has_parent(parent_field, Query::term(title_field, Term::from_string("howdy")))).unwrap(), 1);
+
+        let mut collector = TopScoreCollector::new(10);
+        index_reader.search(&mut collector, &Query::has_child(parent_field, Query::term(body_field, Term::from_string("red")))).unwrap();
+        let docs = collector.into_sorted_vec();
+        assert_eq!(index_reader.get_document_id("test_doc").unwrap().map(|doc_id| doc_id.as_u64()), Some(docs[0].doc_id()));
+        assert_eq!(docs[0].score(), Some(1.0f32));
+        drop(index_reader);
+
+        // Children can be updated and deleted without touching the parent
+        insert_child(&store, "red_child", "another_test_doc", "red");
+        store.remove_document_by_key("blue_child").unwrap();
+
+        let index_reader = store.reader();
+        assert_eq!(index_reader.count(&Query::has_parent(parent_field, Query::term(title_field, Term::from_string("hello")))).unwrap(), 0);
+        assert_eq!(index_reader.count(&Query::has_parent(parent_field, Query::term(title_field, Term::from_string("howdy")))).unwrap(), 2);
+        assert_eq!(index_reader.count(&Query::has_child(parent_field, Query::term(body_field, Term::from_string("blue")))).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_search_during_merge() {
+        use std::thread;
+        use std::sync::Barrier;
+
+        remove_dir_all_ignore_error("test_indices/test_search_during_merge");
+
+        make_test_store("test_indices/test_search_during_merge");
+
+        let mut store = RocksDBStore::open("test_indices/test_search_during_merge").unwrap();
+        store.set_write_segment_files(true);
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+
+        let insert_hello_doc = |store: &RocksDBStore, key: &str| {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+
+            store.insert_or_update_document(&Document {
+                key: key.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        };
+
+        let count_hello_docs = move |reader: &RocksDBReader| {
+            let query = Query::term(title_field, Term::from_string("hello"));
+            let mut collector = TotalCountCollector::new();
+            reader.search(&mut collector, &query).unwrap();
+            collector.get_total_count()
+        };
+
+        // Put the documents in a segment file, these are the easiest to break by purging
+        // them too early
+        insert_hello_doc(&store, "third_test_doc");
+        store.merge_segments(&vec![3, 4]).unwrap();
+        store.purge_segments(&vec![3, 4]).unwrap();
+        assert!(Path::new("test_indices/test_search_during_merge/segments/5.seg").exists());
+
+        let store = Arc::new(store);
+        let reader_opened = Arc::new(Barrier::new(2));
+        let merged = Arc::new(Barrier::new(2));
+
+        // Open a reader then search it once segment 5 has been merged away and purged
+        let search_thread = {
+            let store = store.clone();
+            let reader_opened = reader_opened.clone();
+            let merged = merged.clone();
+
+            thread::spawn(move || {
+                let reader = store.reader();
+                reader_opened.wait();
+                merged.wait();
+                count_hello_docs(&reader)
+            })
+        };
+
+        reader_opened.wait();
+        insert_hello_doc(&store, "fourth_test_doc");
+        store.merge_segments(&vec![5, 6]).unwrap();
+        store.purge_segments(&vec![5, 6]).unwrap();
+
+        // The reader can still see segment 5 so it mustn't be purged yet
+        assert!(Path::new("test_indices/test_search_during_merge/segments/5.seg").exists());
+        merged.wait();
+        assert_eq!(search_thread.join().unwrap(), 2);
+
+        // Now the reader has gone, the segments can be purged
+        store.purge_released_segments().unwrap();
+        assert!(!Path::new("test_indices/test_search_during_merge/segments/5.seg").exists());
+        assert_eq!(count_hello_docs(&store.reader()), 3);
+    }
+
+    #[test]
+    fn test_reader_segment_generation() {
+        remove_dir_all_ignore_error("test_indices/test_reader_segment_generation");
+
+        make_test_store("test_indices/test_reader_segment_generation");
+
+        let mut store = RocksDBStore::open("test_indices/test_reader_segment_generation").unwrap();
+        store.set_write_segment_files(true);
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+
+        let active_segments = |reader: &RocksDBReader| {
+            store.segments.iter_active(reader).map(|segment| segment.id().0).collect::<Vec<_>>()
+        };
+
+        let count_hello_docs = |reader: &RocksDBReader| {
+            let query = Query::term(title_field, Term::from_string("hello"));
+            let mut collector = TotalCountCollector::new();
+            reader.search(&mut collector, &query).unwrap();
+            collector.get_total_count()
+        };
+
+        let insert_hello_doc = |store: &RocksDBStore, key: &str| {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+
+            store.insert_or_update_document(&Document {
+                key: key.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        };
+
+        // Put the documents in a segment file
+        insert_hello_doc(&store, "third_test_doc");
+        store.merge_segments(&vec![3, 4]).unwrap();
+        store.purge_segments(&vec![3, 4]).unwrap();
+
+        // Readers opened without any changes in between share a generation
+        let old_reader = store.reader();
+        let other_reader = store.reader();
+        assert!(old_reader.segment_generation().is_some());
+        assert!(Arc::ptr_eq(&old_reader.generation, &other_reader.generation));
+        drop(other_reader);
+
+        insert_hello_doc(&store, "fourth_test_doc");
+        store.merge_segments(&vec![5, 6]).unwrap();
+        store.purge_segments(&vec![5, 6]).unwrap();
+
+        // The old reader still sees the segments from before the merge
+        let new_reader = store.reader();
+        assert!(new_reader.segment_generation() > old_reader.segment_generation());
+        assert_eq!(active_segments(&old_reader), vec![5]);
+        assert_eq!(active_segments(&new_reader), vec![7]);
+        assert_eq!(count_hello_docs(&old_reader), 2);
+        assert_eq!(count_hello_docs(&new_reader), 3);
+        match old_reader.read_stored_field(pk_field, DocId(SegmentId(5), 0)) {
+            Ok(Some(FieldValue::Integer(1))) => {}
+            _ => panic!("expected the old reader to read from the merged away segment"),
+        }
+
+        // Generations continue from where they were when the store is reopened
+        let generation = new_reader.segment_generation();
+        drop(old_reader);
+        drop(new_reader);
+        drop(store);
+        let store = RocksDBStore::open("test_indices/test_reader_segment_generation").unwrap();
+        assert_eq!(store.reader().segment_generation(), generation);
+    }
+
+    #[test]
+    fn test_suggest() {
+        remove_dir_all_ignore_error("test_indices/test_suggest");
+
+        let store = make_test_store("test_indices/test_suggest");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let index_reader = store.reader();
+
+        let suggestions = index_reader.suggest(title_field, "helo world").unwrap();
+        assert_eq!(suggestions.len(), 2);
+        assert_eq!(suggestions[0].best(), Some(&Suggestion {
+            term: "hello".to_string(),
+            distance: 1,
+            doc_frequency: 1,
+        }));
+
+        // Terms that are in the field aren't corrected
+        assert_eq!(suggestions[1].doc_frequency, 1);
+        assert!(suggestions[1].suggestions.is_empty());
+
+        // "lorem" is only in the body field
+        assert!(index_reader.suggest(title_field, "lorm").unwrap()[0].suggestions.is_empty());
+
+        assert_eq!(index_reader.did_you_mean(title_field, "howdyy partner").unwrap(), Some("howdy partner".to_string()));
+        assert_eq!(index_reader.did_you_mean(title_field, "hello world").unwrap(), None);
+    }
+
+    #[test]
+    fn test_popular_terms() {
+        remove_dir_all_ignore_error("test_indices/test_popular_terms");
+
+        let store = make_test_store("test_indices/test_popular_terms");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(body_field, vec![Token { term: Term::from_string("ipsum"), position: 1, offsets: None }, Token { term: Term::from_string("lorax"), position: 2, offsets: None }].into());
+        store.insert_or_update_document(&Document {
+            key: "third_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+
+        let index_reader = store.reader();
+        assert_eq!(index_reader.popular_terms(body_field, "lo", 10).unwrap(), vec![
+            PopularTerm { term: "lorem".to_string(), doc_frequency: 2 },
+            PopularTerm { term: "lorax".to_string(), doc_frequency: 1 },
+        ]);
+        assert_eq!(index_reader.popular_terms(body_field, "", 1).unwrap(), vec![
+            PopularTerm { term: "ipsum".to_string(), doc_frequency: 3 },
+        ]);
+
+        // "lorem" is only in the body field
+        assert!(index_reader.popular_terms(title_field, "lo", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_completion_suggester() {
+        use kite::document::Completion;
+
+        remove_dir_all_ignore_error("test_indices/test_completion_suggester");
+
+        let mut store = RocksDBStore::create("test_indices/test_completion_suggester").unwrap();
+        let suggest_field = store.add_field("suggest".to_string(), FieldType::Completion, FieldFlags::empty()).unwrap();
+        let pk_field = store.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+
+        for &(key, input, weight) in [("1", "nirvana", 5), ("2", "nevermind", 10), ("3", "oasis", 20)].iter() {
+            let mut completions = FnvHashMap::default();
+            completions.insert(suggest_field, vec![Completion {
+                input: input.to_string(),
+                weight: weight,
+                payload: key.as_bytes().to_vec(),
+            }]);
+
+            store.insert_or_update_document(&Document {
+                key: key.to_string(),
+                indexed_fields: FnvHashMap::default(),
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: completions,
+                source: None,
+                routing: None,
+            }).unwrap();
+        }
+
+        let complete = |store: &RocksDBStore, prefix: &str, size: usize| {
+            store.reader().complete(suggest_field, prefix, size).unwrap().into_iter()
+                .map(|suggestion| (suggestion.input, suggestion.weight, suggestion.payload, suggestion.doc_id))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(complete(&store, "n", 10), vec![
+            ("nevermind".to_string(), 10, b"2".to_vec(), DocId(SegmentId(2), 0)),
+            ("nirvana".to_string(), 5, b"1".to_vec(), DocId(SegmentId(1), 0)),
+        ]);
+
+        // Completion indexes are rebuilt when segments are merged
+        store.merge_segments(&vec![1, 2, 3]).unwrap();
+        store.purge_segments(&vec![1, 2, 3]).unwrap();
+        assert_eq!(complete(&store, "n", 1), vec![
+            ("nevermind".to_string(), 10, b"2".to_vec(), DocId(SegmentId(4), 1)),
+        ]);
+        assert_eq!(complete(&store, "", 10).len(), 3);
+        assert!(complete(&store, "x", 10).is_empty());
+
+        assert!(store.reader().complete(pk_field, "n", 10).is_err());
+    }
+
+    #[test]
+    fn test_facet_counts() {
+        use kite::facet::FacetPath;
+
+        remove_dir_all_ignore_error("test_indices/test_facet_counts");
+
+        let mut store = RocksDBStore::create("test_indices/test_facet_counts").unwrap();
+        let category_field = store.add_field("category".to_string(), FieldType::Facet, FIELD_INDEXED).unwrap();
+
+        let categories = ["Electronics/Cameras/DSLR", "Electronics/Cameras/Compact", "Electronics/Phones", "Books/Fiction"];
+        for (i, category) in categories.iter().enumerate() {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(category_field, FacetPath::to_term_vector(&[FacetPath::parse(category)]));
+
+            store.insert_or_update_document(&Document {
+                key: i.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        }
+
+        let index_reader = store.reader();
+        let facet_counts = |query: &Query, parent: &str, depth: usize| {
+            index_reader.facet_counts(query, category_field, &FacetPath::parse(parent), depth).unwrap().into_iter()
+                .map(|facet_count| (facet_count.path.to_string(), facet_count.count))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(facet_counts(&Query::all(), "", 1), vec![
+            ("Electronics".to_string(), 3),
+            ("Books".to_string(), 1),
+        ]);
+        assert_eq!(facet_counts(&Query::all(), "", 2), vec![
+            ("Electronics/Cameras".to_string(), 2),
+            ("Books/Fiction".to_string(), 1),
+            ("Electronics/Phones".to_string(), 1),
+        ]);
+
+        // Drill down into cameras
+        let cameras = FacetPath::parse("Electronics/Cameras");
+        let query = Query::all().filter(Query::facet(category_field, &cameras));
+        assert_eq!(index_reader.count(&query).unwrap(), 2);
+        assert_eq!(facet_counts(&query, "Electronics/Cameras", 1), vec![
+            ("Electronics/Cameras/Compact".to_string(), 1),
+            ("Electronics/Cameras/DSLR".to_string(), 1),
+        ]);
+        assert_eq!(facet_counts(&query, "Books", 1), vec![]);
+    }
+
+    #[test]
+    fn test_term_vector() {
+        use kite::schema::FIELD_TERM_VECTORS;
+
+        remove_dir_all_ignore_error("test_indices/test_term_vector");
+
+        let mut store = RocksDBStore::create("test_indices/test_term_vector").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let body_field = store.add_field("body".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_TERM_VECTORS).unwrap();
+        store.set_write_segment_files(true);
+
+        for key in ["first", "second"].iter() {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+            indexed_fields.insert(body_field, vec![
+                Token { term: Term::from_string("to"), position: 1, offsets: None },
+                Token { term: Term::from_string("be"), position: 2, offsets: None },
+                Token { term: Term::from_string("or"), position: 3, offsets: None },
+                Token { term: Term::from_string("not"), position: 4, offsets: None },
+                Token { term: Term::from_string("to"), position: 5, offsets: None },
+                Token { term: Term::from_string(key), position: 6, offsets: None },
+            ].into());
+
+            store.insert_or_update_document(&Document {
+                key: key.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        }
+
+        let term_positions = |store: &RocksDBStore, doc_id: DocId, term: &str| {
+            let term_vector = store.reader().term_vector(body_field, doc_id).unwrap().unwrap();
+            term_vector.get(&Term::from_string(term)).map(|positions| positions.iter().collect::<Vec<_>>())
+        };
+
+        assert_eq!(term_positions(&store, DocId(SegmentId(1), 0), "to"), Some(vec![1, 5]));
+        assert_eq!(term_positions(&store, DocId(SegmentId(1), 0), "first"), Some(vec![6]));
+        assert_eq!(term_positions(&store, DocId(SegmentId(1), 0), "second"), None);
+
+        // Term vectors are moved with their documents into the merged segment's file
+        store.merge_segments(&vec![1, 2]).unwrap();
+        store.purge_segments(&vec![1, 2]).unwrap();
+        assert_eq!(term_positions(&store, DocId(SegmentId(3), 1), "second"), Some(vec![6]));
+        assert_eq!(store.reader().term_vector(body_field, DocId(SegmentId(3), 1)).unwrap().unwrap().len(), 5);
+
+        assert!(store.reader().term_vector(title_field, DocId(SegmentId(3), 0)).is_err());
+    }
+
+    #[test]
+    fn test_term_vector_offsets() {
+        use kite::schema::FIELD_TERM_VECTORS;
+
+        remove_dir_all_ignore_error("test_indices/test_term_vector_offsets");
+
+        let mut store = RocksDBStore::create("test_indices/test_term_vector_offsets").unwrap();
+        let body_field = store.add_field("body".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_TERM_VECTORS).unwrap();
+        store.set_write_segment_files(true);
+
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"first\"}}\n{\"body\": [\"To be, or not\", \"to be\"]}\n",
+            "{\"index\": {\"_id\": \"second\"}}\n{\"body\": \"Hello\"}\n",
+        ), &BulkConfig::default()).unwrap();
+        store.bulk("{\"index\": {\"_id\": \"third\"}}\n{\"body\": \"Hello again\"}\n", &BulkConfig::default()).unwrap();
+
+        let offsets = |store: &RocksDBStore, doc_id: DocId| {
+            let term_vector = store.reader().term_vector(body_field, doc_id).unwrap().unwrap();
+            term_vector.offsets.iter().map(|(position, offsets)| (*position, *offsets)).collect::<Vec<_>>()
+        };
+
+        // The offsets of later values follow on from the earlier ones
+        assert_eq!(offsets(&store, DocId(SegmentId(1), 0)), vec![(1, (0, 2)), (2, (3, 5)), (3, (7, 9)), (4, (10, 13)), (5, (14, 16)), (6, (17, 19))]);
+        assert_eq!(offsets(&store, DocId(SegmentId(1), 1)), vec![(1, (0, 5))]);
+
+        // Offsets are kept when segments are merged
+        store.merge_segments(&vec![1, 2]).unwrap();
+        store.purge_segments(&vec![1, 2]).unwrap();
+        assert_eq!(offsets(&store, DocId(SegmentId(3), 2)), vec![(1, (0, 5)), (2, (6, 11))]);
+    }
+
+    #[test]
+    fn test_get_document() {
+        remove_dir_all_ignore_error("test_indices/test_get_document");
+
+        let store = make_test_store("test_indices/test_get_document");
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+        let index_reader = store.reader();
+
+        let doc = index_reader.get_document("test_doc").unwrap().unwrap();
+        assert_eq!(doc.key, "test_doc");
+        assert_eq!(doc.stored_fields.len(), 1);
+        assert_eq!(doc.get_i64(pk_field), Some(1));
+        assert_eq!(doc.get_str(pk_field), None);
+
+        assert_eq!(index_reader.get_document_id("another_test_doc").unwrap(), Some(DocId(SegmentId(3), 1)));
+        assert!(index_reader.get_document("missing").unwrap().is_none());
+
+        // Deleted documents can't be found by new readers
+        store.remove_document_by_key("test_doc").unwrap();
+        assert!(index_reader.get_document("test_doc").unwrap().is_some());
+        assert!(store.reader().get_document("test_doc").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_multi_get() {
+        remove_dir_all_ignore_error("test_indices/test_multi_get");
+
+        let store = make_test_store("test_indices/test_multi_get");
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+        let index_reader = store.reader();
+
+        let docs = index_reader.multi_get(&["another_test_doc", "missing", "test_doc"]).unwrap();
+        assert_eq!(docs.len(), 3);
+        assert!(docs[1].is_none());
+
+        // Results are in the same order as the keys
+        let keys = docs.iter().map(|doc| doc.as_ref().map(|doc| doc.key.clone())).collect::<Vec<_>>();
+        assert_eq!(keys, vec![Some("another_test_doc".to_string()), None, Some("test_doc".to_string())]);
+
+        let pks = docs.iter().filter_map(|doc| doc.as_ref()).map(|doc| {
+            assert_eq!(doc.stored_fields.len(), 1);
+            match doc.stored_fields.get(&pk_field) {
+                Some(&FieldValue::Integer(pk)) => pk,
+                _ => panic!("expected the document's stored pk"),
+            }
+        }).collect::<Vec<_>>();
+        assert_eq!(pks, vec![2, 1]);
+
+        assert!(index_reader.multi_get(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fetch_documents() {
+        remove_dir_all_ignore_error("test_indices/test_fetch_documents");
+
+        let store = make_test_store("test_indices/test_fetch_documents");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+        let popularity_field = store.schema.get_field_by_name("popularity").unwrap();
+        let index_reader = store.reader();
+
+        let mut collector = TopScoreCollector::new(10);
+        index_reader.search(&mut collector, &Query::term(body_field, Term::from_string("lorem"))).unwrap();
+        let mut doc_ids = collector.into_sorted_vec().iter().map(|doc| DocId::from_u64(doc.doc_id())).collect::<Vec<_>>();
+        assert_eq!(doc_ids.len(), 2);
+
+        // Documents come back in the order they were asked for, even when that isn't id order
+        doc_ids.sort_by_key(|doc_id| ::std::cmp::Reverse(doc_id.1));
+        doc_ids.push(DocId(SegmentId(100), 0));
+
+        let documents = index_reader.fetch_documents(&doc_ids, &[pk_field, title_field]).unwrap();
+        assert_eq!(documents.len(), 3);
+        for (doc_id, fields) in doc_ids.iter().zip(documents.iter()).take(2) {
+            let expected = index_reader.read_stored_field(pk_field, *doc_id).unwrap();
+            assert_eq!(fields.get(&pk_field).map(|value| format!("{:?}", value)), expected.map(|value| format!("{:?}", value)));
+
+            // The title isn't stored
+            assert_eq!(fields.len(), 1);
+        }
+        assert!(documents[2].is_empty());
+
+        // Unless they're asked for, no fields are read
+        assert!(index_reader.fetch_documents(&doc_ids, &[]).unwrap().iter().all(|fields| fields.is_empty()));
+
+        match index_reader.fetch_documents(&doc_ids, &[popularity_field]) {
+            Err(StoredFieldReadError::FieldTypeNotStored(FieldType::RankFeature)) => {}
+            _ => panic!("expected an error for a rank feature field"),
+        }
+        match index_reader.fetch_documents(&doc_ids, &[FieldId(100)]) {
+            Err(StoredFieldReadError::InvalidFieldId(FieldId(100))) => {}
+            _ => panic!("expected an error for an unknown field"),
+        }
+    }
+
+    #[test]
+    fn test_scroll() {
+        remove_dir_all_ignore_error("test_indices/test_scroll");
+
+        let store = make_test_store("test_indices/test_scroll");
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let index_reader = store.reader();
+        let query = Query::term(body_field, Term::from_string("lorem"));
+
+        let batches = index_reader.scroll(&query, 1).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(batches, vec![vec![DocId(SegmentId(3), 0)], vec![DocId(SegmentId(3), 1)]]);
+
+        // Resume a scroll from where another left off
+        let mut scroll = index_reader.scroll(&query, 1).unwrap();
+        assert_eq!(scroll.next_batch().unwrap(), vec![DocId(SegmentId(3), 0)]);
+        let mut resumed = index_reader.scroll_after(&query, 10, scroll.last_doc_id()).unwrap();
+        assert_eq!(resumed.next_batch().unwrap(), vec![DocId(SegmentId(3), 1)]);
+        assert!(resumed.next_batch().unwrap().is_empty());
+
+        // The scroll is pinned to the reader's snapshot
+        store.remove_document_by_key("test_doc").unwrap();
+        assert_eq!(index_reader.scroll(&query, 10).unwrap().next_batch().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_reindex() {
+        remove_dir_all_ignore_error("test_indices/test_reindex");
+        remove_dir_all_ignore_error("test_indices/test_reindex_target");
+
+        let store = make_test_store("test_indices/test_reindex");
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+
+        let mut target = RocksDBStore::create("test_indices/test_reindex_target").unwrap();
+        let target_pk_field = target.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+        let pk_text_field = target.add_field("pk_text".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+
+        let config = ReindexConfig {
+            batch_size: 1,
+            max_documents_per_second: Some(1000),
+        };
+        let mut progress_updates = Vec::new();
+        let progress = store.reindex(&target, &config, |doc| {
+            if doc.key == "another_test_doc" {
+                return None;
+            }
+
+            // Index the stored pk as a string in the new index
+            let pk = match doc.stored_fields.get(&pk_field) {
+                Some(&FieldValue::Integer(pk)) => pk,
+                _ => return None,
+            };
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(pk_text_field, vec![Token { term: Term::from_string(&pk.to_string()), position: 1, offsets: None }].into());
+            let mut stored_fields = FnvHashMap::default();
+            stored_fields.insert(target_pk_field, FieldValue::Integer(pk));
+
+            Some(Document {
+                key: doc.key,
+                indexed_fields: indexed_fields,
+                stored_fields: stored_fields,
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            })
+        }, |progress| progress_updates.push(progress.clone())).unwrap();
+
+        assert_eq!(progress, ReindexProgress { total: 2, reindexed: 1, skipped: 1 });
+        assert!(progress.is_finished());
+        assert_eq!(progress_updates.len(), 2);
+
+        let target_reader = target.reader();
+        assert!(target_reader.get_document("another_test_doc").unwrap().is_none());
+        assert_eq!(target_reader.count(&Query::term(pk_text_field, Term::from_string("1"))).unwrap(), 1);
+        match target_reader.get_document("test_doc").unwrap().unwrap().stored_fields.get(&target_pk_field) {
+            Some(&FieldValue::Integer(1)) => {}
+            _ => panic!("expected the document's stored pk"),
+        }
+    }
+
+    #[test]
+    fn test_clone_to() {
+        remove_dir_all_ignore_error("test_indices/test_clone_to");
+        remove_dir_all_ignore_error("test_indices/test_clone_to_copy");
+
+        make_test_store("test_indices/test_clone_to");
+        let mut store = RocksDBStore::open("test_indices/test_clone_to").unwrap();
+        store.set_write_segment_files(true);
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+
+        // Merged segments are written to segment files, which are linked rather than copied
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+        store.insert_or_update_document(&Document {
+            key: "file_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+        store.merge_segments(&vec![3, 4]).unwrap();
+        store.purge_segments(&vec![3, 4]).unwrap();
+
+        let query = Query::term(title_field, Term::from_string("hello"));
+        let copy = store.clone_to("test_indices/test_clone_to_copy").unwrap();
+        assert!(Path::new("test_indices/test_clone_to_copy/segments/5.seg").exists());
+        assert_eq!(copy.reader().count(&query).unwrap(), 2);
+        assert!(copy.reader().get_document("test_doc").unwrap().is_some());
+
+        // The copy is independent of the original
+        copy.remove_document_by_key("test_doc").unwrap();
+        assert!(copy.reader().get_document("test_doc").unwrap().is_none());
+        assert!(store.reader().get_document("test_doc").unwrap().is_some());
+
+        assert!(store.clone_to("test_indices/test_clone_to_copy").is_err());
+    }
+
+    #[test]
+    fn test_field_statistics() {
+        remove_dir_all_ignore_error("test_indices/test_field_statistics");
+
+        let store = make_test_store("test_indices/test_field_statistics");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+        let index_reader = store.reader();
+
+        // Combined when segments 1 and 2 were merged
+        assert_eq!(index_reader.field_statistics(pk_field).unwrap(), FieldStatistics {
+            doc_count: 2,
+            min_value: Some(1),
+            max_value: Some(2),
+            distinct_terms: 0,
+        });
+
+        // "hello world" and "howdy partner"
+        let title_stats = index_reader.field_statistics(title_field).unwrap();
+        assert_eq!(title_stats.doc_count, 2);
+        assert_eq!(title_stats.distinct_terms, 4);
+        assert_eq!(title_stats.min_value, None);
+
+        // Both documents have "lorem ipsum dolar"
+        assert_eq!(index_reader.field_statistics(body_field).unwrap().distinct_terms, 3);
+
+        assert!(index_reader.field_statistics(FieldId(100)).is_err());
+    }
+
+    #[test]
+    fn test_corpus_statistics() {
+        remove_dir_all_ignore_error("test_indices/test_corpus_statistics");
+
+        let store = make_test_store("test_indices/test_corpus_statistics");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let query = Query::term(title_field, Term::from_string("hello"));
+
+        let local_stats = store.reader().corpus_statistics(&query).unwrap();
+        let title_stats = local_stats.field(title_field).unwrap();
+        assert_eq!(title_stats.total_docs, 2);
+        assert_eq!(title_stats.term_document_frequency(&Term::from_string("hello")), Some(1));
+
+        let score = |index_reader: &RocksDBReader| {
+            let mut collector = TopScoreCollector::new(10);
+            index_reader.search(&mut collector, &query).unwrap();
+            collector.into_sorted_vec()[0].score().unwrap()
+        };
+
+        // Another shard where "hello" is in every document makes it less rare overall
+        let mut other_shard_stats = CorpusStatistics::new();
+        other_shard_stats.add_field(title_field, 100, 200);
+        other_shard_stats.add_term(title_field, &Term::from_string("hello"), 100);
+        let mut global_stats = local_stats.clone();
+        global_stats.merge(&other_shard_stats);
+
+        let mut index_reader = store.reader();
+        let local_score = score(&index_reader);
+        index_reader.set_corpus_statistics(Arc::new(global_stats.clone()));
+        assert!(score(&index_reader) < local_score);
+        assert_eq!(index_reader.corpus_statistics(&query).unwrap(), global_stats);
+    }
+
+    #[test]
+    fn test_change_log() {
+        remove_dir_all_ignore_error("test_indices/test_change_log");
+
+        make_test_store("test_indices/test_change_log");
+        let mut store = RocksDBStore::open("test_indices/test_change_log").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+
+        // Changes made before the log was enabled aren't recorded
+        assert_eq!(store.last_change_sequence(), 0);
+        assert!(store.changes_since(0, 10).unwrap().is_empty());
+
+        store.set_change_log_enabled(true);
+        store.set_change_payload(move |doc| {
+            match doc.stored_fields.get(&pk_field) {
+                Some(&FieldValue::Integer(pk)) => Some(pk.to_string().into_bytes()),
+                _ => None,
+            }
+        });
+
+        let mut stored_fields = FnvHashMap::default();
+        stored_fields.insert(pk_field, FieldValue::Integer(3));
+        store.insert_or_update_document(&Document {
+            key: "third_test_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+        store.remove_document_by_key("test_doc").unwrap();
+        store.remove_document_by_key("missing").unwrap();
+
+        let changes = store.changes_since(0, 10).unwrap();
+        assert_eq!(changes, vec![
+            Change { sequence: 1, kind: ChangeKind::Upsert, tenant: None, key: "third_test_doc".to_string(), payload: Some(b"3".to_vec()) },
+            Change { sequence: 2, kind: ChangeKind::Delete, tenant: None, key: "test_doc".to_string(), payload: None },
+        ]);
+        assert_eq!(store.changes_since(1, 10).unwrap(), changes[1..].to_vec());
+        assert_eq!(store.changes_since(0, 1).unwrap(), changes[..1].to_vec());
+
+        // The sequence continues after the store is reopened
+        drop(store);
+        let mut store = RocksDBStore::open("test_indices/test_change_log").unwrap();
+        store.set_change_log_enabled(true);
+        assert_eq!(store.last_change_sequence(), 2);
+        store.remove_document_by_key("another_test_doc").unwrap();
+        assert_eq!(store.changes_since(2, 10).unwrap()[0].sequence, 3);
+
+        store.truncate_change_log(2).unwrap();
+        assert_eq!(store.changes_since(0, 10).unwrap().iter().map(|change| change.sequence).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[derive(Default)]
+    struct TestListener {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl IndexListener for TestListener {
+        fn document_indexed(&self, key: &str) {
+            self.events.lock().unwrap().push(format!("indexed {}", key));
+        }
+
+        fn document_deleted(&self, key: &str) {
+            self.events.lock().unwrap().push(format!("deleted {}", key));
+        }
+
+        fn segment_flushed(&self, segment: u32, num_docs: usize) {
+            self.events.lock().unwrap().push(format!("flushed {} ({} docs)", segment, num_docs));
+        }
+
+        fn merge_started(&self, source_segments: &[u32], dest_segment: u32) {
+            self.events.lock().unwrap().push(format!("merge started {:?} -> {}", source_segments, dest_segment));
+        }
+
+        fn merge_finished(&self, source_segments: &[u32], dest_segment: u32, succeeded: bool) {
+            self.events.lock().unwrap().push(format!("merge finished {:?} -> {} {}", source_segments, dest_segment, succeeded));
+        }
+
+        fn committed(&self, generation: u64) {
+            self.events.lock().unwrap().push(format!("committed {}", generation));
+        }
+    }
+
+    #[test]
+    fn test_listener() {
+        remove_dir_all_ignore_error("test_indices/test_listener");
+
+        make_test_store("test_indices/test_listener");
+        let listener = Arc::new(TestListener::default());
+        let mut store = RocksDBStore::open("test_indices/test_listener").unwrap();
+        store.add_listener(listener.clone());
+        let generation = store.reader().segment_generation().unwrap();
+
+        store.insert_or_update_document(&Document {
+            key: "third_test_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+        store.merge_segments(&vec![3, 4]).unwrap();
+        store.remove_document_by_key("test_doc").unwrap();
+
+        let events = listener.events.lock().unwrap();
+        assert_eq!(*events, vec![
+            "flushed 4 (1 docs)".to_string(),
+            format!("committed {}", generation + 1),
+            "indexed third_test_doc".to_string(),
+            "merge started [3, 4] -> 5".to_string(),
+            format!("committed {}", generation + 2),
+            "merge finished [3, 4] -> 5 true".to_string(),
+            "deleted test_doc".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_count_live_documents() {
+        remove_dir_all_ignore_error("test_indices/test_count_live_documents");
+
+        let store = make_test_store("test_indices/test_count_live_documents");
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let query = Query::term(body_field, Term::from_string("lorem"));
+        assert_eq!(store.reader().count_all().unwrap(), 2);
+
+        // Replacing a document deletes the old version
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(body_field, vec![Token { term: Term::from_string("lorem"), position: 1, offsets: None }].into());
+        store.insert_or_update_document(&Document {
+            key: "test_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+        assert_eq!(store.reader().count_all().unwrap(), 2);
+        assert_eq!(store.reader().count(&query).unwrap(), 2);
+
+        store.remove_document_by_key("another_test_doc").unwrap();
+        let index_reader = store.reader();
+        assert_eq!(index_reader.count_all().unwrap(), 1);
+        assert_eq!(index_reader.count(&query).unwrap(), 1);
+        assert_eq!(index_reader.count(&Query::all()).unwrap(), 1);
+
+        // Deletion lists are carried over when segments are merged
+        store.merge_segments(&vec![3, 4]).unwrap();
+        assert_eq!(store.reader().count_all().unwrap(), 1);
+        assert_eq!(store.reader().count(&query).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_source() {
+        remove_dir_all_ignore_error("test_indices/test_source");
+
+        make_test_store("test_indices/test_source");
+        let mut store = RocksDBStore::open("test_indices/test_source").unwrap();
+        store.set_write_segment_files(true);
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+
+        let mut stored_fields = FnvHashMap::default();
+        stored_fields.insert(pk_field, FieldValue::Integer(3));
+        store.insert_or_update_document(&Document {
+            key: "source_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: Some(br#"{"pk": 3}"#.to_vec()),
+            routing: None,
+        }).unwrap();
+
+        let check_source = |store: &RocksDBStore| {
+            let index_reader = store.reader();
+            let doc = index_reader.get_document("source_doc").unwrap().unwrap();
+            assert_eq!(doc.source, Some(br#"{"pk": 3}"#.to_vec()));
+
+            let docs = index_reader.multi_get(&["source_doc", "test_doc"]).unwrap();
+            assert_eq!(docs[0].as_ref().unwrap().source, Some(br#"{"pk": 3}"#.to_vec()));
+            assert_eq!(docs[1].as_ref().unwrap().source, None);
+
+            // The source isn't mistaken for a stored field
+            assert_eq!(docs[0].as_ref().unwrap().stored_fields.len(), 1);
+
+            // Sources can be read for a batch of hits
+            let doc_ids = vec![
+                index_reader.get_document_id("test_doc").unwrap().unwrap(),
+                index_reader.get_document_id("source_doc").unwrap().unwrap(),
+            ];
+            assert_eq!(index_reader.fetch_sources(&doc_ids).unwrap(), vec![None, Some(br#"{"pk": 3}"#.to_vec())]);
+        };
+        check_source(&store);
+
+        // Merged segments are read from segment files
+        store.merge_segments(&vec![3, 4]).unwrap();
+        store.purge_segments(&vec![3, 4]).unwrap();
+        check_source(&store);
+
+        let doc_id = store.reader().get_document_id("source_doc").unwrap().unwrap();
+        assert_eq!(store.reader().read_source(doc_id).unwrap(), Some(br#"{"pk": 3}"#.to_vec()));
+    }
+
+    #[test]
+    fn test_routing() {
+        remove_dir_all_ignore_error("test_indices/test_routing");
+
+        make_test_store("test_indices/test_routing");
+        let mut store = RocksDBStore::open("test_indices/test_routing").unwrap();
+        store.set_write_segment_files(true);
+
+        store.insert_or_update_document(&Document {
+            key: "routed_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: Some("user1".to_string()),
+        }).unwrap();
+
+        let check_routing = |store: &RocksDBStore| {
+            let index_reader = store.reader();
+            let doc = index_reader.get_document("routed_doc").unwrap().unwrap();
+            assert_eq!(doc.routing, Some("user1".to_string()));
+            assert_eq!(doc.routing_key(), "user1");
+
+            let docs = index_reader.multi_get(&["routed_doc", "test_doc"]).unwrap();
+            assert_eq!(docs[0].as_ref().unwrap().routing, Some("user1".to_string()));
+            assert_eq!(docs[1].as_ref().unwrap().routing, None);
+            assert_eq!(docs[1].as_ref().unwrap().routing_key(), "test_doc");
+        };
+        check_routing(&store);
+
+        // Merged segments are read from segment files
+        store.merge_segments(&vec![3, 4]).unwrap();
+        store.purge_segments(&vec![3, 4]).unwrap();
+        check_routing(&store);
+    }
+
+    #[test]
+    fn test_replication() {
+        remove_dir_all_ignore_error("test_indices/test_replication");
+        remove_dir_all_ignore_error("test_indices/test_replication_follower");
+
+        make_test_store("test_indices/test_replication");
+        let mut leader = RocksDBStore::open("test_indices/test_replication").unwrap();
+        leader.set_write_segment_files(true);
+        let mut follower = RocksDBStore::create("test_indices/test_replication_follower").unwrap();
+
+        let replicate = |leader: &RocksDBStore, follower: &mut RocksDBStore| {
+            let delta = leader.replication_delta(&follower.replication_checkpoint().unwrap()).unwrap();
+            follower.apply_replication_delta(&delta).unwrap();
+            delta
+        };
+
+        // The first delta copies everything
+        replicate(&leader, &mut follower);
+        let title_field = follower.schema.get_field_by_name("title").unwrap();
+        let body_field = follower.schema.get_field_by_name("body").unwrap();
+        let query = Query::term(body_field, Term::from_string("lorem"));
+        assert_eq!(follower.reader().count(&query).unwrap(), 2);
+        assert!(follower.reader().get_document("test_doc").unwrap().is_some());
+        assert_eq!(follower.reader().segment_generation(), leader.reader().segment_generation());
+
+        // Later deltas only include new segments and deletes
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }, Token { term: Term::from_string("replica"), position: 2, offsets: None }].into());
+        leader.insert_or_update_document(&Document {
+            key: "new_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+        leader.remove_document_by_key("test_doc").unwrap();
+
+        let delta = replicate(&leader, &mut follower);
+        assert_eq!(delta.segments.len(), 2);
+        assert!(delta.segment_files.is_empty());
+        assert!(!delta.puts.iter().any(|&(ref key, _)| key.starts_with(b"v3/")));
+        assert_eq!(follower.reader().count(&query).unwrap(), 1);
+        assert!(follower.reader().get_document("test_doc").unwrap().is_none());
+        assert!(follower.reader().get_document("new_doc").unwrap().is_some());
+        assert_eq!(follower.reader().count(&Query::term(title_field, Term::from_string("replica"))).unwrap(), 1);
+
+        // Merged segments are replaced on the follower
+        let segments = leader.reader().generation.segments().to_vec();
+        let merged_segment = leader.merge_segments(&segments).unwrap();
+        leader.purge_segments(&segments).unwrap();
+
+        let delta = replicate(&leader, &mut follower);
+        assert_eq!(delta.segments, vec![merged_segment]);
+        assert_eq!(delta.segment_files.len(), 1);
+        assert_eq!(follower.reader().generation.segments(), &[merged_segment]);
+        assert!(Path::new(&format!("test_indices/test_replication_follower/segments/{}.seg", merged_segment)).exists());
+        assert_eq!(follower.reader().count(&query).unwrap(), 1);
+        assert_eq!(follower.reader().count_all().unwrap(), 2);
+        assert!(follower.reader().get_document("new_doc").unwrap().is_some());
+        assert!(follower.reader().get_document("test_doc").unwrap().is_none());
+
+        // Nothing has changed since
+        let delta = replicate(&leader, &mut follower);
+        assert!(delta.segment_files.is_empty());
+        assert!(delta.puts.iter().all(|&(ref key, _)| key[0] == b'.'));
+    }
+
+    #[test]
+    fn test_replication_integer_terms() {
+        remove_dir_all_ignore_error("test_indices/test_replication_integer_terms");
+        remove_dir_all_ignore_error("test_indices/test_replication_integer_terms_follower");
+
+        let mut leader = RocksDBStore::create("test_indices/test_replication_integer_terms").unwrap();
+        let count_field = leader.add_field("count".to_string(), FieldType::I64, FIELD_INDEXED).unwrap();
+        leader.set_write_segment_files(true);
+        let mut follower = RocksDBStore::create("test_indices/test_replication_integer_terms_follower").unwrap();
+
+        // Terms of segments that have been written to files are sent too
+        leader.bulk("{\"index\": {\"_id\": \"doc1\"}}\n{\"count\": 1}\n", &BulkConfig::default()).unwrap();
+        let segments = leader.reader().generation.segments().to_vec();
+        leader.merge_segments(&segments).unwrap();
+        leader.purge_segments(&segments).unwrap();
+        leader.bulk("{\"index\": {\"_id\": \"doc2\"}}\n{\"count\": 2}\n", &BulkConfig::default()).unwrap();
+
+        let delta = leader.replication_delta(&follower.replication_checkpoint().unwrap()).unwrap();
+        follower.apply_replication_delta(&delta).unwrap();
+        assert_eq!(follower.reader().count(&Query::range(count_field, Some(1), None)).unwrap(), 2);
+
+        // Values that are already in the term dictionary are added to the follower's index of the field too
+        let other_count_field = leader.add_field("other_count".to_string(), FieldType::I64, FIELD_INDEXED).unwrap();
+        leader.bulk("{\"index\": {\"_id\": \"doc3\"}}\n{\"other_count\": 1}\n", &BulkConfig::default()).unwrap();
+
+        let delta = leader.replication_delta(&follower.replication_checkpoint().unwrap()).unwrap();
+        follower.apply_replication_delta(&delta).unwrap();
+        assert_eq!(follower.reader().count(&Query::range(other_count_field, None, Some(1))).unwrap(), 1);
+        assert_eq!(follower.reader().count(&Query::range(count_field, None, Some(1))).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_snapshot_repository() {
+        remove_dir_all_ignore_error("test_indices/test_snapshot_repository");
+        remove_dir_all_ignore_error("test_indices/test_snapshot_repository_restored");
+        remove_dir_all_ignore_error("test_indices/test_snapshot_repository_snapshots");
+
+        make_test_store("test_indices/test_snapshot_repository");
+        let mut store = RocksDBStore::open("test_indices/test_snapshot_repository").unwrap();
+        store.set_write_segment_files(true);
+        store.set_metadata("checkpoint", b"1");
+        store.commit_metadata().unwrap();
+
+        let repository = FsSnapshotRepository::new("test_indices/test_snapshot_repository_snapshots").unwrap();
+        store.save_snapshot(&repository, "first").unwrap();
+        assert_eq!(repository.list().unwrap(), vec!["first".to_string()]);
+        assert!(repository.put("../escape", b"").is_err());
+
+        // Changes made after the snapshot aren't restored
+        store.remove_document_by_key("test_doc").unwrap();
+
+        let restored = RocksDBStore::restore_snapshot(&repository, "first", "test_indices/test_snapshot_repository_restored").unwrap();
+        let body_field = restored.schema.get_field_by_name("body").unwrap();
+        assert_eq!(restored.reader().count(&Query::term(body_field, Term::from_string("lorem"))).unwrap(), 2);
+        assert!(restored.reader().get_document("test_doc").unwrap().is_some());
+        assert_eq!(restored.get_metadata("checkpoint").unwrap(), Some(b"1".to_vec()));
+
+        assert!(RocksDBStore::restore_snapshot(&repository, "missing", "test_indices/test_snapshot_repository_missing").is_err());
+
+        repository.delete("first").unwrap();
+        repository.delete("first").unwrap();
+        assert!(repository.list().unwrap().is_empty());
+        assert_eq!(repository.get("first").unwrap(), None);
+    }
+
+    #[test]
+    fn test_warm_queries() {
+        remove_dir_all_ignore_error("test_indices/test_warm_queries");
+
+        let store = make_test_store("test_indices/test_warm_queries");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let warm_query = Query::term(title_field, Term::from_string("hello"));
+        store.register_warm_query("hello", Query::term(title_field, Term::from_string("hello"))).unwrap();
+        assert!(store.warm_queries.find(&store.reader(), &warm_query).is_some());
+
+        // Searches that contain the warm query should use its cached matches
+        let query = Query::Filter {
+            query: Box::new(Query::term(body_field, Term::from_string("lorem"))),
+            filter: Box::new(Query::term(title_field, Term::from_string("hello"))),
+        };
+        assert_eq!(store.reader().count(&query).unwrap(), 1);
+
+        // The warm query should be run on new segments as soon as they're written
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+        indexed_fields.insert(body_field, vec![Token { term: Term::from_string("lorem"), position: 1, offsets: None }].into());
+        store.insert_or_update_document(&Document {
+            key: "new_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+
+        let index_reader = store.reader();
+        assert!(store.warm_queries.find(&index_reader, &warm_query).is_some());
+        assert_eq!(index_reader.count(&query).unwrap(), 2);
+
+        let mut collector = TopScoreCollector::new(10);
+        index_reader.search(&mut collector, &query).unwrap();
+        assert_eq!(collector.into_sorted_vec().len(), 2);
+
+        // Other queries aren't cached
+        assert!(store.warm_queries.find(&index_reader, &query).is_none());
+
+        assert!(store.unregister_warm_query("hello"));
+        assert!(!store.unregister_warm_query("hello"));
+        assert!(store.warm_queries.find(&index_reader, &warm_query).is_none());
+        assert_eq!(index_reader.count(&query).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_group_commit() {
+        use std::thread;
+        use std::sync::Barrier;
+        use super::GroupCommitConfig;
+
+        remove_dir_all_ignore_error("test_indices/test_group_commit");
+
+        make_test_store("test_indices/test_group_commit");
+
+        let mut store = RocksDBStore::open("test_indices/test_group_commit").unwrap();
+        store.set_group_commit(GroupCommitConfig {
+            commit_interval: Duration::from_millis(50),
+            max_batch_size: 100,
+        });
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+
+        let store = Arc::new(store);
+        let barrier = Arc::new(Barrier::new(8));
+
+        let threads = (0..8).map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                let mut indexed_fields = FnvHashMap::default();
+                indexed_fields.insert(title_field, vec![Token { term: Term::from_string("grouped"), position: 1, offsets: None }].into());
+
+                barrier.wait();
+                store.insert_or_update_document(&Document {
+                    key: format!("grouped_doc_{}", i),
+                    indexed_fields: indexed_fields,
+                    stored_fields: FnvHashMap::default(),
+                    rank_features: FnvHashMap::default(),
+                    completions: FnvHashMap::default(),
+                    source: None,
+                    routing: None,
+                })
+            })
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+
+        // Every document must be searchable once its insert has returned
+        let index_reader = store.reader();
+        assert_eq!(index_reader.count(&Query::term(title_field, Term::from_string("grouped"))).unwrap(), 8);
+
+        // The documents should share segments rather than getting one each
+        let num_segments = store.segments.iter_active(&index_reader).count();
+        assert!(num_segments < 2 + 8, "expected documents to be grouped, got {} segments", num_segments);
+    }
+
+    #[test]
+    fn test_indexer() {
+        use super::{Indexer, IndexerConfig};
+
+        remove_dir_all_ignore_error("test_indices/test_indexer");
+
+        let store = Arc::new(make_test_store("test_indices/test_indexer"));
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+
+        let make_doc = |key: String| {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(title_field, vec![Token { term: Term::from_string("queued"), position: 1, offsets: None }].into());
+
+            Document {
+                key: key,
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }
+        };
+
+        let indexer = Indexer::new(store.clone(), IndexerConfig {
+            queue_size: 4,
+            max_batch_size: 100,
+        });
+
+        // Submitting more documents than fit in the queue blocks rather than failing
+        let handles = (0..20).map(|i| indexer.submit(make_doc(format!("queued_doc_{}", i))).unwrap()).collect::<Vec<_>>();
+        for handle in handles {
+            handle.wait().unwrap();
+        }
+
+        assert_eq!(store.reader().count(&Query::term(title_field, Term::from_string("queued"))).unwrap(), 20);
+
+        // Dropping the indexer commits anything still in the queue
+        let handle = indexer.submit(make_doc("last_queued_doc".to_string())).unwrap();
+        drop(indexer);
+        assert!(handle.try_wait().unwrap().is_ok());
+        assert_eq!(store.reader().count(&Query::term(title_field, Term::from_string("queued"))).unwrap(), 21);
+    }
+
+    #[test]
+    fn test_indexer_queue_full() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll, Wake, Waker};
+        use std::thread::{self, Thread};
+        use super::{Indexer, IndexerConfig, IndexerError};
+
+        /// Blocks the indexer thread while the test holds the lock
+        struct GateListener(Arc<Mutex<()>>);
+
+        impl IndexListener for GateListener {
+            fn document_indexed(&self, _key: &str) {
+                // Waits until the gate is opened
+                drop(self.0.lock().unwrap());
+            }
+        }
+
+        struct ThreadWaker(Thread);
+
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        remove_dir_all_ignore_error("test_indices/test_indexer_queue_full");
+
+        let mut store = make_test_store("test_indices/test_indexer_queue_full");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let gate = Arc::new(Mutex::new(()));
+        store.add_listener(Arc::new(GateListener(gate.clone())));
+        let store = Arc::new(store);
+
+        let make_doc = |key: String| {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(title_field, vec![Token { term: Term::from_string("gated"), position: 1, offsets: None }].into());
+
+            Document {
+                key: key,
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }
+        };
+
+        let indexer = Indexer::new(store.clone(), IndexerConfig {
+            queue_size: 2,
+            max_batch_size: 1,
+        });
+
+        // The indexer thread takes one document then blocks, so the first document and two
+        // queued documents are accepted at most
+        let closed_gate = gate.lock().unwrap();
+        let mut handles = Vec::new();
+        let mut error = None;
+        for i in 0..10 {
+            match indexer.try_submit(make_doc(format!("gated_doc_{}", i))) {
+                Ok(handle) => handles.push(handle),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        match error {
+            Some(IndexerError::QueueFull) => {}
+            error => panic!("expected the queue to be full, got {:?}", error),
+        }
+        assert!(handles.len() >= 2 && handles.len() <= 3, "expected 2 or 3 documents to be accepted, got {}", handles.len());
+
+        // The handles are futures that are woken by the indexer thread
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut handles[0]).poll(&mut cx).is_pending());
+        assert!(handles[1].try_wait().is_none());
+
+        drop(closed_gate);
+        let num_docs = handles.len() as u64;
+        for mut handle in handles {
+            loop {
+                match Pin::new(&mut handle).poll(&mut cx) {
+                    Poll::Ready(result) => {
+                        result.unwrap();
+                        break;
+                    }
+                    Poll::Pending => thread::park(),
+                }
+            }
+        }
+
+        assert_eq!(store.reader().count(&Query::term(title_field, Term::from_string("gated"))).unwrap(), num_docs);
+    }
+
+    #[test]
+    fn test_conjunction_and_exclusion() {
+        remove_dir_all_ignore_error("test_indices/test_conjunction_and_exclusion");
+
+        make_test_store("test_indices/test_conjunction_and_exclusion");
+
+        let store = RocksDBStore::open("test_indices/test_conjunction_and_exclusion").unwrap();
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+
+        let index_reader = store.reader();
+
+        let query = Query::Conjunction {
+            queries: vec![
+                Query::term(body_field, Term::from_string("lorem")),
+                Query::term(title_field, Term::from_string("howdy")),
+            ]
+        };
+
+        let mut collector = TotalCountCollector::new();
+        index_reader.search(&mut collector, &query).unwrap();
+        assert_eq!(collector.get_total_count(), 1);
+
+        let query = Query::Exclude {
+            query: Box::new(Query::term(body_field, Term::from_string("lorem"))),
+            exclude: Box::new(Query::term(title_field, Term::from_string("howdy"))),
+        };
+
+        let mut collector = TotalCountCollector::new();
+        index_reader.search(&mut collector, &query).unwrap();
+        assert_eq!(collector.get_total_count(), 1);
+    }
+
+    /// Counts the documents passed into a TopScoreCollector
+    struct CountingCollector(TopScoreCollector, usize);
+
+    impl Collector for CountingCollector {
+        fn needs_score(&self) -> bool {
+            true
+        }
+
+        fn collect(&mut self, doc: DocumentMatch) {
+            self.1 += 1;
+            self.0.collect(doc);
+        }
+
+        fn min_competitive_score(&self) -> Option<f32> {
+            self.0.min_competitive_score()
+        }
+    }
+
+    /// Counts the documents passed into a TopFieldCollector
+    struct CountingFieldCollector(TopFieldCollector, usize);
+
+    impl Collector for CountingFieldCollector {
+        fn needs_score(&self) -> bool {
+            false
+        }
+
+        fn collect(&mut self, doc: DocumentMatch) {
+            self.1 += 1;
+            self.0.collect(doc);
+        }
+
+        fn sort_field(&self) -> Option<FieldId> {
+            self.0.sort_field()
+        }
+
+        fn sort_order(&self) -> Option<SortOrder> {
+            self.0.sort_order()
+        }
+
+        fn is_competitive_sort_value(&self, sort_value: Option<i64>) -> bool {
+            self.0.is_competitive_sort_value(sort_value)
+        }
+    }
+
+    #[test]
+    fn test_index_sort() {
+        remove_dir_all_ignore_error("test_indices/test_index_sort");
+
+        let mut store = RocksDBStore::create("test_indices/test_index_sort").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let pk_field = store.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+        store.set_index_sort(pk_field, SortOrder::Descending).unwrap();
+
+        let mut segments = Vec::new();
+        for pk in vec![3, 9, 1, 7, 5] {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+
+            let mut stored_fields = FnvHashMap::default();
+            stored_fields.insert(pk_field, FieldValue::Integer(pk));
+
+            store.insert_or_update_document(&Document {
+                key: format!("doc_{}", pk),
+                indexed_fields: indexed_fields,
+                stored_fields: stored_fields,
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+            segments.push(segments.len() as u32 + 1);
+        }
+
+        // Merged documents should be written in order of their pk, highest first
+        let merged_segment = store.merge_segments(&segments).unwrap();
+        store.purge_segments(&segments).unwrap();
+
+        let index_reader = store.reader();
+        let pks = (0..5).map(|ord| {
+            match index_reader.read_stored_field(pk_field, DocId(SegmentId(merged_segment), ord)) {
+                Ok(Some(FieldValue::Integer(pk))) => pk,
+                _ => panic!("expected a pk"),
+            }
+        }).collect::<Vec<_>>();
+        assert_eq!(pks, vec![9, 7, 5, 3, 1]);
+
+        // The search should stop reading the segment once the collector is full
+        let query = Query::term(title_field, Term::from_string("hello"));
+        let mut collector = CountingFieldCollector(TopFieldCollector::new(pk_field, SortOrder::Descending, 2), 0);
+        index_reader.search(&mut collector, &query).unwrap();
+        assert_eq!(collector.1, 2);
+        let docs = collector.0.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.sort_value()).collect::<Vec<_>>(), vec![Some(9), Some(7)]);
+
+        // Sorting the other way can't stop early
+        let mut collector = CountingFieldCollector(TopFieldCollector::new(pk_field, SortOrder::Ascending, 2), 0);
+        index_reader.search(&mut collector, &query).unwrap();
+        assert_eq!(collector.1, 5);
+        let docs = collector.0.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.sort_value()).collect::<Vec<_>>(), vec![Some(1), Some(3)]);
+        drop(index_reader);
+
+        // The index sort is saved with the store
+        drop(store);
+        let store = RocksDBStore::open("test_indices/test_index_sort").unwrap();
+        assert_eq!(store.index_sort().map(|index_sort| index_sort.order), Some(SortOrder::Descending));
+    }
+
+    #[test]
+    fn test_wand_top_k() {
+        remove_dir_all_ignore_error("test_indices/test_wand_top_k");
+
+        let mut store = RocksDBStore::create("test_indices/test_wand_top_k").unwrap();
+        let body_field = store.add_field("body".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        // Enough documents to fill a few postings blocks, with varying term frequencies
+        for i in 0..500 {
+            let mut tokens = Vec::new();
+            for position in 0..(i % 7) {
+                tokens.push(Token { term: Term::from_string("lorem"), position: position, offsets: None });
+            }
+            if i % 3 == 0 {
+                tokens.push(Token { term: Term::from_string("ipsum"), position: 10, offsets: None });
+            }
+            for position in 0..(i % 11) {
+                tokens.push(Token { term: Term::from_string("padding"), position: 20 + position, offsets: None });
+            }
+
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(body_field, tokens.into());
+
+            store.insert_or_update_document(&Document {
+                key: i.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        }
+
+        store.merge_segments(&(1..501).collect()).unwrap();
+        store.purge_segments(&(1..501).collect()).unwrap();
+
+        let index_reader = store.reader();
+
+        let query = Query::Disjunction {
+            queries: vec![
+                Query::term(body_field, Term::from_string("lorem")),
+                Query::term(body_field, Term::from_string("ipsum")),
+            ]
+        };
+
+        // Pruned search
+        let mut collector = CountingCollector(TopScoreCollector::new(10), 0);
+        index_reader.search(&mut collector, &query).unwrap();
+        let docs_collected = collector.1;
+        let docs = collector.0.into_sorted_vec();
+
+        // Exhaustive search
+        let mut expected_collector = TopScoreCollector::new(10);
+        for doc in index_reader.search_iter(&query, true).unwrap() {
+            expected_collector.collect(doc.unwrap());
+        }
+        let expected_docs = expected_collector.into_sorted_vec();
+
+        // Documents that couldn't make the top 10 should've been skipped
+        let mut total_matches = TotalCountCollector::new();
+        index_reader.search(&mut total_matches, &query).unwrap();
+        assert!((docs_collected as u64) < total_matches.get_total_count());
+
+        assert_eq!(docs.len(), 10);
+        assert_eq!(docs.iter().map(|doc| doc.score()).collect::<Vec<_>>(), expected_docs.iter().map(|doc| doc.score()).collect::<Vec<_>>());
+
+        // Tracking the total hits exactly prevents documents from being skipped
+        let mut collector = TopScoreCollector::new(10);
+        collector.set_track_total_hits(TrackTotalHits::Exact);
+        index_reader.search(&mut collector, &query).unwrap();
+        assert_eq!(collector.total_hits(), TotalHits::Exact(total_matches.get_total_count()));
+        assert_eq!(collector.into_sorted_vec().iter().map(|doc| doc.score()).collect::<Vec<_>>(), expected_docs.iter().map(|doc| doc.score()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_block_max_conjunction_top_k() {
+        remove_dir_all_ignore_error("test_indices/test_block_max_conjunction_top_k");
+
+        let mut store = RocksDBStore::create("test_indices/test_block_max_conjunction_top_k").unwrap();
+        let body_field = store.add_field("body".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        // The documents in the first postings block have a higher frequency of "lorem" so,
+        // once the top documents have been found, the later blocks can be skipped
+        for i in 0..500 {
+            let mut tokens = Vec::new();
+            for position in 0..(if i < 100 { 5 } else { 1 }) {
+                tokens.push(Token { term: Term::from_string("lorem"), position: position, offsets: None });
+            }
+            tokens.push(Token { term: Term::from_string("ipsum"), position: 10, offsets: None });
+
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(body_field, tokens.into());
+
+            store.insert_or_update_document(&Document {
+                key: i.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        }
+
+        store.merge_segments(&(1..501).collect()).unwrap();
+        store.purge_segments(&(1..501).collect()).unwrap();
+
+        let index_reader = store.reader();
+
+        // Conjunctions can't be run with WAND
+        let query = Query::Conjunction {
+            queries: vec![
+                Query::term(body_field, Term::from_string("lorem")),
+                Query::term(body_field, Term::from_string("ipsum")),
+            ]
+        };
+
+        // Pruned search
+        let mut collector = CountingCollector(TopScoreCollector::new(10), 0);
+        index_reader.search(&mut collector, &query).unwrap();
+        let docs_collected = collector.1;
+        let docs = collector.0.into_sorted_vec();
+
+        // Exhaustive search
+        let mut expected_collector = TopScoreCollector::new(10);
+        for doc in index_reader.search_iter(&query, true).unwrap() {
+            expected_collector.collect(doc.unwrap());
+        }
+        let expected_docs = expected_collector.into_sorted_vec();
+
+        // Documents in the blocks that couldn't make the top 10 should've been skipped
+        assert!(docs_collected < 200);
+
+        assert_eq!(docs.len(), 10);
+        assert_eq!(docs.iter().map(|doc| doc.score()).collect::<Vec<_>>(), expected_docs.iter().map(|doc| doc.score()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_index_writer() {
+        remove_dir_all_ignore_error("test_indices/test_index_writer");
+
+        let store = Arc::new(make_test_store("test_indices/test_index_writer"));
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+
+        let make_doc = |key: &str| {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+            Document {
+                key: key.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }
+        };
+
+        let mut writer = IndexWriter::open(store.clone()).unwrap();
+        match IndexWriter::open(store.clone()) {
+            Err(IndexWriterError::WriterAlreadyOpen) => {}
+            _ => panic!("a second writer was opened"),
+        }
+
+        let index_reader = writer.reader();
+        writer.add_document(&make_doc("first")).unwrap();
+        writer.add_document(&make_doc("second")).unwrap();
+        writer.delete_document("second");
+        writer.delete_document("test_doc");
+        assert_eq!(writer.num_pending(), 4);
+
+        // Nothing is visible until the writer commits
+        assert_eq!(index_reader.searcher().get_document_id("first").unwrap(), None);
+        assert!(index_reader.searcher().get_document_id("test_doc").unwrap().is_some());
+
+        let segment = writer.commit().unwrap().unwrap();
+        assert_eq!(writer.num_pending(), 0);
+
+        let cloned_reader = index_reader.clone();
+        let searcher = cloned_reader.searcher();
+        assert_eq!(searcher.get_document_id("first").unwrap(), Some(DocId(SegmentId(segment), 0)));
+        assert_eq!(searcher.get_document_id("second").unwrap(), None);
+        assert_eq!(searcher.get_document_id("test_doc").unwrap(), None);
+
+        let mut collector = TotalCountCollector::new();
+        searcher.search(&mut collector, &Query::term(title_field, Term::from_string("hello"))).unwrap();
+        assert_eq!(collector.get_total_count(), 1);
+
+        // A commit with nothing to add doesn't write a segment
+        assert_eq!(writer.commit().unwrap(), None);
+
+        // Another writer can be opened once the first is dropped
+        drop(writer);
+        let writer = IndexWriter::open(store.clone());
+        assert!(writer.is_ok());
+    }
+
+    #[test]
+    fn test_typed_fields() {
+        use kite::DocumentBuilder;
+
+        remove_dir_all_ignore_error("test_indices/test_typed_fields");
+
+        let mut store = make_test_store("test_indices/test_typed_fields");
+        let year_field = store.add_typed_field::<i64>("year".to_string(), FIELD_INDEXED).unwrap();
+
+        let doc = DocumentBuilder::new(&store.schema, "typed_doc").add(year_field, 2017).build().unwrap();
+        store.insert_or_update_document(&doc).unwrap();
+
+        let index_reader = store.reader();
+        let mut collector = TotalCountCollector::new();
+        index_reader.search(&mut collector, &Query::typed_term(year_field, 2017)).unwrap();
+        assert_eq!(collector.get_total_count(), 1);
+
+        let mut collector = TotalCountCollector::new();
+        index_reader.search(&mut collector, &Query::typed_term(year_field, 2018)).unwrap();
+        assert_eq!(collector.get_total_count(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_reads_and_writes() {
+        use std::thread;
+        use std::sync::Barrier;
+
+        remove_dir_all_ignore_error("test_indices/test_concurrent_reads_and_writes");
+
+        let store = Arc::new(make_test_store("test_indices/test_concurrent_reads_and_writes"));
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let barrier = Arc::new(Barrier::new(8));
+
+        let writers = (0..4).map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+                for j in 0..50 {
+                    let mut indexed_fields = FnvHashMap::default();
+                    indexed_fields.insert(title_field, vec![Token { term: Term::from_string("stress"), position: 1, offsets: None }].into());
+
+                    store.insert_or_update_document(&Document {
+                        key: format!("stress_doc_{}_{}", i, j),
+                        indexed_fields: indexed_fields,
+                        stored_fields: FnvHashMap::default(),
+                        rank_features: FnvHashMap::default(),
+                        completions: FnvHashMap::default(),
+                        source: None,
+                        routing: None,
+                    }).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        let readers = (0..4).map(|_| {
+            let index_reader = IndexReader::new(store.clone());
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                let query = Query::term(title_field, Term::from_string("stress"));
+                barrier.wait();
+                let mut last_count = 0;
+                for _ in 0..50 {
+                    // Each search sees a consistent view that only grows as documents are committed
+                    let count = index_reader.searcher().count(&query).unwrap();
+                    assert!(count >= last_count && count <= 200, "unexpected count {} after {}", count, last_count);
+                    last_count = count;
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for thread in writers.into_iter().chain(readers) {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(store.reader().count(&Query::term(title_field, Term::from_string("stress"))).unwrap(), 200);
+    }
+
+    #[test]
+    fn test_reader_shared_between_threads() {
+        use std::thread;
+
+        remove_dir_all_ignore_error("test_indices/test_reader_shared_between_threads");
+
+        let store = make_test_store("test_indices/test_reader_shared_between_threads");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let query = Query::term(title_field, Term::from_string("hello"));
+
+        let reader = store.reader();
+        store.remove_document_by_key("test_doc").unwrap();
+
+        // Every thread searches the same reader, none of them see the delete
+        thread::scope(|scope| {
+            let threads = (0..4).map(|_| scope.spawn(|| reader.count(&query).unwrap())).collect::<Vec<_>>();
+
+            for thread in threads {
+                assert_eq!(thread.join().unwrap(), 1);
+            }
+        });
+
+        // A reader can be moved to another thread too
+        let count = thread::scope(|scope| scope.spawn(move || reader.count(&query).unwrap()).join().unwrap());
+        assert_eq!(count, 1);
+        assert_eq!(store.reader().count(&Query::term(title_field, Term::from_string("hello"))).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_bulk() {
+        use serde_json;
+
+        remove_dir_all_ignore_error("test_indices/test_bulk");
+
+        let store = make_test_store("test_indices/test_bulk");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let pk_field = store.schema.get_field_by_name("pk").unwrap();
+        let config = BulkConfig { batch_size: 2, ..BulkConfig::default() };
+
+        let response = store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n",
+            "{\"title\": \"Hello bulk\", \"pk\": 1}\n",
+            "{\"create\": {\"_id\": \"a\"}}\n",
+            "{\"title\": \"Conflict\"}\n",
+            "{\"update\": {\"_id\": \"a\"}}\n",
+            "{\"doc\": {\"pk\": 2}}\n",
+            "{\"create\": {\"_id\": \"b\"}}\n",
+            "{\"title\": \"Bulk two\"}\n",
+            "{\"delete\": {\"_id\": \"b\"}}\n",
+            "{\"delete\": {\"_id\": \"missing\"}}\n",
+            "{\"update\": {\"_id\": \"missing\"}}\n",
+            "{\"doc\": {\"pk\": 3}}\n",
+            "{\"update\": {\"_id\": \"c\"}}\n",
+            "{\"doc\": {\"title\": \"Bulk upsert\"}, \"doc_as_upsert\": true}\n",
+            "{\"index\": {\"_id\": \"d\"}}\n",
+            "{\"title\": \"Bulk\", \"unknown\": 1}\n",
+            "{\"update\": {\"_id\": \"test_doc\"}}\n",
+            "{\"doc\": {\"pk\": 4}}\n",
+            "{\"update\": {\"_id\": \"a\"}}\n",
+            "{\"script\": {\"source\": \"ctx._source.pk += 1\"}}\n",
+        ), &config).unwrap();
+
+        let statuses = response.items.iter().map(|item| item.status()).collect::<Vec<_>>();
+        assert_eq!(statuses, vec![201, 409, 200, 201, 200, 404, 404, 201, 400, 400, 400]);
+        assert!(response.has_errors());
+
+        let json = response.to_json();
+        assert_eq!(json["errors"], true);
+        assert_eq!(json["items"][0], json!({"index": {"_id": "a", "status": 201, "result": "created"}}));
+        assert_eq!(json["items"][1]["create"]["error"]["type"], "version_conflict_engine_exception");
+        assert_eq!(json["items"][5], json!({"delete": {"_id": "missing", "status": 404, "result": "not_found"}}));
+
+        let reader = store.reader();
+        let query = Query::term(title_field, Term::from_string("bulk"));
+        assert_eq!(reader.count(&query).unwrap(), 2);
+        assert_eq!(reader.get_document_id("b").unwrap(), None);
+        let doc_id = reader.get_document_id("a").unwrap().unwrap();
+        match reader.read_stored_field(pk_field, doc_id) {
+            Ok(Some(FieldValue::Integer(2))) => {}
+            value => panic!("expected the update to be applied, got {:?}", value),
+        }
+
+        // Updates merge into the stored source of documents from earlier requests
+        let response = store.bulk("{\"update\": {\"_id\": \"a\"}}\n{\"doc\": {\"pk\": 3}}\n", &config).unwrap();
+        assert!(!response.has_errors());
+
+        let reader = store.reader();
+        let doc_id = reader.get_document_id("a").unwrap().unwrap();
+        match reader.read_stored_field(pk_field, doc_id) {
+            Ok(Some(FieldValue::Integer(3))) => {}
+            value => panic!("expected the update to be applied, got {:?}", value),
+        }
+        let source: serde_json::Value = serde_json::from_slice(&reader.read_source(doc_id).unwrap().unwrap()).unwrap();
+        assert_eq!(source, json!({"title": "Hello bulk", "pk": 3}));
+
+        // Nothing runs if the body can't be parsed
+        assert!(store.bulk("{\"delete\": {\"_id\": \"a\"}}\n{\"index\": {}}\n", &config).is_err());
+        assert!(store.reader().get_document_id("a").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_export_json_lines() {
+        use serde_json;
+
+        remove_dir_all_ignore_error("test_indices/test_export_json_lines");
+
+        let store = make_test_store("test_indices/test_export_json_lines");
+        store.bulk("{\"index\": {\"_id\": \"bulk_doc\", \"routing\": \"user1\"}}\n{\"title\": \"Exported\", \"pk\": 3}\n", &BulkConfig::default()).unwrap();
+
+        let mut output = Vec::new();
+        assert_eq!(store.export_json_lines(&mut output, &ExportConfig::default()).unwrap(), 3);
+
+        let lines = String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect::<Vec<serde_json::Value>>();
+        assert_eq!(lines, vec![
+            json!({"_id": "another_test_doc", "fields": {"pk": 2}}),
+            json!({"_id": "bulk_doc", "_routing": "user1", "_source": {"title": "Exported", "pk": 3}, "fields": {"pk": 3}}),
+            json!({"_id": "test_doc", "fields": {"pk": 1}}),
+        ]);
+
+        // Only the sources
+        let mut output = Vec::new();
+        store.export_json_lines(&mut output, &ExportConfig { include_fields: false, batch_size: 1, ..ExportConfig::default() }).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().lines().nth(1), Some("{\"_id\":\"bulk_doc\",\"_routing\":\"user1\",\"_source\":{\"pk\":3,\"title\":\"Exported\"}}"));
+    }
+
+    #[test]
+    fn test_export_doc_values_arrow() {
+        remove_dir_all_ignore_error("test_indices/test_export_doc_values_arrow");
+
+        let mut store = RocksDBStore::create("test_indices/test_export_doc_values_arrow").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let count_field = store.add_field("count".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+        let flag_field = store.add_field("flag".to_string(), FieldType::Boolean, FIELD_STORED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"title\": \"hello\", \"count\": 1, \"flag\": true}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"title\": \"world\", \"count\": 2}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"title\": \"hello\", \"flag\": false}\n",
+        ), &BulkConfig::default()).unwrap();
+        store.bulk("{\"index\": {\"_id\": \"d\"}}\n{\"title\": \"hello\", \"count\": 4}\n", &BulkConfig::default()).unwrap();
+        store.remove_document_by_key("b").unwrap();
+
+        // Split into two record batches, a stream is a schema, the batches then an end marker
+        let config = ArrowExportConfig { batch_size: 2 };
+        let mut output = Vec::new();
+        assert_eq!(store.export_doc_values_arrow(&mut output, &[count_field, flag_field], &Query::all(), &config).unwrap(), 3);
+        assert_eq!(&output[..4], &[0xFF; 4]);
+        assert_eq!(&output[output.len() - 8..], &[0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]);
+        assert_eq!(output.windows(4).filter(|bytes| bytes == &[0xFF; 4]).count(), 4);
+
+        let mut output = Vec::new();
+        assert_eq!(store.export_doc_values_arrow(&mut output, &[count_field], &Query::term(title_field, Term::from_string("hello")), &config).unwrap(), 3);
+
+        let mut output = Vec::new();
+        assert_eq!(store.export_doc_values_arrow(&mut output, &[count_field], &Query::term(title_field, Term::from_string("world")), &config).unwrap(), 0);
+
+        // Fields without doc values can't be exported
+        match store.export_doc_values_arrow(Vec::new(), &[count_field, title_field], &Query::all(), &config) {
+            Err(ExportError::InvalidField(field_id)) => assert_eq!(field_id, title_field),
+            result => panic!("expected an invalid field error, got {:?}", result),
+        }
+        match store.export_doc_values_arrow(Vec::new(), &[FieldId(100)], &Query::all(), &config) {
+            Err(ExportError::InvalidField(FieldId(100))) => {}
+            result => panic!("expected an invalid field error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_read_stored_field() {
+        use chrono::{DateTime, Utc};
+
+        remove_dir_all_ignore_error("test_indices/test_read_stored_field");
+
+        let mut store = RocksDBStore::create("test_indices/test_read_stored_field").unwrap();
+        let mut fields = Vec::new();
+        for &(name, ref field_type) in &[("title", FieldType::Text), ("tag", FieldType::PlainString), ("count", FieldType::I64), ("flag", FieldType::Boolean), ("born", FieldType::DateTime), ("category", FieldType::Facet), ("popularity", FieldType::RankFeature)] {
+            fields.push(store.add_field(name.to_string(), field_type.clone(), FIELD_INDEXED | FIELD_STORED).unwrap());
+        }
+
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"doc\"}}\n",
+            "{\"title\": \"Hello world\", \"tag\": \"a\", \"count\": -3, \"flag\": true, \"born\": \"1960-05-01T10:00:00.25Z\", \"category\": \"books/fiction\", \"popularity\": 2.5}\n",
+        ), &BulkConfig::default()).unwrap();
+
+        let reader = store.reader();
+        let doc_id = reader.get_document_id("doc").unwrap().unwrap();
+        let values = fields[..6].iter().map(|field| reader.read_stored_field(*field, doc_id).unwrap().unwrap()).collect::<Vec<_>>();
+        match &values[..] {
+            &[FieldValue::String(ref title), FieldValue::String(ref tag), FieldValue::Integer(-3), FieldValue::Boolean(true), FieldValue::DateTime(born), FieldValue::String(ref category)] => {
+                assert_eq!(title, "Hello world");
+                assert_eq!(tag, "a");
+                assert_eq!(born, DateTime::parse_from_rfc3339("1960-05-01T10:00:00.25Z").unwrap().with_timezone(&Utc));
+                assert_eq!(category, "books/fiction");
+            }
+            values => panic!("unexpected values {:?}", values),
+        }
+
+        // Fields that are kept elsewhere or don't exist
+        match reader.read_stored_field(fields[6], doc_id) {
+            Err(StoredFieldReadError::FieldTypeNotStored(FieldType::RankFeature)) => {}
+            value => panic!("expected a not stored error, got {:?}", value),
+        }
+        match reader.read_stored_field(FieldId(100), doc_id) {
+            Err(StoredFieldReadError::InvalidFieldId(FieldId(100))) => {}
+            value => panic!("expected an invalid field id error, got {:?}", value),
+        }
+        assert!(reader.get_document("doc").unwrap().is_some());
+
+        // Corrupt values
+        let corrupt = |field: FieldId, value: &[u8]| {
+            let kb = KeyBuilder::stored_field_value((doc_id.0).0, doc_id.1, field.0, b"val");
+            store.db.put(kb.key(), value).unwrap();
+            store.reader().read_stored_field(field, doc_id)
+        };
+        match corrupt(fields[0], &[0xff, 0xfe]) {
+            Err(StoredFieldReadError::TextFieldUTF8DecodeError(ref value, _)) if value == &[0xff, 0xfe] => {}
+            value => panic!("expected a UTF-8 error, got {:?}", value),
+        }
+        match corrupt(fields[2], &[1, 2, 3]) {
+            Err(StoredFieldReadError::IntegerFieldValueSizeError(3)) => {}
+            value => panic!("expected a size error, got {:?}", value),
+        }
+        match corrupt(fields[3], b"x") {
+            Err(StoredFieldReadError::BooleanFieldDecodeError(ref value)) if value == b"x" => {}
+            value => panic!("expected a boolean error, got {:?}", value),
+        }
+        match corrupt(fields[4], &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]) {
+            Err(StoredFieldReadError::DateTimeFieldOutOfRange(_)) => {}
+            value => panic!("expected an out of range error, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn test_multi_term_rewrite() {
+        use kite::MultiTermRewrite;
+
+        remove_dir_all_ignore_error("test_indices/test_multi_term_rewrite");
+
+        let mut store = RocksDBStore::create("test_indices/test_multi_term_rewrite").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"title\": \"help\"}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"title\": \"help helium\"}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"title\": \"hello\"}\n",
+            "{\"index\": {\"_id\": \"d\"}}\n{\"title\": \"help\"}\n",
+            "{\"index\": {\"_id\": \"e\"}}\n{\"title\": \"world\"}\n",
+        ), &BulkConfig::default()).unwrap();
+
+        let reader = store.reader();
+        let query = || Query::prefix(title_field, "hel");
+        assert_eq!(reader.count(&query()).unwrap(), 4);
+
+        // The first terms are "helium" and "hello"
+        assert_eq!(reader.count(&query().max_expansions(Some(1))).unwrap(), 1);
+        assert_eq!(reader.count(&query().max_expansions(Some(2))).unwrap(), 2);
+        assert_eq!(reader.count(&query().max_expansions(Some(0))).unwrap(), 0);
+        assert_eq!(reader.count(&query().max_expansions(None)).unwrap(), 4);
+
+        // "help" is in the most documents
+        assert_eq!(reader.count(&query().rewrite(MultiTermRewrite::TopTermsByDocFrequency(1))).unwrap(), 3);
+        assert_eq!(reader.count(&query().rewrite(MultiTermRewrite::TopTermsByDocFrequency(5)).max_expansions(Some(1))).unwrap(), 4);
+
+        let scores = |query: Query| {
+            let mut collector = TopScoreCollector::new(10);
+            reader.search(&mut collector, &query).unwrap();
+            collector.into_sorted_vec().iter().map(|doc| doc.score().unwrap()).collect::<Vec<f32>>()
+        };
+        assert_eq!(scores(query().rewrite(MultiTermRewrite::ConstantScore).boost(2.0)), vec![2.0; 4]);
+        assert!(scores(query()).iter().all(|score| *score > 0.0 && *score != 2.0));
+    }
+
+    #[test]
+    fn test_verify_query() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        remove_dir_all_ignore_error("test_indices/test_verify_query");
+
+        let mut store = RocksDBStore::create("test_indices/test_verify_query").unwrap();
+        let colour_field = store.add_field("colour".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let pk_field = store.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+
+        let mut body = String::new();
+        for pk in 0..10 {
+            let colour = if pk < 4 { "red" } else { "blue" };
+            body.push_str(&format!("{{\"index\": {{\"_id\": \"doc{}\"}}}}\n{{\"colour\": \"{}\", \"pk\": {}}}\n", pk, colour, pk));
+        }
+        store.bulk(&body, &BulkConfig::default()).unwrap();
+
+        let verified = Arc::new(AtomicUsize::new(0));
+        let even_pk = || {
+            let verified = verified.clone();
+            Query::all().verify(move |doc_values| {
+                verified.fetch_add(1, Ordering::SeqCst);
+                doc_values.get_i64(pk_field).map_or(false, |pk| pk % 2 == 0)
+            })
+        };
+        let red = || Query::term(colour_field, Term::from_string("red"));
+
+        let reader = store.reader();
+        assert_eq!(reader.count(&even_pk()).unwrap(), 5);
+        assert_eq!(verified.swap(0, Ordering::SeqCst), 10);
+
+        // Only the documents that match the other side of the conjunction are verified
+        assert_eq!(reader.count(&red().and(even_pk())).unwrap(), 2);
+        assert_eq!(verified.swap(0, Ordering::SeqCst), 4);
+        assert_eq!(reader.count(&even_pk().filter(red())).unwrap(), 2);
+        assert_eq!(verified.swap(0, Ordering::SeqCst), 4);
+        assert_eq!(reader.count(&even_pk().must_not(red())).unwrap(), 3);
+        assert_eq!(verified.swap(0, Ordering::SeqCst), 6);
+
+        // Verified queries can be negated and combined in disjunctions
+        assert_eq!(reader.count(&red().must_not(even_pk())).unwrap(), 2);
+        assert_eq!(reader.count(&red().or(even_pk())).unwrap(), 7);
+
+        // Documents are scored by the approximation
+        let mut collector = TopScoreCollector::new(10);
+        reader.search(&mut collector, &red().verify(|_| true)).unwrap();
+        let mut plain_collector = TopScoreCollector::new(10);
+        reader.search(&mut plain_collector, &red()).unwrap();
+        assert_eq!(collector.into_sorted_vec().iter().map(|doc| doc.score()).collect::<Vec<_>>(),
+                   plain_collector.into_sorted_vec().iter().map(|doc| doc.score()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_range_query() {
+        use chrono::{TimeZone, Utc};
+
+        remove_dir_all_ignore_error("test_indices/test_range_query");
+
+        let mut store = RocksDBStore::create("test_indices/test_range_query").unwrap();
+        let timestamp_field = store.add_field("timestamp".to_string(), FieldType::DateTime, FIELD_STORED).unwrap();
+        let count_field = store.add_field("count".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+
+        // Each day is written into its own segment
+        for day in 1..4 {
+            let mut body = String::new();
+            for hour in 0..4 {
+                let timestamp = Utc.ymd(2020, 1, day).and_hms(hour * 6, 0, 0).to_rfc3339();
+                body.push_str(&format!("{{\"index\": {{\"_id\": \"{}-{}\"}}}}\n{{\"timestamp\": \"{}\"}}\n", day, hour, timestamp));
+            }
+            if day == 2 {
+                body.push_str("{\"index\": {\"_id\": \"counted\"}}\n{\"count\": 5}\n");
+            }
+            store.bulk(&body, &BulkConfig::default()).unwrap();
+        }
+
+        let reader = store.reader();
+        let day = |day| Utc.ymd(2020, 1, day).and_hms(0, 0, 0);
+        assert_eq!(reader.count(&Query::datetime_range(timestamp_field, Some(day(2)), None)).unwrap(), 8);
+        assert_eq!(reader.count(&Query::datetime_range(timestamp_field, Some(day(2)), Some(day(3)))).unwrap(), 5);
+        assert_eq!(reader.count(&Query::datetime_range(timestamp_field, None, Some(day(1)))).unwrap(), 1);
+        assert_eq!(reader.count(&Query::datetime_range(timestamp_field, Some(day(5)), None)).unwrap(), 0);
+        assert_eq!(reader.count(&Query::datetime_range(timestamp_field, None, None)).unwrap(), 12);
+        assert_eq!(reader.count(&Query::range(count_field, Some(5), Some(5))).unwrap(), 1);
+        assert_eq!(reader.count(&Query::range(count_field, Some(6), None)).unwrap(), 0);
+
+        // Matches are given a constant score
+        let mut collector = TopScoreCollector::new(10);
+        reader.search(&mut collector, &Query::range(count_field, None, None).boost(2.0)).unwrap();
+        assert_eq!(collector.into_sorted_vec().iter().map(|doc| doc.score().unwrap()).collect::<Vec<_>>(), vec![2.0]);
+
+        // Segments that can't have a match are skipped without reading their values, so this
+        // bogus value in the first day's segment is never seen
+        let kb = KeyBuilder::segment_doc_values_column(1, timestamp_field.0);
+        store.db.put(kb.key(), &::doc_values::encode_doc_values_column(&[Some(day(3).timestamp() * 1000000)])).unwrap();
+        let reader = store.reader();
+        assert_eq!(reader.count(&Query::datetime_range(timestamp_field, Some(day(3)), Some(day(3)))).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_range_query_indexed_field() {
+        use chrono::{TimeZone, Utc, Duration};
+
+        remove_dir_all_ignore_error("test_indices/test_range_query_indexed_field");
+
+        // These fields aren't stored so they don't have doc values, their terms are searched instead
+        let mut store = RocksDBStore::create("test_indices/test_range_query_indexed_field").unwrap();
+        let published_field = store.add_field("published".to_string(), FieldType::DateTime, FIELD_INDEXED).unwrap();
+        let count_field = store.add_field("count".to_string(), FieldType::I64, FIELD_INDEXED).unwrap();
+
+        let now = Utc.ymd(2020, 3, 1).and_hms(12, 0, 0);
+        let mut body = String::new();
+        for days_ago in 0..10 {
+            let published = (now - Duration::days(days_ago * 10)).to_rfc3339();
+            body.push_str(&format!("{{\"index\": {{\"_id\": \"doc{}\"}}}}\n{{\"published\": \"{}\", \"count\": {}}}\n", days_ago, published, days_ago - 5));
+        }
+        store.bulk(&body, &BulkConfig::default()).unwrap();
+
+        let reader = store.reader();
+        assert_eq!(reader.count(&Query::datetime_range(published_field, Some(now - Duration::days(30)), Some(now))).unwrap(), 4);
+        assert_eq!(reader.count(&Query::datetime_range(published_field, Some(now + Duration::days(1)), None)).unwrap(), 0);
+        assert_eq!(reader.count(&Query::datetime_range(published_field, None, None)).unwrap(), 10);
+        assert_eq!(reader.count(&Query::range(count_field, Some(-2), Some(2))).unwrap(), 5);
+        assert_eq!(reader.count(&Query::range(count_field, None, Some(-5))).unwrap(), 1);
+
+        // Terms of other fields with the same values aren't matched
+        assert_eq!(reader.count(&Query::range(count_field, Some(1000000), None)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_range_query_seeks_field_terms() {
+        remove_dir_all_ignore_error("test_indices/test_range_query_seeks_field_terms");
+
+        let mut store = RocksDBStore::create("test_indices/test_range_query_seeks_field_terms").unwrap();
+        let count_field = store.add_field("count".to_string(), FieldType::I64, FIELD_INDEXED).unwrap();
+        let other_count_field = store.add_field("other_count".to_string(), FieldType::I64, FIELD_INDEXED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"doc1\"}}\n{\"count\": -3, \"other_count\": 5}\n",
+            "{\"index\": {\"_id\": \"doc2\"}}\n{\"count\": 7, \"other_count\": 500}\n",
+            "{\"index\": {\"_id\": \"doc3\"}}\n{\"count\": 300}\n",
+        ), &BulkConfig::default()).unwrap();
+
+        // Only the field's own terms in the range are selected, in value order
+        let reader = store.reader();
+        let select = |field, min, max| {
+            let selector = MultiTermSelector::IntegerRange { min: min, max: max };
+            reader.select_integer_terms_until(field, &selector, || false)
+        };
+        let term_id = |value| store.term_dictionary.get(&Term::from_integer(value)).unwrap();
+        assert_eq!(select(count_field, None, None), vec![term_id(-3), term_id(7), term_id(300)]);
+        assert_eq!(select(count_field, Some(-3), Some(7)), vec![term_id(-3), term_id(7)]);
+        assert_eq!(select(count_field, Some(8), Some(299)), vec![]);
+        assert_eq!(select(other_count_field, Some(0), None), vec![term_id(5), term_id(500)]);
+
+        // The range stops early once the search is cancelled
+        assert_eq!(reader.select_integer_terms_until(count_field, &MultiTermSelector::IntegerRange { min: None, max: None }, || true), vec![]);
+    }
+
+    #[test]
+    fn test_migrate_integer_terms() {
+        use chrono::{TimeZone, Utc, Duration};
+
+        remove_dir_all_ignore_error("test_indices/test_migrate_integer_terms");
+
+        let mut store = RocksDBStore::create("test_indices/test_migrate_integer_terms").unwrap();
+        let published_field = store.add_field("published".to_string(), FieldType::DateTime, FIELD_INDEXED).unwrap();
+        let count_field = store.add_field("count".to_string(), FieldType::I64, FIELD_INDEXED).unwrap();
+        let tag_field = store.add_field("tag".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+        store.set_write_segment_files(true);
+
+        let now = Utc.ymd(2020, 3, 1).and_hms(12, 0, 0);
+        let mut body = String::new();
+        for days_ago in 0..10 {
+            let published = (now - Duration::days(days_ago)).to_rfc3339();
+            body.push_str(&format!("{{\"index\": {{\"_id\": \"doc{}\"}}}}\n{{\"published\": \"{}\", \"count\": {}, \"tag\": \"tag{}\"}}\n", days_ago, published, days_ago - 5, days_ago % 2));
+        }
+        store.bulk(&body, &BulkConfig::default()).unwrap();
+
+        // Put the terms back into the little endian encoding of older versions, which didn't
+        // have ordered indexes of integer terms
+        let old_term = |value: i64| {
+            let mut bytes = vec![0; 8];
+            LittleEndian::write_i64(&mut bytes, value);
+            bytes
+        };
+        let mut write_batch = WriteBatch::default();
+        for (key, value) in KeyIterator::new(store.db.raw_iterator(), KeyBuilder::all_integer_terms_prefix()) {
+            if let Key::IntegerTerm { ref term, .. } = key {
+                write_batch.delete(KeyBuilder::term_dict_mapping(term).key()).unwrap();
+                write_batch.put(KeyBuilder::term_dict_mapping(&old_term(Term::from_bytes(term).as_integer().unwrap())).key(), &value).unwrap();
+            }
+
+            write_batch.delete(key.key_builder().key()).unwrap();
+        }
+        write_batch.delete(SORTABLE_INTEGER_TERMS).unwrap();
+        store.db.write(write_batch).unwrap();
+        drop(store);
+
+        let store = RocksDBStore::open("test_indices/test_migrate_integer_terms").unwrap();
+        let reader = store.reader();
+        assert_eq!(reader.count(&Query::datetime_range(published_field, Some(now - Duration::days(3)), Some(now))).unwrap(), 4);
+        assert_eq!(reader.count(&Query::range(count_field, Some(-2), Some(2))).unwrap(), 5);
+        assert_eq!(reader.count(&Query::term(count_field, Term::from_integer(4))).unwrap(), 1);
+        assert_eq!(reader.count(&Query::term(tag_field, Term::from_string("tag0"))).unwrap(), 5);
+        assert_eq!(store.term_dictionary.get(&Term::from_bytes(&old_term(4))), None);
+        drop(reader);
+        drop(store);
+
+        // It's only converted once
+        let store = RocksDBStore::open("test_indices/test_migrate_integer_terms").unwrap();
+        assert_eq!(store.reader().count(&Query::range(count_field, Some(-2), Some(2))).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_migrate_integer_terms_shared_with_other_field() {
+        remove_dir_all_ignore_error("test_indices/test_migrate_integer_terms_shared");
+
+        let mut store = RocksDBStore::create("test_indices/test_migrate_integer_terms_shared").unwrap();
+        store.add_field("count".to_string(), FieldType::I64, FIELD_INDEXED).unwrap();
+        store.add_field("tag".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+
+        // The little endian encoding of 0x6867666564636261 is the string "abcdefgh"
+        store.bulk("{\"index\": {\"_id\": \"doc1\"}}\n{\"count\": 7523094288207667809, \"tag\": \"abcdefgh\"}\n", &BulkConfig::default()).unwrap();
+        let term_id = store.term_dictionary.get(&Term::from_integer(0x6867666564636261)).unwrap();
+        let tag_term_id = store.term_dictionary.get(&Term::from_string("abcdefgh")).unwrap();
+
+        // Older versions would have given both the same term, which can't be converted
+        // without breaking the other field
+        let mut write_batch = WriteBatch::default();
+        write_batch.delete(KeyBuilder::term_dict_mapping(Term::from_integer(0x6867666564636261).as_bytes()).key()).unwrap();
+        write_batch.delete(SORTABLE_INTEGER_TERMS).unwrap();
+        store.db.write(write_batch).unwrap();
+        let segment = store.reader().generation.segments()[0];
+        store.db.put(KeyBuilder::segment_dir_list(segment, 1, tag_term_id.0).key(), &store.db.get(KeyBuilder::segment_dir_list(segment, 1, term_id.0).key()).unwrap().unwrap()).unwrap();
+        drop(store);
+
+        match RocksDBStore::open("test_indices/test_migrate_integer_terms_shared") {
+            Err(StoreOpenError::IntegerTermsError(message)) => assert_eq!(message, format!("term {} is used by both integer or date fields and other fields, the index must be rebuilt", tag_term_id.0)),
+            result => panic!("expected an integer terms error, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_exists_query() {
+        remove_dir_all_ignore_error("test_indices/test_exists_query");
+
+        let mut store = RocksDBStore::create("test_indices/test_exists_query").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let pk_field = store.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+        let tag_field = store.add_field("tag".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"title\": \"Hello\", \"pk\": 1}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"title\": \"World\", \"tag\": null}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"pk\": 3, \"tag\": \"red\"}\n",
+        ), &BulkConfig::default()).unwrap();
+        store.bulk("{\"index\": {\"_id\": \"d\"}}\n{\"tag\": \"blue\"}\n", &BulkConfig::default()).unwrap();
+
+        let check = |store: &RocksDBStore, title_count, pk_count, tag_count| {
+            let reader = store.reader();
+            assert_eq!(reader.count(&Query::exists(title_field)).unwrap(), title_count);
+            assert_eq!(reader.count(&Query::exists(pk_field)).unwrap(), pk_count);
+            assert_eq!(reader.count(&Query::exists(tag_field)).unwrap(), tag_count);
+        };
+        check(&store, 2, 2, 2);
+
+        // Documents that are missing a value
+        assert_eq!(store.reader().count(&Query::all().must_not(Query::exists(pk_field))).unwrap(), 2);
+        assert_eq!(store.reader().count(&Query::exists(tag_field).filter(Query::exists(pk_field))).unwrap(), 1);
+
+        // The bitmaps are kept when segments are merged
+        store.merge_segments(&vec![1, 2]).unwrap();
+        store.purge_segments(&vec![1, 2]).unwrap();
+        check(&store, 2, 2, 2);
+
+        store.remove_document_by_key("a").unwrap();
+        check(&store, 1, 1, 2);
+    }
+
+    #[test]
+    fn test_bitmap_format() {
+        use roaring::RoaringBitmap;
+        use kite::bitmap_format::{BitmapDecodeError, encode_roaring_bitmap, decode_roaring_bitmap};
+
+        remove_dir_all_ignore_error("test_indices/test_bitmap_format");
+
+        let mut store = RocksDBStore::create("test_indices/test_bitmap_format").unwrap();
+
+        // The term directories are rewritten underneath the store so they mustn't be cached
+        store.set_term_directory_cache_size(0);
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"title\": \"hello\"}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"title\": \"hello\"}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"title\": \"hello\"}\n",
+        ), &BulkConfig::default()).unwrap();
+        store.bulk("{\"index\": {\"_id\": \"d\"}}\n{\"title\": \"hello\"}\n", &BulkConfig::default()).unwrap();
+
+        let term_directory_keys = || {
+            let prefix = KeyBuilder::field_dir_list_prefix(title_field.0);
+            let mut keys = Vec::new();
+            let mut iter = store.db.raw_iterator();
+            iter.seek(prefix.key());
+            while iter.valid() && iter.key().unwrap().starts_with(prefix.key()) {
+                keys.push(iter.key().unwrap());
+                iter.next();
+            }
+            keys
+        };
+        let query = Query::term(title_field, Term::from_string("hello"));
+
+        // Rewrite the first segment as it would have been written before the formats were
+        // versioned: plain roaring term directories and a deletion list with no header
+        for key in term_directory_keys() {
+            if key.ends_with(b"/1") {
+                let term_directory = decode_roaring_bitmap(&store.db.get(&key).unwrap().unwrap()).unwrap();
+                let mut term_directory_bytes = Vec::new();
+                term_directory.serialize_into(&mut term_directory_bytes).unwrap();
+                store.db.put(&key, &term_directory_bytes).unwrap();
+            }
+        }
+        store.db.put(KeyBuilder::segment_del_list(1).key(), b"").unwrap();
+
+        assert_eq!(store.reader().count(&query).unwrap(), 4);
+        store.remove_document_by_key("a").unwrap();
+        store.remove_document_by_key("d").unwrap();
+        assert_eq!(store.reader().count(&query).unwrap(), 2);
+
+        // Merging rewrites both segments in the current format
+        store.merge_segments(&vec![1, 2]).unwrap();
+        store.purge_segments(&vec![1, 2]).unwrap();
+        assert_eq!(store.reader().count(&query).unwrap(), 2);
+        assert_eq!(store.db.get(KeyBuilder::segment_del_list(3).key()).unwrap().unwrap().len(), 5);
+
+        // Bitmaps written by a newer version of the format are reported as errors
+        let keys = term_directory_keys();
+        assert_eq!(keys.len(), 1);
+        let mut term_directory_bytes = encode_roaring_bitmap(&RoaringBitmap::new());
+        term_directory_bytes[2] = 5;
+        store.db.put(&keys[0], &term_directory_bytes).unwrap();
+        assert_eq!(store.reader().count(&query).unwrap_err().to_string(), "search failed: segment is corrupt: term directory of field 1 term 1: unsupported bitmap format version: 5");
+
+        store.bulk("{\"index\": {\"_id\": \"e\"}}\n{\"title\": \"hello\"}\n", &BulkConfig::default()).unwrap();
+        match store.merge_segments(&vec![3, 4]) {
+            Err(SegmentMergeError::BitmapDecodeError(BitmapDecodeError::UnsupportedVersion(5))) => {}
+            result => panic!("expected an unsupported version error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_reopen_escaped_keys() {
+        remove_dir_all_ignore_error("test_indices/test_reopen_escaped_keys");
+
+        {
+            let mut store = RocksDBStore::create("test_indices/test_reopen_escaped_keys").unwrap();
+            store.add_field("url".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+            store.bulk("{\"index\": {\"_id\": \"a/b\\\\c\"}}\n{\"url\": \"http://example.com/\"}\n", &BulkConfig::default()).unwrap();
+        }
+
+        // Primary keys and terms are escaped in the keys they're stored under, so must be
+        // unescaped when they're loaded back into memory
+        let store = RocksDBStore::open("test_indices/test_reopen_escaped_keys").unwrap();
+        let url_field = store.schema.get_field_by_name("url").unwrap();
+        assert!(store.reader().get_document_id("a/b\\c").unwrap().is_some());
+
+        store.bulk("{\"index\": {\"_id\": \"d\"}}\n{\"url\": \"http://example.com/\"}\n", &BulkConfig::default()).unwrap();
+        assert_eq!(store.reader().count(&Query::term(url_field, Term::from_string("http://example.com/"))).unwrap(), 2);
+
+        assert!(store.remove_document_by_key("a/b\\c").unwrap());
+        assert_eq!(store.reader().count(&Query::term(url_field, Term::from_string("http://example.com/"))).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_garbage_report() {
+        use super::{GarbageReportConfig, CompactionAction};
+
+        remove_dir_all_ignore_error("test_indices/test_garbage_report");
+
+        let mut store = RocksDBStore::create("test_indices/test_garbage_report").unwrap();
+        store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"title\": \"Hello\"}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"title\": \"World\"}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"title\": \"Hello world\"}\n",
+            "{\"index\": {\"_id\": \"d\"}}\n{\"title\": \"Goodbye\"}\n",
+        ), &BulkConfig::default()).unwrap();
+        store.bulk("{\"index\": {\"_id\": \"e\"}}\n{\"title\": \"Hello again\"}\n", &BulkConfig::default()).unwrap();
+
+        // Nothing to reclaim yet
+        let report = store.garbage_report(&GarbageReportConfig::default()).unwrap();
+        assert_eq!(report.segments.iter().map(|garbage| (garbage.segment, garbage.active, garbage.total_docs)).collect::<Vec<_>>(), vec![(1, true, 4), (2, true, 1)]);
+        assert_eq!((report.purgeable_bytes, report.deleted_bytes), (0, 0));
+        assert!(report.actions.is_empty());
+
+        // Half of the first segment's documents are deleted
+        store.remove_document_by_key("a").unwrap();
+        store.remove_document_by_key("b").unwrap();
+        let report = store.garbage_report(&GarbageReportConfig::default()).unwrap();
+        let garbage = &report.segments[0];
+        assert_eq!(garbage.deleted_docs, 2);
+        assert!(garbage.deleted_stored_bytes > 0);
+        assert!(garbage.deleted_bytes > garbage.deleted_stored_bytes);
+        assert!(garbage.deleted_bytes < garbage.total_bytes);
+        assert_eq!(report.deleted_bytes, garbage.deleted_bytes);
+        assert_eq!(report.actions, vec![CompactionAction::Reindex]);
+
+        let report = store.garbage_report(&GarbageReportConfig { reindex_deleted_ratio: 0.6 }).unwrap();
+        assert!(report.actions.is_empty());
+
+        // Once merged, the old segments can be purged. The deleted documents are carried over.
+        store.merge_segments(&vec![1, 2]).unwrap();
+        let report = store.garbage_report(&GarbageReportConfig::default()).unwrap();
+        assert_eq!(report.segments.iter().map(|garbage| (garbage.segment, garbage.active, garbage.deleted_docs)).collect::<Vec<_>>(), vec![(1, false, 2), (2, false, 0), (3, true, 2)]);
+        assert_eq!(report.purgeable_bytes, report.segments[0].total_bytes + report.segments[1].total_bytes);
+        assert_eq!(report.deleted_bytes, report.segments[2].deleted_bytes);
+        assert_eq!(report.actions, vec![CompactionAction::Purge(vec![1, 2]), CompactionAction::Reindex]);
+
+        store.purge_segments(&vec![1, 2]).unwrap();
+        let report = store.garbage_report(&GarbageReportConfig::default()).unwrap();
+        assert_eq!(report.segments.iter().map(|garbage| garbage.segment).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(report.purgeable_bytes, 0);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        use std::fmt;
+        use std::thread::{self, ThreadId};
+        use kite::DocumentBuilder;
+        use tracing::{self, Event, Metadata, Subscriber};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+
+        #[derive(Default)]
+        struct Captured {
+            /// The name and parent of each span, a span's id is its index plus one
+            spans: Vec<(&'static str, Option<u64>)>,
+
+            /// The message and span of each event
+            events: Vec<(String, Option<u64>)>,
+
+            /// The spans each thread is in
+            entered: FnvHashMap<ThreadId, Vec<u64>>,
+        }
+
+        impl Captured {
+            fn current_span(&self) -> Option<u64> {
+                self.entered.get(&thread::current().id()).and_then(|entered| entered.last().cloned())
+            }
+        }
+
+        /// Captures the spans and events of the "kite" target
+        struct CapturingSubscriber(Arc<Mutex<Captured>>);
+
+        struct MessageVisitor(String);
+
+        impl Visit for MessageVisitor {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{:?}", value);
+                }
+            }
+        }
+
+        impl Subscriber for CapturingSubscriber {
+            fn enabled(&self, metadata: &Metadata) -> bool {
+                metadata.target() == "kite"
+            }
+
+            fn new_span(&self, attributes: &Attributes) -> Id {
+                let mut captured = self.0.lock().unwrap();
+                let parent = match attributes.parent() {
+                    Some(parent) => Some(parent.into_u64()),
+                    None if attributes.is_contextual() => captured.current_span(),
+                    None => None,
+                };
+                captured.spans.push((attributes.metadata().name(), parent));
+                Id::from_u64(captured.spans.len() as u64)
+            }
+
+            fn record(&self, _span: &Id, _values: &Record) {}
+
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+            fn event(&self, event: &Event) {
+                let mut visitor = MessageVisitor(String::new());
+                event.record(&mut visitor);
+
+                let mut captured = self.0.lock().unwrap();
+                let span = captured.current_span();
+                captured.events.push((visitor.0, span));
+            }
+
+            fn enter(&self, span: &Id) {
+                self.0.lock().unwrap().entered.entry(thread::current().id()).or_insert_with(Vec::new).push(span.into_u64());
+            }
+
+            fn exit(&self, _span: &Id) {
+                self.0.lock().unwrap().entered.entry(thread::current().id()).or_insert_with(Vec::new).pop();
+            }
+        }
+
+        remove_dir_all_ignore_error("test_indices/test_tracing");
+
+        let captured = Arc::new(Mutex::new(Captured::default()));
+        tracing::subscriber::with_default(CapturingSubscriber(captured.clone()), || {
+            let mut store = RocksDBStore::create("test_indices/test_tracing").unwrap();
+            let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+            for key in vec!["a", "b", "c"] {
+                let doc = DocumentBuilder::new(&store.schema, key).text(title_field, "hello").build().unwrap();
+                store.insert_or_update_document(&doc).unwrap();
+            }
+            store.merge_segments(&vec![1, 2]).unwrap();
+
+            let mut collector = TotalCountCollector::new();
+            store.reader().search(&mut collector, &Query::term(title_field, Term::from_string("hello"))).unwrap();
+            assert_eq!(collector.get_total_count(), 3);
+        });
+
+        let captured = captured.lock().unwrap();
+
+        // Flushes and merges emit events
+        let messages = captured.events.iter().map(|&(ref message, _)| &message[..]).collect::<Vec<_>>();
+        assert_eq!(messages.iter().filter(|message| **message == "flushed segment").count(), 3);
+        assert!(messages.contains(&"merging segments"));
+        assert!(messages.contains(&"merged segments"));
+
+        // The search has a span with a child for each of the two segments left after the merge
+        let search_spans = captured.spans.iter().enumerate().filter(|&(_, &(name, _))| name == "search").map(|(i, _)| i as u64 + 1).collect::<Vec<_>>();
+        assert_eq!(search_spans.len(), 1);
+        let segment_spans = captured.spans.iter().filter(|&&(name, _)| name == "segment").collect::<Vec<_>>();
+        assert_eq!(segment_spans.len(), 2);
+        for &&(_, parent) in segment_spans.iter() {
+            assert_eq!(parent, Some(search_spans[0]));
+        }
+    }
+
+    #[test]
+    fn test_tenants() {
+        use kite::DocumentBuilder;
+        use super::Tenant;
+
+        remove_dir_all_ignore_error("test_indices/test_tenants");
+
+        let mut store = RocksDBStore::create("test_indices/test_tenants").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let pk_field = store.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+
+        let insert = |tenant: &Tenant, key: &str, title: &str, pk: i64| {
+            let doc = DocumentBuilder::new(&store.schema, key).text(title_field, title).integer(pk_field, pk).build().unwrap();
+            tenant.insert_or_update_document(&doc).unwrap();
+        };
+        let count = |reader: &RocksDBReader, term: &str| {
+            let mut collector = TotalCountCollector::new();
+            reader.search(&mut collector, &Query::term(title_field, Term::from_string(term))).unwrap();
+            collector.get_total_count()
+        };
+        let pk = |value: Option<FieldValue>| match value {
+            Some(FieldValue::Integer(pk)) => Some(pk),
+            _ => None,
+        };
+
+        // Both tenants can use the same key
+        let acme = store.tenant("acme");
+        let globex = store.tenant("globex");
+        insert(&acme, "doc1", "hello world", 1);
+        insert(&globex, "doc1", "hello there", 2);
+        insert(&acme, "doc2", "goodbye world", 3);
+        assert_eq!(store.tenants(), vec!["acme".to_string(), "globex".to_string()]);
+        assert_eq!(acme.segments(), vec![1, 3]);
+        assert_eq!(globex.segments(), vec![2]);
+
+        let acme_reader = acme.reader();
+        let globex_reader = globex.reader();
+        assert_eq!(pk(acme_reader.get_document("doc1").unwrap().unwrap().stored_fields.remove(&pk_field)), Some(1));
+        assert_eq!(pk(globex_reader.get_document("doc1").unwrap().unwrap().stored_fields.remove(&pk_field)), Some(2));
+        assert!(acme_reader.contains_document_key("doc2"));
+        assert!(!globex_reader.contains_document_key("doc2"));
+        assert_eq!(acme_reader.document_keys(), vec!["doc1".to_string(), "doc2".to_string()]);
+        assert_eq!((count(&acme_reader, "hello"), count(&acme_reader, "world"), count(&acme_reader, "there")), (1, 2, 0));
+        assert_eq!((count(&globex_reader, "hello"), count(&globex_reader, "world"), count(&globex_reader, "there")), (1, 0, 1));
+
+        // Documents of other tenants can't be read by id either
+        let globex_doc_id = globex_reader.get_document_id("doc1").unwrap().unwrap();
+        assert!(acme_reader.read_stored_field(pk_field, globex_doc_id).unwrap().is_none());
+
+        // The store's reader only sees documents that weren't written through a tenant
+        let doc = DocumentBuilder::new(&store.schema, "doc3").text(title_field, "hello store").integer(pk_field, 4).build().unwrap();
+        store.insert_or_update_document(&doc).unwrap();
+        let reader = store.reader();
+        assert_eq!(reader.tenant(), None);
+        assert_eq!((count(&reader, "hello"), count(&reader, "world"), count(&reader, "there")), (1, 0, 0));
+        assert!(reader.get_document("doc1").unwrap().is_none());
+        assert_eq!(pk(reader.get_document("doc3").unwrap().unwrap().stored_fields.remove(&pk_field)), Some(4));
+        assert!(reader.read_stored_field(pk_field, globex_doc_id).unwrap().is_none());
+        assert_eq!(count(&acme.reader(), "hello"), 1);
+        assert!(!acme.reader().contains_document_key("doc3"));
+        drop((acme_reader, globex_reader, reader));
+
+        // Segments of different tenants can't be merged
+        match store.merge_segments(&vec![1, 2]) {
+            Err(SegmentMergeError::MixedTenants) => {}
+            result => panic!("expected a mixed tenants error, got {:?}", result),
+        }
+
+        store.merge_segments(&vec![1, 3]).unwrap();
+        store.purge_segments(&vec![1, 3]).unwrap();
+        assert_eq!(acme.segments(), vec![5]);
+        let acme_reader = acme.reader();
+        assert_eq!(pk(acme_reader.get_document("doc2").unwrap().unwrap().stored_fields.remove(&pk_field)), Some(3));
+        assert_eq!(count(&acme_reader, "world"), 2);
+        drop(acme_reader);
+
+        // Removing a tenant's document doesn't touch other tenants
+        assert!(acme.remove_document_by_key("doc1").unwrap());
+        assert!(!acme.remove_document_by_key("doc1").unwrap());
+        assert!(!store.remove_document_by_key("doc1").unwrap());
+        assert!(globex.reader().get_document("doc1").unwrap().is_some());
+
+        // The tenants' primary key indexes are loaded when the store is reopened
+        drop((acme, globex));
+        drop(store);
+        let store = RocksDBStore::open("test_indices/test_tenants").unwrap();
+        let acme_reader = store.tenant("acme").reader();
+        assert!(!acme_reader.contains_document_key("doc1"));
+        assert!(acme_reader.contains_document_key("doc2"));
+        assert!(store.tenant("globex").reader().contains_document_key("doc1"));
+    }
+
+    #[test]
+    fn test_encryption() {
+        use std::collections::BTreeMap;
+        use kite::DocumentBuilder;
+        use super::{BlockEncryptor, EncryptionConfig, DecryptionError};
+
+        /// Marks data with its key id and XORs it, enough to check that the right keys are used
+        struct XorEncryptor {
+            current_key_id: u32,
+            key_ids: Vec<u32>,
+        }
+
+        impl BlockEncryptor for XorEncryptor {
+            fn current_key_id(&self) -> u32 {
+                self.current_key_id
+            }
+
+            fn encrypt(&self, key_id: u32, plaintext: &[u8]) -> Vec<u8> {
+                let mut ciphertext = vec![key_id as u8];
+                ciphertext.extend(plaintext.iter().map(|b| b ^ 0x5a));
+                ciphertext
+            }
+
+            fn decrypt(&self, key_id: u32, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+                if !self.key_ids.contains(&key_id) {
+                    return Err(format!("unknown key {}", key_id));
+                }
+
+                match ciphertext.split_first() {
+                    Some((&marker, ciphertext)) if marker == key_id as u8 => Ok(ciphertext.iter().map(|b| b ^ 0x5a).collect()),
+                    _ => Err("wrong key".to_string()),
+                }
+            }
+        }
+
+        remove_dir_all_ignore_error("test_indices/test_encryption");
+
+        let mut store = RocksDBStore::create("test_indices/test_encryption").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+        store.set_encryptor(Arc::new(XorEncryptor { current_key_id: 1, key_ids: vec![1] }), EncryptionConfig { encrypt_postings: true });
+
+        let insert = |store: &RocksDBStore, key: &str, title: &str| {
+            let source = format!("{{\"title\": \"{}\"}}", title).into_bytes();
+            let doc = DocumentBuilder::new(&store.schema, key).text(title_field, title).source(source).build().unwrap();
+            store.insert_or_update_document(&doc).unwrap();
+        };
+        let count = |store: &RocksDBStore, term: &str| {
+            let mut collector = TotalCountCollector::new();
+            store.reader().search(&mut collector, &Query::term(title_field, Term::from_string(term))).unwrap();
+            collector.get_total_count()
+        };
+        let title = |store: &RocksDBStore, key: &str| match store.reader().get_document(key).unwrap().unwrap().stored_fields.remove(&title_field) {
+            Some(FieldValue::String(title)) => title,
+            value => panic!("expected a string, got {:?}", value),
+        };
+        let key_ids = |entries: &[(u32, Vec<u32>)]| entries.iter().cloned().collect::<BTreeMap<_, _>>();
+
+        insert(&store, "doc1", "hello world");
+
+        // The values are encrypted in the database
+        let kb = KeyBuilder::stored_field_value(1, 0, title_field.0, b"val");
+        let value = store.db.get(kb.key()).unwrap().unwrap();
+        assert_eq!(value[0], 1);
+        assert!(&value[1..] != b"hello world");
+        let kb = KeyBuilder::stored_source(1, 0);
+        assert_eq!(store.db.get(kb.key()).unwrap().unwrap()[0], 1);
+
+        // But they're decrypted when read
+        assert_eq!(title(&store, "doc1"), "hello world");
+        assert_eq!(count(&store, "hello"), 1);
+        let reader = store.reader();
+        let doc = reader.multi_get(&["doc1"]).unwrap().remove(0).unwrap();
+        assert_eq!(doc.source, Some(br#"{"title": "hello world"}"#.to_vec()));
+        drop(reader);
+        assert_eq!(store.segments_by_encryption_key(), key_ids(&[(1, vec![1])]));
+
+        // Rotate the key, new segments use the new key while older ones can still be read
+        store.set_encryptor(Arc::new(XorEncryptor { current_key_id: 2, key_ids: vec![1, 2] }), EncryptionConfig { encrypt_postings: true });
+        insert(&store, "doc2", "goodbye world");
+        assert_eq!(store.segments_by_encryption_key(), key_ids(&[(1, vec![1]), (2, vec![2])]));
+        assert_eq!(count(&store, "world"), 2);
+
+        // Merging moves the data onto the new key
+        let segment = store.merge_segments(&vec![1, 2]).unwrap();
+        store.purge_segments(&vec![1, 2]).unwrap();
+        assert_eq!(store.segments_by_encryption_key(), key_ids(&[(2, vec![segment])]));
+        let term_id = store.term_dictionary.get(&Term::from_string("world")).unwrap();
+        let kb = KeyBuilder::segment_postings_impacts(segment, title_field.0, term_id.0);
+        assert_eq!(store.db.get(kb.key()).unwrap().unwrap()[0], 2);
+
+        // So the old key can be retired
+        store.set_encryptor(Arc::new(XorEncryptor { current_key_id: 2, key_ids: vec![2] }), EncryptionConfig { encrypt_postings: true });
+        assert_eq!(title(&store, "doc1"), "hello world");
+        assert_eq!(title(&store, "doc2"), "goodbye world");
+        assert_eq!(count(&store, "world"), 2);
+
+        // The encryptor isn't saved, encrypted segments can't be read without it
+        drop(store);
+        let store = RocksDBStore::open("test_indices/test_encryption").unwrap();
+        let reader = store.reader();
+        let doc_id = reader.get_document_id("doc1").unwrap().unwrap();
+        match reader.read_stored_field(title_field, doc_id) {
+            Err(StoredFieldReadError::DecryptionError(DecryptionError::NoEncryptor { key_id: 2, .. })) => {}
+            result => panic!("expected a decryption error, got {:?}", result),
+        }
+        assert!(reader.read_source(doc_id).is_err());
+    }
+
+    #[test]
+    fn test_segment_conformance() {
+        use kite::testing::{MockSegment, CorpusGenerator, CorpusConfig, assert_segment_conforms};
+        use segment::RocksDBSegment;
+        use segment_builder::SegmentBuilder;
+
+        remove_dir_all_ignore_error("test_indices/test_segment_conformance");
+
+        let mut store = RocksDBStore::create("test_indices/test_segment_conformance").unwrap();
+        store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+        store.add_field("category".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+        store.add_field("rating".to_string(), FieldType::I64, FIELD_INDEXED | FIELD_STORED).unwrap();
+        store.add_field("published".to_string(), FieldType::DateTime, FIELD_STORED).unwrap();
+        store.add_field("popularity".to_string(), FieldType::RankFeature, FIELD_INDEXED).unwrap();
+
+        let docs = CorpusGenerator::new(CorpusConfig::default()).corpus(store.schema(), 500);
+
+        let mut builder = SegmentBuilder::new();
+        for doc in docs.iter() {
+            builder.add_document(doc).unwrap();
+        }
+        let segment_id = store.write_segment(&builder).unwrap();
+
+        let mut expected = MockSegment::from_documents(segment_id, &docs);
+        expected.set_statistic(b"total_docs", 500);
+
+        let reader = store.reader();
+        assert_segment_conforms(&RocksDBSegment::new(&reader, segment_id), &expected, |term| store.term_dictionary.get(term));
+    }
+
+    #[test]
+    fn test_regex_query() {
+        use kite::MultiTermSelector;
+
+        remove_dir_all_ignore_error("test_indices/test_regex_query");
+
+        let mut store = RocksDBStore::create("test_indices/test_regex_query").unwrap();
+        let code_field = store.add_field("code".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"code\": \"SKU-1001\"}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"code\": \"SKU-2002\"}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"code\": \"SKU-30A\"}\n",
+            "{\"index\": {\"_id\": \"d\"}}\n{\"code\": \"XSKU-1001\"}\n",
+        ), &BulkConfig::default()).unwrap();
+
+        let reader = store.reader();
+        let count = |pattern: &str| reader.count(&Query::regex(code_field, pattern).unwrap().max_expansions(None));
+        assert_eq!(count(r"SKU-\d+").unwrap(), 2);
+        assert_eq!(count(r"SKU-[0-9A-Z]+").unwrap(), 3);
+        assert_eq!(count(r".*SKU-1001").unwrap(), 2);
+        assert_eq!(count(r"SKU-1").unwrap(), 0);
+
+        // Invalid patterns are rejected when the query is built, or match nothing if the
+        // selector is built directly
+        assert!(Query::regex(code_field, "SKU-(").is_err());
+        assert_eq!(reader.count(&Query::multi_term(code_field, MultiTermSelector::Regex("SKU-(".to_string()))).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_boolean_query() {
+        remove_dir_all_ignore_error("test_indices/test_boolean_query");
+
+        let mut store = RocksDBStore::create("test_indices/test_boolean_query").unwrap();
+        let tags_field = store.add_field("tags".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"tags\": \"red green blue\"}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"tags\": \"red green\"}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"tags\": \"red blue yellow\"}\n",
+            "{\"index\": {\"_id\": \"d\"}}\n{\"tags\": \"green\"}\n",
+            "{\"index\": {\"_id\": \"e\"}}\n{\"tags\": \"yellow\"}\n",
+        ), &BulkConfig::default()).unwrap();
+
+        let tag = |tag: &str| Query::term(tags_field, Term::from_string(tag)).constant_score(1.0);
+        let colours = || vec![tag("red"), tag("green"), tag("blue")];
+
+        let reader = store.reader();
+
+        // Without any must queries, at least one should query must match
+        assert_eq!(reader.count(&Query::boolean(vec![], colours(), vec![])).unwrap(), 4);
+        assert_eq!(reader.count(&Query::boolean(vec![], colours(), vec![]).minimum_should_match(2)).unwrap(), 3);
+        assert_eq!(reader.count(&Query::boolean(vec![], colours(), vec![]).minimum_should_match(3)).unwrap(), 1);
+        assert_eq!(reader.count(&Query::boolean(vec![], colours(), vec![]).minimum_should_match(4)).unwrap(), 0);
+
+        // With must queries, should queries are optional unless a minimum is given
+        assert_eq!(reader.count(&Query::boolean(vec![tag("red")], colours(), vec![])).unwrap(), 3);
+        assert_eq!(reader.count(&Query::boolean(vec![tag("red")], vec![tag("green"), tag("blue")], vec![]).minimum_should_match(1)).unwrap(), 3);
+        assert_eq!(reader.count(&Query::boolean(vec![tag("red")], vec![tag("green"), tag("blue")], vec![]).minimum_should_match(2)).unwrap(), 1);
+
+        // Must not queries remove documents
+        assert_eq!(reader.count(&Query::boolean(vec![], colours(), vec![tag("yellow")]).minimum_should_match(2)).unwrap(), 2);
+        assert_eq!(reader.count(&Query::boolean(vec![tag("red")], vec![], vec![tag("green"), tag("blue")])).unwrap(), 0);
+        assert_eq!(reader.count(&Query::boolean(vec![], vec![], vec![tag("red")])).unwrap(), 0);
+
+        // Documents that match more should queries score higher
+        let tag = |tag: &str| Query::term(tags_field, Term::from_string(tag));
+        let mut collector = TopScoreCollector::new(10);
+        reader.search(&mut collector, &Query::boolean(vec![tag("red")], vec![tag("green"), tag("blue")], vec![tag("yellow")])).unwrap();
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(reader.get_document_id("a").unwrap().map(|doc_id| doc_id.as_u64()), Some(docs[0].doc_id()));
+        assert_eq!(reader.get_document_id("b").unwrap().map(|doc_id| doc_id.as_u64()), Some(docs[1].doc_id()));
+        assert!(docs[0].score() > docs[1].score());
+    }
+
+    #[test]
+    fn test_function_score() {
+        use chrono::{TimeZone, Utc, Duration};
+        use kite::query::function_score::{Decay, DecayShape, FunctionScoreCombine};
+
+        remove_dir_all_ignore_error("test_indices/test_function_score");
+
+        let mut store = RocksDBStore::create("test_indices/test_function_score").unwrap();
+        store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let published_field = store.add_field("published".to_string(), FieldType::DateTime, FIELD_STORED).unwrap();
+        let likes_field = store.add_field("likes".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"today\"}}\n{\"title\": \"News\", \"published\": \"2020-03-01T00:00:00Z\", \"likes\": 1}\n",
+            "{\"index\": {\"_id\": \"last_week\"}}\n{\"title\": \"News\", \"published\": \"2020-02-23T00:00:00Z\", \"likes\": 3}\n",
+            "{\"index\": {\"_id\": \"undated\"}}\n{\"title\": \"News\"}\n",
+        ), &BulkConfig::default()).unwrap();
+
+        let reader = store.reader();
+        let search = |query: &Query| {
+            let mut collector = TopScoreCollector::new(10);
+            reader.search(&mut collector, query).unwrap();
+            collector.into_sorted_vec().iter().map(|doc| doc.score().unwrap()).collect::<Vec<_>>()
+        };
+        let all = || Query::all().constant_score(2.0);
+
+        // A week old document scores half as much, documents without a date aren't penalised
+        let recency = Decay::datetime(published_field, DecayShape::Linear, Utc.ymd(2020, 3, 1).and_hms(0, 0, 0), Duration::days(7));
+        assert_eq!(search(&all().function_score(vec![recency.clone().into()], FunctionScoreCombine::Multiply)), vec![2.0, 2.0, 1.0]);
+
+        // Documents without a value for "likes" use the factor's "missing" value (1)
+        let functions = vec![recency.into(), FieldValueFactor::new(likes_field).into()];
+        assert_eq!(search(&all().function_score(functions.clone(), FunctionScoreCombine::Multiply)), vec![3.0, 2.0, 2.0]);
+        assert_eq!(search(&all().function_score(functions.clone(), FunctionScoreCombine::Sum)), vec![5.5, 4.0, 4.0]);
+        assert_eq!(search(&all().function_score(functions, FunctionScoreCombine::Max)), vec![3.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn test_terms_query() {
+        remove_dir_all_ignore_error("test_indices/test_terms_query");
+
+        let mut store = RocksDBStore::create("test_indices/test_terms_query").unwrap();
+        let colour_field = store.add_field("colour".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"colour\": \"red\"}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"colour\": \"green\"}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"colour\": \"blue\"}\n",
+        ), &BulkConfig::default()).unwrap();
+
+        let reader = store.reader();
+        let colours = |colours: &[&str]| Query::any_term(colour_field, colours.iter().map(|colour| Term::from_string(colour)));
+        assert_eq!(reader.count(&colours(&["red", "blue"])).unwrap(), 2);
+        assert_eq!(reader.count(&colours(&["red", "purple"])).unwrap(), 1);
+        assert_eq!(reader.count(&colours(&["purple"])).unwrap(), 0);
+        assert_eq!(reader.count(&colours(&[])).unwrap(), 0);
+
+        // Every match gets the same score
+        let mut collector = TopScoreCollector::new(10);
+        reader.search(&mut collector, &colours(&["red", "green", "blue"]).boost(2.0)).unwrap();
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.score()).collect::<Vec<_>>(), vec![Some(2.0f32); 3]);
+    }
+
+    #[test]
+    fn test_span_query() {
+        use kite::schema::FIELD_TERM_VECTORS;
+        use kite::query::span::SpanQuery;
+
+        remove_dir_all_ignore_error("test_indices/test_span_query");
+
+        let mut store = RocksDBStore::create("test_indices/test_span_query").unwrap();
+        let body_field = store.add_field("body".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_TERM_VECTORS).unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"body\": \"the quick brown fox\", \"title\": \"quick fox\"}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"body\": \"the fox was quick\"}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"body\": \"a quick dog chased a very slow fox\"}\n",
+        ), &BulkConfig::default()).unwrap();
+
+        let reader = store.reader();
+        let term = |field, term: &str| SpanQuery::term(field, Term::from_string(term));
+        let near = |slop, in_order| Query::span(SpanQuery::near(vec![term(body_field, "quick"), term(body_field, "fox")], slop, in_order));
+
+        assert_eq!(reader.count(&near(0, true)).unwrap(), 0);
+        assert_eq!(reader.count(&near(1, true)).unwrap(), 1);
+        assert_eq!(reader.count(&near(1, false)).unwrap(), 2);
+        assert_eq!(reader.count(&near(5, true)).unwrap(), 2);
+        assert_eq!(reader.count(&near(5, false)).unwrap(), 3);
+
+        // Nested spans
+        let query = Query::span(SpanQuery::near(vec![
+            term(body_field, "dog"),
+            SpanQuery::near(vec![term(body_field, "slow"), term(body_field, "fox")], 0, true),
+        ], 3, false));
+        assert_eq!(reader.count(&query).unwrap(), 1);
+
+        // Fields without term vectors or with a missing term don't match
+        assert_eq!(reader.count(&Query::span(SpanQuery::near(vec![term(title_field, "quick"), term(title_field, "fox")], 1, true))).unwrap(), 0);
+        assert_eq!(reader.count(&Query::span(SpanQuery::near(vec![term(body_field, "quick"), term(body_field, "cat")], 5, false))).unwrap(), 0);
+
+        // Matches are scored by their terms
+        let mut collector = TopScoreCollector::new(10);
+        reader.search(&mut collector, &near(5, false)).unwrap();
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 3);
+        assert!(docs.iter().all(|doc| doc.score().unwrap() > 0.0));
+    }
+
+    #[test]
+    fn test_boosting_query() {
+        remove_dir_all_ignore_error("test_indices/test_boosting_query");
+
+        let mut store = RocksDBStore::create("test_indices/test_boosting_query").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        store.bulk(concat!(
+            "{\"index\": {\"_id\": \"a\"}}\n{\"title\": \"apple pie\"}\n",
+            "{\"index\": {\"_id\": \"b\"}}\n{\"title\": \"apple phone\"}\n",
+            "{\"index\": {\"_id\": \"c\"}}\n{\"title\": \"banana pie\"}\n",
+        ), &BulkConfig::default()).unwrap();
+
+        let reader = store.reader();
+        let title = |term: &str| Query::term(title_field, Term::from_string(term));
+        let query = || Query::all().constant_score(2.0).demote(title("phone"), 0.25);
+
+        // Documents matching the negative query are demoted rather than excluded
+        assert_eq!(reader.count(&query()).unwrap(), 3);
+
+        let mut collector = TopScoreCollector::new(10);
+        reader.search(&mut collector, &query()).unwrap();
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.score()).collect::<Vec<_>>(), vec![Some(2.0), Some(2.0), Some(0.5)]);
+        assert_eq!(reader.get_document_id("b").unwrap().map(|doc_id| doc_id.as_u64()), Some(docs[2].doc_id()));
+
+        // Only the positive query decides which documents match
+        assert_eq!(reader.count(&title("apple").demote(title("banana"), 0.5)).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_recover_segments() {
+        use key_builder::Key;
+
+        remove_dir_all_ignore_error("test_indices/test_recover_segments");
+
+        let mut store = RocksDBStore::create("test_indices/test_recover_segments").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+        store.bulk("{\"index\": {\"_id\": \"a\"}}\n{\"title\": \"hello\"}\n", &BulkConfig::default()).unwrap();
+        store.bulk("{\"index\": {\"_id\": \"b\"}}\n{\"title\": \"hello\"}\n", &BulkConfig::default()).unwrap();
+        store.bulk("{\"index\": {\"_id\": \"c\"}}\n{\"title\": \"hello\"}\n", &BulkConfig::default()).unwrap();
+
+        // Segments 1 and 2 are merged into segment 4 but the process stops before they're purged
+        assert_eq!(store.merge_segments(&vec![1, 2]).unwrap(), 4);
+
+        // Then it stops part way through writing the data of another merge
+        let segment = store.segments.new_segment(&store.db).unwrap();
+        assert_eq!(segment, 5);
+        store.db.put(KeyBuilder::stored_field_value(segment, 0, title_field.0, b"val").key(), b"hello").unwrap();
+        store.db.put(KeyBuilder::segment_dir_list(segment, title_field.0, 1).key(), b"").unwrap();
+        store.db.put(KeyBuilder::segment_postings_skip_list(segment, title_field.0, 1).key(), b"").unwrap();
+        store.db.put(KeyBuilder::segment_stat(segment, b"total_docs").key(), &[1, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        drop(store);
+
+        let store = RocksDBStore::open("test_indices/test_recover_segments").unwrap();
+        let segments_with_data = || {
+            let mut segments = Vec::new();
+            let mut iter = store.db.raw_iterator();
+            iter.seek_to_first();
+            while iter.valid() {
+                if let Some(segment) = Key::parse(&iter.key().unwrap()).and_then(|key| key.segment()) {
+                    if !segments.contains(&segment) {
+                        segments.push(segment);
+                    }
+                }
+                iter.next();
+            }
+            segments.sort();
+            segments
+        };
+        assert_eq!(segments_with_data(), vec![3, 4]);
+        assert_eq!(store.reader().count(&Query::term(title_field, Term::from_string("hello"))).unwrap(), 3);
+
+        // Nothing left to recover
+        assert_eq!(store.recover_segments().unwrap(), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_recover_segments_removes_every_key() {
+        use key_builder::{Key, SEGMENT_KEY_PREFIXES};
+
+        remove_dir_all_ignore_error("test_indices/test_recover_segments_removes_every_key");
+
+        let store = RocksDBStore::create("test_indices/test_recover_segments_removes_every_key").unwrap();
+        store.bulk("{\"index\": {\"_id\": \"a\"}}\n{}\n", &BulkConfig::default()).unwrap();
+
+        // A segment that was never activated, with every kind of segment data
+        let segment = store.segments.new_segment(&store.db).unwrap();
+        let keys = vec![
+            KeyBuilder::stored_field_value(segment, 0, 1, b"val"),
+            KeyBuilder::tenant_segment(b"tenant", segment),
+            KeyBuilder::segment_dir_list(segment, 1, 1),
+            KeyBuilder::segment_stat(segment, b"total_docs"),
+            KeyBuilder::segment_rank_feature_column(segment, 1),
+            KeyBuilder::segment_doc_values_column(segment, 1),
+            KeyBuilder::segment_field_presence(segment, 1),
+            KeyBuilder::segment_completion_index(segment, 1),
+            KeyBuilder::segment_postings_skip_list(segment, 1, 1),
+            KeyBuilder::segment_postings_block(segment, 1, 1, 0),
+            KeyBuilder::segment_postings_impacts(segment, 1, 1),
+            KeyBuilder::segment_index_sort(segment),
+            KeyBuilder::segment_del_list(segment),
+            KeyBuilder::segment_encryption(segment),
+        ];
+
+        // Every prefix is covered, apart from term directories and tenant records which are
+        // keyed by something else first
+        let mut prefixes = keys.iter().map(|kb| kb.key()[0]).filter(|prefix| *prefix != b'd' && *prefix != b'b').collect::<Vec<_>>();
+        prefixes.sort();
+        prefixes.dedup();
+        let mut expected_prefixes = SEGMENT_KEY_PREFIXES.to_vec();
+        expected_prefixes.sort();
+        assert_eq!(prefixes, expected_prefixes);
+
+        for kb in keys.iter() {
+            assert_eq!(Key::parse(kb.key()).and_then(|key| key.segment()), Some(segment));
+            store.db.put(kb.key(), b"").unwrap();
+        }
+        drop(store);
+
+        let store = RocksDBStore::open("test_indices/test_recover_segments_removes_every_key").unwrap();
+        for kb in keys.iter() {
+            assert!(store.db.get(kb.key()).unwrap().is_none(), "{:?} wasn't removed", String::from_utf8_lossy(kb.key()));
+        }
+
+        // The active segment's data is left alone
+        assert_eq!(store.reader().count(&Query::all()).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_store_traits() {
+        use kite::DocumentBuilder;
+        use kite::store::{Store, StoreReader};
+
+        // Only uses the traits, so would work with any backend
+        fn index<S: Store>(store: &mut S) -> FieldId {
+            let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+            for (key, title) in vec![("a", "hello world"), ("b", "hello"), ("c", "goodbye")] {
+                let doc = DocumentBuilder::new(store.schema(), key).text(title_field, title).build().unwrap();
+                store.insert_or_update_document(&doc).unwrap();
+            }
+            assert!(store.remove_document_by_key("c").unwrap());
+            assert!(!store.remove_document_by_key("c").unwrap());
+            title_field
+        }
+
+        fn count_matches<R: StoreReader>(reader: &R, query: &Query) -> usize {
+            let mut collector = TotalCountCollector::new();
+            reader.search(&mut collector, query).unwrap();
+            collector.get_total_count() as usize
+        }
+
+        remove_dir_all_ignore_error("test_indices/test_store_traits");
+
+        let mut store = RocksDBStore::create("test_indices/test_store_traits").unwrap();
+        let title_field = index(&mut store);
+
+        let reader = Store::reader(&store).unwrap();
+        assert_eq!(StoreReader::segments(&reader).len(), 3);
+        assert!(StoreReader::contains_document_key(&reader, "a").unwrap());
+        assert!(!StoreReader::contains_document_key(&reader, "c").unwrap());
+        assert_eq!(count_matches(&reader, &Query::term(title_field, Term::from_string("hello"))), 2);
+        assert_eq!(count_matches(&reader, &Query::term(title_field, Term::from_string("goodbye"))), 0);
+
+        let doc = StoreReader::get_document(&reader, "b").unwrap().unwrap();
+        match doc.stored_fields.get(&title_field) {
+            Some(&FieldValue::String(ref title)) if title == "hello" => {}
+            _ => panic!("expected the document's stored title"),
+        }
+        assert!(StoreReader::get_document(&reader, "c").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_max_segment_docs() {
+        remove_dir_all_ignore_error("test_indices/test_max_segment_docs");
+
+        let mut store = RocksDBStore::create("test_indices/test_max_segment_docs").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        assert_eq!(store.max_segment_docs(), 65536);
+        assert!(store.set_max_segment_docs(0).is_err());
+        store.set_max_segment_docs(70000).unwrap();
+
+        let make_doc = |key: String, term: &str| {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(title_field, vec![Token { term: Term::from_string(term), position: 1, offsets: None }].into());
+            Document {
+                key: key,
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }
+        };
+
+        let count_matches = |store: &RocksDBStore, term: &str| {
+            let mut collector = TotalCountCollector::new();
+            store.reader().search(&mut collector, &Query::term(title_field, Term::from_string(term))).unwrap();
+            collector.get_total_count()
+        };
+
+        // Fill a segment with more documents than can have u16 ords
+        let store = Arc::new(store);
+        let mut writer = IndexWriter::open(store.clone()).unwrap();
+        for i in 0..66000 {
+            writer.add_document(&make_doc(i.to_string(), if i == 65999 { "last" } else { "hello" })).unwrap();
+        }
+        let big_segment = writer.commit().unwrap().unwrap();
+        for i in 0..100 {
+            writer.add_document(&make_doc(format!("small{}", i), "hello")).unwrap();
+        }
+        let small_segment = writer.commit().unwrap().unwrap();
+        for i in 0..4000 {
+            writer.add_document(&make_doc(format!("other{}", i), "other")).unwrap();
+        }
+        let other_segment = writer.commit().unwrap().unwrap();
+        drop(writer);
+        let store = Arc::try_unwrap(store).ok().unwrap();
+
+        assert_eq!(store.reader().get_document_id("65999").unwrap(), Some(DocId(SegmentId(big_segment), 65999)));
+        let mut collector = TopScoreCollector::new(10);
+        store.reader().search(&mut collector, &Query::term(title_field, Term::from_string("last"))).unwrap();
+        assert_eq!(collector.into_sorted_vec().iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![DocId(SegmentId(big_segment), 65999).as_u64()]);
+
+        // Delete documents with ords on both sides of 65536
+        assert!(store.remove_document_by_key("65998").unwrap());
+        assert!(store.remove_document_by_key("1").unwrap());
+        assert!(store.remove_document_by_key("small0").unwrap());
+        assert_eq!(count_matches(&store, "hello"), 65997 + 99);
+
+        // The deletion lists of both segments are merged
+        let merged_segment = store.merge_segments(&vec![big_segment, small_segment]).unwrap();
+        assert_eq!(store.reader().get_document_id("65999").unwrap(), Some(DocId(SegmentId(merged_segment), 65999)));
+        assert_eq!(store.reader().get_document_id("small1").unwrap(), Some(DocId(SegmentId(merged_segment), 66001)));
+        assert_eq!(count_matches(&store, "hello"), 65997 + 99);
+        assert_eq!(count_matches(&store, "last"), 1);
+
+        // Merges can't make segments with more documents than the limit
+        match store.merge_segments(&vec![merged_segment, other_segment]) {
+            Err(SegmentMergeError::TooManyDocs) => {}
+            result => panic!("expected a too many docs error, got {:?}", result),
+        }
+
+        // The limit and the wide ords are read back when the store is reopened
+        drop(store);
+        let store = RocksDBStore::open("test_indices/test_max_segment_docs").unwrap();
+        assert_eq!(store.max_segment_docs(), 70000);
+        assert_eq!(store.reader().get_document_id("small1").unwrap(), Some(DocId(SegmentId(merged_segment), 66001)));
+        assert!(!store.reader().contains_document_key("65998"));
+        assert_eq!(count_matches(&store, "hello"), 65997 + 99);
+    }
+
+    #[test]
+    fn test_term_directory_cache() {
+        remove_dir_all_ignore_error("test_indices/test_term_directory_cache");
+
+        let mut store = make_test_store("test_indices/test_term_directory_cache");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let hello = store.term_dictionary.get(&Term::from_string("hello")).unwrap();
+        let query = Query::term(title_field, Term::from_string("hello"));
+        assert_eq!(store.term_directory_cache_size(), super::DEFAULT_TERM_DIRECTORY_CACHE_SIZE);
+
+        // Searching caches the term directory for the next reader
+        let matches = store.reader().count(&query).unwrap();
+        assert!(store.term_directory_cache.get(3, title_field, hello).is_some());
+        assert_eq!(store.reader().count(&query).unwrap(), matches);
+
+        // Purging a segment removes its term directories from the cache
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+        store.insert_or_update_document(&Document {
+            key: "new_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+        assert_eq!(store.reader().count(&query).unwrap(), matches + 1);
+        assert!(store.term_directory_cache.get(4, title_field, hello).is_some());
+
+        let merged_segment = store.merge_segments(&vec![3, 4]).unwrap();
+        store.purge_segments(&vec![3, 4]).unwrap();
+        assert!(store.term_directory_cache.get(3, title_field, hello).is_none());
+        assert!(store.term_directory_cache.get(4, title_field, hello).is_none());
+        assert_eq!(store.reader().count(&query).unwrap(), matches + 1);
+        assert!(store.term_directory_cache.get(merged_segment, title_field, hello).is_some());
+
+        // A size of 0 disables the cache
+        store.set_term_directory_cache_size(0);
+        assert_eq!(store.reader().count(&query).unwrap(), matches + 1);
+        assert!(store.term_directory_cache.get(merged_segment, title_field, hello).is_none());
+    }
+
+    #[test]
+    fn test_filter_cache() {
+        remove_dir_all_ignore_error("test_indices/test_filter_cache");
+
+        let mut store = make_test_store("test_indices/test_filter_cache");
+        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let body_field = store.schema.get_field_by_name("body").unwrap();
+        let filter = Query::term(title_field, Term::from_string("hello"));
+        let query = Query::term(body_field, Term::from_string("lorem")).filter(Query::term(title_field, Term::from_string("hello")));
+        let filter_key = store.filter_cache.filter_key(&filter).unwrap();
+        assert_eq!(store.filter_cache_size(), super::DEFAULT_FILTER_CACHE_SIZE);
+
+        // The filter's matches are cached once it's been used by a couple of searches
+        let matches = store.reader().count(&query).unwrap();
+        assert!(store.filter_cache.get(&filter_key, 3).is_none());
+        assert_eq!(store.reader().count(&query).unwrap(), matches);
+        assert!(store.filter_cache.get(&filter_key, 3).is_some());
+        assert_eq!(store.reader().count(&query).unwrap(), matches);
+
+        let mut collector = TopScoreCollector::new(10);
+        store.reader().search(&mut collector, &query).unwrap();
+        assert_eq!(collector.into_sorted_vec().len() as u64, matches);
+
+        // Deleted documents are still excluded when the matches come from the cache
+        assert!(store.remove_document_by_key("test_doc").unwrap());
+        assert_eq!(store.reader().count(&query).unwrap(), matches - 1);
+
+        // New segments are added to the cache and purged segments are removed from it
+        let mut indexed_fields = FnvHashMap::default();
+        indexed_fields.insert(title_field, vec![Token { term: Term::from_string("hello"), position: 1, offsets: None }].into());
+        indexed_fields.insert(body_field, vec![Token { term: Term::from_string("lorem"), position: 1, offsets: None }].into());
+        store.insert_or_update_document(&Document {
+            key: "new_doc".to_string(),
+            indexed_fields: indexed_fields,
+            stored_fields: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: None,
+            routing: None,
+        }).unwrap();
+        assert_eq!(store.reader().count(&query).unwrap(), matches);
+        assert!(store.filter_cache.get(&filter_key, 4).is_some());
+
+        store.merge_segments(&vec![3, 4]).unwrap();
+        store.purge_segments(&vec![3, 4]).unwrap();
+        assert!(store.filter_cache.get(&filter_key, 3).is_none());
+        assert!(store.filter_cache.get(&filter_key, 4).is_none());
+        assert_eq!(store.reader().count(&query).unwrap(), matches);
+
+        // A size of 0 disables the cache
+        store.set_filter_cache_size(0);
+        assert!(store.filter_cache.filter_key(&filter).is_none());
+        assert_eq!(store.reader().count(&query).unwrap(), matches);
+    }
+
+    #[test]
+    fn test_search_parallel() {
+        remove_dir_all_ignore_error("test_indices/test_search_parallel");
+
+        let test_metrics = Arc::new(TestMetrics::default());
+        let mut store = RocksDBStore::create("test_indices/test_search_parallel").unwrap();
+        store.set_metrics(test_metrics.clone());
+        let body_field = store.add_field("body".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        // Each document is inserted into its own segment
+        for i in 0..40 {
+            let mut tokens = Vec::new();
+            for position in 0..(i % 7 + 1) {
+                tokens.push(Token { term: Term::from_string("lorem"), position: position, offsets: None });
+            }
+            if i % 3 == 0 {
+                tokens.push(Token { term: Term::from_string("ipsum"), position: 10, offsets: None });
+            }
+
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(body_field, tokens.into());
+
+            store.insert_or_update_document(&Document {
+                key: i.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+                rank_features: FnvHashMap::default(),
+                completions: FnvHashMap::default(),
+                source: None,
+                routing: None,
+            }).unwrap();
+        }
+
+        let index_reader = store.reader();
+        let query = Query::Disjunction {
+            queries: vec![
+                Query::term(body_field, Term::from_string("lorem")),
+                Query::term(body_field, Term::from_string("ipsum")),
+            ]
+        };
+
+        let mut expected_collector = TopScoreCollector::new(10);
+        index_reader.search(&mut expected_collector, &query).unwrap();
+        let expected_docs = expected_collector.into_sorted_vec();
+
+        for num_threads in [1, 4, 100] {
+            let mut collector = TopScoreCollector::new(10);
+            let result = index_reader.search_parallel(&mut collector, &query, num_threads).unwrap();
+            assert!(!result.timed_out);
+            assert_eq!(collector.into_sorted_vec().iter().map(|doc| doc.score()).collect::<Vec<_>>(), expected_docs.iter().map(|doc| doc.score()).collect::<Vec<_>>());
+
+            let mut collector = TotalCountCollector::new();
+            index_reader.search_parallel(&mut collector, &Query::term(body_field, Term::from_string("ipsum")), num_threads).unwrap();
+            assert_eq!(collector.get_total_count(), 14);
+        }
+
+        // Deadline passes before any segment is searched
+        let mut collector = TotalCountCollector::new();
+        let result = index_reader.search_parallel_with_timeout(&mut collector, &query, 4, Duration::from_secs(0)).unwrap();
+        assert!(result.timed_out);
+        assert_eq!(collector.get_total_count(), 0);
+
+        let mut collector = TotalCountCollector::new();
+        let result = index_reader.search_parallel_with_timeout(&mut collector, &query, 4, Duration::from_secs(3600)).unwrap();
+        assert!(!result.timed_out);
+        assert_eq!(collector.get_total_count(), 40);
+
+        // Parallel searches are recorded like any other search
+        let mut collector = TotalCountCollector::new();
+        index_reader.search_parallel(&mut collector, &query, 4).unwrap();
+        assert_eq!(collector.get_total_count(), 40);
+        assert_eq!(test_metrics.counters.lock().unwrap().get(metrics::SEARCHES), Some(&10));
+        assert_eq!(test_metrics.histograms.lock().unwrap().get(metrics::SEARCH_HITS).and_then(|values| values.last().cloned()), Some(40.0));
+
+        // Cancelling the reader's token stops every thread
+        let token = CancellationToken::new();
+        let mut index_reader = store.reader();
+        index_reader.set_cancellation_token(token.clone());
+        token.cancel();
+
+        let mut collector = TotalCountCollector::new();
+        match index_reader.search_parallel(&mut collector, &query, 4) {
+            Err(SearchError::Cancelled) => {}
+            result => panic!("expected search to be cancelled, got {:?}", result),
+        }
+    }
 }
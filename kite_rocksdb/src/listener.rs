@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use RocksDBStore;
+
+/// Receives events from the index as documents are indexed and segments are written and merged
+///
+/// Listeners are called synchronously by the thread that caused the event, after the change
+/// has been committed, so they should return quickly (for example, by sending the event to
+/// another thread). All methods do nothing by default so implementations only need to handle
+/// the events they're interested in.
+pub trait IndexListener: Send + Sync {
+    /// A document was inserted or replaced
+    fn document_indexed(&self, _key: &str) {}
+
+    /// A document was deleted
+    fn document_deleted(&self, _key: &str) {}
+
+    /// A document of a tenant was inserted or replaced
+    fn tenant_document_indexed(&self, _tenant: &str, _key: &str) {}
+
+    /// A document of a tenant was deleted
+    fn tenant_document_deleted(&self, _tenant: &str, _key: &str) {}
+
+    /// A new segment of documents was written
+    fn segment_flushed(&self, _segment: u32, _num_docs: usize) {}
+
+    /// The source segments are about to be merged into the destination segment
+    fn merge_started(&self, _source_segments: &[u32], _dest_segment: u32) {}
+
+    /// A merge has finished, "succeeded" is false if it failed or was cancelled
+    fn merge_finished(&self, _source_segments: &[u32], _dest_segment: u32, _succeeded: bool) {}
+
+    /// A new generation of segments was committed, readers opened from now on will see it
+    fn committed(&self, _generation: u64) {}
+}
+
+impl RocksDBStore {
+    /// Adds a listener to be called when documents are indexed and segments are written and merged
+    pub fn add_listener(&mut self, listener: Arc<dyn IndexListener>) {
+        self.listeners.push(listener);
+    }
+
+    pub(crate) fn notify_listeners<F: Fn(&dyn IndexListener)>(&self, event: F) {
+        for listener in self.listeners.iter() {
+            event(&**listener);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Log records for indexing, merging and searching
+//!
+//! When the "log" feature is enabled, these macros emit records through the log crate under
+//! the "kite" target so they can be filtered separately from the application's records.
+//! Otherwise they compile to nothing, but the arguments are still type checked.
+//!
+//! When the "tracing" feature is enabled, each search is recorded in a "search" span with a
+//! child "segment" span for each segment it searches, and flushes and merges emit events.
+//! These also use the "kite" target. Otherwise spans are placeholders that do nothing and
+//! the events compile to nothing.
+
+/// A span of the tracing crate, or a placeholder when the "tracing" feature isn't enabled
+#[cfg(feature = "tracing")]
+pub use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub struct Entered;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub fn enter(&self) -> Entered {
+        Entered
+    }
+}
+
+/// Creates a span, the first argument is its name and the rest are its fields
+#[cfg(feature = "tracing")]
+macro_rules! trace_span {
+    ($($arg:tt)*) => { ::tracing::debug_span!(target: "kite", $($arg)*) }
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_span {
+    ($($arg:tt)*) => { ::logging::Span }
+}
+
+/// Emits an event in the current span
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($($arg:tt)*) => { ::tracing::debug!(target: "kite", $($arg)*) }
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($($arg:tt)*) => {}
+}
+
+#[cfg(feature = "log")]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { debug!(target: "kite", $($arg)*) }
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
+
+#[cfg(feature = "log")]
+macro_rules! log_trace {
+    ($($arg:tt)*) => { trace!(target: "kite", $($arg)*) }
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_trace {
+    ($($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
+
+#[cfg(feature = "log")]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { warn!(target: "kite", $($arg)*) }
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
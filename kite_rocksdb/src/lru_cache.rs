@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+use fnv::FnvHashMap;
+
+struct LruEntry<V> {
+    value: V,
+    size: usize,
+    last_used: u64,
+}
+
+/// A cache that evicts its least recently used values once they're larger than its capacity
+///
+/// The size of each value is given when it's inserted, it's up to the caller to decide what
+/// it measures (usually an estimate of the value's memory usage in bytes). This isn't
+/// synchronised, put it in a Mutex to share it between readers.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    size: usize,
+    clock: u64,
+    entries: FnvHashMap<K, LruEntry<V>>,
+
+    /// The key of every entry by when it was last used, least recently used first
+    lru: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity: capacity,
+            size: 0,
+            clock: 0,
+            entries: FnvHashMap::default(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the capacity, evicting values if the cache is now too large
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Returns the total size of the cached values
+    #[cfg(test)]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a value and marks it as the most recently used
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.clock += 1;
+        let clock = self.clock;
+
+        let (value, last_used) = match self.entries.get_mut(key) {
+            Some(entry) => {
+                let last_used = entry.last_used;
+                entry.last_used = clock;
+                (entry.value.clone(), last_used)
+            }
+            None => return None,
+        };
+
+        self.lru.remove(&last_used);
+        self.lru.insert(clock, key.clone());
+        Some(value)
+    }
+
+    /// Caches a value, replacing any value that's already cached with the same key
+    ///
+    /// Values that are larger than the whole cache are not cached.
+    pub fn insert(&mut self, key: K, value: V, size: usize) {
+        if size > self.capacity {
+            return;
+        }
+
+        self.remove(&key);
+
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.insert(key.clone(), LruEntry {
+            value: value,
+            size: size,
+            last_used: clock,
+        });
+        self.lru.insert(clock, key);
+        self.size += size;
+        self.evict();
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+
+    /// Removes every value whose key doesn't match the predicate
+    pub fn retain<F: Fn(&K) -> bool>(&mut self, predicate: F) {
+        let keys = self.entries.keys().filter(|key| !predicate(key)).cloned().collect::<Vec<_>>();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// Evicts the least recently used values until the cache is no larger than its capacity
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let key = match self.lru.iter().next() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+
+            self.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruCache;
+
+    #[test]
+    fn test_get() {
+        let mut cache = LruCache::new(10);
+        cache.insert(1, "a", 1);
+
+        assert_eq!(cache.get(&1), Some("a"));
+        assert_eq!(cache.get(&2), None);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "a", 1);
+        cache.insert(2, "b", 1);
+
+        // Using 1 makes 2 the least recently used
+        assert_eq!(cache.get(&1), Some("a"));
+        cache.insert(3, "c", 1);
+
+        assert_eq!(cache.get(&1), Some("a"));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&3), Some("c"));
+        assert_eq!(cache.size(), 2);
+
+        // Shrinking the cache evicts 1 now
+        cache.set_capacity(1);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&3), Some("c"));
+        assert_eq!(cache.size(), 1);
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "a", 3);
+
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut cache = LruCache::new(10);
+        cache.insert(1, "a", 3);
+        cache.insert(1, "b", 2);
+
+        assert_eq!(cache.get(&1), Some("b"));
+        assert_eq!(cache.size(), 2);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut cache = LruCache::new(10);
+        cache.insert(1, "a", 1);
+        cache.insert(2, "b", 2);
+        cache.insert(3, "c", 3);
+        cache.retain(|key| *key == 2);
+
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some("b"));
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.size(), 2);
+    }
+}
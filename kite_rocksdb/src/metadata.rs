@@ -0,0 +1,79 @@
+use std::sync::{Mutex, MutexGuard};
+
+use rocksdb::{self, WriteBatch};
+use fnv::FnvHashMap;
+
+use {RocksDBStore, RocksDBReader};
+use key_builder::KeyBuilder;
+
+/// Application metadata that's waiting to be written with the next commit
+///
+/// A value of None removes the key.
+pub struct PendingMetadata {
+    changes: Mutex<FnvHashMap<String, Option<Vec<u8>>>>,
+}
+
+impl PendingMetadata {
+    pub fn new() -> PendingMetadata {
+        PendingMetadata {
+            changes: Mutex::new(FnvHashMap::default()),
+        }
+    }
+
+    /// Adds the pending changes to the write batch
+    ///
+    /// The returned guard must be held until the write batch has been written, and then
+    /// cleared so the changes aren't written again.
+    pub fn write(&self, write_batch: &mut WriteBatch) -> Result<MutexGuard<FnvHashMap<String, Option<Vec<u8>>>>, rocksdb::Error> {
+        let changes = self.changes.lock().unwrap();
+        for (key, value) in changes.iter() {
+            let kb = KeyBuilder::metadata(key.as_bytes());
+            match *value {
+                Some(ref value) => try!(write_batch.put(kb.key(), value)),
+                None => try!(write_batch.delete(kb.key())),
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+impl RocksDBStore {
+    /// Sets a key in the application's metadata, such as an ingestion checkpoint or the
+    /// version of the schema the application is using
+    ///
+    /// The value is written atomically with the next segment that's committed, so readers
+    /// that see the value also see the documents that were indexed before it was set. Call
+    /// "commit_metadata" to write it without waiting for a segment.
+    pub fn set_metadata(&self, key: &str, value: &[u8]) {
+        self.pending_metadata.changes.lock().unwrap().insert(key.to_string(), Some(value.to_vec()));
+    }
+
+    /// Removes a key from the application's metadata when the next segment is committed
+    pub fn remove_metadata(&self, key: &str) {
+        self.pending_metadata.changes.lock().unwrap().insert(key.to_string(), None);
+    }
+
+    /// Writes metadata changes that are waiting for the next commit now
+    pub fn commit_metadata(&self) -> Result<(), rocksdb::Error> {
+        let mut write_batch = WriteBatch::default();
+        let mut changes = try!(self.pending_metadata.write(&mut write_batch));
+        try!(self.db.write(write_batch));
+        changes.clear();
+        Ok(())
+    }
+
+    /// Returns the committed value of a key in the application's metadata
+    pub fn get_metadata(&self, key: &str) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        let kb = KeyBuilder::metadata(key.as_bytes());
+        Ok(try!(self.db.get(kb.key())).map(|value| value.to_vec()))
+    }
+}
+
+impl<'a> RocksDBReader<'a> {
+    /// Returns the value of a key in the application's metadata as of when the reader was opened
+    pub fn get_metadata(&self, key: &str) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        let kb = KeyBuilder::metadata(key.as_bytes());
+        Ok(try!(self.snapshot.get(kb.key())).map(|value| value.to_vec()))
+    }
+}
@@ -0,0 +1,118 @@
+use roaring::RoaringBitmap;
+use kite::postings::{split_into_blocks, BlockImpact};
+use byteorder::{ByteOrder, LittleEndian};
+
+use key_builder::KeyBuilder;
+
+/// Marks a list of document ids that are encoded as u32s
+const WIDE_DOC_IDS: u8 = 4;
+
+/// Encodes a list of document ids as little endian u16s
+///
+/// If any of the ids don't fit in a u16, they are all encoded as u32s after a "WIDE_DOC_IDS"
+/// byte instead. That makes the length odd, so the two encodings can be told apart and lists
+/// written before segments could have more than 65536 documents are still readable.
+pub fn encode_doc_ids(doc_ids: &[u32]) -> Vec<u8> {
+    if doc_ids.iter().all(|doc_id| *doc_id <= 0xFFFF) {
+        let mut bytes = vec![0; doc_ids.len() * 2];
+        for (i, doc_id) in doc_ids.iter().enumerate() {
+            LittleEndian::write_u16(&mut bytes[i * 2..], *doc_id as u16);
+        }
+        bytes
+    } else {
+        let mut bytes = vec![0; 1 + doc_ids.len() * 4];
+        bytes[0] = WIDE_DOC_IDS;
+        for (i, doc_id) in doc_ids.iter().enumerate() {
+            LittleEndian::write_u32(&mut bytes[1 + i * 4..], *doc_id);
+        }
+        bytes
+    }
+}
+
+pub fn decode_doc_ids(bytes: &[u8]) -> Vec<u32> {
+    if bytes.len() % 2 == 1 {
+        bytes[1..].chunks(4).map(LittleEndian::read_u32).collect()
+    } else {
+        bytes.chunks(2).map(|chunk| LittleEndian::read_u16(chunk) as u32).collect()
+    }
+}
+
+pub fn encode_impacts(impacts: &[BlockImpact]) -> Vec<u8> {
+    let mut bytes = vec![0; impacts.len() * 5];
+    for (i, impact) in impacts.iter().enumerate() {
+        LittleEndian::write_u32(&mut bytes[i * 5..], impact.max_term_frequency);
+        bytes[i * 5 + 4] = impact.min_field_length;
+    }
+    bytes
+}
+
+pub fn decode_impacts(bytes: &[u8]) -> Vec<BlockImpact> {
+    bytes.chunks(5).map(|chunk| {
+        BlockImpact {
+            max_term_frequency: LittleEndian::read_u32(chunk),
+            min_field_length: chunk[4],
+        }
+    }).collect()
+}
+
+/// Builds the keys and values that store a term directory as a block-wise postings list
+///
+/// The skip list is written to "p{segment}/{field}/{term}" and each block is written to
+/// "p{segment}/{field}/{term}/{block_ord}" so they can be loaded individually at search time.
+///
+/// The impacts of each block are written to "p{segment}/{field}/{term}/i". "doc_impact" must
+/// return the term frequency and encoded field length of a document.
+pub fn build_postings<F>(segment: u32, field_id: u32, term_id: u32, term_directory: &RoaringBitmap, doc_impact: F) -> Vec<(KeyBuilder, Vec<u8>)>
+    where F: Fn(u32) -> (u32, u8)
+{
+    let (skip_list, blocks) = split_into_blocks(term_directory);
+    let mut postings = Vec::with_capacity(blocks.len() + 2);
+
+    postings.push((KeyBuilder::segment_postings_skip_list(segment, field_id, term_id), encode_doc_ids(&skip_list)));
+
+    let mut impacts = Vec::with_capacity(blocks.len());
+    for (block_ord, block) in blocks.iter().enumerate() {
+        let mut impact = BlockImpact {
+            max_term_frequency: 0,
+            min_field_length: u8::max_value(),
+        };
+
+        for doc_id in block.iter() {
+            let (term_frequency, field_length) = doc_impact(*doc_id);
+
+            if term_frequency > impact.max_term_frequency {
+                impact.max_term_frequency = term_frequency;
+            }
+
+            if field_length < impact.min_field_length {
+                impact.min_field_length = field_length;
+            }
+        }
+
+        impacts.push(impact);
+        postings.push((KeyBuilder::segment_postings_block(segment, field_id, term_id, block_ord as u32), encode_doc_ids(block)));
+    }
+
+    postings.push((KeyBuilder::segment_postings_impacts(segment, field_id, term_id), encode_impacts(&impacts)));
+
+    postings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_doc_ids, decode_doc_ids};
+
+    #[test]
+    fn test_encode_doc_ids() {
+        let bytes = encode_doc_ids(&[0, 3, 65535]);
+        assert_eq!(bytes.len(), 6);
+        assert_eq!(decode_doc_ids(&bytes), vec![0, 3, 65535]);
+    }
+
+    #[test]
+    fn test_encode_wide_doc_ids() {
+        let bytes = encode_doc_ids(&[0, 3, 65536, 1000000]);
+        assert_eq!(bytes.len(), 17);
+        assert_eq!(decode_doc_ids(&bytes), vec![0, 3, 65536, 1000000]);
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+struct EpochState {
+    current_epoch: u64,
+
+    /// The number of live readers that were opened in each epoch
+    readers: BTreeMap<u64, usize>,
+
+    /// Segments waiting to be purged and the epoch they were released in
+    pending_purges: Vec<(u64, Vec<u32>)>,
+}
+
+/// Tracks which readers are alive so segments are only purged once no reader can see them
+///
+/// The epoch is advanced every time segments are deactivated (eg, by a merge). Each reader
+/// records the epoch it was opened in, readers from before a segment was released may
+/// still be searching it so it isn't purged until all of them have been dropped.
+pub struct ReaderEpochs {
+    state: Mutex<EpochState>,
+}
+
+impl ReaderEpochs {
+    pub fn new() -> ReaderEpochs {
+        ReaderEpochs {
+            state: Mutex::new(EpochState {
+                current_epoch: 0,
+                readers: BTreeMap::new(),
+                pending_purges: Vec::new(),
+            }),
+        }
+    }
+
+    /// Registers a new reader, returns the epoch it must be released with
+    ///
+    /// This must be called before the reader takes its snapshot.
+    pub fn enter(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let epoch = state.current_epoch;
+        *state.readers.entry(epoch).or_insert(0) += 1;
+        epoch
+    }
+
+    /// Called when a reader is dropped
+    pub fn exit(&self, epoch: u64) {
+        let mut state = self.state.lock().unwrap();
+        let remove = match state.readers.get_mut(&epoch) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+
+        if remove {
+            state.readers.remove(&epoch);
+        }
+    }
+
+    /// Starts a new epoch, this must be called after segments have been deactivated
+    pub fn advance(&self) {
+        self.state.lock().unwrap().current_epoch += 1;
+    }
+
+    /// Queues segments to be purged once every reader that could see them has been dropped
+    pub fn release(&self, segments: Vec<u32>) {
+        let mut state = self.state.lock().unwrap();
+        let epoch = state.current_epoch;
+        state.pending_purges.push((epoch, segments));
+    }
+
+    /// Removes and returns the released segments that no live reader can see
+    pub fn take_purgeable(&self) -> Vec<u32> {
+        let mut state = self.state.lock().unwrap();
+        let oldest_reader = state.readers.keys().next().cloned();
+
+        let (purgeable, pending) = state.pending_purges.drain(..).partition(|&(epoch, _)| {
+            match oldest_reader {
+                Some(oldest_reader) => oldest_reader >= epoch,
+                None => true,
+            }
+        });
+        state.pending_purges = pending;
+
+        purgeable.into_iter().flat_map(|(_, segments): (u64, Vec<u32>)| segments).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReaderEpochs;
+
+    #[test]
+    fn test_purge_without_readers() {
+        let epochs = ReaderEpochs::new();
+
+        epochs.release(vec![1, 2]);
+        assert_eq!(epochs.take_purgeable(), vec![1, 2]);
+        assert!(epochs.take_purgeable().is_empty());
+    }
+
+    #[test]
+    fn test_purge_waits_for_old_readers() {
+        let epochs = ReaderEpochs::new();
+        let old_reader = epochs.enter();
+
+        // Merge deactivates segments 1 and 2
+        epochs.advance();
+        let new_reader = epochs.enter();
+        epochs.release(vec![1, 2]);
+
+        // The old reader can still see the segments
+        assert!(epochs.take_purgeable().is_empty());
+
+        // Readers opened after the segments were released don't block them from being purged
+        epochs.exit(old_reader);
+        assert_eq!(epochs.take_purgeable(), vec![1, 2]);
+
+        epochs.exit(new_reader);
+    }
+}
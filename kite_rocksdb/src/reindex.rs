@@ -0,0 +1,138 @@
+use std::fmt;
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kite::Document;
+
+use {RocksDBStore, StoredFieldReadError, DocumentInsertError};
+
+#[derive(Debug, Clone)]
+pub struct ReindexConfig {
+    /// The number of documents that are written into each segment of the target index
+    pub batch_size: usize,
+
+    /// Documents are reindexed no faster than this so the source index can keep serving searches
+    pub max_documents_per_second: Option<u32>,
+}
+
+impl Default for ReindexConfig {
+    fn default() -> ReindexConfig {
+        ReindexConfig {
+            batch_size: 1000,
+            max_documents_per_second: None,
+        }
+    }
+}
+
+/// Passed to the progress callback after each batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReindexProgress {
+    /// The number of documents in the source index when the reindex started
+    pub total: u64,
+
+    /// The number of documents that have been written into the target index
+    pub reindexed: u64,
+
+    /// The number of documents that the transform dropped or that couldn't be inserted
+    pub skipped: u64,
+}
+
+impl ReindexProgress {
+    pub fn is_finished(&self) -> bool {
+        self.reindexed + self.skipped >= self.total
+    }
+}
+
+#[derive(Debug)]
+pub enum ReindexError {
+    /// A stored document couldn't be read from the source index
+    ReadError(StoredFieldReadError),
+
+    /// A batch couldn't be written into the target index
+    WriteError(DocumentInsertError),
+}
+
+impl fmt::Display for ReindexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReindexError::ReadError(ref e) => write!(f, "couldn't read document from source index: {}", e),
+            ReindexError::WriteError(ref e) => write!(f, "couldn't write documents into target index: {}", e),
+        }
+    }
+}
+
+impl Error for ReindexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ReindexError::ReadError(ref e) => Some(e),
+            ReindexError::WriteError(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<StoredFieldReadError> for ReindexError {
+    fn from(e: StoredFieldReadError) -> ReindexError {
+        ReindexError::ReadError(e)
+    }
+}
+
+impl RocksDBStore {
+    /// Copies every stored document of this index into "target"
+    ///
+    /// The index only keeps the stored fields of each document, so "transform" is called to
+    /// turn each of them back into a full document (usually by running the stored text through
+    /// the current analyzers). If it returns None, the document is skipped.
+    ///
+    /// Documents are read from a snapshot taken when the reindex starts, so documents written
+    /// while it's running aren't copied. The target can be this index, in which case each
+    /// document is replaced with its reindexed version.
+    pub fn reindex<F, P>(&self, target: &RocksDBStore, config: &ReindexConfig, mut transform: F, mut progress: P) -> Result<ReindexProgress, ReindexError>
+        where F: FnMut(Document) -> Option<Document>,
+              P: FnMut(&ReindexProgress)
+    {
+        let reader = self.reader();
+        let keys = reader.document_keys();
+
+        let mut status = ReindexProgress {
+            total: keys.len() as u64,
+            reindexed: 0,
+            skipped: 0,
+        };
+        let batch_size = if config.batch_size == 0 { 1 } else { config.batch_size };
+        let started = Instant::now();
+
+        for batch_keys in keys.chunks(batch_size) {
+            let batch_keys = batch_keys.iter().map(|key| key.as_str()).collect::<Vec<_>>();
+            let mut batch = Vec::with_capacity(batch_keys.len());
+            for doc in try!(reader.multi_get(&batch_keys)) {
+                match doc.and_then(&mut transform) {
+                    Some(doc) => batch.push((batch.len() as u64, doc)),
+                    None => status.skipped += 1,
+                }
+            }
+
+            for (_, result) in target.commit_document_batch(batch) {
+                match result {
+                    Ok(()) => status.reindexed += 1,
+                    Err(DocumentInsertError::RocksDBError(e)) => return Err(ReindexError::WriteError(DocumentInsertError::RocksDBError(e))),
+                    Err(_) => status.skipped += 1,
+                }
+            }
+
+            progress(&status);
+
+            // Sleep until the documents processed so far are within the rate limit
+            if let Some(max_documents_per_second) = config.max_documents_per_second {
+                let processed = status.reindexed + status.skipped;
+                let target_elapsed = Duration::from_millis(processed * 1000 / max_documents_per_second.max(1) as u64);
+                let elapsed = started.elapsed();
+                if target_elapsed > elapsed {
+                    thread::sleep(target_elapsed - elapsed);
+                }
+            }
+        }
+
+        Ok(status)
+    }
+}
@@ -0,0 +1,518 @@
+use std::fs;
+use std::str;
+use std::sync::Arc;
+
+use rocksdb::WriteBatch;
+use roaring::RoaringBitmap;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use fnv::{FnvHashMap, FnvHashSet};
+use serde_json;
+use kite::DocId;
+use kite::segment::SegmentId;
+
+use {RocksDBStore, ReaderScope};
+use key_builder::{KeyBuilder, Key, KeyIterator};
+use segment_manager::SegmentManager;
+use term_dictionary::TermDictionaryManager;
+use document_index::{DocumentIndexManager, decode_doc_id};
+use deletion_list::decode_deletion_list;
+use index_sort::IndexSort;
+
+/// Keys of the store's metadata that are copied to followers
+///
+/// The change log isn't replicated, it only records changes made to the leader.
+const REPLICATED_STORE_KEYS: &'static [&'static [u8]] = &[b".schema", b".next_segment", b".next_term_id", b".index_sort", b".generation"];
+
+/// What a follower already has
+///
+/// The follower sends this to the leader so it only receives the changes it's missing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicationCheckpoint {
+    /// The generation of segments the follower last applied
+    pub generation: u64,
+    pub segments: Vec<u32>,
+
+    /// The size of each segment's deletion list in bytes
+    ///
+    /// Deletes are only ever appended to a deletion list, so a list that has grown has new
+    /// deletes.
+    pub deletion_list_sizes: FnvHashMap<u32, usize>,
+
+    /// Terms with lower ids are already in the follower's term dictionary
+    pub next_term_id: u32,
+}
+
+impl ReplicationCheckpoint {
+    /// The checkpoint of a store that has nothing, a delta from this copies the whole store
+    pub fn empty() -> ReplicationCheckpoint {
+        ReplicationCheckpoint {
+            generation: 0,
+            segments: Vec::new(),
+            deletion_list_sizes: FnvHashMap::default(),
+            next_term_id: 0,
+        }
+    }
+}
+
+/// The changes a follower needs to catch up with a leader
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicationDelta {
+    /// The leader's generation of segments
+    pub generation: u64,
+
+    /// The segments that are active in the leader's generation
+    pub segments: Vec<u32>,
+
+    /// The keys of the new segments, new terms, updated deletion lists and the application's metadata
+    pub puts: Vec<(Vec<u8>, Vec<u8>)>,
+
+    /// The files of new segments that have been written to one
+    pub segment_files: Vec<(u32, Vec<u8>)>,
+}
+
+const DELTA_FORMAT_VERSION: u8 = 1;
+
+fn write_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    bytes.write_u64::<LittleEndian>(value.len() as u64).unwrap();
+    bytes.extend(value);
+}
+
+fn read_bytes<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let len = try!(bytes.read_u64::<LittleEndian>().map_err(|e| e.to_string())) as usize;
+    if bytes.len() < len {
+        return Err("delta is truncated".to_string());
+    }
+
+    let (value, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(value)
+}
+
+impl ReplicationDelta {
+    /// Encodes the delta so it can be sent to a follower or saved
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![DELTA_FORMAT_VERSION];
+        bytes.write_u64::<LittleEndian>(self.generation).unwrap();
+
+        bytes.write_u32::<LittleEndian>(self.segments.len() as u32).unwrap();
+        for segment in self.segments.iter() {
+            bytes.write_u32::<LittleEndian>(*segment).unwrap();
+        }
+
+        bytes.write_u64::<LittleEndian>(self.puts.len() as u64).unwrap();
+        for &(ref key, ref value) in self.puts.iter() {
+            write_bytes(&mut bytes, key);
+            write_bytes(&mut bytes, value);
+        }
+
+        bytes.write_u32::<LittleEndian>(self.segment_files.len() as u32).unwrap();
+        for &(segment, ref data) in self.segment_files.iter() {
+            bytes.write_u32::<LittleEndian>(segment).unwrap();
+            write_bytes(&mut bytes, data);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<ReplicationDelta, String> {
+        match bytes.first() {
+            Some(&DELTA_FORMAT_VERSION) => bytes = &bytes[1..],
+            Some(version) => return Err(format!("unrecognised delta format version {}", version)),
+            None => return Err("delta is empty".to_string()),
+        }
+
+        let generation = try!(bytes.read_u64::<LittleEndian>().map_err(|e| e.to_string()));
+
+        let num_segments = try!(bytes.read_u32::<LittleEndian>().map_err(|e| e.to_string()));
+        let mut segments = Vec::new();
+        for _ in 0..num_segments {
+            segments.push(try!(bytes.read_u32::<LittleEndian>().map_err(|e| e.to_string())));
+        }
+
+        let num_puts = try!(bytes.read_u64::<LittleEndian>().map_err(|e| e.to_string()));
+        let mut puts = Vec::new();
+        for _ in 0..num_puts {
+            let key = try!(read_bytes(&mut bytes)).to_vec();
+            let value = try!(read_bytes(&mut bytes)).to_vec();
+            puts.push((key, value));
+        }
+
+        let num_segment_files = try!(bytes.read_u32::<LittleEndian>().map_err(|e| e.to_string()));
+        let mut segment_files = Vec::new();
+        for _ in 0..num_segment_files {
+            let segment = try!(bytes.read_u32::<LittleEndian>().map_err(|e| e.to_string()));
+            segment_files.push((segment, try!(read_bytes(&mut bytes)).to_vec()));
+        }
+
+        Ok(ReplicationDelta {
+            generation: generation,
+            segments: segments,
+            puts: puts,
+            segment_files: segment_files,
+        })
+    }
+}
+
+impl RocksDBStore {
+    /// Returns what this store has replicated so far
+    pub fn replication_checkpoint(&self) -> Result<ReplicationCheckpoint, String> {
+        let reader = self.new_reader(ReaderScope::WholeStore);
+        let generation = match reader.segment_generation() {
+            Some(generation) => generation,
+            None => return Err("unable to read segment generation".to_string()),
+        };
+
+        let mut deletion_list_sizes = FnvHashMap::default();
+        for segment in reader.generation.segments() {
+            let kb = KeyBuilder::segment_del_list(*segment);
+            if let Some(deletion_list) = try!(reader.snapshot.get(kb.key())) {
+                deletion_list_sizes.insert(*segment, deletion_list.len());
+            }
+        }
+
+        let next_term_id = try!(reader.snapshot.get(b".next_term_id"))
+            .and_then(|next_term_id| next_term_id.to_utf8().and_then(|next_term_id| next_term_id.parse::<u32>().ok()))
+            .unwrap_or(1);
+
+        Ok(ReplicationCheckpoint {
+            generation: generation,
+            segments: reader.generation.segments().to_vec(),
+            deletion_list_sizes: deletion_list_sizes,
+            next_term_id: next_term_id,
+        })
+    }
+
+    /// Returns the changes a follower at "checkpoint" needs to catch up with this store
+    ///
+    /// Only the segments the follower doesn't have are sent in full. For the segments it
+    /// already has, only deletion lists that have grown are sent. Segments that have been
+    /// merged away are removed by the follower when it applies the delta.
+    ///
+    /// The delta is read from a snapshot, so it's consistent even if documents are written
+    /// or segments are merged while it's being built.
+    pub fn replication_delta(&self, checkpoint: &ReplicationCheckpoint) -> Result<ReplicationDelta, String> {
+        let reader = self.new_reader(ReaderScope::WholeStore);
+        let generation = match reader.segment_generation() {
+            Some(generation) => generation,
+            None => return Err("unable to read segment generation".to_string()),
+        };
+
+        let known_segments = checkpoint.segments.iter().cloned().collect::<FnvHashSet<u32>>();
+        let new_segments = reader.generation.segments().iter().cloned()
+            .filter(|segment| !known_segments.contains(segment))
+            .collect::<FnvHashSet<u32>>();
+
+        let mut puts = Vec::new();
+
+        for key in REPLICATED_STORE_KEYS {
+            if let Some(value) = try!(reader.snapshot.get(key)) {
+                puts.push((key.to_vec(), value.to_vec()));
+            }
+        }
+
+        // Terms, primary keys and term directories aren't prefixed by segment so all of them
+        // need to be scanned
+        let mut new_field_terms = FnvHashSet::default();
+        for (key, v) in KeyIterator::new(reader.snapshot.raw_iterator(), KeyBuilder::dir_list_prefix()) {
+            if key.segment().map(|segment| new_segments.contains(&segment)).unwrap_or(false) {
+                if let Key::TermDirectory { field_id, term_id, .. } = key {
+                    new_field_terms.insert((field_id, term_id));
+                }
+
+                puts.push((key.key_builder().key().to_vec(), v));
+            }
+        }
+
+        // New segments can add terms to the ordered indexes of integer and date fields. The
+        // term directories of segments that have files are sent with the file.
+        let prefix = KeyBuilder::dir_list_prefix();
+        for segment in new_segments.iter() {
+            if let Some(segment_file) = reader.generation.segment_file(*segment) {
+                for (k, _) in segment_file.iter().skip_while(|&(k, _)| !k.starts_with(prefix.key())).take_while(|&(k, _)| k.starts_with(prefix.key())) {
+                    if let Some(Key::TermDirectory { field_id, term_id, .. }) = Key::parse(k) {
+                        new_field_terms.insert((field_id, term_id));
+                    }
+                }
+            }
+        }
+
+        for (key, v) in KeyIterator::new(reader.snapshot.raw_iterator(), KeyBuilder::all_integer_terms_prefix()) {
+            if let Key::IntegerTerm { field_id, .. } = key {
+                let term_id = str::from_utf8(&v).ok().and_then(|term_id| term_id.parse::<u32>().ok());
+                if term_id.map(|term_id| new_field_terms.contains(&(field_id, term_id))).unwrap_or(false) {
+                    puts.push((key.key_builder().key().to_vec(), v));
+                }
+            }
+        }
+
+        // Documents in new segments, this includes documents that have been moved by a merge
+        let mut iter = reader.snapshot.raw_iterator();
+        iter.seek(b"k");
+        while iter.valid() {
+            {
+                let k = iter.key().unwrap();
+                if k[0] != b'k' {
+                    break;
+                }
+
+                let v = iter.value().unwrap();
+                if new_segments.contains(&LittleEndian::read_u32(&v[0..4])) {
+                    puts.push((k, v));
+                }
+            }
+
+            iter.next();
+        }
+
+        // Tenants have their own primary key indexes, and own the segments their documents are in
+        for (key, v) in KeyIterator::new(reader.snapshot.raw_iterator(), KeyBuilder::all_tenants_primary_key_index_prefix()) {
+            if new_segments.contains(&LittleEndian::read_u32(&v[0..4])) {
+                puts.push((key.key_builder().key().to_vec(), v));
+            }
+        }
+
+        for (key, v) in KeyIterator::new(reader.snapshot.raw_iterator(), KeyBuilder::all_tenants_segments_prefix()) {
+            if key.segment().map(|segment| new_segments.contains(&segment)).unwrap_or(false) {
+                puts.push((key.key_builder().key().to_vec(), v));
+            }
+        }
+
+        iter.seek(b"t");
+        while iter.valid() {
+            {
+                let k = iter.key().unwrap();
+                if k[0] != b't' {
+                    break;
+                }
+
+                let v = iter.value().unwrap();
+                let term_id = str::from_utf8(&v).ok().and_then(|term_id| term_id.parse::<u32>().ok());
+                if term_id.map(|term_id| term_id >= checkpoint.next_term_id).unwrap_or(false) {
+                    puts.push((k, v));
+                }
+            }
+
+            iter.next();
+        }
+
+        // The application's metadata is small so it's always sent in full
+        let prefix = KeyBuilder::metadata_prefix();
+        iter.seek(prefix.key());
+        while iter.valid() {
+            {
+                let k = iter.key().unwrap();
+                if !k.starts_with(prefix.key()) {
+                    break;
+                }
+
+                puts.push((k, iter.value().unwrap()));
+            }
+
+            iter.next();
+        }
+
+        // The rest of the keys of the new segments are prefixed by the segment id
+        let mut segment_files = Vec::new();
+        for segment in reader.generation.segments() {
+            if !new_segments.contains(segment) {
+                continue;
+            }
+
+            for kb in &[KeyBuilder::segment_postings_prefix(*segment), KeyBuilder::segment_stored_values_prefix(*segment), KeyBuilder::segment_stat_prefix(*segment), KeyBuilder::segment_rank_features_prefix(*segment), KeyBuilder::segment_doc_values_prefix(*segment), KeyBuilder::segment_field_presence_prefix(*segment), KeyBuilder::segment_completions_prefix(*segment)] {
+                iter.seek(kb.key());
+                while iter.valid() {
+                    {
+                        let k = iter.key().unwrap();
+                        if !k.starts_with(kb.key()) {
+                            break;
+                        }
+
+                        puts.push((k, iter.value().unwrap()));
+                    }
+
+                    iter.next();
+                }
+            }
+
+            for kb in &[KeyBuilder::segment_active(*segment), KeyBuilder::segment_index_sort(*segment), KeyBuilder::segment_del_list(*segment), KeyBuilder::segment_encryption(*segment)] {
+                if let Some(value) = try!(reader.snapshot.get(kb.key())) {
+                    puts.push((kb.key().to_vec(), value.to_vec()));
+                }
+            }
+
+            if let Some(segment_file) = reader.generation.segment_file(*segment) {
+                segment_files.push((*segment, segment_file.as_bytes().to_vec()));
+            }
+        }
+
+        // Deletes from segments the follower already has
+        for segment in reader.generation.segments() {
+            if new_segments.contains(segment) {
+                continue;
+            }
+
+            let kb = KeyBuilder::segment_del_list(*segment);
+            if let Some(deletion_list) = try!(reader.snapshot.get(kb.key())) {
+                if deletion_list.len() != checkpoint.deletion_list_sizes.get(segment).cloned().unwrap_or(0) {
+                    puts.push((kb.key().to_vec(), deletion_list.to_vec()));
+
+                    let kb = KeyBuilder::segment_stat(*segment, b"deleted_docs");
+                    if let Some(deleted_docs) = try!(reader.snapshot.get(kb.key())) {
+                        puts.push((kb.key().to_vec(), deleted_docs.to_vec()));
+                    }
+                }
+            }
+        }
+
+        Ok(ReplicationDelta {
+            generation: generation,
+            segments: reader.generation.segments().to_vec(),
+            puts: puts,
+            segment_files: segment_files,
+        })
+    }
+
+    /// Applies a delta from the leader, making this store a copy of the leader at the time
+    /// the delta was built
+    ///
+    /// The delta is written in a single write batch so readers either see all of it or none
+    /// of it. This requires a mutable reference so there can't be any readers open while the
+    /// store's in-memory state is reloaded. The store shouldn't be written to other than by
+    /// applying deltas.
+    pub fn apply_replication_delta(&mut self, delta: &ReplicationDelta) -> Result<(), String> {
+        let checkpoint = try!(self.replication_checkpoint());
+        if delta.generation < checkpoint.generation {
+            return Err(format!("delta is from generation {}, which is older than {}", delta.generation, checkpoint.generation));
+        }
+
+        // Files are ignored until their segment is activated, so they can be written first
+        if !delta.segment_files.is_empty() {
+            try!(fs::create_dir_all(self.segment_files_path()).map_err(|e| e.to_string()));
+        }
+
+        for &(segment, ref data) in delta.segment_files.iter() {
+            let path = self.segment_file_path(segment);
+            try!(fs::write(&path, data).map_err(|e| format!("{}: {}", path.display(), e)));
+        }
+
+        let active_segments = delta.segments.iter().cloned().collect::<FnvHashSet<u32>>();
+        let removed_segments = checkpoint.segments.iter().cloned()
+            .filter(|segment| !active_segments.contains(segment))
+            .collect::<Vec<u32>>();
+
+        let mut write_batch = WriteBatch::default();
+        for segment in removed_segments.iter() {
+            let kb = KeyBuilder::segment_active(*segment);
+            try!(write_batch.delete(kb.key()));
+        }
+
+        // Remove the primary keys of documents that have been deleted or merged away. If the
+        // document still exists, its primary key is written back by the puts below.
+        let mut deletion_lists = FnvHashMap::default();
+        for &(ref key, ref value) in delta.puts.iter() {
+            if let Some(Key::DeletionList(segment)) = Key::parse(key) {
+                let deletion_list = try!(decode_deletion_list(value).map_err(|e| format!("deletion list of segment {}: {}", segment, e)));
+                deletion_lists.insert(segment, deletion_list);
+            }
+        }
+
+        let is_stale = |v: &[u8]| {
+            let DocId(SegmentId(segment), ord) = decode_doc_id(v);
+            let is_deleted = deletion_lists.get(&segment).map(|deletion_list: &RoaringBitmap| deletion_list.contains(ord)).unwrap_or(false);
+
+            !active_segments.contains(&segment) || is_deleted
+        };
+
+        let mut iter = self.db.raw_iterator();
+        iter.seek(b"k");
+        while iter.valid() {
+            {
+                let k = iter.key().unwrap();
+                if k[0] != b'k' {
+                    break;
+                }
+
+                if is_stale(&iter.value().unwrap()) {
+                    try!(write_batch.delete(&k));
+                }
+            }
+
+            iter.next();
+        }
+
+        for (key, v) in KeyIterator::new(self.db.raw_iterator(), KeyBuilder::all_tenants_primary_key_index_prefix()) {
+            if is_stale(&v) {
+                try!(write_batch.delete(key.key_builder().key()));
+            }
+        }
+
+        // Metadata that's been removed from the leader isn't in the delta, so all of it is
+        // removed and the leader's is written back by the puts below
+        let prefix = KeyBuilder::metadata_prefix();
+        iter.seek(prefix.key());
+        while iter.valid() {
+            {
+                let k = iter.key().unwrap();
+                if !k.starts_with(prefix.key()) {
+                    break;
+                }
+
+                try!(write_batch.delete(&k));
+            }
+
+            iter.next();
+        }
+
+        for &(ref key, ref value) in delta.puts.iter() {
+            try!(write_batch.put(key, value));
+        }
+
+        try!(self.db.write(write_batch));
+
+        // The removed segments aren't active any more and there are no readers that can see them
+        try!(self.purge_segments_now(&removed_segments));
+
+        // Reload everything that's held in memory
+        self.schema = match try!(self.db.get(b".schema")) {
+            Some(schema) => {
+                match serde_json::from_slice(&schema) {
+                    Ok(schema) => Arc::new(schema),
+                    Err(e) => return Err(format!("schema parse error: {:?}", e)),
+                }
+            }
+            None => return Err("unable to find schema in store".to_string()),
+        };
+        self.segments = try!(SegmentManager::open(&self.db));
+        self.term_dictionary = try!(TermDictionaryManager::open(&self.db));
+        self.document_index = try!(DocumentIndexManager::open(&self.db));
+        self.index_sort = try!(self.db.get(b".index_sort")).and_then(|index_sort| IndexSort::from_bytes(&index_sort));
+        try!(self.open_segment_files());
+
+        self.notify_listeners(|listener| listener.committed(delta.generation));
+
+        // Warming is only an optimisation, if it fails the warm queries are run normally
+        let _ = self.refresh_warm_queries();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplicationDelta;
+
+    #[test]
+    fn test_delta_to_bytes() {
+        let delta = ReplicationDelta {
+            generation: 5,
+            segments: vec![1, 3],
+            puts: vec![(b"a1".to_vec(), Vec::new()), (b"x3".to_vec(), vec![1, 0])],
+            segment_files: vec![(3, b"file".to_vec())],
+        };
+
+        let bytes = delta.to_bytes();
+        assert_eq!(ReplicationDelta::from_bytes(&bytes), Ok(delta));
+        assert!(ReplicationDelta::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(ReplicationDelta::from_bytes(&[]).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use std::f32;
+
+use kite::schema::FieldId;
+use kite::term::TermId;
+use kite::segment::Segment;
+use kite::query::term_scorer::TermScorer;
+use kite::error::SearchError;
+use fnv::FnvHashMap;
+
+use search::decode_field_length;
+use search::statistics::StatisticsReader;
+use search::planner::score_function::{CombinatorScorer, ScoreFunctionOp};
+
+/// Calculates an upper bound for the score of a term in each block of its postings list
+///
+/// Returns None if the segment doesn't store impacts for the term.
+pub fn load_block_max_scores<S: Segment, R: StatisticsReader>(segment: &S, stats: &mut R, field_id: FieldId, term_id: TermId, scorer: &TermScorer) -> Result<Option<Vec<f32>>, SearchError> {
+    let impacts = match try!(segment.load_postings_impacts(field_id, term_id)) {
+        Some(impacts) => impacts,
+        None => return Ok(None),
+    };
+
+    let total_tokens = try!(stats.total_tokens(field_id)) as u64;
+    let total_docs = try!(stats.total_docs(field_id)) as u64;
+    let term_document_frequency = try!(stats.term_document_frequency(field_id, term_id)) as u64;
+
+    Ok(Some(impacts.iter()
+        .map(|impact| {
+            let score = scorer.similarity_model.score(impact.max_term_frequency, decode_field_length(impact.min_field_length), total_tokens, total_docs, term_document_frequency);
+            score * scorer.boost
+        })
+        .collect()))
+}
+
+/// The block max scores of a term in a segment
+struct TermBlockMaxScores {
+    skip_list: Vec<u32>,
+    block_max_scores: Option<Vec<f32>>,
+}
+
+impl TermBlockMaxScores {
+    fn max_score(&self, doc_id: u32) -> f32 {
+        let block_ord = match self.skip_list.binary_search(&doc_id) {
+            Ok(i) | Err(i) => i,
+        };
+
+        if block_ord >= self.skip_list.len() {
+            // Document is after the end of the postings list so doesn't contain the term
+            return 0.0;
+        }
+
+        match self.block_max_scores {
+            Some(ref block_max_scores) => block_max_scores.get(block_ord).cloned().unwrap_or(f32::INFINITY),
+            None => f32::INFINITY,
+        }
+    }
+}
+
+/// Upper bounds for the scores of documents in a segment
+///
+/// These are calculated from the impacts stored with the postings lists of the terms in the
+/// score function, they allow documents that cannot beat the lowest score in the collector
+/// to be skipped without loading their term frequencies and field lengths.
+pub struct ScoreUpperBounds {
+    terms: FnvHashMap<(FieldId, TermId), TermBlockMaxScores>,
+}
+
+impl ScoreUpperBounds {
+    /// Loads the block max scores of each term in the score function
+    ///
+    /// Returns None if the score function can't be bounded (eg, it contains a custom score
+    /// function) or it doesn't contain any terms so there would be nothing to gain.
+    pub fn load<S: Segment, R: StatisticsReader>(score_function: &Vec<ScoreFunctionOp>, segment: &S, stats: &mut R) -> Result<Option<ScoreUpperBounds>, SearchError> {
+        let mut terms = FnvHashMap::default();
+
+        for op in score_function.iter() {
+            match *op {
+                ScoreFunctionOp::TermScorer(field_id, term_id, ref scorer) => {
+                    if terms.contains_key(&(field_id, term_id)) {
+                        continue;
+                    }
+
+                    let skip_list = try!(segment.load_postings_skip_list(field_id, term_id)).unwrap_or_else(Vec::new);
+                    let block_max_scores = try!(load_block_max_scores(segment, stats, field_id, term_id, scorer));
+
+                    terms.insert((field_id, term_id), TermBlockMaxScores {
+                        skip_list: skip_list,
+                        block_max_scores: block_max_scores,
+                    });
+                }
+                ScoreFunctionOp::CustomScore(..) | ScoreFunctionOp::FieldValueFactor(..) | ScoreFunctionOp::FunctionScore(..) => return Ok(None),
+                _ => {}
+            }
+        }
+
+        if terms.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(ScoreUpperBounds {
+            terms: terms,
+        }))
+    }
+
+    /// Returns an upper bound for the score of a document
+    ///
+    /// This runs the score function with the block max score of each term in place of its
+    /// actual score. The combinators are monotonic so the result can't be lower than the
+    /// document's actual score.
+    pub fn max_score(&self, doc_id: u32, score_function: &Vec<ScoreFunctionOp>, rank_features: &FnvHashMap<FieldId, Vec<f32>>) -> f32 {
+        let mut stack = Vec::new();
+        for op in score_function.iter() {
+            match *op {
+                ScoreFunctionOp::Literal(val) => stack.push(val),
+                ScoreFunctionOp::TermScorer(field_id, term_id, ref scorer) => {
+                    if !(scorer.boost >= 0.0) {
+                        // Scores are never negative so negatively boosted terms can't add anything
+                        stack.push(0.0);
+                        continue;
+                    }
+
+                    let max_score = self.terms.get(&(field_id, term_id))
+                        .map(|term| term.max_score(doc_id))
+                        .unwrap_or(f32::INFINITY);
+
+                    stack.push(max_score);
+                }
+                ScoreFunctionOp::CombinatorScorer(num_vals, ref scorer) => {
+                    let start = stack.len() - num_vals as usize;
+                    let score = match *scorer {
+                        CombinatorScorer::Avg => stack[start..].iter().sum::<f32>() / num_vals as f32,
+                        CombinatorScorer::Max => stack[start..].iter().fold(0.0f32, |max, score| max.max(*score)),
+                    };
+
+                    stack.truncate(start);
+                    stack.push(score);
+                }
+                ScoreFunctionOp::RankFeature(field_id, ref function, boost) => {
+                    // The columns are already loaded so these can be scored exactly
+                    let value = rank_features.get(&field_id)
+                        .and_then(|column| column.get(doc_id as usize).cloned())
+                        .unwrap_or(0.0f32);
+
+                    if value > 0.0 {
+                        stack.push(function.score(value) * boost);
+                    } else {
+                        stack.push(0.0f32);
+                    }
+                }
+                ScoreFunctionOp::Boosting(_, _, negative_boost) => {
+                    // Whether the document is demoted isn't checked, so assume the boost that
+                    // gives the higher score
+                    let score = stack.pop().expect("score upper bound: stack underflow");
+                    stack.push(score * negative_boost.max(1.0));
+                }
+                ScoreFunctionOp::CustomScore(..) | ScoreFunctionOp::FieldValueFactor(..) | ScoreFunctionOp::FunctionScore(..) => return f32::INFINITY,
+            }
+        }
+
+        match stack.pop() {
+            Some(max_score) if !max_score.is_nan() => max_score,
+            _ => f32::INFINITY,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::time::Instant;
+
+use kite::postings::POSTINGS_BLOCK_SIZE;
+use kite::cancellation::CancellationToken;
+
+/// Tracks the time budget of a search and whether it's been cancelled
+///
+/// Reading the clock isn't free so, while iterating over documents, it's only checked once
+/// per block of documents. This bounds how far a search can overrun its deadline.
+#[derive(Debug, Clone)]
+pub struct Deadline {
+    instant: Option<Instant>,
+    cancellation_token: Option<CancellationToken>,
+    ticks: usize,
+    timed_out: bool,
+    cancelled: bool,
+}
+
+impl Deadline {
+    /// A deadline that never passes
+    pub fn none() -> Deadline {
+        Deadline {
+            instant: None,
+            cancellation_token: None,
+            ticks: 0,
+            timed_out: false,
+            cancelled: false,
+        }
+    }
+
+    pub fn at(instant: Instant) -> Deadline {
+        Deadline {
+            instant: Some(instant),
+            cancellation_token: None,
+            ticks: 0,
+            timed_out: false,
+            cancelled: false,
+        }
+    }
+
+    /// Also stops the search if the token is cancelled
+    pub fn with_cancellation_token(mut self, cancellation_token: Option<CancellationToken>) -> Deadline {
+        self.cancellation_token = cancellation_token;
+        self
+    }
+
+    /// Returns true if the search should stop, either because the deadline has passed
+    /// or it's been cancelled
+    ///
+    /// Once this has returned true, it always returns true.
+    pub fn check(&mut self) -> bool {
+        if self.is_stopped() {
+            return true;
+        }
+
+        if let Some(ref cancellation_token) = self.cancellation_token {
+            self.cancelled = cancellation_token.is_cancelled();
+        }
+
+        if let Some(instant) = self.instant {
+            self.timed_out = Instant::now() >= instant;
+        }
+
+        self.is_stopped()
+    }
+
+    #[inline]
+    fn is_stopped(&self) -> bool {
+        self.timed_out || self.cancelled
+    }
+
+    /// Called for each document, checks the clock at the end of each block of documents
+    pub fn tick(&mut self) -> bool {
+        self.ticks += 1;
+
+        if self.ticks % POSTINGS_BLOCK_SIZE == 0 {
+            self.check()
+        } else {
+            self.is_stopped()
+        }
+    }
+
+    /// Returns true if the deadline passed during the search
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Returns true if the search was cancelled
+    pub fn cancelled(&self) -> bool {
+        self.cancelled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Instant, Duration};
+
+    use kite::postings::POSTINGS_BLOCK_SIZE;
+    use kite::cancellation::CancellationToken;
+
+    use super::Deadline;
+
+    #[test]
+    fn test_none() {
+        let mut deadline = Deadline::none();
+
+        assert!(!deadline.check());
+        assert!(!deadline.timed_out());
+    }
+
+    #[test]
+    fn test_passed() {
+        let mut deadline = Deadline::at(Instant::now());
+
+        assert!(deadline.check());
+        assert!(deadline.timed_out());
+    }
+
+    #[test]
+    fn test_not_passed() {
+        let mut deadline = Deadline::at(Instant::now() + Duration::from_secs(3600));
+
+        assert!(!deadline.check());
+        assert!(!deadline.timed_out());
+    }
+
+    #[test]
+    fn test_tick_checks_once_per_block() {
+        let mut deadline = Deadline::at(Instant::now());
+
+        for _ in 0..POSTINGS_BLOCK_SIZE - 1 {
+            assert!(!deadline.tick());
+        }
+
+        assert!(deadline.tick());
+    }
+
+    #[test]
+    fn test_cancelled() {
+        let token = CancellationToken::new();
+        let mut deadline = Deadline::none().with_cancellation_token(Some(token.clone()));
+        assert!(!deadline.check());
+
+        token.cancel();
+        assert!(deadline.check());
+        assert!(deadline.cancelled());
+        assert!(!deadline.timed_out());
+    }
+}
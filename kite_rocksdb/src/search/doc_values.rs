@@ -0,0 +1,48 @@
+use kite::schema::{Schema, FieldId};
+use kite::document::FieldValue;
+use kite::segment::Segment;
+use kite::query::custom_score::DocValues;
+use kite::term_vector::TermVector;
+
+use decode_stored_field_value;
+use term_vectors::decode_term_vector;
+
+/// Reads the stored values of a single document in a segment
+///
+/// Values that cannot be loaded or decoded are treated as missing.
+pub struct SegmentDocValues<'a, S: Segment + 'a> {
+    schema: &'a Schema,
+    segment: &'a S,
+    doc_id: u32,
+}
+
+impl<'a, S: Segment + 'a> SegmentDocValues<'a, S> {
+    pub fn new(schema: &'a Schema, segment: &'a S, doc_id: u32) -> SegmentDocValues<'a, S> {
+        SegmentDocValues {
+            schema: schema,
+            segment: segment,
+            doc_id: doc_id,
+        }
+    }
+}
+
+impl<'a, S: Segment + 'a> DocValues for SegmentDocValues<'a, S> {
+    fn get(&self, field_id: FieldId) -> Option<FieldValue> {
+        let field_info = match self.schema.get(&field_id) {
+            Some(field_info) => field_info,
+            None => return None,
+        };
+
+        match self.segment.load_stored_field_value_raw(self.doc_id, field_id, b"val") {
+            Ok(Some(value)) => decode_stored_field_value(&field_info.field_type, &value).ok(),
+            _ => None,
+        }
+    }
+
+    fn get_term_vector(&self, field_id: FieldId) -> Option<TermVector> {
+        match self.segment.load_stored_field_value_raw(self.doc_id, field_id, b"tv") {
+            Ok(Some(value)) => decode_term_vector(&value).ok(),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use kite::{DocId, MultiTermSelector};
+use kite::schema::{FieldId, FieldType};
+use kite::segment::Segment;
+use kite::query::Query;
+use kite::facet::FacetPath;
+use kite::collectors::{Collector, DocumentMatch};
+use roaring::RoaringBitmap;
+use fnv::FnvHashMap;
+
+use RocksDBReader;
+
+/// The number of matching documents in a facet path
+#[derive(Debug, Clone, PartialEq)]
+pub struct FacetCount {
+    pub path: FacetPath,
+    pub count: u64,
+}
+
+/// Collects the ids of matching documents, grouped by segment
+struct MatchingDocsCollector {
+    docs: FnvHashMap<u32, RoaringBitmap>,
+}
+
+impl Collector for MatchingDocsCollector {
+    fn needs_score(&self) -> bool {
+        false
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        let DocId(segment, ord) = DocId::from_u64(doc.doc_id());
+        self.docs.entry(segment.0).or_insert_with(RoaringBitmap::new).insert(ord);
+    }
+}
+
+impl<'a> RocksDBReader<'a> {
+    /// Counts the documents matching the query in each facet path "depth" levels below "parent"
+    ///
+    /// Use FacetPath::root() as the parent to count the top level paths. To drill down,
+    /// filter the query with Query::facet and pass the same path as the parent. Paths
+    /// without any matching documents are left out, the rest are returned with the highest
+    /// counts first.
+    pub fn facet_counts(&self, query: &Query, field: FieldId, parent: &FacetPath, depth: usize) -> Result<Vec<FacetCount>, String> {
+        match self.schema().get(&field) {
+            Some(field_info) if field_info.field_type == FieldType::Facet => {}
+            Some(_) => return Err(format!("field {:?} isn't a facet field", field)),
+            None => return Err(format!("field {:?} doesn't exist", field)),
+        }
+
+        if depth == 0 {
+            return Err("facet depth must be at least 1".to_string());
+        }
+
+        // Paths at the requested depth are all indexed with terms that start with this prefix
+        let term_selector = MultiTermSelector::Prefix(parent.descendant_term_prefix(depth));
+        let terms = self.store.term_dictionary.select_terms(&term_selector);
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut collector = MatchingDocsCollector {
+            docs: FnvHashMap::default(),
+        };
+        try!(self.search(&mut collector, query));
+
+        let mut counts = FnvHashMap::default();
+        for segment in self.store.segments.iter_active(self) {
+            let matching_docs = match collector.docs.get(&segment.id().0) {
+                Some(matching_docs) => matching_docs,
+                None => continue,
+            };
+
+            for &(ref term, term_id) in terms.iter() {
+                if let Some(mut term_directory) = try!(segment.load_term_directory(field, term_id)) {
+                    term_directory.intersect_with(matching_docs);
+                    if !term_directory.is_empty() {
+                        *counts.entry(term.clone()).or_insert(0) += term_directory.len();
+                    }
+                }
+            }
+        }
+
+        let mut facet_counts = counts.into_iter()
+            .filter_map(|(term, count)| {
+                FacetPath::from_term(&term).map(|path| FacetCount {
+                    path: path,
+                    count: count,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        facet_counts.sort_by(|a, b| b.count.cmp(&a.count).then(a.path.cmp(&b.path)));
+        Ok(facet_counts)
+    }
+}
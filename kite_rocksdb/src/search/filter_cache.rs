@@ -0,0 +1,225 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use kite::query::Query;
+use kite::query::multi_term_rewrite::MultiTermRewrite;
+use kite::schema::Schema;
+use kite::segment::Segment;
+use kite::doc_id_set::DocIdSet;
+use kite::error::SearchError;
+use fnv::FnvHasher;
+
+use lru_cache::LruCache;
+use search::run_boolean_query;
+use search::planner::boolean_query::BooleanQueryOp;
+
+/// The most bytes of filter matches that are cached, unless the store is configured otherwise
+pub const DEFAULT_FILTER_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// The number of recent filters that are remembered to decide which ones are used frequently
+const FILTER_HISTORY_SIZE: usize = 256;
+
+/// A filter's matches are cached once it has been used this many times in the recent filters
+const MIN_FILTER_USES: usize = 2;
+
+/// Checks if the documents that a query matches in a segment can only change when the
+/// segment does
+///
+/// Verifiers can't be told apart from each other so aren't cached. Joins depend on the
+/// documents in other segments and limited term expansions depend on the terms of other
+/// segments, so both could match different documents in the same segment later.
+fn is_cacheable(query: &Query) -> bool {
+    match *query {
+        Query::All{..} | Query::None | Query::Term{..} | Query::Span{..} | Query::Terms{..} => true,
+        Query::Exists{..} | Query::Range{..} | Query::RankFeature{..} => true,
+        Query::MultiTerm{rewrite: MultiTermRewrite::TopTermsByDocFrequency(_), ..} => false,
+        Query::MultiTerm{max_expansions, ..} => max_expansions.is_none(),
+        Query::Conjunction{ref queries} | Query::Disjunction{ref queries} | Query::DisjunctionMax{ref queries} => {
+            queries.iter().all(is_cacheable)
+        }
+        Query::Boolean{ref must, ref should, ref must_not, ..} => {
+            must.iter().chain(should.iter()).chain(must_not.iter()).all(is_cacheable)
+        }
+        Query::Filter{ref query, filter: ref other} | Query::Exclude{ref query, exclude: ref other} => {
+            is_cacheable(query) && is_cacheable(other)
+        }
+        Query::Boosting{positive: ref query, ..} | Query::ConstantScore{ref query, ..} |
+        Query::CustomScore{ref query, ..} | Query::FieldValueFactor{ref query, ..} |
+        Query::FunctionScore{ref query, ..} => is_cacheable(query),
+        Query::HasChild{..} | Query::HasParent{..} | Query::Verify{..} => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    filter: Arc<String>,
+    segment: u32,
+}
+
+struct FilterCacheState {
+    /// Hashes of the most recently used filters, oldest first
+    history: VecDeque<u64>,
+    matches: LruCache<CacheKey, Arc<DocIdSet>>,
+}
+
+/// A size-bounded cache of the documents that frequently used filters match in each segment,
+/// shared by all of a store's readers
+///
+/// Filters are identified by their debug representation. As segments never change once
+/// they're written, the matches of a segment are kept until it's purged. Deleted documents
+/// aren't excluded from the cached matches, the plans that use them exclude these separately.
+pub struct FilterCache {
+    state: Mutex<FilterCacheState>,
+}
+
+impl FilterCache {
+    pub fn new(capacity: usize) -> FilterCache {
+        FilterCache {
+            state: Mutex::new(FilterCacheState {
+                history: VecDeque::with_capacity(FILTER_HISTORY_SIZE),
+                matches: LruCache::new(capacity),
+            }),
+        }
+    }
+
+    /// Returns the most bytes of filter matches that can be cached
+    pub fn capacity(&self) -> usize {
+        self.state.lock().unwrap().matches.capacity()
+    }
+
+    /// Changes the capacity, evicting matches if it's now too large
+    pub fn set_capacity(&self, capacity: usize) {
+        self.state.lock().unwrap().matches.set_capacity(capacity);
+    }
+
+    /// Returns the key that a filter's matches would be cached under, if it can be cached
+    pub fn filter_key(&self, filter: &Query) -> Option<Arc<String>> {
+        if is_cacheable(filter) && self.capacity() > 0 {
+            Some(Arc::new(format!("{:?}", filter)))
+        } else {
+            None
+        }
+    }
+
+    /// Records that a search used the filter, returns true if it's used frequently enough to
+    /// cache its matches
+    pub fn record_use(&self, filter: &str) -> bool {
+        let mut hasher = FnvHasher::default();
+        filter.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut state = self.state.lock().unwrap();
+        if state.history.len() == FILTER_HISTORY_SIZE {
+            state.history.pop_front();
+        }
+        state.history.push_back(hash);
+
+        state.history.iter().filter(|used| **used == hash).count() >= MIN_FILTER_USES
+    }
+
+    pub fn get(&self, filter: &Arc<String>, segment: u32) -> Option<Arc<DocIdSet>> {
+        let key = CacheKey { filter: filter.clone(), segment: segment };
+        self.state.lock().unwrap().matches.get(&key)
+    }
+
+    fn insert(&self, filter: &Arc<String>, segment: u32, matches: Arc<DocIdSet>) {
+        let key = CacheKey { filter: filter.clone(), segment: segment };
+        let size = filter.len() + matches.heap_size();
+        self.state.lock().unwrap().matches.insert(key, matches, size);
+    }
+
+    /// Removes the matches in segments that have been purged
+    pub fn remove_segments(&self, segments: &[u32]) {
+        self.state.lock().unwrap().matches.retain(|key| !segments.contains(&key.segment));
+    }
+}
+
+/// A filter in a boolean query whose matches are read from the filter cache, or are
+/// cached after they've been found
+#[derive(Clone)]
+pub struct CachedFilter {
+    cache: Arc<FilterCache>,
+    filter: Arc<String>,
+    boolean_query: Arc<Vec<BooleanQueryOp>>,
+    is_negated: bool,
+}
+
+impl CachedFilter {
+    pub fn new(cache: Arc<FilterCache>, filter: Arc<String>, boolean_query: Vec<BooleanQueryOp>, is_negated: bool) -> CachedFilter {
+        CachedFilter {
+            cache: cache,
+            filter: filter,
+            boolean_query: Arc::new(boolean_query),
+            is_negated: is_negated,
+        }
+    }
+
+    /// Returns the documents that the filter matches in the segment, including deleted ones
+    pub fn matches<S: Segment>(&self, schema: &Schema, segment: &S) -> Result<DocIdSet, SearchError> {
+        let segment_id = segment.id().0;
+        if let Some(matches) = self.cache.get(&self.filter, segment_id) {
+            return Ok((*matches).clone());
+        }
+
+        let matches = try!(run_boolean_query(&self.boolean_query, self.is_negated, schema, segment));
+        self.cache.insert(&self.filter, segment_id, Arc::new(matches.clone()));
+        Ok(matches)
+    }
+}
+
+impl PartialEq for CachedFilter {
+    fn eq(&self, other: &CachedFilter) -> bool {
+        self.filter == other.filter && self.boolean_query == other.boolean_query && self.is_negated == other.is_negated
+    }
+}
+
+impl fmt::Debug for CachedFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CachedFilter({})", self.filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kite::{Query, Term};
+    use kite::schema::FieldId;
+    use kite::query::verify::MatchVerifier;
+
+    use super::{FilterCache, FILTER_HISTORY_SIZE};
+
+    #[test]
+    fn test_record_use() {
+        let cache = FilterCache::new(1024);
+        assert!(!cache.record_use("published"));
+        assert!(!cache.record_use("draft"));
+        assert!(cache.record_use("published"));
+
+        // Filters are forgotten once enough other filters have been used since
+        for i in 0..FILTER_HISTORY_SIZE {
+            cache.record_use(&i.to_string());
+        }
+        assert!(!cache.record_use("published"));
+    }
+
+    #[test]
+    fn test_filter_key() {
+        let cache = FilterCache::new(1024);
+        let published = Query::term(FieldId(1), Term::from_string("true"));
+        assert_eq!(cache.filter_key(&published), cache.filter_key(&Query::term(FieldId(1), Term::from_string("true"))));
+        assert!(cache.filter_key(&published) != cache.filter_key(&Query::term(FieldId(1), Term::from_string("false"))));
+
+        // Queries that can match different documents in the same segment later aren't cached
+        assert_eq!(cache.filter_key(&Query::has_child(FieldId(2), Query::all())), None);
+        assert_eq!(cache.filter_key(&Query::prefix(FieldId(1), "t").max_expansions(Some(10))), None);
+        assert_eq!(cache.filter_key(&Query::Verify {
+            approximation: Box::new(Query::all()),
+            verifier: MatchVerifier::new(|_| true),
+        }), None);
+
+        // Nothing's cached while the cache is disabled
+        cache.set_capacity(0);
+        assert_eq!(cache.filter_key(&published), None);
+    }
+}
@@ -0,0 +1,176 @@
+use std::str;
+use std::fmt;
+use std::sync::Arc;
+
+use kite::{DocId, TermId};
+use kite::query::Query;
+use kite::schema::FieldId;
+use kite::segment::{Segment, SegmentId};
+use kite::doc_id_set::DocIdSet;
+use fnv::{FnvHashMap, FnvHashSet};
+
+use RocksDBReader;
+use key_builder::{KeyBuilder, Key, KeyIterator};
+use search::run_boolean_query;
+use search::planner::plan_query;
+
+/// Relates the children of a join field to their parents
+///
+/// Documents are identified by their ids in the reader's snapshot, which is what allows the
+/// map to be built once per reader and reused by every join query on the field. Parents and
+/// children can be updated independently, a new reader will see the new relationships.
+pub struct JoinMap {
+    parents: FnvHashMap<DocId, DocId>,
+    children: FnvHashMap<DocId, Vec<DocId>>,
+}
+
+/// Converts term directory keys "d1/2/3" into the term id (2) and segment id (3)
+impl JoinMap {
+    fn build(reader: &RocksDBReader, field: FieldId) -> Result<JoinMap, String> {
+        // Each term in a join field is the key of a parent, its term directory in each segment
+        // lists the children of that parent
+        let prefix = KeyBuilder::field_dir_list_prefix(field.0);
+        let mut term_directories = Vec::new();
+
+        for segment in reader.generation.segments() {
+            if let Some(file) = reader.generation.segment_file(*segment) {
+                for (k, v) in file.iter().skip_while(|&(k, _)| !k.starts_with(prefix.key())).take_while(|&(k, _)| k.starts_with(prefix.key())) {
+                    if let Some(Key::TermDirectory { term_id, .. }) = Key::parse(k) {
+                        let term_directory = try!(DocIdSet::deserialize_from(v).map_err(|e| format!("term directory of segment {}: {}", segment, e)));
+                        term_directories.push((TermId(term_id), *segment, term_directory));
+                    }
+                }
+            }
+        }
+
+        for (key, v) in KeyIterator::new(reader.snapshot.raw_iterator(), prefix) {
+            if let Key::TermDirectory { term_id, segment, .. } = key {
+                // Segments with files are read from the file above. Any other segments that
+                // aren't in the reader's generation are being built or have been merged away.
+                if reader.generation.segments().contains(&segment) && reader.generation.segment_file(segment).is_none() {
+                    let term_directory = try!(DocIdSet::deserialize_from(&v[..]).map_err(|e| format!("term directory of segment {}: {}", segment, e)));
+                    term_directories.push((TermId(term_id), segment, term_directory));
+                }
+            }
+        }
+
+        let term_ids = term_directories.iter().map(|&(term_id, _, _)| term_id).collect::<FnvHashSet<TermId>>();
+        let terms = reader.store.term_dictionary.get_terms(&term_ids);
+
+        let mut parent_ids = FnvHashMap::default();
+        for (term_id, term) in terms.iter() {
+            if let Ok(key) = str::from_utf8(term.as_bytes()) {
+                // Children of parents that don't exist aren't joined to anything
+                if let Some(parent) = try!(reader.get_document_id(key)) {
+                    parent_ids.insert(*term_id, parent);
+                }
+            }
+        }
+
+        let mut join_map = JoinMap {
+            parents: FnvHashMap::default(),
+            children: FnvHashMap::default(),
+        };
+
+        for (term_id, segment, doc_id_set) in term_directories {
+            let parent = match parent_ids.get(&term_id) {
+                Some(parent) => *parent,
+                None => continue,
+            };
+
+            for doc_local_id in doc_id_set.iter() {
+                let child = DocId(SegmentId(segment), doc_local_id);
+                join_map.parents.insert(child, parent);
+                join_map.children.entry(parent).or_insert_with(Vec::new).push(child);
+            }
+        }
+
+        Ok(join_map)
+    }
+
+    /// Returns the parent of a document, if it's a child
+    pub fn parent(&self, child: DocId) -> Option<DocId> {
+        self.parents.get(&child).cloned()
+    }
+
+    /// Returns the children of a document
+    ///
+    /// This may include children that have been deleted, they're excluded when searching.
+    pub fn children(&self, parent: DocId) -> &[DocId] {
+        self.children.get(&parent).map(|children| &children[..]).unwrap_or(&[])
+    }
+}
+
+/// The documents in each segment that are joined to the matches of a has_child or has_parent query
+#[derive(Clone)]
+pub struct JoinMatches(Arc<FnvHashMap<SegmentId, DocIdSet>>);
+
+impl JoinMatches {
+    pub fn get(&self, segment_id: SegmentId) -> Option<&DocIdSet> {
+        self.0.get(&segment_id)
+    }
+
+    /// Returns the total number of matches across all segments
+    pub fn len(&self) -> u64 {
+        self.0.values().map(|doc_id_set| doc_id_set.len() as u64).sum()
+    }
+}
+
+impl PartialEq for JoinMatches {
+    fn eq(&self, other: &JoinMatches) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for JoinMatches {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JoinMatches({} segments)", self.0.len())
+    }
+}
+
+impl<'a> RocksDBReader<'a> {
+    /// Returns the join map of a field, building it if this is the first time it's been used
+    /// by this reader
+    pub fn join_map(&self, field: FieldId) -> Result<Arc<JoinMap>, String> {
+        if let Some(join_map) = self.join_maps.lock().unwrap().get(&field) {
+            return Ok(join_map.clone());
+        }
+
+        // Built without holding the lock so other join fields can be used at the same time
+        let join_map = Arc::new(try!(JoinMap::build(self, field)));
+        self.join_maps.lock().unwrap().insert(field, join_map.clone());
+        Ok(join_map)
+    }
+
+    fn run_join(&self, field: FieldId, query: &Query, to_parents: bool) -> Result<FnvHashMap<SegmentId, DocIdSet>, String> {
+        let join_map = try!(self.join_map(field));
+        let plan = try!(plan_query(self, query, false));
+
+        let mut matches = FnvHashMap::default();
+        for segment in self.store.segments.iter_active(self) {
+            let doc_id_set = try!(run_boolean_query(&plan.boolean_query, plan.boolean_query_is_negated, self.schema(), &segment));
+
+            for doc_local_id in doc_id_set.iter() {
+                let doc_id = DocId(segment.id(), doc_local_id);
+
+                if to_parents {
+                    if let Some(parent) = join_map.parent(doc_id) {
+                        matches.entry(parent.0).or_insert_with(DocIdSet::new).insert(parent.1);
+                    }
+                } else {
+                    for child in join_map.children(doc_id) {
+                        matches.entry(child.0).or_insert_with(DocIdSet::new).insert(child.1);
+                    }
+                }
+            }
+        }
+
+        Ok(matches)
+    }
+
+    /// Finds the documents that match "query" and returns their parents (if "to_parents" is
+    /// true) or their children
+    pub(crate) fn join_matches(&self, field: FieldId, query: &Query, to_parents: bool) -> Result<JoinMatches, String> {
+        Ok(JoinMatches(Arc::new(try!(self.run_join(field, query, to_parents)))))
+    }
+}
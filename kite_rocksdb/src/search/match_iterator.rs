@@ -0,0 +1,236 @@
+use kite::schema::FieldId;
+use kite::doc_id_set::{DocIdSet, IntoIter as DocIdSetIter};
+use kite::segment::Segment;
+use kite::collectors::DocumentMatch;
+use kite::error::SearchError;
+use fnv::FnvHashMap;
+
+use RocksDBReader;
+use segment::RocksDBSegment;
+use segment_manager::ActiveSegmentsIterator;
+use search::{run_boolean_query, score_doc, load_rank_feature_columns, load_negative_matches};
+use search::statistics::RocksDBStatisticsReader;
+use search::deadline::Deadline;
+use search::block_max::ScoreUpperBounds;
+use search::planner::SearchPlan;
+use index_sort::IndexSort;
+use logging::Span;
+
+/// The remaining matches of the segment that is currently being searched
+struct SegmentMatches<'a> {
+    segment: RocksDBSegment<'a>,
+    matches: DocIdSetIter,
+    rank_features: FnvHashMap<FieldId, Vec<f32>>,
+
+    /// The documents matched by the negative queries of the score function's Boosting operations
+    negative_matches: Vec<DocIdSet>,
+
+    sort_values: Vec<Option<i64>>,
+
+    /// The order the segment's documents were written in, only loaded when sorting
+    index_sort: Option<IndexSort>,
+
+    /// Loaded the first time a minimum competitive score is given
+    score_bounds: Option<Option<ScoreUpperBounds>>,
+
+    /// Entered while the segment is being searched
+    span: Span,
+}
+
+/// Lazily yields the documents that match a query
+///
+/// Segments are searched one at a time as the iterator is advanced and each document
+/// is scored just before it's yielded. If an error occurs, it's yielded and the
+/// iterator stops.
+///
+/// If a deadline is set, the iterator stops early once it passes. If the reader's
+/// cancellation token is cancelled, an error is yielded and the iterator stops. These are
+/// checked before each segment and after each block of documents.
+pub struct MatchIterator<'a> {
+    reader: &'a RocksDBReader<'a>,
+    plan: SearchPlan,
+    stats: RocksDBStatisticsReader<'a>,
+    segments: ActiveSegmentsIterator<'a>,
+    current_segment: Option<SegmentMatches<'a>>,
+    deadline: Deadline,
+    sort_field: Option<FieldId>,
+    fused: bool,
+}
+
+impl<'a> MatchIterator<'a> {
+    pub fn new(reader: &'a RocksDBReader<'a>, plan: SearchPlan) -> MatchIterator<'a> {
+        MatchIterator::for_segments(reader, plan, reader.generation.segments())
+    }
+
+    /// Only searches some of the reader's segments
+    pub fn for_segments(reader: &'a RocksDBReader<'a>, plan: SearchPlan, segments: &'a [u32]) -> MatchIterator<'a> {
+        MatchIterator {
+            reader: reader,
+            plan: plan,
+            stats: RocksDBStatisticsReader::new(reader),
+            segments: reader.store.segments.iter_segments(reader, segments),
+            current_segment: None,
+            deadline: Deadline::none().with_cancellation_token(reader.cancellation_token.clone()),
+            sort_field: None,
+            fused: false,
+        }
+    }
+
+    /// Stops the iterator once the deadline passes
+    pub fn set_deadline(&mut self, deadline: Deadline) {
+        self.deadline = deadline.with_cancellation_token(self.reader.cancellation_token.clone());
+    }
+
+    /// Attaches the value of this field to each document
+    ///
+    /// The values are read from the field's doc values column.
+    pub fn set_sort_field(&mut self, sort_field: Option<FieldId>) {
+        self.sort_field = sort_field;
+    }
+
+    /// Called when the deadline has passed or the search has been cancelled
+    fn stop(&mut self) -> Option<Result<DocumentMatch, SearchError>> {
+        self.fused = true;
+
+        if self.deadline.cancelled() {
+            Some(Err(SearchError::Cancelled))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the iterator was stopped because the deadline passed
+    pub fn timed_out(&self) -> bool {
+        self.deadline.timed_out()
+    }
+
+    /// Returns true if the documents of the segment currently being searched were written in this order
+    ///
+    /// Only known if a sort field has been set.
+    pub fn segment_is_sorted_by(&self, index_sort: IndexSort) -> bool {
+        match self.current_segment {
+            Some(ref current) => current.index_sort == Some(index_sort),
+            None => false,
+        }
+    }
+
+    /// Skips the remaining documents of the segment currently being searched
+    pub fn skip_segment(&mut self) {
+        self.current_segment = None;
+    }
+
+    fn start_segment(&self, segment: RocksDBSegment<'a>) -> Result<SegmentMatches<'a>, SearchError> {
+        let span = trace_span!("segment", segment = segment.id().0);
+        let _entered = span.enter();
+
+        let matches = try!(run_boolean_query(&self.plan.boolean_query, self.plan.boolean_query_is_negated, self.reader.schema(), &segment));
+        log_trace!("segment {} has {} matching documents", segment.id().0, matches.len());
+        let rank_features = try!(load_rank_feature_columns(&self.plan, &segment));
+        let negative_matches = try!(load_negative_matches(&self.plan, self.reader.schema(), &segment));
+        let (sort_values, index_sort) = match self.sort_field {
+            Some(sort_field) => (try!(segment.load_doc_values_column(sort_field)).unwrap_or_else(Vec::new), try!(segment.index_sort())),
+            None => (Vec::new(), None),
+        };
+
+        Ok(SegmentMatches {
+            segment: segment,
+            matches: matches.into_iter(),
+            rank_features: rank_features,
+            negative_matches: negative_matches,
+            sort_values: sort_values,
+            index_sort: index_sort,
+            score_bounds: None,
+            span: span.clone(),
+        })
+    }
+
+    /// Returns the next document that could score higher than "min_competitive_score"
+    ///
+    /// Documents are skipped if the impacts of the terms in the score function show they
+    /// can't beat it. This saves loading the term frequencies and field lengths needed to
+    /// score them.
+    pub fn next_competitive(&mut self, min_competitive_score: Option<f32>) -> Option<Result<DocumentMatch, SearchError>> {
+        while !self.fused {
+            if let Some(ref mut current) = self.current_segment {
+                if let Some(doc) = current.matches.next() {
+                    if self.deadline.tick() {
+                        return self.stop();
+                    }
+
+                    let _entered = current.span.enter();
+
+                    let doc_id = current.segment.doc_id(doc).as_u64();
+                    let sort_value = current.sort_values.get(doc as usize).cloned().unwrap_or(None);
+
+                    if self.plan.score_function.is_empty() {
+                        // Scores aren't needed
+                        return Some(Ok(DocumentMatch::new_unscored(doc_id).with_sort_value(sort_value)));
+                    }
+
+                    if let Some(score) = self.plan.constant_score() {
+                        // All documents have the same score (eg, the query only contains filters) so
+                        // there's no need to run the score function for each one
+                        return Some(Ok(DocumentMatch::new_scored(doc_id, score).with_sort_value(sort_value)));
+                    }
+
+                    if let Some(min_competitive_score) = min_competitive_score {
+                        if current.score_bounds.is_none() {
+                            match ScoreUpperBounds::load(&self.plan.score_function, &current.segment, &mut self.stats) {
+                                Ok(score_bounds) => current.score_bounds = Some(score_bounds),
+                                Err(e) => {
+                                    self.fused = true;
+                                    return Some(Err(e));
+                                }
+                            }
+                        }
+
+                        if let Some(Some(ref score_bounds)) = current.score_bounds {
+                            if !(score_bounds.max_score(doc, &self.plan.score_function, &current.rank_features) > min_competitive_score) {
+                                continue;
+                            }
+                        }
+                    }
+
+                    match score_doc(doc, &self.plan.score_function, self.reader.schema(), &current.segment, &current.rank_features, &current.negative_matches, &mut self.stats) {
+                        Ok(score) => return Some(Ok(DocumentMatch::new_scored(doc_id, score).with_sort_value(sort_value))),
+                        Err(e) => {
+                            self.fused = true;
+                            return Some(Err(e));
+                        }
+                    }
+                }
+            }
+
+            // Current segment finished, start the next one
+            match self.segments.next() {
+                Some(segment) => {
+                    if self.deadline.check() {
+                        return self.stop();
+                    }
+
+                    match self.start_segment(segment) {
+                        Ok(segment_matches) => self.current_segment = Some(segment_matches),
+                        Err(e) => {
+                            self.fused = true;
+                            return Some(Err(e));
+                        }
+                    }
+                }
+                None => {
+                    self.current_segment = None;
+                    self.fused = true;
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a> Iterator for MatchIterator<'a> {
+    type Item = Result<DocumentMatch, SearchError>;
+
+    fn next(&mut self) -> Option<Result<DocumentMatch, SearchError>> {
+        self.next_competitive(None)
+    }
+}
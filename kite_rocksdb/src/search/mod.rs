@@ -1,37 +1,224 @@
 mod statistics;
 mod planner;
+mod doc_values;
+mod match_iterator;
+mod wand;
+mod block_max;
+mod deadline;
+pub mod warm_queries;
+pub mod filter_cache;
+pub mod suggest;
+pub mod facets;
+pub mod scroll;
+pub mod join;
 
-use roaring::RoaringBitmap;
-use kite::segment::Segment;
+use std::time::{Instant, Duration};
+use std::thread;
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kite::DocId;
+use kite::segment::{Segment, SegmentId};
+use kite::doc_id_set::DocIdSet;
+use kite::postings::{PostingsIterator, BlockPostingsIterator};
+use kite::schema::{Schema, FieldId};
+use kite::term::TermId;
 use kite::query::Query;
-use kite::collectors::{Collector, DocumentMatch};
+use kite::query::term_scorer::TermScorer;
+use kite::query::rescore::Rescore;
+use kite::query::custom_score::DocValues;
+use kite::collectors::{Collector, ParallelCollector, DocumentMatch};
+use kite::collectors::top_score::TopScoreCollector;
+use kite::metrics;
+use kite::error::SearchError;
 use byteorder::{ByteOrder, LittleEndian};
+use fnv::FnvHashMap;
 
 use super::RocksDBReader;
 use search::statistics::{StatisticsReader, RocksDBStatisticsReader};
 use search::planner::{SearchPlan, plan_query};
 use search::planner::boolean_query::BooleanQueryOp;
 use search::planner::score_function::{CombinatorScorer, ScoreFunctionOp};
+use search::doc_values::SegmentDocValues;
+use search::match_iterator::MatchIterator;
+use search::wand::{WandQuery, search_segment_wand};
+use search::deadline::Deadline;
+use slow_query_log::HitCountingCollector;
+use key_builder::KeyBuilder;
+use index_sort::IndexSort;
+
+/// Information about how a search was run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchResult {
+    /// True if the search ran out of time before every matching document was collected
+    ///
+    /// The collector will contain the documents that were found before the deadline.
+    pub timed_out: bool,
+}
+
+/// Filters a set of documents by whether they appear in a postings list
+///
+/// If "keep_matches" is false, the documents that appear in the postings list are removed instead.
+fn filter_by_postings<P: PostingsIterator>(doc_id_set: &mut DocIdSet, postings: &mut P, keep_matches: bool) -> Result<(), SearchError> {
+    let mut matches = DocIdSet::new();
+    try!(doc_id_set.intersect_postings_into(postings, &mut matches));
+
+    if keep_matches {
+        *doc_id_set = matches;
+    } else {
+        doc_id_set.difference_with(&matches);
+    }
+
+    Ok(())
+}
+
+/// Finds the documents that are in at least "minimum" of the sets
+fn at_least(sets: &[DocIdSet], minimum: usize) -> DocIdSet {
+    if minimum == 0 {
+        return DocIdSet::new();
+    }
+
+    // "counts[i]" holds the documents that have been seen in at least i + 1 of the sets
+    let mut counts: Vec<DocIdSet> = vec![DocIdSet::new(); minimum];
+    for set in sets {
+        for i in (1..minimum).rev() {
+            let seen_again = counts[i - 1].intersection(set).collect::<DocIdSet>();
+            counts[i].union_with(&seen_again);
+        }
+
+        counts[0].union_with(set);
+    }
+
+    counts.pop().unwrap()
+}
+
+/// Finds the documents in a segment with a doc value between "min" and "max" (inclusive)
+///
+/// The range of the segment's doc values is checked first so the column isn't loaded if no
+/// documents in the segment can match.
+fn match_doc_values_range<S: Segment>(segment: &S, field_id: FieldId, min: Option<i64>, max: Option<i64>) -> Result<DocIdSet, SearchError> {
+    let in_range = |value: i64| min.map_or(true, |min| value >= min) && max.map_or(true, |max| value <= max);
+
+    let segment_min = try!(segment.load_statistic(&KeyBuilder::segment_stat_field_min_value_stat_name(field_id.0)));
+    let segment_max = try!(segment.load_statistic(&KeyBuilder::segment_stat_field_max_value_stat_name(field_id.0)));
+    let (segment_min, segment_max) = match (segment_min, segment_max) {
+        (Some(segment_min), Some(segment_max)) => (segment_min, segment_max),
+
+        // The segment doesn't have any values for the field
+        _ => return Ok(DocIdSet::new()),
+    };
+
+    // Skip the segment if none of its values can be in the range
+    if max.map_or(false, |max| segment_min > max) || min.map_or(false, |min| segment_max < min) {
+        return Ok(DocIdSet::new());
+    }
 
-fn run_boolean_query<S: Segment>(boolean_query: &Vec<BooleanQueryOp>, is_negated: bool, segment: &S) -> Result<RoaringBitmap, String> {
+    // If all of the segment's values are in the range and every document has one, they all match
+    if in_range(segment_min) && in_range(segment_max) {
+        let total_docs = try!(segment.load_statistic(b"total_docs")).unwrap_or(0);
+        let field_docs = try!(segment.load_statistic(&KeyBuilder::segment_stat_field_doc_count_stat_name(field_id.0))).unwrap_or(0);
+
+        if field_docs == total_docs {
+            return Ok(DocIdSet::full(total_docs as u32));
+        }
+    }
+
+    let mut doc_id_set = DocIdSet::new();
+    if let Some(column) = try!(segment.load_doc_values_column(field_id)) {
+        for (doc_id, value) in column.iter().enumerate() {
+            if value.map_or(false, &in_range) {
+                doc_id_set.insert(doc_id as u32);
+            }
+        }
+    }
+
+    Ok(doc_id_set)
+}
+
+fn run_boolean_query<S: Segment>(boolean_query: &Vec<BooleanQueryOp>, is_negated: bool, schema: &Schema, segment: &S) -> Result<DocIdSet, SearchError> {
     // Execute boolean query
     let mut stack = Vec::new();
     for op in boolean_query.iter() {
         match *op {
             BooleanQueryOp::PushEmpty => {
-                stack.push(RoaringBitmap::new());
+                stack.push(DocIdSet::new());
+            }
+            BooleanQueryOp::PushAll => {
+                let total_docs = try!(segment.load_statistic(b"total_docs")).unwrap_or(0);
+                stack.push(DocIdSet::full(total_docs as u32));
             }
             BooleanQueryOp::PushTermDirectory(field_id, term_id) => {
                 match try!(segment.load_term_directory(field_id, term_id)) {
-                    Some(doc_id_set) => stack.push(doc_id_set),
-                    None => stack.push(RoaringBitmap::new()),
+                    Some(doc_id_set) => stack.push(DocIdSet::from(&doc_id_set)),
+                    None => stack.push(DocIdSet::new()),
                 }
             }
             BooleanQueryOp::PushDeletionList => {
                     match try!(segment.load_deletion_list()) {
-                    Some(doc_id_set) => stack.push(doc_id_set),
-                    None => stack.push(RoaringBitmap::new()),
+                    Some(doc_id_set) => stack.push(DocIdSet::from(&doc_id_set)),
+                    None => stack.push(DocIdSet::new()),
+                }
+            }
+            BooleanQueryOp::PushRankFeature(field_id) => {
+                let mut doc_id_set = DocIdSet::new();
+
+                if let Some(column) = try!(segment.load_rank_feature_column(field_id)) {
+                    for (doc_id, value) in column.iter().enumerate() {
+                        if *value > 0.0 {
+                            doc_id_set.insert(doc_id as u32);
+                        }
+                    }
+                }
+
+                stack.push(doc_id_set);
+            }
+            BooleanQueryOp::PushFieldPresence(field_id) => {
+                match try!(segment.load_field_presence(field_id)) {
+                    Some(doc_id_set) => stack.push(DocIdSet::from(&doc_id_set)),
+                    None => stack.push(DocIdSet::new()),
+                }
+            }
+            BooleanQueryOp::PushDocValuesRange(field_id, min, max) => {
+                stack.push(try!(match_doc_values_range(segment, field_id, min, max)));
+            }
+            BooleanQueryOp::PushWarmQueryMatches(ref matches) => {
+                match matches.get(segment.id()) {
+                    Some(doc_id_set) => stack.push(doc_id_set.clone()),
+                    None => return Err(SearchError::Plan(format!("warm query hasn't been run on segment {}", segment.id().0))),
+                }
+            }
+            BooleanQueryOp::PushCachedFilter(ref filter) => {
+                stack.push(try!(filter.matches(schema, segment)));
+            }
+            BooleanQueryOp::PushJoinMatches(ref matches) => {
+                match matches.get(segment.id()) {
+                    Some(doc_id_set) => stack.push(doc_id_set.clone()),
+                    None => stack.push(DocIdSet::new()),
+                }
+            }
+            BooleanQueryOp::IntersectPostings(field_id, term_id) => {
+                let a = stack.last_mut().expect("boolean query executor: stack underflow");
+                let mut postings = try!(BlockPostingsIterator::new(segment, field_id, term_id));
+
+                try!(filter_by_postings(a, &mut postings, true));
+            }
+            BooleanQueryOp::ExcludePostings(field_id, term_id) => {
+                let a = stack.last_mut().expect("boolean query executor: stack underflow");
+                let mut postings = try!(BlockPostingsIterator::new(segment, field_id, term_id));
+
+                try!(filter_by_postings(a, &mut postings, false));
+            }
+            BooleanQueryOp::Verify(ref verifier) => {
+                let a = stack.last_mut().expect("boolean query executor: stack underflow");
+                let mut result = DocIdSet::new();
+
+                for doc_id in a.iter() {
+                    if verifier.matches(&SegmentDocValues::new(schema, segment, doc_id)) {
+                        result.insert(doc_id);
+                    }
                 }
+
+                *a = result;
             }
             BooleanQueryOp::And => {
                 let b = stack.pop().expect("boolean query executor: stack underflow");
@@ -51,6 +238,11 @@ fn run_boolean_query<S: Segment>(boolean_query: &Vec<BooleanQueryOp>, is_negated
 
                 a.difference_with(&b);
             }
+            BooleanQueryOp::AtLeast(count, minimum) => {
+                let start = stack.len().checked_sub(count as usize).expect("boolean query executor: stack underflow");
+                let sets = stack.split_off(start);
+                stack.push(at_least(&sets, minimum as usize));
+            }
         }
     }
 
@@ -64,11 +256,7 @@ fn run_boolean_query<S: Segment>(boolean_query: &Vec<BooleanQueryOp>, is_negated
     if is_negated {
         // Query returns a negated result so we need to correct this by inverting the returned bitmap
         let total_docs = try!(segment.load_statistic(b"total_docs")).unwrap_or(0);
-        let mut all_docs = RoaringBitmap::new();
-        for doc_id in 0..total_docs {
-            all_docs.insert(doc_id as u32);
-        }
-
+        let mut all_docs = DocIdSet::full(total_docs as u32);
         all_docs.difference_with(&matches);
         matches = all_docs;
     }
@@ -76,9 +264,40 @@ fn run_boolean_query<S: Segment>(boolean_query: &Vec<BooleanQueryOp>, is_negated
     Ok(matches)
 }
 
-fn score_doc<S: Segment, R: StatisticsReader>(doc_id: u16, score_function: &Vec<ScoreFunctionOp>, segment: &S, stats: &mut R) -> Result<f32, String> {
+/// Decodes a field length that was encoded by the segment builder
+#[inline]
+fn decode_field_length(encoded_length: u8) -> f32 {
+    let length_sqrt = (encoded_length as f32) / 3.0 + 1.0;
+    length_sqrt * length_sqrt
+}
+
+/// Scores a term in a document that's known to contain it
+fn score_term<S: Segment, R: StatisticsReader>(doc_id: u32, field_id: FieldId, term_id: TermId, scorer: &TermScorer, segment: &S, stats: &mut R) -> Result<f32, SearchError> {
+    // Read field length
+    // TODO: we only need this for BM25
+    let field_length_raw = try!(segment.load_stored_field_value_raw(doc_id, field_id, b"len"));
+    let field_length = match field_length_raw {
+        Some(value) => decode_field_length(value[0]),
+        None => 1.0
+    };
+
+    // Read term frequency
+    let mut value_type = vec![b't', b'f'];
+    value_type.extend(term_id.0.to_string().as_bytes());
+    let term_frequency_raw = try!(segment.load_stored_field_value_raw(doc_id, field_id, &value_type));
+    let term_frequency = match term_frequency_raw {
+        Some(value) => LittleEndian::read_i64(&value),
+        None => 1,
+    };
+
+    let score = scorer.similarity_model.score(term_frequency as u32, field_length, try!(stats.total_tokens(field_id)) as u64, try!(stats.total_docs(field_id)) as u64, try!(stats.term_document_frequency(field_id, term_id)) as u64);
+    Ok(score * scorer.boost)
+}
+
+fn score_doc<S: Segment, R: StatisticsReader>(doc_id: u32, score_function: &Vec<ScoreFunctionOp>, schema: &Schema, segment: &S, rank_features: &FnvHashMap<FieldId, Vec<f32>>, negative_matches: &[DocIdSet], stats: &mut R) -> Result<f32, SearchError> {
     // Execute score function
     let mut stack = Vec::new();
+    let mut negative_matches = negative_matches.iter();
     for op in score_function.iter() {
         match *op {
             ScoreFunctionOp::Literal(val) => stack.push(val),
@@ -86,29 +305,8 @@ fn score_doc<S: Segment, R: StatisticsReader>(doc_id: u16, score_function: &Vec<
                 // TODO: Check this isn't really slow
                 match try!(segment.load_term_directory(field_id, term_id)) {
                     Some(term_directory) => {
-                        if term_directory.contains(doc_id as u32) {
-                            // Read field length
-                            // TODO: we only need this for BM25
-                            let field_length_raw = try!(segment.load_stored_field_value_raw(doc_id, field_id, b"len"));
-                            let field_length = match field_length_raw {
-                                Some(value) => {
-                                    let length_sqrt = (value[0] as f32) / 3.0 + 1.0;
-                                    length_sqrt * length_sqrt
-                                }
-                                None => 1.0
-                            };
-
-                            // Read term frequency
-                            let mut value_type = vec![b't', b'f'];
-                            value_type.extend(term_id.0.to_string().as_bytes());
-                            let term_frequency_raw = try!(segment.load_stored_field_value_raw(doc_id, field_id, &value_type));
-                            let term_frequency = match term_frequency_raw {
-                                Some(value) => LittleEndian::read_i64(&value),
-                                None => 1,
-                            };
-
-                            let score = scorer.similarity_model.score(term_frequency as u32, field_length, try!(stats.total_tokens(field_id)) as u64, try!(stats.total_docs(field_id)) as u64, try!(stats.term_document_frequency(field_id, term_id)) as u64);
-                            stack.push(score * scorer.boost);
+                        if term_directory.contains(doc_id) {
+                            stack.push(try!(score_term(doc_id, field_id, term_id, scorer, segment, stats)));
                         } else {
                             stack.push(0.0f32);
                         }
@@ -143,6 +341,52 @@ fn score_doc<S: Segment, R: StatisticsReader>(doc_id: u16, score_function: &Vec<
 
                 stack.push(score);
             }
+            ScoreFunctionOp::CustomScore(ref function, boost) => {
+                let base_score = stack.pop().expect("document scorer: stack underflow");
+                let doc_values = SegmentDocValues::new(schema, segment, doc_id);
+
+                stack.push(function.score(base_score, &doc_values) * boost);
+            }
+            ScoreFunctionOp::FieldValueFactor(ref factor) => {
+                let base_score = stack.pop().expect("document scorer: stack underflow");
+                let doc_values = SegmentDocValues::new(schema, segment, doc_id);
+                let value = doc_values.get_i64(factor.field).map(|value| value as f64);
+
+                stack.push(factor.apply(base_score, value));
+            }
+            ScoreFunctionOp::FunctionScore(ref functions, combine) => {
+                let base_score = stack.pop().expect("document scorer: stack underflow");
+                let doc_values = SegmentDocValues::new(schema, segment, doc_id);
+
+                // Integers and dates are read the same way as doc values, dates in microseconds
+                let values = functions.iter().map(|function| {
+                    let value = doc_values.get(function.field()).and_then(|value| value.to_doc_value());
+                    function.compute(value.map(|value| value as f64))
+                });
+
+                stack.push(combine.combine(base_score, values));
+            }
+            ScoreFunctionOp::Boosting(_, _, negative_boost) => {
+                let base_score = stack.pop().expect("document scorer: stack underflow");
+                let matches = negative_matches.next().expect("document scorer: negative matches weren't loaded");
+
+                if matches.contains(doc_id) {
+                    stack.push(base_score * negative_boost);
+                } else {
+                    stack.push(base_score);
+                }
+            }
+            ScoreFunctionOp::RankFeature(field_id, ref function, boost) => {
+                let value = rank_features.get(&field_id)
+                    .and_then(|column| column.get(doc_id as usize).cloned())
+                    .unwrap_or(0.0f32);
+
+                if value > 0.0 {
+                    stack.push(function.score(value) * boost);
+                } else {
+                    stack.push(0.0f32);
+                }
+            }
         }
     }
 
@@ -154,32 +398,332 @@ fn score_doc<S: Segment, R: StatisticsReader>(doc_id: u16, score_function: &Vec<
     Ok(stack.pop().expect("document scorer: stack underflow"))
 }
 
-fn search_segment<C: Collector, S: Segment, R: StatisticsReader>(collector: &mut C, plan: &SearchPlan, segment: &S, stats: &mut R) -> Result<(), String> {
-    let matches = try!(run_boolean_query(&plan.boolean_query, plan.boolean_query_is_negated, segment));
+/// Loads the rank feature columns used by the score function
+/// These are small so we load them once per segment rather than once per document
+fn load_rank_feature_columns<S: Segment>(plan: &SearchPlan, segment: &S) -> Result<FnvHashMap<FieldId, Vec<f32>>, SearchError> {
+    let mut rank_features = FnvHashMap::default();
+
+    for op in plan.score_function.iter() {
+        if let ScoreFunctionOp::RankFeature(field_id, ..) = *op {
+            if !rank_features.contains_key(&field_id) {
+                let column = try!(segment.load_rank_feature_column(field_id)).unwrap_or_else(Vec::new);
+                rank_features.insert(field_id, column);
+            }
+        }
+    }
+
+    Ok(rank_features)
+}
 
-    // Score documents and pass to collector
-    for doc in matches.iter() {
-        let score = try!(score_doc(doc as u16, &plan.score_function, segment, stats));
+/// Runs the boolean queries of the Boosting operations in the score function
+/// The results are in the same order as the operations
+fn load_negative_matches<S: Segment>(plan: &SearchPlan, schema: &Schema, segment: &S) -> Result<Vec<DocIdSet>, SearchError> {
+    let mut negative_matches = Vec::new();
 
-        let doc_id = segment.doc_id(doc as u16);
-        let doc_match = DocumentMatch::new_scored(doc_id.as_u64(), score);
-        collector.collect(doc_match);
+    for op in plan.score_function.iter() {
+        if let ScoreFunctionOp::Boosting(ref boolean_query, is_negated, _) = *op {
+            negative_matches.push(try!(run_boolean_query(boolean_query, is_negated, schema, segment)));
+        }
     }
 
-    Ok(())
+    Ok(negative_matches)
 }
 
 impl<'a> RocksDBReader<'a> {
-    pub fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), String> {
-        // Plan query
-        let plan = plan_query(&self, query, collector.needs_score());
+    pub fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), SearchError> {
+        try!(self.search_until(collector, query, Deadline::none()));
+        Ok(())
+    }
+
+    /// Searches the index, giving up once "timeout" has elapsed
+    ///
+    /// If the search times out, the collector is left with the documents that were found
+    /// before the deadline and the result is flagged as "timed_out". The deadline is checked
+    /// before each segment and after each block of documents, so the search may overrun it
+    /// by the time it takes to process one block.
+    pub fn search_with_timeout<C: Collector>(&self, collector: &mut C, query: &Query, timeout: Duration) -> Result<SearchResult, SearchError> {
+        self.search_until(collector, query, Deadline::at(Instant::now() + timeout))
+    }
+
+    fn search_until<C: Collector>(&self, collector: &mut C, query: &Query, deadline: Deadline) -> Result<SearchResult, SearchError> {
+        let span = trace_span!("search", query = ?query);
+        let _entered = span.enter();
+
+        let start_time = Instant::now();
+        let plan = try!(plan_query(&self, query, collector.needs_score()).map_err(SearchError::Plan));
+        let planning_time = start_time.elapsed();
+        log_trace!("searching {:?}", query);
+
+        let mut collector = HitCountingCollector::new(collector);
+        let result = try!(self.execute_plan(&mut collector, plan, self.generation.segments(), deadline));
+        self.record_search(query, start_time, planning_time, collector.hits(), result);
+
+        Ok(result)
+    }
+
+    /// Searches the index, searching segments on up to "num_threads" threads at the same time
+    ///
+    /// Each thread takes the next segment that hasn't been searched yet until there are none
+    /// left, collecting the matches into its own collector. These are merged into "collector"
+    /// once every segment has been searched. A search with few segments won't use more than
+    /// one thread for each of them.
+    pub fn search_parallel<C: ParallelCollector>(&self, collector: &mut C, query: &Query, num_threads: usize) -> Result<SearchResult, SearchError> {
+        self.search_parallel_until(collector, query, num_threads, Deadline::none())
+    }
+
+    /// Searches the index on up to "num_threads" threads, giving up once "timeout" has elapsed
+    ///
+    /// Each thread checks the deadline before it takes another segment, as well as while
+    /// searching it. Like "search_with_timeout", the collector is left with the documents
+    /// that were found before the deadline and the result is flagged as "timed_out".
+    pub fn search_parallel_with_timeout<C: ParallelCollector>(&self, collector: &mut C, query: &Query, num_threads: usize, timeout: Duration) -> Result<SearchResult, SearchError> {
+        self.search_parallel_until(collector, query, num_threads, Deadline::at(Instant::now() + timeout))
+    }
+
+    fn search_parallel_until<C: ParallelCollector>(&self, collector: &mut C, query: &Query, num_threads: usize, deadline: Deadline) -> Result<SearchResult, SearchError> {
+        let span = trace_span!("search", query = ?query, threads = num_threads);
+        let _entered = span.enter();
+
+        let start_time = Instant::now();
+        let plan = try!(plan_query(&self, query, collector.needs_score()).map_err(SearchError::Plan));
+        let planning_time = start_time.elapsed();
+        log_trace!("searching {:?} on {} threads", query, num_threads);
+
+        let segments = self.generation.segments();
+        let next_segment = AtomicUsize::new(0);
+        let num_threads = num_threads.min(segments.len()).max(1);
+
+        let worker = |mut segment_collector: C| -> Result<(C, u64, bool), SearchError> {
+            // So the spans of the segments searched on this thread are children of the search's
+            let _entered = span.enter();
+
+            let mut deadline = deadline.clone().with_cancellation_token(self.cancellation_token.clone());
+            let mut hits = 0;
+            let mut timed_out = false;
+
+            // Each thread stops taking segments once the deadline passes
+            while !deadline.check() {
+                let segment = next_segment.fetch_add(1, Ordering::SeqCst);
+                if segment >= segments.len() {
+                    break;
+                }
+
+                let mut counting_collector = HitCountingCollector::new(&mut segment_collector);
+                let result = try!(self.execute_plan(&mut counting_collector, plan.clone(), &segments[segment..segment + 1], deadline.clone()));
+                hits += counting_collector.hits();
+                timed_out |= result.timed_out;
+            }
+
+            if deadline.cancelled() {
+                return Err(SearchError::Cancelled);
+            }
+
+            Ok((segment_collector, hits, timed_out || deadline.timed_out()))
+        };
+
+        let results = thread::scope(|scope| {
+            let threads = (0..num_threads)
+                .map(|_| {
+                    let segment_collector = collector.segment_collector();
+                    scope.spawn(|| worker(segment_collector))
+                })
+                .collect::<Vec<_>>();
+
+            threads.into_iter()
+                .map(|thread| thread.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect::<Vec<_>>()
+        });
+
+        let mut hits = 0;
+        let mut result = SearchResult {
+            timed_out: false,
+        };
+        for thread_result in results {
+            let (segment_collector, thread_hits, timed_out) = try!(thread_result);
+            collector.merge(segment_collector);
+            hits += thread_hits;
+            result.timed_out |= timed_out;
+        }
+
+        self.record_search(query, start_time, planning_time, hits, result);
+
+        Ok(result)
+    }
+
+    /// Logs a search and records its metrics
+    fn record_search(&self, query: &Query, start_time: Instant, planning_time: Duration, hits: u64, result: SearchResult) {
+        let total_time = start_time.elapsed();
+        log_debug!("search found {} documents in {:?} (planning took {:?}){}", hits, total_time, planning_time, if result.timed_out { ", timed out" } else { "" });
+        self.store.metrics.increment_counter(metrics::SEARCHES, 1);
+        self.store.metrics.record_histogram(metrics::SEARCH_LATENCY_SECONDS, metrics::duration_to_seconds(total_time));
+        self.store.metrics.record_histogram(metrics::SEARCH_HITS, hits as f64);
+        if result.timed_out {
+            self.store.metrics.increment_counter(metrics::SEARCH_TIMEOUTS, 1);
+        }
+
+        if let Some(ref slow_query_log) = self.store.slow_query_log {
+            slow_query_log.record(query, planning_time, total_time - planning_time, hits, result.timed_out);
+        }
+    }
+
+    /// Runs a plan on some of the reader's segments
+    fn execute_plan<C: Collector>(&self, collector: &mut C, plan: SearchPlan, segments: &[u32], deadline: Deadline) -> Result<SearchResult, SearchError> {
+        let mut deadline = deadline.with_cancellation_token(self.cancellation_token.clone());
+
+        // Disjunctions of terms can skip over documents that the collector won't keep
+        // This is only worth doing when the collector is ordering documents by score
+        let wand_query = match collector.sort_field() {
+            Some(_) => None,
+            None => WandQuery::from_plan(&plan),
+        };
+
+        if let Some(wand_query) = wand_query {
+            let mut stats = RocksDBStatisticsReader::new(&self);
+
+            for segment in self.store.segments.iter_segments(&self, segments) {
+                if deadline.check() {
+                    break;
+                }
 
-        // Initialise statistics reader
+                let segment_span = trace_span!("segment", segment = segment.id().0);
+                let _entered = segment_span.enter();
+
+                log_trace!("searching segment {} with WAND", segment.id().0);
+                try!(search_segment_wand(collector, &wand_query, &segment, &mut stats, &mut deadline));
+            }
+
+            if deadline.cancelled() {
+                return Err(SearchError::Cancelled);
+            }
+
+            return Ok(SearchResult {
+                timed_out: deadline.timed_out(),
+            });
+        }
+
+        let mut matches = MatchIterator::for_segments(&self, plan, segments);
+        matches.set_deadline(deadline);
+        matches.set_sort_field(collector.sort_field());
+
+        let collector_sort = match (collector.sort_field(), collector.sort_order()) {
+            (Some(field), Some(order)) => Some(IndexSort { field: field, order: order }),
+            _ => None,
+        };
+
+        // Documents that can't beat the lowest score in the collector are skipped
+        while let Some(doc) = matches.next_competitive(collector.min_competitive_score()) {
+            let doc = try!(doc);
+
+            if let Some(collector_sort) = collector_sort {
+                if !collector.is_competitive_sort_value(doc.sort_value()) && matches.segment_is_sorted_by(collector_sort) {
+                    // The rest of the segment sorts after this document so can't be competitive either
+                    matches.skip_segment();
+                    continue;
+                }
+            }
+
+            collector.collect(doc);
+        }
+
+        Ok(SearchResult {
+            timed_out: matches.timed_out(),
+        })
+    }
+
+    /// Counts the live documents that match the query
+    ///
+    /// This is much faster than collecting the documents with a TotalCountCollector as the
+    /// query is evaluated entirely with bitmap operations. Nothing is scored and no documents
+    /// are passed to a collector. Deleted documents aren't counted.
+    pub fn count(&self, query: &Query) -> Result<u64, SearchError> {
+        let plan = try!(plan_query(&self, query, false).map_err(SearchError::Plan));
+        let mut total = 0;
+
+        for segment in self.store.segments.iter_active(&self) {
+            if self.is_cancelled() {
+                return Err(SearchError::Cancelled);
+            }
+
+            let matches = try!(run_boolean_query(&plan.boolean_query, plan.boolean_query_is_negated, self.schema(), &segment));
+            total += matches.len() as u64;
+        }
+
+        Ok(total)
+    }
+
+    /// Counts the live documents in the index
+    ///
+    /// This only reads the number of documents and the deletion list of each segment.
+    pub fn count_all(&self) -> Result<u64, SearchError> {
+        let mut total = 0;
+
+        for segment in self.store.segments.iter_active(&self) {
+            let total_docs = try!(segment.load_statistic(b"total_docs")).unwrap_or(0) as u64;
+            let deleted_docs = try!(segment.load_deletion_list()).map(|deletion_list| deletion_list.len()).unwrap_or(0);
+            total += total_docs.saturating_sub(deleted_docs);
+        }
+
+        Ok(total)
+    }
+
+    /// Returns an iterator over the documents that match the query
+    ///
+    /// The query is evaluated lazily, one segment at a time, as the iterator is advanced.
+    /// If "score" is false, the documents are yielded without scores.
+    pub fn search_iter(&self, query: &Query, score: bool) -> Result<MatchIterator, SearchError> {
+        let plan = try!(plan_query(&self, query, score).map_err(SearchError::Plan));
+
+        Ok(MatchIterator::new(&self, plan))
+    }
+
+    /// Searches the index, re-scoring the top documents with a second query
+    ///
+    /// The top "window_size" documents of the query are collected first, then each of them is
+    /// re-scored with the rescore query before being passed to the collector. Only documents
+    /// in the window are passed to the collector.
+    pub fn search_with_rescore<C: Collector>(&self, collector: &mut C, query: &Query, rescore: &Rescore) -> Result<(), SearchError> {
+        if !collector.needs_score() {
+            // Scores won't be used so there's no point re-scoring
+            return self.search(collector, query);
+        }
+
+        // First pass, find the top documents using the original query
+        let mut top_docs = TopScoreCollector::new(rescore.window_size);
+        try!(self.search(&mut top_docs, query));
+
+        // Group the top documents by segment
+        let mut candidates: FnvHashMap<SegmentId, Vec<(u32, f32)>> = FnvHashMap::default();
+        for doc in top_docs.into_sorted_vec() {
+            let doc_id = DocId::from_u64(doc.doc_id());
+            let score = doc.score().unwrap_or(0.0f32);
+            candidates.entry(doc_id.0).or_insert_with(Vec::new).push((doc_id.1, score));
+        }
+
+        // Second pass, score the top documents with the rescore query
+        let plan = try!(plan_query(&self, &rescore.query, true).map_err(SearchError::Plan));
         let mut stats = RocksDBStatisticsReader::new(&self);
 
-        // Run query on each segment
         for segment in self.store.segments.iter_active(&self) {
-            try!(search_segment(collector, &plan, &segment, &mut stats));
+            let segment_candidates = match candidates.get(&segment.id()) {
+                Some(segment_candidates) => segment_candidates,
+                None => continue,
+            };
+
+            let matches = try!(run_boolean_query(&plan.boolean_query, plan.boolean_query_is_negated, self.schema(), &segment));
+            let rank_features = try!(load_rank_feature_columns(&plan, &segment));
+            let negative_matches = try!(load_negative_matches(&plan, self.schema(), &segment));
+
+            for &(doc, original_score) in segment_candidates.iter() {
+                let score = if matches.contains(doc) {
+                    let rescore_score = try!(score_doc(doc, &plan.score_function, self.schema(), &segment, &rank_features, &negative_matches, &mut stats));
+                    rescore.combine(original_score, rescore_score)
+                } else {
+                    rescore.no_match(original_score)
+                };
+
+                let doc_id = segment.doc_id(doc);
+                collector.collect(DocumentMatch::new_scored(doc_id.as_u64(), score));
+            }
         }
 
         Ok(())
@@ -1,19 +1,42 @@
 use std::rc::Rc;
 
-use kite::schema::FieldId;
+use kite::schema::{FieldId, FIELD_INDEXED, FIELD_STORED};
 use kite::term::TermId;
-use kite::Query;
+use kite::{Query, MultiTermSelector};
+use kite::query::verify::MatchVerifier;
 
 use RocksDBReader;
+use search::statistics::{StatisticsReader, RocksDBStatisticsReader};
+use search::warm_queries::WarmQueryMatches;
+use search::filter_cache::CachedFilter;
+use search::join::JoinMatches;
+use search::planner::multi_term::expand_multi_term;
+
+/// A postings iterator is used instead of loading a whole term directory when the set it's being
+/// intersected with (or excluded from) is estimated to be at least this many times smaller
+const POSTINGS_ITERATOR_COST_RATIO: u64 = 4;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum BooleanQueryOp {
     PushEmpty,
+    PushAll,
     PushTermDirectory(FieldId, TermId),
     PushDeletionList,
+    PushRankFeature(FieldId),
+    PushFieldPresence(FieldId),
+    PushDocValuesRange(FieldId, Option<i64>, Option<i64>),
+    PushWarmQueryMatches(WarmQueryMatches),
+    PushCachedFilter(CachedFilter),
+    PushJoinMatches(JoinMatches),
+    IntersectPostings(FieldId, TermId),
+    ExcludePostings(FieldId, TermId),
+    Verify(MatchVerifier),
     And,
     Or,
     AndNot,
+
+    /// Replaces the top "count" sets with the documents that are in at least "minimum" of them
+    AtLeast(u32, u32),
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -29,13 +52,27 @@ enum BooleanQueryBlock {
     Leaf {
         op: BooleanQueryOp,
         return_type: BooleanQueryBlockReturnType,
+        cost: u64,
     },
     Combinator {
         op: BooleanQueryOp,
         child_a: Rc<BooleanQueryBlock>,
         child_b: Rc<BooleanQueryBlock>,
         return_type: BooleanQueryBlockReturnType,
-    }
+        cost: u64,
+    },
+    Verify {
+        verifier: MatchVerifier,
+        child: Rc<BooleanQueryBlock>,
+        return_type: BooleanQueryBlockReturnType,
+        cost: u64,
+    },
+    AtLeast {
+        children: Vec<Rc<BooleanQueryBlock>>,
+        minimum: u32,
+        return_type: BooleanQueryBlockReturnType,
+        cost: u64,
+    },
 }
 
 impl BooleanQueryBlock {
@@ -45,6 +82,20 @@ impl BooleanQueryBlock {
         match *self {
             Leaf{return_type, ..} => return_type,
             Combinator{return_type, ..} => return_type,
+            Verify{return_type, ..} => return_type,
+            AtLeast{return_type, ..} => return_type,
+        }
+    }
+
+    /// An estimate of the number of documents in the set this block produces
+    fn cost(&self) -> u64 {
+        use self::BooleanQueryBlock::*;
+
+        match *self {
+            Leaf{cost, ..} => cost,
+            Combinator{cost, ..} => cost,
+            Verify{cost, ..} => cost,
+            AtLeast{cost, ..} => cost,
         }
     }
 
@@ -54,6 +105,8 @@ impl BooleanQueryBlock {
         match *self {
             Leaf{ref mut return_type, ..} => *return_type = new_type,
             Combinator{ref mut return_type, ..} => *return_type = new_type,
+            Verify{ref mut return_type, ..} => *return_type = new_type,
+            AtLeast{ref mut return_type, ..} => *return_type = new_type,
         }
     }
 
@@ -65,10 +118,41 @@ impl BooleanQueryBlock {
                 boolean_query.push(op.clone());
             }
             Combinator{ref op, ref child_a, ref child_b, ..} => {
+                // If the right hand side is a term directory that's much larger than the left hand
+                // side, it's cheaper to advance a postings iterator through the documents on the
+                // left than to load the whole term directory
+                if let Leaf{op: BooleanQueryOp::PushTermDirectory(field_id, term_id), cost, ..} = **child_b {
+                    if child_a.cost().saturating_mul(POSTINGS_ITERATOR_COST_RATIO) <= cost {
+                        match *op {
+                            BooleanQueryOp::And => {
+                                child_a.build(boolean_query);
+                                boolean_query.push(BooleanQueryOp::IntersectPostings(field_id, term_id));
+                                return;
+                            }
+                            BooleanQueryOp::AndNot => {
+                                child_a.build(boolean_query);
+                                boolean_query.push(BooleanQueryOp::ExcludePostings(field_id, term_id));
+                                return;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+
                 child_a.build(boolean_query);
                 child_b.build(boolean_query);
                 boolean_query.push(op.clone());
             }
+            Verify{ref verifier, ref child, ..} => {
+                child.build(boolean_query);
+                boolean_query.push(BooleanQueryOp::Verify(verifier.clone()));
+            }
+            AtLeast{ref children, minimum, ..} => {
+                for child in children.iter() {
+                    child.build(boolean_query);
+                }
+                boolean_query.push(BooleanQueryOp::AtLeast(children.len() as u32, minimum));
+            }
         }
     }
 }
@@ -92,6 +176,7 @@ impl BooleanQueryBuilder {
         self.stack.push(Rc::new(Leaf{
             op: PushEmpty,
             return_type: Empty,
+            cost: 0,
         }));
     }
 
@@ -103,10 +188,12 @@ impl BooleanQueryBuilder {
         self.stack.push(Rc::new(Leaf{
             op: PushEmpty,
             return_type: Full,
+            cost: 0,
         }));
     }
 
-    pub fn push_term_directory(&mut self, field_id: FieldId, term_id: TermId) {
+    /// Pushes a term directory, "doc_frequency" is the number of documents that contain the term
+    pub fn push_term_directory(&mut self, field_id: FieldId, term_id: TermId, doc_frequency: u64) {
         use self::BooleanQueryOp::*;
         use self::BooleanQueryBlock::*;
         use self::BooleanQueryBlockReturnType::*;
@@ -114,6 +201,7 @@ impl BooleanQueryBuilder {
         self.stack.push(Rc::new(Leaf{
             op: PushTermDirectory(field_id, term_id),
             return_type: Sparse,
+            cost: doc_frequency,
         }));
     }
 
@@ -125,14 +213,194 @@ impl BooleanQueryBuilder {
         self.stack.push(Rc::new(Leaf{
             op: PushDeletionList,
             return_type: Sparse,
+            cost: 0,
+        }));
+    }
+
+    pub fn push_rank_feature(&mut self, field_id: FieldId) {
+        use self::BooleanQueryOp::*;
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        // The number of documents with a value isn't known so always treat this as expensive
+        self.stack.push(Rc::new(Leaf{
+            op: PushRankFeature(field_id),
+            return_type: Sparse,
+            cost: u64::max_value(),
+        }));
+    }
+
+    /// Pushes the documents that have any value for the field
+    pub fn push_field_presence(&mut self, field_id: FieldId, doc_count: u64) {
+        use self::BooleanQueryOp::*;
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        self.stack.push(Rc::new(Leaf{
+            op: PushFieldPresence(field_id),
+            return_type: Sparse,
+            cost: doc_count,
+        }));
+    }
+
+    /// Pushes the documents with a doc value between "min" and "max" (inclusive)
+    pub fn push_doc_values_range(&mut self, field_id: FieldId, min: Option<i64>, max: Option<i64>) {
+        use self::BooleanQueryOp::*;
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        // The number of documents in the range isn't known so always treat this as expensive
+        self.stack.push(Rc::new(Leaf{
+            op: PushDocValuesRange(field_id, min, max),
+            return_type: Sparse,
+            cost: u64::max_value(),
+        }));
+    }
+
+    /// Pushes the cached matches of a warm query
+    pub fn push_warm_query_matches(&mut self, matches: WarmQueryMatches) {
+        use self::BooleanQueryOp::*;
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        let cost = matches.len();
+        self.stack.push(Rc::new(Leaf{
+            op: PushWarmQueryMatches(matches),
+            return_type: Sparse,
+            cost: cost,
+        }));
+    }
+
+    /// Pushes a filter whose matches are cached, "filter_builder" contains its plan
+    ///
+    /// The filter must produce a sparse set, see "is_sparse".
+    pub fn push_cached_filter<F: FnOnce(Vec<BooleanQueryOp>, bool) -> CachedFilter>(&mut self, filter_builder: &BooleanQueryBuilder, make_filter: F) {
+        use self::BooleanQueryOp::*;
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        let root_block = filter_builder.stack.last().unwrap();
+        let cost = if root_block.return_type() == Sparse { root_block.cost() } else { u64::max_value() };
+        let (boolean_query, is_negated) = filter_builder.build();
+        self.stack.push(Rc::new(Leaf{
+            op: PushCachedFilter(make_filter(boolean_query, is_negated)),
+            return_type: Sparse,
+            cost: cost,
+        }));
+    }
+
+    /// Pushes the query planned by another builder
+    pub fn push_builder(&mut self, mut other: BooleanQueryBuilder) {
+        self.stack.push(other.stack.pop().unwrap());
+    }
+
+    /// Returns true if the query that's been planned produces a set of documents that has to
+    /// be loaded (rather than nothing or everything)
+    pub fn is_sparse(&self) -> bool {
+        use self::BooleanQueryBlockReturnType::*;
+
+        match self.stack.last().map(|block| block.return_type()) {
+            Some(Sparse) | Some(NegatedSparse) => true,
+            _ => false,
+        }
+    }
+
+    /// Pushes the documents joined to the matches of a has_child or has_parent query
+    pub fn push_join_matches(&mut self, matches: JoinMatches) {
+        use self::BooleanQueryOp::*;
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        let cost = matches.len();
+        self.stack.push(Rc::new(Leaf{
+            op: PushJoinMatches(matches),
+            return_type: Sparse,
+            cost: cost,
+        }));
+    }
+
+    /// Pushes every document in the segment, including deleted ones
+    fn push_all(&mut self) {
+        use self::BooleanQueryOp::*;
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        self.stack.push(Rc::new(Leaf{
+            op: PushAll,
+            return_type: Sparse,
+            cost: u64::max_value(),
         }));
     }
 
+    /// Only keeps the documents of the top block that the verifier accepts
+    ///
+    /// The verifier is called on each document so, when this block is intersected with
+    /// another, the verification is moved above the intersection.
+    pub fn verify(&mut self, verifier: MatchVerifier) {
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        let child = self.stack.pop().expect("stack underflow");
+
+        let child = match child.return_type() {
+            Empty => {
+                self.push_empty();
+                return;
+            }
+            Sparse => child,
+            Full => {
+                self.push_all();
+                self.stack.pop().expect("stack underflow")
+            }
+            NegatedSparse => {
+                // The verifier can only filter a set of documents, so the negated set needs
+                // to be computed first
+                self.push_all();
+                self.stack.push(child);
+                self.and_combinator();
+                self.stack.pop().expect("stack underflow")
+            }
+        };
+
+        let cost = child.cost();
+        self.stack.push(Rc::new(Verify{
+            verifier: verifier,
+            child: child,
+            return_type: Sparse,
+            cost: cost,
+        }));
+    }
+
+    /// If the block "depth" places from the top of the stack is a verification that isn't negated,
+    /// replaces it with its child and returns the verifier so it can be applied after combining
+    /// the child with another block
+    fn unwrap_verify(&mut self, depth: usize) -> Option<MatchVerifier> {
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        let index = self.stack.len().checked_sub(depth + 1).expect("stack underflow");
+        let (verifier, child) = match *self.stack[index] {
+            Verify{ref verifier, ref child, return_type: Sparse, ..} => (verifier.clone(), child.clone()),
+            _ => return None,
+        };
+
+        self.stack[index] = child;
+        Some(verifier)
+    }
+
     pub fn and_combinator(&mut self) {
         use self::BooleanQueryOp::*;
         use self::BooleanQueryBlock::*;
         use self::BooleanQueryBlockReturnType::*;
 
+        // Intersecting with a verified block gives the same result as verifying the intersection,
+        // so verifications are done last and only on the documents that match both blocks
+        if let Some(verifier) = self.unwrap_verify(0).or_else(|| self.unwrap_verify(1)) {
+            self.and_combinator();
+            self.verify(verifier);
+            return;
+        }
+
         let b = self.stack.pop().expect("stack underflow");
         let a = self.stack.pop().expect("stack underflow");
 
@@ -146,9 +414,12 @@ impl BooleanQueryBuilder {
             (_, Empty) => self.push_empty(),
 
             (Sparse, Sparse) => {  // (a AND b)
-                // Intersection
+                // Intersection, starting with the smallest set
+                let (a, b) = if b.cost() < a.cost() { (b, a) } else { (a, b) };
+
                 self.stack.push(Rc::new(Combinator{
                     op: And,
+                    cost: a.cost().min(b.cost()),
                     child_a: a,
                     child_b: b,
                     return_type: Sparse,
@@ -159,6 +430,7 @@ impl BooleanQueryBuilder {
                 // Exclusion
                 self.stack.push(Rc::new(Combinator{
                     op: AndNot,
+                    cost: a.cost(),
                     child_a: a,
                     child_b: b,
                     return_type: Sparse,
@@ -169,6 +441,7 @@ impl BooleanQueryBuilder {
                 // Exclusion, with operands swapped
                 self.stack.push(Rc::new(Combinator{
                     op: AndNot,
+                    cost: b.cost(),
                     child_a: b,
                     child_b: a,
                     return_type: Sparse,
@@ -179,6 +452,7 @@ impl BooleanQueryBuilder {
                 // Negated union (NOT (a OR b))
                 self.stack.push(Rc::new(Combinator{
                     op: Or,
+                    cost: a.cost().saturating_add(b.cost()),
                     child_a: a,
                     child_b: b,
                     return_type: NegatedSparse,
@@ -187,6 +461,26 @@ impl BooleanQueryBuilder {
         }
     }
 
+    /// Intersects the top "n" blocks on the stack
+    ///
+    /// The blocks are combined in order of their estimated cost so the smallest sets are
+    /// evaluated first and the largest ones can be read with postings iterators.
+    pub fn and_combinator_many(&mut self, n: usize) {
+        let start = self.stack.len().checked_sub(n).expect("stack underflow");
+        let mut blocks = self.stack.split_off(start);
+        blocks.sort_by_key(|block| block.cost());
+
+        let mut blocks_iter = blocks.into_iter();
+        if let Some(first_block) = blocks_iter.next() {
+            self.stack.push(first_block);
+
+            for block in blocks_iter {
+                self.stack.push(block);
+                self.and_combinator();
+            }
+        }
+    }
+
     pub fn or_combinator(&mut self) {
         use self::BooleanQueryOp::*;
         use self::BooleanQueryBlock::*;
@@ -208,6 +502,7 @@ impl BooleanQueryBuilder {
                 // Union
                 self.stack.push(Rc::new(Combinator{
                     op: Or,
+                    cost: a.cost().saturating_add(b.cost()),
                     child_a: a,
                     child_b: b,
                     return_type: Sparse,
@@ -218,6 +513,7 @@ impl BooleanQueryBuilder {
                 // Negated exclusion, with operands swapped (NOT (b AND NOT a))
                 self.stack.push(Rc::new(Combinator{
                     op: AndNot,
+                    cost: b.cost(),
                     child_a: b,
                     child_b: a,
                     return_type: NegatedSparse,
@@ -228,6 +524,7 @@ impl BooleanQueryBuilder {
                 // Negated exclusion (NOT (a AND NOT b))
                 self.stack.push(Rc::new(Combinator{
                     op: AndNot,
+                    cost: a.cost(),
                     child_a: a,
                     child_b: b,
                     return_type: NegatedSparse,
@@ -238,6 +535,7 @@ impl BooleanQueryBuilder {
                 // Negated intersection (NOT (a AND b))
                 self.stack.push(Rc::new(Combinator{
                     op: And,
+                    cost: a.cost().min(b.cost()),
                     child_a: a,
                     child_b: b,
                     return_type: NegatedSparse,
@@ -251,6 +549,13 @@ impl BooleanQueryBuilder {
         use self::BooleanQueryBlock::*;
         use self::BooleanQueryBlockReturnType::*;
 
+        // As with intersections, a verification of the left block can be done after the exclusion
+        if let Some(verifier) = self.unwrap_verify(1) {
+            self.andnot_combinator();
+            self.verify(verifier);
+            return;
+        }
+
         let b = self.stack.pop().expect("stack underflow");
         let a = self.stack.pop().expect("stack underflow");
 
@@ -282,6 +587,7 @@ impl BooleanQueryBuilder {
                 // Exclusion
                 self.stack.push(Rc::new(Combinator{
                     op: AndNot,
+                    cost: a.cost(),
                     child_a: a,
                     child_b: b,
                     return_type: Sparse,
@@ -292,6 +598,7 @@ impl BooleanQueryBuilder {
                 // Intersection (data AND other_data)
                 self.stack.push(Rc::new(Combinator{
                     op: And,
+                    cost: a.cost().min(b.cost()),
                     child_a: a,
                     child_b: b,
                     return_type: Sparse,
@@ -302,6 +609,7 @@ impl BooleanQueryBuilder {
                 // Negated union (NOT (data OR other_data))
                 self.stack.push(Rc::new(Combinator{
                     op: Or,
+                    cost: a.cost().saturating_add(b.cost()),
                     child_a: a,
                     child_b: b,
                     return_type: NegatedSparse,
@@ -312,6 +620,7 @@ impl BooleanQueryBuilder {
                 // Exclusion, with operands swapped (b AND NOT a)
                 self.stack.push(Rc::new(Combinator{
                     op: AndNot,
+                    cost: b.cost(),
                     child_a: b,
                     child_b: a,
                     return_type: Sparse,
@@ -320,6 +629,65 @@ impl BooleanQueryBuilder {
         }
     }
 
+    /// Replaces the top "n" blocks on the stack with the documents that are in at least
+    /// "minimum" of them
+    ///
+    /// Full and empty blocks are resolved while planning, so this becomes an intersection or a
+    /// union when the minimum is all or one of the remaining blocks.
+    pub fn at_least_combinator(&mut self, n: usize, minimum: usize) {
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        let start = self.stack.len().checked_sub(n).expect("stack underflow");
+        let blocks = self.stack.split_off(start);
+
+        let mut minimum = minimum;
+        let mut children = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            match block.return_type() {
+                // Every document is in a full block, so one less of the other blocks is needed
+                Full => minimum = minimum.saturating_sub(1),
+                Empty => {}
+                Sparse => children.push(block),
+                NegatedSparse => {
+                    // Documents need to be counted, so the negated set needs to be computed first
+                    self.push_all();
+                    self.stack.push(block);
+                    self.and_combinator();
+                    children.push(self.stack.pop().expect("stack underflow"));
+                }
+            }
+        }
+
+        if minimum == 0 {
+            self.push_full();
+        } else if minimum > children.len() {
+            self.push_empty();
+        } else if minimum == 1 {
+            let count = children.len();
+            self.stack.extend(children);
+            for _ in 1..count {
+                self.or_combinator();
+            }
+        } else if minimum == children.len() {
+            let count = children.len();
+            self.stack.extend(children);
+            self.and_combinator_many(count);
+        } else {
+            // At most the documents in all but the smallest "minimum - 1" blocks can match
+            let mut costs = children.iter().map(|child| child.cost()).collect::<Vec<_>>();
+            costs.sort();
+            let cost = costs[minimum - 1..].iter().fold(0u64, |total, cost| total.saturating_add(*cost));
+
+            self.stack.push(Rc::new(AtLeast{
+                children: children,
+                minimum: minimum as u32,
+                return_type: Sparse,
+                cost: cost,
+            }));
+        }
+    }
+
     pub fn build(&self) -> (Vec<BooleanQueryOp>, bool) {
         use self::BooleanQueryBlockReturnType::*;
 
@@ -333,27 +701,80 @@ impl BooleanQueryBuilder {
     }
 }
 
-fn plan_boolean_query_combinator<J: Fn(&mut BooleanQueryBuilder) -> ()> (index_reader: &RocksDBReader, mut builder: &mut BooleanQueryBuilder, queries: &Vec<Query>, join_cb: J) {
+fn plan_boolean_query_combinator<J: Fn(&mut BooleanQueryBuilder) -> ()> (index_reader: &RocksDBReader, mut builder: &mut BooleanQueryBuilder, queries: &Vec<Query>, join_cb: J) -> Result<(), String> {
     match queries.len() {
         0 => {
             builder.push_empty();
         }
-        1 =>  plan_boolean_query(index_reader, &mut builder, &queries[0]),
+        1 => try!(plan_boolean_query(index_reader, &mut builder, &queries[0])),
         _ => {
             let mut query_iter = queries.iter();
-            plan_boolean_query(index_reader, &mut builder, query_iter.next().unwrap());
+            try!(plan_boolean_query(index_reader, &mut builder, query_iter.next().unwrap()));
 
             for query in query_iter {
-                plan_boolean_query(index_reader, &mut builder, query);
+                try!(plan_boolean_query(index_reader, &mut builder, query));
 
                 // Add the join operation
                 join_cb(&mut builder);
             }
         }
     }
+
+    Ok(())
 }
 
-pub fn plan_boolean_query(index_reader: &RocksDBReader, mut builder: &mut BooleanQueryBuilder, query: &Query) {
+/// Estimates the number of documents that contain a term
+fn term_doc_frequency(index_reader: &RocksDBReader, field_id: FieldId, term_id: TermId) -> u64 {
+    // This is only used for planning so, if it can't be read, just assume the term is common
+    RocksDBStatisticsReader::new(index_reader).term_document_frequency(field_id, term_id)
+        .map(|doc_frequency| doc_frequency as u64)
+        .unwrap_or(u64::max_value())
+}
+
+/// Estimates the number of documents that have a value for a field
+fn field_doc_count(index_reader: &RocksDBReader, field_id: FieldId) -> u64 {
+    // Like term_doc_frequency, assume the field is common if this can't be read
+    RocksDBStatisticsReader::new(index_reader).field_doc_count(field_id)
+        .map(|doc_count| doc_count as u64)
+        .unwrap_or(u64::max_value())
+}
+
+/// Plans the filter of a Filter query
+///
+/// Filters that are used frequently have their matches in each segment cached, so they aren't
+/// found again by each search that uses them.
+fn plan_filter(index_reader: &RocksDBReader, builder: &mut BooleanQueryBuilder, filter: &Query) -> Result<(), String> {
+    let filter_cache = &index_reader.store.filter_cache;
+    let filter_key = match filter_cache.filter_key(filter) {
+        Some(ref filter_key) if filter_cache.record_use(filter_key) => filter_key.clone(),
+        _ => {
+            try!(plan_boolean_query(index_reader, builder, filter));
+            return Ok(());
+        }
+    };
+
+    let mut filter_builder = BooleanQueryBuilder::new();
+    try!(plan_boolean_query(index_reader, &mut filter_builder, filter));
+
+    // Planning may have stopped part way through if the search was cancelled
+    if filter_builder.is_sparse() && !index_reader.is_cancelled() {
+        builder.push_cached_filter(&filter_builder, |boolean_query, is_negated| {
+            CachedFilter::new(filter_cache.clone(), filter_key, boolean_query, is_negated)
+        });
+    } else {
+        builder.push_builder(filter_builder);
+    }
+
+    Ok(())
+}
+
+pub fn plan_boolean_query(index_reader: &RocksDBReader, mut builder: &mut BooleanQueryBuilder, query: &Query) -> Result<(), String> {
+    // Use the cached matches if this is a warm query
+    if let Some(matches) = index_reader.store.warm_queries.find(index_reader, query) {
+        builder.push_warm_query_matches(matches);
+        return Ok(());
+    }
+
     match *query {
         Query::All{..} => {
             builder.push_full();
@@ -368,46 +789,189 @@ pub fn plan_boolean_query(index_reader: &RocksDBReader, mut builder: &mut Boolea
                 None => {
                     // Term doesn't exist, so will never match
                     builder.push_empty();
-                    return
+                    return Ok(());
+                }
+            };
+
+            builder.push_term_directory(field, term_id, term_doc_frequency(index_reader, field, term_id));
+        }
+        Query::Span{ref query, ..} => {
+            // Candidates must contain every term, their positions are checked by the verifier
+            let field = match query.field() {
+                Some(field) => field,
+                None => {
+                    builder.push_empty();
+                    return Ok(());
                 }
             };
 
-            builder.push_term_directory(field, term_id);
+            let mut term_ids = Vec::new();
+            for term in query.terms() {
+                match index_reader.store.term_dictionary.get(term) {
+                    Some(term_id) => term_ids.push(term_id),
+                    None => {
+                        // Term doesn't exist, so will never match
+                        builder.push_empty();
+                        return Ok(());
+                    }
+                }
+            }
+            term_ids.sort_by_key(|term_id| term_id.0);
+            term_ids.dedup();
+
+            for term_id in term_ids.iter() {
+                builder.push_term_directory(field, *term_id, term_doc_frequency(index_reader, field, *term_id));
+            }
+            builder.and_combinator_many(term_ids.len());
+
+            let query = query.clone();
+            builder.verify(MatchVerifier::new(move |doc_values| {
+                doc_values.get_term_vector(field).map_or(false, |term_vector| query.matches(&term_vector))
+            }));
         }
-        Query::MultiTerm{field, ref term_selector, ..} => {
+        Query::Terms{field, ref terms, ..} => {
+            // Terms that don't exist can't match anything so are skipped
+            builder.push_empty();
+            for term in terms.iter() {
+                if let Some(term_id) = index_reader.store.term_dictionary.get(term) {
+                    builder.push_term_directory(field, term_id, term_doc_frequency(index_reader, field, term_id));
+                    builder.or_combinator();
+                }
+            }
+        }
+        Query::MultiTerm{field, ref term_selector, rewrite, max_expansions, ..} => {
             // Get terms
             builder.push_empty();
-            for term_id in index_reader.store.term_dictionary.select(term_selector) {
-                builder.push_term_directory(field, term_id);
+            for term_id in try!(expand_multi_term(index_reader, field, term_selector, rewrite, max_expansions)) {
+                if index_reader.is_cancelled() {
+                    // Don't waste time expanding the rest of the terms, the executor will
+                    // notice the search was cancelled before it runs the query
+                    break;
+                }
+
+                builder.push_term_directory(field, term_id, term_doc_frequency(index_reader, field, term_id));
                 builder.or_combinator();
             }
         }
         Query::Conjunction{ref queries} => {
-            plan_boolean_query_combinator(index_reader, &mut builder, queries, |builder| builder.and_combinator());
+            if queries.is_empty() {
+                builder.push_empty();
+                return Ok(());
+            }
+
+            // Plan all the clauses first so they can be reordered by cost
+            for query in queries.iter() {
+                try!(plan_boolean_query(index_reader, &mut builder, query));
+            }
+
+            builder.and_combinator_many(queries.len());
         }
         Query::Disjunction{ref queries} => {
-            plan_boolean_query_combinator(index_reader, &mut builder, queries, |builder| builder.or_combinator());
+            try!(plan_boolean_query_combinator(index_reader, &mut builder, queries, |builder| builder.or_combinator()));
         }
         Query::DisjunctionMax{ref queries} => {
-            plan_boolean_query_combinator(index_reader, &mut builder, queries, |builder| builder.or_combinator());
+            try!(plan_boolean_query_combinator(index_reader, &mut builder, queries, |builder| builder.or_combinator()));
+        }
+        Query::Boolean{ref must, ref should, ref must_not, minimum_should_match} => {
+            if must.is_empty() && should.is_empty() {
+                builder.push_empty();
+                return Ok(());
+            }
+
+            // Without any "must" queries, at least one "should" query must match
+            let minimum_should_match = if must.is_empty() { minimum_should_match.max(1) } else { minimum_should_match };
+
+            for query in must.iter() {
+                try!(plan_boolean_query(index_reader, &mut builder, query));
+            }
+
+            let mut clauses = must.len();
+            if minimum_should_match > 0 {
+                for query in should.iter() {
+                    try!(plan_boolean_query(index_reader, &mut builder, query));
+                }
+                builder.at_least_combinator(should.len(), minimum_should_match);
+                clauses += 1;
+            }
+
+            builder.and_combinator_many(clauses);
+
+            for query in must_not.iter() {
+                try!(plan_boolean_query(index_reader, &mut builder, query));
+                builder.andnot_combinator();
+            }
         }
         Query::Filter{ref query, ref filter} => {
-            plan_boolean_query(index_reader, &mut builder, query);
-            plan_boolean_query(index_reader, &mut builder, filter);
+            try!(plan_boolean_query(index_reader, &mut builder, query));
+            try!(plan_filter(index_reader, &mut builder, filter));
             builder.and_combinator();
         }
         Query::Exclude{ref query, ref exclude} => {
-            plan_boolean_query(index_reader, &mut builder, query);
-            plan_boolean_query(index_reader, &mut builder, exclude);
+            try!(plan_boolean_query(index_reader, &mut builder, query));
+            try!(plan_boolean_query(index_reader, &mut builder, exclude));
             builder.andnot_combinator();
         }
+        Query::ConstantScore{ref query, ..} => {
+            try!(plan_boolean_query(index_reader, &mut builder, query));
+        }
+        Query::CustomScore{ref query, ..} => {
+            try!(plan_boolean_query(index_reader, &mut builder, query));
+        }
+        Query::FieldValueFactor{ref query, ..} => {
+            try!(plan_boolean_query(index_reader, &mut builder, query));
+        }
+        Query::FunctionScore{ref query, ..} => {
+            try!(plan_boolean_query(index_reader, &mut builder, query));
+        }
+        Query::Boosting{ref positive, ..} => {
+            try!(plan_boolean_query(index_reader, &mut builder, positive));
+        }
+        Query::Verify{ref approximation, ref verifier} => {
+            try!(plan_boolean_query(index_reader, &mut builder, approximation));
+            builder.verify(verifier.clone());
+        }
+        Query::Exists{field, ..} => {
+            builder.push_field_presence(field, field_doc_count(index_reader, field));
+        }
+        Query::Range{field, min, max, ..} => {
+            let field_flags = index_reader.schema().get(&field).map(|field_info| field_info.field_flags);
+
+            match field_flags {
+                Some(flags) if flags.contains(FIELD_INDEXED) && !flags.contains(FIELD_STORED) => {
+                    // The field doesn't have doc values, so search the terms of its values
+                    builder.push_empty();
+                    let term_selector = MultiTermSelector::IntegerRange { min: min, max: max };
+                    for term_id in index_reader.select_integer_terms_until(field, &term_selector, || index_reader.is_cancelled()) {
+                        if index_reader.is_cancelled() {
+                            break;
+                        }
+
+                        builder.push_term_directory(field, term_id, term_doc_frequency(index_reader, field, term_id));
+                        builder.or_combinator();
+                    }
+                }
+                _ => builder.push_doc_values_range(field, min, max),
+            }
+        }
+        Query::RankFeature{field, ..} => {
+            builder.push_rank_feature(field);
+        }
+        Query::HasChild{join_field, ref query, ..} => {
+            builder.push_join_matches(try!(index_reader.join_matches(join_field, query, true)));
+        }
+        Query::HasParent{join_field, ref query, ..} => {
+            builder.push_join_matches(try!(index_reader.join_matches(join_field, query, false)));
+        }
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod builder_tests {
     use kite::schema::FieldId;
     use kite::term::TermId;
+    use kite::query::verify::MatchVerifier;
 
     use super::BooleanQueryOp;
     use super::BooleanQueryBuilder;
@@ -444,7 +1008,7 @@ mod builder_tests {
     fn test_push_term_directory() {
         let mut builder = BooleanQueryBuilder::new();
 
-        builder.push_term_directory(FieldId(1), TermId(1));
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
 
         let (query, negated) = builder.build();
 
@@ -472,8 +1036,8 @@ mod builder_tests {
     fn test_and_combinator() {
         let mut builder = BooleanQueryBuilder::new();
 
-        builder.push_term_directory(FieldId(1), TermId(1));
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.and_combinator();
 
         let (query, negated) = builder.build();
@@ -490,8 +1054,8 @@ mod builder_tests {
     fn test_or_combinator() {
         let mut builder = BooleanQueryBuilder::new();
 
-        builder.push_term_directory(FieldId(1), TermId(1));
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.or_combinator();
 
         let (query, negated) = builder.build();
@@ -508,8 +1072,8 @@ mod builder_tests {
     fn test_andnot_combinator() {
         let mut builder = BooleanQueryBuilder::new();
 
-        builder.push_term_directory(FieldId(1), TermId(1));
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.andnot_combinator();
 
         let (query, negated) = builder.build();
@@ -528,7 +1092,7 @@ mod builder_tests {
         let mut builder = BooleanQueryBuilder::new();
 
         builder.push_full();
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.or_combinator();
 
         let (query, negated) = builder.build();
@@ -545,7 +1109,7 @@ mod builder_tests {
         let mut builder = BooleanQueryBuilder::new();
 
         builder.push_empty();
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.or_combinator();
 
         let (query, negated) = builder.build();
@@ -562,7 +1126,7 @@ mod builder_tests {
         let mut builder = BooleanQueryBuilder::new();
 
         builder.push_full();
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.and_combinator();
 
         let (query, negated) = builder.build();
@@ -579,7 +1143,7 @@ mod builder_tests {
         let mut builder = BooleanQueryBuilder::new();
 
         builder.push_empty();
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.and_combinator();
 
         let (query, negated) = builder.build();
@@ -597,7 +1161,7 @@ mod builder_tests {
         let mut builder = BooleanQueryBuilder::new();
 
         builder.push_full();
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.andnot_combinator();
 
         let (query, negated) = builder.build();
@@ -615,7 +1179,7 @@ mod builder_tests {
         let mut builder = BooleanQueryBuilder::new();
 
         builder.push_empty();
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.andnot_combinator();
 
         let (query, negated) = builder.build();
@@ -632,7 +1196,7 @@ mod builder_tests {
         // (basically: we're filtering a set by a full set, so there can't be anything left)
         let mut builder = BooleanQueryBuilder::new();
 
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.push_full();
         builder.andnot_combinator();
 
@@ -650,7 +1214,7 @@ mod builder_tests {
         // (basically: we're filtering a set by an empty set, leaving the set untouched)
         let mut builder = BooleanQueryBuilder::new();
 
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.push_empty();
         builder.andnot_combinator();
 
@@ -669,10 +1233,10 @@ mod builder_tests {
 
         // (ALL NOT TD(1, 2)) OR (TD(1,1) AND (TD(1, 3) AND NOT ALL))
         builder.push_full();
-        builder.push_term_directory(FieldId(1), TermId(2));
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
         builder.andnot_combinator();
-        builder.push_term_directory(FieldId(1), TermId(1));
-        builder.push_term_directory(FieldId(1), TermId(3));
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_term_directory(FieldId(1), TermId(3), 10);
         builder.push_full();
         builder.andnot_combinator();
         builder.and_combinator();
@@ -686,4 +1250,242 @@ mod builder_tests {
         ]);
         assert_eq!(negated, true);
     }
+
+    #[test]
+    fn test_and_combinator_starts_with_smallest_set() {
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_term_directory(FieldId(1), TermId(2), 5);
+        builder.and_combinator();
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(2)),
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::And,
+        ]);
+        assert_eq!(negated, false);
+    }
+
+    #[test]
+    fn test_and_combinator_with_large_term_directory_uses_postings() {
+        // If a term directory is much larger than the set it's being intersected with, it
+        // should be read with a postings iterator instead of being loaded
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_term_directory(FieldId(1), TermId(2), 1000);
+        builder.and_combinator();
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::IntersectPostings(FieldId(1), TermId(2)),
+        ]);
+        assert_eq!(negated, false);
+    }
+
+    #[test]
+    fn test_andnot_combinator_with_large_term_directory_uses_postings() {
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_term_directory(FieldId(1), TermId(2), 1000);
+        builder.andnot_combinator();
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::ExcludePostings(FieldId(1), TermId(2)),
+        ]);
+        assert_eq!(negated, false);
+    }
+
+    #[test]
+    fn test_and_combinator_many() {
+        // Clauses should be intersected in order of cost, starting with the cheapest
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 30);
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
+        builder.push_term_directory(FieldId(1), TermId(3), 20);
+        builder.and_combinator_many(3);
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(2)),
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(3)),
+            BooleanQueryOp::And,
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::And,
+        ]);
+        assert_eq!(negated, false);
+    }
+
+    #[test]
+    fn test_at_least_combinator() {
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 30);
+        builder.push_term_directory(FieldId(1), TermId(2), 10);
+        builder.push_term_directory(FieldId(1), TermId(3), 20);
+        builder.at_least_combinator(3, 2);
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(2)),
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(3)),
+            BooleanQueryOp::AtLeast(3, 2),
+        ]);
+        assert_eq!(negated, false);
+    }
+
+    #[test]
+    fn test_at_least_combinator_simplifies() {
+        // A full block counts towards the minimum and an empty block is dropped, leaving an
+        // intersection of the other two blocks
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_full();
+        builder.push_empty();
+        builder.push_term_directory(FieldId(1), TermId(2), 20);
+        builder.at_least_combinator(4, 3);
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(2)),
+            BooleanQueryOp::And,
+        ]);
+        assert_eq!(negated, false);
+
+        // A minimum of one is a union
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_term_directory(FieldId(1), TermId(2), 20);
+        builder.at_least_combinator(2, 1);
+
+        let (query, _) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(2)),
+            BooleanQueryOp::Or,
+        ]);
+
+        // Nothing can match if the minimum is more than the number of blocks
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.push_term_directory(FieldId(1), TermId(2), 20);
+        builder.at_least_combinator(2, 3);
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushEmpty,
+        ]);
+        assert_eq!(negated, false);
+    }
+
+    #[test]
+    fn test_verify_after_and_combinator() {
+        // The verification should be moved above the intersection so it's only run on the
+        // documents that match both sides
+        let verifier = MatchVerifier::new(|_| true);
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.verify(verifier.clone());
+        builder.push_term_directory(FieldId(1), TermId(2), 20);
+        builder.and_combinator();
+        builder.push_term_directory(FieldId(1), TermId(3), 30);
+        builder.and_combinator();
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(2)),
+            BooleanQueryOp::And,
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(3)),
+            BooleanQueryOp::And,
+            BooleanQueryOp::Verify(verifier),
+        ]);
+        assert_eq!(negated, false);
+    }
+
+    #[test]
+    fn test_verify_after_andnot_combinator() {
+        let verifier = MatchVerifier::new(|_| true);
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.verify(verifier.clone());
+        builder.push_term_directory(FieldId(1), TermId(2), 20);
+        builder.andnot_combinator();
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(2)),
+            BooleanQueryOp::AndNot,
+            BooleanQueryOp::Verify(verifier),
+        ]);
+        assert_eq!(negated, false);
+    }
+
+    #[test]
+    fn test_verify_isnt_moved_out_of_or_combinator() {
+        let verifier = MatchVerifier::new(|_| true);
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.verify(verifier.clone());
+        builder.push_term_directory(FieldId(1), TermId(2), 20);
+        builder.or_combinator();
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::Verify(verifier),
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(2)),
+            BooleanQueryOp::Or,
+        ]);
+        assert_eq!(negated, false);
+    }
+
+    #[test]
+    fn test_verify_negated_block() {
+        // Negated sets are computed before they're verified
+        let verifier = MatchVerifier::new(|_| true);
+        let mut builder = BooleanQueryBuilder::new();
+
+        builder.push_full();
+        builder.push_term_directory(FieldId(1), TermId(1), 10);
+        builder.andnot_combinator();
+        builder.verify(verifier.clone());
+
+        let (query, negated) = builder.build();
+
+        assert_eq!(query, vec![
+            BooleanQueryOp::PushAll,
+            BooleanQueryOp::PushTermDirectory(FieldId(1), TermId(1)),
+            BooleanQueryOp::AndNot,
+            BooleanQueryOp::Verify(verifier),
+        ]);
+        assert_eq!(negated, false);
+    }
 }
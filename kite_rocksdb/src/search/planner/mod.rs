@@ -1,5 +1,6 @@
 pub mod boolean_query;
 pub mod score_function;
+pub mod multi_term;
 
 use kite::Query;
 
@@ -7,7 +8,7 @@ use RocksDBReader;
 use search::planner::boolean_query::{BooleanQueryOp, BooleanQueryBuilder, plan_boolean_query};
 use search::planner::score_function::{ScoreFunctionOp, plan_score_function};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SearchPlan {
     pub boolean_query: Vec<BooleanQueryOp>,
     pub boolean_query_is_negated: bool,
@@ -22,14 +23,29 @@ impl SearchPlan {
             score_function: Vec::new(),
         }
     }
+
+    /// Returns the score if every document matched by this plan gets the same score
+    ///
+    /// This is the case when the query is unscored or only made up of filters. The
+    /// executor can skip scoring each document individually.
+    pub fn constant_score(&self) -> Option<f32> {
+        if self.score_function.len() != 1 {
+            return None;
+        }
+
+        match self.score_function[0] {
+            ScoreFunctionOp::Literal(score) => Some(score),
+            _ => None,
+        }
+    }
 }
 
-pub fn plan_query(index_reader: &RocksDBReader, query: &Query, score: bool) -> SearchPlan {
+pub fn plan_query(index_reader: &RocksDBReader, query: &Query, score: bool) -> Result<SearchPlan, String> {
     let mut plan = SearchPlan::new();
 
     // Plan boolean query
     let mut builder = BooleanQueryBuilder::new();
-    plan_boolean_query(index_reader, &mut builder, query);
+    try!(plan_boolean_query(index_reader, &mut builder, query));
 
     // Add operations to exclude deleted documents to boolean query
     builder.push_deletion_list();
@@ -40,11 +56,10 @@ pub fn plan_query(index_reader: &RocksDBReader, query: &Query, score: bool) -> S
     plan.boolean_query_is_negated = boolean_query_is_negated;
 
     // Plan score function
+    // If the collector doesn't need scores, leave it empty so the executor knows not to score anything
     if score {
-        plan_score_function(index_reader, &mut plan.score_function, query);
-    } else {
-        plan.score_function.push(ScoreFunctionOp::Literal(0.0f32));
+        try!(plan_score_function(index_reader, &mut plan.score_function, query));
     }
 
-    plan
+    Ok(plan)
 }
@@ -0,0 +1,71 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use kite::schema::FieldId;
+use kite::term::TermId;
+use kite::MultiTermSelector;
+use kite::query::multi_term_rewrite::MultiTermRewrite;
+
+use RocksDBReader;
+use search::statistics::{StatisticsReader, RocksDBStatisticsReader};
+
+/// Selects the terms that a MultiTerm query searches
+///
+/// Both the boolean query and score function planners call this so, for a query to be
+/// consistent, it must always return the same terms for the same reader.
+///
+/// The reader's cancellation token is checked while the term dictionary is scanned. If the
+/// search is cancelled, the terms found so far are returned and the executor reports the
+/// cancellation before the query is run.
+pub fn expand_multi_term(index_reader: &RocksDBReader, field: FieldId, term_selector: &MultiTermSelector, rewrite: MultiTermRewrite, max_expansions: Option<usize>) -> Result<Vec<TermId>, String> {
+    let term_dictionary = &index_reader.store.term_dictionary;
+    let is_cancelled = || index_reader.is_cancelled();
+
+    match rewrite {
+        MultiTermRewrite::ScoringBoolean | MultiTermRewrite::ConstantScore => {
+            match max_expansions {
+                Some(max_expansions) => Ok(term_dictionary.select_first_until(term_selector, max_expansions, is_cancelled)),
+                None => Ok(term_dictionary.select_until(term_selector, is_cancelled)),
+            }
+        }
+        MultiTermRewrite::TopTermsByDocFrequency(size) => {
+            if size == 0 {
+                return Ok(Vec::new());
+            }
+
+            // A min-heap of the most frequent terms seen so far. Ties are broken by term id
+            // so the same terms are chosen each time
+            let mut statistics = RocksDBStatisticsReader::new(index_reader);
+            let mut top_terms = BinaryHeap::with_capacity(size + 1);
+            let mut error = None;
+            term_dictionary.for_each_match(term_selector, |_term, term_id| {
+                if is_cancelled() {
+                    return false;
+                }
+
+                let doc_frequency = match statistics.term_document_frequency(field, term_id) {
+                    Ok(doc_frequency) => doc_frequency,
+                    Err(e) => {
+                        error = Some(e.to_string());
+                        return false;
+                    }
+                };
+
+                if doc_frequency != 0 {
+                    top_terms.push(Reverse((doc_frequency, Reverse(term_id.0))));
+                    if top_terms.len() > size {
+                        top_terms.pop();
+                    }
+                }
+
+                true
+            });
+
+            if let Some(error) = error {
+                return Err(error);
+            }
+
+            Ok(top_terms.into_sorted_vec().into_iter().map(|Reverse((_, Reverse(term_id)))| TermId(term_id)).collect())
+        }
+    }
+}
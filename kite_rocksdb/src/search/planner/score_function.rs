@@ -2,8 +2,15 @@ use kite::schema::FieldId;
 use kite::term::TermId;
 use kite::Query;
 use kite::query::term_scorer::TermScorer;
+use kite::query::multi_term_rewrite::MultiTermRewrite;
+use kite::query::custom_score::CustomScoreFunction;
+use kite::query::field_value_factor::FieldValueFactor;
+use kite::query::function_score::{ScoreFunction, FunctionScoreCombine};
+use kite::query::rank_feature::RankFeatureFunction;
 
 use RocksDBReader;
+use search::planner::multi_term::expand_multi_term;
+use search::planner::boolean_query::{BooleanQueryOp, BooleanQueryBuilder, plan_boolean_query};
 
 #[derive(Debug, Clone)]
 pub enum CombinatorScorer {
@@ -16,28 +23,65 @@ pub enum ScoreFunctionOp {
     Literal(f32),
     TermScorer(FieldId, TermId, TermScorer),
     CombinatorScorer(u32, CombinatorScorer),
+    CustomScore(CustomScoreFunction, f32),
+    FieldValueFactor(FieldValueFactor),
+    FunctionScore(Vec<ScoreFunction>, FunctionScoreCombine),
+
+    /// Multiplies the score by the boost if the document matches the boolean query
+    ///
+    /// The boolean query is run once for each segment before any documents are scored.
+    Boosting(Vec<BooleanQueryOp>, bool, f32),
+    RankFeature(FieldId, RankFeatureFunction, f32),
 }
 
-fn plan_score_function_combinator(index_reader: &RocksDBReader, mut score_function: &mut Vec<ScoreFunctionOp>, queries: &Vec<Query>, scorer: CombinatorScorer) {
+fn plan_score_function_combinator<'a, I: IntoIterator<Item = &'a Query>>(index_reader: &RocksDBReader, mut score_function: &mut Vec<ScoreFunctionOp>, queries: I, scorer: CombinatorScorer) -> Result<(), String> {
+    let queries = queries.into_iter().collect::<Vec<_>>();
+    let start = score_function.len();
+
     match queries.len() {
         0 => {
             score_function.push(ScoreFunctionOp::Literal(0.0f32));
         }
-        1 =>  plan_score_function(index_reader, &mut score_function, &queries[0]),
+        1 => try!(plan_score_function(index_reader, &mut score_function, queries[0])),
         _ => {
             let mut query_iter = queries.iter();
-            plan_score_function(index_reader, &mut score_function, query_iter.next().unwrap());
+            try!(plan_score_function(index_reader, &mut score_function, query_iter.next().unwrap()));
 
             for query in query_iter {
-                plan_score_function(index_reader, &mut score_function, query);
+                try!(plan_score_function(index_reader, &mut score_function, query));
             }
         }
     }
 
+    // If every subquery produces a constant score (eg, they are all filters), combine them now
+    // so the documents don't need to be scored individually
+    let literals = score_function[start..].iter()
+        .map(|op| {
+            match *op {
+                ScoreFunctionOp::Literal(val) => Some(val),
+                _ => None,
+            }
+        })
+        .collect::<Option<Vec<f32>>>();
+
+    if let Some(literals) = literals {
+        if literals.len() == queries.len() && !literals.is_empty() {
+            let score = match scorer {
+                CombinatorScorer::Avg => literals.iter().sum::<f32>() / literals.len() as f32,
+                CombinatorScorer::Max => literals.iter().fold(0.0f32, |max, val| max.max(*val)),
+            };
+
+            score_function.truncate(start);
+            score_function.push(ScoreFunctionOp::Literal(score));
+            return Ok(());
+        }
+    }
+
     score_function.push(ScoreFunctionOp::CombinatorScorer(queries.len() as u32, scorer));
+    Ok(())
 }
 
-pub fn plan_score_function(index_reader: &RocksDBReader, mut score_function: &mut Vec<ScoreFunctionOp>, query: &Query) {
+pub fn plan_score_function(index_reader: &RocksDBReader, mut score_function: &mut Vec<ScoreFunctionOp>, query: &Query) -> Result<(), String> {
     match *query {
         Query::All{ref score} => {
             score_function.push(ScoreFunctionOp::Literal(*score));
@@ -52,16 +96,35 @@ pub fn plan_score_function(index_reader: &RocksDBReader, mut score_function: &mu
                 None => {
                     // Term doesn't exist, so will never match
                     score_function.push(ScoreFunctionOp::Literal(0.0f32));
-                    return
+                    return Ok(());
                 }
             };
 
             score_function.push(ScoreFunctionOp::TermScorer(field, term_id, scorer.clone()));
         }
-        Query::MultiTerm{field, ref term_selector, ref scorer} => {
+        Query::Span{ref query, ref scorer} => {
+            match query.field() {
+                Some(field) => {
+                    let term_queries = query.terms().into_iter().map(|term| Query::Term {
+                        field: field,
+                        term: term.clone(),
+                        scorer: scorer.clone(),
+                    }).collect::<Vec<_>>();
+
+                    try!(plan_score_function_combinator(index_reader, &mut score_function, &term_queries, CombinatorScorer::Avg));
+                }
+                None => score_function.push(ScoreFunctionOp::Literal(0.0f32)),
+            }
+        }
+        Query::MultiTerm{ref scorer, rewrite: MultiTermRewrite::ConstantScore, ..} => {
+            // Every match gets the same score, so the terms aren't needed. The query can
+            // still be boosted
+            score_function.push(ScoreFunctionOp::Literal(scorer.boost));
+        }
+        Query::MultiTerm{field, ref term_selector, ref scorer, rewrite, max_expansions} => {
             // Get terms
             let mut total_terms = 0;
-            for term_id in index_reader.store.term_dictionary.select(term_selector) {
+            for term_id in try!(expand_multi_term(index_reader, field, term_selector, rewrite, max_expansions)) {
                 score_function.push(ScoreFunctionOp::TermScorer(field, term_id, scorer.clone()));
                 total_terms += 1;
             }
@@ -78,19 +141,62 @@ pub fn plan_score_function(index_reader: &RocksDBReader, mut score_function: &mu
             }
         }
         Query::Conjunction{ref queries} => {
-            plan_score_function_combinator(index_reader, &mut score_function, queries, CombinatorScorer::Avg);
+            try!(plan_score_function_combinator(index_reader, &mut score_function, queries, CombinatorScorer::Avg));
         }
         Query::Disjunction{ref queries} => {
-            plan_score_function_combinator(index_reader, &mut score_function, queries, CombinatorScorer::Avg);
+            try!(plan_score_function_combinator(index_reader, &mut score_function, queries, CombinatorScorer::Avg));
         }
         Query::DisjunctionMax{ref queries} => {
-            plan_score_function_combinator(index_reader, &mut score_function, queries, CombinatorScorer::Max);
+            try!(plan_score_function_combinator(index_reader, &mut score_function, queries, CombinatorScorer::Max));
+        }
+        Query::Boolean{ref must, ref should, ..} => {
+            // The must_not clauses only remove documents
+            try!(plan_score_function_combinator(index_reader, &mut score_function, must.iter().chain(should.iter()), CombinatorScorer::Avg));
         }
         Query::Filter{ref query, ..} => {
-            plan_score_function(index_reader, &mut score_function, query);
+            try!(plan_score_function(index_reader, &mut score_function, query));
+        }
+        Query::Boosting{ref positive, ref negative, negative_boost} => {
+            try!(plan_score_function(index_reader, &mut score_function, positive));
+
+            let mut builder = BooleanQueryBuilder::new();
+            try!(plan_boolean_query(index_reader, &mut builder, negative));
+            let (negative_query, negative_query_is_negated) = builder.build();
+            score_function.push(ScoreFunctionOp::Boosting(negative_query, negative_query_is_negated, negative_boost));
         }
         Query::Exclude{ref query, ..} => {
-            plan_score_function(index_reader, &mut score_function, query);
+            try!(plan_score_function(index_reader, &mut score_function, query));
+        }
+        Query::ConstantScore{score, ..} => {
+            // The inner query is only used for filtering, it doesn't need a scorer
+            score_function.push(ScoreFunctionOp::Literal(score));
+        }
+        Query::CustomScore{ref query, ref function, boost} => {
+            try!(plan_score_function(index_reader, &mut score_function, query));
+            score_function.push(ScoreFunctionOp::CustomScore(function.clone(), boost));
+        }
+        Query::FieldValueFactor{ref query, ref factor} => {
+            try!(plan_score_function(index_reader, &mut score_function, query));
+            score_function.push(ScoreFunctionOp::FieldValueFactor(factor.clone()));
+        }
+        Query::FunctionScore{ref query, ref functions, combine} => {
+            try!(plan_score_function(index_reader, &mut score_function, query));
+            score_function.push(ScoreFunctionOp::FunctionScore(functions.clone(), combine));
+        }
+        Query::Verify{ref approximation, ..} => {
+            try!(plan_score_function(index_reader, &mut score_function, approximation));
+        }
+        Query::RankFeature{field, ref function, boost} => {
+            score_function.push(ScoreFunctionOp::RankFeature(field, function.clone(), boost));
+        }
+        Query::Terms{score, ..} | Query::Exists{score, ..} | Query::Range{score, ..} => {
+            score_function.push(ScoreFunctionOp::Literal(score));
+        }
+        Query::HasChild{score, ..} | Query::HasParent{score, ..} => {
+            // The inner query is only used to find the documents to join to
+            score_function.push(ScoreFunctionOp::Literal(score));
         }
     }
+
+    Ok(())
 }
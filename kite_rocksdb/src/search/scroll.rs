@@ -0,0 +1,105 @@
+use kite::DocId;
+use kite::query::Query;
+use kite::error::SearchError;
+
+use RocksDBReader;
+use search::planner::plan_query;
+use search::match_iterator::MatchIterator;
+
+/// Iterates every document that matches a query, in batches
+///
+/// Documents are yielded in index order (segment by segment, then by their id in the
+/// segment) without being scored. The scroll is pinned to the reader's snapshot so writes,
+/// merges and deletions made after the reader was opened don't affect it: every document
+/// that matched when the reader was opened is yielded exactly once.
+pub struct Scroll<'a> {
+    reader: &'a RocksDBReader<'a>,
+    matches: MatchIterator<'a>,
+    batch_size: usize,
+
+    /// Matches up to and including this position are skipped, used when resuming
+    after: Option<(usize, u32)>,
+
+    last_doc_id: Option<DocId>,
+}
+
+impl<'a> Scroll<'a> {
+    /// Returns the next batch of documents, this is empty once the scroll has finished
+    pub fn next_batch(&mut self) -> Result<Vec<DocId>, SearchError> {
+        let mut batch = Vec::with_capacity(self.batch_size);
+
+        while batch.len() < self.batch_size {
+            let doc_id = match self.matches.next() {
+                Some(doc) => DocId::from_u64(try!(doc).doc_id()),
+                None => break,
+            };
+
+            if let Some(after) = self.after {
+                if self.reader.scroll_position(doc_id) <= Some(after) {
+                    continue;
+                }
+
+                self.after = None;
+            }
+
+            batch.push(doc_id);
+        }
+
+        if let Some(doc_id) = batch.last() {
+            self.last_doc_id = Some(*doc_id);
+        }
+
+        Ok(batch)
+    }
+
+    /// The last document that was yielded
+    ///
+    /// Pass this to "scroll_after" to resume the scroll from another Scroll created on the
+    /// same reader.
+    pub fn last_doc_id(&self) -> Option<DocId> {
+        self.last_doc_id
+    }
+}
+
+impl<'a> Iterator for Scroll<'a> {
+    type Item = Result<Vec<DocId>, SearchError>;
+
+    fn next(&mut self) -> Option<Result<Vec<DocId>, SearchError>> {
+        match self.next_batch() {
+            Ok(ref batch) if batch.is_empty() => None,
+            result => Some(result),
+        }
+    }
+}
+
+impl<'a> RocksDBReader<'a> {
+    /// Returns a scroll over every document that matches the query
+    pub fn scroll(&self, query: &Query, batch_size: usize) -> Result<Scroll, SearchError> {
+        self.scroll_after(query, batch_size, None)
+    }
+
+    /// Returns a scroll over the documents that match the query that come after "after"
+    ///
+    /// "after" must be a document returned by a scroll created on this reader, documents
+    /// are only in a stable order while the reader's snapshot is held.
+    pub fn scroll_after(&self, query: &Query, batch_size: usize, after: Option<DocId>) -> Result<Scroll, SearchError> {
+        let plan = try!(plan_query(&self, query, false).map_err(SearchError::Plan));
+
+        Ok(Scroll {
+            reader: &self,
+            matches: MatchIterator::new(&self, plan),
+            batch_size: if batch_size == 0 { 1 } else { batch_size },
+            after: after.map(|after| self.scroll_position(after).unwrap_or((usize::max_value(), 0))),
+            last_doc_id: None,
+        })
+    }
+
+    /// The position of a document in the order scrolls yield them in
+    ///
+    /// This is None if the document's segment isn't in the reader's segment generation.
+    fn scroll_position(&self, doc_id: DocId) -> Option<(usize, u32)> {
+        self.generation.segments().iter()
+            .position(|segment| *segment == (doc_id.0).0)
+            .map(|segment_position| (segment_position, doc_id.1))
+    }
+}
@@ -1,16 +1,19 @@
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 
 use kite::schema::FieldId;
 use kite::term::TermId;
 use kite::segment::Segment;
+use kite::query::Query;
+use kite::distributed::CorpusStatistics;
+use kite::error::SegmentError;
 
 use RocksDBReader;
 use key_builder::KeyBuilder;
 
 pub trait StatisticsReader {
-    fn total_docs(&mut self, field_id: FieldId) -> Result<i64, String>;
-    fn total_tokens(&mut self, field_id: FieldId) -> Result<i64, String>;
-    fn term_document_frequency(&mut self, field_id: FieldId, term_id: TermId) -> Result<i64, String>;
+    fn total_docs(&mut self, field_id: FieldId) -> Result<i64, SegmentError>;
+    fn total_tokens(&mut self, field_id: FieldId) -> Result<i64, SegmentError>;
+    fn term_document_frequency(&mut self, field_id: FieldId, term_id: TermId) -> Result<i64, SegmentError>;
 }
 
 pub struct RocksDBStatisticsReader<'a> {
@@ -22,15 +25,59 @@ pub struct RocksDBStatisticsReader<'a> {
 
 impl<'a> RocksDBStatisticsReader<'a> {
     pub fn new(index_reader: &'a RocksDBReader) -> RocksDBStatisticsReader<'a> {
-        RocksDBStatisticsReader {
+        let mut stats = RocksDBStatisticsReader {
             index_reader: index_reader,
             total_docs: FnvHashMap::default(),
             total_tokens: FnvHashMap::default(),
             term_document_frequencies: FnvHashMap::default(),
+        };
+
+        // Statistics given to the reader are put in the cache so they're used instead of the
+        // index's own statistics
+        if let Some(ref corpus_statistics) = index_reader.corpus_statistics {
+            for field_id in corpus_statistics.fields() {
+                let field_stats = corpus_statistics.field(field_id).unwrap();
+                stats.total_docs.insert(field_id, field_stats.total_docs as i64);
+                stats.total_tokens.insert(field_id, field_stats.total_tokens as i64);
+
+                for (term, frequency) in field_stats.terms() {
+                    if let Some(term_id) = index_reader.store.term_dictionary.get(&term) {
+                        stats.term_document_frequencies.insert((field_id, term_id), frequency as i64);
+                    }
+                }
+            }
+        }
+
+        stats
+    }
+
+    fn corpus_statistics(&mut self, query: &Query) -> Result<CorpusStatistics, SegmentError> {
+        let mut corpus_statistics = CorpusStatistics::new();
+        let mut fields = FnvHashSet::default();
+
+        for (field_id, term) in query.terms() {
+            if fields.insert(field_id) {
+                let total_docs = try!(self.total_docs(field_id));
+                let total_tokens = try!(self.total_tokens(field_id));
+                corpus_statistics.add_field(field_id, total_docs as u64, total_tokens as u64);
+            }
+
+            let document_frequency = match self.index_reader.store.term_dictionary.get(term) {
+                Some(term_id) => try!(self.term_document_frequency(field_id, term_id)),
+                None => 0,
+            };
+            corpus_statistics.add_term(field_id, term, document_frequency as u64);
         }
+
+        Ok(corpus_statistics)
+    }
+
+    /// The number of documents that have a value for the field (indexed or stored)
+    pub fn field_doc_count(&self, field_id: FieldId) -> Result<i64, SegmentError> {
+        self.get_statistic(&KeyBuilder::segment_stat_field_doc_count_stat_name(field_id.0))
     }
 
-    fn get_statistic(&self, name: &[u8]) -> Result<i64, String> {
+    fn get_statistic(&self, name: &[u8]) -> Result<i64, SegmentError> {
         let mut val = 0;
 
         for segment in self.index_reader.store.segments.iter_active(&self.index_reader) {
@@ -44,7 +91,7 @@ impl<'a> RocksDBStatisticsReader<'a> {
 }
 
 impl<'a> StatisticsReader for RocksDBStatisticsReader<'a> {
-    fn total_docs(&mut self, field_id: FieldId) -> Result<i64, String> {
+    fn total_docs(&mut self, field_id: FieldId) -> Result<i64, SegmentError> {
         if let Some(val) = self.total_docs.get(&field_id) {
             return Ok(*val);
         }
@@ -55,7 +102,7 @@ impl<'a> StatisticsReader for RocksDBStatisticsReader<'a> {
         Ok(val)
     }
 
-    fn total_tokens(&mut self, field_id: FieldId) -> Result<i64, String> {
+    fn total_tokens(&mut self, field_id: FieldId) -> Result<i64, SegmentError> {
         if let Some(val) = self.total_tokens.get(&field_id) {
             return Ok(*val);
         }
@@ -66,7 +113,7 @@ impl<'a> StatisticsReader for RocksDBStatisticsReader<'a> {
         Ok(val)
     }
 
-    fn term_document_frequency(&mut self, field_id: FieldId, term_id: TermId) -> Result<i64, String> {
+    fn term_document_frequency(&mut self, field_id: FieldId, term_id: TermId) -> Result<i64, SegmentError> {
         if let Some(val) = self.term_document_frequencies.get(&(field_id, term_id)) {
             return Ok(*val);
         }
@@ -77,3 +124,13 @@ impl<'a> StatisticsReader for RocksDBStatisticsReader<'a> {
         Ok(val)
     }
 }
+
+impl<'a> RocksDBReader<'a> {
+    /// Returns the statistics of the fields and terms of a query
+    ///
+    /// These are merged with the statistics of the other shards and given to the reader of
+    /// each shard with "set_corpus_statistics".
+    pub fn corpus_statistics(&self, query: &Query) -> Result<CorpusStatistics, SegmentError> {
+        RocksDBStatisticsReader::new(self).corpus_statistics(query)
+    }
+}
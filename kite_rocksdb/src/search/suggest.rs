@@ -0,0 +1,207 @@
+use std::cmp;
+use std::mem;
+use std::str;
+
+use kite::Term;
+use kite::schema::FieldId;
+use kite::query::multi_term_selector::MultiTermSelector;
+
+use RocksDBReader;
+use search::statistics::{StatisticsReader, RocksDBStatisticsReader};
+
+/// The most suggestions that are returned for each term
+const MAX_SUGGESTIONS: usize = 5;
+
+/// A possible correction for a term
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub term: String,
+
+    /// The number of single character edits between the term and the suggestion
+    pub distance: u32,
+
+    /// The number of documents that contain the suggestion in the field
+    pub doc_frequency: i64,
+}
+
+/// The suggestions for a single term of the text
+#[derive(Debug, Clone, PartialEq)]
+pub struct TermSuggestions {
+    pub term: String,
+
+    /// The number of documents that contain the term in the field
+    pub doc_frequency: i64,
+
+    /// Best first, this is empty if the term is in the field
+    pub suggestions: Vec<Suggestion>,
+}
+
+/// A term of a field and the number of documents that contain it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PopularTerm {
+    pub term: String,
+    pub doc_frequency: i64,
+}
+
+impl TermSuggestions {
+    pub fn best(&self) -> Option<&Suggestion> {
+        self.suggestions.first()
+    }
+}
+
+/// Returns the Levenshtein distance between two strings, counted in characters
+pub fn edit_distance(a: &str, b: &str) -> u32 {
+    let b = b.chars().collect::<Vec<_>>();
+
+    // Only the previous row of the matrix is needed to compute the next one
+    let mut previous_row = (0..b.len() as u32 + 1).collect::<Vec<_>>();
+    let mut row = vec![0; b.len() + 1];
+
+    for (i, a_char) in a.chars().enumerate() {
+        row[0] = i as u32 + 1;
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution_cost = if a_char == *b_char { 0 } else { 1 };
+
+            row[j + 1] = cmp::min(
+                cmp::min(row[j] + 1, previous_row[j + 1] + 1),
+                previous_row[j] + substitution_cost
+            );
+        }
+
+        mem::swap(&mut row, &mut previous_row);
+    }
+
+    previous_row[b.len()]
+}
+
+/// The number of edits allowed when correcting a term, short terms get fewer so they
+/// aren't "corrected" into unrelated words
+fn max_edits(term: &str) -> u32 {
+    let length = term.chars().count();
+
+    if length <= 2 {
+        0
+    } else if length <= 5 {
+        1
+    } else {
+        2
+    }
+}
+
+impl<'a> RocksDBReader<'a> {
+    /// Suggests corrections for each whitespace separated term in the text
+    ///
+    /// Terms that don't appear in the field are matched against the term dictionary.
+    /// Suggestions must appear in the field and are ranked by edit distance, then by how
+    /// many documents contain them.
+    pub fn suggest(&self, field: FieldId, text: &str) -> Result<Vec<TermSuggestions>, String> {
+        let mut statistics = RocksDBStatisticsReader::new(self);
+        let mut term_suggestions = Vec::new();
+
+        for term in text.split_whitespace() {
+            let doc_frequency = match self.store.term_dictionary.get(&Term::from_string(term)) {
+                Some(term_id) => try!(statistics.term_document_frequency(field, term_id)),
+                None => 0,
+            };
+
+            let mut suggestions = Vec::new();
+            if doc_frequency == 0 {
+                for (similar_term, term_id, distance) in self.store.term_dictionary.find_similar(term, max_edits(term)) {
+                    let similar_term_doc_frequency = try!(statistics.term_document_frequency(field, term_id));
+
+                    // The term dictionary is shared by all fields
+                    if similar_term_doc_frequency > 0 {
+                        suggestions.push(Suggestion {
+                            term: similar_term,
+                            distance: distance,
+                            doc_frequency: similar_term_doc_frequency,
+                        });
+                    }
+                }
+
+                suggestions.sort_by(|a, b| {
+                    a.distance.cmp(&b.distance)
+                        .then(b.doc_frequency.cmp(&a.doc_frequency))
+                        .then(a.term.cmp(&b.term))
+                });
+                suggestions.truncate(MAX_SUGGESTIONS);
+            }
+
+            term_suggestions.push(TermSuggestions {
+                term: term.to_string(),
+                doc_frequency: doc_frequency,
+                suggestions: suggestions,
+            });
+        }
+
+        Ok(term_suggestions)
+    }
+
+    /// Returns the terms of the field that start with the prefix, most frequent first
+    ///
+    /// This is a cheap autocomplete that works with any indexed field. Unlike "complete", it
+    /// doesn't need a completion field to be indexed. Like the other statistics, document
+    /// frequencies include deleted documents until their segment is merged.
+    pub fn popular_terms(&self, field: FieldId, prefix: &str, size: usize) -> Result<Vec<PopularTerm>, String> {
+        let mut statistics = RocksDBStatisticsReader::new(self);
+        let mut popular_terms = Vec::new();
+
+        for (term, term_id) in self.store.term_dictionary.select_terms(&MultiTermSelector::Prefix(prefix.to_string())) {
+            // The term dictionary is shared by all fields
+            let doc_frequency = try!(statistics.term_document_frequency(field, term_id));
+            if doc_frequency <= 0 {
+                continue;
+            }
+
+            if let Ok(term) = str::from_utf8(term.as_bytes()) {
+                popular_terms.push(PopularTerm {
+                    term: term.to_string(),
+                    doc_frequency: doc_frequency,
+                });
+            }
+        }
+
+        popular_terms.sort_by(|a, b| b.doc_frequency.cmp(&a.doc_frequency).then(a.term.cmp(&b.term)));
+        popular_terms.truncate(size);
+        Ok(popular_terms)
+    }
+
+    /// Returns the text with each misspelt term replaced by its best suggestion
+    ///
+    /// This is None if there's nothing to correct.
+    pub fn did_you_mean(&self, field: FieldId, text: &str) -> Result<Option<String>, String> {
+        let term_suggestions = try!(self.suggest(field, text));
+
+        if term_suggestions.iter().all(|term| term.best().is_none()) {
+            return Ok(None);
+        }
+
+        let terms = term_suggestions.iter()
+            .map(|term| term.best().map(|suggestion| &suggestion.term).unwrap_or(&term.term).clone())
+            .collect::<Vec<_>>();
+
+        Ok(Some(terms.join(" ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, max_edits};
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("hello", "hello"), 0);
+        assert_eq!(edit_distance("helo", "hello"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn test_max_edits() {
+        assert_eq!(max_edits("on"), 0);
+        assert_eq!(max_edits("helo"), 1);
+        assert_eq!(max_edits("wonderfull"), 2);
+    }
+}
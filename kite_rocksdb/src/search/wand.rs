@@ -0,0 +1,275 @@
+use std::f32;
+
+use kite::schema::FieldId;
+use kite::term::TermId;
+use kite::segment::Segment;
+use kite::postings::{PostingsIterator, BlockPostingsIterator};
+use kite::query::term_scorer::TermScorer;
+use kite::collectors::{Collector, DocumentMatch};
+use kite::error::SearchError;
+
+use search::score_term;
+use search::block_max::load_block_max_scores;
+use search::statistics::StatisticsReader;
+use search::deadline::Deadline;
+use search::planner::SearchPlan;
+use search::planner::boolean_query::BooleanQueryOp;
+use search::planner::score_function::{CombinatorScorer, ScoreFunctionOp};
+
+/// A disjunction of terms that can be executed with block-max WAND
+///
+/// WAND skips documents that cannot score highly enough to be collected. Each term has an upper
+/// bound on the score it can give a document (its "max score"), if the sum of these for the terms
+/// that match a document is less than the score of the lowest document in the collector, the
+/// document can be skipped without scoring it.
+///
+/// Block-max WAND refines this by using the impacts of each block of the postings lists to
+/// calculate a tighter upper bound, allowing whole blocks to be skipped.
+#[derive(Debug)]
+pub struct WandQuery {
+    terms: Vec<(FieldId, TermId, TermScorer)>,
+    combinator: CombinatorScorer,
+}
+
+impl WandQuery {
+    /// Returns a WandQuery if the plan is a simple disjunction of terms
+    pub fn from_plan(plan: &SearchPlan) -> Option<WandQuery> {
+        if plan.boolean_query_is_negated {
+            return None;
+        }
+
+        // Find the terms in the score function
+        let mut terms = Vec::new();
+        let mut combinator = CombinatorScorer::Avg;
+        for (i, op) in plan.score_function.iter().enumerate() {
+            match *op {
+                ScoreFunctionOp::TermScorer(field_id, term_id, ref scorer) => {
+                    if !(scorer.boost >= 0.0) {
+                        // Negative boosts would break the upper bounds
+                        return None;
+                    }
+
+                    terms.push((field_id, term_id, scorer.clone()));
+                }
+                ScoreFunctionOp::CombinatorScorer(num_vals, ref scorer) if i == plan.score_function.len() - 1 && num_vals as usize == terms.len() => {
+                    combinator = scorer.clone();
+                }
+                _ => return None,
+            }
+        }
+
+        if terms.is_empty() {
+            return None;
+        }
+
+        // Check the boolean query is a union of the same terms, excluding deleted docs
+        let mut num_term_directories = 0;
+        let mut ops = plan.boolean_query.iter().peekable();
+        while let Some(op) = ops.next() {
+            match *op {
+                BooleanQueryOp::PushTermDirectory(field_id, term_id) => {
+                    if !terms.iter().any(|&(f, t, _)| f == field_id && t == term_id) {
+                        return None;
+                    }
+
+                    num_term_directories += 1;
+                }
+                BooleanQueryOp::Or => {}
+                BooleanQueryOp::PushDeletionList if ops.peek() == Some(&&BooleanQueryOp::AndNot) => {
+                    ops.next();
+
+                    if ops.peek().is_some() {
+                        return None;
+                    }
+                }
+                _ => return None,
+            }
+        }
+
+        if num_term_directories != terms.len() {
+            return None;
+        }
+
+        Some(WandQuery {
+            terms: terms,
+            combinator: combinator,
+        })
+    }
+
+    /// Combines the scores of the terms that matched a document
+    ///
+    /// Terms that didn't match the document score 0
+    fn combine<I: Iterator<Item = f32>>(&self, scores: I) -> f32 {
+        match self.combinator {
+            CombinatorScorer::Avg => scores.fold(0.0f32, |total, score| total + score) / self.terms.len() as f32,
+            CombinatorScorer::Max => scores.fold(0.0f32, |max, score| max.max(score)),
+        }
+    }
+}
+
+struct WandTerm<'a, S: Segment + 'a> {
+    ord: usize,
+    postings: BlockPostingsIterator<'a, S>,
+    block_max_scores: Vec<f32>,
+    max_score: f32,
+}
+
+impl<'a, S: Segment + 'a> WandTerm<'a, S> {
+    /// Returns an upper bound for the score of the document in this term
+    fn block_max_score(&self, doc_id: u32) -> f32 {
+        match self.postings.block_for(doc_id) {
+            Some(block_ord) => self.block_max_scores.get(block_ord).cloned().unwrap_or(f32::INFINITY),
+            None => 0.0,
+        }
+    }
+
+    /// Moves to the first document on or after the target
+    ///
+    /// Targets after the last possible document exhaust the iterator
+    fn advance_to(&mut self, target: u64) -> Result<(), SearchError> {
+        if target > u32::max_value() as u64 {
+            try!(self.postings.advance(u32::max_value()));
+            if self.postings.doc() == Some(u32::max_value()) {
+                try!(self.postings.next_doc());
+            }
+        } else {
+            try!(self.postings.advance(target as u32));
+        }
+
+        Ok(())
+    }
+}
+
+#[inline]
+fn is_competitive(score: f32, min_competitive_score: Option<f32>) -> bool {
+    match min_competitive_score {
+        Some(min_competitive_score) => score > min_competitive_score,
+        None => true,
+    }
+}
+
+/// Runs the query on a segment, passing documents that could be competitive into the collector
+///
+/// Stops early if the deadline passes.
+pub fn search_segment_wand<C: Collector, S: Segment, R: StatisticsReader>(collector: &mut C, query: &WandQuery, segment: &S, stats: &mut R, deadline: &mut Deadline) -> Result<(), SearchError> {
+    let deletion_list = try!(segment.load_deletion_list());
+
+    // Set up the terms
+    let mut terms = Vec::with_capacity(query.terms.len());
+    for (ord, &(field_id, term_id, ref scorer)) in query.terms.iter().enumerate() {
+        let mut postings = try!(BlockPostingsIterator::new(segment, field_id, term_id));
+        if try!(postings.next_doc()).is_none() {
+            // Term isn't in this segment
+            continue;
+        }
+
+        // Work out the upper bound scores of each block
+        // If the segment doesn't have impacts, the scores can't be bounded
+        let block_max_scores = try!(load_block_max_scores(segment, stats, field_id, term_id, scorer)).unwrap_or_else(Vec::new);
+
+        let max_score = if block_max_scores.is_empty() {
+            f32::INFINITY
+        } else {
+            block_max_scores.iter().fold(0.0f32, |max, score| max.max(*score))
+        };
+
+        terms.push(WandTerm {
+            ord: ord,
+            postings: postings,
+            block_max_scores: block_max_scores,
+            max_score: max_score,
+        });
+    }
+
+    loop {
+        terms.retain(|term| term.postings.doc().is_some());
+        if terms.is_empty() || deadline.tick() {
+            break;
+        }
+
+        terms.sort_by_key(|term| term.postings.doc());
+        let min_competitive_score = collector.min_competitive_score();
+
+        // Find the pivot, the first document that could be competitive if it matches every
+        // term before it
+        let mut pivot = None;
+        for i in 0..terms.len() {
+            let max_score = query.combine(terms[..i + 1].iter().map(|term| term.max_score));
+
+            if is_competitive(max_score, min_competitive_score) {
+                pivot = Some(i);
+                break;
+            }
+        }
+
+        let mut pivot = match pivot {
+            Some(pivot) => pivot,
+            None => break,  // No remaining documents could be competitive
+        };
+
+        let pivot_doc = terms[pivot].postings.doc().unwrap();
+
+        // Include any other terms on the pivot document
+        while pivot + 1 < terms.len() && terms[pivot + 1].postings.doc() == Some(pivot_doc) {
+            pivot += 1;
+        }
+
+        // Check the pivot document against the impacts of the blocks it's in
+        let block_max_score = query.combine(terms[..pivot + 1].iter().map(|term| term.block_max_score(pivot_doc)));
+
+        if !is_competitive(block_max_score, min_competitive_score) {
+            // Nothing can be competitive until one of these blocks ends or the next term starts
+            let mut target = terms[..pivot + 1].iter()
+                .map(|term| {
+                    match term.postings.block_for(pivot_doc) {
+                        Some(block_ord) => term.postings.block_last_doc(block_ord) as u64 + 1,
+                        None => u32::max_value() as u64 + 1,
+                    }
+                })
+                .min()
+                .unwrap_or(pivot_doc as u64 + 1);
+
+            if let Some(next_doc) = terms.get(pivot + 1).and_then(|term| term.postings.doc()) {
+                if (next_doc as u64) < target {
+                    target = next_doc as u64;
+                }
+            }
+
+            for term in terms[..pivot + 1].iter_mut() {
+                try!(term.advance_to(target));
+            }
+
+            continue;
+        }
+
+        if terms[0].postings.doc() == Some(pivot_doc) {
+            // Every term up to the pivot is on the pivot document, score it
+            let is_deleted = deletion_list.as_ref().map(|deletion_list| deletion_list.contains(pivot_doc)).unwrap_or(false);
+
+            if !is_deleted {
+                // Terms are scored in reverse order, this is the same order the score function
+                // combines them in
+                let mut term_scores = Vec::with_capacity(pivot + 1);
+                for term in terms[..pivot + 1].iter() {
+                    let (field_id, term_id, ref scorer) = query.terms[term.ord];
+                    term_scores.push((term.ord, try!(score_term(pivot_doc, field_id, term_id, scorer, segment, stats))));
+                }
+                term_scores.sort_by(|a, b| b.0.cmp(&a.0));
+
+                let score = query.combine(term_scores.iter().map(|&(_, score)| score));
+                collector.collect(DocumentMatch::new_scored(segment.doc_id(pivot_doc).as_u64(), score));
+            }
+
+            for term in terms[..pivot + 1].iter_mut() {
+                try!(term.postings.next_doc());
+            }
+        } else {
+            // Move the terms before the pivot up to it
+            for term in terms[..pivot].iter_mut() {
+                try!(term.postings.advance(pivot_doc));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use kite::query::Query;
+use kite::segment::{Segment, SegmentId};
+use kite::doc_id_set::DocIdSet;
+use fnv::FnvHashMap;
+
+use {RocksDBStore, RocksDBReader, ReaderScope};
+use search::run_boolean_query;
+use search::planner::boolean_query::{BooleanQueryOp, BooleanQueryBuilder, plan_boolean_query};
+
+/// The documents matched by a warm query in each segment
+///
+/// This is an immutable snapshot, refreshing the warm queries replaces it rather than changing
+/// it so a plan that uses it isn't affected by segments being merged away while it runs.
+#[derive(Clone)]
+pub struct WarmQueryMatches(Arc<FnvHashMap<SegmentId, Arc<DocIdSet>>>);
+
+impl WarmQueryMatches {
+    fn new() -> WarmQueryMatches {
+        WarmQueryMatches(Arc::new(FnvHashMap::default()))
+    }
+
+    pub fn get(&self, segment_id: SegmentId) -> Option<&DocIdSet> {
+        self.0.get(&segment_id).map(|doc_id_set| &**doc_id_set)
+    }
+
+    /// Returns the total number of matches across all segments
+    pub fn len(&self) -> u64 {
+        self.0.values().map(|doc_id_set| doc_id_set.len() as u64).sum()
+    }
+}
+
+impl PartialEq for WarmQueryMatches {
+    fn eq(&self, other: &WarmQueryMatches) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for WarmQueryMatches {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WarmQueryMatches({} segments)", self.0.len())
+    }
+}
+
+struct WarmQuery {
+    name: String,
+    query: Arc<Query>,
+    matches: WarmQueryMatches,
+}
+
+/// Queries that are run ahead of time whenever new segments become active
+///
+/// The documents each query matches are cached per segment. When the planner comes across a
+/// query (or sub-query) that's equal to a warm query, it uses the cached documents instead of
+/// loading the term directories again.
+pub struct WarmQueries {
+    queries: RwLock<Vec<WarmQuery>>,
+}
+
+impl WarmQueries {
+    pub fn new() -> WarmQueries {
+        WarmQueries {
+            queries: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Returns the cached matches of a query
+    ///
+    /// Returns None if the query isn't a warm query or it hasn't been run on every segment
+    /// the reader can see yet.
+    pub fn find(&self, reader: &RocksDBReader, query: &Query) -> Option<WarmQueryMatches> {
+        let matches = {
+            let queries = self.queries.read().unwrap();
+
+            match queries.iter().find(|warm_query| *warm_query.query == *query) {
+                Some(warm_query) => warm_query.matches.clone(),
+                None => return None,
+            }
+        };
+
+        if reader.store.segments.iter_active(reader).all(|segment| matches.get(segment.id()).is_some()) {
+            Some(matches)
+        } else {
+            None
+        }
+    }
+}
+
+/// Plans a warm query
+///
+/// Unlike normal searches, deleted documents aren't excluded. Deletion lists can change
+/// without the segment changing so these are excluded by the plans that use the matches.
+fn plan_warm_query(reader: &RocksDBReader, query: &Query) -> Result<(Vec<BooleanQueryOp>, bool), String> {
+    let mut builder = BooleanQueryBuilder::new();
+    try!(plan_boolean_query(reader, &mut builder, query));
+    Ok(builder.build())
+}
+
+impl RocksDBStore {
+    /// Registers a query to be run whenever new segments become active
+    ///
+    /// The documents it matches are cached so searches that contain this query (for example,
+    /// as a filter) don't have to load the segments' term directories after a refresh.
+    /// Registering a query with the name of an existing one replaces it.
+    pub fn register_warm_query(&self, name: &str, query: Query) -> Result<(), String> {
+        {
+            let mut queries = self.warm_queries.queries.write().unwrap();
+            queries.retain(|warm_query| warm_query.name != name);
+            queries.push(WarmQuery {
+                name: name.to_string(),
+                query: Arc::new(query),
+                matches: WarmQueryMatches::new(),
+            });
+        }
+
+        self.refresh_warm_queries()
+    }
+
+    /// Removes a warm query, returns false if there isn't one with this name
+    pub fn unregister_warm_query(&self, name: &str) -> bool {
+        let mut queries = self.warm_queries.queries.write().unwrap();
+        let num_queries = queries.len();
+        queries.retain(|warm_query| warm_query.name != name);
+
+        queries.len() != num_queries
+    }
+
+    /// Runs the warm queries on any segments they haven't been run on yet
+    ///
+    /// This is called automatically after segments are written or merged. The matches of
+    /// segments that are no longer active are dropped.
+    pub fn refresh_warm_queries(&self) -> Result<(), String> {
+        // Take a copy of the queries so the lock isn't held while they run
+        let queries = self.warm_queries.queries.read().unwrap().iter()
+            .map(|warm_query| (warm_query.name.clone(), warm_query.query.clone(), warm_query.matches.clone()))
+            .collect::<Vec<_>>();
+
+        if queries.is_empty() {
+            return Ok(());
+        }
+
+        let reader = self.new_reader(ReaderScope::WholeStore);
+        let mut refreshed = Vec::with_capacity(queries.len());
+
+        for (name, query, matches) in queries {
+            let mut new_matches = FnvHashMap::default();
+            let mut plan = None;
+
+            for segment in self.segments.iter_active(&reader) {
+                let doc_id_set = match (matches.0).get(&segment.id()) {
+                    Some(doc_id_set) => doc_id_set.clone(),
+                    None => {
+                        // Only plan the query if there's a segment it needs to be run on
+                        if plan.is_none() {
+                            plan = Some(try!(plan_warm_query(&reader, &query)));
+                        }
+
+                        let (ref boolean_query, boolean_query_is_negated) = *plan.as_ref().unwrap();
+                        Arc::new(try!(run_boolean_query(boolean_query, boolean_query_is_negated, &self.schema, &segment)))
+                    }
+                };
+
+                new_matches.insert(segment.id(), doc_id_set);
+            }
+
+            refreshed.push((name, query, WarmQueryMatches(Arc::new(new_matches))));
+        }
+
+        // Store the new matches, unless the query was replaced while they were running
+        let mut queries = self.warm_queries.queries.write().unwrap();
+        for (name, query, matches) in refreshed {
+            if let Some(warm_query) = queries.iter_mut().find(|warm_query| warm_query.name == name && Arc::ptr_eq(&warm_query.query, &query)) {
+                warm_query.matches = matches;
+            }
+        }
+
+        Ok(())
+    }
+}
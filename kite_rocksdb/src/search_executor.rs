@@ -0,0 +1,183 @@
+use std::fmt;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, channel, SyncSender, Receiver, TrySendError};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+use {RocksDBStore, RocksDBReader};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Clone)]
+pub struct SearchExecutorConfig {
+    /// The number of searches that can run at the same time
+    pub num_threads: usize,
+
+    /// The number of searches that can wait for a thread before new ones are rejected
+    pub queue_size: usize,
+}
+
+impl Default for SearchExecutorConfig {
+    fn default() -> SearchExecutorConfig {
+        SearchExecutorConfig {
+            num_threads: 4,
+            queue_size: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchExecutorError {
+    /// The queue is full, the search was not run
+    QueueFull,
+
+    /// The search was dropped without finishing (eg, it panicked)
+    Aborted,
+}
+
+impl fmt::Display for SearchExecutorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SearchExecutorError::QueueFull => write!(f, "Search queue full"),
+            SearchExecutorError::Aborted => write!(f, "Search aborted"),
+        }
+    }
+}
+
+impl Error for SearchExecutorError {}
+
+impl From<SearchExecutorError> for String {
+    fn from(e: SearchExecutorError) -> String {
+        e.to_string()
+    }
+}
+
+/// A bounded pool of threads for running searches on
+///
+/// Searches are queued until a thread is free. Each search runs on a single thread so one
+/// expensive query can't take over the pool and, once the queue is full, new searches are
+/// rejected rather than piling up. This keeps the resources used by searching predictable.
+pub struct SearchExecutor {
+    sender: Mutex<SyncSender<Job>>,
+}
+
+impl SearchExecutor {
+    pub fn new(config: SearchExecutorConfig) -> SearchExecutor {
+        let (sender, receiver) = sync_channel::<Job>(config.queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..config.num_threads {
+            let receiver = receiver.clone();
+
+            thread::Builder::new()
+                .name(format!("kite-search-{}", i))
+                .spawn(move || {
+                    loop {
+                        let job = match receiver.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => break,  // Executor dropped
+                        };
+
+                        // Don't let a panicking search take the thread down with it
+                        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                    }
+                })
+                .expect("failed to spawn search thread");
+        }
+
+        SearchExecutor {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    fn submit(&self, job: Job) -> Result<(), SearchExecutorError> {
+        match self.sender.lock().unwrap().try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(SearchExecutorError::QueueFull),
+            Err(TrySendError::Disconnected(_)) => Err(SearchExecutorError::Aborted),
+        }
+    }
+}
+
+/// A search that has been submitted to the executor
+pub struct SearchHandle<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> SearchHandle<T> {
+    /// Blocks until the search has finished and returns its result
+    pub fn wait(self) -> Result<T, SearchExecutorError> {
+        self.receiver.recv().map_err(|_| SearchExecutorError::Aborted)
+    }
+}
+
+impl RocksDBStore {
+    /// Runs searches submitted with "spawn_search" on a bounded thread pool
+    pub fn set_search_executor(&mut self, config: SearchExecutorConfig) {
+        self.search_executor = Some(SearchExecutor::new(config));
+    }
+
+    /// Runs "search" with a reader of the store
+    ///
+    /// If the store has a search executor, the search is queued to run on it. Otherwise, it
+    /// runs straight away on the calling thread.
+    pub fn spawn_search<F, T>(store: &Arc<RocksDBStore>, search: F) -> Result<SearchHandle<T>, SearchExecutorError>
+        where F: FnOnce(&RocksDBReader) -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let (sender, receiver) = channel();
+
+        match store.search_executor {
+            Some(ref executor) => {
+                let job_store = store.clone();
+                try!(executor.submit(Box::new(move || {
+                    let reader = job_store.reader();
+                    let _ = sender.send(search(&reader));
+                })));
+            }
+            None => {
+                let reader = store.reader();
+                let _ = sender.send(search(&reader));
+            }
+        }
+
+        Ok(SearchHandle {
+            receiver: receiver,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+
+    use super::{SearchExecutor, SearchExecutorConfig, SearchExecutorError};
+
+    #[test]
+    fn test_queue_full() {
+        let executor = SearchExecutor::new(SearchExecutorConfig {
+            num_threads: 1,
+            queue_size: 1,
+        });
+
+        // Block the only thread until the end of the test
+        let started = Arc::new(Barrier::new(2));
+        let finish = Arc::new(Barrier::new(2));
+        {
+            let started = started.clone();
+            let finish = finish.clone();
+            executor.submit(Box::new(move || {
+                started.wait();
+                finish.wait();
+            })).unwrap();
+        }
+        started.wait();
+
+        // One job can wait in the queue, the next is rejected
+        assert_eq!(executor.submit(Box::new(|| {})), Ok(()));
+        assert_eq!(executor.submit(Box::new(|| {})), Err(SearchExecutorError::QueueFull));
+
+        finish.wait();
+    }
+}
@@ -1,17 +1,34 @@
-use std::io::Cursor;
+use std::sync::Arc;
 
 use kite::segment::{SegmentId, Segment};
 use kite::schema::FieldId;
 use kite::term::TermId;
+use kite::postings::BlockImpact;
+use kite::error::SegmentError;
+use kite::doc_id_set::DocIdSet;
+use kite::bitmap_format::BitmapDecodeError;
+use rocksdb::DBVector;
 use roaring::RoaringBitmap;
 use byteorder::{ByteOrder, LittleEndian};
 
 use RocksDBReader;
 use key_builder::KeyBuilder;
+use postings::{decode_doc_ids, decode_impacts};
+use doc_values::decode_doc_values_column;
+use segment_file::SegmentFile;
+use encryption::is_encrypted_value_type;
+use index_sort::IndexSort;
+use deletion_list::decode_deletion_list;
 
 pub struct RocksDBSegment<'a> {
     reader: &'a RocksDBReader<'a>,
     id: u32,
+    file: Option<Arc<SegmentFile>>,
+}
+
+/// Deserializes a term directory or field presence bitmap with "DocIdSet::deserialize_from"
+fn decode_doc_id_set(bytes: &[u8]) -> Result<RoaringBitmap, BitmapDecodeError> {
+    DocIdSet::deserialize_from(bytes).map(|doc_id_set| doc_id_set.iter().collect())
 }
 
 impl<'a> RocksDBSegment<'a> {
@@ -19,6 +36,47 @@ impl<'a> RocksDBSegment<'a> {
         RocksDBSegment {
             reader: reader,
             id: id,
+            file: reader.generation.segment_file(id),
+        }
+    }
+
+    /// Returns the order the segment's documents were written in
+    pub fn index_sort(&self) -> Result<Option<IndexSort>, SegmentError> {
+        let kb = KeyBuilder::segment_index_sort(self.id);
+        Ok(try!(self.reader.snapshot.get(kb.key()).map_err(SegmentError::storage)).and_then(|value| IndexSort::from_bytes(&value)))
+    }
+
+    /// Loads the encoded completion index of a field
+    pub fn load_completion_index(&self, field_id: FieldId) -> Result<Option<DBVector>, String> {
+        let kb = KeyBuilder::segment_completion_index(self.id, field_id.0);
+        Ok(try!(self.reader.snapshot.get(kb.key())))
+    }
+
+    /// Loads and decodes a value of the segment's postings or doc values
+    ///
+    /// These are read from the segment's file if it has one, otherwise from RocksDB.
+    fn load_data<T, F: FnOnce(&[u8]) -> T>(&self, kb: KeyBuilder, decode: F) -> Result<Option<T>, SegmentError> {
+        match self.file {
+            Some(ref file) => Ok(file.get(kb.key()).map(decode)),
+            None => Ok(try!(self.reader.snapshot.get(kb.key()).map_err(SegmentError::storage)).map(|value| decode(&value))),
+        }
+    }
+
+    /// Loads and decodes a value of the segment's postings, decrypting it first if the
+    /// segment's postings are encrypted
+    fn load_postings_data<T, F: FnOnce(&[u8]) -> T>(&self, kb: KeyBuilder, decode: F) -> Result<Option<T>, SegmentError> {
+        let value = try!(self.load_data(kb, |value| {
+            match self.reader.decrypt_postings(self.id, value) {
+                Ok(Some(decrypted)) => Ok(decode(&decrypted)),
+                Ok(None) => Ok(decode(value)),
+                Err(e) => Err(e),
+            }
+        }));
+
+        match value {
+            Some(Ok(value)) => Ok(Some(value)),
+            Some(Err(e)) => Err(SegmentError::storage(e)),
+            None => Ok(None),
         }
     }
 }
@@ -28,27 +86,84 @@ impl<'a> Segment for RocksDBSegment<'a> {
         SegmentId(self.id)
     }
 
-    fn load_statistic(&self, stat_name: &[u8]) -> Result<Option<i64>, String> {
+    fn load_statistic(&self, stat_name: &[u8]) -> Result<Option<i64>, SegmentError> {
         let kb = KeyBuilder::segment_stat(self.id, stat_name);
-        let val = try!(self.reader.snapshot.get(&kb.key())).map(|val| LittleEndian::read_i64(&val));
+        let val = try!(self.reader.snapshot.get(&kb.key()).map_err(SegmentError::storage)).map(|val| LittleEndian::read_i64(&val));
         Ok(val)
     }
 
-    fn load_stored_field_value_raw(&self, doc_local_id: u16, field_id: FieldId, value_type: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn load_stored_field_value_raw(&self, doc_local_id: u32, field_id: FieldId, value_type: &[u8]) -> Result<Option<Vec<u8>>, SegmentError> {
         let kb = KeyBuilder::stored_field_value(self.id, doc_local_id, field_id.0, value_type);
-        let val = try!(self.reader.snapshot.get(&kb.key()));
-        Ok(val.map(|v| v.to_vec()))
+        let value = try!(self.load_data(kb, |value| value.to_vec()));
+
+        match value {
+            Some(value) if is_encrypted_value_type(value_type) => {
+                let decrypted = try!(self.reader.decrypt_stored_value(self.id, &value).map_err(SegmentError::storage));
+                Ok(Some(decrypted.unwrap_or(value)))
+            }
+            value => Ok(value),
+        }
     }
 
-    fn load_term_directory(&self, field_id: FieldId, term_id: TermId) -> Result<Option<RoaringBitmap>, String> {
+    fn load_term_directory(&self, field_id: FieldId, term_id: TermId) -> Result<Option<RoaringBitmap>, SegmentError> {
+        let cache = &self.reader.store.term_directory_cache;
+        if let Some(term_directory) = cache.get(self.id, field_id, term_id) {
+            return Ok(Some((*term_directory).clone()));
+        }
+
         let kb = KeyBuilder::segment_dir_list(self.id, field_id.0, term_id.0);
-        let doc_id_set = try!(self.reader.snapshot.get(&kb.key())).map(|doc_id_set| RoaringBitmap::deserialize_from(Cursor::new(&doc_id_set[..])).unwrap());
-        Ok(doc_id_set)
+        match try!(self.load_data(kb, decode_doc_id_set)) {
+            Some(Ok(doc_id_set)) => {
+                let doc_id_set = Arc::new(doc_id_set);
+                cache.insert(self.id, field_id, term_id, doc_id_set.clone());
+                Ok(Some(Arc::try_unwrap(doc_id_set).unwrap_or_else(|doc_id_set| (*doc_id_set).clone())))
+            }
+            Some(Err(e)) => Err(SegmentError::Corrupt(format!("term directory of field {} term {}: {}", field_id.0, term_id.0, e))),
+            None => Ok(None),
+        }
+    }
+
+    fn load_postings_skip_list(&self, field_id: FieldId, term_id: TermId) -> Result<Option<Vec<u32>>, SegmentError> {
+        let kb = KeyBuilder::segment_postings_skip_list(self.id, field_id.0, term_id.0);
+        self.load_postings_data(kb, decode_doc_ids)
+    }
+
+    fn load_postings_block(&self, field_id: FieldId, term_id: TermId, block_ord: u32) -> Result<Option<Vec<u32>>, SegmentError> {
+        let kb = KeyBuilder::segment_postings_block(self.id, field_id.0, term_id.0, block_ord);
+        self.load_postings_data(kb, decode_doc_ids)
+    }
+
+    fn load_postings_impacts(&self, field_id: FieldId, term_id: TermId) -> Result<Option<Vec<BlockImpact>>, SegmentError> {
+        let kb = KeyBuilder::segment_postings_impacts(self.id, field_id.0, term_id.0);
+        self.load_postings_data(kb, decode_impacts)
     }
 
-    fn load_deletion_list(&self) -> Result<Option<RoaringBitmap>, String> {
+    fn load_rank_feature_column(&self, field_id: FieldId) -> Result<Option<Vec<f32>>, SegmentError> {
+        let kb = KeyBuilder::segment_rank_feature_column(self.id, field_id.0);
+        self.load_data(kb, |column| {
+            column.chunks(4).map(LittleEndian::read_f32).collect()
+        })
+    }
+
+    fn load_doc_values_column(&self, field_id: FieldId) -> Result<Option<Vec<Option<i64>>>, SegmentError> {
+        let kb = KeyBuilder::segment_doc_values_column(self.id, field_id.0);
+        self.load_data(kb, decode_doc_values_column)
+    }
+
+    fn load_field_presence(&self, field_id: FieldId) -> Result<Option<RoaringBitmap>, SegmentError> {
+        let kb = KeyBuilder::segment_field_presence(self.id, field_id.0);
+        match try!(self.load_data(kb, decode_doc_id_set)) {
+            Some(Ok(field_presence)) => Ok(Some(field_presence)),
+            Some(Err(e)) => Err(SegmentError::Corrupt(format!("field presence bitmap of field {}: {}", field_id.0, e))),
+            None => Ok(None),
+        }
+    }
+
+    fn load_deletion_list(&self) -> Result<Option<RoaringBitmap>, SegmentError> {
         let kb = KeyBuilder::segment_del_list(self.id);
-        let doc_id_set = try!(self.reader.snapshot.get(&kb.key())).map(|doc_id_set| RoaringBitmap::deserialize_from(Cursor::new(&doc_id_set[..])).unwrap());
-        Ok(doc_id_set)
+        match try!(self.reader.snapshot.get(&kb.key()).map_err(SegmentError::storage)) {
+            Some(deletion_list) => decode_deletion_list(&deletion_list).map(Some).map_err(|e| SegmentError::Corrupt(format!("deletion list: {}", e))),
+            None => Ok(None),
+        }
     }
 }
@@ -1,28 +1,74 @@
+use std::mem;
+use std::str;
 use std::collections::HashMap;
 
 use kite::{Document, Term, TermId};
 use kite::schema::FieldId;
+use kite::term_vector::TermVector;
+use kite::query::infix::{infix_field, index_ngrams};
 use kite::segment::{SegmentId, Segment};
-use byteorder::{LittleEndian, WriteBytesExt};
+use kite::error::SegmentError;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use roaring::RoaringBitmap;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 
+use DEFAULT_MAX_SEGMENT_DOCS;
 use key_builder::KeyBuilder;
+use index_sort::{IndexSort, sort_mapping};
+use completion::CompletionEntry;
+use term_vectors::{encode_term_vector, encode_term_offsets};
 
 #[derive(Debug)]
 pub struct SegmentBuilder {
-    current_doc: u16,
+    current_doc: u32,
     pub term_dictionary: HashMap<Term, TermId>,
     current_term_id: u32,
     pub term_directories: FnvHashMap<(FieldId, TermId), RoaringBitmap>,
     pub statistics: FnvHashMap<Vec<u8>, i64>,
-    pub stored_field_values: FnvHashMap<(FieldId, u16, Vec<u8>), Vec<u8>>,
+    pub stored_field_values: FnvHashMap<(FieldId, u32, Vec<u8>), Vec<u8>>,
+    pub rank_features: FnvHashMap<FieldId, Vec<f32>>,
+    pub doc_values: FnvHashMap<FieldId, Vec<Option<i64>>>,
+
+    /// The documents that have any value for each field
+    pub field_presence: FnvHashMap<FieldId, RoaringBitmap>,
+    pub completions: FnvHashMap<FieldId, Vec<CompletionEntry>>,
+
+    /// Fields to record the term vectors of
+    pub term_vector_fields: FnvHashSet<FieldId>,
+
+    /// Fields to index the n-grams of, so they can be searched with infix queries
+    pub infix_fields: FnvHashSet<FieldId>,
+
+    /// Set once the documents have been sorted with "sort_documents"
+    pub index_sort: Option<IndexSort>,
+
+    /// The most documents that can be added
+    pub max_docs: u32,
+}
+
+/// Builds the n-grams of each term in a field, at the positions of the terms they came from
+fn build_infix_term_vector(tokens: &TermVector) -> TermVector {
+    let mut ngrams = TermVector::new();
+
+    for (term, positions) in tokens.iter() {
+        // Only text can be split into characters
+        if let Ok(term) = str::from_utf8(term.as_bytes()) {
+            for ngram in index_ngrams(term) {
+                ngrams.entry(Term::from_string(&ngram)).or_insert_with(RoaringBitmap::new).union_with(positions);
+            }
+        }
+    }
+
+    ngrams
 }
 
 #[derive(Debug)]
 pub enum DocumentInsertError {
     /// Segment couldn't hold any more docs
     SegmentFull,
+
+    /// A rank feature value was zero, negative or not a number
+    InvalidRankFeatureValue(FieldId, f32),
 }
 
 impl SegmentBuilder {
@@ -34,6 +80,14 @@ impl SegmentBuilder {
             term_directories: FnvHashMap::default(),
             statistics: FnvHashMap::default(),
             stored_field_values: FnvHashMap::default(),
+            rank_features: FnvHashMap::default(),
+            doc_values: FnvHashMap::default(),
+            field_presence: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            term_vector_fields: FnvHashSet::default(),
+            infix_fields: FnvHashSet::default(),
+            index_sort: None,
+            max_docs: DEFAULT_MAX_SEGMENT_DOCS,
         }
     }
 
@@ -50,15 +104,61 @@ impl SegmentBuilder {
         term_id
     }
 
-    pub fn add_document(&mut self, doc: &Document) -> Result<u16, DocumentInsertError> {
+    /// Returns the number of times the term appears in the field of a document
+    /// The number of documents that have been added
+    pub fn num_docs(&self) -> usize {
+        self.current_doc as usize
+    }
+
+    /// Returns true if no more documents can be added
+    pub fn is_full(&self) -> bool {
+        self.current_doc >= self.max_docs
+    }
+
+    pub fn term_frequency(&self, field_id: FieldId, term_id: TermId, doc_id: u32) -> u32 {
+        let mut value_type = vec![b't', b'f'];
+        value_type.extend(term_id.0.to_string().as_bytes());
+
+        // Frequencies of 1 aren't stored
+        self.stored_field_values.get(&(field_id, doc_id, value_type))
+            .map(|value| LittleEndian::read_i64(value) as u32)
+            .unwrap_or(1)
+    }
+
+    /// Returns the encoded length of the field in a document
+    pub fn field_length(&self, field_id: FieldId, doc_id: u32) -> u8 {
+        self.stored_field_values.get(&(field_id, doc_id, b"len".to_vec()))
+            .map(|value| value[0])
+            .unwrap_or(0)
+    }
+
+    pub fn add_document(&mut self, doc: &Document) -> Result<u32, DocumentInsertError> {
+        // Check rank features before modifying anything
+        for (field_id, value) in doc.rank_features.iter() {
+            if !(*value > 0.0 && value.is_finite()) {
+                return Err(DocumentInsertError::InvalidRankFeatureValue(*field_id, *value));
+            }
+        }
+
+        if self.is_full() {
+            return Err(DocumentInsertError::SegmentFull);
+        }
+
         // Get document ord
         let doc_id = self.current_doc;
         self.current_doc += 1;
-        try!(self.current_doc.checked_add(1).ok_or(DocumentInsertError::SegmentFull));
+
+        // The n-grams of fields that can be searched by infix are indexed into a hidden sub-field
+        let mut infix_fields = Vec::new();
+        for (field_id, tokens) in doc.indexed_fields.iter() {
+            if self.infix_fields.contains(field_id) {
+                infix_fields.push((infix_field(*field_id), build_infix_term_vector(tokens)));
+            }
+        }
 
         // Insert indexed fields
         let mut term_frequencies = FnvHashMap::default();
-        for (field_id, tokens) in doc.indexed_fields.iter() {
+        for (field_id, tokens) in doc.indexed_fields.iter().chain(infix_fields.iter().map(|&(ref field_id, ref tokens)| (field_id, tokens))) {
             let mut field_token_count = 0;
 
             for (term, positions) in tokens.iter() {
@@ -73,7 +173,7 @@ impl SegmentBuilder {
                 *term_frequency += frequency;
 
                 // Write directory list
-                self.term_directories.entry((*field_id, term_id)).or_insert_with(RoaringBitmap::new).insert(doc_id as u32);
+                self.term_directories.entry((*field_id, term_id)).or_insert_with(RoaringBitmap::new).insert(doc_id);
 
                 // Write term frequency
                 // 1 is by far the most common frequency. At search time, we interpret a missing
@@ -94,6 +194,16 @@ impl SegmentBuilder {
                 *stat += 1;
             }
 
+            // Term vector
+            if self.term_vector_fields.contains(field_id) {
+                self.stored_field_values.insert((*field_id, doc_id, b"tv".to_vec()), encode_term_vector(tokens));
+
+                // Offsets are stored separately as not every analyzer records them
+                if !tokens.offsets.is_empty() {
+                    self.stored_field_values.insert((*field_id, doc_id, b"tvo".to_vec()), encode_term_offsets(tokens));
+                }
+            }
+
             // Field length
             // Used by the BM25 similarity model
             let length = ((field_token_count as f32).sqrt() - 1.0) * 3.0;
@@ -122,6 +232,80 @@ impl SegmentBuilder {
             self.stored_field_values.insert((*field, doc_id, b"val".to_vec()), value.to_bytes());
         }
 
+        // Insert source
+        if let Some(ref source) = doc.source {
+            self.stored_field_values.insert((FieldId(0), doc_id, b"src".to_vec()), source.clone());
+        }
+
+        // Insert routing key
+        if let Some(ref routing) = doc.routing {
+            self.stored_field_values.insert((FieldId(0), doc_id, b"rt".to_vec()), routing.as_bytes().to_vec());
+        }
+
+        // Insert doc values
+        // Stored values that can be converted into integers are also stored in a column per
+        // field so they can be sorted on without loading each document's stored fields
+        for (field, value) in doc.stored_fields.iter() {
+            if let Some(value) = value.to_doc_value() {
+                let column = self.doc_values.entry(*field).or_insert_with(Vec::new);
+                if column.len() <= doc_id as usize {
+                    column.resize(doc_id as usize + 1, None);
+                }
+                column[doc_id as usize] = Some(value);
+            }
+        }
+
+        // Insert rank features
+        // These are stored in a column per field, indexed by document ord. Documents without
+        // a value for the field are given a weight of 0
+        for (field, value) in doc.rank_features.iter() {
+            let column = self.rank_features.entry(*field).or_insert_with(Vec::new);
+            if column.len() <= doc_id as usize {
+                column.resize(doc_id as usize + 1, 0.0);
+            }
+            column[doc_id as usize] = *value;
+        }
+
+        // Insert completions
+        // These are compiled into an FST per field when the segment is written
+        for (field, completions) in doc.completions.iter() {
+            let entries = self.completions.entry(*field).or_insert_with(Vec::new);
+            for completion in completions.iter() {
+                entries.push(CompletionEntry::new(completion, doc_id));
+            }
+        }
+
+        // Field presence
+        let present_fields = doc.indexed_fields.keys()
+            .chain(doc.stored_fields.keys())
+            .chain(doc.rank_features.keys())
+            .chain(doc.completions.keys());
+        for field in present_fields {
+            self.field_presence.entry(*field).or_insert_with(RoaringBitmap::new).insert(doc_id);
+        }
+
+        // Field value statistics
+        // The number of documents with each field and the range of each field's doc values
+        let fields = doc.indexed_fields.keys().chain(doc.stored_fields.keys()).cloned().collect::<FnvHashSet<_>>();
+        for field in fields {
+            let stat = self.statistics.entry(KeyBuilder::segment_stat_field_doc_count_stat_name(field.0)).or_insert(0);
+            *stat += 1;
+        }
+
+        for (field, value) in doc.stored_fields.iter() {
+            if let Some(value) = value.to_doc_value() {
+                let min = self.statistics.entry(KeyBuilder::segment_stat_field_min_value_stat_name(field.0)).or_insert(value);
+                if value < *min {
+                    *min = value;
+                }
+
+                let max = self.statistics.entry(KeyBuilder::segment_stat_field_max_value_stat_name(field.0)).or_insert(value);
+                if value > *max {
+                    *max = value;
+                }
+            }
+        }
+
         // Increment total docs
         {
             let stat = self.statistics.entry(b"total_docs".to_vec()).or_insert(0);
@@ -130,6 +314,58 @@ impl SegmentBuilder {
 
         Ok(doc_id)
     }
+
+    /// Reorders the documents by the values of the sort field
+    ///
+    /// Returns the new id of each document, indexed by the id returned from "add_document".
+    pub fn sort_documents(&mut self, index_sort: IndexSort) -> Vec<u32> {
+        let num_docs = self.current_doc as usize;
+        let mut sort_values = self.doc_values.get(&index_sort.field).cloned().unwrap_or_else(Vec::new);
+        sort_values.resize(num_docs, None);
+        let mapping = sort_mapping(index_sort, &sort_values);
+
+        for term_directory in self.term_directories.values_mut() {
+            let mut sorted_term_directory = RoaringBitmap::new();
+            for doc_id in term_directory.iter() {
+                sorted_term_directory.insert(mapping[doc_id as usize]);
+            }
+            *term_directory = sorted_term_directory;
+        }
+
+        let stored_field_values = mem::replace(&mut self.stored_field_values, FnvHashMap::default());
+        self.stored_field_values = stored_field_values.into_iter()
+            .map(|((field_id, doc_id, value_type), value)| ((field_id, mapping[doc_id as usize], value_type), value))
+            .collect();
+
+        for column in self.rank_features.values_mut() {
+            let mut sorted_column = vec![0.0; num_docs];
+            for (doc_id, value) in column.iter().enumerate() {
+                sorted_column[mapping[doc_id] as usize] = *value;
+            }
+            *column = sorted_column;
+        }
+
+        for column in self.doc_values.values_mut() {
+            let mut sorted_column = vec![None; num_docs];
+            for (doc_id, value) in column.iter().enumerate() {
+                sorted_column[mapping[doc_id] as usize] = *value;
+            }
+            *column = sorted_column;
+        }
+
+        for field_presence in self.field_presence.values_mut() {
+            *field_presence = field_presence.iter().map(|doc_id| mapping[doc_id as usize]).collect();
+        }
+
+        for entries in self.completions.values_mut() {
+            for entry in entries.iter_mut() {
+                entry.doc_id = mapping[entry.doc_id as usize];
+            }
+        }
+
+        self.index_sort = Some(index_sort);
+        mapping
+    }
 }
 
 impl Segment for SegmentBuilder {
@@ -137,19 +373,31 @@ impl Segment for SegmentBuilder {
         SegmentId(0)
     }
 
-    fn load_statistic(&self, stat_name: &[u8]) -> Result<Option<i64>, String> {
+    fn load_statistic(&self, stat_name: &[u8]) -> Result<Option<i64>, SegmentError> {
         Ok(self.statistics.get(stat_name).cloned())
     }
 
-    fn load_stored_field_value_raw(&self, doc_local_id: u16, field_id: FieldId, value_type: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn load_stored_field_value_raw(&self, doc_local_id: u32, field_id: FieldId, value_type: &[u8]) -> Result<Option<Vec<u8>>, SegmentError> {
         Ok(self.stored_field_values.get(&(field_id, doc_local_id, value_type.to_vec())).cloned())
     }
 
-    fn load_term_directory(&self, field_id: FieldId, term_id: TermId) -> Result<Option<RoaringBitmap>, String> {
+    fn load_term_directory(&self, field_id: FieldId, term_id: TermId) -> Result<Option<RoaringBitmap>, SegmentError> {
         Ok(self.term_directories.get(&(field_id, term_id)).cloned())
     }
 
-    fn load_deletion_list(&self) -> Result<Option<RoaringBitmap>, String> {
+    fn load_rank_feature_column(&self, field_id: FieldId) -> Result<Option<Vec<f32>>, SegmentError> {
+        Ok(self.rank_features.get(&field_id).cloned())
+    }
+
+    fn load_doc_values_column(&self, field_id: FieldId) -> Result<Option<Vec<Option<i64>>>, SegmentError> {
+        Ok(self.doc_values.get(&field_id).cloned())
+    }
+
+    fn load_field_presence(&self, field_id: FieldId) -> Result<Option<RoaringBitmap>, SegmentError> {
+        Ok(self.field_presence.get(&field_id).cloned())
+    }
+
+    fn load_deletion_list(&self) -> Result<Option<RoaringBitmap>, SegmentError> {
         Ok(None)
     }
 }
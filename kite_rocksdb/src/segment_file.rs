@@ -0,0 +1,527 @@
+use std::fs::{self, File};
+use std::io::{self, Write, BufWriter};
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::ptr;
+#[cfg(unix)]
+use std::slice;
+#[cfg(not(unix))]
+use std::io::Read;
+
+use rocksdb::WriteBatch;
+use byteorder::{ByteOrder, LittleEndian};
+#[cfg(unix)]
+use libc;
+
+use RocksDBStore;
+use key_builder::{KeyBuilder, KeyIterator};
+
+const SEGMENT_FILE_MAGIC: &'static [u8] = b"KITESEG1";
+
+/// Size of the footer: number of entries (8 bytes), index offset (8 bytes) and magic (8 bytes)
+const FOOTER_SIZE: usize = 24;
+
+/// Size of each index entry: key offset (8 bytes), key length (4 bytes), value offset (8 bytes)
+/// and value length (4 bytes)
+const INDEX_ENTRY_SIZE: usize = 24;
+
+/// Returns where the key and value of the entry at "position" in the index are in the file
+///
+/// The entry is read from the file so could say anything. None is returned if the entry or
+/// anything it points to is outside of the file's key/value data.
+fn entry_ranges(data: &[u8], index_offset: usize, position: usize) -> Option<(Range<usize>, Range<usize>)> {
+    let entry_offset = position.checked_mul(INDEX_ENTRY_SIZE).and_then(|offset| offset.checked_add(index_offset));
+    let entry = match entry_offset {
+        Some(offset) if offset.checked_add(INDEX_ENTRY_SIZE).map(|end| end <= data.len()).unwrap_or(false) => &data[offset..offset + INDEX_ENTRY_SIZE],
+        _ => return None,
+    };
+
+    let range = |offset: u64, len: u32| {
+        if offset > index_offset as u64 {
+            return None;
+        }
+
+        let start = offset as usize;
+        match start.checked_add(len as usize) {
+            Some(end) if end <= index_offset => Some(start..end),
+            _ => None,
+        }
+    };
+
+    match (range(LittleEndian::read_u64(&entry[0..8]), LittleEndian::read_u32(&entry[8..12])), range(LittleEndian::read_u64(&entry[12..20]), LittleEndian::read_u32(&entry[20..24]))) {
+        (Some(key), Some(value)) => Some((key, value)),
+        _ => None,
+    }
+}
+
+/// A read-only memory map of a whole file
+#[cfg(unix)]
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(unix)]
+impl Mmap {
+    fn open(file: &File) -> io::Result<Mmap> {
+        let len = try!(file.metadata()).len() as usize;
+
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "segment file is empty"));
+        }
+
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Mmap {
+            ptr: ptr,
+            len: len,
+        })
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+// The mapping is read-only so it's safe to share between threads
+#[cfg(unix)]
+unsafe impl Send for Mmap {}
+#[cfg(unix)]
+unsafe impl Sync for Mmap {}
+
+/// The contents of a whole file
+///
+/// Memory mapping is only implemented for Unix, elsewhere the file is read into memory.
+#[cfg(not(unix))]
+struct Mmap {
+    data: Vec<u8>,
+}
+
+#[cfg(not(unix))]
+impl Mmap {
+    fn open(mut file: &File) -> io::Result<Mmap> {
+        let mut data = Vec::new();
+        try!(file.read_to_end(&mut data));
+
+        if data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "segment file is empty"));
+        }
+
+        Ok(Mmap {
+            data: data,
+        })
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// An immutable, memory-mapped file containing the postings and doc values of a segment
+///
+/// This is a sorted table of the same keys that would otherwise be stored in RocksDB
+/// (term directories, postings, stored values, rank feature and doc values columns). Values are read
+/// straight out of the mapping so they don't use the RocksDB block cache and don't need to
+/// be copied before they're decoded.
+///
+/// Layout:
+///
+/// ```text
+/// [key/value data] [index entry]* [num entries: u64] [index offset: u64] [magic]
+/// ```
+///
+/// Index entries are sorted by key so they can be binary searched.
+pub struct SegmentFile {
+    mmap: Mmap,
+    num_entries: usize,
+    index_offset: usize,
+}
+
+impl SegmentFile {
+    /// Writes a segment file. The entries must be sorted by key
+    pub fn write<P: AsRef<Path>>(path: P, entries: &[(Vec<u8>, Vec<u8>)]) -> io::Result<()> {
+        let mut writer = BufWriter::new(try!(File::create(path)));
+        let mut index = Vec::with_capacity(entries.len() * INDEX_ENTRY_SIZE);
+        let mut offset = 0u64;
+
+        for &(ref key, ref value) in entries.iter() {
+            let mut entry = [0; INDEX_ENTRY_SIZE];
+            LittleEndian::write_u64(&mut entry[0..8], offset);
+            LittleEndian::write_u32(&mut entry[8..12], key.len() as u32);
+            LittleEndian::write_u64(&mut entry[12..20], offset + key.len() as u64);
+            LittleEndian::write_u32(&mut entry[20..24], value.len() as u32);
+            index.extend_from_slice(&entry);
+
+            try!(writer.write_all(key));
+            try!(writer.write_all(value));
+            offset += (key.len() + value.len()) as u64;
+        }
+
+        try!(writer.write_all(&index));
+
+        let mut footer = [0; FOOTER_SIZE];
+        LittleEndian::write_u64(&mut footer[0..8], entries.len() as u64);
+        LittleEndian::write_u64(&mut footer[8..16], offset);
+        footer[16..24].copy_from_slice(SEGMENT_FILE_MAGIC);
+        try!(writer.write_all(&footer));
+
+        let file = try!(writer.into_inner().map_err(|e| e.into_error()));
+        file.sync_all()
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SegmentFile> {
+        let file = try!(File::open(path));
+        let mmap = try!(Mmap::open(&file));
+
+        let (num_entries, index_offset) = {
+            let data = mmap.as_slice();
+            if data.len() < FOOTER_SIZE || &data[data.len() - 8..] != SEGMENT_FILE_MAGIC {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not a segment file"));
+            }
+
+            let footer = &data[data.len() - FOOTER_SIZE..];
+            let num_entries = LittleEndian::read_u64(&footer[0..8]) as usize;
+            let index_offset = LittleEndian::read_u64(&footer[8..16]) as usize;
+
+            // These are read from the file so could be anything, a corrupt file mustn't overflow
+            let expected_len = num_entries.checked_mul(INDEX_ENTRY_SIZE)
+                .and_then(|index_len| index_len.checked_add(index_offset))
+                .and_then(|len| len.checked_add(FOOTER_SIZE));
+
+            if expected_len != Some(data.len()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "segment file is corrupt"));
+            }
+
+            // So are the index entries. They're all checked here so that looking up a key never
+            // slices outside of the file
+            for position in 0..num_entries {
+                if entry_ranges(data, index_offset, position).is_none() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "segment file is corrupt"));
+                }
+            }
+
+            (num_entries, index_offset)
+        };
+
+        Ok(SegmentFile {
+            mmap: mmap,
+            num_entries: num_entries,
+            index_offset: index_offset,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_entries
+    }
+
+    /// The contents of the whole file, for copying it elsewhere
+    pub fn as_bytes(&self) -> &[u8] {
+        self.mmap.as_slice()
+    }
+
+    /// Returns the key and value of the entry at "position" in the index
+    fn entry(&self, position: usize) -> (&[u8], &[u8]) {
+        let data = self.mmap.as_slice();
+        let (key, value) = entry_ranges(data, self.index_offset, position).expect("segment file entries are checked when it's opened");
+        (&data[key], &data[value])
+    }
+
+    /// Returns the position of a key in the index
+    fn find(&self, key: &[u8]) -> Option<usize> {
+        let mut low = 0;
+        let mut high = self.num_entries;
+
+        while low < high {
+            let mid = (low + high) / 2;
+            let (entry_key, _) = self.entry(mid);
+
+            if entry_key == key {
+                return Some(mid);
+            } else if entry_key < key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        None
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.find(key).map(|position| self.entry(position).1)
+    }
+
+    /// Looks up a key, returning a handle to its value that keeps the file mapped
+    pub fn get_shared(file: &Arc<SegmentFile>, key: &[u8]) -> Option<SegmentFileValue> {
+        file.find(key).map(|position| SegmentFileValue {
+            file: file.clone(),
+            position: position,
+        })
+    }
+
+    /// Iterates over all entries in key order
+    pub fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a [u8], &'a [u8])> + 'a> {
+        Box::new((0..self.num_entries).map(move |position| self.entry(position)))
+    }
+}
+
+/// A value in a segment file that can outlive the borrow of the file it was read from
+pub struct SegmentFileValue {
+    file: Arc<SegmentFile>,
+    position: usize,
+}
+
+impl Deref for SegmentFileValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.file.entry(self.position).1
+    }
+}
+
+impl RocksDBStore {
+    /// Write merged segments to immutable memory-mapped files instead of RocksDB
+    ///
+    /// This is intended for large indexes that are mostly read from. The postings and doc
+    /// values of merged segments are moved out of RocksDB into a file per segment (kept in a
+    /// "segments" directory inside the store). Statistics, deletion lists and the document
+    /// index stay in RocksDB as they are either small or can change after the segment has
+    /// been written.
+    ///
+    /// Segments that were written before this was enabled are not affected.
+    pub fn set_write_segment_files(&mut self, enabled: bool) {
+        self.write_segment_files = enabled;
+    }
+
+    pub(crate) fn segment_files_path(&self) -> PathBuf {
+        self.db.path().join("segments")
+    }
+
+    pub(crate) fn segment_file_path(&self, segment: u32) -> PathBuf {
+        self.segment_files_path().join(format!("{}.seg", segment))
+    }
+
+    /// Opens all the segment files in the store
+    pub(crate) fn open_segment_files(&self) -> Result<(), String> {
+        let dir = match fs::read_dir(self.segment_files_path()) {
+            Ok(dir) => dir,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let mut segment_files = self.segment_files.write().unwrap();
+        for entry in dir {
+            let path = try!(entry.map_err(|e| e.to_string())).path();
+
+            if path.extension().and_then(|extension| extension.to_str()) != Some("seg") {
+                continue;
+            }
+
+            let segment = match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u32>().ok()) {
+                Some(segment) => segment,
+                None => continue,
+            };
+
+            let segment_file = try!(SegmentFile::open(&path).map_err(|e| format!("{}: {}", path.display(), e)));
+            segment_files.insert(segment, Arc::new(segment_file));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the file of the segment, if it's been written to one
+    pub(crate) fn segment_file(&self, segment: u32) -> Option<Arc<SegmentFile>> {
+        self.segment_files.read().unwrap().get(&segment).cloned()
+    }
+
+    /// Moves the postings and doc values of a segment out of RocksDB and into a segment file
+    ///
+    /// This must only be called before the segment is activated.
+    pub(crate) fn write_segment_file(&self, segment: u32) -> Result<(), String> {
+        let mut entries = Vec::new();
+
+        // Term directories are keyed by field/term/segment so all of them need to be scanned
+        for (key, v) in KeyIterator::new(self.db.raw_iterator(), KeyBuilder::dir_list_prefix()) {
+            if key.segment() == Some(segment) {
+                entries.push((key.key_builder().key().to_vec(), v));
+            }
+        }
+
+        // The rest are prefixed by the segment id
+        for kb in &[KeyBuilder::segment_postings_prefix(segment), KeyBuilder::segment_stored_values_prefix(segment), KeyBuilder::segment_rank_features_prefix(segment), KeyBuilder::segment_doc_values_prefix(segment), KeyBuilder::segment_field_presence_prefix(segment)] {
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
+
+                if !k.starts_with(kb.key()) {
+                    break;
+                }
+
+                entries.push((k, iter.value().unwrap()));
+
+                iter.next();
+            }
+        }
+
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // Write to a temporary file first so a crash can't leave a partially-written segment file behind
+        try!(fs::create_dir_all(self.segment_files_path()).map_err(|e| e.to_string()));
+        let path = self.segment_file_path(segment);
+        let tmp_path = path.with_extension("tmp");
+        try!(SegmentFile::write(&tmp_path, &entries).map_err(|e| e.to_string()));
+        try!(fs::rename(&tmp_path, &path).map_err(|e| e.to_string()));
+
+        let segment_file = try!(SegmentFile::open(&path).map_err(|e| e.to_string()));
+        self.segment_files.write().unwrap().insert(segment, Arc::new(segment_file));
+
+        // Now the data can be read from the file, remove it from RocksDB
+        let mut write_batch = WriteBatch::default();
+        for &(ref key, _) in entries.iter() {
+            try!(write_batch.delete(key));
+        }
+        try!(self.db.write(write_batch));
+
+        Ok(())
+    }
+
+    /// Copies the data in a segment file back into RocksDB
+    ///
+    /// Merges read their source segments from RocksDB so this is done to any segment files
+    /// before they're merged. The copied data is removed along with the rest of the segment
+    /// when it's purged.
+    pub(crate) fn restore_segment_file(&self, segment: u32) -> Result<(), String> {
+        let segment_file = match self.segment_file(segment) {
+            Some(segment_file) => segment_file,
+            None => return Ok(()),
+        };
+
+        let mut write_batch = WriteBatch::default();
+        for (key, value) in segment_file.iter() {
+            try!(write_batch.put(key, value));
+        }
+        try!(self.db.write(write_batch));
+
+        Ok(())
+    }
+
+    /// Deletes a segment's file
+    ///
+    /// Readers that already have the file open can continue to use it. If the file can't be
+    /// deleted, it's left behind. This is harmless, as it belongs to a segment that's no
+    /// longer active, but it will take up disk space.
+    pub(crate) fn remove_segment_file(&self, segment: u32) {
+        if self.segment_files.write().unwrap().remove(&segment).is_some() {
+            let _ = fs::remove_file(self.segment_file_path(segment));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::SegmentFile;
+
+    #[test]
+    fn test_write_and_read() {
+        fs::create_dir_all("test_indices").unwrap();
+        let path = "test_indices/test_segment_file.seg";
+
+        let entries = vec![
+            (b"d1/2/3".to_vec(), b"foo".to_vec()),
+            (b"p3/1/2".to_vec(), b"".to_vec()),
+            (b"v3/0/1/val".to_vec(), b"barbaz".to_vec()),
+        ];
+        SegmentFile::write(path, &entries).unwrap();
+
+        let segment_file = SegmentFile::open(path).unwrap();
+        assert_eq!(segment_file.len(), 3);
+        assert_eq!(segment_file.get(b"d1/2/3"), Some(&b"foo"[..]));
+        assert_eq!(segment_file.get(b"p3/1/2"), Some(&b""[..]));
+        assert_eq!(segment_file.get(b"v3/0/1/val"), Some(&b"barbaz"[..]));
+        assert_eq!(segment_file.get(b"v3/0/1/len"), None);
+        assert_eq!(segment_file.iter().map(|(key, _)| key.to_vec()).collect::<Vec<_>>(), entries.iter().map(|&(ref key, _)| key.clone()).collect::<Vec<_>>());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_open_invalid() {
+        fs::create_dir_all("test_indices").unwrap();
+        let path = "test_indices/test_segment_file_invalid.seg";
+        fs::write(path, b"not a segment file at all").unwrap();
+
+        assert!(SegmentFile::open(path).is_err());
+
+        // A footer whose sizes overflow when they're added up
+        let mut footer = vec![0xff; 16];
+        footer.extend_from_slice(b"KITESEG1");
+        fs::write(path, &footer).unwrap();
+
+        assert!(SegmentFile::open(path).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_open_corrupt_index_entry() {
+        fs::create_dir_all("test_indices").unwrap();
+        let path = "test_indices/test_segment_file_corrupt_index_entry.seg";
+
+        let entries = vec![
+            (b"d1/2/3".to_vec(), b"foo".to_vec()),
+            (b"v3/0/1/val".to_vec(), b"barbaz".to_vec()),
+        ];
+        SegmentFile::write(path, &entries).unwrap();
+        let bytes = fs::read(path).unwrap();
+        let index_offset = 6 + 3 + 10 + 6;
+
+        // Value lengths that go past the data, or overflow, and offsets that overflow
+        let corruptions: Vec<Box<dyn Fn(&mut [u8])>> = vec![
+            Box::new(|entry| LittleEndian::write_u32(&mut entry[20..24], 7)),
+            Box::new(|entry| LittleEndian::write_u32(&mut entry[20..24], u32::max_value())),
+            Box::new(|entry| LittleEndian::write_u64(&mut entry[12..20], u64::max_value())),
+            Box::new(|entry| LittleEndian::write_u64(&mut entry[0..8], u64::max_value() - 1)),
+        ];
+
+        for corrupt in corruptions {
+            let mut corrupted = bytes.clone();
+            corrupt(&mut corrupted[index_offset + 24..index_offset + 48]);
+            fs::write(path, &corrupted).unwrap();
+
+            match SegmentFile::open(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {}
+                Err(e) => panic!("expected a corrupt segment file error, got {}", e),
+                Ok(_) => panic!("expected a corrupt segment file error"),
+            }
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,10 +1,70 @@
 use std::str;
+use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use rocksdb::{self, DB, DBRawIterator};
+use rocksdb::{self, DB, WriteBatch, Snapshot};
+use fnv::{FnvHashMap, FnvHashSet};
 
 use RocksDBReader;
+use key_builder::{KeyBuilder, Key, KeyIterator};
 use segment::RocksDBSegment;
+use segment_file::SegmentFile;
+use encryption::SegmentEncryption;
+
+/// A version of the set of active segments
+///
+/// Every change to which segments are active (writing a segment or committing a merge)
+/// creates a new generation. Readers capture the generation their snapshot can see along
+/// with the files of its segments, so they keep seeing exactly that set of segments for as
+/// long as they're open, even if the segments are merged away in the meantime.
+pub struct SegmentGeneration {
+    number: Option<u64>,
+    segments: Vec<u32>,
+    files: FnvHashMap<u32, Arc<SegmentFile>>,
+    encryption: FnvHashMap<u32, Result<SegmentEncryption, String>>,
+}
+
+impl SegmentGeneration {
+    /// The generation number, this is None if it couldn't be read from the snapshot
+    pub fn number(&self) -> Option<u64> {
+        self.number
+    }
+
+    pub fn segments(&self) -> &[u32] {
+        &self.segments
+    }
+
+    /// Returns the file of the segment, if it had been written to one
+    pub fn segment_file(&self, segment: u32) -> Option<Arc<SegmentFile>> {
+        self.files.get(&segment).cloned()
+    }
+
+    /// Returns how the segment's data was encrypted, None if it isn't encrypted
+    ///
+    /// Returns an error if the segment's encryption record couldn't be decoded.
+    pub fn segment_encryption(&self, segment: u32) -> Result<Option<SegmentEncryption>, String> {
+        match self.encryption.get(&segment) {
+            Some(&Ok(encryption)) => Ok(Some(encryption)),
+            Some(&Err(ref e)) => Err(e.clone()),
+            None => Ok(None),
+        }
+    }
+
+    /// A copy of the generation that only has the given segments
+    ///
+    /// This is used for the readers of tenants, which only see the tenant's segments. It keeps
+    /// the generation number, readers of the same tenant with the same number see the same
+    /// segments.
+    pub fn restrict(&self, segments: &FnvHashSet<u32>) -> SegmentGeneration {
+        SegmentGeneration {
+            number: self.number,
+            segments: self.segments.iter().cloned().filter(|segment| segments.contains(segment)).collect(),
+            files: self.files.iter().filter(|&(segment, _)| segments.contains(segment)).map(|(segment, file)| (*segment, file.clone())).collect(),
+            encryption: self.encryption.iter().filter(|&(segment, _)| segments.contains(segment)).map(|(segment, encryption)| (*segment, encryption.clone())).collect(),
+        }
+    }
+}
 
 /// Manages "segments" within the index
 ///
@@ -13,6 +73,12 @@ use segment::RocksDBSegment;
 /// controlling routine tasks such as merging and vacuuming
 pub struct SegmentManager {
     next_segment: AtomicUsize,
+
+    /// The last generation number that was committed, locked while committing
+    generation: Mutex<u64>,
+
+    /// The most recently captured generation, shared by readers until it changes
+    current_generation: RwLock<Option<Arc<SegmentGeneration>>>,
 }
 
 impl SegmentManager {
@@ -24,6 +90,8 @@ impl SegmentManager {
 
         Ok(SegmentManager {
             next_segment: AtomicUsize::new(1),
+            generation: Mutex::new(0),
+            current_generation: RwLock::new(None),
         })
     }
 
@@ -36,8 +104,16 @@ impl SegmentManager {
             None => 1,  // TODO: error
         };
 
+        // Indexes created before generations were added don't have this
+        let generation = match try!(db.get(b".generation")) {
+            Some(generation) => parse_generation(&generation).unwrap_or(0),
+            None => 0,
+        };
+
         Ok(SegmentManager {
             next_segment: AtomicUsize::new(next_segment as usize),
+            generation: Mutex::new(generation),
+            current_generation: RwLock::new(None),
         })
     }
 
@@ -48,45 +124,117 @@ impl SegmentManager {
         Ok(next_segment)
     }
 
-    /// Iterates currently active segments
-    pub fn iter_active<'a>(&self, reader: &'a RocksDBReader) -> ActiveSegmentsIterator<'a> {
-        let mut iter = reader.snapshot.raw_iterator();
+    /// Starts a change to the set of active segments
+    ///
+    /// Adds the next generation number to the write batch. The returned guard must be held
+    /// until the write batch has been written so generations are committed in order.
+    pub fn begin_commit(&self, write_batch: &mut WriteBatch) -> Result<MutexGuard<u64>, rocksdb::Error> {
+        let mut generation = self.generation.lock().unwrap();
+        *generation += 1;
+        try!(write_batch.put(b".generation", generation.to_string().as_bytes()));
+        Ok(generation)
+    }
+
+    /// Returns the generation of active segments that the snapshot can see
+    pub fn generation(&self, snapshot: &Snapshot, segment_files: &RwLock<FnvHashMap<u32, Arc<SegmentFile>>>) -> Arc<SegmentGeneration> {
+        let number = match snapshot.get(b".generation") {
+            Ok(Some(generation)) => parse_generation(&generation),
+            Ok(None) => Some(0),
+            Err(_) => None,
+        };
+
+        if let Some(ref current_generation) = *self.current_generation.read().unwrap() {
+            if number.is_some() && current_generation.number == number {
+                return current_generation.clone();
+            }
+        }
+
+        // Files are written before their segment is activated and removed after it has been
+        // purged, so every active segment that has a file can be found in the map
+        let mut segments = Vec::new();
+        let mut files = FnvHashMap::default();
+        let segment_files = segment_files.read().unwrap();
+        let mut iter = snapshot.raw_iterator();
         iter.seek(b"a");
+        while iter.valid() {
+            let segment = {
+                let k = iter.key().unwrap();
+
+                if k[0] != b'a' {
+                    break;
+                }
+
+                str::from_utf8(&k[1..]).unwrap().parse::<u32>().unwrap()
+            };
+
+            if let Some(file) = segment_files.get(&segment) {
+                files.insert(segment, file.clone());
+            }
+
+            segments.push(segment);
+            iter.next();
+        }
+
+        // Encryption records are written along with the segment, so the snapshot has the
+        // records of all of its active segments
+        let mut encryption = FnvHashMap::default();
+        for (key, value) in KeyIterator::new(snapshot.raw_iterator(), KeyBuilder::all_segments_encryption_prefix()) {
+            if let Key::SegmentEncryption(segment) = key {
+                encryption.insert(segment, SegmentEncryption::from_bytes(&value));
+            }
+        }
+
+        let generation = Arc::new(SegmentGeneration {
+            number: number,
+            segments: segments,
+            files: files,
+            encryption: encryption,
+        });
+
+        // Only replace the shared generation with a newer one, an older reader could be
+        // capturing its generation at the same time
+        if let Some(number) = number {
+            let mut current_generation = self.current_generation.write().unwrap();
+            let is_newer = match *current_generation {
+                Some(ref current_generation) => current_generation.number.map(|current| number > current).unwrap_or(true),
+                None => true,
+            };
+
+            if is_newer {
+                *current_generation = Some(generation.clone());
+            }
+        }
+
+        generation
+    }
+
+    /// Iterates the active segments of the reader's generation
+    pub fn iter_active<'a>(&self, reader: &'a RocksDBReader) -> ActiveSegmentsIterator<'a> {
+        self.iter_segments(reader, reader.generation.segments())
+    }
+
+    /// Iterates over some of the segments that are active in the reader
+    pub fn iter_segments<'a>(&self, reader: &'a RocksDBReader, segments: &'a [u32]) -> ActiveSegmentsIterator<'a> {
         ActiveSegmentsIterator {
             reader: reader,
-            iter: iter,
-            fused: false,
+            segments: segments.iter(),
         }
     }
 }
 
+fn parse_generation(value: &[u8]) -> Option<u64> {
+    str::from_utf8(value).ok().and_then(|value| value.parse::<u64>().ok())
+}
+
 pub struct ActiveSegmentsIterator<'a> {
     reader: &'a RocksDBReader<'a>,
-    iter: DBRawIterator,
-    fused: bool,
+    segments: slice::Iter<'a, u32>,
 }
 
 impl<'a> Iterator for ActiveSegmentsIterator<'a> {
     type Item = RocksDBSegment<'a>;
 
     fn next(&mut self) -> Option<RocksDBSegment<'a>> {
-        if !self.fused && self.iter.valid() {
-            let segment_id = {
-                let k = self.iter.key().unwrap();
-
-                if k[0] != b'a' {
-                    self.fused = true;
-                    return None;
-                }
-
-                str::from_utf8(&k[1..]).unwrap().parse::<u32>().unwrap()
-            };
-
-            self.iter.next();
-
-            Some(RocksDBSegment::new(self.reader, segment_id))
-        } else {
-            None
-        }
+        self.segments.next().map(|segment_id| RocksDBSegment::new(self.reader, *segment_id))
     }
 }
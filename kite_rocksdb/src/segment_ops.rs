@@ -1,19 +1,42 @@
 use std::str;
-use std::io::Cursor;
+use std::cmp;
+use std::fmt;
+use std::error::Error;
+use std::time::Instant;
 
 use rocksdb::{self, WriteBatch, WriteOptions};
 use roaring::RoaringBitmap;
 use kite::document::DocId;
 use kite::segment::SegmentId;
+use kite::cancellation::CancellationToken;
+use kite::metrics;
+use kite::doc_id_set::DocIdSet;
+use kite::bitmap_format::BitmapDecodeError;
 use byteorder::{ByteOrder, LittleEndian};
 use fnv::{FnvHashMap, FnvHashSet};
 
-use RocksDBStore;
-use key_builder::KeyBuilder;
+use {RocksDBStore, encode_doc_id_set};
+use key_builder::{Key, KeyBuilder, KeyIterator, SEGMENT_KEY_PREFIXES};
+use postings::build_postings;
+use doc_values::{encode_doc_values_column, decode_doc_values_column};
+use completion::{CompletionEntry, CompletionIndex, encode_completion_index};
+use encryption::{SegmentEncryption, DecryptionError, is_encrypted_value_type};
 
 #[derive(Debug)]
 pub enum SegmentMergeError {
     TooManyDocs,
+    Cancelled,
+
+    /// The segments are owned by different tenants (or only some of them are owned by one)
+    MixedTenants,
+
+    SegmentFileError(String),
+    CompletionIndexError(String),
+    BitmapDecodeError(BitmapDecodeError),
+
+    /// A source segment is encrypted and couldn't be decrypted
+    DecryptionError(DecryptionError),
+
     RocksDBError(rocksdb::Error),
 }
 
@@ -23,17 +46,52 @@ impl From<rocksdb::Error> for SegmentMergeError {
     }
 }
 
+impl From<BitmapDecodeError> for SegmentMergeError {
+    fn from(e: BitmapDecodeError) -> SegmentMergeError {
+        SegmentMergeError::BitmapDecodeError(e)
+    }
+}
+
+impl From<DecryptionError> for SegmentMergeError {
+    fn from(e: DecryptionError) -> SegmentMergeError {
+        SegmentMergeError::DecryptionError(e)
+    }
+}
+
+impl fmt::Display for SegmentMergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SegmentMergeError::TooManyDocs => write!(f, "Too many docs"),
+            SegmentMergeError::Cancelled => write!(f, "Merge cancelled"),
+            SegmentMergeError::MixedTenants => write!(f, "Segments of different tenants can't be merged"),
+            SegmentMergeError::SegmentFileError(ref e) => write!(f, "{}", e),
+            SegmentMergeError::CompletionIndexError(ref e) => write!(f, "{}", e),
+            SegmentMergeError::BitmapDecodeError(ref e) => write!(f, "{}", e),
+            SegmentMergeError::DecryptionError(ref e) => write!(f, "{}", e),
+            SegmentMergeError::RocksDBError(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for SegmentMergeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SegmentMergeError::BitmapDecodeError(ref e) => Some(e),
+            SegmentMergeError::DecryptionError(ref e) => Some(e),
+            SegmentMergeError::RocksDBError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<SegmentMergeError> for String {
     fn from(e: SegmentMergeError) -> String {
-        match e {
-            SegmentMergeError::TooManyDocs => "Too many docs".to_string(),
-            SegmentMergeError::RocksDBError(e) => e.into(),
-        }
+        e.to_string()
     }
 }
 
 impl RocksDBStore {
-    fn merge_segment_data(&self, source_segments: &Vec<u32>, dest_segment: u32, doc_id_mapping: &FnvHashMap<DocId, u16>) -> Result<(), SegmentMergeError> {
+    fn merge_segment_data(&self, source_segments: &Vec<u32>, dest_segment: u32, doc_id_mapping: &FnvHashMap<DocId, u32>, encryption: Option<&SegmentEncryption>, cancellation_token: &CancellationToken) -> Result<(), SegmentMergeError> {
         // Put source_segments in a FnvHashSet as this is much faster for performing contains queries against
         let source_segments_btree = source_segments.iter().collect::<FnvHashSet<_>>();
 
@@ -57,6 +115,10 @@ impl RocksDBStore {
         let mut current_td_key: Option<(u32, u32)> = None;
         let mut current_td = RoaringBitmap::new();
 
+        // The term frequency and field length of each document in the current term directory
+        // These are needed to work out the impacts of each block of the postings list
+        let mut current_td_impacts: FnvHashMap<u32, (u32, u8)> = FnvHashMap::default();
+
         let mut iter = self.db.raw_iterator();
         iter.seek(b"d");
         while iter.valid() {
@@ -67,29 +129,48 @@ impl RocksDBStore {
                 break;
             }
 
+            if cancellation_token.is_cancelled() {
+                return Err(SegmentMergeError::Cancelled);
+            }
+
             let (field, term, segment) = parse_term_directory_key(&k);
 
             if source_segments_btree.contains(&segment) {
                 if current_td_key != Some((field, term)) {
                     // Finished current term directory. Write it to the DB and start the next one
                     if let Some((field, term)) = current_td_key {
-                        let mut current_td_vec = Vec::new();
-                        current_td.serialize_into(&mut current_td_vec).unwrap();
-
                         let kb = KeyBuilder::segment_dir_list(dest_segment, field, term);
-                        try!(self.db.put_opt(&kb.key(), &current_td_vec, &write_options));
+                        try!(self.db.put_opt(&kb.key(), &encode_doc_id_set(&current_td), &write_options));
+
+                        let doc_impact = |doc_id| current_td_impacts.get(&doc_id).cloned().unwrap_or((1, 0));
+                        for (kb, value) in build_postings(dest_segment, field, term, &current_td, doc_impact) {
+                            try!(self.db.put_opt(&kb.key(), &self.encrypt_postings(encryption, value), &write_options));
+                        }
+
                         current_td.clear();
+                        current_td_impacts.clear();
                     }
 
                     current_td_key = Some((field, term));
                 }
 
                 // Merge term directory into the new one (and remap the doc ids)
-                let bitmap = RoaringBitmap::deserialize_from(Cursor::new(iter.value().unwrap())).unwrap();
+                let bitmap = try!(DocIdSet::deserialize_from(&iter.value().unwrap()[..]));
                 for doc_id in bitmap.iter() {
-                    let doc_id = DocId(SegmentId(segment), doc_id as u16);
+                    let doc_id = DocId(SegmentId(segment), doc_id);
                     let new_doc_id = doc_id_mapping.get(&doc_id).unwrap();
-                    current_td.insert(*new_doc_id as u32);
+                    current_td.insert(*new_doc_id);
+
+                    // Read the term frequency and field length from the source segment
+                    let mut value_type = vec![b't', b'f'];
+                    value_type.extend(term.to_string().as_bytes());
+                    let kb = KeyBuilder::stored_field_value(segment, doc_id.1, field, &value_type);
+                    let term_frequency = try!(self.db.get(&kb.key())).map(|value| LittleEndian::read_i64(&value) as u32).unwrap_or(1);
+
+                    let kb = KeyBuilder::stored_field_value(segment, doc_id.1, field, b"len");
+                    let field_length = try!(self.db.get(&kb.key())).map(|value| value[0]).unwrap_or(0);
+
+                    current_td_impacts.insert(*new_doc_id, (term_frequency, field_length));
                 }
             }
 
@@ -98,12 +179,16 @@ impl RocksDBStore {
 
         // All done, write the last term directory
         if let Some((field, term)) = current_td_key {
-            let mut current_td_vec = Vec::new();
-            current_td.serialize_into(&mut current_td_vec).unwrap();
-
             let kb = KeyBuilder::segment_dir_list(dest_segment, field, term);
-            try!(self.db.put_opt(&kb.key(), &current_td_vec, &write_options));
+            try!(self.db.put_opt(&kb.key(), &encode_doc_id_set(&current_td), &write_options));
+
+            let doc_impact = |doc_id| current_td_impacts.get(&doc_id).cloned().unwrap_or((1, 0));
+            for (kb, value) in build_postings(dest_segment, field, term, &current_td, doc_impact) {
+                try!(self.db.put_opt(&kb.key(), &self.encrypt_postings(encryption, value), &write_options));
+            }
+
             current_td.clear();
+            current_td_impacts.clear();
         }
 
         // Merge the stored values
@@ -124,6 +209,10 @@ impl RocksDBStore {
         }
 
         for source_segment in source_segments.iter() {
+            // Encrypted values are decrypted with the source segment's key and encrypted again
+            // with the new segment's
+            let source_encryption = try!(self.load_segment_encryption(*source_segment));
+
             let kb = KeyBuilder::segment_stored_values_prefix(*source_segment);
             let mut iter = self.db.raw_iterator();
             iter.seek(&kb.key());
@@ -135,6 +224,10 @@ impl RocksDBStore {
                     break;
                 }
 
+                if cancellation_token.is_cancelled() {
+                    return Err(SegmentMergeError::Cancelled);
+                }
+
                 let (segment, doc_id, field, value_type) = parse_stored_value_key(&k);
 
                 if segment != *source_segment {
@@ -143,12 +236,21 @@ impl RocksDBStore {
                 }
 
                 // Remap doc id
-                let doc_id = DocId(SegmentId(segment), doc_id as u16);
+                let doc_id = DocId(SegmentId(segment), doc_id);
                 let new_doc_id = doc_id_mapping.get(&doc_id).unwrap();
 
                 // Write value into new segment
                 let kb = KeyBuilder::stored_field_value(dest_segment, *new_doc_id, field, &value_type);
-                try!(self.db.put_opt(&kb.key(), unsafe { &iter.value_inner().unwrap() }, &write_options));
+                let value = unsafe { iter.value_inner().unwrap() };
+                match source_encryption {
+                    Some(ref source_encryption) if is_encrypted_value_type(&value_type) => {
+                        let value = try!(self.decrypt_block(segment, source_encryption, value));
+                        try!(self.db.put_opt(&kb.key(), &self.encrypt_stored_value(encryption, &value_type, &value), &write_options));
+                    }
+                    _ => {
+                        try!(self.db.put_opt(&kb.key(), &self.encrypt_stored_value(encryption, &value_type, value), &write_options));
+                    }
+                }
 
                 iter.next();
             }
@@ -156,7 +258,7 @@ impl RocksDBStore {
 
         // Merge the statistics
         // Like stored values, these start with segment ids. But instead of just rewriting the
-        // key, we need to sum up all the statistics across the segments being merged.
+        // key, we need to combine the statistics across the segments being merged.
 
         let mut statistics = FnvHashMap::default();
 
@@ -190,13 +292,27 @@ impl RocksDBStore {
                 }
 
 
-                let stat = statistics.entry(statistic_name).or_insert(0);
-                *stat += LittleEndian::read_i64(unsafe { &iter.value_inner().unwrap() });
+                let value = LittleEndian::read_i64(unsafe { &iter.value_inner().unwrap() });
+
+                // The value ranges of fields are combined, everything else is a count
+                if statistic_name.starts_with(b"fmin-") {
+                    let stat = statistics.entry(statistic_name).or_insert(value);
+                    *stat = cmp::min(*stat, value);
+                } else if statistic_name.starts_with(b"fmax-") {
+                    let stat = statistics.entry(statistic_name).or_insert(value);
+                    *stat = cmp::max(*stat, value);
+                } else {
+                    let stat = statistics.entry(statistic_name).or_insert(0);
+                    *stat += value;
+                }
 
                 iter.next();
             }
         }
 
+        // Deletes are merged into this so it must exist
+        statistics.entry(b"deleted_docs".to_vec()).or_insert(0);
+
         // Write merged statistics to new segment
         for (stat_name, stat_value) in statistics {
             let kb = KeyBuilder::segment_stat(dest_segment, &stat_name);
@@ -205,6 +321,227 @@ impl RocksDBStore {
             try!(self.db.put_opt(&kb.key(), &val_bytes, &write_options));
         }
 
+        // Merge the rank feature columns
+        // These are also prefixed by segment id. Each value needs to be moved to the position
+        // of its document in the new segment.
+
+        /// Converts rank feature column key strings "r1/2" into tuples of 2 u32s (1, 2)
+        fn parse_rank_feature_key(key: &[u8]) -> (u32, u32) {
+            let mut nums_iter = key[1..].split(|b| *b == b'/').map(|s| str::from_utf8(s).unwrap().parse::<u32>().unwrap());
+            (nums_iter.next().unwrap(), nums_iter.next().unwrap())
+        }
+
+        let mut rank_features: FnvHashMap<u32, Vec<f32>> = FnvHashMap::default();
+
+        for source_segment in source_segments.iter() {
+            let kb = KeyBuilder::segment_rank_features_prefix(*source_segment);
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
+
+                if k[0] != b'r' {
+                    // No more rank features to merge
+                    break;
+                }
+
+                let (segment, field) = parse_rank_feature_key(&k);
+
+                if segment != *source_segment {
+                    // Segment finished
+                    break;
+                }
+
+                let column = rank_features.entry(field).or_insert_with(Vec::new);
+                for (doc_id, value_bytes) in iter.value().unwrap().chunks(4).enumerate() {
+                    let value = LittleEndian::read_f32(value_bytes);
+                    if value == 0.0 {
+                        // Document doesn't have a value
+                        continue;
+                    }
+
+                    // Remap doc id
+                    let doc_id = DocId(SegmentId(segment), doc_id as u32);
+                    let new_doc_id = *doc_id_mapping.get(&doc_id).unwrap() as usize;
+
+                    if column.len() <= new_doc_id {
+                        column.resize(new_doc_id + 1, 0.0);
+                    }
+                    column[new_doc_id] = value;
+                }
+
+                iter.next();
+            }
+        }
+
+        // Write merged rank feature columns to new segment
+        for (field, column) in rank_features {
+            let mut column_bytes = vec![0; column.len() * 4];
+            for (doc_id, value) in column.iter().enumerate() {
+                LittleEndian::write_f32(&mut column_bytes[doc_id * 4..], *value);
+            }
+
+            let kb = KeyBuilder::segment_rank_feature_column(dest_segment, field);
+            try!(self.db.put_opt(&kb.key(), &column_bytes, &write_options));
+        }
+
+        // Merge the doc values columns
+        // Like rank features, each value needs to be moved to the position of its document in
+        // the new segment
+
+        /// Converts doc values column key strings "c1/2" into tuples of 2 u32s (1, 2)
+        fn parse_doc_values_key(key: &[u8]) -> (u32, u32) {
+            let mut nums_iter = key[1..].split(|b| *b == b'/').map(|s| str::from_utf8(s).unwrap().parse::<u32>().unwrap());
+            (nums_iter.next().unwrap(), nums_iter.next().unwrap())
+        }
+
+        let mut doc_values: FnvHashMap<u32, Vec<Option<i64>>> = FnvHashMap::default();
+
+        for source_segment in source_segments.iter() {
+            let kb = KeyBuilder::segment_doc_values_prefix(*source_segment);
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
+
+                if k[0] != b'c' {
+                    // No more doc values to merge
+                    break;
+                }
+
+                let (segment, field) = parse_doc_values_key(&k);
+
+                if segment != *source_segment {
+                    // Segment finished
+                    break;
+                }
+
+                let column = doc_values.entry(field).or_insert_with(Vec::new);
+                for (doc_id, value) in decode_doc_values_column(&iter.value().unwrap()).into_iter().enumerate() {
+                    if value.is_none() {
+                        // Document doesn't have a value
+                        continue;
+                    }
+
+                    // Remap doc id
+                    let doc_id = DocId(SegmentId(segment), doc_id as u32);
+                    let new_doc_id = *doc_id_mapping.get(&doc_id).unwrap() as usize;
+
+                    if column.len() <= new_doc_id {
+                        column.resize(new_doc_id + 1, None);
+                    }
+                    column[new_doc_id] = value;
+                }
+
+                iter.next();
+            }
+        }
+
+        // Write merged doc values columns to new segment
+        for (field, column) in doc_values {
+            let kb = KeyBuilder::segment_doc_values_column(dest_segment, field);
+            try!(self.db.put_opt(&kb.key(), &encode_doc_values_column(&column), &write_options));
+        }
+
+        // Merge the field presence bitmaps
+        // Like the doc values columns, these are keyed by segment so the doc ids need remapping
+
+        /// Converts field presence key strings "e1/2" into tuples of 2 u32s (1, 2)
+        fn parse_field_presence_key(key: &[u8]) -> (u32, u32) {
+            let mut nums_iter = key[1..].split(|b| *b == b'/').map(|s| str::from_utf8(s).unwrap().parse::<u32>().unwrap());
+            (nums_iter.next().unwrap(), nums_iter.next().unwrap())
+        }
+
+        let mut field_presence: FnvHashMap<u32, RoaringBitmap> = FnvHashMap::default();
+
+        for source_segment in source_segments.iter() {
+            let kb = KeyBuilder::segment_field_presence_prefix(*source_segment);
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
+
+                if k[0] != b'e' {
+                    // No more field presence bitmaps to merge
+                    break;
+                }
+
+                let (segment, field) = parse_field_presence_key(&k);
+
+                if segment != *source_segment {
+                    // Segment finished
+                    break;
+                }
+
+                let bitmap = try!(DocIdSet::deserialize_from(&iter.value().unwrap()[..]));
+                let merged_bitmap = field_presence.entry(field).or_insert_with(RoaringBitmap::new);
+                for doc_id in bitmap.iter() {
+                    // Remap doc id
+                    let doc_id = DocId(SegmentId(segment), doc_id);
+                    merged_bitmap.insert(*doc_id_mapping.get(&doc_id).unwrap());
+                }
+
+                iter.next();
+            }
+        }
+
+        // Write merged field presence bitmaps to new segment
+        for (field, bitmap) in field_presence {
+            let kb = KeyBuilder::segment_field_presence(dest_segment, field);
+            try!(self.db.put_opt(&kb.key(), &encode_doc_id_set(&bitmap), &write_options));
+        }
+
+        // Merge the completion indexes
+        // The entries of each source segment are read back out of its FST and rebuilt into a
+        // single FST with the documents' new ids
+
+        /// Converts completion index key strings "f1/2" into tuples of 2 u32s (1, 2)
+        fn parse_completion_key(key: &[u8]) -> (u32, u32) {
+            let mut nums_iter = key[1..].split(|b| *b == b'/').map(|s| str::from_utf8(s).unwrap().parse::<u32>().unwrap());
+            (nums_iter.next().unwrap(), nums_iter.next().unwrap())
+        }
+
+        let mut completions: FnvHashMap<u32, Vec<CompletionEntry>> = FnvHashMap::default();
+
+        for source_segment in source_segments.iter() {
+            let kb = KeyBuilder::segment_completions_prefix(*source_segment);
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
+
+                if k[0] != b'f' {
+                    // No more completions to merge
+                    break;
+                }
+
+                let (segment, field) = parse_completion_key(&k);
+
+                if segment != *source_segment {
+                    // Segment finished
+                    break;
+                }
+
+                let index_bytes = iter.value().unwrap();
+                let index = try!(CompletionIndex::new(&index_bytes).map_err(SegmentMergeError::CompletionIndexError));
+                let entries = completions.entry(field).or_insert_with(Vec::new);
+                for mut entry in index.entries() {
+                    // Remap doc id
+                    let doc_id = DocId(SegmentId(segment), entry.doc_id);
+                    entry.doc_id = *doc_id_mapping.get(&doc_id).unwrap();
+                    entries.push(entry);
+                }
+
+                iter.next();
+            }
+        }
+
+        // Write merged completion indexes to new segment
+        for (field, entries) in completions {
+            let kb = KeyBuilder::segment_completion_index(dest_segment, field);
+            try!(self.db.put_opt(&kb.key(), &encode_completion_index(entries), &write_options));
+        }
+
         // Note: Don't merge the deletion lists
         // Deletion lists can change at any time so we must lock the "document index"
         // before merging them so they can't be altered during merge. we cannot lock
@@ -213,13 +550,19 @@ impl RocksDBStore {
         Ok(())
     }
 
-    fn commit_segment_merge(&self, source_segments: &Vec<u32>, dest_segment: u32, doc_id_mapping: &FnvHashMap<DocId, u16>) -> Result<(), SegmentMergeError> {
+    fn commit_segment_merge(&self, source_segments: &Vec<u32>, dest_segment: u32, doc_id_mapping: &FnvHashMap<DocId, u32>, tenant: Option<&[u8]>) -> Result<(), SegmentMergeError> {
         let mut write_batch = WriteBatch::default();
 
         // Activate new segment
         let kb = KeyBuilder::segment_active(dest_segment);
         try!(write_batch.put(&kb.key(), b""));
 
+        // The new segment belongs to the tenant of the source segments
+        if let Some(tenant) = tenant {
+            let kb = KeyBuilder::tenant_segment(tenant, dest_segment);
+            try!(write_batch.put(&kb.key(), b""));
+        }
+
         // Deactivate old segments
         for source_segment in source_segments.iter() {
             // Activate new segment
@@ -229,14 +572,66 @@ impl RocksDBStore {
 
         // Update document index and commit
         // This will write the write batch
-        try!(self.document_index.commit_segment_merge(&self.db, write_batch, source_segments, dest_segment, doc_id_mapping));
+        let generation = {
+            let generation = try!(self.segments.begin_commit(&mut write_batch));
+            try!(self.document_index.commit_segment_merge(&self.db, write_batch, source_segments, dest_segment, doc_id_mapping));
+            *generation
+        };
+        self.notify_listeners(|listener| listener.committed(generation));
+
+        // Readers opened from now on can't see the source segments
+        self.reader_epochs.advance();
 
         Ok(())
     }
 
     pub fn merge_segments(&self, source_segments: &Vec<u32>) -> Result<u32, SegmentMergeError> {
+        self.merge_segments_cancellable(source_segments, &CancellationToken::new())
+    }
+
+    /// Merges the segments, stopping early if the token is cancelled
+    ///
+    /// The token is checked while the segment data is being copied. If the merge is cancelled,
+    /// the partially-written segment is purged and the source segments are left as they were.
+    /// Once the merge starts committing it can no longer be cancelled.
+    pub fn merge_segments_cancellable(&self, source_segments: &Vec<u32>, cancellation_token: &CancellationToken) -> Result<u32, SegmentMergeError> {
+        // Documents of a tenant must stay in segments that only contain the tenant's documents
+        let tenants = self.segment_tenants(source_segments);
+        let tenant = tenants.first().map(|&(_, ref tenant)| tenant.clone());
+        if !tenants.is_empty() && (tenants.len() != source_segments.len() || tenants.iter().any(|&(_, ref other)| Some(other) != tenant.as_ref())) {
+            return Err(SegmentMergeError::MixedTenants);
+        }
+
         let dest_segment = try!(self.segments.new_segment(&self.db));
 
+        log_debug!("merging segments {:?} into segment {}", source_segments, dest_segment);
+        trace_event!(source_segments = ?source_segments, segment = dest_segment, "merging segments");
+        self.notify_listeners(|listener| listener.merge_started(source_segments, dest_segment));
+        let result = self.merge_segments_into(source_segments, dest_segment, tenant.as_ref().map(|tenant| &tenant[..]), cancellation_token);
+
+        match result {
+            Ok(()) => {
+                log_debug!("merged segments {:?} into segment {}", source_segments, dest_segment);
+                trace_event!(source_segments = ?source_segments, segment = dest_segment, "merged segments");
+            }
+            Err(ref e) => {
+                log_warn!("merging segments {:?} into segment {} failed: {}", source_segments, dest_segment, e);
+                trace_event!(source_segments = ?source_segments, segment = dest_segment, error = %e, "merging segments failed");
+            }
+        }
+        self.notify_listeners(|listener| listener.merge_finished(source_segments, dest_segment, result.is_ok()));
+
+        result.map(|_| dest_segment)
+    }
+
+    fn merge_segments_into(&self, source_segments: &Vec<u32>, dest_segment: u32, tenant: Option<&[u8]>, cancellation_token: &CancellationToken) -> Result<(), SegmentMergeError> {
+        let start_time = Instant::now();
+
+        // Merges read the source segments from RocksDB, copy back any that are in segment files
+        for source_segment in source_segments.iter() {
+            try!(self.restore_segment_file(*source_segment).map_err(SegmentMergeError::SegmentFileError));
+        }
+
         // Generate a mapping between the ids of the documents in the old segments to the new one
         // This packs the id spaces of the old segments together:
         // For example, say we have to merge 3 segments with 100 documents each:
@@ -244,26 +639,39 @@ impl RocksDBStore {
         //  - The second segment's ids will be remapped to 100 - 199
         //  - The third segment's ids will be remapped to 200 - 299
 
-        let mut doc_id_mapping: FnvHashMap<DocId, u16> = FnvHashMap::default();
-        let mut current_doc_id: u32 = 0;
+        let mut doc_id_mapping: FnvHashMap<DocId, u32> = FnvHashMap::default();
 
-        for source_segment in source_segments.iter() {
-            let kb = KeyBuilder::segment_stat(*source_segment, b"total_docs");
-            let total_docs = match try!(self.db.get(&kb.key())) {
-                Some(total_docs_bytes) => {
-                    LittleEndian::read_i64(&total_docs_bytes)
-                }
-                None => continue,
-            };
+        if let Some(index_sort) = self.index_sort {
+            // Interleave the documents of the source segments in the index's sort order instead
+            let docs = try!(self.sorted_merge_docs(source_segments, index_sort));
+            if docs.len() > self.max_segment_docs as usize {
+                return Err(SegmentMergeError::TooManyDocs);
+            }
 
-            for source_doc_id in 0..total_docs {
-                if current_doc_id >= 65536 {
-                    return Err(SegmentMergeError::TooManyDocs);
-                }
+            for (new_doc_id, doc_id) in docs.into_iter().enumerate() {
+                doc_id_mapping.insert(doc_id, new_doc_id as u32);
+            }
+        } else {
+            let mut current_doc_id: u32 = 0;
+
+            for source_segment in source_segments.iter() {
+                let kb = KeyBuilder::segment_stat(*source_segment, b"total_docs");
+                let total_docs = match try!(self.db.get(&kb.key())) {
+                    Some(total_docs_bytes) => {
+                        LittleEndian::read_i64(&total_docs_bytes)
+                    }
+                    None => continue,
+                };
 
-                let from = DocId(SegmentId(*source_segment), source_doc_id as u16);
-                doc_id_mapping.insert(from, current_doc_id as u16);
-                current_doc_id += 1;
+                for source_doc_id in 0..total_docs {
+                    if current_doc_id >= self.max_segment_docs {
+                        return Err(SegmentMergeError::TooManyDocs);
+                    }
+
+                    let from = DocId(SegmentId(*source_segment), source_doc_id as u32);
+                    doc_id_mapping.insert(from, current_doc_id);
+                    current_doc_id += 1;
+                }
             }
         }
 
@@ -274,7 +682,41 @@ impl RocksDBStore {
         // This means that nothing bad will happen if it crashes half way through -- the
         // worst that could happen is we're left with a partially-written segment that we
         // have to clean up.
-        try!(self.merge_segment_data(&source_segments, dest_segment, &doc_id_mapping));
+        // The new segment is encrypted with the current key, so merging the segments that use
+        // an old key moves their data onto the new one
+        let encryption = self.new_segment_encryption();
+
+        match self.merge_segment_data(&source_segments, dest_segment, &doc_id_mapping, encryption.as_ref(), cancellation_token) {
+            Ok(()) => {}
+            Err(SegmentMergeError::Cancelled) => {
+                // Clean up the data that was written before the merge was cancelled
+                // The segment was never activated so no reader can be using it
+                try!(self.purge_segments_now(&vec![dest_segment]));
+                return Err(SegmentMergeError::Cancelled);
+            }
+            Err(SegmentMergeError::DecryptionError(e)) => {
+                // Likewise if a source segment's key isn't available
+                try!(self.purge_segments_now(&vec![dest_segment]));
+                return Err(SegmentMergeError::DecryptionError(e));
+            }
+            Err(e) => return Err(e),
+        }
+
+        if let Some(index_sort) = self.index_sort {
+            let kb = KeyBuilder::segment_index_sort(dest_segment);
+            try!(self.db.put(&kb.key(), &index_sort.to_bytes()));
+        }
+
+        if let Some(ref encryption) = encryption {
+            let kb = KeyBuilder::segment_encryption(dest_segment);
+            try!(self.db.put(&kb.key(), &encryption.to_bytes()));
+        }
+
+        // Move the postings and doc values of the new segment into a file
+        // This is done before the segment is activated so nothing can be reading it yet
+        if self.write_segment_files {
+            try!(self.write_segment_file(dest_segment).map_err(SegmentMergeError::SegmentFileError));
+        }
 
         // Commit the merge
         // This activates the new segment and updates the document index. Effectively committing
@@ -283,12 +725,121 @@ impl RocksDBStore {
         // prevent documents in the source segments being deleted/updated so we don't accidentally
         // undelete them (this will block until the merge is complete so they delete/update from
         // the new segment).
-        try!(self.commit_segment_merge(&source_segments, dest_segment, &doc_id_mapping));
+        try!(self.commit_segment_merge(&source_segments, dest_segment, &doc_id_mapping, tenant));
+
+        // Warming is only an optimisation, if it fails the warm queries are run normally
+        let _ = self.refresh_warm_queries();
+
+        self.metrics.increment_counter(metrics::SEGMENTS_MERGED, source_segments.len() as u64);
+        self.metrics.record_histogram(metrics::MERGE_DURATION_SECONDS, metrics::duration_to_seconds(start_time.elapsed()));
 
-        Ok(dest_segment)
+        Ok(())
     }
 
+    /// Deletes the data of segments that are no longer active
+    ///
+    /// Readers that were opened before the segments were merged away may still be searching
+    /// them. If there are any, the segments are purged by a later call to this method or
+    /// "purge_released_segments" once those readers have been dropped.
     pub fn purge_segments(&self, segments: &Vec<u32>) -> Result<(), rocksdb::Error> {
+        self.reader_epochs.release(segments.clone());
+        self.purge_released_segments()
+    }
+
+    /// Purges the segments passed to "purge_segments" that no reader can see any more
+    pub fn purge_released_segments(&self) -> Result<(), rocksdb::Error> {
+        let segments = self.reader_epochs.take_purgeable();
+        if segments.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(e) = self.purge_segments_now(&segments) {
+            // Try again next time
+            self.reader_epochs.release(segments);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Purges the data of segments that aren't active
+    ///
+    /// Segments are written and merged without holding a lock on the database, so if the
+    /// process stops part way through a merge the new segment's data is left behind without
+    /// being activated. The same happens to source segments that were merged away but not
+    /// purged yet. This is run when the store is opened, before there are any readers, so
+    /// every inactive segment can be purged. Returns the segments that were purged.
+    pub(crate) fn recover_segments(&self) -> Result<Vec<u32>, rocksdb::Error> {
+        let mut active = FnvHashSet::default();
+        let mut iter = self.db.raw_iterator();
+        iter.seek(b"a");
+        while iter.valid() {
+            let k = iter.key().unwrap();
+            if k[0] != b'a' {
+                break;
+            }
+
+            if let Some(segment) = str::from_utf8(&k[1..]).ok().and_then(|segment| segment.parse::<u32>().ok()) {
+                active.insert(segment);
+            }
+
+            iter.next();
+        }
+
+        let mut segments = FnvHashSet::default();
+
+        // Term directories and tenant records are keyed by something else first, so all of
+        // them need to be scanned
+        for prefix in vec![KeyBuilder::dir_list_prefix(), KeyBuilder::all_tenants_segments_prefix()] {
+            for (key, _) in KeyIterator::new(self.db.raw_iterator(), prefix) {
+                if let Some(segment) = key.segment() {
+                    segments.insert(segment);
+                }
+            }
+        }
+
+        // The rest of the segment data is keyed by segment first. Only the first key of each
+        // segment is read, then the iterator skips over the rest of the segment's keys
+        // (digits sort after "/", so "v12" followed by "0" is after every "v12/..." key)
+        for prefix in SEGMENT_KEY_PREFIXES.iter() {
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&[*prefix]);
+            while iter.valid() {
+                let k = iter.key().unwrap();
+                if k[0] != *prefix {
+                    break;
+                }
+
+                match Key::parse(&k).and_then(|key| key.segment()) {
+                    Some(segment) => {
+                        segments.insert(segment);
+
+                        let mut next = vec![*prefix];
+                        next.extend(segment.to_string().as_bytes());
+                        next.push(b'0');
+                        iter.seek(&next);
+                    }
+                    None => iter.next(),
+                }
+            }
+        }
+
+        // As well as any segment files that were written before a merge was interrupted
+        segments.extend(self.segment_files.read().unwrap().keys().cloned());
+
+        let mut inactive = segments.into_iter().filter(|segment| !active.contains(segment)).collect::<Vec<_>>();
+        if inactive.is_empty() {
+            return Ok(inactive);
+        }
+
+        inactive.sort();
+        log_warn!("purging data of inactive segments {:?}", inactive);
+        try!(self.purge_segments_now(&inactive));
+
+        Ok(inactive)
+    }
+
+    pub(crate) fn purge_segments_now(&self, segments: &Vec<u32>) -> Result<(), rocksdb::Error> {
         // Put segments in a FnvHashSet as this is much faster for performing contains queries against
         let segments_btree = segments.iter().collect::<FnvHashSet<_>>();
 
@@ -296,31 +847,40 @@ impl RocksDBStore {
         write_options.set_sync(false);
         write_options.disable_wal(true);
 
+        self.term_directory_cache.remove_segments(segments);
+        self.filter_cache.remove_segments(segments);
+
         // Purge term directories
+        for (key, _) in KeyIterator::new(self.db.raw_iterator(), KeyBuilder::dir_list_prefix()) {
+            if key.segment().map(|segment| segments_btree.contains(&segment)).unwrap_or(false) {
+                try!(self.db.delete(key.key_builder().key()));
+            }
+        }
 
-        /// Converts term directory key strings "d1/2/3" into tuples of 3 i32s (1, 2, 3)
-        fn parse_term_directory_key(key: &[u8]) -> (u32, u32, u32) {
-            let mut nums_iter = key[1..].split(|b| *b == b'/').map(|s| str::from_utf8(s).unwrap().parse::<u32>().unwrap());
-            (nums_iter.next().unwrap(), nums_iter.next().unwrap(), nums_iter.next().unwrap())
+        // Purge the records of which tenant owns the segments
+        for (key, _) in KeyIterator::new(self.db.raw_iterator(), KeyBuilder::all_tenants_segments_prefix()) {
+            if key.segment().map(|segment| segments_btree.contains(&segment)).unwrap_or(false) {
+                try!(self.db.delete(key.key_builder().key()));
+            }
         }
 
-        let mut iter = self.db.raw_iterator();
-        iter.seek(b"d");
-        while iter.valid() {
-            let k = iter.key().unwrap();
+        // Purge the postings lists
+        for source_segment in segments.iter() {
+            let kb = KeyBuilder::segment_postings_prefix(*source_segment);
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
 
-            if k[0] != b'd' {
-                // No more term directories to delete
-                break;
-            }
+                if !k.starts_with(kb.key()) {
+                    // Segment finished
+                    break;
+                }
 
-            let (_, _, segment) = parse_term_directory_key(&k);
+                try!(self.db.delete_opt(&k, &write_options));
 
-            if segments_btree.contains(&segment) {
-                try!(self.db.delete(&k));
+                iter.next();
             }
-
-            iter.next();
         }
 
         // Purge the stored values
@@ -397,12 +957,105 @@ impl RocksDBStore {
             }
         }
 
+        // Purge the rank feature columns
+        for source_segment in segments.iter() {
+            let kb = KeyBuilder::segment_rank_features_prefix(*source_segment);
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
+
+                if !k.starts_with(kb.key()) {
+                    // Segment finished
+                    break;
+                }
+
+                try!(self.db.delete_opt(&k, &write_options));
+
+                iter.next();
+            }
+        }
+
+        // Purge the doc values columns
+        for source_segment in segments.iter() {
+            let kb = KeyBuilder::segment_doc_values_prefix(*source_segment);
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
+
+                if !k.starts_with(kb.key()) {
+                    // Segment finished
+                    break;
+                }
+
+                try!(self.db.delete_opt(&k, &write_options));
+
+                iter.next();
+            }
+        }
+
+        // Purge the field presence bitmaps
+        for source_segment in segments.iter() {
+            let kb = KeyBuilder::segment_field_presence_prefix(*source_segment);
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
+
+                if !k.starts_with(kb.key()) {
+                    // Segment finished
+                    break;
+                }
+
+                try!(self.db.delete_opt(&k, &write_options));
+
+                iter.next();
+            }
+        }
+
+        // Purge the completion indexes
+        for source_segment in segments.iter() {
+            let kb = KeyBuilder::segment_completions_prefix(*source_segment);
+            let mut iter = self.db.raw_iterator();
+            iter.seek(&kb.key());
+            while iter.valid() {
+                let k = iter.key().unwrap();
+
+                if !k.starts_with(kb.key()) {
+                    // Segment finished
+                    break;
+                }
+
+                try!(self.db.delete_opt(&k, &write_options));
+
+                iter.next();
+            }
+        }
+
         // Purge the deletion lists
         for source_segment in segments.iter() {
             let kb = KeyBuilder::segment_del_list(*source_segment);
             try!(self.db.delete_opt(&kb.key(), &write_options));
         }
 
+        // Purge the sort orders
+        for source_segment in segments.iter() {
+            let kb = KeyBuilder::segment_index_sort(*source_segment);
+            try!(self.db.delete_opt(&kb.key(), &write_options));
+        }
+
+        // Purge the encryption records
+        for source_segment in segments.iter() {
+            let kb = KeyBuilder::segment_encryption(*source_segment);
+            try!(self.db.delete_opt(&kb.key(), &write_options));
+        }
+
+        // Delete the segment files
+        for source_segment in segments.iter() {
+            self.remove_segment_file(*source_segment);
+        }
+
         Ok(())
     }
 }
@@ -1,6 +1,13 @@
+use std::collections::BTreeMap;
+
+use roaring::RoaringBitmap;
+use fnv::FnvHashSet;
 use kite::segment::Segment;
+use kite::error::SegmentError;
 
 use RocksDBStore;
+use key_builder::{KeyBuilder, Key, KeyIterator};
+use segment::RocksDBSegment;
 
 #[derive(Debug)]
 pub struct SegmentStatistics {
@@ -9,7 +16,7 @@ pub struct SegmentStatistics {
 }
 
 impl SegmentStatistics {
-    fn read<S: Segment>(segment: &S) -> Result<SegmentStatistics, String> {
+    fn read<S: Segment>(segment: &S) -> Result<SegmentStatistics, SegmentError> {
         let total_docs = try!(segment.load_statistic(b"total_docs")).unwrap_or(0);
         let deleted_docs = try!(segment.load_statistic(b"deleted_docs")).unwrap_or(0);
 
@@ -31,7 +38,7 @@ impl SegmentStatistics {
 }
 
 impl RocksDBStore {
-    pub fn get_segment_statistics(&self) -> Result<Vec<(u32, SegmentStatistics)>, String> {
+    pub fn get_segment_statistics(&self) -> Result<Vec<(u32, SegmentStatistics)>, SegmentError> {
         let mut segment_stats = Vec::new();
         let reader = self.reader();
 
@@ -43,3 +50,167 @@ impl RocksDBStore {
         Ok(segment_stats)
     }
 }
+
+/// An estimate of how much of a segment's data is garbage
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentGarbage {
+    pub segment: u32,
+
+    /// Inactive segments have been merged away (or were never finished) so all of their data
+    /// can be purged
+    pub active: bool,
+
+    pub total_docs: i64,
+    pub deleted_docs: i64,
+
+    /// The size of all the segment's keys and values, in bytes
+    pub total_bytes: u64,
+
+    /// The size of the stored fields of the segment's deleted documents
+    pub deleted_stored_bytes: u64,
+
+    /// The estimated size of all the data of the segment's deleted documents
+    ///
+    /// Term directories, postings and columns aren't split by document, so the deleted
+    /// documents' share of them is estimated from the proportion of documents that are deleted.
+    pub deleted_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactionAction {
+    /// Purge the data of these inactive segments with "purge_segments"
+    Purge(Vec<u32>),
+
+    /// Reindex into a new store with "reindex"
+    ///
+    /// Merges carry deleted documents over into the new segment (so they can be deleted while
+    /// the merge is running), reindexing is the only way to reclaim their space.
+    Reindex,
+}
+
+#[derive(Debug, Clone)]
+pub struct GarbageReportConfig {
+    /// Reindexing is recommended once at least this proportion of the active segments' data
+    /// belongs to deleted documents
+    pub reindex_deleted_ratio: f64,
+}
+
+impl Default for GarbageReportConfig {
+    fn default() -> GarbageReportConfig {
+        GarbageReportConfig {
+            reindex_deleted_ratio: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GarbageReport {
+    /// Every segment that has data in the store, by id
+    pub segments: Vec<SegmentGarbage>,
+
+    /// The size of the inactive segments' data
+    pub purgeable_bytes: u64,
+
+    /// The estimated size of the data of deleted documents in active segments
+    pub deleted_bytes: u64,
+
+    /// What to run to reclaim the space
+    pub actions: Vec<CompactionAction>,
+}
+
+impl RocksDBStore {
+    /// Estimates how much of each segment's data is garbage and recommends how to reclaim it
+    ///
+    /// This reads every key in the store, so may take a while on large indexes. Segments that
+    /// are being written by a merge that's still running aren't active yet, so they're reported
+    /// as purgeable. Don't run the purges it recommends while merging.
+    pub fn garbage_report(&self, config: &GarbageReportConfig) -> Result<GarbageReport, String> {
+        let reader = self.reader();
+        let active_segments = reader.generation.segments().iter().cloned().collect::<FnvHashSet<u32>>();
+
+        // Documents are deleted by adding them to their segment's deletion list, so the values
+        // of deleted documents can be told apart from live ones
+        let mut deletion_lists = BTreeMap::new();
+        for segment in reader.generation.segments() {
+            let deletion_list = try!(RocksDBSegment::new(&reader, *segment).load_deletion_list());
+            deletion_lists.insert(*segment, deletion_list.unwrap_or_else(RoaringBitmap::new));
+        }
+
+        let mut segments: BTreeMap<u32, SegmentGarbage> = BTreeMap::new();
+        {
+            let mut add_entry = |key: Key, size: usize| {
+                let segment = match key.segment() {
+                    Some(segment) => segment,
+                    None => return,
+                };
+
+                let garbage = segments.entry(segment).or_insert_with(|| SegmentGarbage {
+                    segment: segment,
+                    active: active_segments.contains(&segment),
+                    total_docs: 0,
+                    deleted_docs: 0,
+                    total_bytes: 0,
+                    deleted_stored_bytes: 0,
+                    deleted_bytes: 0,
+                });
+                garbage.total_bytes += size as u64;
+
+                if let Key::StoredFieldValue { doc_local_id, .. } = key {
+                    if deletion_lists.get(&segment).map(|deletion_list| deletion_list.contains(doc_local_id)).unwrap_or(false) {
+                        garbage.deleted_stored_bytes += size as u64;
+                    }
+                }
+            };
+
+            for (key, value) in KeyIterator::new(reader.snapshot.raw_iterator(), KeyBuilder::new()) {
+                let size = key.key_builder().key().len() + value.len();
+                add_entry(key, size);
+            }
+
+            // Segment files hold the data of segments that have been moved out of RocksDB,
+            // including any that have been merged away and not purged yet
+            for (_, file) in self.segment_files.read().unwrap().iter() {
+                for (k, v) in file.iter() {
+                    if let Some(key) = Key::parse(k) {
+                        add_entry(key, k.len() + v.len());
+                    }
+                }
+            }
+        }
+
+        for garbage in segments.values_mut() {
+            let stats = try!(SegmentStatistics::read(&RocksDBSegment::new(&reader, garbage.segment)));
+            garbage.total_docs = stats.total_docs();
+            garbage.deleted_docs = stats.deleted_docs();
+
+            if garbage.total_docs > 0 {
+                let index_bytes = garbage.total_bytes - garbage.deleted_stored_bytes;
+                garbage.deleted_bytes = garbage.deleted_stored_bytes + index_bytes * garbage.deleted_docs as u64 / garbage.total_docs as u64;
+            }
+        }
+
+        let (active, inactive): (Vec<_>, Vec<_>) = segments.into_iter().map(|(_, garbage)| garbage).partition(|garbage| garbage.active);
+        let purgeable_bytes = inactive.iter().map(|garbage| garbage.total_bytes).sum();
+        let active_bytes = active.iter().map(|garbage| garbage.total_bytes).sum::<u64>();
+        let deleted_bytes = active.iter().map(|garbage| garbage.deleted_bytes).sum::<u64>();
+
+        let mut actions = Vec::new();
+        if !inactive.is_empty() {
+            actions.push(CompactionAction::Purge(inactive.iter().map(|garbage| garbage.segment).collect()));
+        }
+        if deleted_bytes > 0 && deleted_bytes as f64 >= active_bytes as f64 * config.reindex_deleted_ratio {
+            actions.push(CompactionAction::Reindex);
+        }
+
+        let mut segments = active;
+        segments.extend(inactive);
+        segments.sort_by_key(|garbage| garbage.segment);
+
+        Ok(GarbageReport {
+            segments: segments,
+            purgeable_bytes: purgeable_bytes,
+            deleted_bytes: deleted_bytes,
+            actions: actions,
+        })
+    }
+}
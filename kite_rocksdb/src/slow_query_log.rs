@@ -0,0 +1,125 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use kite::query::Query;
+use kite::schema::FieldId;
+use kite::collectors::{Collector, DocumentMatch};
+use kite::collectors::top_field::SortOrder;
+
+use RocksDBStore;
+
+/// Details of a search that took longer than the slow query log's threshold
+#[derive(Debug, Clone)]
+pub struct SlowQuery {
+    /// The query, formatted with its Debug representation
+    pub query: String,
+
+    /// Time spent turning the query into a search plan
+    pub planning_time: Duration,
+
+    /// Time spent finding, scoring and collecting the matching documents
+    pub execution_time: Duration,
+
+    /// The number of documents that were passed to the collector
+    pub hits: u64,
+
+    /// True if the search was stopped early by its deadline
+    pub timed_out: bool,
+}
+
+impl SlowQuery {
+    pub fn total_time(&self) -> Duration {
+        self.planning_time + self.execution_time
+    }
+}
+
+/// Calls a function with the details of every search that takes longer than the threshold
+#[derive(Clone)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    callback: Arc<dyn Fn(&SlowQuery) + Send + Sync>,
+}
+
+impl SlowQueryLog {
+    /// Records the search if it took longer than the threshold
+    pub fn record(&self, query: &Query, planning_time: Duration, execution_time: Duration, hits: u64, timed_out: bool) {
+        if planning_time + execution_time < self.threshold {
+            return;
+        }
+
+        (self.callback)(&SlowQuery {
+            query: format!("{:?}", query),
+            planning_time: planning_time,
+            execution_time: execution_time,
+            hits: hits,
+            timed_out: timed_out,
+        });
+    }
+}
+
+impl fmt::Debug for SlowQueryLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SlowQueryLog {{ threshold: {:?} }}", self.threshold)
+    }
+}
+
+/// Wraps a collector, counting the documents passed into it
+pub struct HitCountingCollector<'a, C: Collector + 'a> {
+    collector: &'a mut C,
+    hits: u64,
+}
+
+impl<'a, C: Collector + 'a> HitCountingCollector<'a, C> {
+    pub fn new(collector: &'a mut C) -> HitCountingCollector<'a, C> {
+        HitCountingCollector {
+            collector: collector,
+            hits: 0,
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+impl<'a, C: Collector + 'a> Collector for HitCountingCollector<'a, C> {
+    fn needs_score(&self) -> bool {
+        self.collector.needs_score()
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        self.hits += 1;
+        self.collector.collect(doc);
+    }
+
+    fn min_competitive_score(&self) -> Option<f32> {
+        self.collector.min_competitive_score()
+    }
+
+    fn sort_field(&self) -> Option<FieldId> {
+        self.collector.sort_field()
+    }
+
+    fn sort_order(&self) -> Option<SortOrder> {
+        self.collector.sort_order()
+    }
+
+    fn is_competitive_sort_value(&self, sort_value: Option<i64>) -> bool {
+        self.collector.is_competitive_sort_value(sort_value)
+    }
+}
+
+impl RocksDBStore {
+    /// Calls "callback" with the details of every search that takes longer than "threshold"
+    ///
+    /// This is useful for finding pathological queries in production.
+    pub fn set_slow_query_log<F>(&mut self, threshold: Duration, callback: F)
+        where F: Fn(&SlowQuery) + Send + Sync + 'static
+    {
+        self.slow_query_log = Some(SlowQueryLog {
+            threshold: threshold,
+            callback: Arc::new(callback),
+        });
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use RocksDBStore;
+use replication::{ReplicationCheckpoint, ReplicationDelta};
+
+/// Somewhere to keep snapshots of indexes, such as a directory or a bucket in an object store
+///
+/// Each snapshot is saved as a single blob. Implementations for object stores (S3, GCS) can
+/// be provided by the application, this crate only includes one for the local filesystem.
+pub trait SnapshotRepository: Send + Sync {
+    /// Saves a blob, replacing any blob with the same name
+    fn put(&self, name: &str, data: &[u8]) -> Result<(), String>;
+
+    /// Returns a blob, or None if there isn't one with the name
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String>;
+
+    /// Returns the names of all the blobs, in any order
+    fn list(&self) -> Result<Vec<String>, String>;
+
+    /// Deletes a blob, this isn't an error if it doesn't exist
+    fn delete(&self, name: &str) -> Result<(), String>;
+}
+
+/// Keeps snapshots as files in a directory
+#[derive(Debug, Clone)]
+pub struct FsSnapshotRepository {
+    path: PathBuf,
+}
+
+impl FsSnapshotRepository {
+    /// Creates the directory if it doesn't exist
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<FsSnapshotRepository, String> {
+        try!(fs::create_dir_all(path.as_ref()).map_err(|e| format!("{}: {}", path.as_ref().display(), e)));
+
+        Ok(FsSnapshotRepository {
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    fn blob_path(&self, name: &str) -> Result<PathBuf, String> {
+        // Names starting with "." are used for partially written blobs
+        if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+            return Err(format!("invalid snapshot name {:?}", name));
+        }
+
+        Ok(self.path.join(name))
+    }
+}
+
+impl SnapshotRepository for FsSnapshotRepository {
+    fn put(&self, name: &str, data: &[u8]) -> Result<(), String> {
+        let path = try!(self.blob_path(name));
+
+        // Write to a temporary file first so a partially written blob is never read
+        let temp_path = self.path.join(format!(".{}.tmp", name));
+        try!(fs::write(&temp_path, data).map_err(|e| format!("{}: {}", temp_path.display(), e)));
+        fs::rename(&temp_path, &path).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        let path = try!(self.blob_path(name));
+
+        match fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        let mut names = Vec::new();
+        for entry in try!(fs::read_dir(&self.path).map_err(|e| format!("{}: {}", self.path.display(), e))) {
+            let entry = try!(entry.map_err(|e| e.to_string()));
+            if let Some(name) = entry.file_name().to_str() {
+                if !name.starts_with('.') {
+                    names.push(name.to_string());
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), String> {
+        let path = try!(self.blob_path(name));
+
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+}
+
+impl RocksDBStore {
+    /// Saves a snapshot of the committed state of the index into a repository
+    ///
+    /// The snapshot contains everything a follower would need to replicate the index from
+    /// scratch, including the segment files. The change log isn't included.
+    pub fn save_snapshot(&self, repository: &dyn SnapshotRepository, name: &str) -> Result<(), String> {
+        let delta = try!(self.replication_delta(&ReplicationCheckpoint::empty()));
+        repository.put(name, &delta.to_bytes())
+    }
+
+    /// Creates a new index at "path" from a snapshot in a repository
+    pub fn restore_snapshot<P: AsRef<Path>>(repository: &dyn SnapshotRepository, name: &str, path: P) -> Result<RocksDBStore, String> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(format!("{} already exists", path.display()));
+        }
+
+        let delta = match try!(repository.get(name)) {
+            Some(data) => try!(ReplicationDelta::from_bytes(&data)),
+            None => return Err(format!("snapshot {:?} not found", name)),
+        };
+
+        let mut store = try!(RocksDBStore::create(path));
+        try!(store.apply_replication_delta(&delta));
+        Ok(store)
+    }
+}
@@ -0,0 +1,70 @@
+use kite::{Document, StoredDocument};
+use kite::schema::{Schema, FieldType, FieldFlags, FieldId, AddFieldError};
+use kite::segment::SegmentId;
+use kite::collectors::Collector;
+use kite::query::Query;
+use kite::error::{StoreError, SearchError};
+use kite::store::{Store, StoreReader};
+
+use {RocksDBStore, RocksDBReader, DocumentInsertError};
+
+impl From<DocumentInsertError> for StoreError {
+    fn from(e: DocumentInsertError) -> StoreError {
+        match e {
+            DocumentInsertError::RocksDBError(e) => StoreError::storage(e),
+            e => StoreError::InvalidDocument(e.to_string()),
+        }
+    }
+}
+
+impl Store for RocksDBStore {
+    type Reader<'a> = RocksDBReader<'a>;
+
+    fn schema(&self) -> &Schema {
+        RocksDBStore::schema(self)
+    }
+
+    fn add_field(&mut self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError> {
+        RocksDBStore::add_field(self, name, field_type, field_flags)
+    }
+
+    fn remove_field(&mut self, field_id: &FieldId) -> Result<bool, StoreError> {
+        Ok(RocksDBStore::remove_field(self, field_id))
+    }
+
+    fn insert_or_update_document(&self, doc: &Document) -> Result<(), StoreError> {
+        Ok(try!(RocksDBStore::insert_or_update_document(self, doc)))
+    }
+
+    fn remove_document_by_key(&self, doc_key: &str) -> Result<bool, StoreError> {
+        RocksDBStore::remove_document_by_key(self, doc_key).map_err(StoreError::storage)
+    }
+
+    fn reader<'a>(&'a self) -> Result<RocksDBReader<'a>, StoreError> {
+        Ok(RocksDBStore::reader(self))
+    }
+}
+
+impl<'a> StoreReader for RocksDBReader<'a> {
+    fn schema(&self) -> &Schema {
+        RocksDBReader::schema(self)
+    }
+
+    fn segments(&self) -> Vec<SegmentId> {
+        self.generation.segments().iter().map(|segment| SegmentId(*segment)).collect()
+    }
+
+    fn contains_document_key(&self, doc_key: &str) -> Result<bool, StoreError> {
+        Ok(RocksDBReader::contains_document_key(self, doc_key))
+    }
+
+    fn get_document(&self, doc_key: &str) -> Result<Option<Document>, StoreError> {
+        RocksDBReader::get_document(self, doc_key)
+            .map(|doc| doc.map(StoredDocument::into_document))
+            .map_err(StoreError::storage)
+    }
+
+    fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), SearchError> {
+        RocksDBReader::search(self, collector, query)
+    }
+}
@@ -0,0 +1,207 @@
+use std::str;
+use std::ops::Deref;
+
+use rocksdb::DBVector;
+use kite::document::FieldValue;
+use kite::schema::FieldType;
+use byteorder::{ByteOrder, LittleEndian};
+use chrono::{NaiveDateTime, DateTime, Utc};
+
+use StoredFieldReadError;
+use segment_file::SegmentFileValue;
+
+/// A stored field value decoded without copying it
+///
+/// Strings borrow from the buffer they were read from, so a page of results can be
+/// serialised straight from RocksDB's (or the segment file's) memory.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredFieldRef<'a> {
+    String(&'a str),
+    Integer(i64),
+    Boolean(bool),
+    DateTime(DateTime<Utc>),
+}
+
+impl<'a> StoredFieldRef<'a> {
+    /// Copies the value into an owned FieldValue
+    pub fn to_field_value(&self) -> FieldValue {
+        match *self {
+            StoredFieldRef::String(value) => FieldValue::String(value.to_string()),
+            StoredFieldRef::Integer(value) => FieldValue::Integer(value),
+            StoredFieldRef::Boolean(value) => FieldValue::Boolean(value),
+            StoredFieldRef::DateTime(value) => FieldValue::DateTime(value),
+        }
+    }
+}
+
+/// Decodes the raw bytes of a stored field value, borrowing strings from "value"
+pub fn decode_stored_field_ref<'a>(field_type: &FieldType, value: &'a [u8]) -> Result<StoredFieldRef<'a>, StoredFieldReadError> {
+    match *field_type {
+        FieldType::Text | FieldType::PlainString | FieldType::Facet | FieldType::Join => {
+            match str::from_utf8(value) {
+                Ok(value_str) => Ok(StoredFieldRef::String(value_str)),
+                Err(e) => Err(StoredFieldReadError::TextFieldUTF8DecodeError(value.to_vec(), e)),
+            }
+        }
+        FieldType::I64 => {
+            if value.len() != 8 {
+                return Err(StoredFieldReadError::IntegerFieldValueSizeError(value.len()));
+            }
+
+            Ok(StoredFieldRef::Integer(LittleEndian::read_i64(value)))
+        }
+        FieldType::Boolean => {
+            if value[..] == [b't'] {
+                Ok(StoredFieldRef::Boolean(true))
+            } else if value[..] == [b'f'] {
+                Ok(StoredFieldRef::Boolean(false))
+            } else {
+                Err(StoredFieldReadError::BooleanFieldDecodeError(value.to_vec()))
+            }
+        }
+        FieldType::DateTime => {
+            if value.len() != 8 {
+                return Err(StoredFieldReadError::IntegerFieldValueSizeError(value.len()))
+            }
+
+            // Dates before 1970 are negative, the micros are always counted forwards from the
+            // second before so they must be rounded down rather than towards zero
+            let timestamp_with_micros = LittleEndian::read_i64(value);
+            let timestamp = timestamp_with_micros.div_euclid(1000000);
+            let micros = timestamp_with_micros.rem_euclid(1000000);
+            let nanos = micros * 1000;
+            match NaiveDateTime::from_timestamp_opt(timestamp, nanos as u32) {
+                Some(datetime) => Ok(StoredFieldRef::DateTime(DateTime::from_utc(datetime, Utc))),
+                None => Err(StoredFieldReadError::DateTimeFieldOutOfRange(timestamp_with_micros)),
+            }
+        }
+        FieldType::RankFeature => {
+            // Rank features are kept in a separate column
+            Err(StoredFieldReadError::FieldTypeNotStored(FieldType::RankFeature))
+        }
+        FieldType::Completion => {
+            // Completions are kept in the completion index
+            Err(StoredFieldReadError::FieldTypeNotStored(FieldType::Completion))
+        }
+    }
+}
+
+enum StoredFieldBuffer {
+    /// Allocated by RocksDB, freed when this is dropped
+    RocksDB(DBVector),
+
+    /// Points into a memory-mapped segment file
+    SegmentFile(SegmentFileValue),
+
+    /// The value had to be decrypted so it was copied
+    Decrypted(Vec<u8>),
+}
+
+/// The raw bytes of a stored field value, read without copying them into a Vec
+///
+/// Values of encrypted segments are the exception, they're decrypted into a Vec.
+pub struct StoredFieldBytes {
+    field_type: FieldType,
+    buffer: StoredFieldBuffer,
+}
+
+impl StoredFieldBytes {
+    pub(crate) fn from_rocksdb(field_type: FieldType, value: DBVector) -> StoredFieldBytes {
+        StoredFieldBytes {
+            field_type: field_type,
+            buffer: StoredFieldBuffer::RocksDB(value),
+        }
+    }
+
+    pub(crate) fn from_segment_file(field_type: FieldType, value: SegmentFileValue) -> StoredFieldBytes {
+        StoredFieldBytes {
+            field_type: field_type,
+            buffer: StoredFieldBuffer::SegmentFile(value),
+        }
+    }
+
+    pub(crate) fn from_decrypted(field_type: FieldType, value: Vec<u8>) -> StoredFieldBytes {
+        StoredFieldBytes {
+            field_type: field_type,
+            buffer: StoredFieldBuffer::Decrypted(value),
+        }
+    }
+
+    pub fn field_type(&self) -> &FieldType {
+        &self.field_type
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self.buffer {
+            StoredFieldBuffer::RocksDB(ref value) => value,
+            StoredFieldBuffer::SegmentFile(ref value) => value,
+            StoredFieldBuffer::Decrypted(ref value) => value,
+        }
+    }
+
+    /// Decodes the value, strings are borrowed from the buffer
+    pub fn decode(&self) -> Result<StoredFieldRef, StoredFieldReadError> {
+        decode_stored_field_ref(&self.field_type, self.as_bytes())
+    }
+}
+
+impl Deref for StoredFieldBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use kite::document::FieldValue;
+    use kite::schema::FieldType;
+
+    use StoredFieldReadError;
+    use super::{decode_stored_field_ref, StoredFieldRef};
+
+    #[test]
+    fn test_decode_borrows_strings() {
+        let value = b"hello world".to_vec();
+
+        match decode_stored_field_ref(&FieldType::Text, &value) {
+            Ok(StoredFieldRef::String(string)) => {
+                assert_eq!(string, "hello world");
+                assert_eq!(string.as_ptr(), value.as_ptr());
+            }
+            _ => panic!("expected a string"),
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode_stored_field_ref(&FieldType::I64, &[1, 0, 0, 0, 0, 0, 0, 0]).ok(), Some(StoredFieldRef::Integer(1)));
+        assert_eq!(decode_stored_field_ref(&FieldType::Boolean, b"t").ok(), Some(StoredFieldRef::Boolean(true)));
+
+        match decode_stored_field_ref(&FieldType::PlainString, b"foo").map(|value| value.to_field_value()) {
+            Ok(FieldValue::String(ref string)) if string == "foo" => {}
+            _ => panic!("expected a string"),
+        }
+
+        match decode_stored_field_ref(&FieldType::I64, &[1, 0]) {
+            Err(StoredFieldReadError::IntegerFieldValueSizeError(2)) => {}
+            _ => panic!("expected a size error"),
+        }
+    }
+
+    #[test]
+    fn test_decode_datetime() {
+        for datetime in &["2017-06-01T12:30:00.123456Z", "1969-12-31T23:59:59.5Z", "1900-01-01T00:00:00Z"] {
+            let datetime = DateTime::parse_from_rfc3339(datetime).unwrap().with_timezone(&Utc);
+            let bytes = FieldValue::DateTime(datetime).to_bytes();
+            assert_eq!(decode_stored_field_ref(&FieldType::DateTime, &bytes).ok(), Some(StoredFieldRef::DateTime(datetime)));
+        }
+
+        match decode_stored_field_ref(&FieldType::DateTime, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]) {
+            Err(StoredFieldReadError::DateTimeFieldOutOfRange(_)) => {}
+            _ => panic!("expected an out of range error"),
+        }
+    }
+}
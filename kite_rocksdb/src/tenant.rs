@@ -0,0 +1,116 @@
+//! Namespaces that keep the documents of different tenants apart within one store
+//!
+//! Applications that host many customers in a single store can give each of them a tenant.
+//! The tenant id is part of the keys that tie documents to the store:
+//!
+//!  - Documents are looked up by their tenant and key ("n{tenant}/{key}"), so each tenant has
+//!    its own key space
+//!  - Every segment written through a tenant only contains that tenant's documents and is
+//!    recorded as being owned by it ("b{tenant}/{segment}"). Term directories, postings, stored
+//!    fields and everything else in a segment is keyed by the segment id, so these are scoped
+//!    to the tenant too.
+//!
+//! Readers opened through a tenant only see the tenant's segments, so no search (or lookup of a
+//! document by key or id) can return documents of another tenant. Readers opened on the store
+//! itself ("RocksDBStore::reader") leave out the segments of every tenant, so they only see the
+//! documents that weren't written through one. Segments of different tenants can't be merged
+//! together.
+//!
+//! The term dictionary is shared by all tenants. It only maps terms to ids, so tenants can't
+//! find out which terms are used by others: suggestions and statistics are counted from the
+//! reader's segments.
+
+use rocksdb;
+use kite::Document;
+use fnv::FnvHashSet;
+
+use {RocksDBStore, RocksDBReader, ReaderScope, DocumentInsertError};
+use key_builder::{KeyBuilder, Key, KeyIterator};
+use document_index::DocumentKey;
+
+/// A handle for reading and writing the documents of one tenant, see "RocksDBStore::tenant"
+pub struct Tenant<'a> {
+    store: &'a RocksDBStore,
+    id: String,
+}
+
+impl<'a> Tenant<'a> {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Inserts a document into the tenant, replacing the tenant's document with the same key
+    ///
+    /// Each document is written into a segment of its own, group commit isn't used as its
+    /// segments may contain documents of many tenants.
+    pub fn insert_or_update_document(&self, doc: &Document) -> Result<(), DocumentInsertError> {
+        try!(self.store.insert_document_segment(doc, Some(self.id.as_bytes())));
+        self.store.notify_listeners(|listener| listener.tenant_document_indexed(&self.id, &doc.key));
+
+        Ok(())
+    }
+
+    pub fn remove_document_by_key(&self, doc_key: &str) -> Result<bool, rocksdb::Error> {
+        let key = DocumentKey::for_tenant(self.id.as_bytes().to_vec(), doc_key.as_bytes().to_vec());
+
+        match try!(self.store.document_index.delete_document_by_key(&self.store.db, &key, &self.store.change_log)) {
+            Some(_doc_id) => {
+                self.store.notify_listeners(|listener| listener.tenant_document_deleted(&self.id, doc_key));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Opens a reader that only sees the tenant's documents
+    pub fn reader(&self) -> RocksDBReader<'a> {
+        self.store.new_reader(ReaderScope::Tenant(self.id.as_bytes()))
+    }
+
+    /// The ids of the tenant's active segments
+    ///
+    /// The tenant's segments can only be merged with each other, use this to choose which
+    /// ones to merge.
+    pub fn segments(&self) -> Vec<u32> {
+        self.reader().generation.segments().to_vec()
+    }
+}
+
+impl RocksDBStore {
+    /// Returns a handle for the documents of a tenant
+    ///
+    /// Tenants don't need to be created, a tenant exists once a document has been written
+    /// through it.
+    pub fn tenant<'a>(&'a self, id: &str) -> Tenant<'a> {
+        Tenant {
+            store: self,
+            id: id.to_string(),
+        }
+    }
+
+    /// The ids of the tenants that own at least one segment, in byte order
+    pub fn tenants(&self) -> Vec<String> {
+        let mut tenants = Vec::new();
+        for (key, _) in KeyIterator::new(self.db.raw_iterator(), KeyBuilder::all_tenants_segments_prefix()) {
+            if let Key::TenantSegment { tenant, .. } = key {
+                if tenants.last().map(|last: &Vec<u8>| *last != tenant).unwrap_or(true) {
+                    tenants.push(tenant);
+                }
+            }
+        }
+
+        tenants.into_iter().filter_map(|tenant| String::from_utf8(tenant).ok()).collect()
+    }
+
+    /// Finds the tenant that owns each of the segments, segments that aren't owned by a tenant
+    /// are left out
+    pub(crate) fn segment_tenants(&self, segments: &[u32]) -> Vec<(u32, Vec<u8>)> {
+        let segments = segments.iter().cloned().collect::<FnvHashSet<u32>>();
+        KeyIterator::new(self.db.raw_iterator(), KeyBuilder::all_tenants_segments_prefix())
+            .filter_map(|(key, _)| match key {
+                Key::TenantSegment { tenant, segment } if segments.contains(&segment) => Some((segment, tenant)),
+                _ => None,
+            })
+            .collect()
+    }
+}
@@ -1,13 +1,115 @@
 use std::str;
-use std::sync::{Mutex, RwLock};
+use std::iter;
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::collections::HashMap;
+use std::collections::{HashMap, BinaryHeap};
+use std::collections::hash_map;
 
 use rocksdb::{self, DB};
+use fnv::{FnvHashMap, FnvHashSet};
 use kite::{Term, TermId};
 use kite::query::multi_term_selector::MultiTermSelector;
 
-use key_builder::KeyBuilder;
+use key_builder::{KeyBuilder, Key, KeyIterator};
+use search::suggest::edit_distance;
+
+/// The number of new terms the mutable tip of the term dictionary holds before it's frozen
+const TIP_CAPACITY: usize = 1024;
+
+/// An immutable generation of the term dictionary
+///
+/// Layers form a chain from the newest to the oldest. Every term is in exactly one layer, so
+/// a lookup falls back through the chain until it finds the term.
+struct TermDictionaryLayer {
+    terms: HashMap<Term, TermId>,
+    parent: Option<Arc<TermDictionaryLayer>>,
+}
+
+impl TermDictionaryLayer {
+    /// Adds a layer of terms on top of "parent"
+    ///
+    /// Layers that aren't more than twice as big as the new one are compacted into it, so the
+    /// chain stays logarithmic in the number of terms and each term is only copied a
+    /// logarithmic number of times.
+    fn push(parent: Option<Arc<TermDictionaryLayer>>, mut terms: HashMap<Term, TermId>) -> Arc<TermDictionaryLayer> {
+        let mut parent = parent;
+        while let Some(layer) = parent.clone() {
+            if layer.terms.len() > terms.len() * 2 {
+                break;
+            }
+
+            terms.extend(layer.terms.iter().map(|(term, term_id)| (term.clone(), *term_id)));
+            parent = layer.parent.clone();
+        }
+
+        Arc::new(TermDictionaryLayer {
+            terms: terms,
+            parent: parent,
+        })
+    }
+
+    fn get(&self, term: &Term) -> Option<TermId> {
+        let mut layer = Some(self);
+        while let Some(current) = layer {
+            if let Some(term_id) = current.terms.get(term) {
+                return Some(*term_id);
+            }
+
+            layer = current.parent.as_ref().map(|parent| &**parent);
+        }
+
+        None
+    }
+
+    #[cfg(test)]
+    fn depth(&self) -> usize {
+        1 + self.parent.as_ref().map(|parent| parent.depth()).unwrap_or(0)
+    }
+
+    fn iter(&self) -> TermDictionaryLayerIter {
+        TermDictionaryLayerIter {
+            terms: self.terms.iter(),
+            parent: self.parent.as_ref().map(|parent| &**parent),
+        }
+    }
+}
+
+/// Iterates the terms of a layer and all of its parents
+struct TermDictionaryLayerIter<'a> {
+    terms: hash_map::Iter<'a, Term, TermId>,
+    parent: Option<&'a TermDictionaryLayer>,
+}
+
+impl<'a> Iterator for TermDictionaryLayerIter<'a> {
+    type Item = (&'a Term, &'a TermId);
+
+    fn next(&mut self) -> Option<(&'a Term, &'a TermId)> {
+        loop {
+            if let Some(entry) = self.terms.next() {
+                return Some(entry);
+            }
+
+            let parent = match self.parent {
+                Some(parent) => parent,
+                None => return None,
+            };
+            self.terms = parent.terms.iter();
+            self.parent = parent.parent.as_ref().map(|parent| &**parent);
+        }
+    }
+}
+
+/// A consistent view of every term in the dictionary
+struct TermDictionarySnapshot {
+    tip: HashMap<Term, TermId>,
+    layers: Arc<TermDictionaryLayer>,
+}
+
+impl TermDictionarySnapshot {
+    fn iter(&self) -> iter::Chain<hash_map::Iter<Term, TermId>, TermDictionaryLayerIter> {
+        self.tip.iter().chain(self.layers.iter())
+    }
+}
 
 /// Manages the index's "term dictionary"
 ///
@@ -16,9 +118,14 @@ use key_builder::KeyBuilder;
 ///
 /// The term dictionary is a mapping between terms and their internal IDs
 /// (aka. TermId). It is entirely held in memory and persisted to the disk.
+///
+/// In memory, the dictionary is a chain of immutable layers with a small mutable tip on top.
+/// New terms are only ever added to the tip, so allocating a term never rehashes (or locks)
+/// the whole dictionary. Once the tip is full it's frozen into a new layer.
 pub struct TermDictionaryManager {
     next_term_id: AtomicUsize,
-    terms: RwLock<HashMap<Term, TermId>>,
+    tip: RwLock<HashMap<Term, TermId>>,
+    layers: RwLock<Arc<TermDictionaryLayer>>,
     write_lock: Mutex<i32>,
 }
 
@@ -31,7 +138,8 @@ impl TermDictionaryManager {
 
         Ok(TermDictionaryManager {
             next_term_id: AtomicUsize::new(1),
-            terms: RwLock::new(HashMap::new()),
+            tip: RwLock::new(HashMap::new()),
+            layers: RwLock::new(TermDictionaryLayer::push(None, HashMap::new())),
             write_lock: Mutex::new(0),
         })
     }
@@ -47,40 +155,166 @@ impl TermDictionaryManager {
 
         // Read dictionary
         let mut terms = HashMap::new();
-        let mut iter = db.raw_iterator();
-        iter.seek(b"t");
-        while iter.valid() {
-            let k = iter.key().unwrap();
-
-            if k[0] != b't' {
-                break;
+        for (key, v) in KeyIterator::new(db.raw_iterator(), KeyBuilder::term_dict_prefix()) {
+            if let Key::TermDictMapping(term) = key {
+                let term_id = TermId(str::from_utf8(&v).unwrap().parse::<u32>().unwrap());
+                terms.insert(Term::from_bytes(&term), term_id);
             }
-
-            let term_id = TermId(str::from_utf8(unsafe { &iter.value_inner().unwrap() }).unwrap().parse::<u32>().unwrap());
-            terms.insert(Term::from_bytes(&k[1..]), term_id);
-
-            iter.next();
         }
 
         Ok(TermDictionaryManager {
             next_term_id: AtomicUsize::new(next_term_id as usize),
-            terms: RwLock::new(terms),
+            tip: RwLock::new(HashMap::new()),
+            layers: RwLock::new(TermDictionaryLayer::push(None, terms)),
             write_lock: Mutex::new(0),
         })
     }
 
+    /// Takes a view of all the terms, only the tip is copied
+    fn snapshot(&self) -> TermDictionarySnapshot {
+        // The tip is locked while the layers are read so a tip that's being frozen can't be
+        // seen twice
+        let tip = self.tip.read().unwrap();
+        let layers = self.layers.read().unwrap().clone();
+
+        TermDictionarySnapshot {
+            tip: tip.clone(),
+            layers: layers,
+        }
+    }
+
     /// Retrieves the TermId for the given term
     pub fn get(&self, term: &Term) -> Option<TermId> {
-        self.terms.read().unwrap().get(term).cloned()
+        // The tip must be read before the layers, terms move from the tip into the layers
+        // when it's frozen
+        if let Some(term_id) = self.tip.read().unwrap().get(term) {
+            return Some(*term_id);
+        }
+
+        let layers = self.layers.read().unwrap().clone();
+        layers.get(term)
+    }
+
+    /// Calls "f" with each term in the dictionary which matches the selector
+    ///
+    /// The scan stops as soon as "f" returns false. Selectors that can't be compiled (such as
+    /// invalid regexes) don't match any terms.
+    pub fn for_each_match<F: FnMut(&Term, TermId) -> bool>(&self, term_selector: &MultiTermSelector, mut f: F) {
+        let matcher = match term_selector.compile() {
+            Ok(matcher) => matcher,
+            Err(_) => return,
+        };
+
+        for (term, term_id) in self.snapshot().iter() {
+            if matcher.matches(term) && !f(term, *term_id) {
+                return;
+            }
+        }
+    }
+
+    /// Returns the terms in the dictionary which match the selector
+    ///
+    /// The scan stops (returning the terms found so far) once "is_cancelled" returns true. This
+    /// is checked before each matching term, so a selector that matches a huge number of terms
+    /// doesn't hold up a cancelled search.
+    pub fn select_until<C: Fn() -> bool>(&self, term_selector: &MultiTermSelector, is_cancelled: C) -> Vec<TermId> {
+        let mut term_ids = Vec::new();
+        self.for_each_match(term_selector, |_term, term_id| {
+            if is_cancelled() {
+                return false;
+            }
+
+            term_ids.push(term_id);
+            true
+        });
+
+        term_ids
+    }
+
+    /// Returns the first "limit" terms (in term order) which match the selector
+    ///
+    /// Only "limit" terms are held while the dictionary is scanned, so this doesn't use more
+    /// memory when the selector matches a huge number of terms. Like "select_until", the scan
+    /// stops (returning the lowest terms found so far) once "is_cancelled" returns true.
+    pub fn select_first_until<C: Fn() -> bool>(&self, term_selector: &MultiTermSelector, limit: usize, is_cancelled: C) -> Vec<TermId> {
+        if limit == 0 {
+            return Vec::new();
+        }
+
+        // A max-heap of the lowest terms seen so far, the highest is dropped when it's full
+        let mut first_terms = BinaryHeap::with_capacity(limit + 1);
+        self.for_each_match(term_selector, |term, term_id| {
+            if is_cancelled() {
+                return false;
+            }
+
+            if first_terms.len() == limit {
+                if first_terms.peek().map(|&(ref highest, _): &(Term, u32)| term >= highest).unwrap_or(false) {
+                    return true;
+                }
+                first_terms.pop();
+            }
+
+            first_terms.push((term.clone(), term_id.0));
+            true
+        });
+
+        first_terms.into_sorted_vec().into_iter().map(|(_term, term_id)| TermId(term_id)).collect()
     }
 
-    /// Iterates over terms in the dictionary which match the selector
-    pub fn select(&self, term_selector: &MultiTermSelector) -> Vec<TermId> {
-        self.terms.read().unwrap().iter()
+    /// Returns the terms in the dictionary which match the selector along with their TermIds
+    pub fn select_terms(&self, term_selector: &MultiTermSelector) -> Vec<(Term, TermId)> {
+        let matcher = match term_selector.compile() {
+            Ok(matcher) => matcher,
+            Err(_) => return Vec::new(),
+        };
+
+        self.snapshot().iter()
             .filter(|&(term, _term_id)| {
-                term_selector.matches(term)
+                matcher.matches(term)
+            })
+            .map(|(term, term_id)| (term.clone(), *term_id))
+            .collect()
+    }
+
+    /// Returns the terms with the given TermIds
+    ///
+    /// The dictionary is only indexed by term so this has to scan the whole dictionary.
+    pub fn get_terms(&self, term_ids: &FnvHashSet<TermId>) -> FnvHashMap<TermId, Term> {
+        self.snapshot().iter()
+            .filter(|&(_term, term_id)| term_ids.contains(term_id))
+            .map(|(term, term_id)| (*term_id, term.clone()))
+            .collect()
+    }
+
+    /// Finds terms in the dictionary that are within "max_edits" edits of the given term
+    ///
+    /// Returns the terms with their TermIds and edit distances. Terms that aren't valid
+    /// UTF-8 are skipped.
+    pub fn find_similar(&self, term: &str, max_edits: u32) -> Vec<(String, TermId, u32)> {
+        let term_length = term.chars().count();
+
+        self.snapshot().iter()
+            .filter_map(|(other_term, term_id)| {
+                let other_term = match str::from_utf8(other_term.as_bytes()) {
+                    Ok(other_term) => other_term,
+                    Err(_) => return None,
+                };
+
+                // The length difference is a lower bound of the distance, this skips most
+                // terms before computing it
+                let other_term_length = other_term.chars().count();
+                if (term_length as i64 - other_term_length as i64).abs() > max_edits as i64 {
+                    return None;
+                }
+
+                let distance = edit_distance(term, other_term);
+                if distance > max_edits {
+                    return None;
+                }
+
+                Some((other_term.to_string(), *term_id, distance))
             })
-            .map(|(_term, term_id)| *term_id)
             .collect()
     }
 
@@ -109,8 +343,8 @@ impl TermDictionaryManager {
         // It's possible that another thread has written the term to the dictionary
         // since we checked earlier. If this is the case, We should forget about
         // writing our TermId and use the one that has been inserted already.
-        if let Some(term_id) = self.terms.read().unwrap().get(term) {
-            return Ok(*term_id);
+        if let Some(term_id) = self.get(term) {
+            return Ok(term_id);
         }
 
         // Write it to the on-disk term dictionary
@@ -118,8 +352,111 @@ impl TermDictionaryManager {
         try!(db.put(kb.key(), next_term_id.to_string().as_bytes()));
 
         // Write it to the term dictionary
-        self.terms.write().unwrap().insert(term.clone(), term_id);;
+        let mut tip = self.tip.write().unwrap();
+        tip.insert(term.clone(), term_id);
+
+        // Freeze the tip once it's full
+        // The new layers are published before the tip is cleared, so readers that miss the
+        // term in the tip will find it in the layers
+        if tip.len() >= TIP_CAPACITY {
+            let mut layers = self.layers.write().unwrap();
+            *layers = TermDictionaryLayer::push(Some(layers.clone()), tip.clone());
+            drop(layers);
+            tip.clear();
+        }
 
         Ok(term_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::fs::remove_dir_all;
+
+    use rocksdb::DB;
+    use kite::{Term, TermId, MultiTermSelector};
+
+    use super::{TermDictionaryLayer, TermDictionaryManager, TIP_CAPACITY};
+
+    fn terms(range: ::std::ops::Range<u32>) -> HashMap<Term, TermId> {
+        range.map(|i| (Term::from_string(&i.to_string()), TermId(i))).collect()
+    }
+
+    #[test]
+    fn test_layers() {
+        // Layers of the same size are compacted together
+        let layers = TermDictionaryLayer::push(None, terms(0..10));
+        let layers = TermDictionaryLayer::push(Some(layers), terms(10..20));
+        assert_eq!(layers.depth(), 1);
+        assert_eq!(layers.terms.len(), 20);
+
+        // Smaller layers are chained on top
+        let layers = TermDictionaryLayer::push(Some(layers), terms(20..25));
+        let layers = TermDictionaryLayer::push(Some(layers), terms(25..27));
+        assert_eq!(layers.depth(), 3);
+
+        // Lookups fall back through the chain
+        for i in 0..27 {
+            assert_eq!(layers.get(&Term::from_string(&i.to_string())), Some(TermId(i)));
+        }
+        assert_eq!(layers.get(&Term::from_string("27")), None);
+
+        let mut term_ids = layers.iter().map(|(_, term_id)| term_id.0).collect::<Vec<_>>();
+        term_ids.sort();
+        assert_eq!(term_ids, (0..27).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_tip_is_frozen() {
+        let _ = remove_dir_all("test_indices/test_term_dictionary_tip");
+        let db = DB::open_default("test_indices/test_term_dictionary_tip").unwrap();
+        let term_dictionary = TermDictionaryManager::new(&db).unwrap();
+
+        let num_terms = TIP_CAPACITY * 3 + 10;
+        for i in 0..num_terms {
+            term_dictionary.get_or_create(&db, &Term::from_string(&format!("term{}", i))).unwrap();
+        }
+
+        assert_eq!(term_dictionary.tip.read().unwrap().len(), 10);
+        assert!(term_dictionary.layers.read().unwrap().depth() <= 3);
+        assert_eq!(term_dictionary.get(&Term::from_string("term0")), Some(TermId(1)));
+        assert_eq!(term_dictionary.get(&Term::from_string(&format!("term{}", num_terms - 1))), Some(TermId(num_terms as u32)));
+        assert_eq!(term_dictionary.get_or_create(&db, &Term::from_string("term5")).unwrap(), TermId(6));
+        assert_eq!(term_dictionary.snapshot().iter().count(), num_terms);
+
+        // Reopening loads every term into a single layer
+        let term_dictionary = TermDictionaryManager::open(&db).unwrap();
+        assert_eq!(term_dictionary.layers.read().unwrap().depth(), 1);
+        assert_eq!(term_dictionary.get(&Term::from_string("term5")), Some(TermId(6)));
+    }
+
+    #[test]
+    fn test_select_until_cancelled() {
+        let _ = remove_dir_all("test_indices/test_term_dictionary_select_until");
+        let db = DB::open_default("test_indices/test_term_dictionary_select_until").unwrap();
+        let term_dictionary = TermDictionaryManager::new(&db).unwrap();
+
+        for i in 0..100 {
+            term_dictionary.get_or_create(&db, &Term::from_string(&format!("term{}", i))).unwrap();
+        }
+
+        let selector = MultiTermSelector::Prefix("term".to_string());
+        assert_eq!(term_dictionary.select_until(&selector, || false).len(), 100);
+        assert_eq!(term_dictionary.select_first_until(&selector, 10, || false).len(), 10);
+
+        // Cancellation is checked before each matching term, not after the scan
+        let checks = Cell::new(0);
+        let is_cancelled = || {
+            checks.set(checks.get() + 1);
+            checks.get() > 5
+        };
+        assert_eq!(term_dictionary.select_until(&selector, &is_cancelled).len(), 5);
+        assert_eq!(checks.get(), 6);
+
+        checks.set(0);
+        assert_eq!(term_dictionary.select_first_until(&selector, 10, &is_cancelled).len(), 5);
+        assert_eq!(checks.get(), 6);
+    }
+}
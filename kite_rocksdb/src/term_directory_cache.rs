@@ -0,0 +1,151 @@
+use std::sync::{Arc, Mutex};
+
+use kite::schema::FieldId;
+use kite::term::TermId;
+use roaring::RoaringBitmap;
+
+use lru_cache::LruCache;
+
+/// The most bytes of term directories that are cached, unless the store is configured otherwise
+pub const DEFAULT_TERM_DIRECTORY_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CacheKey {
+    segment: u32,
+    field: FieldId,
+    term: TermId,
+}
+
+/// A size-bounded cache of decoded term directories, shared by all of a store's readers
+///
+/// Segments never change once they're written so a term directory can be cached until its
+/// segment is purged. Deletion lists aren't cached as they change as documents are deleted.
+///
+/// The size of a term directory is taken to be the size of its roaring serialization, which is
+/// close to how much memory it uses once decoded. When the cache is full, the least recently
+/// used term directories are evicted first.
+pub struct TermDirectoryCache {
+    state: Mutex<LruCache<CacheKey, Arc<RoaringBitmap>>>,
+}
+
+impl TermDirectoryCache {
+    pub fn new(capacity: usize) -> TermDirectoryCache {
+        TermDirectoryCache {
+            state: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Returns the most bytes of term directories that can be cached
+    pub fn capacity(&self) -> usize {
+        self.state.lock().unwrap().capacity()
+    }
+
+    /// Changes the capacity, evicting term directories if it's now too large
+    pub fn set_capacity(&self, capacity: usize) {
+        self.state.lock().unwrap().set_capacity(capacity);
+    }
+
+    pub fn get(&self, segment: u32, field: FieldId, term: TermId) -> Option<Arc<RoaringBitmap>> {
+        let key = CacheKey { segment: segment, field: field, term: term };
+        self.state.lock().unwrap().get(&key)
+    }
+
+    /// Caches a term directory
+    ///
+    /// Term directories that are larger than the whole cache are not cached.
+    pub fn insert(&self, segment: u32, field: FieldId, term: TermId, term_directory: Arc<RoaringBitmap>) {
+        let key = CacheKey { segment: segment, field: field, term: term };
+        let size = term_directory.serialized_size();
+        self.state.lock().unwrap().insert(key, term_directory, size);
+    }
+
+    /// Removes the term directories of segments that have been purged
+    pub fn remove_segments(&self, segments: &[u32]) {
+        self.state.lock().unwrap().retain(|key| !segments.contains(&key.segment));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use kite::schema::FieldId;
+    use kite::term::TermId;
+    use roaring::RoaringBitmap;
+
+    use super::TermDirectoryCache;
+
+    fn term_directory(doc_ids: &[u32]) -> Arc<RoaringBitmap> {
+        Arc::new(doc_ids.iter().cloned().collect())
+    }
+
+    fn size(cache: &TermDirectoryCache) -> usize {
+        cache.state.lock().unwrap().size()
+    }
+
+    #[test]
+    fn test_get() {
+        let cache = TermDirectoryCache::new(1024);
+        cache.insert(1, FieldId(1), TermId(1), term_directory(&[1, 2, 3]));
+
+        assert_eq!(cache.get(1, FieldId(1), TermId(1)), Some(term_directory(&[1, 2, 3])));
+        assert_eq!(cache.get(2, FieldId(1), TermId(1)), None);
+        assert_eq!(cache.get(1, FieldId(2), TermId(1)), None);
+        assert_eq!(cache.get(1, FieldId(1), TermId(2)), None);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let entry_size = term_directory(&[1, 2, 3]).serialized_size();
+        let cache = TermDirectoryCache::new(entry_size * 2);
+        cache.insert(1, FieldId(1), TermId(1), term_directory(&[1, 2, 3]));
+        cache.insert(1, FieldId(1), TermId(2), term_directory(&[4, 5, 6]));
+
+        // Using term 1 makes term 2 the least recently used
+        assert!(cache.get(1, FieldId(1), TermId(1)).is_some());
+        cache.insert(1, FieldId(1), TermId(3), term_directory(&[7, 8, 9]));
+
+        assert!(cache.get(1, FieldId(1), TermId(1)).is_some());
+        assert!(cache.get(1, FieldId(1), TermId(2)).is_none());
+        assert!(cache.get(1, FieldId(1), TermId(3)).is_some());
+        assert_eq!(size(&cache), entry_size * 2);
+
+        // Shrinking the cache evicts term 1 now
+        cache.set_capacity(entry_size);
+        assert!(cache.get(1, FieldId(1), TermId(1)).is_none());
+        assert!(cache.get(1, FieldId(1), TermId(3)).is_some());
+        assert_eq!(size(&cache), entry_size);
+    }
+
+    #[test]
+    fn test_too_large() {
+        let cache = TermDirectoryCache::new(8);
+        cache.insert(1, FieldId(1), TermId(1), term_directory(&[1, 2, 3]));
+
+        assert!(cache.get(1, FieldId(1), TermId(1)).is_none());
+        assert_eq!(size(&cache), 0);
+    }
+
+    #[test]
+    fn test_insert_twice() {
+        let cache = TermDirectoryCache::new(1024);
+        cache.insert(1, FieldId(1), TermId(1), term_directory(&[1, 2, 3]));
+        cache.insert(1, FieldId(1), TermId(1), term_directory(&[1, 2, 3]));
+
+        assert_eq!(size(&cache), term_directory(&[1, 2, 3]).serialized_size());
+    }
+
+    #[test]
+    fn test_remove_segments() {
+        let cache = TermDirectoryCache::new(1024);
+        cache.insert(1, FieldId(1), TermId(1), term_directory(&[1]));
+        cache.insert(2, FieldId(1), TermId(1), term_directory(&[2]));
+        cache.insert(3, FieldId(1), TermId(1), term_directory(&[3]));
+        cache.remove_segments(&[1, 3]);
+
+        assert!(cache.get(1, FieldId(1), TermId(1)).is_none());
+        assert!(cache.get(2, FieldId(1), TermId(1)).is_some());
+        assert!(cache.get(3, FieldId(1), TermId(1)).is_none());
+        assert_eq!(size(&cache), term_directory(&[2]).serialized_size());
+    }
+}
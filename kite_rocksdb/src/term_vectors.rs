@@ -0,0 +1,167 @@
+use kite::{Term, DocId};
+use kite::term_vector::TermVector;
+use kite::schema::{FieldId, FIELD_TERM_VECTORS};
+use kite::segment::Segment;
+use byteorder::{ByteOrder, LittleEndian};
+use roaring::RoaringBitmap;
+
+use RocksDBReader;
+use segment::RocksDBSegment;
+
+/// Encodes the positions of each term in a field of a document
+///
+/// Terms are written in order, each one as:
+///
+/// [term length: u32][term][position count: u32][positions: u32 * count]
+pub fn encode_term_vector(term_vector: &TermVector) -> Vec<u8> {
+    let mut terms = term_vector.iter().collect::<Vec<_>>();
+    terms.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut bytes = Vec::new();
+    let mut buf = [0; 4];
+    for (term, positions) in terms {
+        LittleEndian::write_u32(&mut buf, term.as_bytes().len() as u32);
+        bytes.extend(&buf);
+        bytes.extend(term.as_bytes());
+
+        LittleEndian::write_u32(&mut buf, positions.len() as u32);
+        bytes.extend(&buf);
+        for position in positions.iter() {
+            LittleEndian::write_u32(&mut buf, position);
+            bytes.extend(&buf);
+        }
+    }
+
+    bytes
+}
+
+fn read_u32(bytes: &[u8], position: &mut usize) -> Result<u32, String> {
+    if bytes.len() < *position + 4 {
+        return Err("term vector is truncated".to_string());
+    }
+
+    let value = LittleEndian::read_u32(&bytes[*position..]);
+    *position += 4;
+    Ok(value)
+}
+
+pub fn decode_term_vector(bytes: &[u8]) -> Result<TermVector, String> {
+    let mut term_vector = TermVector::new();
+    let mut position = 0;
+    while position < bytes.len() {
+        let term_length = try!(read_u32(bytes, &mut position)) as usize;
+        if bytes.len() < position + term_length {
+            return Err("term vector is truncated".to_string());
+        }
+        let term = Term::from_bytes(&bytes[position..position + term_length]);
+        position += term_length;
+
+        let position_count = try!(read_u32(bytes, &mut position));
+        let mut positions = RoaringBitmap::new();
+        for _ in 0..position_count {
+            positions.insert(try!(read_u32(bytes, &mut position)));
+        }
+
+        term_vector.insert(term, positions);
+    }
+
+    Ok(term_vector)
+}
+
+/// Encodes the start and end offsets of the token at each position in a field of a document
+///
+/// Positions are written in order, each one as:
+///
+/// [position: u32][start offset: u32][end offset: u32]
+pub fn encode_term_offsets(term_vector: &TermVector) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(term_vector.offsets.len() * 12);
+    let mut buf = [0; 4];
+    for (position, &(start, end)) in term_vector.offsets.iter() {
+        for value in &[*position, start, end] {
+            LittleEndian::write_u32(&mut buf, *value);
+            bytes.extend(&buf);
+        }
+    }
+
+    bytes
+}
+
+/// Decodes the offsets of a term vector into it
+pub fn decode_term_offsets(bytes: &[u8], term_vector: &mut TermVector) -> Result<(), String> {
+    let mut position = 0;
+    while position < bytes.len() {
+        let token_position = try!(read_u32(bytes, &mut position));
+        let start = try!(read_u32(bytes, &mut position));
+        let end = try!(read_u32(bytes, &mut position));
+        term_vector.offsets.insert(token_position, (start, end));
+    }
+
+    Ok(())
+}
+
+impl<'a> RocksDBReader<'a> {
+    /// Returns the terms indexed in a field of a document, with the positions of each one
+    ///
+    /// The frequency of a term is the number of positions it has. The offsets of each position
+    /// are included if the analyzer recorded them. Only fields with the FIELD_TERM_VECTORS flag
+    /// record their term vectors, these are recorded for documents inserted after the flag was set.
+    pub fn term_vector(&self, field_id: FieldId, doc_id: DocId) -> Result<Option<TermVector>, String> {
+        match self.schema().get(&field_id) {
+            Some(field_info) if field_info.field_flags.contains(FIELD_TERM_VECTORS) => {}
+            Some(_) => return Err(format!("field {:?} doesn't record term vectors", field_id)),
+            None => return Err(format!("field {:?} doesn't exist", field_id)),
+        }
+
+        if !self.can_read(doc_id) {
+            return Ok(None);
+        }
+
+        let segment = RocksDBSegment::new(self, (doc_id.0).0);
+        let mut term_vector = match try!(segment.load_stored_field_value_raw(doc_id.1, field_id, b"tv")) {
+            Some(bytes) => try!(decode_term_vector(&bytes)),
+            None => return Ok(None),
+        };
+
+        if let Some(bytes) = try!(segment.load_stored_field_value_raw(doc_id.1, field_id, b"tvo")) {
+            try!(decode_term_offsets(&bytes, &mut term_vector));
+        }
+
+        Ok(Some(term_vector))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kite::{Term, Token};
+    use kite::term_vector::TermVector;
+
+    use super::{encode_term_vector, decode_term_vector, encode_term_offsets, decode_term_offsets};
+
+    #[test]
+    fn test_encode() {
+        let term_vector: TermVector = vec![
+            Token { term: Term::from_string("hello"), position: 1, offsets: None },
+            Token { term: Term::from_string("world"), position: 2, offsets: None },
+            Token { term: Term::from_string("hello"), position: 3, offsets: None },
+        ].into();
+
+        assert_eq!(decode_term_vector(&encode_term_vector(&term_vector)), Ok(term_vector));
+        assert_eq!(decode_term_vector(&[]), Ok(TermVector::new()));
+        assert!(decode_term_vector(&[5, 0, 0, 0, b'a']).is_err());
+    }
+
+    #[test]
+    fn test_encode_offsets() {
+        let term_vector: TermVector = vec![
+            Token { term: Term::from_string("hello"), position: 1, offsets: Some((0, 5)) },
+            Token { term: Term::from_string("world"), position: 2, offsets: Some((6, 11)) },
+            Token { term: Term::from_string("again"), position: 3, offsets: None },
+        ].into();
+
+        let mut decoded = decode_term_vector(&encode_term_vector(&term_vector)).unwrap();
+        decode_term_offsets(&encode_term_offsets(&term_vector), &mut decoded).unwrap();
+        assert_eq!(decoded, term_vector);
+        assert_eq!(decoded.offsets.len(), 2);
+        assert!(decode_term_offsets(&[1, 0, 0, 0], &mut decoded).is_err());
+    }
+}
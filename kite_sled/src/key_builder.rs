@@ -0,0 +1,172 @@
+//! The keys of the sled tree that an index is stored in
+//!
+//! Integers are big endian so the keys of a field or document sort together and can be
+//! scanned by prefix. Document keys are strings so they never contain 0xFF, which is used to
+//! separate them from the document number.
+
+use byteorder::{ByteOrder, BigEndian};
+
+use kite::schema::FieldId;
+
+pub const SCHEMA: &'static [u8] = b".schema";
+
+/// The number of the next document to be inserted
+pub const NEXT_DOC: &'static [u8] = b".next_doc";
+
+/// Set once the terms of integer and date fields are in the sortable encoding
+pub const SORTABLE_INTEGER_TERMS: &'static [u8] = b".sortable_integer_terms";
+
+fn push_u32(key: &mut Vec<u8>, value: u32) {
+    let mut bytes = [0; 4];
+    BigEndian::write_u32(&mut bytes, value);
+    key.extend_from_slice(&bytes);
+}
+
+fn field_key(prefix: u8, field_id: FieldId) -> Vec<u8> {
+    let mut key = vec![prefix];
+    push_u32(&mut key, field_id.0);
+    key
+}
+
+fn doc_key(prefix: u8, doc: u32) -> Vec<u8> {
+    let mut key = vec![prefix];
+    push_u32(&mut key, doc);
+    key
+}
+
+/// Prefix of the primary key entries of a document key, one for each time it was inserted
+pub fn primary_key_prefix(doc_key: &str) -> Vec<u8> {
+    let mut key = vec![b'k'];
+    key.extend_from_slice(doc_key.as_bytes());
+    key.push(0xFF);
+    key
+}
+
+pub fn primary_key(doc_key: &str, doc: u32) -> Vec<u8> {
+    let mut key = primary_key_prefix(doc_key);
+    push_u32(&mut key, doc);
+    key
+}
+
+/// Reads the document number from the end of a primary key, live document or term
+/// directory entry
+pub fn parse_doc_suffix(key: &[u8]) -> u32 {
+    BigEndian::read_u32(&key[key.len() - 4..])
+}
+
+/// Marks a document as live, the entry is removed when it's deleted or replaced
+pub fn live_doc(doc: u32) -> Vec<u8> {
+    doc_key(b'd', doc)
+}
+
+pub fn live_docs_prefix() -> Vec<u8> {
+    vec![b'd']
+}
+
+/// Prefix of the entries of the documents that contain a term, one for each document
+///
+/// The term is prefixed with its length so the entries of a term aren't mixed with those of
+/// longer terms that start with it.
+pub fn term_directory(field_id: FieldId, term: &[u8]) -> Vec<u8> {
+    let mut key = field_key(b't', field_id);
+    push_u32(&mut key, term.len() as u32);
+    key.extend_from_slice(term);
+    key
+}
+
+pub fn term_directory_entry(field_id: FieldId, term: &[u8], doc: u32) -> Vec<u8> {
+    let mut key = term_directory(field_id, term);
+    push_u32(&mut key, doc);
+    key
+}
+
+pub fn term_directory_prefix() -> Vec<u8> {
+    vec![b't']
+}
+
+/// Prefix of the term directory entries of every term of a field
+pub fn field_term_directories_prefix(field_id: FieldId) -> Vec<u8> {
+    field_key(b't', field_id)
+}
+
+/// Prefix of the term frequencies of every document of a field
+pub fn field_term_frequencies_prefix(field_id: FieldId) -> Vec<u8> {
+    field_key(b'f', field_id)
+}
+
+pub fn term_frequency(field_id: FieldId, doc: u32, term: &[u8]) -> Vec<u8> {
+    let mut key = term_frequency_prefix(field_id, doc);
+    key.extend_from_slice(term);
+    key
+}
+
+pub fn term_frequency_prefix(field_id: FieldId, doc: u32) -> Vec<u8> {
+    let mut key = field_key(b'f', field_id);
+    push_u32(&mut key, doc);
+    key
+}
+
+/// The number of tokens in a field of a document
+pub fn field_length(field_id: FieldId, doc: u32) -> Vec<u8> {
+    let mut key = field_key(b'l', field_id);
+    push_u32(&mut key, doc);
+    key
+}
+
+/// The number of documents that have been indexed with the field
+pub fn field_total_docs(field_id: FieldId) -> Vec<u8> {
+    field_key(b'n', field_id)
+}
+
+/// The number of tokens that have been indexed in the field
+pub fn field_total_tokens(field_id: FieldId) -> Vec<u8> {
+    field_key(b'o', field_id)
+}
+
+pub fn stored_field_value(doc: u32, field_id: FieldId) -> Vec<u8> {
+    let mut key = stored_values_prefix(doc);
+    push_u32(&mut key, field_id.0);
+    key
+}
+
+pub fn stored_values_prefix(doc: u32) -> Vec<u8> {
+    doc_key(b'v', doc)
+}
+
+pub fn stored_source(doc: u32) -> Vec<u8> {
+    doc_key(b's', doc)
+}
+
+pub fn stored_routing(doc: u32) -> Vec<u8> {
+    doc_key(b'r', doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use kite::schema::FieldId;
+
+    use super::{primary_key, primary_key_prefix, parse_doc_suffix, term_directory, term_directory_entry};
+
+    #[test]
+    fn test_primary_key() {
+        let key = primary_key("foo", 300);
+        assert!(key.starts_with(&primary_key_prefix("foo")));
+        assert!(!key.starts_with(&primary_key_prefix("fo")));
+        assert_eq!(parse_doc_suffix(&key), 300);
+    }
+
+    #[test]
+    fn test_term_directory_entry() {
+        let key = term_directory_entry(FieldId(1), b"foo", 300);
+        assert!(key.starts_with(&term_directory(FieldId(1), b"foo")));
+        assert!(!key.starts_with(&term_directory(FieldId(1), b"fo")));
+        assert!(!term_directory_entry(FieldId(1), b"food", 300).starts_with(&term_directory(FieldId(1), b"foo")));
+        assert_eq!(parse_doc_suffix(&key), 300);
+    }
+
+    #[test]
+    fn test_term_directory_sorts_by_field() {
+        assert!(term_directory(FieldId(1), b"zzz") < term_directory(FieldId(2), b"aaa"));
+        assert!(term_directory(FieldId(255), b"zzz") < term_directory(FieldId(256), b"aaa"));
+    }
+}
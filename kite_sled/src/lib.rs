@@ -0,0 +1,830 @@
+//! Sled storage for the Kite search engine
+//!
+//! This is an alternative to kite_rocksdb for applications that can't build RocksDB (such as
+//! on musl or Windows), sled is written in pure Rust. It's simpler than kite_rocksdb: the
+//! index is kept in a single sled tree rather than in segments, and only some queries are
+//! supported (see "search"). Both implement the "Store" traits in kite, so applications that
+//! are written against those can use either.
+//!
+//! Documents are numbered in the order they're inserted. A document is never changed once it's
+//! written, updating it inserts a new document and deletes the old one. Each live document and
+//! each document in a term directory has its own entry in the tree, so a write only touches
+//! the entries of the document and a few per-field counters. Readers get a consistent view of
+//! the index by loading the live documents and counters when they're opened (see
+//! "SledReader"). The data of deleted documents stays in the tree until
+//! "purge_deleted_documents" is called.
+
+extern crate kite;
+extern crate sled;
+extern crate serde_json;
+extern crate roaring;
+extern crate byteorder;
+extern crate chrono;
+extern crate fnv;
+
+mod key_builder;
+mod search;
+mod store;
+
+use std::str;
+use std::fmt;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+
+use kite::{Document, DocId, Term};
+use kite::document::FieldValue;
+use kite::schema::{Schema, FieldType, FieldFlags, FieldId, AddFieldError};
+use kite::segment::SegmentId;
+use kite::field::{Field, FieldKind};
+use kite::error::{SegmentError, SearchError};
+use roaring::RoaringBitmap;
+use byteorder::{ByteOrder, LittleEndian, BigEndian};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use fnv::FnvHashMap;
+
+#[derive(Debug)]
+pub enum StoreOpenError {
+    /// A sled error occurred while opening the database
+    SledError(sled::Error),
+
+    /// The database doesn't have a schema, so it isn't an index
+    SchemaMissing,
+
+    /// The schema couldn't be encoded or decoded
+    SchemaError(serde_json::Error),
+}
+
+impl From<sled::Error> for StoreOpenError {
+    fn from(e: sled::Error) -> StoreOpenError {
+        StoreOpenError::SledError(e)
+    }
+}
+
+impl fmt::Display for StoreOpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreOpenError::SledError(ref e) => write!(f, "sled error: {}", e),
+            StoreOpenError::SchemaMissing => write!(f, "unable to find schema in store"),
+            StoreOpenError::SchemaError(ref e) => write!(f, "schema error: {}", e),
+        }
+    }
+}
+
+impl Error for StoreOpenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            StoreOpenError::SledError(ref e) => Some(e),
+            StoreOpenError::SchemaError(ref e) => Some(e),
+            StoreOpenError::SchemaMissing => None,
+        }
+    }
+}
+
+impl From<StoreOpenError> for String {
+    fn from(e: StoreOpenError) -> String {
+        e.to_string()
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// A sled error occurred
+    SledError(sled::Error),
+
+    /// A value was read but couldn't be decoded
+    Corrupt(String),
+}
+
+impl From<sled::Error> for ReadError {
+    fn from(e: sled::Error) -> ReadError {
+        ReadError::SledError(e)
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadError::SledError(ref e) => write!(f, "sled error: {}", e),
+            ReadError::Corrupt(ref message) => write!(f, "index is corrupt: {}", message),
+        }
+    }
+}
+
+impl Error for ReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ReadError::SledError(ref e) => Some(e),
+            ReadError::Corrupt(_) => None,
+        }
+    }
+}
+
+impl From<ReadError> for String {
+    fn from(e: ReadError) -> String {
+        e.to_string()
+    }
+}
+
+impl From<ReadError> for SearchError {
+    fn from(e: ReadError) -> SearchError {
+        match e {
+            ReadError::Corrupt(message) => SearchError::Storage(SegmentError::Corrupt(message)),
+            e => SearchError::Storage(SegmentError::storage(e)),
+        }
+    }
+}
+
+fn encode_i64(value: i64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    LittleEndian::write_i64(&mut bytes, value);
+    bytes
+}
+
+fn decode_i64(bytes: &[u8]) -> Result<i64, ReadError> {
+    if bytes.len() != 8 {
+        return Err(ReadError::Corrupt(format!("expected an 8 byte integer, found {} bytes", bytes.len())));
+    }
+
+    Ok(LittleEndian::read_i64(bytes))
+}
+
+/// Decodes a stored field value that was encoded with "FieldValue::to_bytes"
+fn decode_stored_field(field_type: &FieldType, value: &[u8]) -> Result<FieldValue, ReadError> {
+    match *field_type {
+        FieldType::Text | FieldType::PlainString | FieldType::Facet | FieldType::Join => {
+            match str::from_utf8(value) {
+                Ok(value) => Ok(FieldValue::String(value.to_string())),
+                Err(e) => Err(ReadError::Corrupt(format!("stored string isn't UTF-8: {}", e))),
+            }
+        }
+        FieldType::I64 => Ok(FieldValue::Integer(try!(decode_i64(value)))),
+        FieldType::Boolean => {
+            match value {
+                b"t" => Ok(FieldValue::Boolean(true)),
+                b"f" => Ok(FieldValue::Boolean(false)),
+                _ => Err(ReadError::Corrupt(format!("invalid stored boolean: {:?}", value))),
+            }
+        }
+        FieldType::DateTime => {
+            let timestamp_with_micros = try!(decode_i64(value));
+            let timestamp = timestamp_with_micros.div_euclid(1000000);
+            let nanos = timestamp_with_micros.rem_euclid(1000000) * 1000;
+            match NaiveDateTime::from_timestamp_opt(timestamp, nanos as u32) {
+                Some(datetime) => Ok(FieldValue::DateTime(DateTime::from_utc(datetime, Utc))),
+                None => Err(ReadError::Corrupt(format!("stored date is out of range: {}", timestamp_with_micros))),
+            }
+        }
+        FieldType::RankFeature | FieldType::Completion => {
+            Err(ReadError::Corrupt(format!("{:?} fields can't be stored", field_type)))
+        }
+    }
+}
+
+/// Converts the terms of integer and date fields that were written by older versions of kite
+/// to the sortable encoding
+///
+/// These terms used to be little endian, which doesn't sort in value order. Term directory
+/// and term frequency keys are scoped to their field and every term of an integer or date
+/// field is 8 bytes, so the terms of these fields are all converted.
+fn migrate_integer_terms(db: &sled::Db, schema: &Schema) -> Result<(), sled::Error> {
+    if try!(db.get(key_builder::SORTABLE_INTEGER_TERMS)).is_some() {
+        return Ok(());
+    }
+
+    let mut batch = sled::Batch::default();
+    let mut new_entries = Vec::new();
+    for (field_id, field_info) in schema.iter() {
+        match field_info.field_type {
+            FieldType::I64 | FieldType::DateTime => {}
+            _ => continue,
+        }
+
+        // [t][field][term length][term][doc]
+        for entry in db.scan_prefix(key_builder::field_term_directories_prefix(*field_id)) {
+            let (key, value) = try!(entry);
+            if let Some(term) = Term::from_little_endian_integer(&key[9..key.len() - 4]) {
+                new_entries.push((key_builder::term_directory_entry(*field_id, term.as_bytes(), key_builder::parse_doc_suffix(&key)), value));
+                batch.remove(key);
+            }
+        }
+
+        // [f][field][doc][term]
+        for entry in db.scan_prefix(key_builder::field_term_frequencies_prefix(*field_id)) {
+            let (key, value) = try!(entry);
+            if let Some(term) = Term::from_little_endian_integer(&key[9..]) {
+                new_entries.push((key_builder::term_frequency(*field_id, BigEndian::read_u32(&key[5..9]), term.as_bytes()), value));
+                batch.remove(key);
+            }
+        }
+    }
+
+    // The new entries are inserted after all the old ones are removed, a converted term can
+    // have the old encoding of another term
+    for (key, value) in new_entries {
+        batch.insert(key, value);
+    }
+
+    batch.insert(key_builder::SORTABLE_INTEGER_TERMS, &[]);
+    try!(db.apply_batch(batch));
+    try!(db.flush());
+
+    Ok(())
+}
+
+/// The id of a document, every document is in segment 0 with its number as its ord
+#[inline]
+fn doc_id(doc: u32) -> DocId {
+    DocId(SegmentId(0), doc)
+}
+
+/// An index stored in sled
+///
+/// Like RocksDBStore, this can be shared between threads. Writes are serialized by a lock,
+/// readers don't block them.
+pub struct SledStore {
+    db: sled::Db,
+    schema: Schema,
+    write_lock: Mutex<()>,
+}
+
+impl SledStore {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<SledStore, StoreOpenError> {
+        let db = try!(sled::open(path));
+        let schema = Schema::new();
+        let schema_json = try!(serde_json::to_string(&schema).map_err(StoreOpenError::SchemaError));
+
+        let mut batch = sled::Batch::default();
+        batch.insert(key_builder::SCHEMA, schema_json.as_bytes());
+        batch.insert(key_builder::NEXT_DOC, &encode_i64(0));
+        batch.insert(key_builder::SORTABLE_INTEGER_TERMS, &[]);
+        try!(db.apply_batch(batch));
+        try!(db.flush());
+
+        Ok(SledStore {
+            db: db,
+            schema: schema,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledStore, StoreOpenError> {
+        if !path.as_ref().exists() {
+            return Err(StoreOpenError::SchemaMissing);
+        }
+
+        let db = try!(sled::open(path));
+        let schema = match try!(db.get(key_builder::SCHEMA)) {
+            Some(schema) => try!(serde_json::from_slice(&schema).map_err(StoreOpenError::SchemaError)),
+            None => return Err(StoreOpenError::SchemaMissing),
+        };
+
+        // Convert the integer terms of indexes written by older versions of kite
+        try!(migrate_integer_terms(&db, &schema));
+
+        Ok(SledStore {
+            db: db,
+            schema: schema,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn write_schema(&self, schema: &Schema) -> Result<(), sled::Error> {
+        // The schema only contains strings and integers so this can't fail
+        let schema_json = serde_json::to_string(schema).unwrap();
+        try!(self.db.insert(key_builder::SCHEMA, schema_json.as_bytes()));
+        Ok(())
+    }
+
+    /// Adds a field to the schema
+    ///
+    /// The schema is only changed if it was written to the tree.
+    pub fn add_field(&mut self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError> {
+        let mut schema = self.schema.clone();
+        let field_id = try!(schema.add_field(name, field_type, field_flags));
+        try!(self.write_schema(&schema).map_err(AddFieldError::storage));
+        self.schema = schema;
+
+        Ok(field_id)
+    }
+
+    /// Adds a field, returning a handle that only accepts values of the field's type
+    pub fn add_typed_field<T: FieldKind>(&mut self, name: String, field_flags: FieldFlags) -> Result<Field<T>, AddFieldError> {
+        self.add_field(name, T::field_type(), field_flags).map(Field::new)
+    }
+
+    /// Removes a field from the schema, returns false if there wasn't a field with the id
+    ///
+    /// The schema is only changed if it was written to the tree.
+    pub fn remove_field(&mut self, field_id: &FieldId) -> Result<bool, ReadError> {
+        let mut schema = self.schema.clone();
+        if !schema.remove_field(field_id) {
+            return Ok(false);
+        }
+
+        try!(self.write_schema(&schema));
+        self.schema = schema;
+
+        Ok(true)
+    }
+
+    fn live_docs(&self) -> Result<RoaringBitmap, ReadError> {
+        let mut live_docs = RoaringBitmap::new();
+        for entry in self.db.scan_prefix(key_builder::live_docs_prefix()) {
+            live_docs.insert(key_builder::parse_doc_suffix(&try!(entry).0));
+        }
+
+        Ok(live_docs)
+    }
+
+    fn read_i64(&self, key: &[u8]) -> Result<i64, ReadError> {
+        match try!(self.db.get(key)) {
+            Some(value) => decode_i64(&value),
+            None => Ok(0),
+        }
+    }
+
+    /// Finds the document with the key that "is_live" returns true for
+    fn find_document<F: Fn(u32) -> Result<bool, ReadError>>(&self, doc_key: &str, is_live: F) -> Result<Option<u32>, ReadError> {
+        for entry in self.db.scan_prefix(key_builder::primary_key_prefix(doc_key)) {
+            let (key, _) = try!(entry);
+            let doc = key_builder::parse_doc_suffix(&key);
+
+            if try!(is_live(doc)) {
+                return Ok(Some(doc));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Finds the document with the key that's currently live
+    fn find_live_document(&self, doc_key: &str) -> Result<Option<u32>, ReadError> {
+        self.find_document(doc_key, |doc| Ok(try!(self.db.contains_key(key_builder::live_doc(doc)))))
+    }
+
+    pub fn insert_or_update_document(&self, doc: &Document) -> Result<(), ReadError> {
+        let _lock = self.write_lock.lock().unwrap();
+        let mut batch = sled::Batch::default();
+
+        let doc_number = try!(self.read_i64(key_builder::NEXT_DOC)) as u32;
+        batch.insert(key_builder::NEXT_DOC, &encode_i64(doc_number as i64 + 1));
+
+        // Replace the current version of the document
+        if let Some(previous_doc) = try!(self.find_live_document(&doc.key)) {
+            batch.remove(key_builder::live_doc(previous_doc));
+        }
+        batch.insert(key_builder::live_doc(doc_number), &[]);
+        batch.insert(key_builder::primary_key(&doc.key, doc_number), &[]);
+
+        for (field_id, term_vector) in doc.indexed_fields.iter() {
+            let mut field_length = 0;
+
+            for (term, positions) in term_vector.iter() {
+                batch.insert(key_builder::term_directory_entry(*field_id, term.as_bytes(), doc_number), &[]);
+
+                let term_frequency = positions.len() as i64;
+                batch.insert(key_builder::term_frequency(*field_id, doc_number, term.as_bytes()), &encode_i64(term_frequency));
+                field_length += term_frequency;
+            }
+
+            batch.insert(key_builder::field_length(*field_id, doc_number), &encode_i64(field_length));
+
+            let total_docs_key = key_builder::field_total_docs(*field_id);
+            let total_docs = try!(self.read_i64(&total_docs_key));
+            batch.insert(total_docs_key, &encode_i64(total_docs + 1));
+
+            let total_tokens_key = key_builder::field_total_tokens(*field_id);
+            let total_tokens = try!(self.read_i64(&total_tokens_key));
+            batch.insert(total_tokens_key, &encode_i64(total_tokens + field_length));
+        }
+
+        for (field_id, value) in doc.stored_fields.iter() {
+            batch.insert(key_builder::stored_field_value(doc_number, *field_id), value.to_bytes());
+        }
+
+        if let Some(ref source) = doc.source {
+            batch.insert(key_builder::stored_source(doc_number), &source[..]);
+        }
+
+        if let Some(ref routing) = doc.routing {
+            batch.insert(key_builder::stored_routing(doc_number), routing.as_bytes());
+        }
+
+        try!(self.db.apply_batch(batch));
+        Ok(())
+    }
+
+    pub fn remove_document_by_key(&self, doc_key: &str) -> Result<bool, ReadError> {
+        let _lock = self.write_lock.lock().unwrap();
+
+        match try!(self.find_live_document(doc_key)) {
+            Some(doc) => {
+                try!(self.db.remove(key_builder::live_doc(doc)));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Removes the data of documents that have been deleted or replaced
+    ///
+    /// This scans the whole index. It takes "&mut self" as open readers may still be reading
+    /// the documents.
+    pub fn purge_deleted_documents(&mut self) -> Result<(), ReadError> {
+        let _lock = self.write_lock.lock().unwrap();
+        let live_docs = try!(self.live_docs());
+        let mut batch = sled::Batch::default();
+
+        for entry in self.db.scan_prefix(key_builder::term_directory_prefix()) {
+            let (key, _) = try!(entry);
+            if !live_docs.contains(key_builder::parse_doc_suffix(&key)) {
+                batch.remove(key);
+            }
+        }
+
+        let next_doc = try!(self.read_i64(key_builder::NEXT_DOC)) as u32;
+        for doc in (0..next_doc).filter(|doc| !live_docs.contains(*doc)) {
+            // Fields that have since been removed from the schema are left behind
+            for field_id in self.schema.keys() {
+                batch.remove(key_builder::field_length(*field_id, doc));
+
+                for entry in self.db.scan_prefix(key_builder::term_frequency_prefix(*field_id, doc)) {
+                    batch.remove(try!(entry).0);
+                }
+            }
+
+            for entry in self.db.scan_prefix(key_builder::stored_values_prefix(doc)) {
+                batch.remove(try!(entry).0);
+            }
+
+            batch.remove(key_builder::stored_source(doc));
+            batch.remove(key_builder::stored_routing(doc));
+        }
+
+        for entry in self.db.scan_prefix(b"k") {
+            let (key, _) = try!(entry);
+            if !live_docs.contains(key_builder::parse_doc_suffix(&key)) {
+                batch.remove(key);
+            }
+        }
+
+        try!(self.db.apply_batch(batch));
+        Ok(())
+    }
+
+    /// Opens a reader that sees the index as it was when it was opened
+    pub fn reader<'a>(&'a self) -> Result<SledReader<'a>, ReadError> {
+        // Taking the lock makes sure everything is read between writes
+        let _lock = self.write_lock.lock().unwrap();
+        let live_docs = try!(self.live_docs());
+
+        let mut field_totals = FnvHashMap::default();
+        for field_id in self.schema.keys() {
+            let total_docs = try!(self.read_i64(&key_builder::field_total_docs(*field_id)));
+            let total_tokens = try!(self.read_i64(&key_builder::field_total_tokens(*field_id)));
+            field_totals.insert(*field_id, (total_docs, total_tokens));
+        }
+
+        Ok(SledReader {
+            store: self,
+            live_docs: live_docs,
+            field_totals: field_totals,
+        })
+    }
+}
+
+impl fmt::Debug for SledStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SledStore")
+    }
+}
+
+/// A view of a SledStore as it was when the reader was opened
+///
+/// Sled doesn't have snapshots, so the live documents and the number of documents and tokens
+/// indexed in each field are loaded when the reader is opened. Everything else the reader
+/// reads either belongs to a single document, and a document is never changed once it's
+/// written, or is filtered by the live documents. The data of deleted documents can only be
+/// removed by "purge_deleted_documents", which can't be called while a reader borrows the
+/// store.
+pub struct SledReader<'a> {
+    store: &'a SledStore,
+    live_docs: RoaringBitmap,
+
+    /// The number of documents and tokens indexed in each field, used for scoring
+    field_totals: FnvHashMap<FieldId, (i64, i64)>,
+}
+
+impl<'a> SledReader<'a> {
+    pub fn schema(&self) -> &Schema {
+        &self.store.schema
+    }
+
+    /// The segments that contain live documents
+    ///
+    /// Documents aren't split into segments, so this is a single segment unless the index is
+    /// empty. It's only used to give documents ids that are compatible with kite_rocksdb.
+    pub fn segments(&self) -> Vec<SegmentId> {
+        if self.live_docs.is_empty() {
+            Vec::new()
+        } else {
+            vec![SegmentId(0)]
+        }
+    }
+
+    /// Finds the document with the key that was live when the reader was opened
+    fn find_document(&self, doc_key: &str) -> Result<Option<u32>, ReadError> {
+        self.store.find_document(doc_key, |doc| Ok(self.live_docs.contains(doc)))
+    }
+
+    pub fn contains_document_key(&self, doc_key: &str) -> Result<bool, ReadError> {
+        Ok(try!(self.find_document(doc_key)).is_some())
+    }
+
+    /// Finds the id of a document from its key
+    pub fn get_document_id(&self, doc_key: &str) -> Result<Option<DocId>, ReadError> {
+        Ok(try!(self.find_document(doc_key)).map(doc_id))
+    }
+
+    fn read_stored_field_raw(&self, doc: u32, field_id: FieldId) -> Result<Option<FieldValue>, ReadError> {
+        let field_type = match self.store.schema.get(&field_id) {
+            Some(field_info) => &field_info.field_type,
+            None => return Ok(None),
+        };
+
+        match try!(self.store.db.get(key_builder::stored_field_value(doc, field_id))) {
+            Some(value) => Ok(Some(try!(decode_stored_field(field_type, &value)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the value of a stored field of a document
+    pub fn read_stored_field(&self, field_id: FieldId, doc_id: DocId) -> Result<Option<FieldValue>, ReadError> {
+        let doc = ((doc_id.0).0 << 16) | doc_id.1 as u32;
+        if !self.live_docs.contains(doc) {
+            return Ok(None);
+        }
+
+        self.read_stored_field_raw(doc, field_id)
+    }
+
+    /// Fetches a document by its key
+    ///
+    /// The document has the values of all the fields that are stored and its source and
+    /// routing key (if it was indexed with them), other fields are left empty.
+    pub fn get_document(&self, doc_key: &str) -> Result<Option<Document>, ReadError> {
+        let doc = match try!(self.find_document(doc_key)) {
+            Some(doc) => doc,
+            None => return Ok(None),
+        };
+
+        let mut stored_fields = FnvHashMap::default();
+        for entry in self.store.db.scan_prefix(key_builder::stored_values_prefix(doc)) {
+            let (key, value) = try!(entry);
+            let field_id = FieldId(BigEndian::read_u32(&key[key.len() - 4..]));
+
+            // Values of fields that have been removed from the schema can't be decoded
+            if let Some(field_info) = self.store.schema.get(&field_id) {
+                stored_fields.insert(field_id, try!(decode_stored_field(&field_info.field_type, &value)));
+            }
+        }
+
+        let source = try!(self.store.db.get(key_builder::stored_source(doc))).map(|source| source.to_vec());
+        let routing = match try!(self.store.db.get(key_builder::stored_routing(doc))) {
+            Some(routing) => match str::from_utf8(&routing) {
+                Ok(routing) => Some(routing.to_string()),
+                Err(e) => return Err(ReadError::Corrupt(format!("routing key isn't UTF-8: {}", e))),
+            },
+            None => None,
+        };
+
+        Ok(Some(Document {
+            key: doc_key.to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: stored_fields,
+            rank_features: FnvHashMap::default(),
+            completions: FnvHashMap::default(),
+            source: source,
+            routing: routing,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    use sled;
+    use byteorder::{ByteOrder, LittleEndian};
+    use kite::{Term, DocumentBuilder};
+    use kite::document::FieldValue;
+    use kite::schema::{FieldId, FieldType, FIELD_INDEXED, FIELD_STORED};
+    use kite::query::Query;
+    use kite::collectors::top_score::TopScoreCollector;
+    use kite::collectors::total_count::TotalCountCollector;
+
+    use super::{SledStore, SledReader, StoreOpenError};
+    use super::key_builder;
+
+    fn remove_dir_all_ignore_error<P: AsRef<Path>>(path: P) {
+        match remove_dir_all(&path) {
+            Ok(_) => {}
+            Err(_) => {}  // Don't care if this fails
+        }
+    }
+
+    fn make_test_store(path: &str) -> (SledStore, FieldId, FieldId) {
+        remove_dir_all_ignore_error(path);
+
+        let mut store = SledStore::create(path).unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED | FIELD_STORED).unwrap();
+        let pk_field = store.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+
+        for (pk, title) in vec!["hello world", "hello", "goodbye world"].into_iter().enumerate() {
+            let doc = DocumentBuilder::new(store.schema(), &pk.to_string())
+                .text(title_field, title)
+                .integer(pk_field, pk as i64)
+                .build().unwrap();
+            store.insert_or_update_document(&doc).unwrap();
+        }
+
+        (store, title_field, pk_field)
+    }
+
+    fn count(reader: &SledReader, query: &Query) -> u64 {
+        let mut collector = TotalCountCollector::new();
+        reader.search(&mut collector, query).unwrap();
+        collector.get_total_count()
+    }
+
+    #[test]
+    fn test_open() {
+        remove_dir_all_ignore_error("test_indices/test_open");
+
+        match SledStore::open("test_indices/test_open") {
+            Err(StoreOpenError::SchemaMissing) => {}
+            _ => panic!("opened a store which doesn't exist"),
+        }
+
+        let (store, title_field, _) = make_test_store("test_indices/test_open");
+        drop(store);
+
+        let store = SledStore::open("test_indices/test_open").unwrap();
+        assert_eq!(store.schema().get_field_by_name("title"), Some(title_field));
+        assert_eq!(count(&store.reader().unwrap(), &Query::all()), 3);
+    }
+
+    #[test]
+    fn test_get_document() {
+        let (store, title_field, pk_field) = make_test_store("test_indices/test_get_document");
+        let reader = store.reader().unwrap();
+
+        let doc = reader.get_document("1").unwrap().unwrap();
+        match doc.stored_fields.get(&title_field) {
+            Some(&FieldValue::String(ref title)) if title == "hello" => {}
+            _ => panic!("expected the document's stored title"),
+        }
+        match doc.stored_fields.get(&pk_field) {
+            Some(&FieldValue::Integer(1)) => {}
+            _ => panic!("expected the document's stored pk"),
+        }
+
+        assert!(reader.get_document("3").unwrap().is_none());
+        assert_eq!(reader.get_document_id("2").unwrap().map(|doc_id| doc_id.as_u64()), Some(2));
+    }
+
+    #[test]
+    fn test_update_and_delete() {
+        let (store, title_field, _) = make_test_store("test_indices/test_update_and_delete");
+        let hello = Query::term(title_field, Term::from_string("hello"));
+        let before = store.reader().unwrap();
+
+        let doc = DocumentBuilder::new(store.schema(), "0").text(title_field, "goodbye").build().unwrap();
+        store.insert_or_update_document(&doc).unwrap();
+        assert!(store.remove_document_by_key("1").unwrap());
+        assert!(!store.remove_document_by_key("1").unwrap());
+
+        // Readers don't see changes made after they were opened
+        assert_eq!(count(&before, &hello), 2);
+        assert!(before.contains_document_key("1").unwrap());
+
+        let after = store.reader().unwrap();
+        assert_eq!(count(&after, &hello), 0);
+        assert_eq!(count(&after, &Query::all()), 2);
+        assert!(!after.contains_document_key("1").unwrap());
+        assert_eq!(after.get_document_id("0").unwrap().map(|doc_id| doc_id.as_u64()), Some(3));
+    }
+
+    #[test]
+    fn test_purge_deleted_documents() {
+        let (mut store, title_field, _) = make_test_store("test_indices/test_purge_deleted_documents");
+        assert!(store.remove_document_by_key("0").unwrap());
+        assert!(store.remove_document_by_key("1").unwrap());
+        store.purge_deleted_documents().unwrap();
+
+        assert_eq!(store.db.scan_prefix(b"k").count(), 1);
+        assert_eq!(store.db.scan_prefix(super::key_builder::stored_values_prefix(0)).count(), 0);
+        assert_eq!(store.db.scan_prefix(super::key_builder::term_directory(title_field, b"hello")).count(), 0);
+
+        let reader = store.reader().unwrap();
+        assert_eq!(count(&reader, &Query::term(title_field, Term::from_string("world"))), 1);
+        assert!(reader.get_document("2").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_search_scores() {
+        let (store, title_field, _) = make_test_store("test_indices/test_search_scores");
+        let reader = store.reader().unwrap();
+
+        // The shorter title scores higher
+        let mut collector = TopScoreCollector::new(10);
+        reader.search(&mut collector, &Query::term(title_field, Term::from_string("hello"))).unwrap();
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![1, 0]);
+        assert!(docs[0].score().unwrap() > docs[1].score().unwrap());
+    }
+
+    #[test]
+    fn test_reader_statistics() {
+        let (store, title_field, _) = make_test_store("test_indices/test_reader_statistics");
+        let world = Query::term(title_field, Term::from_string("world"));
+        let scores = |reader: &SledReader| {
+            let mut collector = TopScoreCollector::new(10);
+            reader.search(&mut collector, &world).unwrap();
+            collector.into_sorted_vec().iter().map(|doc| (doc.doc_id(), doc.score().unwrap())).collect::<Vec<_>>()
+        };
+
+        let before = store.reader().unwrap();
+        let before_scores = scores(&before);
+
+        for key in vec!["3", "4", "5"] {
+            let doc = DocumentBuilder::new(store.schema(), key).text(title_field, "world world world").build().unwrap();
+            store.insert_or_update_document(&doc).unwrap();
+        }
+
+        // The field statistics used for scoring are loaded when the reader is opened, so the
+        // reader's scores don't change when more documents are inserted
+        assert_eq!(scores(&before), before_scores);
+
+        let after_scores = scores(&store.reader().unwrap());
+        let score_of = |scores: &[(u64, f32)], doc_id: u64| scores.iter().find(|&&(id, _)| id == doc_id).unwrap().1;
+        assert_eq!(after_scores.len(), 5);
+        assert_ne!(score_of(&after_scores, 0), score_of(&before_scores, 0));
+    }
+
+    #[test]
+    fn test_remove_field() {
+        let (mut store, title_field, _) = make_test_store("test_indices/test_remove_field");
+        assert!(store.remove_field(&title_field).unwrap());
+        assert!(!store.remove_field(&title_field).unwrap());
+        drop(store);
+
+        let store = SledStore::open("test_indices/test_remove_field").unwrap();
+        assert_eq!(store.schema().get_field_by_name("title"), None);
+    }
+
+    #[test]
+    fn test_migrate_integer_terms() {
+        remove_dir_all_ignore_error("test_indices/test_migrate_integer_terms");
+
+        let mut store = SledStore::create("test_indices/test_migrate_integer_terms").unwrap();
+        let count_field = store.add_field("count".to_string(), FieldType::I64, FIELD_INDEXED).unwrap();
+        for value in -2..3 {
+            let doc = DocumentBuilder::new(store.schema(), &value.to_string()).integer(count_field, value).build().unwrap();
+            store.insert_or_update_document(&doc).unwrap();
+        }
+
+        // Put the terms back into the little endian encoding of older versions
+        let old_term = |value: i64| {
+            let mut bytes = vec![0; 8];
+            LittleEndian::write_i64(&mut bytes, value);
+            bytes
+        };
+        let mut batch = sled::Batch::default();
+        for (doc, value) in (-2..3).enumerate() {
+            let doc = doc as u32;
+            let term = Term::from_integer(value);
+            batch.remove(key_builder::term_directory_entry(count_field, term.as_bytes(), doc));
+            batch.insert(key_builder::term_directory_entry(count_field, &old_term(value), doc), &[]);
+            let term_frequency = store.db.get(key_builder::term_frequency(count_field, doc, term.as_bytes())).unwrap().unwrap();
+            batch.remove(key_builder::term_frequency(count_field, doc, term.as_bytes()));
+            batch.insert(key_builder::term_frequency(count_field, doc, &old_term(value)), term_frequency);
+        }
+        batch.remove(key_builder::SORTABLE_INTEGER_TERMS);
+        store.db.apply_batch(batch).unwrap();
+        drop(store);
+
+        let store = SledStore::open("test_indices/test_migrate_integer_terms").unwrap();
+        let reader = store.reader().unwrap();
+        assert_eq!(count(&reader, &Query::term(count_field, Term::from_integer(-2))), 1);
+        assert_eq!(count(&reader, &Query::term(count_field, Term::from_integer(2))), 1);
+        assert_eq!(store.db.scan_prefix(key_builder::term_directory(count_field, &old_term(2))).count(), 0);
+        assert!(store.db.get(key_builder::term_frequency(count_field, 4, Term::from_integer(2).as_bytes())).unwrap().is_some());
+    }
+}
@@ -0,0 +1,361 @@
+//! Searching a sled index
+//!
+//! Queries are run in two passes: the set of documents that each part of the query matches is
+//! found from the term directories, then each match is scored by a tree of scorers that was
+//! built alongside. Scores are combined the same way as kite_rocksdb.
+//!
+//! Queries that need data that isn't kept in sled indexes (such as term vectors, rank features
+//! and doc values columns) aren't supported and return an error. Range queries are run by
+//! reading the stored value of every live document.
+
+use kite::Term;
+use kite::schema::FieldId;
+use kite::query::Query;
+use kite::query::term_scorer::TermScorer;
+use kite::query::field_value_factor::FieldValueFactor;
+use kite::query::function_score::{ScoreFunction, FunctionScoreCombine};
+use kite::collectors::{Collector, DocumentMatch};
+use kite::error::SearchError;
+use roaring::RoaringBitmap;
+
+use {SledReader, ReadError, key_builder, decode_i64};
+
+enum Scorer {
+    Literal(f32),
+    Term {
+        field_id: FieldId,
+        term: Term,
+        scorer: TermScorer,
+
+        /// The live documents that contain the term
+        docs: RoaringBitmap,
+    },
+    Avg(Vec<Scorer>),
+    Max(Vec<Scorer>),
+    FieldValueFactor(Box<Scorer>, FieldValueFactor),
+    FunctionScore(Box<Scorer>, Vec<ScoreFunction>, FunctionScoreCombine),
+    Boosting(Box<Scorer>, RoaringBitmap, f32),
+}
+
+impl<'a> SledReader<'a> {
+    /// Loads the live documents that contain the term
+    fn load_term_directory(&self, field_id: FieldId, term: &Term) -> Result<RoaringBitmap, ReadError> {
+        let mut term_directory = RoaringBitmap::new();
+        for entry in self.store.db.scan_prefix(key_builder::term_directory(field_id, term.as_bytes())) {
+            let doc = key_builder::parse_doc_suffix(&try!(entry).0);
+
+            // Documents inserted after the reader was opened aren't live in it
+            if self.live_docs.contains(doc) {
+                term_directory.insert(doc);
+            }
+        }
+
+        Ok(term_directory)
+    }
+
+    fn read_i64(&self, key: &[u8]) -> Result<Option<i64>, ReadError> {
+        match try!(self.store.db.get(key)) {
+            Some(value) => Ok(Some(try!(decode_i64(&value)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a stored value of a document as an integer (dates in microseconds)
+    fn read_doc_value(&self, doc: u32, field_id: FieldId) -> Result<Option<i64>, ReadError> {
+        Ok(try!(self.read_stored_field_raw(doc, field_id)).and_then(|value| value.to_doc_value()))
+    }
+
+    fn plan_all<'q, I: IntoIterator<Item = &'q Query>>(&self, queries: I) -> Result<(Vec<RoaringBitmap>, Vec<Scorer>), SearchError> {
+        let mut matches = Vec::new();
+        let mut scorers = Vec::new();
+
+        for query in queries {
+            let (docs, scorer) = try!(self.plan(query));
+            matches.push(docs);
+            scorers.push(scorer);
+        }
+
+        Ok((matches, scorers))
+    }
+
+    /// Finds the documents that match the query and builds the scorer for them
+    fn plan(&self, query: &Query) -> Result<(RoaringBitmap, Scorer), SearchError> {
+        match *query {
+            Query::All{score} => Ok((self.live_docs.clone(), Scorer::Literal(score))),
+            Query::None => Ok((RoaringBitmap::new(), Scorer::Literal(0.0f32))),
+            Query::Term{field, ref term, ref scorer} => {
+                let docs = try!(self.load_term_directory(field, term));
+
+                Ok((docs.clone(), Scorer::Term {
+                    field_id: field,
+                    term: term.clone(),
+                    scorer: scorer.clone(),
+                    docs: docs,
+                }))
+            }
+            Query::Terms{field, ref terms, score} => {
+                let mut docs = RoaringBitmap::new();
+                for term in terms.iter() {
+                    docs |= try!(self.load_term_directory(field, term));
+                }
+
+                Ok((docs, Scorer::Literal(score)))
+            }
+            Query::Conjunction{ref queries} => {
+                let (matches, scorers) = try!(self.plan_all(queries));
+                let mut matches = matches.into_iter();
+                let docs = match matches.next() {
+                    Some(first) => matches.fold(first, |docs, other| docs & other),
+                    None => RoaringBitmap::new(),
+                };
+
+                Ok((docs, Scorer::Avg(scorers)))
+            }
+            Query::Disjunction{ref queries} => {
+                let (matches, scorers) = try!(self.plan_all(queries));
+                let docs = matches.into_iter().fold(RoaringBitmap::new(), |docs, other| docs | other);
+
+                Ok((docs, Scorer::Avg(scorers)))
+            }
+            Query::DisjunctionMax{ref queries} => {
+                let (matches, scorers) = try!(self.plan_all(queries));
+                let docs = matches.into_iter().fold(RoaringBitmap::new(), |docs, other| docs | other);
+
+                Ok((docs, Scorer::Max(scorers)))
+            }
+            Query::Boolean{ref must, ref should, ref must_not, minimum_should_match} => {
+                let (must_matches, mut scorers) = try!(self.plan_all(must));
+                let (should_matches, should_scorers) = try!(self.plan_all(should));
+                scorers.extend(should_scorers);
+
+                let mut docs = must_matches.into_iter().fold(self.live_docs.clone(), |docs, other| docs & other);
+
+                // Without any "must" queries, at least one "should" query must match
+                let minimum_should_match = if must.is_empty() {
+                    minimum_should_match.max(1)
+                } else {
+                    minimum_should_match
+                };
+
+                if minimum_should_match > 0 {
+                    docs = docs.iter()
+                        .filter(|doc| should_matches.iter().filter(|matches| matches.contains(*doc)).count() >= minimum_should_match)
+                        .collect();
+                }
+
+                for query in must_not.iter() {
+                    docs -= try!(self.plan(query)).0;
+                }
+
+                Ok((docs, Scorer::Avg(scorers)))
+            }
+            Query::Filter{ref query, ref filter} => {
+                let (docs, scorer) = try!(self.plan(query));
+                Ok((docs & try!(self.plan(filter)).0, scorer))
+            }
+            Query::Exclude{ref query, ref exclude} => {
+                let (docs, scorer) = try!(self.plan(query));
+                Ok((docs - try!(self.plan(exclude)).0, scorer))
+            }
+            Query::Boosting{ref positive, ref negative, negative_boost} => {
+                let (docs, scorer) = try!(self.plan(positive));
+                let negative_docs = try!(self.plan(negative)).0;
+
+                Ok((docs, Scorer::Boosting(Box::new(scorer), negative_docs, negative_boost)))
+            }
+            Query::ConstantScore{ref query, score} => {
+                Ok((try!(self.plan(query)).0, Scorer::Literal(score)))
+            }
+            Query::FieldValueFactor{ref query, ref factor} => {
+                let (docs, scorer) = try!(self.plan(query));
+                Ok((docs, Scorer::FieldValueFactor(Box::new(scorer), factor.clone())))
+            }
+            Query::FunctionScore{ref query, ref functions, combine} => {
+                let (docs, scorer) = try!(self.plan(query));
+                Ok((docs, Scorer::FunctionScore(Box::new(scorer), functions.clone(), combine)))
+            }
+            Query::Exists{field, score} => {
+                let mut docs = RoaringBitmap::new();
+                for doc in self.live_docs.iter() {
+                    let is_indexed = try!(self.read_i64(&key_builder::field_length(field, doc))).is_some();
+                    if is_indexed || try!(self.read_stored_field_raw(doc, field)).is_some() {
+                        docs.insert(doc);
+                    }
+                }
+
+                Ok((docs, Scorer::Literal(score)))
+            }
+            Query::Range{field, min, max, score} => {
+                let mut docs = RoaringBitmap::new();
+                for doc in self.live_docs.iter() {
+                    if let Some(value) = try!(self.read_doc_value(doc, field)) {
+                        if min.map_or(true, |min| value >= min) && max.map_or(true, |max| value <= max) {
+                            docs.insert(doc);
+                        }
+                    }
+                }
+
+                Ok((docs, Scorer::Literal(score)))
+            }
+            _ => Err(SearchError::Plan(format!("query isn't supported by the sled backend: {:?}", query))),
+        }
+    }
+
+    fn score_term(&self, doc: u32, field_id: FieldId, term: &Term, scorer: &TermScorer, docs: &RoaringBitmap) -> Result<f32, ReadError> {
+        let term_frequency = try!(self.read_i64(&key_builder::term_frequency(field_id, doc, term.as_bytes()))).unwrap_or(1);
+        let field_length = try!(self.read_i64(&key_builder::field_length(field_id, doc))).unwrap_or(1);
+        let (total_docs, total_tokens) = self.field_totals.get(&field_id).cloned().unwrap_or((0, 0));
+
+        let score = scorer.similarity_model.score(term_frequency as u32, field_length as f32, total_tokens as u64, total_docs as u64, docs.len());
+        Ok(score * scorer.boost)
+    }
+
+    fn score(&self, doc: u32, scorer: &Scorer) -> Result<f32, ReadError> {
+        match *scorer {
+            Scorer::Literal(score) => Ok(score),
+            Scorer::Term{field_id, ref term, ref scorer, ref docs} => {
+                if docs.contains(doc) {
+                    self.score_term(doc, field_id, term, scorer, docs)
+                } else {
+                    Ok(0.0f32)
+                }
+            }
+            Scorer::Avg(ref scorers) => {
+                let mut total_score = 0.0f32;
+                for scorer in scorers.iter() {
+                    total_score += try!(self.score(doc, scorer));
+                }
+
+                Ok(total_score / scorers.len() as f32)
+            }
+            Scorer::Max(ref scorers) => {
+                let mut max_score = 0.0f32;
+                for scorer in scorers.iter() {
+                    max_score = max_score.max(try!(self.score(doc, scorer)));
+                }
+
+                Ok(max_score)
+            }
+            Scorer::FieldValueFactor(ref scorer, ref factor) => {
+                let base_score = try!(self.score(doc, scorer));
+                let value = try!(self.read_doc_value(doc, factor.field));
+
+                Ok(factor.apply(base_score, value.map(|value| value as f64)))
+            }
+            Scorer::FunctionScore(ref scorer, ref functions, combine) => {
+                let base_score = try!(self.score(doc, scorer));
+                let mut values = Vec::with_capacity(functions.len());
+                for function in functions.iter() {
+                    let value = try!(self.read_doc_value(doc, function.field()));
+                    values.push(function.compute(value.map(|value| value as f64)));
+                }
+
+                Ok(combine.combine(base_score, values))
+            }
+            Scorer::Boosting(ref scorer, ref negative_docs, negative_boost) => {
+                let base_score = try!(self.score(doc, scorer));
+
+                if negative_docs.contains(doc) {
+                    Ok(base_score * negative_boost)
+                } else {
+                    Ok(base_score)
+                }
+            }
+        }
+    }
+
+    pub fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), SearchError> {
+        let (docs, scorer) = try!(self.plan(query));
+        let sort_field = collector.sort_field();
+
+        for doc in docs.iter() {
+            let mut doc_match = if collector.needs_score() {
+                DocumentMatch::new_scored(doc as u64, try!(self.score(doc, &scorer)))
+            } else {
+                DocumentMatch::new_unscored(doc as u64)
+            };
+
+            if let Some(sort_field) = sort_field {
+                doc_match = doc_match.with_sort_value(try!(self.read_doc_value(doc, sort_field)));
+            }
+
+            collector.collect(doc_match);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use kite::{Term, DocumentBuilder};
+    use kite::schema::{FieldType, FIELD_INDEXED, FIELD_STORED};
+    use kite::query::Query;
+    use kite::collectors::Collector;
+    use kite::collectors::top_score::TopScoreCollector;
+    use kite::collectors::top_field::{TopFieldCollector, SortOrder};
+
+    use SledStore;
+
+    #[test]
+    fn test_search() {
+        let _ = remove_dir_all("test_indices/test_search");
+
+        let mut store = SledStore::create("test_indices/test_search").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let rating_field = store.add_field("rating".to_string(), FieldType::I64, FIELD_STORED).unwrap();
+
+        for (key, title, rating) in vec![("a", "red apple", 3), ("b", "green apple", 5), ("c", "red cherry", 1)] {
+            let doc = DocumentBuilder::new(store.schema(), key)
+                .text(title_field, title)
+                .integer(rating_field, rating)
+                .build().unwrap();
+            store.insert_or_update_document(&doc).unwrap();
+        }
+
+        let reader = store.reader().unwrap();
+        let term = |term: &str| Query::term(title_field, Term::from_string(term));
+        let search = |query: &Query| {
+            let mut collector = TopScoreCollector::new(10);
+            reader.search(&mut collector, query).unwrap();
+            collector.into_sorted_vec().iter().map(|doc| (doc.doc_id(), doc.score().unwrap())).collect::<Vec<_>>()
+        };
+        let matches = |query: &Query| {
+            let mut docs = search(query).iter().map(|&(doc_id, _)| doc_id).collect::<Vec<_>>();
+            docs.sort();
+            docs
+        };
+
+        assert_eq!(matches(&Query::conjunction(vec![term("red"), term("apple")])), vec![0]);
+        assert_eq!(matches(&Query::disjunction(vec![term("green"), term("cherry")])), vec![1, 2]);
+        assert_eq!(matches(&term("apple").filter(term("red"))), vec![0]);
+        assert_eq!(matches(&term("apple").exclude(term("red"))), vec![1]);
+        assert_eq!(matches(&Query::any_term(title_field, vec![Term::from_string("green"), Term::from_string("cherry")])), vec![1, 2]);
+        assert_eq!(matches(&Query::boolean(vec![], vec![term("red"), term("apple"), term("green")], vec![]).minimum_should_match(2)), vec![0, 1]);
+        assert_eq!(matches(&Query::range(rating_field, Some(2), None)), vec![0, 1]);
+        assert_eq!(matches(&Query::exists(rating_field)), vec![0, 1, 2]);
+
+        // Non-matching clauses of a disjunction score 0, so documents matching both score higher
+        let scores = search(&Query::disjunction(vec![term("red"), term("apple")]));
+        assert_eq!(scores[0].0, 0);
+
+        // Boosted terms score higher
+        assert_eq!(search(&term("red").boost(2.0))[0].1, search(&term("red"))[0].1 * 2.0);
+
+        // Demoted documents score lower
+        let scores = search(&term("red").demote(term("cherry"), 0.1));
+        assert_eq!(scores[0].0, 0);
+
+        // Sorting by a stored field
+        let mut collector = TopFieldCollector::new(rating_field, SortOrder::Descending, 10);
+        reader.search(&mut collector, &Query::all()).unwrap();
+        assert!(!collector.needs_score());
+        assert_eq!(collector.into_sorted_vec().iter().map(|doc| doc.doc_id()).collect::<Vec<_>>(), vec![1, 0, 2]);
+
+        // Unsupported queries return an error
+        let mut collector = TopScoreCollector::new(10);
+        assert!(reader.search(&mut collector, &Query::prefix(title_field, "re")).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use kite::Document;
+use kite::schema::{Schema, FieldType, FieldFlags, FieldId, AddFieldError};
+use kite::segment::SegmentId;
+use kite::collectors::Collector;
+use kite::query::Query;
+use kite::error::{StoreError, SearchError};
+use kite::store::{Store, StoreReader};
+
+use {SledStore, SledReader};
+
+impl Store for SledStore {
+    type Reader<'a> = SledReader<'a>;
+
+    fn schema(&self) -> &Schema {
+        SledStore::schema(self)
+    }
+
+    fn add_field(&mut self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError> {
+        SledStore::add_field(self, name, field_type, field_flags)
+    }
+
+    fn remove_field(&mut self, field_id: &FieldId) -> Result<bool, StoreError> {
+        SledStore::remove_field(self, field_id).map_err(StoreError::storage)
+    }
+
+    fn insert_or_update_document(&self, doc: &Document) -> Result<(), StoreError> {
+        SledStore::insert_or_update_document(self, doc).map_err(StoreError::storage)
+    }
+
+    fn remove_document_by_key(&self, doc_key: &str) -> Result<bool, StoreError> {
+        SledStore::remove_document_by_key(self, doc_key).map_err(StoreError::storage)
+    }
+
+    fn reader<'a>(&'a self) -> Result<SledReader<'a>, StoreError> {
+        SledStore::reader(self).map_err(StoreError::storage)
+    }
+}
+
+impl<'a> StoreReader for SledReader<'a> {
+    fn schema(&self) -> &Schema {
+        SledReader::schema(self)
+    }
+
+    fn segments(&self) -> Vec<SegmentId> {
+        SledReader::segments(self)
+    }
+
+    fn contains_document_key(&self, doc_key: &str) -> Result<bool, StoreError> {
+        SledReader::contains_document_key(self, doc_key).map_err(StoreError::storage)
+    }
+
+    fn get_document(&self, doc_key: &str) -> Result<Option<Document>, StoreError> {
+        SledReader::get_document(self, doc_key).map_err(StoreError::storage)
+    }
+
+    fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), SearchError> {
+        SledReader::search(self, collector, query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use kite::{Term, DocumentBuilder};
+    use kite::schema::{FieldType, FIELD_INDEXED};
+    use kite::query::Query;
+    use kite::segment::SegmentId;
+    use kite::collectors::total_count::TotalCountCollector;
+    use kite::store::{Store, StoreReader};
+
+    use SledStore;
+
+    #[test]
+    fn test_store_traits() {
+        let _ = remove_dir_all("test_indices/test_store_traits");
+
+        let mut store = SledStore::create("test_indices/test_store_traits").unwrap();
+        let title_field = Store::add_field(&mut store, "title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        for key in vec!["a", "b"] {
+            let doc = DocumentBuilder::new(Store::schema(&store), key).text(title_field, "hello").build().unwrap();
+            Store::insert_or_update_document(&store, &doc).unwrap();
+        }
+        assert!(Store::remove_document_by_key(&store, "a").unwrap());
+
+        let reader = Store::reader(&store).unwrap();
+        assert_eq!(StoreReader::segments(&reader), vec![SegmentId(0)]);
+        assert!(StoreReader::contains_document_key(&reader, "b").unwrap());
+        assert!(!StoreReader::contains_document_key(&reader, "a").unwrap());
+
+        let mut collector = TotalCountCollector::new();
+        StoreReader::search(&reader, &mut collector, &Query::term(title_field, Term::from_string("hello"))).unwrap();
+        assert_eq!(collector.get_total_count(), 1);
+    }
+}